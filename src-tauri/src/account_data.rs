@@ -0,0 +1,39 @@
+use matrix_sdk::ruma::events::{AnyGlobalAccountDataEventContent, GlobalAccountDataEventType};
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::Client;
+
+/// Prefix for the custom account data types owned by this app.
+pub(crate) const APP_NAMESPACE: &str = "com.thoma.matrix-client";
+
+/// Reads a global account data event's content as JSON from the local store.
+pub(crate) async fn get_global_json(
+    client: &Client,
+    event_type: &str,
+) -> Result<Option<serde_json::Value>, String> {
+    let raw = client
+        .account()
+        .account_data_raw(GlobalAccountDataEventType::from(event_type))
+        .await
+        .map_err(|e| format!("Failed to read account data: {}", e))?;
+
+    Ok(raw.and_then(|raw| raw.deserialize_as_unchecked::<serde_json::Value>().ok()))
+}
+
+/// Uploads a global account data event from arbitrary JSON content.
+pub(crate) async fn set_global_json(
+    client: &Client,
+    event_type: &str,
+    content: &serde_json::Value,
+) -> Result<(), String> {
+    let raw: Raw<AnyGlobalAccountDataEventContent> = Raw::new(content)
+        .map_err(|e| format!("Failed to serialize account data: {}", e))?
+        .cast_unchecked();
+
+    client
+        .account()
+        .set_account_data_raw(GlobalAccountDataEventType::from(event_type), raw)
+        .await
+        .map_err(|e| format!("Failed to save account data: {}", e))?;
+
+    Ok(())
+}
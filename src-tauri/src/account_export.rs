@@ -0,0 +1,309 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedRoomId};
+use matrix_sdk::{Client, Room};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tauri::{AppHandle, Emitter, State};
+use tracing::{info, warn};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::account_data::{get_global_json, APP_NAMESPACE};
+use crate::breadcrumbs::BREADCRUMBS_EVENT_TYPE;
+use crate::devices::own_devices;
+use crate::emoji::{EMOTE_ROOMS_EVENT_TYPE, USER_PACK_EVENT_TYPE};
+use crate::errors::CommandError;
+use crate::export::{collect_messages, format_iso8601, render_export, ExportFormat};
+use crate::identity::IDENTITY_SERVER_EVENT_TYPE;
+use crate::invites::invite_filter_type;
+use crate::rooms::room_info;
+use crate::saved_messages::saved_messages_type;
+use crate::state::MatrixState;
+use crate::user_notes::user_notes_type;
+use crate::warnings::dismissed_warnings_type;
+
+/// Bumped when the layout of the archive changes incompatibly.
+const ACCOUNT_EXPORT_VERSION: u32 = 1;
+
+/// Global account data types included in the export. Secret storage is
+/// left out: it holds the account's encrypted cross-signing keys.
+const EXPORTED_ACCOUNT_DATA: [&str; 4] = ["m.direct", "m.push_rules", "m.ignored_user_list", "m.accepted_terms"];
+
+/// Payload of `matrix://account-export-progress`.
+#[derive(Serialize, Clone)]
+pub struct AccountExportProgress {
+    /// `rooms`, `keys`, `account_data`, `profile`, `devices`, `messages` or
+    /// `done`.
+    pub step: String,
+    /// Rooms whose messages are exported so far, during `messages`.
+    pub rooms_done: usize,
+    pub rooms_total: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AccountExportResult {
+    pub path: String,
+    pub rooms: usize,
+    pub messages_exported: usize,
+}
+
+/// `manifest.json`: what the archive holds, for importing it later.
+#[derive(Serialize)]
+struct AccountExportManifest {
+    format: String,
+    version: u32,
+    user_id: String,
+    homeserver: String,
+    device_id: Option<String>,
+    exported_at: String,
+    files: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize)]
+struct ManifestEntry {
+    path: String,
+    description: String,
+    /// For message exports, the room they are from.
+    room_id: Option<String>,
+}
+
+/// Writes a zip of everything needed to move the account elsewhere: joined
+/// rooms, room keys encrypted with `passphrase`, account data, profile and
+/// devices, plus message history for the rooms in `message_room_ids`.
+/// Access tokens and other session secrets are never included. Progress is
+/// reported as `matrix://account-export-progress`, and `cancel_export`
+/// stops it, removing the partial file.
+#[tauri::command]
+pub async fn export_account_data(
+    app: AppHandle,
+    state: State<'_, MatrixState>,
+    path: String,
+    passphrase: String,
+    message_room_ids: Option<Vec<String>>,
+) -> Result<AccountExportResult, String> {
+    let client = state
+        .client
+        .read()
+        .await
+        .clone()
+        .ok_or("Not logged in")?;
+
+    if passphrase.is_empty() {
+        return Err(CommandError::new(
+            "PASSPHRASE_REQUIRED",
+            "Choose a passphrase to protect the exported keys",
+        )
+        .into());
+    }
+    let message_rooms = message_room_ids
+        .unwrap_or_default()
+        .iter()
+        .map(|id| {
+            let room_id: OwnedRoomId = id.parse().map_err(|e| format!("Invalid room ID: {}", e))?;
+            client.get_room(&room_id).ok_or_else(|| format!("Room not found: {}", room_id))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    state.export_cancelled.store(false, Ordering::SeqCst);
+
+    let path = PathBuf::from(path);
+    info!("Exporting account data to {:?}", path);
+
+    let result = write_archive(&app, &state, &client, &path, &passphrase, &message_rooms).await;
+    if result.is_err() {
+        let _ = fs::remove_file(&path);
+    }
+    let (rooms, messages_exported) = result?;
+
+    let _ = app.emit(
+        "matrix://account-export-progress",
+        AccountExportProgress {
+            step: "done".to_string(),
+            rooms_done: message_rooms.len(),
+            rooms_total: message_rooms.len(),
+        },
+    );
+    info!("Exported account data for {} rooms to {:?}", rooms, path);
+
+    Ok(AccountExportResult {
+        path: path.to_string_lossy().to_string(),
+        rooms,
+        messages_exported,
+    })
+}
+
+/// Returns the number of joined rooms and of exported messages.
+async fn write_archive(
+    app: &AppHandle,
+    state: &MatrixState,
+    client: &Client,
+    path: &Path,
+    passphrase: &str,
+    message_rooms: &[Room],
+) -> Result<(usize, usize), String> {
+    let user_id = client.user_id().ok_or("Not logged in")?.to_owned();
+    let file = File::create(path).map_err(|e| format!("Failed to create export: {}", e))?;
+    let mut zip = ZipWriter::new(file);
+    let mut files = Vec::new();
+    let progress = |step: &str, rooms_done: usize| {
+        let _ = app.emit(
+            "matrix://account-export-progress",
+            AccountExportProgress {
+                step: step.to_string(),
+                rooms_done,
+                rooms_total: message_rooms.len(),
+            },
+        );
+        if state.export_cancelled.load(Ordering::SeqCst) {
+            info!("Account export cancelled");
+            return Err("Export cancelled".to_string());
+        }
+        Ok(())
+    };
+
+    progress("rooms", 0)?;
+    let joined = client.joined_rooms();
+    let mut rooms = Vec::with_capacity(joined.len());
+    for room in &joined {
+        let tags = room.tags().await.ok().flatten();
+        rooms.push(json!({
+            "info": room_info(room).await,
+            "canonical_alias": room.canonical_alias(),
+            "is_direct": room.is_direct().await.unwrap_or(false),
+            "tags": tags,
+        }));
+    }
+    write_json(&mut zip, &mut files, "rooms.json", "Joined rooms and their settings", &rooms)?;
+
+    progress("keys", 0)?;
+    let keys_path = state.data_dir.join("account-export-keys.tmp");
+    let keys = client
+        .encryption()
+        .export_room_keys(keys_path.clone(), passphrase, |_| true)
+        .await
+        .map_err(|e| format!("Failed to export room keys: {}", e))
+        .and_then(|()| fs::read(&keys_path).map_err(|e| format!("Failed to read exported keys: {}", e)));
+    let _ = fs::remove_file(&keys_path);
+    write_file(
+        &mut zip,
+        &mut files,
+        "room-keys.txt",
+        "Room keys in the standard key export format, encrypted with the export passphrase",
+        &keys?,
+    )?;
+
+    progress("account_data", 0)?;
+    let mut account_data = serde_json::Map::new();
+    let app_types = [
+        BREADCRUMBS_EVENT_TYPE.to_string(),
+        IDENTITY_SERVER_EVENT_TYPE.to_string(),
+        USER_PACK_EVENT_TYPE.to_string(),
+        EMOTE_ROOMS_EVENT_TYPE.to_string(),
+        dismissed_warnings_type(),
+        invite_filter_type(),
+        user_notes_type(),
+        saved_messages_type(),
+    ];
+    for event_type in EXPORTED_ACCOUNT_DATA.iter().map(|t| t.to_string()).chain(app_types) {
+        match get_global_json(client, &event_type).await {
+            Ok(Some(content)) => {
+                account_data.insert(event_type, content);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Skipping {} in account export: {}", event_type, e),
+        }
+    }
+    write_json(
+        &mut zip,
+        &mut files,
+        "account_data.json",
+        "Global account data events, by type",
+        &account_data,
+    )?;
+
+    progress("profile", 0)?;
+    let account = client.account();
+    let profile = json!({
+        "user_id": user_id,
+        "display_name": account.get_display_name().await.ok().flatten(),
+        "avatar_url": account.get_avatar_url().await.ok().flatten(),
+    });
+    write_json(&mut zip, &mut files, "profile.json", "The user's profile", &profile)?;
+
+    progress("devices", 0)?;
+    let devices = own_devices(client).await?;
+    write_json(&mut zip, &mut files, "devices.json", "The account's sessions", &devices)?;
+
+    let mut messages_exported = 0;
+    for (done, room) in message_rooms.iter().enumerate() {
+        progress("messages", done)?;
+        let messages = collect_messages(app, state, client, room, None, None, None).await?;
+        messages_exported += messages.len();
+
+        let name = format!("messages/{}.json", room.room_id().as_str().replace([':', '!', '/'], "_"));
+        let contents = render_export(&messages, ExportFormat::Json)?;
+        add_file(&mut zip, &name, contents.as_bytes())?;
+        files.push(ManifestEntry {
+            path: name,
+            description: "Message history, oldest first".to_string(),
+            room_id: Some(room.room_id().to_string()),
+        });
+    }
+
+    let manifest = AccountExportManifest {
+        format: format!("{}.account_export", APP_NAMESPACE),
+        version: ACCOUNT_EXPORT_VERSION,
+        user_id: user_id.to_string(),
+        homeserver: client.homeserver().to_string(),
+        device_id: client.device_id().map(|id| id.to_string()),
+        exported_at: format_iso8601(MilliSecondsSinceUnixEpoch::now().get().into()),
+        files,
+    };
+    let manifest = serde_json::to_string_pretty(&manifest)
+        .map_err(|e| format!("Failed to serialize manifest: {}", e))?;
+    add_file(&mut zip, "manifest.json", manifest.as_bytes())?;
+
+    zip.finish().map_err(|e| format!("Failed to write export: {}", e))?;
+
+    Ok((joined.len(), messages_exported))
+}
+
+fn write_json(
+    zip: &mut ZipWriter<File>,
+    files: &mut Vec<ManifestEntry>,
+    name: &str,
+    description: &str,
+    value: &impl Serialize,
+) -> Result<(), String> {
+    let contents =
+        serde_json::to_string_pretty(value).map_err(|e| format!("Failed to serialize {}: {}", name, e))?;
+    write_file(zip, files, name, description, contents.as_bytes())
+}
+
+/// Adds a file to the archive and lists it in the manifest.
+fn write_file(
+    zip: &mut ZipWriter<File>,
+    files: &mut Vec<ManifestEntry>,
+    name: &str,
+    description: &str,
+    contents: &[u8],
+) -> Result<(), String> {
+    add_file(zip, name, contents)?;
+    files.push(ManifestEntry {
+        path: name.to_string(),
+        description: description.to_string(),
+        room_id: None,
+    });
+    Ok(())
+}
+
+fn add_file(zip: &mut ZipWriter<File>, name: &str, contents: &[u8]) -> Result<(), String> {
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file(name, options)
+        .and_then(|_| Ok(zip.write_all(contents)?))
+        .map_err(|e| format!("Failed to write export: {}", e))
+}
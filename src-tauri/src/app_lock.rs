@@ -0,0 +1,390 @@
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use matrix_sdk_store_encryption::StoreCipher;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tauri::async_runtime::JoinHandle;
+use tauri::ipc::Invoke;
+use tauri::{Emitter, Manager, Runtime, State};
+use tokio::sync::RwLock;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::auth::{clear_session_state, remove_store_dir};
+use crate::errors::CommandError;
+use crate::local_store::{CRYPTO_DB, EVENT_CACHE_DB, MEDIA_DB, STATE_DB};
+use crate::session::{saved_session_client, saved_store_dir};
+use crate::state::MatrixState;
+use crate::store_meta::has_cipher;
+
+const APP_LOCK_FILE: &str = "app_lock.json";
+const MIN_PIN_LENGTH: usize = 4;
+/// Commands that still answer while the app is locked.
+const LOCKED_COMMANDS: [&str; 4] = ["unlock_app", "lock_app", "is_locked", "notify_activity"];
+
+pub type AppLockState = Arc<RwLock<AppLock>>;
+
+/// The PIN lock in front of the UI and the SDK's sqlite stores. Only those
+/// stores are encrypted with the PIN: the saved session with its access
+/// token, the local message index, the media cache and the logs are not.
+pub struct AppLock {
+    config: Option<AppLockConfig>,
+    /// Derived from the PIN on unlock; encrypts the sqlite stores.
+    store_key: Option<[u8; 32]>,
+    last_activity: Instant,
+    auto_lock: Option<JoinHandle<()>>,
+}
+
+impl AppLock {
+    pub fn load(data_dir: &Path) -> Self {
+        let config = fs::read_to_string(data_dir.join(APP_LOCK_FILE))
+            .ok()
+            .and_then(|json| {
+                serde_json::from_str(&json)
+                    .inspect_err(|e| warn!("Ignoring unreadable app lock: {}", e))
+                    .ok()
+            });
+        Self {
+            config,
+            store_key: None,
+            last_activity: Instant::now(),
+            auto_lock: None,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.is_some()
+    }
+}
+
+/// `app_lock.json`, in the app data directory.
+#[derive(Serialize, Deserialize, Clone)]
+struct AppLockConfig {
+    /// Argon2 hash of the PIN, as a PHC string.
+    pin_hash: String,
+    /// Salt for deriving the store key from the PIN, base64.
+    key_salt: String,
+    auto_lock_secs: Option<u64>,
+    wipe_after: Option<u32>,
+    failed_attempts: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AppLockStatus {
+    pub enabled: bool,
+    pub locked: bool,
+    pub auto_lock_secs: Option<u64>,
+    pub wipe_after: Option<u32>,
+    /// Whether the current session's sqlite stores (state, crypto, event
+    /// cache and media) are encrypted with the PIN. A session logged in
+    /// before the lock was set stays unencrypted until the next login.
+    pub store_encrypted: bool,
+}
+
+/// Sets or changes the PIN, which is then needed to unlock the app and to
+/// open the SDK's stores of the session; see `AppLock` for what it doesn't
+/// cover. Changing it takes `current_pin`.
+/// `auto_lock_secs` locks the app after that long without
+/// `notify_activity`; `wipe_after` erases the session after that many wrong
+/// PINs in a row.
+#[tauri::command]
+pub async fn set_app_lock(
+    state: State<'_, MatrixState>,
+    pin: String,
+    current_pin: Option<String>,
+    auto_lock_secs: Option<u64>,
+    wipe_after: Option<u32>,
+) -> Result<AppLockStatus, String> {
+    if pin.chars().count() < MIN_PIN_LENGTH {
+        return Err(CommandError::new(
+            "PIN_TOO_SHORT",
+            format!("The PIN needs at least {} characters", MIN_PIN_LENGTH),
+        )
+        .into());
+    }
+
+    let mut lock = state.app_lock.write().await;
+    if let Some(config) = &lock.config {
+        if !current_pin.is_some_and(|current| verify_pin(config, &current)) {
+            return Err(CommandError::new("WRONG_PIN", "The current PIN is wrong").into());
+        }
+    }
+
+    let mut key_salt = [0u8; 16];
+    OsRng.fill_bytes(&mut key_salt);
+    let key = derive_key(&pin, &key_salt)?;
+    let pin_hash = Argon2::default()
+        .hash_password(pin.as_bytes(), &SaltString::generate(&mut OsRng))
+        .map_err(|e| format!("Failed to hash PIN: {}", e))?
+        .to_string();
+
+    let store_dir = saved_store_dir(&state.data_dir);
+    if let (Some(old_key), Some(dir)) = (lock.store_key, &store_dir) {
+        rekey_stores(dir, &old_key, &key)?;
+    }
+
+    let config = AppLockConfig {
+        pin_hash,
+        key_salt: STANDARD.encode(key_salt),
+        auto_lock_secs: auto_lock_secs.filter(|secs| *secs > 0),
+        wipe_after: wipe_after.filter(|attempts| *attempts > 0),
+        failed_attempts: 0,
+    };
+    save_config(&state.data_dir, &config)?;
+    lock.config = Some(config);
+    lock.store_key = Some(key);
+    schedule_auto_lock(&state, &mut lock);
+    info!("App lock set");
+
+    Ok(AppLockStatus {
+        enabled: true,
+        locked: false,
+        auto_lock_secs: lock.config.as_ref().and_then(|c| c.auto_lock_secs),
+        wipe_after: lock.config.as_ref().and_then(|c| c.wipe_after),
+        store_encrypted: store_dir.is_some_and(|dir| has_cipher(&dir.join(CRYPTO_DB))),
+    })
+}
+
+/// Unlocks the app with the PIN. After `wipe_after` wrong PINs in a row the
+/// session is erased as by `logout`, the lock is removed, and this fails
+/// with `APP_WIPED`.
+#[tauri::command]
+pub async fn unlock_app(state: State<'_, MatrixState>, pin: String) -> Result<(), String> {
+    let mut lock = state.app_lock.write().await;
+    let Some(config) = lock.config.as_mut() else {
+        state.app_locked.store(false, Ordering::SeqCst);
+        return Ok(());
+    };
+
+    if verify_pin(config, &pin) {
+        let key_salt = STANDARD
+            .decode(&config.key_salt)
+            .map_err(|e| format!("Invalid app lock salt: {}", e))?;
+        let key = derive_key(&pin, &key_salt)?;
+        if config.failed_attempts > 0 {
+            config.failed_attempts = 0;
+            save_config(&state.data_dir, config)?;
+        }
+        lock.store_key = Some(key);
+        schedule_auto_lock(&state, &mut lock);
+        state.app_locked.store(false, Ordering::SeqCst);
+        info!("App unlocked");
+        return Ok(());
+    }
+
+    config.failed_attempts += 1;
+    save_config(&state.data_dir, config)?;
+    warn!("Wrong PIN, {} failed attempts", config.failed_attempts);
+
+    let attempts_left = config.wipe_after.map(|limit| limit.saturating_sub(config.failed_attempts));
+    if attempts_left == Some(0) {
+        drop(lock);
+        wipe(&state).await;
+        return Err(CommandError::new(
+            "APP_WIPED",
+            "Too many wrong PINs. You were logged out and this device's data was erased.",
+        )
+        .into());
+    }
+
+    Err(CommandError::new("WRONG_PIN", "Wrong PIN")
+        .with_details(json!({ "attempts_left": attempts_left }))
+        .into())
+}
+
+/// Locks the app now. The client keeps running, but commands other than
+/// unlocking are refused until the PIN is entered.
+#[tauri::command]
+pub async fn lock_app(state: State<'_, MatrixState>) -> Result<(), String> {
+    let mut lock = state.app_lock.write().await;
+    if !lock.is_enabled() {
+        return Err(CommandError::new("NO_APP_LOCK", "Set a PIN before locking the app").into());
+    }
+    if let Some(task) = lock.auto_lock.take() {
+        task.abort();
+    }
+    state.app_locked.store(true, Ordering::SeqCst);
+    info!("App locked");
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn is_locked(state: State<'_, MatrixState>) -> Result<bool, String> {
+    Ok(state.app_locked.load(Ordering::SeqCst))
+}
+
+/// Restarts the auto-lock countdown. The frontend calls this on user input.
+#[tauri::command]
+pub async fn notify_activity(state: State<'_, MatrixState>) -> Result<(), String> {
+    state.app_lock.write().await.last_activity = Instant::now();
+    Ok(())
+}
+
+/// Refuses every command but unlocking while the app is locked. Returns the
+/// invoke to run, or `None` when it was answered with `LOCKED`.
+pub(crate) fn refuse_while_locked<R: Runtime>(invoke: Invoke<R>) -> Option<Invoke<R>> {
+    if LOCKED_COMMANDS.contains(&invoke.message.command()) {
+        return Some(invoke);
+    }
+    let locked = invoke
+        .message
+        .webview_ref()
+        .try_state::<MatrixState>()
+        .is_some_and(|state| state.app_locked.load(Ordering::SeqCst));
+    if !locked {
+        return Some(invoke);
+    }
+
+    invoke
+        .resolver
+        .reject(String::from(CommandError::new("LOCKED", "The app is locked")));
+    None
+}
+
+/// The key to open the stores in `dir` with: the PIN-derived key for stores
+/// encrypted with it, and for new stores while a PIN is set. Stores created
+/// before the PIN was set are opened without one.
+pub(crate) async fn store_key(state: &MatrixState, dir: &Path) -> Result<Option<[u8; 32]>, String> {
+    let crypto_db = dir.join(CRYPTO_DB);
+    let encrypted = has_cipher(&crypto_db);
+    if crypto_db.exists() && !encrypted {
+        return Ok(None);
+    }
+
+    let lock = state.app_lock.read().await;
+    if !lock.is_enabled() {
+        return match encrypted {
+            true => Err("This session's data is encrypted with a PIN that is no longer set".to_string()),
+            false => Ok(None),
+        };
+    }
+    match lock.store_key {
+        Some(key) => Ok(Some(key)),
+        None => Err(CommandError::new("LOCKED", "The app is locked").into()),
+    }
+}
+
+fn verify_pin(config: &AppLockConfig, pin: &str) -> bool {
+    PasswordHash::new(&config.pin_hash)
+        .is_ok_and(|hash| Argon2::default().verify_password(pin.as_bytes(), &hash).is_ok())
+}
+
+fn derive_key(pin: &str, salt: &[u8]) -> Result<[u8; 32], String> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(pin.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Failed to derive store key: {}", e))?;
+    Ok(key)
+}
+
+fn save_config(data_dir: &Path, config: &AppLockConfig) -> Result<(), String> {
+    let json = serde_json::to_string(config).map_err(|e| format!("Failed to serialize app lock: {}", e))?;
+    fs::write(data_dir.join(APP_LOCK_FILE), json).map_err(|e| format!("Failed to save app lock: {}", e))
+}
+
+/// Re-encrypts each store's cipher with the new key. The data itself stays
+/// encrypted with the cipher, so nothing else is rewritten.
+fn rekey_stores(dir: &Path, old_key: &[u8; 32], new_key: &[u8; 32]) -> Result<(), String> {
+    let mut rekeyed = Vec::new();
+    for name in [STATE_DB, CRYPTO_DB, EVENT_CACHE_DB, MEDIA_DB] {
+        let db = dir.join(name);
+        if !db.exists() {
+            continue;
+        }
+        let connection = Connection::open(&db).map_err(|e| format!("Failed to open {}: {}", name, e))?;
+        let cipher: Option<Vec<u8>> = connection
+            .query_row("SELECT value FROM kv WHERE key = 'cipher'", [], |row| row.get(0))
+            .optional()
+            .map_err(|e| format!("Failed to read {}: {}", name, e))?;
+        let Some(cipher) = cipher else {
+            continue;
+        };
+        let export = StoreCipher::import_with_key(old_key, &cipher)
+            .and_then(|cipher| cipher.export_with_key(new_key))
+            .map_err(|e| format!("Failed to re-encrypt {}: {}", name, e))?;
+        rekeyed.push((connection, name, export));
+    }
+
+    // Written only once every store re-encrypted, so a failure leaves them
+    // all on the old key.
+    for (connection, name, export) in rekeyed {
+        connection
+            .execute("UPDATE kv SET value = ?1 WHERE key = 'cipher'", [export])
+            .map_err(|e| format!("Failed to update {}: {}", name, e))?;
+    }
+    Ok(())
+}
+
+/// Locks the app once `auto_lock_secs` pass without activity.
+fn schedule_auto_lock(state: &MatrixState, lock: &mut AppLock) {
+    if let Some(task) = lock.auto_lock.take() {
+        task.abort();
+    }
+    let Some(timeout) = lock.config.as_ref().and_then(|c| c.auto_lock_secs).map(Duration::from_secs) else {
+        return;
+    };
+    lock.last_activity = Instant::now();
+
+    let app_lock = state.app_lock.clone();
+    let locked: Arc<AtomicBool> = state.app_locked.clone();
+    let app = state.app.clone();
+    lock.auto_lock = Some(tauri::async_runtime::spawn(async move {
+        loop {
+            let idle = app_lock.read().await.last_activity.elapsed();
+            if idle >= timeout {
+                break;
+            }
+            sleep(timeout - idle).await;
+        }
+        locked.store(true, Ordering::SeqCst);
+        info!("App locked after {}s without activity", timeout.as_secs());
+        let _ = app.emit("matrix://app-locked", ());
+    }));
+}
+
+/// Erases the session after too many wrong PINs: the device is logged out
+/// on the server when it can be reached, and local data is removed either
+/// way, along with the lock itself.
+async fn wipe(state: &MatrixState) {
+    warn!("Too many wrong PINs, erasing the session");
+    let store_dir = saved_store_dir(&state.data_dir);
+
+    let client = match state.client.read().await.clone() {
+        Some(client) => Some(client),
+        None => saved_session_client(&state.data_dir).await,
+    };
+    if let Some(client) = client {
+        if let Err(e) = client.logout().await {
+            warn!("Failed to log out on the server: {}", e);
+        }
+    }
+
+    clear_session_state(state).await;
+    if let Some(store_dir) = store_dir {
+        if let Err(e) = remove_store_dir(&store_dir) {
+            warn!("{}", e);
+        }
+    }
+
+    let mut lock = state.app_lock.write().await;
+    if let Some(task) = lock.auto_lock.take() {
+        task.abort();
+    }
+    lock.config = None;
+    lock.store_key = None;
+    if let Err(e) = fs::remove_file(state.data_dir.join(APP_LOCK_FILE)) {
+        warn!("Failed to remove app lock: {}", e);
+    }
+    state.app_locked.store(false, Ordering::SeqCst);
+}
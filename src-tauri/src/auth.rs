@@ -1,9 +1,57 @@
-use matrix_sdk::{config::SyncSettings, Client};
+use matrix_sdk::ruma::api::client::account::{get_username_availability, register};
+use matrix_sdk::authentication::matrix::MatrixSession;
+use matrix_sdk::authentication::SessionTokens;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::{OwnedDeviceId, OwnedUserId};
+use matrix_sdk::store::RoomLoadSettings;
+use matrix_sdk::{config::SyncSettings, Client, ClientBuildError, HttpError, SessionMeta, SqliteStoreConfig};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::fs;
-use tauri::State;
-
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::async_runtime::JoinHandle;
+use tauri::{AppHandle, Manager, State};
+use tokio::time::timeout;
+use tracing::{debug, info, warn};
+
+use crate::app_lock::store_key;
+use crate::client_config::refresh_client_config;
+use crate::errors::{error_code, CommandError};
+use crate::identity_changes::watch_identity_changes;
+use crate::instance_lock::{check_store_lock, lock_store, unlock_store};
+use crate::key_imports::watch_key_import;
+use crate::session::{
+    clear_preserved_device, clear_saved_session, preserve_device, preserved_device, restore_saved_session,
+    save_session, saved_store_dir,
+};
+use crate::settings::{load_settings, Settings};
 use crate::state::MatrixState;
+use crate::sync_mod::install_event_handlers;
+use crate::uiaa::{default_auth_error, run_with_auth, AuthOperation};
+
+/// How long the `/versions` pre-flight may take before the server counts as
+/// unreachable.
+const PREFLIGHT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Session directories of sign-ins that haven't succeeded yet are named
+/// after the user with this prefix.
+const STAGING_PREFIX: &str = ".staging-";
+
+/// How long `check_session` waits for `/account/whoami`.
+const WHOAMI_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionStatus {
+    /// `valid`, `expired`, `no_session` or `offline_assumed_valid`.
+    pub status: String,
+    pub user_id: Option<String>,
+    pub device_id: Option<String>,
+    pub homeserver: Option<String>,
+    pub is_guest: bool,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct LoginResponse {
@@ -11,6 +59,10 @@ pub struct LoginResponse {
     pub user_id: String,
     pub device_id: String,
     pub message: String,
+    /// Whether the new device should be verified to read encrypted history.
+    pub needs_verification: bool,
+    /// A read-only guest session from `guest_login`.
+    pub is_guest: bool,
 }
 
 #[tauri::command]
@@ -19,6 +71,7 @@ pub async fn matrix_login(
     homeserver: String,
     username: String,
     password: String,
+    device_display_name: Option<String>,
 ) -> Result<LoginResponse, String> {
     if homeserver.trim().is_empty() || username.trim().is_empty() || password.is_empty() {
         return Err("All fields are required".to_string());
@@ -28,58 +81,525 @@ pub async fn matrix_login(
         return Err("Homeserver URL must start with http:// or https://".to_string());
     }
 
-    let session_dir = state.data_dir.join(sanitize_user_id(&username));
-
-    if session_dir.exists() {
-        println!("Found existing session data, clearing...");
-        fs::remove_dir_all(&session_dir)
-            .map_err(|e| format!("Failed to clear old session: {}", e))?;
-    }
-
-    fs::create_dir_all(&session_dir)
-        .map_err(|e| format!("Failed to create data directory: {}", e))?;
+    check_homeserver_reachable(&homeserver).await?;
 
-    println!("Using session directory: {:?}", session_dir);
+    // A device kept at logout is logged back into, so its keys and
+    // verification still apply.
+    let store_dir = state.data_dir.join(sanitize_user_id(&username));
+    let preserved = preserved_device(&store_dir, &homeserver, &username);
 
-    let client = Client::builder()
-        .homeserver_url(homeserver.trim())
-        .sqlite_store(&session_dir, None)
-        .build()
-        .await
-        .map_err(|e| format!("Failed to connect: {}", e))?;
+    let client = build_client(&state, &homeserver, &username, preserved.is_some()).await?;
+    let device_name = device_display_name_or_default(&state.app, device_display_name);
 
-    let response = client
+    let mut login = client
         .matrix_auth()
         .login_username(username.trim(), &password)
-        .initial_device_display_name("Matrix Client (Rust)")
-        .await
-        .map_err(|e| format!("Login failed: {}", e))?;
+        .initial_device_display_name(&device_name);
+    if let Some(preserved) = &preserved {
+        info!("Logging back into kept device {}", preserved.device_id);
+        login = login.device_id(preserved.device_id.as_str());
+    }
+    let response = login.await.map_err(|e| map_login_error(&e))?;
+    clear_preserved_device(&store_dir);
 
     let user_id = response.user_id.to_string();
     let device_id = response.device_id.to_string();
 
-    println!("Logged in as {} on device {}", user_id, device_id);
+    info!("Logged in as {} on device {}", user_id, device_id);
 
-    println!("Performing initial sync...");
+    install_event_handlers(&client, &state).await;
+
+    info!("Performing initial sync...");
     client
         .sync_once(SyncSettings::default())
         .await
         .map_err(|e| format!("Initial sync failed: {}", e))?;
 
-    println!("Login and sync completed successfully");
+    info!("Login and sync completed successfully");
+
+    if let Err(e) = save_session(&state.data_dir, &client, &store_dir) {
+        warn!("{}", e);
+    }
+    refresh_client_config(&state.data_dir, &state.client_config, &client, true);
+    load_settings(&state.data_dir, &state.settings, &client).await;
+    watch_identity_changes(&state.app, &state.identity_watcher, &client).await;
 
     *state.client.write().await = Some(client);
     *state.user_id.write().await = Some(user_id.clone());
+    state.is_guest.store(false, Ordering::SeqCst);
 
     Ok(LoginResponse {
         success: true,
         user_id,
         device_id,
         message: "Login successful - encryption enabled".to_string(),
+        needs_verification: true,
+        is_guest: false,
+    })
+}
+
+/// Registers `username` on `homeserver` and logs in, like `matrix_login`.
+///
+/// Dummy stages are answered automatically and `token` is used for the
+/// registration-token stage. Other stages (recaptcha, terms, email) come back
+/// as an `AUTH_REQUIRED` error to finish with `complete_auth`, which then
+/// returns the `LoginResponse`. Username and password problems are reported
+/// with the codes `USERNAME_IN_USE`, `INVALID_USERNAME`, `USERNAME_EXCLUSIVE`,
+/// `WEAK_PASSWORD` and `REGISTRATION_DISABLED`.
+#[tauri::command]
+pub async fn register_account(
+    state: State<'_, MatrixState>,
+    homeserver: String,
+    username: String,
+    password: String,
+    initial_device_name: Option<String>,
+    token: Option<String>,
+) -> Result<LoginResponse, String> {
+    if homeserver.trim().is_empty() || username.trim().is_empty() || password.is_empty() {
+        return Err("All fields are required".to_string());
+    }
+
+    if !homeserver.starts_with("http://") && !homeserver.starts_with("https://") {
+        return Err("Homeserver URL must start with http:// or https://".to_string());
+    }
+
+    let username = username.trim().trim_start_matches('@').to_string();
+    let username = username.split(':').next().unwrap_or_default().to_string();
+
+    check_homeserver_reachable(&homeserver).await?;
+
+    // An existing session of this user stays untouched until the
+    // registration succeeds.
+    let (client, staging_dir) = build_staged_client(&state, &homeserver, &username).await?;
+
+    if let Err(e) = client
+        .send(get_username_availability::v3::Request::new(username.clone()))
+        .await
+    {
+        if matches!(
+            e.client_api_error_kind(),
+            Some(ErrorKind::UserInUse | ErrorKind::InvalidUsername | ErrorKind::Exclusive)
+        ) {
+            discard_store(&staging_dir);
+            return Err(map_registration_error(&e.into(), "register"));
+        }
+    }
+
+    let device_name = device_display_name_or_default(&state.app, initial_device_name);
+    let app = state.app.clone();
+    let store_dir = state.data_dir.join(sanitize_user_id(&username));
+    let staged = staging_dir.clone();
+
+    let operation: AuthOperation = Arc::new(move |client, auth| {
+        let mut request = register::v3::Request::new();
+        request.username = Some(username.clone());
+        request.password = Some(password.clone());
+        request.initial_device_display_name = Some(device_name.clone());
+        request.auth = auth;
+
+        let app = app.clone();
+        let username = username.clone();
+        let staging_dir = staged.clone();
+        let store_dir = store_dir.clone();
+        Box::pin(async move {
+            let response = client.matrix_auth().register(request).await?;
+            let state = app.state::<MatrixState>();
+            let client = adopt_staged_client(&state, client, &staging_dir, &username)
+                .await
+                .map_err(std::io::Error::other)?;
+            let user_id = response.user_id.to_string();
+            let device_id = response
+                .device_id
+                .map(|d| d.to_string())
+                .unwrap_or_default();
+
+            info!("Registered {} on device {}", user_id, device_id);
+
+            install_event_handlers(&client, &state).await;
+
+            info!("Performing initial sync...");
+            client.sync_once(SyncSettings::default()).await?;
+
+            if let Err(e) = save_session(&state.data_dir, &client, &store_dir) {
+                warn!("{}", e);
+            }
+            refresh_client_config(&state.data_dir, &state.client_config, &client, true);
+            load_settings(&state.data_dir, &state.settings, &client).await;
+            watch_identity_changes(&state.app, &state.identity_watcher, &client).await;
+
+            *state.client.write().await = Some(client);
+            *state.user_id.write().await = Some(user_id.clone());
+            state.is_guest.store(false, Ordering::SeqCst);
+
+            Ok(serde_json::to_value(LoginResponse {
+                success: true,
+                user_id,
+                device_id,
+                message: "Registration successful - encryption enabled".to_string(),
+                needs_verification: true,
+                is_guest: false,
+            })?)
+        })
+    });
+
+    let provided_stages = token
+        .map(|token| vec![json!({ "type": "m.login.registration_token", "token": token.trim() })])
+        .unwrap_or_default();
+
+    let response = run_with_auth(
+        &state,
+        &client,
+        "register",
+        operation,
+        provided_stages,
+        map_registration_error,
+    )
+    .await;
+    // Kept while the server waits on an auth stage; `complete_auth` goes on
+    // with the same staged client.
+    if response.as_ref().is_err_and(|e| error_code(e).as_deref() != Some("AUTH_REQUIRED")) {
+        discard_store(&staging_dir);
+    }
+    let response = response?;
+
+    serde_json::from_value(response).map_err(|e| format!("Unexpected registration result: {}", e))
+}
+
+/// Signs in with an access token the user already has, e.g. from a bot
+/// registration or another client's settings, instead of a password. The
+/// token is checked with `/account/whoami`; a `device_id` that doesn't belong
+/// to the token fails with `DEVICE_ID_MISMATCH`. Encryption starts from
+/// scratch on this device, so it needs verifying afterwards.
+#[tauri::command]
+pub async fn login_with_token(
+    state: State<'_, MatrixState>,
+    homeserver: String,
+    user_id: String,
+    access_token: String,
+    device_id: String,
+) -> Result<LoginResponse, String> {
+    if homeserver.trim().is_empty()
+        || user_id.trim().is_empty()
+        || access_token.trim().is_empty()
+        || device_id.trim().is_empty()
+    {
+        return Err("All fields are required".to_string());
+    }
+
+    if !homeserver.starts_with("http://") && !homeserver.starts_with("https://") {
+        return Err("Homeserver URL must start with http:// or https://".to_string());
+    }
+
+    let user_id = OwnedUserId::try_from(user_id.trim()).map_err(|e| format!("Invalid user ID: {}", e))?;
+    let device_id = OwnedDeviceId::from(device_id.trim());
+
+    check_homeserver_reachable(&homeserver).await?;
+
+    // The token is checked from a staged store, so a bad one leaves an
+    // existing session of this user alone.
+    let (client, staging_dir) = build_staged_client(&state, &homeserver, user_id.as_str()).await?;
+    let store_dir = state.data_dir.join(sanitize_user_id(user_id.as_str()));
+
+    let session = MatrixSession {
+        meta: SessionMeta {
+            user_id: user_id.clone(),
+            device_id: device_id.clone(),
+        },
+        tokens: SessionTokens {
+            access_token: access_token.trim().to_string(),
+            refresh_token: None,
+        },
+    };
+    if let Err(e) = client.matrix_auth().restore_session(session, RoomLoadSettings::default()).await {
+        discard_store(&staging_dir);
+        return Err(format!("Failed to restore session: {}", e));
+    }
+
+    let whoami = match client.whoami().await {
+        Ok(whoami) => whoami,
+        Err(e) => {
+            discard_store(&staging_dir);
+            return Err(match e.client_api_error_kind() {
+                Some(ErrorKind::UnknownToken { .. }) => {
+                    CommandError::new("INVALID_TOKEN", "The access token is not valid").into()
+                }
+                _ => format!("Failed to check access token: {}", e),
+            });
+        }
+    };
+
+    // Tokens from appservices aren't tied to a device, so whoami may not
+    // report one.
+    let token_device = whoami.device_id.unwrap_or_else(|| device_id.clone());
+    if whoami.user_id != user_id || token_device != device_id {
+        discard_store(&staging_dir);
+        return Err(CommandError::new(
+            "DEVICE_ID_MISMATCH",
+            "The access token belongs to a different user or device",
+        )
+        .with_details(json!({
+            "user_id": whoami.user_id,
+            "device_id": token_device,
+        }))
+        .into());
+    }
+
+    let client = adopt_staged_client(&state, client, &staging_dir, user_id.as_str()).await?;
+    info!("Logged in as {} on device {} with an access token", user_id, device_id);
+
+    install_event_handlers(&client, &state).await;
+
+    info!("Performing initial sync...");
+    client
+        .sync_once(SyncSettings::default())
+        .await
+        .map_err(|e| format!("Initial sync failed: {}", e))?;
+
+    if let Err(e) = save_session(&state.data_dir, &client, &store_dir) {
+        warn!("{}", e);
+    }
+    refresh_client_config(&state.data_dir, &state.client_config, &client, true);
+    load_settings(&state.data_dir, &state.settings, &client).await;
+    watch_identity_changes(&state.app, &state.identity_watcher, &client).await;
+
+    *state.client.write().await = Some(client);
+    *state.user_id.write().await = Some(user_id.to_string());
+    state.is_guest.store(false, Ordering::SeqCst);
+
+    Ok(LoginResponse {
+        success: true,
+        user_id: user_id.to_string(),
+        device_id: device_id.to_string(),
+        message: "Login successful - verify this device to read encrypted history".to_string(),
+        needs_verification: true,
+        is_guest: false,
     })
 }
 
-fn sanitize_user_id(user_id: &str) -> String {
+/// Creates a client for `username` on `homeserver` backed by a fresh sqlite
+/// store in the user's session directory. With `keep_store` the existing
+/// store is reused instead, for logging back into a kept device.
+pub(crate) async fn build_client(
+    state: &MatrixState,
+    homeserver: &str,
+    username: &str,
+    keep_store: bool,
+) -> Result<Client, String> {
+    let session_dir = state.data_dir.join(sanitize_user_id(username));
+
+    state.room_cache.write().await.clear();
+
+    check_store_lock(&session_dir)?;
+    if session_dir.exists() && !keep_store {
+        debug!("Found existing session data, clearing...");
+        fs::remove_dir_all(&session_dir)
+            .map_err(|e| format!("Failed to clear old session: {}", e))?;
+    }
+
+    fs::create_dir_all(&session_dir)
+        .map_err(|e| format!("Failed to create data directory: {}", e))?;
+
+    lock_store(state, &session_dir).await?;
+
+    debug!("Using session directory: {:?}", session_dir);
+    open_client(state, homeserver, &session_dir).await
+}
+
+/// Creates a client like `build_client`, but with its store in a staging
+/// directory, so the session directory of `username` is only replaced by
+/// `adopt_staged_client` once the new session turns out to be valid.
+pub(crate) async fn build_staged_client(
+    state: &MatrixState,
+    homeserver: &str,
+    username: &str,
+) -> Result<(Client, PathBuf), String> {
+    let staging_dir = state
+        .data_dir
+        .join(format!("{}{}", STAGING_PREFIX, sanitize_user_id(username)));
+    if staging_dir.exists() {
+        discard_store(&staging_dir);
+    }
+    fs::create_dir_all(&staging_dir)
+        .map_err(|e| format!("Failed to create data directory: {}", e))?;
+
+    debug!("Using staging directory: {:?}", staging_dir);
+    let client = open_client(state, homeserver, &staging_dir).await?;
+    Ok((client, staging_dir))
+}
+
+/// Moves the store of a client from `build_staged_client` into the session
+/// directory of `username`, replacing what was there, and returns a client
+/// for the same session opened from its new place. Other handles to the
+/// staged client must not be used afterwards.
+pub(crate) async fn adopt_staged_client(
+    state: &MatrixState,
+    client: Client,
+    staging_dir: &Path,
+    username: &str,
+) -> Result<Client, String> {
+    let session = client.matrix_auth().session().ok_or("The new session has no access token")?;
+    let homeserver = client.homeserver().to_string();
+    drop(client);
+
+    let session_dir = state.data_dir.join(sanitize_user_id(username));
+    check_store_lock(&session_dir)?;
+    if session_dir.exists() {
+        debug!("Replacing existing session data with the new session");
+        fs::remove_dir_all(&session_dir)
+            .map_err(|e| format!("Failed to clear old session: {}", e))?;
+    }
+    fs::rename(staging_dir, &session_dir).map_err(|e| format!("Failed to move the new session into place: {}", e))?;
+
+    reopen_client(state, session, &homeserver, username).await
+}
+
+/// Opens a client on the kept store of `username` and restores `session`
+/// into it, for when the previous client was dropped to work on its store.
+pub(crate) async fn reopen_client(
+    state: &MatrixState,
+    session: MatrixSession,
+    homeserver: &str,
+    username: &str,
+) -> Result<Client, String> {
+    let client = build_client(state, homeserver, username, true).await?;
+    client
+        .matrix_auth()
+        .restore_session(session, RoomLoadSettings::default())
+        .await
+        .map_err(|e| format!("Failed to restore session: {}", e))?;
+    Ok(client)
+}
+
+/// Opens the sqlite stores in `dir`, encrypted with the app lock key if set.
+async fn open_client(state: &MatrixState, homeserver: &str, dir: &Path) -> Result<Client, String> {
+    let key = store_key(state, dir).await?;
+
+    Client::builder()
+        .homeserver_url(homeserver.trim())
+        .sqlite_store_with_config_and_cache_path(SqliteStoreConfig::new(dir).key(key.as_ref()), None::<&Path>)
+        // Lets `invite_user` share room history, and imports history
+        // shared with us when we join a room we were invited to.
+        .with_enable_share_history_on_invite(true)
+        .build()
+        .await
+        .map_err(|e| match e {
+            ClientBuildError::Http(e) => unreachable_error(&e),
+            e => format!("Failed to connect: {}", e),
+        })
+}
+
+/// Fails fast with `HOMESERVER_UNREACHABLE` or `NOT_A_HOMESERVER` instead of
+/// letting the login request hang on an unreachable server.
+pub(crate) async fn check_homeserver_reachable(homeserver: &str) -> Result<(), String> {
+    let http = matrix_sdk::reqwest::Client::builder()
+        .timeout(PREFLIGHT_TIMEOUT)
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+
+    let url = format!(
+        "{}/_matrix/client/versions",
+        homeserver.trim().trim_end_matches('/')
+    );
+    let response = http.get(&url).send().await.map_err(|e| unreachable_error(&e))?;
+
+    let is_homeserver = response.status().is_success()
+        && response
+            .text()
+            .await
+            .ok()
+            .and_then(|body| serde_json::from_str::<serde_json::Value>(&body).ok())
+            .is_some_and(|body| body.get("versions").is_some());
+
+    if !is_homeserver {
+        return Err(CommandError::new(
+            "NOT_A_HOMESERVER",
+            format!("{} does not look like a Matrix homeserver", homeserver.trim()),
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn map_login_error(error: &matrix_sdk::Error) -> String {
+    match error.client_api_error_kind() {
+        Some(ErrorKind::Forbidden { .. }) => {
+            CommandError::new("INVALID_CREDENTIALS", "Incorrect username or password").into()
+        }
+        Some(ErrorKind::UserDeactivated) => {
+            CommandError::new("USER_DEACTIVATED", "This account has been deactivated").into()
+        }
+        _ => match error {
+            matrix_sdk::Error::Http(http) => match http.as_ref() {
+                HttpError::Reqwest(e) => unreachable_error(e),
+                _ => format!("Login failed: {}", error),
+            },
+            _ => format!("Login failed: {}", error),
+        },
+    }
+}
+
+/// A `HOMESERVER_UNREACHABLE` error listing the whole cause chain (DNS, TLS,
+/// connection refused, ...) in `details.causes`.
+fn unreachable_error(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut causes = vec![error.to_string()];
+    let mut source = error.source();
+    while let Some(cause) = source {
+        causes.push(cause.to_string());
+        source = cause.source();
+    }
+
+    let message = if causes.iter().any(|c| c.contains("timed out")) {
+        "The homeserver did not respond in time"
+    } else {
+        "Could not reach the homeserver"
+    };
+
+    CommandError::new("HOMESERVER_UNREACHABLE", message)
+        .with_details(json!({ "causes": causes }))
+        .into()
+}
+
+pub(crate) fn map_registration_error(error: &matrix_sdk::Error, description: &str) -> String {
+    match error.client_api_error_kind() {
+        Some(ErrorKind::UserInUse) => {
+            CommandError::new("USERNAME_IN_USE", "This username is already taken").into()
+        }
+        Some(ErrorKind::InvalidUsername) => {
+            CommandError::new("INVALID_USERNAME", "This username contains invalid characters")
+                .into()
+        }
+        Some(ErrorKind::Exclusive) => CommandError::new(
+            "USERNAME_EXCLUSIVE",
+            "This username is reserved by an application service",
+        )
+        .into(),
+        Some(ErrorKind::WeakPassword) => {
+            CommandError::new("WEAK_PASSWORD", "This password is too weak").into()
+        }
+        Some(ErrorKind::Forbidden { .. }) => CommandError::new(
+            "REGISTRATION_DISABLED",
+            "This homeserver does not allow registration",
+        )
+        .into(),
+        _ => default_auth_error(error, description),
+    }
+}
+
+/// The name given to a new device: `requested` when it isn't blank,
+/// otherwise "<app name> <version> (<OS>)" so sessions can be told apart in
+/// other clients' device lists.
+pub(crate) fn device_display_name_or_default(app: &AppHandle, requested: Option<String>) -> String {
+    requested
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| {
+            let package = app.package_info();
+            format!("{} {} ({})", package.name, package.version, std::env::consts::OS)
+        })
+}
+
+pub(crate) fn sanitize_user_id(user_id: &str) -> String {
     user_id
         .replace("@", "")
         .replace(":", "_")
@@ -87,35 +607,168 @@ fn sanitize_user_id(user_id: &str) -> String {
         .replace("\\", "_")
 }
 
+/// Removes a session directory created for a login that was then rejected,
+/// so its crypto store isn't reused for another device.
+fn discard_store(store_dir: &Path) {
+    if let Err(e) = fs::remove_dir_all(store_dir) {
+        warn!("Failed to remove session data: {}", e);
+    }
+}
+
+/// Restores the saved session if needed and confirms the access token with
+/// `/account/whoami`. When the server can't be reached the session is assumed
+/// valid; the sync loop will surface auth errors later.
 #[tauri::command]
-pub async fn check_session(state: State<'_, MatrixState>) -> Result<Option<String>, String> {
-    let user_id = state.user_id.read().await;
-    Ok(user_id.clone())
+pub async fn check_session(state: State<'_, MatrixState>) -> Result<SessionStatus, String> {
+    let existing = state.client.read().await.clone();
+    let client = match existing {
+        Some(client) => client,
+        None => match restore_saved_session(&state).await? {
+            Some(client) => client,
+            None => {
+                return Ok(SessionStatus {
+                    status: "no_session".to_string(),
+                    user_id: None,
+                    device_id: None,
+                    homeserver: None,
+                    is_guest: false,
+                })
+            }
+        },
+    };
+
+    let mut status = SessionStatus {
+        status: "valid".to_string(),
+        user_id: client.user_id().map(|u| u.to_string()),
+        device_id: client.device_id().map(|d| d.to_string()),
+        homeserver: Some(client.homeserver().to_string()),
+        is_guest: state.is_guest.load(Ordering::SeqCst),
+    };
+
+    match timeout(WHOAMI_TIMEOUT, client.whoami()).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) if matches!(e.client_api_error_kind(), Some(ErrorKind::UnknownToken { .. })) => {
+            warn!("Access token is no longer valid");
+            clear_session_state(&state).await;
+            status.status = "expired".to_string();
+        }
+        Ok(Err(e)) => {
+            warn!("whoami failed, assuming session is valid: {}", e);
+            status.status = "offline_assumed_valid".to_string();
+        }
+        Err(_) => {
+            warn!("whoami timed out, assuming session is valid");
+            status.status = "offline_assumed_valid".to_string();
+        }
+    }
+
+    Ok(status)
 }
 
 #[tauri::command]
-pub async fn logout(state: State<'_, MatrixState>) -> Result<String, String> {
-    let client_read = state.client.read().await;
+pub async fn logout(state: State<'_, MatrixState>, erase: Option<bool>) -> Result<String, String> {
+    let erase = erase.unwrap_or(true);
+    let client = state.client.read().await.clone();
+    let store_dir = saved_store_dir(&state.data_dir).or_else(|| {
+        let user_id = client.as_ref()?.user_id()?;
+        Some(state.data_dir.join(sanitize_user_id(user_id.as_str())))
+    });
+
+    // Logging out on the server deletes the device, and with it the
+    // identity other users verified; keeping the device means not telling
+    // the server at all.
+    match &client {
+        Some(client) if erase => client.logout().await.map_err(|e| e.to_string())?,
+        Some(client) => {
+            let store_dir = store_dir.as_deref().ok_or("No session directory to keep")?;
+            preserve_device(store_dir, client)?;
+        }
+        None => {}
+    }
 
-    if let Some(client) = client_read.as_ref() {
-        client.logout().await.map_err(|e| e.to_string())?;
+    clear_session_state(&state).await;
+
+    if !erase {
+        info!("Logged out, keeping the device's encryption identity");
+        return Ok("Logged out. This device's encryption keys stay on this computer so you won't need your \
+            recovery key when you log in again; the device also stays in your session list. Anyone who can \
+            read this computer's files can read the keys, so erase them on shared computers."
+            .to_string());
+    }
+
+    if let Some(store_dir) = store_dir {
+        remove_store_dir(&store_dir)?;
     }
-    drop(client_read);
 
+    info!("Logged out and erased the device");
+    Ok("Logged out. This device and its encryption keys were deleted; logging in again will need \
+        your recovery key or another session to verify."
+        .to_string())
+}
+
+/// Forgets the logged-in session in memory and the saved session on disk,
+/// leaving the session directory alone.
+pub(crate) async fn clear_session_state(state: &MatrixState) {
     *state.client.write().await = None;
     *state.user_id.write().await = None;
-    *state.verification_flow_id.write().await = None;
-
-    let user_id_guard = state.user_id.read().await;
-    if let Some(user_id) = user_id_guard.as_ref() {
-        let session_dir = state.data_dir.join(sanitize_user_id(user_id));
-        if session_dir.exists() {
-            fs::remove_dir_all(&session_dir)
-                .map_err(|e| format!("Failed to clear session: {}", e))?;
-        }
+    *state.verification_flow.write().await = None;
+    *state.user_verification.write().await = None;
+    state.room_cache.write().await.clear();
+    state.sent_at.write().await.clear();
+    state.sent_plaintext.write().await.clear();
+    state.sent_transactions.write().await.clear();
+    *state.sync_health.write().await = Default::default();
+    state.recent_senders.write().await.clear();
+    state.member_index.write().await.clear();
+    state.page_boundaries.write().await.clear();
+    state.page_days.write().await.clear();
+    state.notifications.write().await.clear();
+    *state.active_room.write().await = None;
+    state.own_activity.write().await.clear();
+    state.pagination_tokens.write().await.clear();
+    *state.view_states.write().await = Default::default();
+    stop_session_tasks(state).await;
+    *state.client_config.write().await = None;
+    *state.settings.write().await = Settings::default();
+    state.reported_room_names.write().await.clear();
+    *state.push_rules.write().await = None;
+    state.prefetched_pages.write().await.clear();
+    state.sync_gaps.write().await.clear();
+    *state.last_prune.write().await = None;
+    state.thread_participation.write().await.clear();
+    *state.outbound.write().await = Default::default();
+    *state.identity_server.write().await = None;
+    state.pending_auth.write().await.clear();
+    state.pending_threepids.write().await.clear();
+    clear_saved_session(&state.data_dir);
+    state.is_guest.store(false, Ordering::SeqCst);
+    unlock_store(state).await;
+}
+
+/// Aborts the background tasks of the session, which hold on to its client,
+/// and returns their handles for waiting until they are gone.
+pub(crate) async fn stop_session_tasks(state: &MatrixState) -> Vec<JoinHandle<()>> {
+    let mut tasks: Vec<_> = state.member_fetches.write().await.drain().map(|(_, task)| task).collect();
+    for slot in [
+        &state.identity_watcher,
+        &state.rename_watcher,
+        &state.push_rules_fetch,
+        &state.prefetch_task,
+        &state.retention_task,
+    ] {
+        tasks.extend(slot.write().await.take());
     }
+    for task in &tasks {
+        task.abort();
+    }
+    tasks
+}
 
-    Ok("Logged out successfully".to_string())
+pub(crate) fn remove_store_dir(store_dir: &Path) -> Result<(), String> {
+    if !store_dir.exists() {
+        return Ok(());
+    }
+    fs::remove_dir_all(store_dir).map_err(|e| format!("Failed to clear session: {}", e))
 }
 
 #[tauri::command]
@@ -133,14 +786,14 @@ pub async fn verify_with_recovery_key(
     let encryption = client.encryption();
     let recovery = encryption.recovery();
 
-    println!("Attempting to recover using recovery key...");
+    info!("Attempting to recover using recovery key...");
 
-    recovery
-        .recover(&recovery_key)
-        .await
-        .map_err(|e| format!("Failed to verify with recovery key: {}", e))?;
+    let import = watch_key_import(&state, client).await;
+    let result = recovery.recover(&recovery_key).await;
+    drop(import);
+    result.map_err(|e| format!("Failed to verify with recovery key: {}", e))?;
 
-    println!("Recovery completed successfully.");
+    info!("Recovery completed successfully.");
 
     Ok("Recovery key verification completed".to_string())
 }
@@ -165,16 +818,18 @@ pub async fn request_room_keys(
         return Err("Room not found".to_string());
     }
 
-    println!("Requesting backup keys for room: {}", room_id);
+    info!("Requesting backup keys for room: {}", room_id);
 
     // Access the encryption module and then the backups submodule
     // This downloads the keys from the server-side backup if available
-    client
+    let import = watch_key_import(&state, client).await;
+    let result = client
         .encryption()
         .backups()
         .download_room_keys_for_room(&room_id)
-        .await
-        .map_err(|e| format!("Failed to download room keys from backup: {}", e))?;
+        .await;
+    drop(import);
+    result.map_err(|e| format!("Failed to download room keys from backup: {}", e))?;
 
     Ok("Room keys downloaded from backup".to_string())
 }
\ No newline at end of file
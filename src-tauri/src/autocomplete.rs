@@ -0,0 +1,181 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId, UserId};
+use matrix_sdk::{Room, RoomMemberships};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tokio::sync::RwLock;
+use tracing::{debug, warn};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::state::MatrixState;
+
+/// Suggestions returned when the caller doesn't say.
+const DEFAULT_AUTOCOMPLETE_LIMIT: usize = 10;
+/// Senders remembered per room for ranking suggestions.
+const RECENT_SENDERS_PER_ROOM: usize = 50;
+
+/// Per room, the newest timestamp at which each recent sender was seen.
+pub type RecentSenders = Arc<RwLock<HashMap<OwnedRoomId, HashMap<OwnedUserId, u64>>>>;
+/// Per room, the joined and invited members prepared for prefix matching.
+/// Dropped whenever a member event arrives for the room.
+pub type MemberIndex = Arc<RwLock<HashMap<OwnedRoomId, Arc<Vec<IndexedMember>>>>>;
+
+pub struct IndexedMember {
+    user_id: OwnedUserId,
+    display_name: Option<String>,
+    avatar_url: Option<String>,
+    /// Folded display name, falling back to the localpart.
+    name_key: String,
+    localpart_key: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MemberSuggestion {
+    pub user_id: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MemberAutocomplete {
+    /// Recent senders first, then alphabetical.
+    pub members: Vec<MemberSuggestion>,
+    /// Set when only part of the member list is known locally. The rest is
+    /// being fetched; asking again later gives complete results.
+    pub may_be_incomplete: bool,
+}
+
+/// Members of the room whose display name, any word of it, or localpart
+/// starts with `prefix`, ignoring case and accents. Answers from the local
+/// store only.
+#[tauri::command]
+pub async fn autocomplete_members(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    prefix: String,
+    limit: Option<usize>,
+) -> Result<MemberAutocomplete, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let may_be_incomplete = !room.are_members_synced();
+    if may_be_incomplete {
+        fetch_members(&state, room.clone());
+    }
+
+    let members = member_index(&state.member_index, &room).await?;
+    let prefix = fold(prefix.trim().trim_start_matches('@'));
+    let recent = state.recent_senders.read().await;
+    let recent = recent.get(&room_id);
+    let last_active = |user_id: &UserId| recent.and_then(|senders| senders.get(user_id).copied());
+
+    let mut matches: Vec<&IndexedMember> = members
+        .iter()
+        .filter(|member| {
+            member.localpart_key.starts_with(&prefix)
+                || member.name_key.starts_with(&prefix)
+                || member.name_key.split_whitespace().any(|word| word.starts_with(&prefix))
+        })
+        .collect();
+    matches.sort_by(|a, b| {
+        Reverse(last_active(&a.user_id))
+            .cmp(&Reverse(last_active(&b.user_id)))
+            .then_with(|| a.name_key.cmp(&b.name_key))
+            .then_with(|| a.user_id.cmp(&b.user_id))
+    });
+
+    let members = matches
+        .into_iter()
+        .take(limit.unwrap_or(DEFAULT_AUTOCOMPLETE_LIMIT))
+        .map(|member| MemberSuggestion {
+            user_id: member.user_id.to_string(),
+            display_name: member.display_name.clone(),
+            avatar_url: member.avatar_url.clone(),
+        })
+        .collect();
+
+    Ok(MemberAutocomplete {
+        members,
+        may_be_incomplete,
+    })
+}
+
+/// Remembers that `user_id` sent something in the room at `timestamp`,
+/// forgetting the least recent sender once the room has too many.
+pub(crate) async fn note_sender(recent: &RecentSenders, room_id: &OwnedRoomId, user_id: &UserId, timestamp: u64) {
+    let mut recent = recent.write().await;
+    let senders = recent.entry(room_id.clone()).or_default();
+
+    let last = senders.entry(user_id.to_owned()).or_insert(timestamp);
+    *last = (*last).max(timestamp);
+
+    if senders.len() > RECENT_SENDERS_PER_ROOM {
+        let oldest = senders
+            .iter()
+            .min_by_key(|(_, ts)| **ts)
+            .map(|(user_id, _)| user_id.clone());
+        if let Some(oldest) = oldest {
+            senders.remove(&oldest);
+        }
+    }
+}
+
+async fn member_index(index: &MemberIndex, room: &Room) -> Result<Arc<Vec<IndexedMember>>, String> {
+    if let Some(members) = index.read().await.get(room.room_id()) {
+        return Ok(members.clone());
+    }
+
+    let members = room
+        .members_no_sync(RoomMemberships::JOIN | RoomMemberships::INVITE)
+        .await
+        .map_err(|e| format!("Failed to load members: {}", e))?;
+    let members: Arc<Vec<IndexedMember>> = Arc::new(
+        members
+            .iter()
+            .map(|member| {
+                let localpart_key = fold(member.user_id().localpart());
+                IndexedMember {
+                    user_id: member.user_id().to_owned(),
+                    display_name: member.display_name().map(str::to_string),
+                    avatar_url: member.avatar_url().map(|url| url.to_string()),
+                    name_key: member.display_name().map(fold).unwrap_or_else(|| localpart_key.clone()),
+                    localpart_key,
+                }
+            })
+            .collect(),
+    );
+
+    index.write().await.insert(room.room_id().to_owned(), members.clone());
+    Ok(members)
+}
+
+/// Loads the full member list of a lazy-loaded room in the background.
+fn fetch_members(state: &MatrixState, room: Room) {
+    let index = state.member_index.clone();
+    tauri::async_runtime::spawn(async move {
+        debug!("Fetching members of {}", room.room_id());
+        match room.sync_members().await {
+            Ok(_) => {
+                index.write().await.remove(room.room_id());
+            }
+            Err(e) => warn!("Failed to fetch members of {}: {}", room.room_id(), e),
+        }
+    });
+}
+
+/// Lowercases and strips accents, so "Élodie" matches "elo".
+fn fold(text: &str) -> String {
+    text.nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
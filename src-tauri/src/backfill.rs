@@ -0,0 +1,514 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use matrix_sdk::deserialized_responses::TimelineEvent;
+use matrix_sdk::room::{Messages, MessagesOptions};
+use matrix_sdk::ruma::{uint, MilliSecondsSinceUnixEpoch, OwnedRoomId};
+use matrix_sdk::Room;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tauri::{Emitter, State};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::auth::sanitize_user_id;
+use crate::errors::{rate_limit_delay, CommandError};
+use crate::local_store::db_size;
+use crate::rooms::{normalize_message_content, origin_server_ts};
+use crate::state::MatrixState;
+
+/// Local index of backfilled messages, next to the SDK's own stores.
+const MESSAGE_INDEX_DB: &str = "message-index.sqlite3";
+/// 429s in a row before giving up.
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+
+/// Cancellation flags of the backfills currently running, per room.
+pub type BackfillFlags = Arc<RwLock<HashMap<OwnedRoomId, Arc<AtomicBool>>>>;
+
+/// Payload of `matrix://backfill-progress`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BackfillProgress {
+    pub room_id: String,
+    /// Events stored for the room so far, including earlier runs.
+    pub events_fetched: u64,
+    pub oldest_timestamp: Option<u64>,
+    /// Set once the room's creation event was reached.
+    pub reached_start: bool,
+    pub finished: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BackfillResult {
+    pub room_id: String,
+    /// Events fetched by this call.
+    pub events_fetched: u64,
+    pub total_events: u64,
+    pub oldest_timestamp: Option<u64>,
+    pub reached_start: bool,
+}
+
+/// How far back a room has been backfilled.
+#[derive(Clone, Default)]
+struct Checkpoint {
+    token: Option<String>,
+    events_fetched: u64,
+    oldest_timestamp: Option<u64>,
+    reached_start: bool,
+}
+
+/// Pages backwards through a room's history, decrypting what it can and
+/// storing messages in the local index, until `max_events` were fetched or
+/// the room's creation is reached. Where it stopped is remembered, so the
+/// next call for the room continues from there.
+#[tauri::command]
+pub async fn backfill_room(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    max_events: Option<u64>,
+) -> Result<BackfillResult, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    {
+        let mut running = state.backfills.write().await;
+        if running.contains_key(&room_id) {
+            return Err("This room is already being backfilled".to_string());
+        }
+        running.insert(room_id.clone(), cancelled.clone());
+    }
+
+    let index = index_path(&state).await;
+    let result = match index {
+        Ok(index) => run_backfill(&state, &room, &index, max_events, &cancelled).await,
+        Err(e) => Err(e),
+    };
+
+    state.backfills.write().await.remove(&room_id);
+    result
+}
+
+/// Stops a running backfill after its current page. Its progress is kept.
+#[tauri::command]
+pub async fn cancel_backfill(state: State<'_, MatrixState>, room_id: String) -> Result<(), String> {
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+
+    let running = state.backfills.read().await;
+    let cancelled = running.get(&room_id).ok_or("This room is not being backfilled")?;
+    cancelled.store(true, Ordering::SeqCst);
+
+    Ok(())
+}
+
+async fn run_backfill(
+    state: &MatrixState,
+    room: &Room,
+    index: &Path,
+    max_events: Option<u64>,
+    cancelled: &AtomicBool,
+) -> Result<BackfillResult, String> {
+    let room_id = room.room_id().to_owned();
+    let (retention_days, index_encrypted_rooms) = {
+        let settings = state.settings.read().await;
+        (settings.local_history_retention_days, settings.index_encrypted_rooms)
+    };
+    if !index_encrypted_rooms && room.latest_encryption_state().await.is_ok_and(|s| s.is_encrypted()) {
+        return Err(CommandError::new(
+            "INDEXING_DISABLED",
+            "Indexing encrypted rooms is turned off in the settings",
+        )
+        .into());
+    }
+    // Nothing older than the retention window is stored, so paging stops
+    // there; the checkpoint lets a longer window pick up from it later.
+    let cutoff = retention_cutoff(retention_days);
+
+    let mut checkpoint = {
+        let room_id = room_id.clone();
+        with_index(index, move |connection| load_checkpoint(connection, &room_id)).await?
+    };
+    let mut fetched = 0;
+
+    info!(
+        "Backfilling {} from {} events already stored",
+        room_id, checkpoint.events_fetched
+    );
+
+    let past_window = |checkpoint: &Checkpoint| {
+        cutoff.is_some_and(|cutoff| checkpoint.oldest_timestamp.is_some_and(|oldest| oldest < cutoff))
+    };
+    while !checkpoint.reached_start && !past_window(&checkpoint) && max_events.is_none_or(|max| fetched < max) {
+        if cancelled.load(Ordering::SeqCst) {
+            info!("Backfill of {} cancelled after {} events", room_id, fetched);
+            return Err("Backfill cancelled".to_string());
+        }
+
+        let response = fetch_page(room, checkpoint.token.as_deref()).await?;
+
+        let mut page = Vec::new();
+        for timeline_event in &response.chunk {
+            let timestamp = origin_server_ts(timeline_event);
+            if cutoff.is_none_or(|cutoff| timestamp >= cutoff) {
+                page.push((timeline_event.clone(), timestamp));
+            }
+            if timeline_event.raw().get_field::<String>("type").ok().flatten().as_deref() == Some("m.room.create") {
+                checkpoint.reached_start = true;
+            }
+            checkpoint.oldest_timestamp = Some(match checkpoint.oldest_timestamp {
+                Some(oldest) => oldest.min(timestamp),
+                None => timestamp,
+            });
+        }
+
+        let count = response.chunk.len() as u64;
+        fetched += count;
+        checkpoint.events_fetched += count;
+        checkpoint.reached_start |= response.chunk.is_empty() || response.end.is_none();
+        checkpoint.token = response.end;
+        {
+            let room_id = room_id.clone();
+            let checkpoint = checkpoint.clone();
+            with_index(index, move |connection| {
+                for (event, timestamp) in &page {
+                    store_event(connection, &room_id, event, *timestamp)?;
+                }
+                save_checkpoint(connection, &room_id, &checkpoint)
+            })
+            .await?;
+        }
+
+        let _ = state.app.emit(
+            "matrix://backfill-progress",
+            BackfillProgress {
+                room_id: room_id.to_string(),
+                events_fetched: checkpoint.events_fetched,
+                oldest_timestamp: checkpoint.oldest_timestamp,
+                reached_start: checkpoint.reached_start,
+                finished: false,
+            },
+        );
+    }
+
+    info!("Backfilled {} events in {}", fetched, room_id);
+
+    let _ = state.app.emit(
+        "matrix://backfill-progress",
+        BackfillProgress {
+            room_id: room_id.to_string(),
+            events_fetched: checkpoint.events_fetched,
+            oldest_timestamp: checkpoint.oldest_timestamp,
+            reached_start: checkpoint.reached_start,
+            finished: true,
+        },
+    );
+
+    Ok(BackfillResult {
+        room_id: room_id.to_string(),
+        events_fetched: fetched,
+        total_events: checkpoint.events_fetched,
+        oldest_timestamp: checkpoint.oldest_timestamp,
+        reached_start: checkpoint.reached_start,
+    })
+}
+
+/// Fetches one page, waiting out the server's rate limit when it asks.
+async fn fetch_page(room: &Room, token: Option<&str>) -> Result<Messages, String> {
+    let mut retries = 0;
+
+    loop {
+        let mut options = MessagesOptions::backward().from(token);
+        options.limit = uint!(100);
+
+        match room.messages(options).await {
+            Ok(response) => return Ok(response),
+            Err(e) => {
+                let Some(delay) = rate_limit_delay(e.client_api_error_kind()) else {
+                    return Err(format!("Failed to fetch messages: {}", e));
+                };
+                if retries == MAX_RATE_LIMIT_RETRIES {
+                    return Err("The server kept rate-limiting the backfill".to_string());
+                }
+                retries += 1;
+
+                warn!("Rate limited while backfilling {}, waiting {:?}", room.room_id(), delay);
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Adds events fetched to fill a sync gap to the index of a room that was
+/// backfilled, so the index doesn't keep the hole. The settings that limit
+/// backfills apply: nothing of an encrypted room unless those are indexed,
+/// and nothing older than the retention window.
+pub(crate) async fn index_gap_events(state: &MatrixState, room: &Room, events: &[&TimelineEvent]) -> Result<(), String> {
+    let (retention_days, index_encrypted_rooms) = {
+        let settings = state.settings.read().await;
+        (settings.local_history_retention_days, settings.index_encrypted_rooms)
+    };
+    if !index_encrypted_rooms && room.latest_encryption_state().await.is_ok_and(|s| s.is_encrypted()) {
+        return Ok(());
+    }
+    let cutoff = retention_cutoff(retention_days);
+    let events: Vec<(TimelineEvent, u64)> = events
+        .iter()
+        .map(|event| ((*event).clone(), origin_server_ts(event)))
+        .filter(|(_, timestamp)| cutoff.is_none_or(|cutoff| *timestamp >= cutoff))
+        .collect();
+
+    let path = index_path(state).await?;
+    let room_id = room.room_id().to_owned();
+    tauri::async_runtime::spawn_blocking(move || {
+        if !path.exists() {
+            return Ok(());
+        }
+        let connection = open_index(&path)?;
+        if load_checkpoint(&connection, &room_id)?.events_fetched == 0 {
+            return Ok(());
+        }
+        for (event, timestamp) in &events {
+            store_event(&connection, &room_id, event, *timestamp)?;
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|e| format!("Failed to index messages: {}", e))?
+}
+
+/// The oldest timestamp kept under a retention of `retention_days`.
+fn retention_cutoff(retention_days: Option<u32>) -> Option<u64> {
+    let now: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+    retention_days.map(|days| now.saturating_sub(u64::from(days) * 24 * 60 * 60 * 1000))
+}
+
+async fn index_path(state: &MatrixState) -> Result<PathBuf, String> {
+    let user_id = state.user_id.read().await.clone().ok_or("Not logged in")?;
+    Ok(state.data_dir.join(sanitize_user_id(&user_id)).join(MESSAGE_INDEX_DB))
+}
+
+/// Runs `f` on the index at `path` off the async runtime, as rusqlite
+/// blocks.
+async fn with_index<T: Send + 'static>(
+    path: &Path,
+    f: impl FnOnce(&Connection) -> Result<T, String> + Send + 'static,
+) -> Result<T, String> {
+    let path = path.to_path_buf();
+    tauri::async_runtime::spawn_blocking(move || f(&open_index(&path)?))
+        .await
+        .map_err(|e| format!("Failed to access message index: {}", e))?
+}
+
+fn open_index(path: &Path) -> Result<Connection, String> {
+    let connection = Connection::open(path).map_err(|e| format!("Failed to open message index: {}", e))?;
+    connection
+        .execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                event_id TEXT PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                origin_server_ts INTEGER NOT NULL,
+                body TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS messages_room ON messages (room_id, origin_server_ts);
+            CREATE TABLE IF NOT EXISTS backfill (
+                room_id TEXT PRIMARY KEY,
+                token TEXT,
+                events_fetched INTEGER NOT NULL,
+                oldest_timestamp INTEGER,
+                reached_start INTEGER NOT NULL
+            );",
+        )
+        .map_err(|e| format!("Failed to open message index: {}", e))?;
+    Ok(connection)
+}
+
+/// Indexes the event when it is a message with a body; other events only
+/// count towards progress.
+fn store_event(
+    connection: &Connection,
+    room_id: &OwnedRoomId,
+    timeline_event: &TimelineEvent,
+    timestamp: u64,
+) -> Result<(), String> {
+    let raw = timeline_event.raw();
+    if raw.get_field::<String>("type").ok().flatten().as_deref() != Some("m.room.message") {
+        return Ok(());
+    }
+    let (Some(event_id), Some(sender)) = (
+        raw.get_field::<String>("event_id").ok().flatten(),
+        raw.get_field::<String>("sender").ok().flatten(),
+    ) else {
+        return Ok(());
+    };
+    let Some(body) = raw
+        .get_field::<serde_json::Value>("content")
+        .ok()
+        .flatten()
+        .and_then(|content| normalize_message_content(&content)?.get("body")?.as_str().map(str::to_string))
+        .filter(|body| !body.is_empty())
+    else {
+        return Ok(());
+    };
+
+    connection
+        .execute(
+            "INSERT OR REPLACE INTO messages (event_id, room_id, sender, origin_server_ts, body)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![event_id, room_id.as_str(), sender, timestamp as i64, body],
+        )
+        .map_err(|e| format!("Failed to index message: {}", e))?;
+    Ok(())
+}
+
+fn load_checkpoint(connection: &Connection, room_id: &OwnedRoomId) -> Result<Checkpoint, String> {
+    let checkpoint = connection
+        .query_row(
+            "SELECT token, events_fetched, oldest_timestamp, reached_start FROM backfill WHERE room_id = ?1",
+            params![room_id.as_str()],
+            |row| {
+                Ok(Checkpoint {
+                    token: row.get(0)?,
+                    events_fetched: row.get::<_, i64>(1)? as u64,
+                    oldest_timestamp: row.get::<_, Option<i64>>(2)?.map(|ts| ts as u64),
+                    reached_start: row.get(3)?,
+                })
+            },
+        )
+        .optional()
+        .map_err(|e| format!("Failed to read backfill progress: {}", e))?;
+
+    Ok(checkpoint.unwrap_or_default())
+}
+
+fn save_checkpoint(connection: &Connection, room_id: &OwnedRoomId, checkpoint: &Checkpoint) -> Result<(), String> {
+    connection
+        .execute(
+            "INSERT OR REPLACE INTO backfill (room_id, token, events_fetched, oldest_timestamp, reached_start)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                room_id.as_str(),
+                checkpoint.token,
+                checkpoint.events_fetched as i64,
+                checkpoint.oldest_timestamp.map(|ts| ts as i64),
+                checkpoint.reached_start,
+            ],
+        )
+        .map_err(|e| format!("Failed to save backfill progress: {}", e))?;
+    Ok(())
+}
+
+/// Deletes indexed messages sent before `cutoff` and every message of
+/// `room_ids`, then vacuums so the space is given back. Returns how many
+/// messages were deleted. Backfill checkpoints are kept, so a room isn't
+/// fetched all over again. Blocking; call it from `spawn_blocking`.
+pub(crate) fn prune_index(session_dir: &Path, cutoff: Option<u64>, room_ids: &[String]) -> Result<u64, String> {
+    let path = session_dir.join(MESSAGE_INDEX_DB);
+    if !path.exists() {
+        return Ok(0);
+    }
+    let connection = open_index(&path)?;
+    let prune_error = |e: rusqlite::Error| format!("Failed to prune message index: {}", e);
+
+    let mut removed = 0;
+    if let Some(cutoff) = cutoff {
+        removed += connection
+            .execute("DELETE FROM messages WHERE origin_server_ts < ?1", params![cutoff as i64])
+            .map_err(prune_error)?;
+    }
+    for room_id in room_ids {
+        removed += connection
+            .execute("DELETE FROM messages WHERE room_id = ?1", params![room_id])
+            .map_err(prune_error)?;
+    }
+    if removed > 0 {
+        connection.execute_batch("VACUUM").map_err(prune_error)?;
+    }
+    Ok(removed as u64)
+}
+
+/// Bytes the message index takes on disk.
+pub(crate) fn message_index_size(session_dir: &Path) -> u64 {
+    db_size(session_dir, MESSAGE_INDEX_DB)
+}
+
+/// Backfilled messages in `room_ids` whose body contains `query`, ignoring
+/// case. An account that never backfilled has no index and no results.
+pub(crate) fn search_index(
+    session_dir: &Path,
+    room_ids: &[String],
+    query: &str,
+) -> Result<Vec<IndexedMessage>, String> {
+    let path = session_dir.join(MESSAGE_INDEX_DB);
+    if !path.exists() || room_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let connection = open_index(&path)?;
+
+    let placeholders = vec!["?"; room_ids.len()].join(", ");
+    let sql = format!(
+        "SELECT event_id, room_id, sender, origin_server_ts, body FROM messages
+         WHERE room_id IN ({}) AND instr(lower(body), ?) > 0",
+        placeholders
+    );
+    let mut statement = connection
+        .prepare(&sql)
+        .map_err(|e| format!("Failed to search message index: {}", e))?;
+
+    let mut values: Vec<&dyn rusqlite::ToSql> = room_ids.iter().map(|id| id as &dyn rusqlite::ToSql).collect();
+    values.push(&query);
+
+    let rows = statement
+        .query_map(values.as_slice(), indexed_message)
+        .map_err(|e| format!("Failed to search message index: {}", e))?;
+
+    rows.collect::<Result<_, _>>()
+        .map_err(|e| format!("Failed to search message index: {}", e))
+}
+
+/// Every backfilled message of a room, oldest first.
+pub(crate) fn indexed_messages(session_dir: &Path, room_id: &str) -> Result<Vec<IndexedMessage>, String> {
+    let path = session_dir.join(MESSAGE_INDEX_DB);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let connection = open_index(&path)?;
+
+    let mut statement = connection
+        .prepare(
+            "SELECT event_id, room_id, sender, origin_server_ts, body FROM messages
+             WHERE room_id = ?1 ORDER BY origin_server_ts",
+        )
+        .map_err(|e| format!("Failed to read message index: {}", e))?;
+    let rows = statement
+        .query_map(params![room_id], indexed_message)
+        .map_err(|e| format!("Failed to read message index: {}", e))?;
+
+    rows.collect::<Result<_, _>>()
+        .map_err(|e| format!("Failed to read message index: {}", e))
+}
+
+fn indexed_message(row: &rusqlite::Row) -> rusqlite::Result<IndexedMessage> {
+    Ok(IndexedMessage {
+        event_id: row.get(0)?,
+        room_id: row.get(1)?,
+        sender: row.get(2)?,
+        timestamp: row.get::<_, i64>(3)? as u64,
+        body: row.get(4)?,
+    })
+}
+
+pub(crate) struct IndexedMessage {
+    pub event_id: String,
+    pub room_id: String,
+    pub sender: String,
+    pub timestamp: u64,
+    pub body: String,
+}
@@ -0,0 +1,461 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+use matrix_sdk::deserialized_responses::RawAnySyncOrStrippedState;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::api::client::membership::ban_user;
+use matrix_sdk::ruma::events::StateEventType;
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedUserId, RoomId, UserId};
+use matrix_sdk::{Client, Room, RoomMemberships, RoomState};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::{Emitter, State};
+use tracing::{info, warn};
+
+use crate::account_data::APP_NAMESPACE;
+use crate::outbound::{note_rate_limit, pace, paced_request_config, OutboundClass};
+use crate::settings::{change_settings, Settings};
+use crate::state::MatrixState;
+
+/// Rate limits waited out per ban before giving up on it.
+const MAX_BAN_RETRIES: u32 = 3;
+/// The spec's user rule type, and the one Mjolnir-era lists still use.
+const USER_RULE_TYPES: [&str; 2] = ["m.policy.rule.user", "org.matrix.mjolnir.rule.user"];
+
+fn ban_list_format() -> String {
+    format!("{}.ban_list", APP_NAMESPACE)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BanListEntry {
+    pub user_id: String,
+    pub reason: Option<String>,
+}
+
+/// The file `export_ban_list` writes. Imports only need `bans`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct BanListFile {
+    #[serde(default)]
+    format: String,
+    #[serde(default)]
+    room_id: String,
+    #[serde(default)]
+    exported_at: u64,
+    bans: Vec<BanListEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BanListExport {
+    pub path: String,
+    pub bans: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BanImportResult {
+    pub user_id: String,
+    /// `banned`, `would_ban` on a dry run, `already_banned`, `invalid_id`,
+    /// `permission_denied`, `rate_limited` or `failed`.
+    pub status: String,
+    pub error: Option<String>,
+}
+
+/// Payload of `matrix://ban-import-progress`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BanImportProgress {
+    pub room_id: String,
+    pub done: usize,
+    pub total: usize,
+}
+
+/// A user rule from a policy list linked to a room. Listed on the members
+/// it matches; nothing is enforced.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PolicyRule {
+    pub list_room_id: String,
+    pub list_name: Option<String>,
+    /// User ID glob, with `*` and `?`.
+    pub entity: String,
+    /// Usually `m.ban`.
+    pub recommendation: String,
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PolicyList {
+    pub room_id: String,
+    pub name: Option<String>,
+    /// Unset when we aren't in the policy room, so its rules can't be read.
+    pub joined: bool,
+    pub user_rules: usize,
+}
+
+/// Writes the room's banned users and the reasons given to `path` as JSON,
+/// for sharing with other communities.
+#[tauri::command]
+pub async fn export_ban_list(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    path: String,
+) -> Result<BanListExport, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let mut bans: Vec<BanListEntry> = room
+        .members(RoomMemberships::BAN)
+        .await
+        .map_err(|e| format!("Failed to load members: {}", e))?
+        .iter()
+        .map(|member| BanListEntry {
+            user_id: member.user_id().to_string(),
+            reason: member.event().reason().map(str::to_string),
+        })
+        .collect();
+    bans.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+
+    let file = BanListFile {
+        format: ban_list_format(),
+        room_id: room_id.to_string(),
+        exported_at: MilliSecondsSinceUnixEpoch::now().get().into(),
+        bans,
+    };
+    let contents = serde_json::to_string_pretty(&file).map_err(|e| e.to_string())?;
+    let path = PathBuf::from(path);
+    fs::write(&path, contents).map_err(|e| format!("Failed to write ban list: {}", e))?;
+
+    info!("Exported {} bans of {} to {:?}", file.bans.len(), room_id, path);
+    Ok(BanListExport {
+        path: path.to_string_lossy().to_string(),
+        bans: file.bans.len(),
+    })
+}
+
+/// Bans the users in a ban list file one at a time, paced by the outbound
+/// moderation limit, skipping ones already banned. With `dry_run` nothing is sent and
+/// the users that would be banned come back as `would_ban`. Progress is
+/// reported through `matrix://ban-import-progress`.
+#[tauri::command]
+pub async fn import_ban_list(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    path: String,
+    dry_run: bool,
+) -> Result<Vec<BanImportResult>, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let contents = fs::read_to_string(&path).map_err(|e| format!("Failed to read ban list: {}", e))?;
+    let file: BanListFile = serde_json::from_str(&contents).map_err(|e| format!("Not a ban list: {}", e))?;
+
+    let banned: HashSet<OwnedUserId> = room
+        .members(RoomMemberships::BAN)
+        .await
+        .map_err(|e| format!("Failed to load members: {}", e))?
+        .iter()
+        .map(|member| member.user_id().to_owned())
+        .collect();
+
+    let result = |user_id: &str, status: &str, error: Option<String>| BanImportResult {
+        user_id: user_id.to_string(),
+        status: status.to_string(),
+        error,
+    };
+
+    let mut seen = HashSet::new();
+    let mut results = Vec::new();
+    let mut pending = Vec::new();
+    for entry in file.bans {
+        let user_id = entry.user_id.trim().to_string();
+        if !seen.insert(user_id.clone()) {
+            continue;
+        }
+        match OwnedUserId::try_from(user_id.as_str()) {
+            Ok(parsed) if banned.contains(&parsed) => results.push(result(&user_id, "already_banned", None)),
+            Ok(parsed) => {
+                pending.push((results.len(), parsed, entry.reason));
+                results.push(result(&user_id, "would_ban", None));
+            }
+            Err(e) => results.push(result(&user_id, "invalid_id", Some(e.to_string()))),
+        }
+    }
+    if dry_run {
+        return Ok(results);
+    }
+
+    let total = pending.len();
+    for (done, (index, user_id, reason)) in pending.into_iter().enumerate() {
+        results[index] = match ban_with_retry(&state, &room, &user_id, reason.as_deref()).await {
+            Ok(()) => result(user_id.as_str(), "banned", None),
+            Err((status, error)) => result(user_id.as_str(), status, Some(error)),
+        };
+
+        let _ = state.app.emit(
+            "matrix://ban-import-progress",
+            BanImportProgress {
+                room_id: room_id.to_string(),
+                done: done + 1,
+                total,
+            },
+        );
+    }
+
+    let applied = results.iter().filter(|r| r.status == "banned").count();
+    info!("Banned {} of {} users from a ban list in {}", applied, results.len(), room_id);
+    Ok(results)
+}
+
+/// The policy lists linked to the room, with how many user rules each has.
+#[tauri::command]
+pub async fn get_policy_lists(state: State<'_, MatrixState>, room_id: String) -> Result<Vec<PolicyList>, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+
+    let mut lists = Vec::new();
+    for list_room_id in linked_lists(&*state.settings.read().await, &room_id) {
+        let list = match client.get_room(&list_room_id).filter(|room| room.state() == RoomState::Joined) {
+            Some(list_room) => PolicyList {
+                room_id: list_room_id.to_string(),
+                name: list_room.display_name().await.ok().map(|name| name.to_string()),
+                joined: true,
+                user_rules: user_rules(&list_room).await.len(),
+            },
+            None => PolicyList {
+                room_id: list_room_id.to_string(),
+                name: None,
+                joined: false,
+                user_rules: 0,
+            },
+        };
+        lists.push(list);
+    }
+    Ok(lists)
+}
+
+/// Links policy rooms to the room, replacing the previous links. Their user
+/// rules then show on the members they match.
+#[tauri::command]
+pub async fn set_policy_lists(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    policy_room_ids: Vec<String>,
+) -> Result<(), String> {
+    if state.client.read().await.is_none() {
+        return Err("Not logged in".to_string());
+    }
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let mut lists: Vec<String> = Vec::new();
+    for id in policy_room_ids {
+        let id: OwnedRoomId = id
+            .trim()
+            .parse()
+            .map_err(|e| format!("Invalid room ID: {}", e))?;
+        if !lists.contains(&id.to_string()) {
+            lists.push(id.to_string());
+        }
+    }
+
+    let count = lists.len();
+    change_settings(&state, |settings| {
+        if lists.is_empty() {
+            settings.policy_lists.remove(room_id.as_str());
+        } else {
+            settings.policy_lists.insert(room_id.to_string(), lists);
+        }
+    })
+    .await?;
+
+    info!("Linked {} policy lists to {}", count, room_id);
+    Ok(())
+}
+
+/// The user rules of every joined policy list linked to the room.
+pub(crate) async fn room_policy_rules(client: &Client, settings: &Settings, room_id: &RoomId) -> Vec<PolicyRule> {
+    let mut rules = Vec::new();
+    for list_room_id in linked_lists(settings, room_id) {
+        if let Some(list_room) = client.get_room(&list_room_id) {
+            rules.extend(user_rules(&list_room).await);
+        }
+    }
+    rules
+}
+
+/// The rules whose entity glob matches `user_id`.
+pub(crate) fn matching_rules(rules: &[PolicyRule], user_id: &UserId) -> Vec<PolicyRule> {
+    rules
+        .iter()
+        .filter(|rule| glob_matches(&rule.entity, user_id.as_str()))
+        .cloned()
+        .collect()
+}
+
+fn linked_lists(settings: &Settings, room_id: &RoomId) -> Vec<OwnedRoomId> {
+    settings
+        .policy_lists
+        .get(room_id.as_str())
+        .into_iter()
+        .flatten()
+        .filter_map(|id| id.parse().ok())
+        .collect()
+}
+
+async fn user_rules(list_room: &Room) -> Vec<PolicyRule> {
+    let list_name = list_room.display_name().await.ok().map(|name| name.to_string());
+    let mut rules = Vec::new();
+    for event_type in USER_RULE_TYPES {
+        let events = match list_room.get_state_events(StateEventType::from(event_type)).await {
+            Ok(events) => events,
+            Err(e) => {
+                warn!("Failed to load {} state in {}: {}", event_type, list_room.room_id(), e);
+                continue;
+            }
+        };
+        rules.extend(events.iter().filter_map(|raw| {
+            let (entity, recommendation, reason) = parse_rule(raw)?;
+            Some(PolicyRule {
+                list_room_id: list_room.room_id().to_string(),
+                list_name: list_name.clone(),
+                entity,
+                recommendation,
+                reason,
+            })
+        }));
+    }
+    rules
+}
+
+/// Entity, recommendation and reason of a rule. Rules are removed by
+/// emptying their content, which gives `None`.
+fn parse_rule(raw: &RawAnySyncOrStrippedState) -> Option<(String, String, Option<String>)> {
+    let event: Value = match raw {
+        RawAnySyncOrStrippedState::Sync(raw) => raw.deserialize_as_unchecked().ok()?,
+        RawAnySyncOrStrippedState::Stripped(raw) => raw.deserialize_as_unchecked().ok()?,
+    };
+    let content = event.get("content")?;
+    let entity = content.get("entity")?.as_str().filter(|e| !e.is_empty())?;
+    let recommendation = content.get("recommendation")?.as_str()?;
+    let reason = content
+        .get("reason")
+        .and_then(Value::as_str)
+        .filter(|r| !r.is_empty())
+        .map(str::to_string);
+    Some((entity.to_string(), recommendation.to_string(), reason))
+}
+
+/// Matches a policy entity glob, where `*` is any run of characters and
+/// `?` any one character.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Sends one ban, waiting out rate limits a few times before giving up.
+/// Errors come with the status to report.
+async fn ban_with_retry(
+    state: &MatrixState,
+    room: &Room,
+    user_id: &UserId,
+    reason: Option<&str>,
+) -> Result<(), (&'static str, String)> {
+    let mut retries = 0;
+
+    loop {
+        pace(state, OutboundClass::Moderation, format!("Ban {} from {}", user_id, room.room_id())).await;
+        let mut request = ban_user::v3::Request::new(room.room_id().to_owned(), user_id.to_owned());
+        request.reason = reason.map(str::to_owned);
+        let error = match room.client().send(request).with_request_config(paced_request_config()).await {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+
+        let kind = error.client_api_error_kind();
+        // The next `pace` waits the delay out.
+        if let Some(delay) = note_rate_limit(state, OutboundClass::Moderation, kind).await {
+            if retries == MAX_BAN_RETRIES {
+                return Err(("rate_limited", "The server kept rate-limiting bans".to_string()));
+            }
+            retries += 1;
+            warn!("Rate limited while banning {}, waiting {:?}", user_id, delay);
+            continue;
+        }
+
+        return Err(match kind {
+            Some(ErrorKind::Forbidden { .. }) => ("permission_denied", error.to_string()),
+            _ => ("failed", error.to_string()),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_user_ids() {
+        assert!(glob_matches("@spammer:example.org", "@spammer:example.org"));
+        assert!(glob_matches("*:evil.example", "@anyone:evil.example"));
+        assert!(glob_matches("@bot_??:*", "@bot_42:example.org"));
+        assert!(glob_matches("*spam*", "@a_spam_bot:example.org"));
+        assert!(!glob_matches("*:evil.example", "@anyone:evil.example.org"));
+        assert!(!glob_matches("@bot_??:*", "@bot_123:example.org"));
+        assert!(!glob_matches("@spammer:example.org", "@spammer2:example.org"));
+    }
+
+    #[test]
+    fn emptied_rules_are_ignored() {
+        let rule = |content: Value| {
+            let raw = serde_json::from_value(serde_json::json!({
+                "type": "m.policy.rule.user",
+                "state_key": "rule",
+                "sender": "@mod:example.org",
+                "event_id": "$rule",
+                "origin_server_ts": 0,
+                "content": content,
+            }))
+            .unwrap();
+            parse_rule(&RawAnySyncOrStrippedState::Sync(raw))
+        };
+        assert_eq!(
+            rule(serde_json::json!({"entity": "*:evil.example", "recommendation": "m.ban", "reason": "spam"})),
+            Some(("*:evil.example".to_string(), "m.ban".to_string(), Some("spam".to_string())))
+        );
+        assert_eq!(rule(serde_json::json!({})), None);
+    }
+}
@@ -0,0 +1,127 @@
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::{Client, RoomState};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::State;
+use tokio::sync::RwLock;
+use tokio::time::sleep;
+use tracing::warn;
+
+use crate::account_data::{get_global_json, set_global_json};
+use crate::rooms::{room_info, RoomInfo};
+use crate::state::MatrixState;
+
+/// Account data type Element uses for the room switcher's recent rooms.
+pub(crate) const BREADCRUMBS_EVENT_TYPE: &str = "im.vector.setting.breadcrumbs";
+const MAX_BREADCRUMBS: usize = 20;
+/// Visits are written to account data at most this often.
+const BREADCRUMB_WRITE_DELAY: Duration = Duration::from_secs(3);
+
+pub type BreadcrumbQueue = Arc<RwLock<PendingBreadcrumbs>>;
+
+/// Breadcrumbs recorded locally but not yet uploaded.
+#[derive(Default)]
+pub struct PendingBreadcrumbs {
+    pub rooms: Option<Vec<String>>,
+    pub flush_scheduled: bool,
+}
+
+/// Moves `room_id` to the front of the recent rooms and schedules an upload.
+/// Returns the updated list, most recent first.
+#[tauri::command]
+pub async fn record_room_visit(
+    state: State<'_, MatrixState>,
+    room_id: String,
+) -> Result<Vec<String>, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+
+    // One lock from reading the list to queueing the new one, so a visit
+    // recorded meanwhile isn't overwritten with a list that lacks it.
+    let mut pending = state.breadcrumbs.write().await;
+    let current = match pending.rooms.clone() {
+        Some(rooms) => rooms,
+        None => stored_breadcrumbs(&client).await?,
+    };
+    let mut rooms: Vec<String> = current
+        .into_iter()
+        .filter(|r| r.as_str() != room_id.as_str())
+        .collect();
+    rooms.insert(0, room_id.to_string());
+    rooms.truncate(MAX_BREADCRUMBS);
+    pending.rooms = Some(rooms.clone());
+
+    if !pending.flush_scheduled {
+        pending.flush_scheduled = true;
+        let queue = state.breadcrumbs.clone();
+        tauri::async_runtime::spawn(async move {
+            sleep(BREADCRUMB_WRITE_DELAY).await;
+
+            let rooms = {
+                let mut pending = queue.write().await;
+                pending.flush_scheduled = false;
+                pending.rooms.take()
+            };
+            let Some(rooms) = rooms else {
+                return;
+            };
+
+            if let Err(e) = set_global_json(
+                &client,
+                BREADCRUMBS_EVENT_TYPE,
+                &json!({ "recent_rooms": rooms }),
+            )
+            .await
+            {
+                warn!("Failed to save breadcrumbs: {}", e);
+            }
+        });
+    }
+
+    Ok(rooms)
+}
+
+/// Recently visited rooms, most recent first, skipping rooms we have left.
+#[tauri::command]
+pub async fn get_recent_rooms(state: State<'_, MatrixState>) -> Result<Vec<RoomInfo>, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let mut rooms = Vec::new();
+    for room_id in current_breadcrumbs(client, &state.breadcrumbs).await? {
+        let Ok(room_id) = OwnedRoomId::try_from(room_id) else {
+            continue;
+        };
+        if let Some(room) = client.get_room(&room_id) {
+            if room.state() == RoomState::Joined {
+                rooms.push(room_info(&room).await);
+            }
+        }
+    }
+
+    Ok(rooms)
+}
+
+/// The locally pending list if a write is queued, otherwise what sync last
+/// delivered, so changes from other clients show up once synced.
+async fn current_breadcrumbs(
+    client: &Client,
+    queue: &BreadcrumbQueue,
+) -> Result<Vec<String>, String> {
+    if let Some(rooms) = queue.read().await.rooms.clone() {
+        return Ok(rooms);
+    }
+    stored_breadcrumbs(client).await
+}
+
+/// The list as last written to account data.
+async fn stored_breadcrumbs(client: &Client) -> Result<Vec<String>, String> {
+    Ok(get_global_json(client, BREADCRUMBS_EVENT_TYPE)
+        .await?
+        .and_then(|content| serde_json::from_value(content.get("recent_rooms")?.clone()).ok())
+        .unwrap_or_default())
+}
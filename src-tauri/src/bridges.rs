@@ -0,0 +1,151 @@
+use matrix_sdk::deserialized_responses::RawAnySyncOrStrippedState;
+use matrix_sdk::ruma::events::StateEventType;
+use matrix_sdk::ruma::UserId;
+use matrix_sdk::Room;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::rooms::Message;
+
+/// MSC2346 state event, and the unstable type most bridges still send.
+const BRIDGE_EVENT_TYPES: [&str; 2] = ["m.bridge", "uk.half-shot.bridge"];
+
+/// A bridge into the room from another network, from its bridge state event.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BridgeInfo {
+    /// Protocol ID, such as `irc`, `telegram` or `whatsapp`.
+    pub protocol: String,
+    pub protocol_name: Option<String>,
+    /// Icon for the protocol.
+    pub avatar_url: Option<String>,
+    /// The network within the protocol, such as an IRC network.
+    pub network: Option<String>,
+    /// The bridge's bot, which the ghost users that stand in for remote
+    /// users are named after.
+    pub bot_user_id: Option<String>,
+}
+
+/// The bridges the room's state declares. A state key set under both event
+/// types counts once.
+pub(crate) async fn room_bridges(room: &Room) -> Vec<BridgeInfo> {
+    let mut seen = Vec::new();
+    let mut bridges = Vec::new();
+    for event_type in BRIDGE_EVENT_TYPES {
+        let events = match room.get_state_events(StateEventType::from(event_type)).await {
+            Ok(events) => events,
+            Err(e) => {
+                warn!("Failed to load {} state in {}: {}", event_type, room.room_id(), e);
+                continue;
+            }
+        };
+        for raw in events {
+            let Some((state_key, bridge)) = parse_bridge_event(&raw) else {
+                continue;
+            };
+            if !seen.contains(&state_key) {
+                seen.push(state_key);
+                bridges.push(bridge);
+            }
+        }
+    }
+    bridges
+}
+
+/// Sets `via_bridge` on messages sent by a bridge's ghost users.
+pub(crate) fn annotate_bridges(messages: &mut [Message], bridges: &[BridgeInfo]) {
+    for message in messages {
+        message.via_bridge = bridge_for_sender(bridges, &message.sender).map(|b| b.protocol.clone());
+    }
+}
+
+/// The bridge whose ghost-user namespace `sender` falls in. Appservice
+/// namespaces aren't visible to clients, so this goes by convention: ghosts
+/// live on the bot's server with a localpart starting with the protocol or
+/// the bot's own name, like `@telegram_123` next to `@telegrambot` or
+/// `@_discord_123` next to `@_discord_bot`.
+pub(crate) fn bridge_for_sender<'a>(bridges: &'a [BridgeInfo], sender: &str) -> Option<&'a BridgeInfo> {
+    let sender = <&UserId>::try_from(sender).ok()?;
+    bridges.iter().find(|bridge| {
+        let Some(bot) = bridge.bot_user_id.as_deref().and_then(|id| <&UserId>::try_from(id).ok()) else {
+            return false;
+        };
+        if sender == bot {
+            return false;
+        }
+        if sender.server_name() != bot.server_name() {
+            return false;
+        }
+
+        let localpart = sender.localpart().to_lowercase();
+        let localpart = localpart.trim_start_matches('_');
+        let bot_name = bot.localpart().to_lowercase();
+        let bot_stem = bot_name
+            .trim_start_matches('_')
+            .trim_end_matches("bot")
+            .trim_end_matches(['_', '-']);
+        [bridge.protocol.to_lowercase(), bot_stem.to_string()]
+            .into_iter()
+            .filter(|prefix| !prefix.is_empty())
+            .any(|prefix| {
+                localpart
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.starts_with(['_', '-', '=']))
+            })
+    })
+}
+
+/// State key and bridge info from a bridge state event. Bridges disagree on
+/// the details: `protocol` and `network` may be objects or bare strings, the
+/// bot may be `bridgebot` or `bridge_bot` or left out in favour of the event
+/// sender, and empty content means the bridge was removed.
+fn parse_bridge_event(raw: &RawAnySyncOrStrippedState) -> Option<(String, BridgeInfo)> {
+    let event: Value = match raw {
+        RawAnySyncOrStrippedState::Sync(raw) => raw.deserialize_as_unchecked().ok()?,
+        RawAnySyncOrStrippedState::Stripped(raw) => raw.deserialize_as_unchecked().ok()?,
+    };
+    let state_key = event.get("state_key")?.as_str()?.to_string();
+    let content = event.get("content")?;
+    let sender = event.get("sender").and_then(Value::as_str);
+
+    let (protocol, protocol_name) = id_and_name(content.get("protocol")?)?;
+    let network = content.get("network").and_then(id_and_name).map(|(id, name)| name.unwrap_or(id));
+    let bot_user_id = ["bridgebot", "bridge_bot"]
+        .iter()
+        .find_map(|key| content.get(*key).and_then(Value::as_str))
+        .or(sender)
+        .map(str::to_string);
+    let avatar_url = content
+        .get("protocol")
+        .and_then(|p| p.get("avatar_url"))
+        .and_then(Value::as_str)
+        .map(str::to_string);
+
+    Some((
+        state_key,
+        BridgeInfo {
+            protocol,
+            protocol_name,
+            avatar_url,
+            network,
+            bot_user_id,
+        },
+    ))
+}
+
+/// `id` and `displayname` of a protocol, network or channel section, which
+/// some bridges send as just the ID.
+fn id_and_name(section: &Value) -> Option<(String, Option<String>)> {
+    let non_empty = |value: Option<&Value>| {
+        value.and_then(Value::as_str).filter(|s| !s.is_empty()).map(str::to_string)
+    };
+    match section {
+        Value::String(id) if !id.is_empty() => Some((id.clone(), None)),
+        Value::Object(_) => {
+            let name = non_empty(section.get("displayname"));
+            let id = non_empty(section.get("id")).or_else(|| name.as_ref().map(|n| n.to_lowercase()))?;
+            Some((id, name))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,139 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::State;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::auth::sanitize_user_id;
+use crate::state::MatrixState;
+
+const CLIENT_CONFIG_FILE: &str = "client-config.json";
+/// How old the stored well-known may get before it is fetched again.
+const CLIENT_CONFIG_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+const WELL_KNOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Defaults the homeserver publishes in `/.well-known/matrix/client`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClientConfig {
+    pub homeserver: String,
+    /// `m.identity_server`, used for email invites when the account hasn't
+    /// picked an identity server itself.
+    pub identity_server: Option<String>,
+    /// `im.vector.riot.jitsi`'s preferred domain for video conferences.
+    pub jitsi_domain: Option<String>,
+    /// When the well-known was fetched, in milliseconds since the epoch.
+    pub fetched_at: u64,
+}
+
+#[tauri::command]
+pub async fn get_client_config(state: State<'_, MatrixState>) -> Result<Option<ClientConfig>, String> {
+    if state.client.read().await.is_none() {
+        return Err("Not logged in".to_string());
+    }
+
+    Ok(state.client_config.read().await.clone())
+}
+
+/// Loads the account's stored well-known config into `config` and
+/// fetches a new one in the background when it is missing, older than a day,
+/// or `force` is set (right after login). A malformed or unreachable
+/// well-known keeps the previous values.
+pub(crate) fn refresh_client_config(
+    data_dir: &Path,
+    config: &Arc<RwLock<Option<ClientConfig>>>,
+    client: &Client,
+    force: bool,
+) {
+    let Some(user_id) = client.user_id().map(|u| u.to_owned()) else {
+        return;
+    };
+    let path = data_dir
+        .join(sanitize_user_id(user_id.as_str()))
+        .join(CLIENT_CONFIG_FILE);
+    let config = config.clone();
+
+    tauri::async_runtime::spawn(async move {
+        let stored = load_client_config(&path);
+        let fresh = stored
+            .as_ref()
+            .is_some_and(|c| now_ms().saturating_sub(c.fetched_at) < CLIENT_CONFIG_MAX_AGE.as_millis() as u64);
+        *config.write().await = stored;
+
+        if fresh && !force {
+            return;
+        }
+
+        match fetch_client_config(user_id.server_name().as_str()).await {
+            Ok(fetched) => {
+                info!("Fetched client config for {}", user_id.server_name());
+                if let Err(e) = save_client_config(&path, &fetched) {
+                    warn!("{}", e);
+                }
+                *config.write().await = Some(fetched);
+            }
+            Err(e) => warn!("Keeping previous client config: {}", e),
+        }
+    });
+}
+
+async fn fetch_client_config(server_name: &str) -> Result<ClientConfig, String> {
+    let http = matrix_sdk::reqwest::Client::builder()
+        .timeout(WELL_KNOWN_TIMEOUT)
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+
+    let url = format!("https://{}/.well-known/matrix/client", server_name);
+    let text = http
+        .get(&url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Failed to fetch {}: {}", url, e))?
+        .text()
+        .await
+        .map_err(|e| format!("Failed to fetch {}: {}", url, e))?;
+    let body: Value =
+        serde_json::from_str(&text).map_err(|e| format!("Malformed well-known: {}", e))?;
+
+    let string_field = |section: &str, field: &str| -> Option<String> {
+        body.get(section)?
+            .get(field)?
+            .as_str()
+            .map(|s| s.trim().trim_end_matches('/').to_string())
+            .filter(|s| !s.is_empty())
+    };
+
+    let homeserver = string_field("m.homeserver", "base_url")
+        .ok_or("Malformed well-known: missing m.homeserver.base_url")?;
+
+    Ok(ClientConfig {
+        homeserver,
+        identity_server: string_field("m.identity_server", "base_url"),
+        jitsi_domain: string_field("im.vector.riot.jitsi", "preferredDomain"),
+        fetched_at: now_ms(),
+    })
+}
+
+fn load_client_config(path: &Path) -> Option<ClientConfig> {
+    let json = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&json).ok()
+}
+
+fn save_client_config(path: &Path, config: &ClientConfig) -> Result<(), String> {
+    let json = serde_json::to_string(config)
+        .map_err(|e| format!("Failed to serialize client config: {}", e))?;
+    fs::write(path, json).map_err(|e| format!("Failed to save client config: {}", e))
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
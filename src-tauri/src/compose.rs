@@ -0,0 +1,281 @@
+use matrix_sdk::deserialized_responses::TimelineEvent;
+use matrix_sdk::ruma::events::relation::Thread;
+use matrix_sdk::ruma::events::room::message::{
+    AddMentions, ForwardThread, Relation, ReplacementMetadata, ReplyMetadata,
+};
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedUserId};
+use matrix_sdk::Room;
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tracing::info;
+
+use crate::messages::{parse_transaction_id, send_text};
+use crate::profiles::ProfileResolver;
+use crate::rooms::{limit_body_sizes, timeline_message, Message};
+use crate::state::MatrixState;
+use crate::threads::{latest_in_thread, thread_root};
+use crate::view_state::schedule_flush;
+
+/// What the message being written will be, besides a new message.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ComposeContext {
+    Reply { event_id: String },
+    Edit { event_id: String },
+    Thread { root_event_id: String },
+}
+
+/// The unsent message of a room, saved with the view state.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Draft {
+    pub body: String,
+    pub context: Option<ComposeContext>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DraftView {
+    pub body: String,
+    pub context: Option<ComposeContext>,
+    /// The event replied to, edited, or starting the thread, for the preview
+    /// above the composer; `None` without a context or when it can't be
+    /// loaded.
+    pub quoted: Option<Message>,
+}
+
+/// The room's draft with a preview of what it relates to; empty when there is
+/// none.
+#[tauri::command]
+pub async fn get_draft(state: State<'_, MatrixState>, room_id: String) -> Result<DraftView, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let draft = state.view_states.read().await.drafts.get(room_id.as_str()).cloned().unwrap_or_default();
+    Ok(draft_view(&state, &room, draft).await)
+}
+
+/// Saves what the user typed so far. Called while typing; writes to disk are
+/// batched.
+#[tauri::command]
+pub async fn set_draft(state: State<'_, MatrixState>, room_id: String, body: String) -> Result<(), String> {
+    if state.client.read().await.is_none() {
+        return Err("Not logged in".to_string());
+    }
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+
+    update_draft(&state, &room_id, |draft| draft.body = body).await;
+    Ok(())
+}
+
+/// Tells the room whether the user is typing, unless typing notifications
+/// are off in the settings. Returns whether a notice was sent.
+#[tauri::command]
+pub async fn set_typing(state: State<'_, MatrixState>, room_id: String, typing: bool) -> Result<bool, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    if !state.settings.read().await.send_typing_notifications {
+        return Ok(false);
+    }
+    room.typing_notice(typing)
+        .await
+        .map_err(|e| format!("Failed to send typing notice: {}", e))?;
+    Ok(true)
+}
+
+/// Makes the room's draft a reply, edit or thread message, or a plain message
+/// again when `context` is `None`. Starting an edit of an empty draft fills in
+/// the text being edited.
+#[tauri::command]
+pub async fn set_compose_context(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    context: Option<ComposeContext>,
+) -> Result<DraftView, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let mut edited_body = None;
+    if let Some(context) = &context {
+        let target = load_target(&room, &context_event_id(context)?).await?;
+        if let ComposeContext::Edit { .. } = context {
+            let sender = target.raw().get_field::<OwnedUserId>("sender").ok().flatten();
+            if sender.as_deref() != client.user_id() {
+                return Err("You can only edit your own messages".to_string());
+            }
+            edited_body = target.raw().get_field::<serde_json::Value>("content").ok().flatten().and_then(|content| {
+                let content = content.get("m.new_content").unwrap_or(&content);
+                content.get("body")?.as_str().map(str::to_string)
+            });
+        }
+    }
+
+    let draft = update_draft(&state, &room_id, |draft| {
+        if draft.body.trim().is_empty() {
+            if let Some(body) = edited_body {
+                draft.body = body;
+            }
+        }
+        draft.context = context;
+    })
+    .await;
+    Ok(draft_view(&state, &room, draft).await)
+}
+
+/// Sends `body` as the room's draft: a reply, edit or thread message as its
+/// context says, or a plain message. The draft is cleared once it is sent.
+/// Retries should pass the same `transaction_id`; see `send_message`.
+#[tauri::command]
+pub async fn send_composed(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    body: String,
+    expand_emoji_shortcodes: Option<bool>,
+    transaction_id: Option<String>,
+) -> Result<String, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let context = state
+        .view_states
+        .read()
+        .await
+        .drafts
+        .get(room_id.as_str())
+        .and_then(|draft| draft.context.clone());
+
+    let txn_id = transaction_id.map(parse_transaction_id).transpose()?;
+    let event_id = match &context {
+        None => send_text(&state, client, &room, &body, expand_emoji_shortcodes, txn_id, |content| content).await?,
+        Some(ComposeContext::Reply { event_id }) => {
+            let event_id: OwnedEventId = event_id
+                .parse()
+                .map_err(|e| format!("Invalid event ID: {}", e))?;
+            let target = load_target(&room, &event_id).await?;
+            let sender: OwnedUserId = target
+                .raw()
+                .get_field("sender")
+                .ok()
+                .flatten()
+                .ok_or("The message being replied to has no sender")?;
+            // Replies to a thread message stay in the thread.
+            let thread = thread_root(target.raw()).map(|root| Thread::plain(root, event_id.clone()));
+            send_text(&state, client, &room, &body, expand_emoji_shortcodes, txn_id, |content| {
+                content.make_reply_to(
+                    ReplyMetadata::new(&event_id, &sender, thread.as_ref()),
+                    ForwardThread::Yes,
+                    AddMentions::Yes,
+                )
+            })
+            .await?
+        }
+        Some(ComposeContext::Edit { event_id }) => {
+            let event_id: OwnedEventId = event_id
+                .parse()
+                .map_err(|e| format!("Invalid event ID: {}", e))?;
+            send_text(&state, client, &room, &body, expand_emoji_shortcodes, txn_id, |content| {
+                content.make_replacement(ReplacementMetadata::new(event_id, None))
+            })
+            .await?
+        }
+        Some(ComposeContext::Thread { root_event_id }) => {
+            let root: OwnedEventId = root_event_id
+                .parse()
+                .map_err(|e| format!("Invalid event ID: {}", e))?;
+            // Points clients without threads at the newest reply.
+            let latest = latest_in_thread(&room, &root).await?;
+            send_text(&state, client, &room, &body, expand_emoji_shortcodes, txn_id, |mut content| {
+                content.relates_to = Some(Relation::Thread(Thread::plain(root, latest)));
+                content
+            })
+            .await?
+        }
+    };
+
+    if state.view_states.write().await.drafts.remove(room_id.as_str()).is_some() {
+        schedule_flush(&state).await;
+    }
+    if let Some(context) = context {
+        info!("Sent {:?} in {}", context, room_id);
+    }
+    Ok(event_id.to_string())
+}
+
+/// Applies `change` to the room's draft, dropping it once it is empty, and
+/// returns the result.
+async fn update_draft(state: &MatrixState, room_id: &OwnedRoomId, change: impl FnOnce(&mut Draft)) -> Draft {
+    let draft = {
+        let mut views = state.view_states.write().await;
+        let mut draft = views.drafts.remove(room_id.as_str()).unwrap_or_default();
+        change(&mut draft);
+        if !draft.body.is_empty() || draft.context.is_some() {
+            views.drafts.insert(room_id.to_string(), draft.clone());
+        }
+        draft
+    };
+    schedule_flush(state).await;
+    draft
+}
+
+async fn draft_view(state: &MatrixState, room: &Room, draft: Draft) -> DraftView {
+    let quoted = match &draft.context {
+        Some(context) => match context_event_id(context) {
+            Ok(event_id) => quoted_message(state, room, &event_id).await,
+            Err(_) => None,
+        },
+        None => None,
+    };
+    DraftView {
+        body: draft.body,
+        context: draft.context,
+        quoted,
+    }
+}
+
+fn context_event_id(context: &ComposeContext) -> Result<OwnedEventId, String> {
+    let event_id = match context {
+        ComposeContext::Reply { event_id } | ComposeContext::Edit { event_id } => event_id,
+        ComposeContext::Thread { root_event_id } => root_event_id,
+    };
+    event_id.parse().map_err(|e| format!("Invalid event ID: {}", e))
+}
+
+async fn load_target(room: &Room, event_id: &EventId) -> Result<TimelineEvent, String> {
+    room.load_or_fetch_event(event_id, None)
+        .await
+        .map_err(|e| format!("Failed to load event {}: {}", event_id, e))
+}
+
+/// The event a draft relates to, as the timeline would show it.
+async fn quoted_message(state: &MatrixState, room: &Room, event_id: &EventId) -> Option<Message> {
+    let event = room.load_or_fetch_event(event_id, None).await.ok()?;
+    let mut profiles = ProfileResolver::new(room, &[]).await;
+    profiles.observe(event.raw());
+
+    let message = {
+        let mut sent_plaintexts = state.sent_plaintext.write().await;
+        timeline_message(&event, &profiles, Some(room.own_user_id()), &mut sent_plaintexts)?
+    };
+    let mut messages = vec![message];
+    limit_body_sizes(&mut messages, state.settings.read().await.message_body_limit);
+    messages.pop()
+}
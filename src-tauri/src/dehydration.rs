@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+use tauri::State;
+
+use crate::state::MatrixState;
+
+/// Whether the account has a dehydrated device (MSC3814) that receives room
+/// keys while none of the user's sessions are online. This client can only
+/// report one; creating and rehydrating them needs the SDK's crypto machine,
+/// which it doesn't expose.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DehydrationStatus {
+    /// Set when another client, e.g. Element, has set one up.
+    pub exists: bool,
+    pub device_id: Option<String>,
+}
+
+/// Reports whether the account has a dehydrated device, going by our own
+/// published device keys.
+#[tauri::command]
+pub async fn get_dehydration_status(state: State<'_, MatrixState>) -> Result<DehydrationStatus, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let user_id = client.user_id().ok_or("Not logged in")?;
+    let devices = client
+        .encryption()
+        .get_user_devices(user_id)
+        .await
+        .map_err(|e| format!("Failed to get devices: {}", e))?;
+    let dehydrated = devices.devices().find(|device| device.is_dehydrated());
+
+    Ok(DehydrationStatus {
+        exists: dehydrated.is_some(),
+        device_id: dehydrated.map(|device| device.device_id().to_string()),
+    })
+}
@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use matrix_sdk::reqwest::Method;
+use matrix_sdk::ruma::api::client::error::{ErrorKind, RetryAfter};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::State;
+use tracing::{info, warn};
+
+use crate::errors::{rate_limit_delay, CommandError};
+use crate::state::MatrixState;
+
+/// Rate limits waited out per request before handing back the 429.
+const MAX_RAW_RETRIES: u32 = 3;
+/// Response headers passed back; the rest are transport noise.
+const HEADERS_OF_INTEREST: [&str; 6] = [
+    "content-type",
+    "content-length",
+    "cache-control",
+    "retry-after",
+    "server",
+    "date",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RawApiResponse {
+    pub status: u16,
+    pub headers: BTreeMap<String, String>,
+    /// The JSON response, a string when the server didn't send JSON, or
+    /// `null` when it sent nothing.
+    pub body: Value,
+}
+
+/// Sends an authenticated request to the homeserver for the developer
+/// console. Only `/_matrix/` paths on the homeserver are allowed, and only
+/// with `developer_mode` on in the settings. Error responses are returned
+/// like any other, after waiting out rate limits a few times.
+///
+/// The body may hold passwords or keys, so it is never logged.
+#[tauri::command]
+pub async fn raw_api_request(
+    state: State<'_, MatrixState>,
+    method: String,
+    path: String,
+    body_json: Option<String>,
+) -> Result<RawApiResponse, String> {
+    if !state.settings.read().await.developer_mode {
+        return Err(CommandError::new(
+            "DEVELOPER_MODE_DISABLED",
+            "Turn on developer mode in the settings to send raw requests",
+        )
+        .into());
+    }
+
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+    let access_token = client.access_token().ok_or("Not logged in")?;
+
+    let method = Method::from_bytes(method.trim().to_uppercase().as_bytes())
+        .map_err(|_| format!("Invalid HTTP method: {}", method))?;
+    let homeserver = client.homeserver();
+    let url = homeserver
+        .join(path.trim())
+        .map_err(|e| format!("Invalid path: {}", e))?;
+    // Checked after joining, so `..` segments and absolute URLs can't leave
+    // the homeserver's client-server API.
+    if !path.trim().starts_with("/_matrix/")
+        || !url.path().starts_with("/_matrix/")
+        || url.origin() != homeserver.origin()
+    {
+        return Err("Only /_matrix/ paths on your homeserver can be requested".to_string());
+    }
+    let body = match body_json.filter(|body| !body.trim().is_empty()) {
+        Some(body) => {
+            serde_json::from_str::<Value>(&body).map_err(|e| format!("The body is not valid JSON: {}", e))?;
+            Some(body)
+        }
+        None => None,
+    };
+
+    let mut retries = 0;
+    loop {
+        let mut request = client
+            .http_client()
+            .request(method.clone(), url.clone())
+            .bearer_auth(&access_token);
+        if let Some(body) = &body {
+            request = request
+                .header("Content-Type", "application/json")
+                .body(body.clone());
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("Failed to reach homeserver: {}", e))?;
+
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .filter(|(name, _)| HEADERS_OF_INTEREST.contains(&name.as_str()))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let text = response.text().await.unwrap_or_default();
+        let body = match text.trim() {
+            "" => Value::Null,
+            trimmed => serde_json::from_str(trimmed).unwrap_or(Value::String(text)),
+        };
+
+        if status == 429 && retries < MAX_RAW_RETRIES {
+            let retry_after = body
+                .get("retry_after_ms")
+                .and_then(Value::as_u64)
+                .map(|ms| RetryAfter::Delay(Duration::from_millis(ms)));
+            if let Some(delay) = rate_limit_delay(Some(&ErrorKind::LimitExceeded { retry_after })) {
+                retries += 1;
+                warn!("Rate limited on raw {} {}, waiting {:?}", method, url.path(), delay);
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+        }
+
+        // The path only; query strings can carry tokens too.
+        info!("Raw {} {} returned {}", method, url.path(), status);
+        return Ok(RawApiResponse { status, headers, body });
+    }
+}
@@ -0,0 +1,297 @@
+use matrix_sdk::encryption::identities::Device;
+use matrix_sdk::encryption::LocalTrust;
+use matrix_sdk::ruma::{OwnedDeviceId, OwnedUserId};
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tauri::State;
+use tracing::info;
+
+use crate::errors::CommandError;
+use crate::state::MatrixState;
+
+/// How far a device is trusted, from the device manager's point of view.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeviceTrust {
+    pub user_id: String,
+    pub device_id: String,
+    pub display_name: Option<String>,
+    /// `cross_signed` when the owner signed it, `locally_trusted` when we
+    /// verified it ourselves, `unverified`, `blacklisted`, `unknown` when the
+    /// device never uploaded keys, or `deleted` when it was removed but is
+    /// still referenced.
+    pub status: String,
+    pub cross_signed_by_owner: bool,
+    pub locally_trusted: bool,
+    pub blacklisted: bool,
+    pub deleted: bool,
+    /// The device's Ed25519 key in groups of four characters, for comparing
+    /// out of band.
+    pub fingerprint: Option<String>,
+}
+
+/// One of our own sessions, as listed by the homeserver.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OwnDevice {
+    pub device_id: String,
+    pub display_name: Option<String>,
+    pub last_seen_ip: Option<String>,
+    pub last_seen_ts: Option<u64>,
+    /// Whether this is the session the app is logged in with.
+    pub is_current: bool,
+    pub trust: DeviceTrust,
+}
+
+/// Lists our own devices, the current one first.
+#[tauri::command]
+pub async fn get_devices(state: State<'_, MatrixState>) -> Result<Vec<OwnDevice>, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    own_devices(client).await
+}
+
+pub(crate) async fn own_devices(client: &Client) -> Result<Vec<OwnDevice>, String> {
+    let user_id = client.user_id().ok_or("Not logged in")?.to_owned();
+    let current = client.device_id().map(|id| id.to_owned());
+
+    let response = client
+        .devices()
+        .await
+        .map_err(|e| format!("Failed to get devices: {}", e))?;
+    let own_devices = client
+        .encryption()
+        .get_user_devices(&user_id)
+        .await
+        .map_err(|e| format!("Failed to get devices: {}", e))?;
+
+    let mut devices: Vec<OwnDevice> = response
+        .devices
+        .into_iter()
+        .map(|device| {
+            let crypto_device = own_devices.get(&device.device_id);
+            OwnDevice {
+                device_id: device.device_id.to_string(),
+                display_name: device.display_name,
+                last_seen_ip: device.last_seen_ip,
+                last_seen_ts: device.last_seen_ts.map(|ts| ts.get().into()),
+                is_current: current.as_ref() == Some(&device.device_id),
+                trust: device_trust(&user_id, &device.device_id, crypto_device.as_ref()),
+            }
+        })
+        .collect();
+    devices.sort_by_key(|device| (!device.is_current, std::cmp::Reverse(device.last_seen_ts)));
+
+    Ok(devices)
+}
+
+/// Changes the display name other clients show for this session.
+#[tauri::command]
+pub async fn rename_current_device(state: State<'_, MatrixState>, name: String) -> Result<(), String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Device name cannot be empty".to_string());
+    }
+    let device_id = client.device_id().ok_or("Not logged in")?;
+
+    client
+        .rename_device(device_id, name)
+        .await
+        .map_err(|e| format!("Failed to rename device: {}", e))?;
+
+    info!("Renamed device {} to {:?}", device_id, name);
+
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_device_trust(
+    state: State<'_, MatrixState>,
+    user_id: String,
+    device_id: String,
+) -> Result<DeviceTrust, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let (user_id, device_id) = parse_device(user_id, device_id)?;
+    let device = find_device(client, &user_id, &device_id).await?;
+
+    Ok(device_trust(&user_id, &device_id, device.as_ref()))
+}
+
+/// Marks a device as verified after the user compared its fingerprint by
+/// hand, as an alternative to emoji verification. The fingerprint must match
+/// the device's Ed25519 key; spaces are ignored.
+#[tauri::command]
+pub async fn manually_verify_device(
+    state: State<'_, MatrixState>,
+    user_id: String,
+    device_id: String,
+    fingerprint: String,
+) -> Result<DeviceTrust, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let (user_id, device_id) = parse_device(user_id, device_id)?;
+    let device = find_device(client, &user_id, &device_id)
+        .await?
+        .ok_or("This device has not uploaded any keys to verify")?;
+    if device.is_deleted() {
+        return Err("This device has been deleted".to_string());
+    }
+
+    let expected = device.ed25519_key().ok_or("This device has no Ed25519 key")?.to_base64();
+    let supplied: String = fingerprint.chars().filter(|c| !c.is_whitespace()).collect();
+    if supplied != expected {
+        return Err(CommandError::new(
+            "FINGERPRINT_MISMATCH",
+            "The fingerprint does not match this device. Do not trust it.",
+        )
+        .with_details(json!({ "expected": format_fingerprint(&expected) }))
+        .into());
+    }
+
+    device
+        .set_local_trust(LocalTrust::Verified)
+        .await
+        .map_err(|e| format!("Failed to mark device as verified: {}", e))?;
+
+    info!("Manually verified device {} of {}", device_id, user_id);
+
+    let device = find_device(client, &user_id, &device_id).await?;
+    Ok(device_trust(&user_id, &device_id, device.as_ref()))
+}
+
+/// Blacklists a device so room keys are withheld from it: it can no longer
+/// read what we send, while everyone else in the room still can. The SDK
+/// rotates a room's key the next time it would have been shared with the
+/// device, so it can't read new messages under the old key either.
+#[tauri::command]
+pub async fn block_device(
+    state: State<'_, MatrixState>,
+    user_id: String,
+    device_id: String,
+) -> Result<DeviceTrust, String> {
+    set_blocked(&state, user_id, device_id, true).await
+}
+
+/// Undoes `block_device`. The device goes back to unverified, even if it
+/// was verified by hand before being blocked.
+#[tauri::command]
+pub async fn unblock_device(
+    state: State<'_, MatrixState>,
+    user_id: String,
+    device_id: String,
+) -> Result<DeviceTrust, String> {
+    set_blocked(&state, user_id, device_id, false).await
+}
+
+async fn set_blocked(
+    state: &MatrixState,
+    user_id: String,
+    device_id: String,
+    blocked: bool,
+) -> Result<DeviceTrust, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let (user_id, device_id) = parse_device(user_id, device_id)?;
+    if client.user_id() == Some(&user_id) && client.device_id() == Some(&device_id) {
+        return Err("This device can't block itself".to_string());
+    }
+    let device = find_device(client, &user_id, &device_id)
+        .await?
+        .ok_or("This device has not uploaded any keys")?;
+    if device.is_blacklisted() == blocked {
+        return Ok(device_trust(&user_id, &device_id, Some(&device)));
+    }
+
+    let trust = if blocked { LocalTrust::BlackListed } else { LocalTrust::Unset };
+    device
+        .set_local_trust(trust)
+        .await
+        .map_err(|e| format!("Failed to update device trust: {}", e))?;
+
+    info!("{} device {} of {}", if blocked { "Blocked" } else { "Unblocked" }, device_id, user_id);
+
+    let device = find_device(client, &user_id, &device_id).await?;
+    Ok(device_trust(&user_id, &device_id, device.as_ref()))
+}
+
+pub(crate) fn device_trust(
+    user_id: &OwnedUserId,
+    device_id: &OwnedDeviceId,
+    device: Option<&Device>,
+) -> DeviceTrust {
+    let Some(device) = device else {
+        return DeviceTrust {
+            user_id: user_id.to_string(),
+            device_id: device_id.to_string(),
+            display_name: None,
+            status: "unknown".to_string(),
+            cross_signed_by_owner: false,
+            locally_trusted: false,
+            blacklisted: false,
+            deleted: false,
+            fingerprint: None,
+        };
+    };
+
+    let cross_signed_by_owner = device.is_cross_signed_by_owner();
+    let locally_trusted = device.is_locally_trusted();
+    let status = if device.is_deleted() {
+        "deleted"
+    } else if device.is_blacklisted() {
+        "blacklisted"
+    } else if cross_signed_by_owner {
+        "cross_signed"
+    } else if locally_trusted {
+        "locally_trusted"
+    } else {
+        "unverified"
+    };
+
+    DeviceTrust {
+        user_id: user_id.to_string(),
+        device_id: device_id.to_string(),
+        display_name: device.display_name().map(str::to_string),
+        status: status.to_string(),
+        cross_signed_by_owner,
+        locally_trusted,
+        blacklisted: device.is_blacklisted(),
+        deleted: device.is_deleted(),
+        fingerprint: device.ed25519_key().map(|k| format_fingerprint(&k.to_base64())),
+    }
+}
+
+/// Looks the device up after any pending key query for its owner finishes.
+pub(crate) async fn find_device(
+    client: &Client,
+    user_id: &OwnedUserId,
+    device_id: &OwnedDeviceId,
+) -> Result<Option<Device>, String> {
+    let devices = client
+        .encryption()
+        .get_user_devices(user_id)
+        .await
+        .map_err(|e| format!("Failed to get devices: {}", e))?;
+
+    Ok(devices.get(device_id))
+}
+
+fn parse_device(user_id: String, device_id: String) -> Result<(OwnedUserId, OwnedDeviceId), String> {
+    let user_id = OwnedUserId::try_from(user_id).map_err(|e| format!("Invalid user ID: {}", e))?;
+    Ok((user_id, device_id.into()))
+}
+
+fn format_fingerprint(key: &str) -> String {
+    key.chars()
+        .collect::<Vec<_>>()
+        .chunks(4)
+        .map(|chunk| chunk.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
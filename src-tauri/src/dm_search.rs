@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use matrix_sdk::room::MessagesOptions;
+use matrix_sdk::ruma::api::client::search::search_events::v3::{
+    Categories, Criteria, Request as SearchRequest,
+};
+use matrix_sdk::ruma::events::AnySyncTimelineEvent;
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{uint, OwnedRoomId, OwnedUserId, UInt, UserId};
+use matrix_sdk::{Client, Room};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tracing::{debug, warn};
+
+use crate::auth::sanitize_user_id;
+use crate::backfill::search_index;
+use crate::retention::{is_expired, room_retention};
+use crate::state::MatrixState;
+
+/// Results returned when the caller doesn't say.
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+/// Events scanned per encrypted room, which the server can't search.
+const MAX_SCANNED_EVENTS: usize = 2000;
+/// Upgrades followed when grouping rooms into one conversation.
+const MAX_UPGRADE_HOPS: usize = 10;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DmSearchHit {
+    pub room_id: String,
+    /// The newest room of the conversation, so hits from before and after a
+    /// room upgrade group together.
+    pub conversation_id: String,
+    pub event_id: String,
+    pub sender: String,
+    pub body: String,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DmSearchResult {
+    /// Newest first.
+    pub hits: Vec<DmSearchHit>,
+    pub rooms_searched: Vec<String>,
+    /// Set when an encrypted room had more history than is scanned.
+    pub truncated: bool,
+}
+
+/// The parts of a message event a search hit needs.
+#[derive(Deserialize)]
+struct RawMessage {
+    #[serde(rename = "type")]
+    event_type: String,
+    event_id: Option<String>,
+    sender: String,
+    origin_server_ts: u64,
+    content: RawMessageContent,
+}
+
+#[derive(Deserialize)]
+struct RawMessageContent {
+    body: Option<String>,
+}
+
+/// Searches every DM with `user_id`, including ones we left and the rooms
+/// they were upgraded from. Encrypted rooms are searched by scanning their
+/// recent history locally, plus whatever `backfill_room` indexed; others use
+/// the server's search.
+#[tauri::command]
+pub async fn search_dm_history(
+    state: State<'_, MatrixState>,
+    user_id: String,
+    query: String,
+    limit: Option<usize>,
+) -> Result<DmSearchResult, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let user_id: OwnedUserId = user_id
+        .parse()
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
+    let query = query.trim().to_lowercase();
+    if query.is_empty() {
+        return Err("Enter something to search for".to_string());
+    }
+    let limit = limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+
+    let rooms = dm_rooms_with(client, &user_id).await;
+    debug!("Searching {} DM rooms with {}", rooms.len(), user_id);
+
+    let mut hits = Vec::new();
+    let mut truncated = false;
+    let mut plain_rooms = Vec::new();
+    let mut encrypted_rooms = Vec::new();
+
+    for (room, conversation_id) in &rooms {
+        if room.encryption_settings().is_some() {
+            truncated |= scan_room(room, conversation_id, &query, &mut hits).await?;
+            encrypted_rooms.push((room.room_id().to_string(), conversation_id.clone()));
+        } else {
+            plain_rooms.push((room.room_id().to_owned(), conversation_id.clone()));
+        }
+    }
+
+    // History older than the scan reaches is only found when it was
+    // backfilled into the local index.
+    if let Some(user) = state.user_id.read().await.clone() {
+        let session_dir = state.data_dir.join(sanitize_user_id(&user));
+        let room_ids: Vec<String> = encrypted_rooms.iter().map(|(id, _)| id.clone()).collect();
+        let mut retentions = HashMap::new();
+        for (room, _) in &rooms {
+            if let Some(retention) = room_retention(room).await {
+                retentions.insert(room.room_id().to_string(), retention);
+            }
+        }
+        let search = {
+            let query = query.clone();
+            tauri::async_runtime::spawn_blocking(move || search_index(&session_dir, &room_ids, &query))
+                .await
+                .map_err(|e| format!("Failed to search the message index: {}", e))?
+        };
+        match search {
+            Ok(messages) => hits.extend(messages.into_iter().filter_map(|message| {
+                let (_, conversation_id) = encrypted_rooms.iter().find(|(id, _)| *id == message.room_id)?;
+                // Indexed copies outlive the server's; skip the ones it deleted.
+                let expires_at = retentions.get(&message.room_id).and_then(|r| r.expires_at(message.timestamp));
+                if is_expired(expires_at) {
+                    return None;
+                }
+                Some(DmSearchHit {
+                    room_id: message.room_id,
+                    conversation_id: conversation_id.clone(),
+                    event_id: message.event_id,
+                    sender: message.sender,
+                    body: message.body,
+                    timestamp: message.timestamp,
+                })
+            })),
+            Err(e) => warn!("{}", e),
+        }
+    }
+
+    if !plain_rooms.is_empty() {
+        if let Err(e) = server_search(client, &plain_rooms, &query, limit, &mut hits).await {
+            warn!("Server search failed: {}", e);
+        }
+    }
+
+    let mut seen = HashSet::new();
+    hits.retain(|hit: &DmSearchHit| seen.insert(hit.event_id.clone()));
+    hits.sort_by_key(|hit| std::cmp::Reverse(hit.timestamp));
+    hits.truncate(limit);
+
+    Ok(DmSearchResult {
+        hits,
+        rooms_searched: rooms.iter().map(|(room, _)| room.room_id().to_string()).collect(),
+        truncated,
+    })
+}
+
+/// Rooms that are, or were, a DM with `user_id`: those listed in `m.direct`,
+/// unnamed rooms with just the two of us, and rooms upgraded to or from
+/// either. Each comes with the ID of the newest room in its upgrade chain.
+async fn dm_rooms_with(client: &Client, user_id: &UserId) -> Vec<(Room, String)> {
+    let mut rooms: BTreeMap<OwnedRoomId, Room> = BTreeMap::new();
+
+    for room in client.rooms() {
+        if room.is_space() {
+            continue;
+        }
+        let listed = room
+            .direct_targets()
+            .iter()
+            .any(|target| target.as_user_id() == Some(user_id));
+        let two_person = room.name().is_none()
+            && room.active_members_count() <= 2
+            && matches!(room.get_member_no_sync(user_id).await, Ok(Some(_)));
+
+        if listed || two_person {
+            rooms.insert(room.room_id().to_owned(), room);
+        }
+    }
+
+    // Pull in the other halves of upgraded DMs, which m.direct often misses.
+    let found: Vec<Room> = rooms.values().cloned().collect();
+    for room in found {
+        let mut current = room.clone();
+        for _ in 0..MAX_UPGRADE_HOPS {
+            let Some(next) = current.predecessor_room().and_then(|p| client.get_room(&p.room_id)) else {
+                break;
+            };
+            rooms.entry(next.room_id().to_owned()).or_insert_with(|| next.clone());
+            current = next;
+        }
+        let mut current = room;
+        for _ in 0..MAX_UPGRADE_HOPS {
+            let Some(next) = current.successor_room().and_then(|s| client.get_room(&s.room_id)) else {
+                break;
+            };
+            rooms.entry(next.room_id().to_owned()).or_insert_with(|| next.clone());
+            current = next;
+        }
+    }
+
+    rooms
+        .into_values()
+        .map(|room| {
+            let conversation_id = newest_room_id(client, &room);
+            (room, conversation_id)
+        })
+        .collect()
+}
+
+fn newest_room_id(client: &Client, room: &Room) -> String {
+    let mut current = room.clone();
+    for _ in 0..MAX_UPGRADE_HOPS {
+        match current.successor_room().and_then(|s| client.get_room(&s.room_id)) {
+            Some(next) => current = next,
+            None => break,
+        }
+    }
+    current.room_id().to_string()
+}
+
+/// Pages back through an encrypted room, matching decrypted message bodies.
+/// Returns whether history remained beyond the scan limit.
+async fn scan_room(
+    room: &Room,
+    conversation_id: &str,
+    query: &str,
+    hits: &mut Vec<DmSearchHit>,
+) -> Result<bool, String> {
+    let mut token: Option<String> = None;
+    let mut scanned = 0;
+
+    loop {
+        let mut options = MessagesOptions::backward().from(token.as_deref());
+        options.limit = uint!(100);
+
+        let response = room
+            .messages(options)
+            .await
+            .map_err(|e| format!("Failed to fetch messages: {}", e))?;
+
+        for timeline_event in &response.chunk {
+            if let Some(hit) = match_message(timeline_event.raw(), room, conversation_id, query) {
+                hits.push(hit);
+            }
+        }
+        scanned += response.chunk.len();
+
+        if response.chunk.is_empty() || response.end.is_none() {
+            return Ok(false);
+        }
+        if scanned >= MAX_SCANNED_EVENTS {
+            return Ok(true);
+        }
+        token = response.end;
+    }
+}
+
+async fn server_search(
+    client: &Client,
+    rooms: &[(OwnedRoomId, String)],
+    query: &str,
+    limit: usize,
+    hits: &mut Vec<DmSearchHit>,
+) -> Result<(), String> {
+    let mut criteria = Criteria::new(query.to_string());
+    criteria.filter.rooms = Some(rooms.iter().map(|(id, _)| id.clone()).collect());
+    criteria.filter.limit = UInt::new(limit as u64);
+    let mut categories = Categories::new();
+    categories.room_events = Some(criteria);
+
+    let response = client
+        .send(SearchRequest::new(categories))
+        .await
+        .map_err(|e| format!("Failed to search: {}", e))?;
+
+    for result in response.search_categories.room_events.results {
+        let Some(raw) = result.result else {
+            continue;
+        };
+        let Some(room_id) = raw.get_field::<OwnedRoomId>("room_id").ok().flatten() else {
+            continue;
+        };
+        let Some((_, conversation_id)) = rooms.iter().find(|(id, _)| *id == room_id) else {
+            continue;
+        };
+        let Some(room) = client.get_room(&room_id) else {
+            continue;
+        };
+        if let Some(hit) = match_message(raw.cast_ref_unchecked::<AnySyncTimelineEvent>(), &room, conversation_id, query) {
+            hits.push(hit);
+        }
+    }
+
+    Ok(())
+}
+
+fn match_message<T>(
+    raw: &Raw<T>,
+    room: &Room,
+    conversation_id: &str,
+    query: &str,
+) -> Option<DmSearchHit> {
+    let message: RawMessage = raw.deserialize_as_unchecked().ok()?;
+    if message.event_type != "m.room.message" {
+        return None;
+    }
+    let body = message.content.body?;
+    if !body.to_lowercase().contains(query) {
+        return None;
+    }
+
+    Some(DmSearchHit {
+        room_id: room.room_id().to_string(),
+        conversation_id: conversation_id.to_string(),
+        event_id: message.event_id?,
+        sender: message.sender,
+        body,
+        timestamp: message.origin_server_ts,
+    })
+}
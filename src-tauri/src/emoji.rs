@@ -0,0 +1,457 @@
+use matrix_sdk::deserialized_responses::RawAnySyncOrStrippedState;
+use matrix_sdk::ruma::events::StateEventType;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::{Client, Room};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tauri::State;
+
+use crate::account_data::get_global_json;
+use crate::state::MatrixState;
+
+/// MSC2545 room image packs (state events, keyed by state key).
+pub(crate) const ROOM_PACK_EVENT_TYPE: &str = "im.ponies.room_emotes";
+/// MSC2545 personal image pack (global account data).
+pub(crate) const USER_PACK_EVENT_TYPE: &str = "im.ponies.user_emotes";
+/// MSC2545 list of room packs the user enabled everywhere (global account data).
+pub(crate) const EMOTE_ROOMS_EVENT_TYPE: &str = "im.ponies.emote_rooms";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PackImage {
+    pub url: String,
+    pub body: Option<String>,
+    pub info: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ImagePack {
+    pub name: String,
+    /// "user" for the personal pack, otherwise the room the pack lives in.
+    pub source: String,
+    pub state_key: Option<String>,
+    pub avatar_url: Option<String>,
+    pub images: BTreeMap<String, PackImage>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InlineEmote {
+    pub shortcode: String,
+    pub mxc: String,
+}
+
+#[tauri::command]
+pub async fn get_emoji_packs(
+    state: State<'_, MatrixState>,
+    room_id: String,
+) -> Result<Vec<ImagePack>, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    load_image_packs(client, Some(&room), "emoticon").await
+}
+
+/// Collects the user's pack, the globally enabled room packs and the packs of
+/// `room`, keeping only images usable as `usage` ("emoticon" or "sticker").
+pub(crate) async fn load_image_packs(
+    client: &Client,
+    room: Option<&Room>,
+    usage: &str,
+) -> Result<Vec<ImagePack>, String> {
+    let mut packs = Vec::new();
+
+    if let Some(content) = get_global_json(client, USER_PACK_EVENT_TYPE).await? {
+        if let Some(pack) = parse_image_pack(&content, "user", None, usage) {
+            packs.push(pack);
+        }
+    }
+
+    let mut rooms: Vec<(Room, Option<Vec<String>>)> = Vec::new();
+    if let Some(room) = room {
+        rooms.push((room.clone(), None));
+    }
+
+    if let Some(content) = get_global_json(client, EMOTE_ROOMS_EVENT_TYPE).await? {
+        if let Some(enabled) = content.get("rooms").and_then(|r| r.as_object()) {
+            for (room_id, state_keys) in enabled {
+                let Ok(room_id) = OwnedRoomId::try_from(room_id.as_str()) else {
+                    continue;
+                };
+                if room.is_some_and(|r| r.room_id() == room_id) {
+                    continue;
+                }
+                if let Some(other) = client.get_room(&room_id) {
+                    let keys = state_keys
+                        .as_object()
+                        .map(|keys| keys.keys().cloned().collect())
+                        .unwrap_or_default();
+                    rooms.push((other, Some(keys)));
+                }
+            }
+        }
+    }
+
+    for (room, only_keys) in rooms {
+        let events = room
+            .get_state_events(StateEventType::from(ROOM_PACK_EVENT_TYPE))
+            .await
+            .map_err(|e| format!("Failed to load room image packs: {}", e))?;
+
+        for raw in events {
+            let Some((state_key, content)) = state_event_json(&raw) else {
+                continue;
+            };
+            if only_keys.as_ref().is_some_and(|keys| !keys.contains(&state_key)) {
+                continue;
+            }
+            if let Some(pack) =
+                parse_image_pack(&content, room.room_id().as_str(), Some(state_key), usage)
+            {
+                packs.push(pack);
+            }
+        }
+    }
+
+    Ok(packs)
+}
+
+/// Returns the state key and content of a raw state event, regardless of type.
+pub(crate) fn state_event_json(raw: &RawAnySyncOrStrippedState) -> Option<(String, serde_json::Value)> {
+    let event: serde_json::Value = match raw {
+        RawAnySyncOrStrippedState::Sync(raw) => raw.deserialize_as_unchecked().ok()?,
+        RawAnySyncOrStrippedState::Stripped(raw) => raw.deserialize_as_unchecked().ok()?,
+    };
+    let state_key = event.get("state_key")?.as_str()?.to_string();
+    let content = event.get("content")?.clone();
+    Some((state_key, content))
+}
+
+fn parse_image_pack(
+    content: &serde_json::Value,
+    source: &str,
+    state_key: Option<String>,
+    usage: &str,
+) -> Option<ImagePack> {
+    let pack_meta = content.get("pack");
+    let pack_usage = pack_meta.and_then(|p| p.get("usage"));
+
+    let allows = |usage_value: Option<&serde_json::Value>| match usage_value.and_then(|u| u.as_array()) {
+        Some(list) if !list.is_empty() => list.iter().any(|u| u.as_str() == Some(usage)),
+        _ => true,
+    };
+
+    let mut images = BTreeMap::new();
+    for (shortcode, image) in content.get("images")?.as_object()? {
+        let Some(url) = image.get("url").and_then(|u| u.as_str()) else {
+            continue;
+        };
+        let image_usage = image.get("usage").or(pack_usage);
+        if !url.starts_with("mxc://") || !allows(image_usage) {
+            continue;
+        }
+        images.insert(
+            shortcode.clone(),
+            PackImage {
+                url: url.to_string(),
+                body: image.get("body").and_then(|b| b.as_str()).map(str::to_string),
+                info: image.get("info").cloned(),
+            },
+        );
+    }
+
+    if images.is_empty() {
+        return None;
+    }
+
+    let name = pack_meta
+        .and_then(|p| p.get("display_name"))
+        .and_then(|n| n.as_str())
+        .map(str::to_string)
+        .or_else(|| state_key.clone().filter(|k| !k.is_empty()))
+        .unwrap_or_else(|| if source == "user" { "Personal".to_string() } else { "Room emotes".to_string() });
+
+    Some(ImagePack {
+        name,
+        source: source.to_string(),
+        state_key,
+        avatar_url: pack_meta
+            .and_then(|p| p.get("avatar_url"))
+            .and_then(|a| a.as_str())
+            .map(str::to_string),
+        images,
+    })
+}
+
+/// Replaces known `:shortcode:` tokens with their unicode emoji.
+pub(crate) fn expand_shortcodes(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find(':') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        let end = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '+' || c == '-'));
+        match end {
+            Some(end) if end > 0 && after[end..].starts_with(':') => {
+                let name = &after[..end];
+                if let Some(emoji) = lookup_shortcode(name) {
+                    out.push_str(emoji);
+                    rest = &after[end + 1..];
+                } else {
+                    // Keep the closing colon in play: it may open the next shortcode.
+                    out.push(':');
+                    out.push_str(name);
+                    rest = &after[end..];
+                }
+            }
+            _ => {
+                out.push(':');
+                rest = after;
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn lookup_shortcode(name: &str) -> Option<&'static str> {
+    SHORTCODES
+        .iter()
+        .find(|(code, _)| *code == name)
+        .map(|(_, emoji)| *emoji)
+}
+
+/// Extracts `<img data-mx-emoticon>` custom emotes from an HTML formatted body.
+pub(crate) fn inline_emotes(formatted_body: &str) -> Vec<InlineEmote> {
+    let mut emotes = Vec::new();
+    let lower = formatted_body.to_ascii_lowercase();
+    let mut offset = 0;
+
+    while let Some(pos) = lower[offset..].find("<img") {
+        let start = offset + pos;
+        let Some(len) = lower[start..].find('>') else {
+            break;
+        };
+        let tag = &formatted_body[start..start + len];
+        offset = start + len;
+
+        if !tag.to_ascii_lowercase().contains("data-mx-emoticon") {
+            continue;
+        }
+        let Some(src) = html_attribute(tag, "src").filter(|s| s.starts_with("mxc://")) else {
+            continue;
+        };
+        let shortcode = html_attribute(tag, "alt")
+            .or_else(|| html_attribute(tag, "title"))
+            .unwrap_or_else(|| src.clone());
+
+        emotes.push(InlineEmote { shortcode, mxc: src });
+    }
+
+    emotes
+}
+
+fn html_attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut search = 0;
+
+    while let Some(pos) = lower[search..].find(name) {
+        let start = search + pos;
+        search = start + name.len();
+
+        let preceded_by_space = lower[..start].ends_with(|c: char| c.is_ascii_whitespace());
+        let rest = lower[search..].trim_start();
+        if !preceded_by_space || !rest.starts_with('=') {
+            continue;
+        }
+
+        let value_start = tag.len() - rest.len() + 1;
+        let value = tag[value_start..].trim_start();
+        let quote = value.chars().next()?;
+        return if quote == '"' || quote == '\'' {
+            value[1..].find(quote).map(|end| value[1..1 + end].to_string())
+        } else {
+            Some(
+                value
+                    .split(|c: char| c.is_ascii_whitespace() || c == '/')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+            )
+        };
+    }
+
+    None
+}
+
+/// A bundled subset of the common gemoji shortcodes.
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("thumbsup", "👍"),
+    ("thumbsdown", "👎"),
+    ("ok_hand", "👌"),
+    ("wave", "👋"),
+    ("clap", "👏"),
+    ("pray", "🙏"),
+    ("raised_hands", "🙌"),
+    ("muscle", "💪"),
+    ("point_up", "☝️"),
+    ("point_right", "👉"),
+    ("point_left", "👈"),
+    ("v", "✌️"),
+    ("handshake", "🤝"),
+    ("eyes", "👀"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("grin", "😁"),
+    ("grinning", "😀"),
+    ("laughing", "😆"),
+    ("sweat_smile", "😅"),
+    ("joy", "😂"),
+    ("rofl", "🤣"),
+    ("slightly_smiling_face", "🙂"),
+    ("upside_down_face", "🙃"),
+    ("wink", "😉"),
+    ("blush", "😊"),
+    ("innocent", "😇"),
+    ("heart_eyes", "😍"),
+    ("kissing_heart", "😘"),
+    ("yum", "😋"),
+    ("stuck_out_tongue", "😛"),
+    ("stuck_out_tongue_winking_eye", "😜"),
+    ("thinking", "🤔"),
+    ("neutral_face", "😐"),
+    ("expressionless", "😑"),
+    ("no_mouth", "😶"),
+    ("smirk", "😏"),
+    ("unamused", "😒"),
+    ("roll_eyes", "🙄"),
+    ("grimacing", "😬"),
+    ("relieved", "😌"),
+    ("pensive", "😔"),
+    ("sleepy", "😪"),
+    ("sleeping", "😴"),
+    ("mask", "😷"),
+    ("nerd_face", "🤓"),
+    ("sunglasses", "😎"),
+    ("confused", "😕"),
+    ("worried", "😟"),
+    ("frowning_face", "☹️"),
+    ("open_mouth", "😮"),
+    ("astonished", "😲"),
+    ("flushed", "😳"),
+    ("pleading_face", "🥺"),
+    ("cry", "😢"),
+    ("sob", "😭"),
+    ("scream", "😱"),
+    ("angry", "😠"),
+    ("rage", "😡"),
+    ("triumph", "😤"),
+    ("skull", "💀"),
+    ("poop", "💩"),
+    ("clown_face", "🤡"),
+    ("ghost", "👻"),
+    ("alien", "👽"),
+    ("robot", "🤖"),
+    ("see_no_evil", "🙈"),
+    ("hear_no_evil", "🙉"),
+    ("speak_no_evil", "🙊"),
+    ("heart", "❤️"),
+    ("orange_heart", "🧡"),
+    ("yellow_heart", "💛"),
+    ("green_heart", "💚"),
+    ("blue_heart", "💙"),
+    ("purple_heart", "💜"),
+    ("black_heart", "🖤"),
+    ("broken_heart", "💔"),
+    ("sparkling_heart", "💖"),
+    ("two_hearts", "💕"),
+    ("100", "💯"),
+    ("fire", "🔥"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("zap", "⚡"),
+    ("boom", "💥"),
+    ("tada", "🎉"),
+    ("confetti_ball", "🎊"),
+    ("gift", "🎁"),
+    ("birthday", "🎂"),
+    ("trophy", "🏆"),
+    ("rocket", "🚀"),
+    ("bulb", "💡"),
+    ("warning", "⚠️"),
+    ("x", "❌"),
+    ("white_check_mark", "✅"),
+    ("heavy_check_mark", "✔️"),
+    ("question", "❓"),
+    ("exclamation", "❗"),
+    ("no_entry", "⛔"),
+    ("lock", "🔒"),
+    ("unlock", "🔓"),
+    ("key", "🔑"),
+    ("bell", "🔔"),
+    ("calendar", "📆"),
+    ("memo", "📝"),
+    ("link", "🔗"),
+    ("pushpin", "📌"),
+    ("paperclip", "📎"),
+    ("email", "📧"),
+    ("computer", "💻"),
+    ("phone", "☎️"),
+    ("iphone", "📱"),
+    ("bug", "🐛"),
+    ("coffee", "☕"),
+    ("beer", "🍺"),
+    ("beers", "🍻"),
+    ("wine_glass", "🍷"),
+    ("pizza", "🍕"),
+    ("cake", "🍰"),
+    ("cookie", "🍪"),
+    ("apple", "🍎"),
+    ("dog", "🐶"),
+    ("cat", "🐱"),
+    ("mouse", "🐭"),
+    ("rabbit", "🐰"),
+    ("fox_face", "🦊"),
+    ("bear", "🐻"),
+    ("panda_face", "🐼"),
+    ("unicorn", "🦄"),
+    ("penguin", "🐧"),
+    ("owl", "🦉"),
+    ("turtle", "🐢"),
+    ("snake", "🐍"),
+    ("crab", "🦀"),
+    ("octopus", "🐙"),
+    ("sunny", "☀️"),
+    ("cloud", "☁️"),
+    ("umbrella", "☔"),
+    ("snowflake", "❄️"),
+    ("rainbow", "🌈"),
+    ("earth_africa", "🌍"),
+    ("moon", "🌙"),
+    ("seedling", "🌱"),
+    ("evergreen_tree", "🌲"),
+    ("rose", "🌹"),
+    ("sunflower", "🌻"),
+    ("hourglass", "⌛"),
+    ("alarm_clock", "⏰"),
+    ("watch", "⌚"),
+    ("shrug", "🤷"),
+    ("facepalm", "🤦"),
+    ("crossed_fingers", "🤞"),
+    ("metal", "🤘"),
+    ("call_me_hand", "🤙"),
+    ("writing_hand", "✍️"),
+    ("zzz", "💤"),
+    ("money_with_wings", "💸"),
+    ("moneybag", "💰"),
+    ("chart_with_upwards_trend", "📈"),
+    ("chart_with_downwards_trend", "📉"),
+];
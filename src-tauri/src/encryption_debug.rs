@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+
+use matrix_sdk::ruma::api::client::keys::get_keys;
+use matrix_sdk::ruma::events::room::history_visibility::HistoryVisibility;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::{Client, Room, RoomMemberships};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tracing::info;
+
+use crate::state::MatrixState;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeviceKeyStatus {
+    pub user_id: String,
+    pub device_id: String,
+    pub display_name: Option<String>,
+    pub verified: bool,
+    pub blacklisted: bool,
+    pub receives_keys: bool,
+    /// Why keys are withheld from this device: `blacklisted` or
+    /// `invited_joined_only_history`.
+    pub withheld_reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EncryptionDebugInfo {
+    pub room_id: String,
+    pub encrypted: bool,
+    pub algorithm: Option<String>,
+    pub rotation_period_ms: Option<u64>,
+    pub rotation_period_msgs: Option<u64>,
+    pub history_visibility: String,
+    /// Whether invited members receive room keys under the current history
+    /// visibility.
+    pub shares_with_invited: bool,
+    /// The strategy the client uses to pick key recipients.
+    pub share_strategy: String,
+    pub devices: Vec<DeviceKeyStatus>,
+    /// Devices blocked with `block_device`, which can't read our messages.
+    pub blacklisted_devices: Vec<DeviceKeyStatus>,
+    pub sharing_with: usize,
+    pub withheld_from: usize,
+    /// Active members with no known devices; they cannot receive keys at all.
+    pub members_without_devices: Vec<String>,
+}
+
+/// Something keeping this device from encrypting for a room right now.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SendBlocker {
+    /// `own_device_not_cross_signed`, `key_upload_pending` or
+    /// `members_not_fetched`. One-time keys aren't checked: the SDK tops
+    /// them up on every sync and doesn't report its count.
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SendReadiness {
+    pub room_id: String,
+    pub encrypted: bool,
+    pub ready: bool,
+    pub blockers: Vec<SendBlocker>,
+}
+
+/// Reports who our room keys go to, to help diagnose "unable to decrypt"
+/// reports from other members.
+///
+/// The SDK does not expose the outbound group session itself, so recipients
+/// are derived the way it picks them: active members' devices (invited ones
+/// only when history is not `joined`), minus blacklisted devices.
+#[tauri::command]
+pub async fn get_encryption_debug(
+    state: State<'_, MatrixState>,
+    room_id: String,
+) -> Result<EncryptionDebugInfo, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let settings = room.encryption_settings();
+    let history_visibility = room.history_visibility_or_default();
+    let shares_with_invited = history_visibility != HistoryVisibility::Joined;
+
+    let joined: Vec<_> = room
+        .members_no_sync(RoomMemberships::JOIN)
+        .await
+        .map_err(|e| format!("Failed to load members: {}", e))?
+        .into_iter()
+        .map(|m| m.user_id().to_owned())
+        .collect();
+    let active = room
+        .members_no_sync(RoomMemberships::ACTIVE)
+        .await
+        .map_err(|e| format!("Failed to load members: {}", e))?;
+
+    let own_device = client.device_id();
+    let mut devices = Vec::new();
+    let mut members_without_devices = Vec::new();
+
+    for member in active {
+        let user_id = member.user_id();
+        let user_devices = client
+            .encryption()
+            .get_user_devices(user_id)
+            .await
+            .map_err(|e| format!("Failed to get devices: {}", e))?;
+
+        let mut found = false;
+        for device in user_devices.devices() {
+            if Some(device.device_id()) == own_device || device.is_deleted() {
+                continue;
+            }
+            found = true;
+
+            let withheld_reason = if device.is_blacklisted() {
+                Some("blacklisted".to_string())
+            } else if !shares_with_invited && !joined.iter().any(|u| u == user_id) {
+                Some("invited_joined_only_history".to_string())
+            } else {
+                None
+            };
+
+            devices.push(DeviceKeyStatus {
+                user_id: user_id.to_string(),
+                device_id: device.device_id().to_string(),
+                display_name: device.display_name().map(str::to_string),
+                verified: device.is_verified(),
+                blacklisted: device.is_blacklisted(),
+                receives_keys: withheld_reason.is_none(),
+                withheld_reason,
+            });
+        }
+
+        if !found && Some(user_id) != client.user_id() {
+            members_without_devices.push(user_id.to_string());
+        }
+    }
+
+    let sharing_with = devices.iter().filter(|d| d.receives_keys).count();
+    let blacklisted_devices = devices.iter().filter(|d| d.blacklisted).cloned().collect();
+
+    Ok(EncryptionDebugInfo {
+        room_id: room_id.to_string(),
+        encrypted: settings.is_some(),
+        algorithm: settings.as_ref().map(|s| s.algorithm.to_string()),
+        rotation_period_ms: settings
+            .as_ref()
+            .and_then(|s| s.rotation_period_ms)
+            .map(Into::into),
+        rotation_period_msgs: settings
+            .as_ref()
+            .and_then(|s| s.rotation_period_msgs)
+            .map(Into::into),
+        history_visibility: history_visibility.to_string(),
+        shares_with_invited,
+        share_strategy: "all_devices".to_string(),
+        withheld_from: devices.len() - sharing_with,
+        sharing_with,
+        devices,
+        blacklisted_devices,
+        members_without_devices,
+    })
+}
+
+/// Discards the room's outbound group session so the next message is sent
+/// with a fresh key shared to the current devices.
+#[tauri::command]
+pub async fn rotate_room_key(
+    state: State<'_, MatrixState>,
+    room_id: String,
+) -> Result<String, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    room.discard_room_key()
+        .await
+        .map_err(|e| format!("Failed to rotate room key: {}", e))?;
+
+    info!("Discarded outbound room key for {}", room_id);
+
+    Ok("Room key discarded; the next message will use a new key".to_string())
+}
+
+/// Reports whether this device can encrypt for the room right now and, if
+/// not, why.
+#[tauri::command]
+pub async fn get_send_readiness(
+    state: State<'_, MatrixState>,
+    room_id: String,
+) -> Result<SendReadiness, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    send_readiness(client, &room).await
+}
+
+/// Gets a big encrypted room ready so the first message isn't held up: loads
+/// the member list and everyone's device keys. The SDK does not expose room
+/// key pre-sharing, so claiming one-time keys and sharing the room key still
+/// happen on the first send.
+#[tauri::command]
+pub async fn prepare_encryption(
+    state: State<'_, MatrixState>,
+    room_id: String,
+) -> Result<SendReadiness, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let readiness = send_readiness(&client, &room).await?;
+    if !readiness.encrypted {
+        return Ok(readiness);
+    }
+
+    room.sync_members()
+        .await
+        .map_err(|e| format!("Failed to load members: {}", e))?;
+
+    let members = room
+        .members_no_sync(RoomMemberships::ACTIVE)
+        .await
+        .map_err(|e| format!("Failed to load members: {}", e))?;
+    for member in &members {
+        client
+            .encryption()
+            .get_user_devices(member.user_id())
+            .await
+            .map_err(|e| format!("Failed to get devices: {}", e))?;
+    }
+
+    info!("Prepared encryption for {} ({} members)", room_id, members.len());
+
+    send_readiness(&client, &room).await
+}
+
+pub(crate) async fn send_readiness(client: &Client, room: &Room) -> Result<SendReadiness, String> {
+    let encrypted = room
+        .latest_encryption_state()
+        .await
+        .map_err(|e| format!("Failed to get encryption state: {}", e))?
+        .is_encrypted();
+
+    let mut blockers = Vec::new();
+    if encrypted {
+        blockers.extend(own_device_blocker(client).await?);
+    }
+    let mut block = |code: &str, message: &str| {
+        blockers.push(SendBlocker {
+            code: code.to_string(),
+            message: message.to_string(),
+        })
+    };
+
+    if encrypted {
+        if !own_keys_uploaded(client).await? {
+            block(
+                "key_upload_pending",
+                "This device's encryption keys have not been uploaded yet",
+            );
+        }
+
+        if !room.are_members_synced() {
+            block(
+                "members_not_fetched",
+                "The member list has not been loaded, so keys cannot be shared yet",
+            );
+        }
+    }
+
+    Ok(SendReadiness {
+        room_id: room.room_id().to_string(),
+        encrypted,
+        ready: blockers.is_empty(),
+        blockers,
+    })
+}
+
+/// Blocks sending from a device its owner hasn't cross-signed, whose
+/// messages others can't trust. Only reads the local crypto store, so it is
+/// cheap enough to check before every send.
+pub(crate) async fn own_device_blocker(client: &Client) -> Result<Option<SendBlocker>, String> {
+    let own_device = client
+        .encryption()
+        .get_own_device()
+        .await
+        .map_err(|e| format!("Failed to get own device: {}", e))?;
+    Ok((!own_device.is_some_and(|d| d.is_cross_signed_by_owner())).then(|| SendBlocker {
+        code: "own_device_not_cross_signed".to_string(),
+        message: "This device is not verified yet; verify it before sending encrypted messages".to_string(),
+    }))
+}
+
+/// Whether the server knows our device keys.
+async fn own_keys_uploaded(client: &Client) -> Result<bool, String> {
+    let user_id = client.user_id().ok_or("No user ID")?.to_owned();
+    let device_id = client.device_id().ok_or("No device ID")?.to_owned();
+
+    let mut request = get_keys::v3::Request::new();
+    request.device_keys = BTreeMap::from([(user_id.clone(), vec![device_id.clone()])]);
+    let response = client
+        .send(request)
+        .await
+        .map_err(|e| format!("Failed to query own keys: {}", e))?;
+
+    Ok(response
+        .device_keys
+        .get(&user_id)
+        .is_some_and(|devices| devices.contains_key(&device_id)))
+}
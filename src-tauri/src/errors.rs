@@ -0,0 +1,59 @@
+use std::time::Duration;
+
+use matrix_sdk::ruma::api::client::error::{ErrorKind, RetryAfter};
+use serde::Serialize;
+
+/// How long to back off after a 429 that doesn't say.
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// An error with a stable `code` the frontend can branch on.
+///
+/// Commands keep returning `Result<T, String>`; a `CommandError` converts into
+/// its JSON form so the frontend can tell coded errors apart from plain
+/// message strings.
+#[derive(Serialize, Clone, Debug)]
+pub struct CommandError {
+    pub code: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl CommandError {
+    pub fn new(code: &str, message: impl Into<String>) -> Self {
+        Self {
+            code: code.to_string(),
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+impl From<CommandError> for String {
+    fn from(error: CommandError) -> Self {
+        serde_json::to_string(&error).unwrap_or(error.message)
+    }
+}
+
+/// The `code` of a command error string, `None` for a plain message.
+pub(crate) fn error_code(error: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(error).ok()?;
+    value.get("code")?.as_str().map(str::to_string)
+}
+
+/// How long the server asked us to wait when `kind` is a rate limit, or
+/// `None` for any other error.
+pub(crate) fn rate_limit_delay(kind: Option<&ErrorKind>) -> Option<Duration> {
+    match kind? {
+        ErrorKind::LimitExceeded { retry_after } => Some(match retry_after {
+            Some(RetryAfter::Delay(delay)) => *delay,
+            _ => DEFAULT_RETRY_DELAY,
+        }),
+        _ => None,
+    }
+}
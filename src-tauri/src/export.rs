@@ -0,0 +1,336 @@
+use matrix_sdk::deserialized_responses::{TimelineEvent, TimelineEventKind};
+use matrix_sdk::media::{MediaFormat, MediaRequestParameters};
+use matrix_sdk::room::MessagesOptions;
+use matrix_sdk::ruma::events::room::message::{MessageType, SyncRoomMessageEvent};
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::events::{AnySyncMessageLikeEvent, AnySyncTimelineEvent};
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId};
+use matrix_sdk::{Client, Room};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use tauri::{AppHandle, Emitter, State};
+use tracing::{info, warn};
+
+use crate::rooms::text_body;
+use crate::state::MatrixState;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Text,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExportedMessage {
+    pub event_id: Option<String>,
+    pub sender: String,
+    pub sender_name: String,
+    pub timestamp: u64,
+    pub time: String,
+    /// One of "message", "media" or "undecryptable".
+    pub kind: String,
+    pub body: String,
+    pub media_url: Option<String>,
+    pub media_file: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportResult {
+    pub path: String,
+    pub messages_exported: usize,
+    pub undecryptable: usize,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ExportProgress {
+    pub room_id: String,
+    pub messages_exported: usize,
+    pub oldest_timestamp: Option<u64>,
+    pub finished: bool,
+}
+
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn export_room_history(
+    app: AppHandle,
+    state: State<'_, MatrixState>,
+    room_id: String,
+    format: ExportFormat,
+    path: String,
+    from_ts: Option<u64>,
+    to_ts: Option<u64>,
+    download_media: Option<bool>,
+) -> Result<ExportResult, String> {
+    let client = state
+        .client
+        .read()
+        .await
+        .clone()
+        .ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let path = PathBuf::from(path);
+    let media_dir = if download_media.unwrap_or(false) {
+        let dir = path.with_extension("media");
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("Failed to create media directory: {}", e))?;
+        Some(dir)
+    } else {
+        None
+    };
+
+    state.export_cancelled.store(false, Ordering::SeqCst);
+
+    info!("Exporting room {} to {:?}", room_id, path);
+
+    let exported = collect_messages(&app, &state, &client, &room, from_ts, to_ts, media_dir.as_deref()).await?;
+    let contents = render_export(&exported, format)?;
+
+    fs::write(&path, contents).map_err(|e| format!("Failed to write export: {}", e))?;
+
+    let undecryptable = exported.iter().filter(|m| m.kind == "undecryptable").count();
+
+    info!("Exported {} messages ({} undecryptable)", exported.len(), undecryptable);
+
+    let _ = app.emit(
+        "matrix://export-progress",
+        ExportProgress {
+            room_id: room_id.to_string(),
+            messages_exported: exported.len(),
+            oldest_timestamp: exported.first().map(|m| m.timestamp),
+            finished: true,
+        },
+    );
+
+    Ok(ExportResult {
+        path: path.to_string_lossy().to_string(),
+        messages_exported: exported.len(),
+        undecryptable,
+    })
+}
+
+/// Pages back through the room's history and returns the exportable
+/// messages between `from_ts` and `to_ts`, oldest first, emitting
+/// `matrix://export-progress` per page. Fails once `cancel_export` is called.
+pub(crate) async fn collect_messages(
+    app: &AppHandle,
+    state: &MatrixState,
+    client: &Client,
+    room: &Room,
+    from_ts: Option<u64>,
+    to_ts: Option<u64>,
+    media_dir: Option<&Path>,
+) -> Result<Vec<ExportedMessage>, String> {
+    let room_id = room.room_id();
+    let mut names: HashMap<OwnedUserId, String> = HashMap::new();
+    let mut exported = Vec::new();
+    let mut token: Option<String> = None;
+
+    loop {
+        if state.export_cancelled.load(Ordering::SeqCst) {
+            info!("Export of {} cancelled", room_id);
+            return Err("Export cancelled".to_string());
+        }
+
+        let options = MessagesOptions::backward().from(token.as_deref());
+        let response = room
+            .messages(options)
+            .await
+            .map_err(|e| format!("Failed to fetch messages: {}", e))?;
+
+        let mut reached_start = false;
+        for timeline_event in &response.chunk {
+            let timestamp: u64 = timeline_event.timestamp.map(|ts| ts.get().into()).unwrap_or(0);
+
+            if from_ts.is_some_and(|from| timestamp < from) {
+                reached_start = true;
+                continue;
+            }
+            if to_ts.is_some_and(|to| timestamp > to) {
+                continue;
+            }
+
+            if let Some(message) = export_event(client, room, timeline_event, timestamp, &mut names, media_dir).await {
+                exported.push(message);
+            }
+        }
+
+        let _ = app.emit(
+            "matrix://export-progress",
+            ExportProgress {
+                room_id: room_id.to_string(),
+                messages_exported: exported.len(),
+                oldest_timestamp: exported.last().map(|m: &ExportedMessage| m.timestamp),
+                finished: false,
+            },
+        );
+
+        if reached_start || response.chunk.is_empty() || response.end.is_none() {
+            break;
+        }
+        token = response.end;
+    }
+
+    exported.reverse();
+    Ok(exported)
+}
+
+pub(crate) fn render_export(exported: &[ExportedMessage], format: ExportFormat) -> Result<String, String> {
+    match format {
+        ExportFormat::Json => serde_json::to_string_pretty(exported)
+            .map_err(|e| format!("Failed to serialize export: {}", e)),
+        ExportFormat::Text => Ok(exported
+            .iter()
+            .map(|m| {
+                let mut line = format!("[{}] {} ({}): {}", m.time, m.sender_name, m.sender, m.body);
+                if let Some(url) = &m.media_url {
+                    line.push_str(&format!(" <{}>", url));
+                }
+                line
+            })
+            .collect::<Vec<_>>()
+            .join("\n")),
+    }
+}
+
+#[tauri::command]
+pub async fn cancel_export(state: State<'_, MatrixState>) -> Result<String, String> {
+    state.export_cancelled.store(true, Ordering::SeqCst);
+    Ok("Export cancelled".to_string())
+}
+
+async fn export_event(
+    client: &Client,
+    room: &Room,
+    timeline_event: &TimelineEvent,
+    timestamp: u64,
+    names: &mut HashMap<OwnedUserId, String>,
+    media_dir: Option<&Path>,
+) -> Option<ExportedMessage> {
+    let event_id = timeline_event.event_id().map(|id| id.to_string());
+
+    let sender: OwnedUserId = timeline_event.raw().get_field("sender").ok().flatten()?;
+    let sender_name = match names.get(&sender) {
+        Some(name) => name.clone(),
+        None => {
+            let name = room
+                .get_member_no_sync(&sender)
+                .await
+                .ok()
+                .flatten()
+                .map(|member| member.name().to_string())
+                .unwrap_or_else(|| sender.to_string());
+            names.insert(sender.clone(), name.clone());
+            name
+        }
+    };
+
+    let mut message = ExportedMessage {
+        event_id,
+        sender: sender.to_string(),
+        sender_name,
+        timestamp,
+        time: format_iso8601(timestamp),
+        kind: "message".to_string(),
+        body: String::new(),
+        media_url: None,
+        media_file: None,
+    };
+
+    if let TimelineEventKind::UnableToDecrypt { .. } = timeline_event.kind {
+        message.kind = "undecryptable".to_string();
+        message.body = "[Unable to decrypt message]".to_string();
+        return Some(message);
+    }
+
+    let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+        SyncRoomMessageEvent::Original(original),
+    ))) = timeline_event.raw().deserialize()
+    else {
+        return None;
+    };
+
+    let msgtype = &original.content.msgtype;
+    if let Some(body) = text_body(msgtype) {
+        message.body = body;
+        return Some(message);
+    }
+
+    let (body, source) = match msgtype {
+        MessageType::Image(c) => (&c.body, &c.source),
+        MessageType::File(c) => (&c.body, &c.source),
+        MessageType::Audio(c) => (&c.body, &c.source),
+        MessageType::Video(c) => (&c.body, &c.source),
+        _ => return None,
+    };
+
+    message.kind = "media".to_string();
+    message.body = body.clone();
+    message.media_url = Some(match source {
+        MediaSource::Plain(uri) => uri.to_string(),
+        MediaSource::Encrypted(file) => file.url.to_string(),
+    });
+
+    if let Some(dir) = media_dir {
+        let request = MediaRequestParameters {
+            source: source.clone(),
+            format: MediaFormat::File,
+        };
+        match client.media().get_media_content(&request, true).await {
+            Ok(bytes) => {
+                let file_name = format!(
+                    "{}-{}",
+                    message.event_id.as_deref().unwrap_or("event").replace(['$', ':', '/'], "_"),
+                    body.replace(['/', '\\'], "_"),
+                );
+                let file_path = dir.join(file_name);
+                match fs::write(&file_path, bytes) {
+                    Ok(()) => message.media_file = Some(file_path.to_string_lossy().to_string()),
+                    Err(e) => warn!("Failed to write media for export: {}", e),
+                }
+            }
+            Err(e) => warn!("Failed to download media for export: {}", e),
+        }
+    }
+
+    Some(message)
+}
+
+/// Formats milliseconds since the Unix epoch as an ISO 8601 UTC timestamp.
+pub(crate) fn format_iso8601(timestamp_ms: u64) -> String {
+    let secs = timestamp_ms / 1000;
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60,
+    )
+}
+
+/// Converts days since 1970-01-01 into a (year, month, day) civil date.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = (if z >= 0 { z } else { z - 146_096 }) / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,414 @@
+use matrix_sdk::ruma::matrix_uri::MatrixId;
+use ruma_html::matrix::{AnchorUri, MatrixElement};
+use ruma_html::{Html, NodeRef};
+use serde::{Deserialize, Serialize};
+
+/// What a spoiler shows as in plain text, so notifications and fallbacks
+/// don't give it away.
+const SPOILER_PLACEHOLDER: &str = "[Spoiler]";
+/// HTML nodes looked at per body. A hostile sender can fit thousands of
+/// tags in one event; past this the rest is dropped.
+const MAX_FORMATTED_NODES: usize = 2000;
+/// Elements nested deeper than this keep only their text.
+const MAX_FORMATTED_DEPTH: usize = 32;
+
+/// A piece of a formatted message body. The frontend renders these instead
+/// of the sender's HTML, so it needs no sanitizer.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Segment {
+    Text {
+        text: String,
+    },
+    /// Inline `<code>`, or a `<pre>` block.
+    Code {
+        text: String,
+        block: bool,
+        language: Option<String>,
+    },
+    /// Hidden until clicked.
+    Spoiler {
+        reason: Option<String>,
+        segments: Vec<Segment>,
+    },
+    Link {
+        text: String,
+        url: String,
+    },
+    /// A link to a user, room or event.
+    Mention {
+        text: String,
+        target: String,
+    },
+}
+
+/// Splits an HTML `formatted_body` into segments. Block elements and `<br>`
+/// become line breaks, reply fallbacks are dropped, and any other markup
+/// keeps only its text.
+pub(crate) fn parse_formatted(html: &str) -> Vec<Segment> {
+    parse_formatted_capped(html).0
+}
+
+/// `parse_formatted`, also saying whether the body was too large or too
+/// deeply nested to convert completely.
+pub(crate) fn parse_formatted_capped(html: &str) -> (Vec<Segment>, bool) {
+    let html = Html::parse(html);
+    let mut segments = Vec::new();
+    let mut walk = Walk {
+        nodes_left: MAX_FORMATTED_NODES,
+        depth: 0,
+        truncated: false,
+    };
+    for node in html.children() {
+        push_node(&node, &mut segments, &mut walk);
+    }
+
+    if let Some(Segment::Text { text }) = segments.last_mut() {
+        text.truncate(text.trim_end().len());
+        if text.is_empty() {
+            segments.pop();
+        }
+    }
+    (segments, walk.truncated)
+}
+
+/// How much of the HTML tree is left to look at.
+struct Walk {
+    nodes_left: usize,
+    depth: usize,
+    truncated: bool,
+}
+
+/// Whether the segments include a spoiler.
+pub(crate) fn contains_spoiler(segments: &[Segment]) -> bool {
+    segments.iter().any(|segment| matches!(segment, Segment::Spoiler { .. }))
+}
+
+/// The segments as plain text, with spoilers replaced by a placeholder.
+pub(crate) fn plain_text(segments: &[Segment]) -> String {
+    let mut plain = String::new();
+    for segment in segments {
+        match segment {
+            Segment::Text { text } | Segment::Code { text, .. } => plain.push_str(text),
+            Segment::Link { text, .. } | Segment::Mention { text, .. } => plain.push_str(text),
+            Segment::Spoiler { .. } => plain.push_str(SPOILER_PLACEHOLDER),
+        }
+    }
+    plain
+}
+
+fn push_node(node: &NodeRef, out: &mut Vec<Segment>, walk: &mut Walk) {
+    if walk.nodes_left == 0 {
+        walk.truncated = true;
+        return;
+    }
+    walk.nodes_left -= 1;
+    if walk.depth >= MAX_FORMATTED_DEPTH {
+        walk.truncated = true;
+        push_text(out, &collapse_whitespace(&text_content(node), ends_line(out)));
+        return;
+    }
+
+    if let Some(text) = node.as_text() {
+        push_text(out, &collapse_whitespace(&text.borrow(), ends_line(out)));
+        return;
+    }
+    let Some(element) = node.as_element() else {
+        return;
+    };
+
+    match element.to_matrix().element {
+        MatrixElement::MatrixReply => {}
+        MatrixElement::Br => push_text(out, "\n"),
+        MatrixElement::Pre => {
+            let language = node.children().find_map(|child| match child.as_element()?.to_matrix().element {
+                MatrixElement::Code(code) => code.language.map(|l| l.to_string()),
+                _ => None,
+            });
+            break_line(out);
+            out.push(Segment::Code {
+                text: text_content(node).trim_end_matches('\n').to_string(),
+                block: true,
+                language,
+            });
+            break_line(out);
+        }
+        MatrixElement::Code(code) => out.push(Segment::Code {
+            text: text_content(node),
+            block: false,
+            language: code.language.map(|l| l.to_string()),
+        }),
+        MatrixElement::Span(span) if span.spoiler.is_some() => {
+            let mut segments = Vec::new();
+            push_children(node, &mut segments, walk);
+            out.push(Segment::Spoiler {
+                reason: span.spoiler.map(|r| r.to_string()).filter(|r| !r.trim().is_empty()),
+                segments,
+            });
+        }
+        MatrixElement::A(anchor) => {
+            let text = text_content(node);
+            // The link text and target would give a spoiler inside away, so
+            // the whole link goes behind it.
+            let spoiler = spoiler_within(node);
+            let mut link = Vec::new();
+            let target = if spoiler.is_some() { &mut link } else { &mut *out };
+            match anchor.href {
+                Some(AnchorUri::MatrixTo(uri)) => push_mention(target, text, uri.id()),
+                Some(AnchorUri::Matrix(uri)) => push_mention(target, text, uri.id()),
+                Some(AnchorUri::Other(url)) => target.push(Segment::Link { text, url: url.to_string() }),
+                _ => push_text(target, &text),
+            }
+            if let Some(reason) = spoiler {
+                out.push(Segment::Spoiler { reason, segments: link });
+            }
+        }
+        MatrixElement::Img(image) => {
+            if let Some(alt) = image.alt.or(image.title) {
+                push_text(out, &alt);
+            }
+        }
+        MatrixElement::Li => {
+            break_line(out);
+            push_text(out, "• ");
+            push_children(node, out, walk);
+            break_line(out);
+        }
+        MatrixElement::P
+        | MatrixElement::Div(_)
+        | MatrixElement::H(_)
+        | MatrixElement::Blockquote
+        | MatrixElement::Ul
+        | MatrixElement::Ol(_)
+        | MatrixElement::Hr
+        | MatrixElement::Table
+        | MatrixElement::Tr
+        | MatrixElement::Caption
+        | MatrixElement::Details
+        | MatrixElement::Summary => {
+            break_line(out);
+            push_children(node, out, walk);
+            break_line(out);
+        }
+        _ => push_children(node, out, walk),
+    }
+}
+
+fn push_children(node: &NodeRef, out: &mut Vec<Segment>, walk: &mut Walk) {
+    walk.depth += 1;
+    for child in node.children() {
+        push_node(&child, out, walk);
+    }
+    walk.depth -= 1;
+}
+
+/// Appends to the last segment if it is text, so text runs stay whole.
+fn push_text(out: &mut Vec<Segment>, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    match out.last_mut() {
+        Some(Segment::Text { text: last }) => last.push_str(text),
+        _ => out.push(Segment::Text { text: text.to_string() }),
+    }
+}
+
+/// Starts a new line unless already at the start of one.
+fn break_line(out: &mut Vec<Segment>) {
+    if !ends_line(out) {
+        push_text(out, "\n");
+    }
+}
+
+fn ends_line(out: &[Segment]) -> bool {
+    match out.last() {
+        None => true,
+        Some(Segment::Text { text }) => text.ends_with('\n'),
+        Some(Segment::Code { block, .. }) => *block,
+        Some(_) => false,
+    }
+}
+
+/// HTML whitespace rules: runs of whitespace, newlines included, are one
+/// space, and none is kept at the start of a line.
+fn collapse_whitespace(text: &str, at_line_start: bool) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut space = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            space = true;
+            continue;
+        }
+        if space && !(collapsed.is_empty() && at_line_start) {
+            collapsed.push(' ');
+        }
+        space = false;
+        collapsed.push(c);
+    }
+    if space && !(collapsed.is_empty() && at_line_start) {
+        collapsed.push(' ');
+    }
+    collapsed
+}
+
+/// All text under `node`. Walks the tree with a stack rather than
+/// recursion, since the nesting is up to the sender.
+fn text_content(node: &NodeRef) -> String {
+    let mut content = String::new();
+    let mut stack = vec![node.clone()];
+    while let Some(node) = stack.pop() {
+        match node.as_text() {
+            Some(text) => content.push_str(&text.borrow()),
+            None => stack.extend(node.children().collect::<Vec<_>>().into_iter().rev()),
+        }
+    }
+    content
+}
+
+/// The reason of the first spoiler under `node`, if there is one. Like
+/// `text_content`, walks the tree with a stack.
+fn spoiler_within(node: &NodeRef) -> Option<Option<String>> {
+    let mut stack: Vec<NodeRef> = node.children().collect();
+    while let Some(node) = stack.pop() {
+        if let Some(element) = node.as_element() {
+            if let MatrixElement::Span(span) = element.to_matrix().element {
+                if let Some(reason) = span.spoiler {
+                    return Some(Some(reason.to_string()).filter(|r| !r.trim().is_empty()));
+                }
+            }
+        }
+        stack.extend(node.children());
+    }
+    None
+}
+
+fn push_mention(out: &mut Vec<Segment>, text: String, id: &MatrixId) {
+    let target = match id {
+        MatrixId::User(user_id) => user_id.to_string(),
+        MatrixId::Room(room_id) => room_id.to_string(),
+        MatrixId::RoomAlias(alias) => alias.to_string(),
+        MatrixId::Event(room, _) => room.to_string(),
+        _ => return push_text(out, &text),
+    };
+    out.push(Segment::Mention { text, target });
+}
+
+/// Plain and HTML bodies for an outgoing message using `||spoiler||`
+/// syntax, or `None` when it has no spoilers. Backtick code spans are left
+/// alone, and the plain body shows spoilers as a placeholder.
+pub(crate) fn spoiler_bodies(body: &str) -> Option<(String, String)> {
+    let mut plain = String::with_capacity(body.len());
+    let mut html = String::with_capacity(body.len());
+    let mut has_spoiler = false;
+    let mut rest = body;
+
+    while let Some(start) = rest.find(['`', '|']) {
+        let (before, from) = rest.split_at(start);
+        plain.push_str(before);
+        push_escaped(&mut html, before);
+
+        if let Some(inner) = from.strip_prefix('`') {
+            let Some(end) = inner.find('`') else {
+                plain.push('`');
+                html.push('`');
+                rest = inner;
+                continue;
+            };
+            plain.push_str(&from[..end + 2]);
+            html.push_str("<code>");
+            push_escaped(&mut html, &inner[..end]);
+            html.push_str("</code>");
+            rest = &inner[end + 1..];
+        } else if let Some((spoiler, after)) = from
+            .strip_prefix("||")
+            .and_then(|inner| inner.split_once("||"))
+            .filter(|(spoiler, _)| !spoiler.trim().is_empty())
+        {
+            has_spoiler = true;
+            plain.push_str(SPOILER_PLACEHOLDER);
+            html.push_str("<span data-mx-spoiler>");
+            push_escaped(&mut html, spoiler);
+            html.push_str("</span>");
+            rest = after;
+        } else {
+            plain.push('|');
+            html.push('|');
+            rest = &from[1..];
+        }
+    }
+    plain.push_str(rest);
+    push_escaped(&mut html, rest);
+
+    has_spoiler.then_some((plain, html))
+}
+
+fn push_escaped(html: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            '\n' => html.push_str("<br>"),
+            _ => html.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordinary_markup_is_not_truncated() {
+        let (segments, truncated) = parse_formatted_capped("<p>Hello <b>there</b></p><ul><li>one</li></ul>");
+        assert!(!truncated);
+        assert_eq!(plain_text(&segments), "Hello there\n• one");
+    }
+
+    #[test]
+    fn deep_nesting_keeps_its_text() {
+        let html = format!("{}deep{}", "<div>".repeat(500), "</div>".repeat(500));
+        let (segments, truncated) = parse_formatted_capped(&html);
+        assert!(truncated);
+        assert_eq!(plain_text(&segments).trim(), "deep");
+    }
+
+    #[test]
+    fn huge_numbers_of_elements_are_capped() {
+        let html = r#"<a href="https://example.org">x</a>"#.repeat(5000);
+        let (segments, truncated) = parse_formatted_capped(&html);
+        assert!(truncated);
+        assert!(segments.len() <= MAX_FORMATTED_NODES);
+    }
+
+    #[test]
+    fn a_spoiler_inside_a_link_hides_the_whole_link() {
+        let segments = parse_formatted(
+            r#"See <a href="https://example.org/snape-kills-dumbledore"><span data-mx-spoiler="plot">Snape</span> did it</a>"#,
+        );
+        assert_eq!(plain_text(&segments), "See [Spoiler]");
+        assert_eq!(
+            segments[1],
+            Segment::Spoiler {
+                reason: Some("plot".to_string()),
+                segments: vec![Segment::Link {
+                    text: "Snape did it".to_string(),
+                    url: "https://example.org/snape-kills-dumbledore".to_string(),
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn nested_spoilers_share_the_node_budget() {
+        let html = format!(
+            "{}{}",
+            r#"<span data-mx-spoiler>"#.repeat(100),
+            r#"<a href="https://example.org">x</a>"#.repeat(3000)
+        );
+        let (_, truncated) = parse_formatted_capped(&html);
+        assert!(truncated);
+    }
+}
@@ -0,0 +1,254 @@
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use matrix_sdk::config::SyncSettings;
+use matrix_sdk::ruma::api::client::account::register::{self, RegistrationKind};
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use serde_json::Value;
+use tauri::ipc::Invoke;
+use tauri::{Manager, Runtime, State};
+use tracing::{info, warn};
+
+use crate::auth::{
+    build_client, check_homeserver_reachable, clear_session_state, device_display_name_or_default,
+    map_registration_error, matrix_login, remove_store_dir, LoginResponse,
+};
+use crate::client_config::refresh_client_config;
+use crate::errors::CommandError;
+use crate::session::{save_session, saved_store_dir};
+use crate::settings::load_settings;
+use crate::state::MatrixState;
+use crate::sync_mod::install_event_handlers;
+use crate::uiaa::{default_auth_error, run_with_auth, AuthOperation};
+
+/// Marks a session directory as holding a guest session.
+const GUEST_FILE: &str = "guest";
+/// Session directory name for guest sessions, whose user ID isn't known
+/// until registration. `#` can't appear in a user ID, so no account's
+/// directory has this name.
+const GUEST_STORE_NAME: &str = "#guest";
+
+/// The only commands a guest can use: reading rooms that allow guests,
+/// joining and leaving them, previews, and local app and session
+/// management. Anything else, including commands added later, is refused.
+const GUEST_ALLOWED_COMMANDS: [&str; 55] = [
+    "greet",
+    "matrix_login",
+    "check_session",
+    "logout",
+    "matrix_sync",
+    "get_sync_diagnostics",
+    "get_rooms",
+    "get_rooms_filtered",
+    "get_messages",
+    "fill_gap",
+    "get_event_context",
+    "get_full_event_body",
+    "get_thread_messages",
+    "get_read_marker",
+    "get_url_preview",
+    "get_emoji_packs",
+    "get_sticker_packs",
+    "get_thumbnail",
+    "get_encrypted_media",
+    "fetch_media",
+    "get_media_support",
+    "get_media_cache_stats",
+    "set_media_cache_limit",
+    "get_room_state",
+    "get_room_state_history",
+    "get_room_members",
+    "cancel_member_loading",
+    "autocomplete_members",
+    "get_join_rule",
+    "get_room_directory_visibility",
+    "preview_room",
+    "join_room",
+    "leave_room",
+    "get_room_view_state",
+    "set_room_view_state",
+    "set_active_room",
+    "get_settings",
+    "update_settings",
+    "get_client_config",
+    "get_store_stats",
+    "clear_local_cache",
+    "complete_auth",
+    "cancel_auth",
+    "register_account",
+    "login_with_token",
+    "guest_login",
+    "upgrade_guest_account",
+    "force_takeover",
+    "set_app_lock",
+    "unlock_app",
+    "lock_app",
+    "is_locked",
+    "notify_activity",
+    "set_log_level",
+    "create_debug_bundle",
+];
+
+/// Registers a guest on `homeserver` for a read-only look around: rooms that
+/// allow guests can be joined and read. Fails with `GUEST_ACCESS_DISABLED`
+/// when the server has guest access turned off.
+#[tauri::command]
+pub async fn guest_login(state: State<'_, MatrixState>, homeserver: String) -> Result<LoginResponse, String> {
+    if !homeserver.starts_with("http://") && !homeserver.starts_with("https://") {
+        return Err("Homeserver URL must start with http:// or https://".to_string());
+    }
+
+    check_homeserver_reachable(&homeserver).await?;
+
+    let client = build_client(&state, &homeserver, GUEST_STORE_NAME, false).await?;
+    install_event_handlers(&client, &state).await;
+
+    let mut request = register::v3::Request::new();
+    request.kind = RegistrationKind::Guest;
+    request.initial_device_display_name = Some(device_display_name_or_default(&state.app, None));
+    let response = client.matrix_auth().register(request).await.map_err(|e| {
+        match e.client_api_error_kind() {
+            Some(ErrorKind::Forbidden { .. } | ErrorKind::GuestAccessForbidden) => CommandError::new(
+                "GUEST_ACCESS_DISABLED",
+                "This homeserver does not allow guest access",
+            )
+            .into(),
+            _ => default_auth_error(&e, "join as a guest"),
+        }
+    })?;
+
+    let user_id = response.user_id.to_string();
+    let device_id = response.device_id.map(|d| d.to_string()).unwrap_or_default();
+    info!("Joined {} as guest {}", homeserver, user_id);
+
+    let store_dir = state.data_dir.join(GUEST_STORE_NAME);
+    if let Err(e) = fs::write(store_dir.join(GUEST_FILE), "") {
+        warn!("Failed to mark the session as a guest session: {}", e);
+    }
+
+    client
+        .sync_once(SyncSettings::default())
+        .await
+        .map_err(|e| format!("Initial sync failed: {}", e))?;
+
+    if let Err(e) = save_session(&state.data_dir, &client, &store_dir) {
+        warn!("{}", e);
+    }
+    refresh_client_config(&state.data_dir, &state.client_config, &client, true);
+    load_settings(&state.data_dir, &state.settings, &client).await;
+
+    *state.client.write().await = Some(client);
+    *state.user_id.write().await = Some(user_id.clone());
+    state.is_guest.store(true, Ordering::SeqCst);
+
+    Ok(LoginResponse {
+        success: true,
+        user_id,
+        device_id,
+        message: "Joined as a guest - read only".to_string(),
+        needs_verification: false,
+        is_guest: true,
+    })
+}
+
+/// Turns the guest session into a full account with `username` and
+/// `password`, keeping the rooms it joined, then logs into it on a new
+/// device in place of the guest one. Registration problems come back like
+/// `register_account`'s, including `AUTH_REQUIRED` to finish with
+/// `complete_auth`, which then returns the `LoginResponse`.
+#[tauri::command]
+pub async fn upgrade_guest_account(
+    state: State<'_, MatrixState>,
+    username: String,
+    password: String,
+) -> Result<LoginResponse, String> {
+    if !state.is_guest.load(Ordering::SeqCst) {
+        return Err("Only guest sessions can be upgraded".to_string());
+    }
+    if username.trim().is_empty() || password.is_empty() {
+        return Err("All fields are required".to_string());
+    }
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+    let guest_token = client.access_token().ok_or("Not logged in")?;
+
+    let username = username.trim().trim_start_matches('@').to_string();
+    let username = username.split(':').next().unwrap_or_default().to_string();
+    let homeserver = client.homeserver().as_str().trim_end_matches('/').to_string();
+    let app = state.app.clone();
+
+    let operation: AuthOperation = Arc::new(move |client, auth| {
+        let mut request = register::v3::Request::new();
+        request.username = Some(username.clone());
+        request.password = Some(password.clone());
+        request.guest_access_token = Some(guest_token.clone());
+        // The guest device can't be reused for the account; a fresh login
+        // below replaces it.
+        request.inhibit_login = true;
+        request.auth = auth;
+
+        let password = password.clone();
+        let homeserver = homeserver.clone();
+        let app = app.clone();
+        Box::pin(async move {
+            let response = client.matrix_auth().register(request).await?;
+            info!("Upgraded guest {} to a full account", response.user_id);
+
+            let state = app.state::<MatrixState>();
+            let guest_dir = saved_store_dir(&state.data_dir);
+            clear_session_state(&state).await;
+            if let Some(dir) = guest_dir {
+                if let Err(e) = remove_store_dir(&dir) {
+                    warn!("{}", e);
+                }
+            }
+
+            let login = matrix_login(state, homeserver, response.user_id.to_string(), password, None)
+                .await
+                .map_err(|e| {
+                    let message = format!("Your account was created, but logging in failed: {}", e);
+                    matrix_sdk::Error::UnknownError(message.into())
+                })?;
+            Ok(serde_json::to_value(login)?)
+        })
+    });
+
+    let response: Value = run_with_auth(
+        &state,
+        &client,
+        "create an account",
+        operation,
+        Vec::new(),
+        map_registration_error,
+    )
+    .await?;
+    serde_json::from_value(response).map_err(|e| format!("Unexpected registration result: {}", e))
+}
+
+/// Whether the session directory holds a guest session.
+pub(crate) fn is_guest_store(dir: &Path) -> bool {
+    dir.join(GUEST_FILE).exists()
+}
+
+/// Refuses commands outside `GUEST_ALLOWED_COMMANDS` with
+/// `GUEST_NOT_ALLOWED` in a guest session, before they run.
+pub(crate) fn refuse_for_guests<R: Runtime>(invoke: Invoke<R>) -> Option<Invoke<R>> {
+    if GUEST_ALLOWED_COMMANDS.contains(&invoke.message.command()) {
+        return Some(invoke);
+    }
+    let guest = invoke
+        .message
+        .webview_ref()
+        .try_state::<MatrixState>()
+        .is_some_and(|state| state.is_guest.load(Ordering::SeqCst));
+    if !guest {
+        return Some(invoke);
+    }
+
+    invoke.resolver.reject(String::from(CommandError::new(
+        "GUEST_NOT_ALLOWED",
+        "Guests can only read; create an account to do this",
+    )));
+    None
+}
@@ -0,0 +1,380 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use matrix_sdk::reqwest::Method;
+use matrix_sdk::ruma::api::client::account::request_openid_token;
+use matrix_sdk::ruma::api::client::membership::invite_user::v3::{InvitationRecipient, Request as InviteRequest};
+use matrix_sdk::ruma::api::client::membership::{Invite3pid, Invite3pidInit};
+use matrix_sdk::ruma::thirdparty::Medium;
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId};
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tauri::State;
+use tracing::info;
+
+use crate::account_data::{get_global_json, set_global_json};
+use crate::errors::CommandError;
+use crate::invites::send_invite;
+use crate::outbound::{paced, OutboundClass};
+use crate::state::MatrixState;
+
+pub(crate) const IDENTITY_SERVER_EVENT_TYPE: &str = "m.identity_server";
+
+/// The identity server in use and the access token the user registered
+/// with it.
+#[derive(Clone, Debug)]
+pub struct IdentityServerSession {
+    pub user_id: OwnedUserId,
+    pub base_url: String,
+    pub access_token: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IdentityServerPolicy {
+    pub name: String,
+    pub version: Option<String>,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EmailInviteResult {
+    /// "user" when the email was bound to a Matrix ID, "email" for a
+    /// third-party invite.
+    pub invite_kind: String,
+    pub user_id: Option<String>,
+}
+
+#[tauri::command]
+pub async fn get_identity_server(state: State<'_, MatrixState>) -> Result<Option<String>, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    configured_identity_server(client, &state).await
+}
+
+#[tauri::command]
+pub async fn set_identity_server(
+    state: State<'_, MatrixState>,
+    url: Option<String>,
+) -> Result<Option<String>, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let base_url = match url.map(|u| u.trim().trim_end_matches('/').to_string()) {
+        Some(url) if !url.is_empty() => {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return Err("Identity server URL must start with http:// or https://".to_string());
+            }
+            let response = client
+                .http_client()
+                .get(format!("{}/_matrix/identity/v2", url))
+                .send()
+                .await
+                .map_err(|e| format!("Failed to reach identity server: {}", e))?;
+            if !response.status().is_success() {
+                return Err("That server does not look like an identity server".to_string());
+            }
+            Some(url)
+        }
+        _ => None,
+    };
+
+    set_global_json(client, IDENTITY_SERVER_EVENT_TYPE, &json!({ "base_url": base_url })).await?;
+    *state.identity_server.write().await = None;
+
+    Ok(base_url)
+}
+
+#[tauri::command]
+pub async fn get_identity_server_terms(
+    state: State<'_, MatrixState>,
+) -> Result<Vec<IdentityServerPolicy>, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let base_url = require_identity_server(client, &state).await?;
+    let (_, body) = identity_request(client, Method::GET, &base_url, "/terms", None, None).await?;
+
+    let mut policies = Vec::new();
+    if let Some(map) = body.get("policies").and_then(|p| p.as_object()) {
+        for (name, policy) in map {
+            let version = policy.get("version").and_then(|v| v.as_str()).map(str::to_string);
+            let localized = policy
+                .get("en")
+                .or_else(|| policy.as_object()?.values().find(|v| v.is_object()));
+            if let Some(url) = localized.and_then(|l| l.get("url")).and_then(|u| u.as_str()) {
+                let title = localized
+                    .and_then(|l| l.get("name"))
+                    .and_then(|n| n.as_str())
+                    .unwrap_or(name);
+                policies.push(IdentityServerPolicy {
+                    name: title.to_string(),
+                    version,
+                    url: url.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(policies)
+}
+
+#[tauri::command]
+pub async fn accept_identity_server_terms(
+    state: State<'_, MatrixState>,
+    policy_urls: Vec<String>,
+) -> Result<String, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let session = identity_session(client, &state).await?;
+    identity_request(
+        client,
+        Method::POST,
+        &session.base_url,
+        "/terms",
+        Some(&session.access_token),
+        Some(json!({ "user_accepts": policy_urls })),
+    )
+    .await?;
+
+    Ok("Identity server terms accepted".to_string())
+}
+
+#[tauri::command]
+pub async fn invite_by_email(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    email: String,
+) -> Result<EmailInviteResult, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let email = email.trim().to_lowercase();
+    if !email.contains('@') {
+        return Err("Invalid email address".to_string());
+    }
+
+    let session = identity_session(client, &state).await?;
+
+    if let Some(user_id) = lookup_email(client, &session, &email).await? {
+        info!("{} is bound to {}, sending a normal invite", email, user_id);
+        send_invite(&state, &room, &user_id).await?;
+        return Ok(EmailInviteResult {
+            invite_kind: "user".to_string(),
+            user_id: Some(user_id.to_string()),
+        });
+    }
+
+    let id_server = session
+        .base_url
+        .split("://")
+        .nth(1)
+        .unwrap_or(&session.base_url)
+        .to_string();
+
+    let invite: Invite3pid = Invite3pidInit {
+        id_server,
+        id_access_token: session.access_token.clone(),
+        medium: Medium::Email,
+        address: email.clone(),
+    }
+    .into();
+
+    let description = format!("Invite {} to {}", email, room_id);
+    paced(&state, OutboundClass::Invites, description, |config| {
+        let recipient = InvitationRecipient::ThirdPartyId(invite.clone());
+        client.send(InviteRequest::new(room_id.clone(), recipient)).with_request_config(config)
+    })
+    .await
+    .map_err(|e| format!("Failed to send email invite: {}", e))?;
+
+    Ok(EmailInviteResult {
+        invite_kind: "email".to_string(),
+        user_id: None,
+    })
+}
+
+/// The identity server the account chose, or the homeserver's well-known
+/// default when it never chose one. Choosing none is respected.
+pub(crate) async fn configured_identity_server(
+    client: &Client,
+    state: &MatrixState,
+) -> Result<Option<String>, String> {
+    match get_global_json(client, IDENTITY_SERVER_EVENT_TYPE).await? {
+        Some(content) => Ok(content.get("base_url").and_then(|url| url.as_str()).map(str::to_string)),
+        None => Ok(state
+            .client_config
+            .read()
+            .await
+            .as_ref()
+            .and_then(|config| config.identity_server.clone())),
+    }
+}
+
+async fn require_identity_server(client: &Client, state: &MatrixState) -> Result<String, String> {
+    configured_identity_server(client, state).await?.ok_or_else(|| {
+        CommandError::new(
+            "NO_IDENTITY_SERVER",
+            "No identity server is configured. Choose one in settings to invite by email.",
+        )
+        .into()
+    })
+}
+
+/// Returns the cached identity server session, registering with the server
+/// through an OpenID token when we don't have an access token yet.
+async fn identity_session(
+    client: &Client,
+    state: &MatrixState,
+) -> Result<IdentityServerSession, String> {
+    let base_url = require_identity_server(client, state).await?;
+    let user_id: OwnedUserId = client.user_id().ok_or("No user ID")?.to_owned();
+
+    if let Some(session) = state.identity_server.read().await.as_ref() {
+        if session.base_url == base_url && session.user_id == user_id {
+            return Ok(session.clone());
+        }
+    }
+
+    let openid = client
+        .send(request_openid_token::v3::Request::new(user_id.clone()))
+        .await
+        .map_err(|e| format!("Failed to get OpenID token: {}", e))?;
+
+    let (_, body) = identity_request(
+        client,
+        Method::POST,
+        &base_url,
+        "/account/register",
+        None,
+        Some(json!({
+            "access_token": openid.access_token,
+            "token_type": "Bearer",
+            "matrix_server_name": openid.matrix_server_name,
+            "expires_in": openid.expires_in.as_secs(),
+        })),
+    )
+    .await?;
+
+    let access_token = body
+        .get("token")
+        .and_then(|t| t.as_str())
+        .ok_or("Identity server did not return an access token")?
+        .to_string();
+
+    let session = IdentityServerSession {
+        user_id,
+        base_url,
+        access_token,
+    };
+    *state.identity_server.write().await = Some(session.clone());
+
+    Ok(session)
+}
+
+async fn lookup_email(
+    client: &Client,
+    session: &IdentityServerSession,
+    email: &str,
+) -> Result<Option<OwnedUserId>, String> {
+    let (_, hash_details) = identity_request(
+        client,
+        Method::GET,
+        &session.base_url,
+        "/hash_details",
+        Some(&session.access_token),
+        None,
+    )
+    .await?;
+
+    let pepper = hash_details
+        .get("lookup_pepper")
+        .and_then(|p| p.as_str())
+        .unwrap_or_default();
+    let algorithms: Vec<String> = hash_details
+        .get("algorithms")
+        .and_then(|a| serde_json::from_value(a.clone()).ok())
+        .unwrap_or_default();
+
+    let (algorithm, address) = if algorithms.iter().any(|a| a == "sha256") {
+        let digest = Sha256::digest(format!("{} email {}", email, pepper).as_bytes());
+        ("sha256", URL_SAFE_NO_PAD.encode(digest))
+    } else if algorithms.iter().any(|a| a == "none") {
+        ("none", format!("{} email", email))
+    } else {
+        return Err("Identity server supports no known lookup algorithm".to_string());
+    };
+
+    let (_, body) = identity_request(
+        client,
+        Method::POST,
+        &session.base_url,
+        "/lookup",
+        Some(&session.access_token),
+        Some(json!({
+            "addresses": [address],
+            "algorithm": algorithm,
+            "pepper": pepper,
+        })),
+    )
+    .await?;
+
+    Ok(body
+        .get("mappings")
+        .and_then(|m| m.get(&address))
+        .and_then(|u| u.as_str())
+        .and_then(|u| OwnedUserId::try_from(u).ok()))
+}
+
+async fn identity_request(
+    client: &Client,
+    method: Method,
+    base_url: &str,
+    path: &str,
+    access_token: Option<&str>,
+    body: Option<serde_json::Value>,
+) -> Result<(u16, serde_json::Value), String> {
+    let mut request = client
+        .http_client()
+        .request(method, format!("{}/_matrix/identity/v2{}", base_url, path));
+
+    if let Some(token) = access_token {
+        request = request.bearer_auth(token);
+    }
+    if let Some(body) = body {
+        request = request
+            .header("Content-Type", "application/json")
+            .body(body.to_string());
+    }
+
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("Failed to reach identity server: {}", e))?;
+
+    let status = response.status().as_u16();
+    let text = response.text().await.unwrap_or_default();
+    let json: serde_json::Value = serde_json::from_str(&text).unwrap_or_default();
+
+    if status >= 400 {
+        let errcode = json.get("errcode").and_then(|c| c.as_str()).unwrap_or_default();
+        if errcode == "M_TERMS_NOT_SIGNED" {
+            return Err(CommandError::new(
+                "IDENTITY_SERVER_TERMS_NOT_ACCEPTED",
+                "Accept the identity server's terms of service to invite by email",
+            )
+            .into());
+        }
+        let message = json.get("error").and_then(|e| e.as_str()).unwrap_or("request failed");
+        return Err(format!("Identity server error ({}): {}", status, message));
+    }
+
+    Ok((status, json))
+}
@@ -0,0 +1,191 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use futures::{FutureExt, StreamExt};
+use matrix_sdk::encryption::identities::UserIdentity;
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId};
+use matrix_sdk::{Client, Room};
+use matrix_sdk_crypto::IdentityState;
+use serde::{Deserialize, Serialize};
+use tauri::async_runtime::JoinHandle;
+use tauri::{AppHandle, Emitter, State};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::state::MatrixState;
+
+/// The background task behind `matrix://identity-changed`.
+pub type IdentityWatcherSlot = Arc<RwLock<Option<JoinHandle<()>>>>;
+
+/// A room member whose cryptographic identity changed since we last saw it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ChangedIdentity {
+    pub user_id: String,
+    /// `pin_violation` when the identity changed and the change hasn't been
+    /// acknowledged, or `verification_violation` when the user was verified
+    /// before the change.
+    pub state: String,
+}
+
+/// Payload of `matrix://identity-changed`: the members of `room_id` whose
+/// identity changed, or an empty list once every change is resolved.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomIdentityChanges {
+    pub room_id: String,
+    pub users: Vec<ChangedIdentity>,
+}
+
+/// Accepts a member's new identity, which stops `send_message` from
+/// refusing to send to them.
+#[tauri::command]
+pub async fn acknowledge_identity_change(
+    state: State<'_, MatrixState>,
+    user_id: String,
+) -> Result<(), String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let identity = user_identity(client, user_id).await?;
+    identity
+        .pin()
+        .await
+        .map_err(|e| format!("Failed to accept new identity: {}", e))?;
+
+    info!("Pinned the new identity of {}", identity.user_id());
+
+    Ok(())
+}
+
+/// Drops the requirement that a previously verified member stays verified,
+/// for when their identity changed and they can't verify again right now.
+#[tauri::command]
+pub async fn withdraw_verification(
+    state: State<'_, MatrixState>,
+    user_id: String,
+) -> Result<(), String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let identity = user_identity(client, user_id).await?;
+    identity
+        .withdraw_verification()
+        .await
+        .map_err(|e| format!("Failed to withdraw verification: {}", e))?;
+
+    info!("Withdrew verification of {}", identity.user_id());
+
+    Ok(())
+}
+
+/// Members of an encrypted room whose identity changed without being
+/// acknowledged.
+pub(crate) async fn identity_violations(room: &Room) -> Vec<ChangedIdentity> {
+    if room.encryption_settings().is_none() {
+        return Vec::new();
+    }
+
+    let stream = match room.subscribe_to_identity_status_changes().await {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Failed to check identities in {}: {}", room.room_id(), e);
+            return Vec::new();
+        }
+    };
+
+    // The stream starts with the room's current violations, if there are
+    // any, and otherwise waits for the next change.
+    let mut stream = Box::pin(stream);
+    let current = stream.next().now_or_never().flatten().unwrap_or_default();
+
+    let mut violations: Vec<ChangedIdentity> = current
+        .into_iter()
+        .filter_map(|change| {
+            let state = match change.changed_to {
+                IdentityState::PinViolation => "pin_violation",
+                IdentityState::VerificationViolation => "verification_violation",
+                _ => return None,
+            };
+            Some(ChangedIdentity {
+                user_id: change.user_id.to_string(),
+                state: state.to_string(),
+            })
+        })
+        .collect();
+    violations.sort();
+    violations
+}
+
+/// Watches for identity changes reported by key queries and emits
+/// `matrix://identity-changed` for every encrypted room whose set of changed
+/// members differs from what was last reported. Replaces any earlier watcher.
+pub(crate) async fn watch_identity_changes(
+    app: &AppHandle,
+    watcher: &IdentityWatcherSlot,
+    client: &Client,
+) {
+    let app = app.clone();
+    let client = client.clone();
+    let task: JoinHandle<()> = tauri::async_runtime::spawn(async move {
+        let updates = match client.encryption().user_identities_stream().await {
+            Ok(updates) => updates,
+            Err(e) => {
+                warn!("Failed to watch identity changes: {}", e);
+                return;
+            }
+        };
+        let mut updates = Box::pin(updates);
+        let mut reported: HashMap<OwnedRoomId, Vec<ChangedIdentity>> = HashMap::new();
+
+        while let Some(update) = updates.next().await {
+            let changed: BTreeSet<OwnedUserId> = update.changed.into_keys().collect();
+            if changed.is_empty() {
+                continue;
+            }
+
+            for room in client.joined_rooms() {
+                if room.encryption_settings().is_none() || !has_any_member(&room, &changed).await {
+                    continue;
+                }
+
+                let users = identity_violations(&room).await;
+                let previous = reported.get(room.room_id()).cloned().unwrap_or_default();
+                if users == previous {
+                    continue;
+                }
+
+                reported.insert(room.room_id().to_owned(), users.clone());
+                let _ = app.emit(
+                    "matrix://identity-changed",
+                    RoomIdentityChanges {
+                        room_id: room.room_id().to_string(),
+                        users,
+                    },
+                );
+            }
+        }
+    });
+
+    if let Some(previous) = watcher.write().await.replace(task) {
+        previous.abort();
+    }
+}
+
+async fn has_any_member(room: &Room, users: &BTreeSet<OwnedUserId>) -> bool {
+    for user_id in users {
+        if let Ok(Some(_)) = room.get_member_no_sync(user_id).await {
+            return true;
+        }
+    }
+    false
+}
+
+async fn user_identity(client: &Client, user_id: String) -> Result<UserIdentity, String> {
+    let user_id = OwnedUserId::try_from(user_id).map_err(|e| format!("Invalid user ID: {}", e))?;
+
+    client
+        .encryption()
+        .get_user_identity(&user_id)
+        .await
+        .map_err(|e| format!("Failed to get identity: {}", e))?
+        .ok_or_else(|| format!("{} has no cross-signing identity", user_id))
+}
@@ -0,0 +1,259 @@
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use matrix_sdk::ruma::MilliSecondsSinceUnixEpoch;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tauri::State;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::errors::CommandError;
+use crate::state::MatrixState;
+
+/// Written into a session directory while a process has its stores open.
+const LOCK_FILE: &str = "instance.lock";
+
+/// The session directory this process holds the lock of.
+pub type StoreLockSlot = Arc<RwLock<Option<PathBuf>>>;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct LockOwner {
+    pid: u32,
+    acquired_at: u64,
+    /// When the process started, as the OS tells it, so a new process that
+    /// was given the same ID isn't taken for the owner.
+    #[serde(default)]
+    started: Option<String>,
+}
+
+impl LockOwner {
+    fn alive(&self) -> bool {
+        if !process_alive(self.pid) {
+            return false;
+        }
+        match (&self.started, process_start(self.pid)) {
+            (Some(started), Some(current)) => *started == current,
+            _ => true,
+        }
+    }
+}
+
+/// Claims `dir` for this process before its sqlite stores are opened. Fails
+/// with `ALREADY_RUNNING` when another app instance holds it, or held it and
+/// crashed; `details.stale` tells the two apart, and a stale lock can be
+/// broken with `force_takeover`.
+pub(crate) async fn lock_store(state: &MatrixState, dir: &Path) -> Result<(), String> {
+    check_store_lock(dir)?;
+
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create data directory: {}", e))?;
+    let pid = std::process::id();
+    let owner = LockOwner {
+        pid,
+        acquired_at: MilliSecondsSinceUnixEpoch::now().get().into(),
+        started: process_start(pid),
+    };
+    let json = serde_json::to_string(&owner).map_err(|e| e.to_string())?;
+    if read_owner(dir).is_some_and(|owner| owner.pid == pid) {
+        fs::write(dir.join(LOCK_FILE), json).map_err(|e| format!("Failed to lock session directory: {}", e))?;
+    } else {
+        create_lock(dir, &json)?;
+    }
+
+    let previous = state.store_lock.write().await.replace(dir.to_path_buf());
+    if let Some(previous) = previous.filter(|previous| previous != dir) {
+        remove_own_lock(&previous);
+    }
+    Ok(())
+}
+
+/// Creates the lock file, failing if one appeared since it was checked. It
+/// is written under a name of its own and linked into place, which fails
+/// when the lock exists, so another instance never sees it half written.
+fn create_lock(dir: &Path, json: &str) -> Result<(), String> {
+    let lock_error = |e: std::io::Error| format!("Failed to lock session directory: {}", e);
+    let temp = dir.join(format!("{}.{}", LOCK_FILE, std::process::id()));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp)
+        .map_err(lock_error)?;
+    file.write_all(json.as_bytes()).map_err(lock_error)?;
+    drop(file);
+
+    let linked = fs::hard_link(&temp, dir.join(LOCK_FILE));
+    let _ = fs::remove_file(&temp);
+    match linked {
+        Ok(()) => Ok(()),
+        // Another instance got there first.
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            check_store_lock(dir)?;
+            Err(lock_error(e))
+        }
+        Err(e) => Err(lock_error(e)),
+    }
+}
+
+/// Fails like `lock_store` if another instance holds `dir`, without taking
+/// it. For checking before a session directory is cleared.
+pub(crate) fn check_store_lock(dir: &Path) -> Result<(), String> {
+    let Some(owner) = read_owner(dir) else {
+        return Ok(());
+    };
+    if owner.pid == std::process::id() {
+        return Ok(());
+    }
+
+    let running = owner.alive();
+    let message = if running {
+        "The app is already running with this account in another window"
+    } else {
+        "The app didn't close cleanly last time; take over its session to continue"
+    };
+    Err(CommandError::new("ALREADY_RUNNING", message)
+        .with_details(json!({ "pid": owner.pid, "stale": !running }))
+        .into())
+}
+
+/// Gives up this process's session directory lock, at logout and exit.
+pub(crate) async fn unlock_store(state: &MatrixState) {
+    if let Some(dir) = state.store_lock.write().await.take() {
+        remove_own_lock(&dir);
+    }
+}
+
+/// Breaks the locks left behind by app instances that crashed, after
+/// checking their processes are gone. Refuses with `ALREADY_RUNNING` while
+/// one of them is still running. Returns how many locks were broken.
+#[tauri::command]
+pub async fn force_takeover(state: State<'_, MatrixState>) -> Result<usize, String> {
+    let entries = fs::read_dir(&state.data_dir).map_err(|e| format!("Failed to read data directory: {}", e))?;
+
+    let mut broken = 0;
+    for dir in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+        let Some(owner) = read_owner(&dir) else {
+            continue;
+        };
+        if owner.pid == std::process::id() {
+            continue;
+        }
+        if owner.alive() {
+            return Err(CommandError::new(
+                "ALREADY_RUNNING",
+                "The other app instance is still running; close it first",
+            )
+            .with_details(json!({ "pid": owner.pid, "stale": false }))
+            .into());
+        }
+
+        fs::remove_file(dir.join(LOCK_FILE)).map_err(|e| format!("Failed to break lock: {}", e))?;
+        warn!("Broke the lock of crashed process {} on {:?}", owner.pid, dir);
+        broken += 1;
+    }
+
+    info!("Took over {} session directories", broken);
+    Ok(broken)
+}
+
+fn read_owner(dir: &Path) -> Option<LockOwner> {
+    let json = fs::read_to_string(dir.join(LOCK_FILE)).ok()?;
+    serde_json::from_str(&json)
+        .inspect_err(|e| warn!("Ignoring unreadable lock file in {:?}: {}", dir, e))
+        .ok()
+}
+
+/// Removes the lock in `dir` if this process still owns it.
+fn remove_own_lock(dir: &Path) {
+    if read_owner(dir).is_some_and(|owner| owner.pid == std::process::id()) {
+        if let Err(e) = fs::remove_file(dir.join(LOCK_FILE)) {
+            warn!("Failed to remove lock file: {}", e);
+        }
+    }
+}
+
+/// Whether a process with this ID exists. Errs towards yes when it can't be
+/// told, so a live instance is never taken over. `ps` finds processes of
+/// other users too, where `kill -0` would be refused.
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    if Path::new("/proc/self").exists() {
+        return Path::new(&format!("/proc/{}", pid)).exists();
+    }
+    Command::new("ps")
+        .args(["-p", &pid.to_string(), "-o", "pid="])
+        .output()
+        .map(|output| output.status.success() || !output.stderr.is_empty())
+        .unwrap_or(true)
+}
+
+#[cfg(windows)]
+fn process_alive(pid: u32) -> bool {
+    Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/NH"])
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).contains(&pid.to_string()))
+        .unwrap_or(true)
+}
+
+/// When the process with this ID started, in whatever form the OS gives
+/// it; `None` when that can't be read.
+#[cfg(unix)]
+fn process_start(pid: u32) -> Option<String> {
+    if let Ok(stat) = fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        return stat_start_time(&stat);
+    }
+    let output = Command::new("ps")
+        .args(["-p", &pid.to_string(), "-o", "lstart="])
+        .output()
+        .ok()?;
+    let started = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !started.is_empty()).then_some(started)
+}
+
+#[cfg(windows)]
+fn process_start(_pid: u32) -> Option<String> {
+    None
+}
+
+/// The start time field of a `/proc/<pid>/stat` line, in clock ticks since
+/// boot. The command name before it may contain spaces and parentheses, so
+/// fields are counted from the last `)`.
+#[cfg(unix)]
+fn stat_start_time(stat: &str) -> Option<String> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19).map(str::to_string)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_time_is_read_past_the_command_name() {
+        let stat = "4242 (my (odd) app) S 1 4242 4242 0 -1 4194560 1000 0 0 0 \
+                    12 3 0 0 20 0 4 0 987654 1000000 200 18446744073709551615";
+        assert_eq!(stat_start_time(stat).as_deref(), Some("987654"));
+        assert_eq!(stat_start_time("4242 (app"), None);
+    }
+
+    #[test]
+    fn own_process_is_alive_and_its_start_stable() {
+        let pid = std::process::id();
+        let owner = LockOwner {
+            pid,
+            acquired_at: 0,
+            started: process_start(pid),
+        };
+        assert!(owner.alive());
+
+        let reused = LockOwner {
+            started: Some("not when it started".to_string()),
+            ..owner
+        };
+        assert!(!reused.alive());
+    }
+}
@@ -0,0 +1,577 @@
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+
+use matrix_sdk::deserialized_responses::RawAnySyncOrStrippedState;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::api::client::membership::invite_user::v3::{InvitationRecipient, Request as InviteRequest};
+use matrix_sdk::ruma::events::room::history_visibility::HistoryVisibility;
+use matrix_sdk::ruma::events::room::member::{MembershipState, StrippedRoomMemberEvent};
+use matrix_sdk::ruma::events::StateEventType;
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId, UserId};
+use matrix_sdk::{Client, Room};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tauri::{Emitter, State};
+use tracing::{info, warn};
+
+use crate::account_data::{get_global_json, set_global_json, APP_NAMESPACE};
+use crate::outbound::{note_rate_limit, pace, paced, paced_request_config, OutboundClass};
+use crate::state::MatrixState;
+
+/// Rate limits waited out per invite before giving up on it.
+const MAX_INVITE_RETRIES: u32 = 3;
+
+pub(crate) fn invite_filter_type() -> String {
+    format!("{}.invite_filter", APP_NAMESPACE)
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InviteFilterAction {
+    #[default]
+    Hide,
+    AutoReject,
+    AutoRejectAndIgnore,
+}
+
+/// Invite spam rules, stored in account data so they apply on every device.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct InviteFilterSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Server names whose users' invites are filtered.
+    #[serde(default)]
+    pub blocked_servers: Vec<String>,
+    /// Filter invites from users we share no joined room with.
+    #[serde(default)]
+    pub block_without_shared_rooms: bool,
+    /// Filter invites from user IDs matching this regex.
+    #[serde(default)]
+    pub user_id_pattern: Option<String>,
+    #[serde(default)]
+    pub action: InviteFilterAction,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InviteInfo {
+    pub room_id: String,
+    pub name: Option<String>,
+    pub inviter: Option<String>,
+    pub inviter_display_name: Option<String>,
+    /// Whether the invite filter hides this invite.
+    pub filtered: bool,
+    /// `blocked_server`, `no_shared_rooms` or `user_id_pattern`.
+    pub filter_reason: Option<String>,
+    /// The reason the inviter gave, if their client lets them set one.
+    pub reason: Option<String>,
+    /// When we were invited, if the server sent the invite's timestamp.
+    pub invited_at: Option<u64>,
+    pub is_direct: bool,
+    /// Names of the joined rooms we share with the inviter.
+    pub shared_rooms: Vec<String>,
+}
+
+/// The fields of our own invite member event that `InviteInfo` reports.
+/// Stripped invite state usually omits `origin_server_ts`.
+#[derive(Deserialize, Default)]
+struct OwnInviteEvent {
+    #[serde(default)]
+    content: OwnInviteContent,
+    origin_server_ts: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+struct OwnInviteContent {
+    reason: Option<String>,
+    #[serde(default)]
+    is_direct: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InviteUserResult {
+    pub user_id: String,
+    /// Whether the room's encrypted history was offered to the invitee.
+    pub history_share_attempted: bool,
+    pub history_shared: bool,
+    /// Why history wasn't shared, when it was asked for.
+    pub history_share_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InviteRuleResult {
+    pub matched: bool,
+    pub reason: Option<String>,
+    pub action: Option<InviteFilterAction>,
+}
+
+#[tauri::command]
+pub async fn get_invite_filter(
+    state: State<'_, MatrixState>,
+) -> Result<InviteFilterSettings, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    load_invite_filter(client).await
+}
+
+#[tauri::command]
+pub async fn set_invite_filter(
+    state: State<'_, MatrixState>,
+    settings: InviteFilterSettings,
+) -> Result<InviteFilterSettings, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    if let Some(pattern) = settings.user_id_pattern.as_deref() {
+        Regex::new(pattern).map_err(|e| format!("Invalid user ID pattern: {}", e))?;
+    }
+
+    let content = serde_json::to_value(&settings)
+        .map_err(|e| format!("Failed to serialize invite filter: {}", e))?;
+    set_global_json(client, &invite_filter_type(), &content).await?;
+
+    Ok(settings)
+}
+
+/// Pending invites, with the ones the invite filter hides marked `filtered`
+/// so the frontend can still reveal them.
+#[tauri::command]
+pub async fn get_invites(state: State<'_, MatrixState>) -> Result<Vec<InviteInfo>, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let settings = load_invite_filter(client).await?;
+    let mut invites = Vec::new();
+
+    for room in client.invited_rooms() {
+        let inviter = room.invite_details().await.ok().and_then(|i| i.inviter);
+        let filter_reason = match (&inviter, settings.enabled) {
+            (Some(inviter), true) => match_invite_rule(client, &settings, inviter.user_id()).await,
+            _ => None,
+        };
+        let invite = own_invite_event(client, &room).await;
+
+        let mut shared_rooms = Vec::new();
+        if let Some(inviter) = &inviter {
+            for shared in rooms_shared_with(client, inviter.user_id()).await {
+                if let Ok(name) = shared.display_name().await {
+                    shared_rooms.push(name.to_string());
+                }
+            }
+        }
+
+        invites.push(InviteInfo {
+            room_id: room.room_id().to_string(),
+            name: room.display_name().await.ok().map(|n| n.to_string()),
+            inviter: inviter.as_ref().map(|m| m.user_id().to_string()),
+            inviter_display_name: inviter
+                .as_ref()
+                .and_then(|m| m.display_name().map(str::to_string)),
+            filtered: filter_reason.is_some(),
+            filter_reason,
+            reason: invite.content.reason,
+            invited_at: invite.origin_server_ts,
+            is_direct: invite.content.is_direct,
+            shared_rooms,
+        });
+    }
+
+    Ok(invites)
+}
+
+/// Invites `user_id` to the room. With `share_history`, the keys for the
+/// room's encrypted history are sent to the invitee first (MSC4268) so they
+/// can read it after joining; that needs cross-signing and a room whose
+/// history visibility is `shared` or `world_readable`. The invite is sent
+/// even when sharing history isn't possible.
+#[tauri::command]
+pub async fn invite_user(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    user_id: String,
+    share_history: bool,
+) -> Result<InviteUserResult, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+    let user_id: OwnedUserId = user_id
+        .parse()
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
+
+    let blocker = if share_history {
+        history_share_blocker(client, &room).await
+    } else {
+        None
+    };
+    let mut result = InviteUserResult {
+        user_id: user_id.to_string(),
+        history_share_attempted: share_history && blocker.is_none(),
+        history_shared: false,
+        history_share_error: blocker,
+    };
+
+    if !result.history_share_attempted {
+        send_invite(&state, &room, &user_id).await?;
+        return Ok(result);
+    }
+
+    // With history sharing enabled on the client, the SDK uploads the key
+    // bundle and sends it to the invitee before inviting them. It takes no
+    // request config, so a 429 here may be retried by the SDK itself.
+    let description = format!("Invite {} to {}", user_id, room_id);
+    match paced(&state, OutboundClass::Invites, description, |_| room.invite_user_by_id(&user_id)).await {
+        Ok(()) => {
+            info!("Invited {} to {} and shared history", user_id, room_id);
+            result.history_shared = true;
+        }
+        Err(e) => {
+            warn!("Sharing history with {} failed: {}", user_id, e);
+            send_invite(&state, &room, &user_id).await?;
+            result.history_share_error = Some(format!("Failed to share history: {}", e));
+        }
+    }
+
+    Ok(result)
+}
+
+/// Outcome of one invite in `invite_users`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BatchInviteResult {
+    pub user_id: String,
+    /// `invited`, `already_in_room`, `invalid_id`, `rate_limited`,
+    /// `permission_denied`, `failed` or `cancelled`.
+    pub status: String,
+    pub error: Option<String>,
+}
+
+/// Payload of `matrix://invite-progress`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InviteProgress {
+    pub room_id: String,
+    pub done: usize,
+    pub total: usize,
+}
+
+/// Invites several users one at a time, paced by the outbound invite limit
+/// so large batches don't trip the server's rate limit. Every user gets a result;
+/// one failing invite doesn't stop the rest. Progress is reported through
+/// `matrix://invite-progress`, and `cancel_invites` stops the batch.
+#[tauri::command]
+pub async fn invite_users(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    user_ids: Vec<String>,
+) -> Result<Vec<BatchInviteResult>, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let mut seen = HashSet::new();
+    let user_ids: Vec<String> = user_ids
+        .into_iter()
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty() && seen.insert(id.clone()))
+        .collect();
+
+    let result = |user_id: &str, status: &str, error: Option<String>| BatchInviteResult {
+        user_id: user_id.to_string(),
+        status: status.to_string(),
+        error,
+    };
+
+    // Validate everything before sending anything. Valid users start out
+    // as cancelled and are updated as their invites go out.
+    let mut results = Vec::with_capacity(user_ids.len());
+    let mut pending = Vec::new();
+    for user_id in &user_ids {
+        match OwnedUserId::try_from(user_id.as_str()) {
+            Ok(parsed) => {
+                pending.push((results.len(), parsed));
+                results.push(result(user_id, "cancelled", None));
+            }
+            Err(e) => results.push(result(user_id, "invalid_id", Some(e.to_string()))),
+        }
+    }
+
+    state.invites_cancelled.store(false, Ordering::SeqCst);
+    let total = pending.len();
+
+    for (done, (index, user_id)) in pending.into_iter().enumerate() {
+        if state.invites_cancelled.load(Ordering::SeqCst) {
+            break;
+        }
+        results[index] = if is_in_room(&room, &user_id).await {
+            result(user_id.as_str(), "already_in_room", None)
+        } else {
+            match invite_with_retry(&state, &room, &user_id).await {
+                Ok(()) => result(user_id.as_str(), "invited", None),
+                Err((status, error)) => result(user_id.as_str(), status, Some(error)),
+            }
+        };
+
+        let _ = state.app.emit(
+            "matrix://invite-progress",
+            InviteProgress {
+                room_id: room_id.to_string(),
+                done: done + 1,
+                total,
+            },
+        );
+    }
+
+    let invited = results.iter().filter(|r| r.status == "invited").count();
+    info!("Invited {} of {} users to {}", invited, results.len(), room_id);
+
+    Ok(results)
+}
+
+/// Stops a running `invite_users` batch before its next invite.
+#[tauri::command]
+pub async fn cancel_invites(state: State<'_, MatrixState>) -> Result<(), String> {
+    state.invites_cancelled.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+async fn is_in_room(room: &Room, user_id: &UserId) -> bool {
+    matches!(
+        room.get_member_no_sync(user_id).await,
+        Ok(Some(member)) if matches!(member.membership(), MembershipState::Join | MembershipState::Invite)
+    )
+}
+
+/// Sends one invite, waiting out rate limits a few times before giving up.
+/// Errors come with the status to report.
+async fn invite_with_retry(
+    state: &MatrixState,
+    room: &Room,
+    user_id: &UserId,
+) -> Result<(), (&'static str, String)> {
+    let mut retries = 0;
+
+    loop {
+        pace(state, OutboundClass::Invites, format!("Invite {} to {}", user_id, room.room_id())).await;
+        let recipient = InvitationRecipient::UserId {
+            user_id: user_id.to_owned(),
+        };
+        let error = match room
+            .client()
+            .send(InviteRequest::new(room.room_id().to_owned(), recipient))
+            .with_request_config(paced_request_config())
+            .await
+        {
+            Ok(_) => {
+                room.mark_members_missing();
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+
+        let kind = error.client_api_error_kind();
+        // The next `pace` waits the delay out.
+        if let Some(delay) = note_rate_limit(state, OutboundClass::Invites, kind).await {
+            if retries == MAX_INVITE_RETRIES {
+                return Err(("rate_limited", "The server kept rate-limiting invites".to_string()));
+            }
+            retries += 1;
+            warn!("Rate limited while inviting {}, waiting {:?}", user_id, delay);
+            continue;
+        }
+
+        return Err(match kind {
+            Some(ErrorKind::Forbidden { .. }) => ("permission_denied", error.to_string()),
+            _ => ("failed", error.to_string()),
+        });
+    }
+}
+
+/// Invites without sharing room history, bypassing the SDK's
+/// share-on-invite.
+pub(crate) async fn send_invite(state: &MatrixState, room: &Room, user_id: &UserId) -> Result<(), String> {
+    let recipient = InvitationRecipient::UserId {
+        user_id: user_id.to_owned(),
+    };
+    let description = format!("Invite {} to {}", user_id, room.room_id());
+    paced(state, OutboundClass::Invites, description, |config| {
+        let request = InviteRequest::new(room.room_id().to_owned(), recipient.clone());
+        room.client().send(request).with_request_config(config)
+    })
+    .await
+    .map_err(|e| format!("Failed to invite {}: {}", user_id, e))?;
+
+    // Make the next send fetch members again, so the invitee gets the room key.
+    room.mark_members_missing();
+
+    Ok(())
+}
+
+/// Why the room's history can't be shared on invite, if it can't.
+async fn history_share_blocker(client: &Client, room: &Room) -> Option<String> {
+    if room.encryption_settings().is_none() {
+        return Some("The room is not encrypted".to_string());
+    }
+
+    if !matches!(
+        room.history_visibility_or_default(),
+        HistoryVisibility::Shared | HistoryVisibility::WorldReadable
+    ) {
+        return Some("The room's history is not visible to new members".to_string());
+    }
+
+    let own_identity = match client.user_id() {
+        Some(user_id) => client.encryption().get_user_identity(user_id).await.ok().flatten(),
+        None => None,
+    };
+    if own_identity.is_none() {
+        return Some("Set up cross-signing to share room history".to_string());
+    }
+
+    None
+}
+
+/// What the invite filter would do with an invite from `user_id`. Uses
+/// `settings` when given, so rules can be tried before saving, otherwise the
+/// saved rules; either way `enabled` is ignored.
+#[tauri::command]
+pub async fn test_invite_rule(
+    state: State<'_, MatrixState>,
+    user_id: String,
+    settings: Option<InviteFilterSettings>,
+) -> Result<InviteRuleResult, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let user_id: OwnedUserId = user_id
+        .parse()
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
+
+    let settings = match settings {
+        Some(settings) => settings,
+        None => load_invite_filter(client).await?,
+    };
+
+    let reason = match_invite_rule(client, &settings, &user_id).await;
+
+    Ok(InviteRuleResult {
+        matched: reason.is_some(),
+        action: reason.as_ref().map(|_| settings.action),
+        reason,
+    })
+}
+
+/// Sync handler applying the invite filter to invites as they arrive.
+pub(crate) async fn on_stripped_member(event: StrippedRoomMemberEvent, room: Room, client: Client) {
+    if event.content.membership != MembershipState::Invite
+        || Some(event.state_key.as_ref()) != client.user_id()
+    {
+        return;
+    }
+
+    let settings = match load_invite_filter(&client).await {
+        Ok(settings) if settings.enabled => settings,
+        _ => return,
+    };
+
+    let Some(reason) = match_invite_rule(&client, &settings, &event.sender).await else {
+        return;
+    };
+
+    info!(
+        "Filtered invite to {} from {} ({})",
+        room.room_id(),
+        event.sender,
+        reason
+    );
+
+    if settings.action == InviteFilterAction::Hide {
+        return;
+    }
+
+    if let Err(e) = room.leave().await {
+        warn!("Failed to reject invite to {}: {}", room.room_id(), e);
+        return;
+    }
+
+    if settings.action == InviteFilterAction::AutoRejectAndIgnore {
+        if let Err(e) = client.account().ignore_user(&event.sender).await {
+            warn!("Failed to ignore {}: {}", event.sender, e);
+        }
+    }
+}
+
+async fn load_invite_filter(client: &Client) -> Result<InviteFilterSettings, String> {
+    Ok(get_global_json(client, &invite_filter_type())
+        .await?
+        .and_then(|content| serde_json::from_value(content).ok())
+        .unwrap_or_default())
+}
+
+/// The first rule that matches `inviter`, if any.
+async fn match_invite_rule(
+    client: &Client,
+    settings: &InviteFilterSettings,
+    inviter: &UserId,
+) -> Option<String> {
+    let server = inviter.server_name().as_str();
+    if settings
+        .blocked_servers
+        .iter()
+        .any(|s| s.trim().eq_ignore_ascii_case(server))
+    {
+        return Some("blocked_server".to_string());
+    }
+
+    if let Some(pattern) = settings.user_id_pattern.as_deref() {
+        if Regex::new(pattern).is_ok_and(|re| re.is_match(inviter.as_str())) {
+            return Some("user_id_pattern".to_string());
+        }
+    }
+
+    if settings.block_without_shared_rooms && !shares_room_with(client, inviter).await {
+        return Some("no_shared_rooms".to_string());
+    }
+
+    None
+}
+
+async fn shares_room_with(client: &Client, user_id: &UserId) -> bool {
+    !rooms_shared_with(client, user_id).await.is_empty()
+}
+
+/// The joined rooms `user_id` is also joined to.
+async fn rooms_shared_with(client: &Client, user_id: &UserId) -> Vec<Room> {
+    let mut shared = Vec::new();
+    for room in client.joined_rooms() {
+        if let Ok(Some(member)) = room.get_member_no_sync(user_id).await {
+            if *member.membership() == MembershipState::Join {
+                shared.push(room);
+            }
+        }
+    }
+    shared
+}
+
+/// Our own `m.room.member` invite event in an invited room.
+async fn own_invite_event(client: &Client, room: &Room) -> OwnInviteEvent {
+    let Some(user_id) = client.user_id() else {
+        return OwnInviteEvent::default();
+    };
+    let raw = room
+        .get_state_event(StateEventType::RoomMember, user_id.as_str())
+        .await
+        .ok()
+        .flatten();
+
+    match raw {
+        Some(RawAnySyncOrStrippedState::Stripped(raw)) => raw.deserialize_as_unchecked().ok(),
+        Some(RawAnySyncOrStrippedState::Sync(raw)) => raw.deserialize_as_unchecked().ok(),
+        None => None,
+    }
+    .unwrap_or_default()
+}
@@ -0,0 +1,173 @@
+use matrix_sdk::ruma::events::room::join_rules::{AllowRule, JoinRule, Restricted};
+use matrix_sdk::ruma::events::StateEventType;
+use matrix_sdk::ruma::{OwnedRoomId, RoomVersionId};
+use matrix_sdk::{Client, Room};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tracing::info;
+
+use crate::errors::CommandError;
+use crate::state::MatrixState;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AllowedSpace {
+    pub room_id: String,
+    /// The space's name when we are in it, otherwise `None`.
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JoinRuleInfo {
+    /// `public`, `invite`, `knock`, `restricted`, `knock_restricted` or
+    /// `private`.
+    pub rule: String,
+    /// Spaces whose members may join, for the restricted rules.
+    pub allowed_spaces: Vec<AllowedSpace>,
+    pub room_version: String,
+    pub supports_knock: bool,
+    pub supports_restricted: bool,
+    pub can_change: bool,
+}
+
+#[tauri::command]
+pub async fn get_join_rule(
+    state: State<'_, MatrixState>,
+    room_id: String,
+) -> Result<JoinRuleInfo, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let join_rule = room.join_rule().unwrap_or(JoinRule::Invite);
+    let allowed_room_ids: Vec<OwnedRoomId> = match &join_rule {
+        JoinRule::Restricted(restricted) | JoinRule::KnockRestricted(restricted) => restricted
+            .allow
+            .iter()
+            .filter_map(|rule| match rule {
+                AllowRule::RoomMembership(membership) => Some(membership.room_id.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let mut allowed_spaces = Vec::new();
+    for space_id in allowed_room_ids {
+        allowed_spaces.push(AllowedSpace {
+            name: space_name(client, &space_id).await,
+            room_id: space_id.to_string(),
+        });
+    }
+
+    let (version, supports_knock, supports_restricted) = version_support(&room);
+
+    Ok(JoinRuleInfo {
+        rule: join_rule.as_str().to_string(),
+        allowed_spaces,
+        room_version: version.to_string(),
+        supports_knock,
+        supports_restricted,
+        can_change: can_change_join_rule(client, &room).await,
+    })
+}
+
+/// Sets the room's join rule to `public`, `invite`, `knock` or `restricted`.
+/// A restricted room lets members of any of `allowed_space_ids` join without
+/// an invite; that rule needs room version 8 or later.
+#[tauri::command]
+pub async fn set_join_rule(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    rule: String,
+    allowed_space_ids: Option<Vec<String>>,
+) -> Result<String, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    if !can_change_join_rule(client, &room).await {
+        return Err(CommandError::new(
+            "INSUFFICIENT_POWER_LEVEL",
+            "You don't have permission to change who can join this room",
+        )
+        .into());
+    }
+
+    let (version, supports_knock, supports_restricted) = version_support(&room);
+    let unsupported = |rule: &str| -> String {
+        CommandError::new(
+            "UNSUPPORTED_ROOM_VERSION",
+            format!(
+                "Room version {} does not support the {} join rule; upgrade the room first",
+                version, rule
+            ),
+        )
+        .into()
+    };
+
+    let join_rule = match rule.as_str() {
+        "public" => JoinRule::Public,
+        "invite" => JoinRule::Invite,
+        "knock" if !supports_knock => return Err(unsupported("knock")),
+        "knock" => JoinRule::Knock,
+        "restricted" if !supports_restricted => return Err(unsupported("restricted")),
+        "restricted" => {
+            let allow = allowed_space_ids
+                .unwrap_or_default()
+                .into_iter()
+                .map(|id| {
+                    OwnedRoomId::try_from(id)
+                        .map(AllowRule::room_membership)
+                        .map_err(|e| format!("Invalid space ID: {}", e))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if allow.is_empty() {
+                return Err("Choose at least one space whose members may join".to_string());
+            }
+            JoinRule::Restricted(Restricted::new(allow))
+        }
+        other => return Err(format!("Unsupported join rule: {}", other)),
+    };
+
+    room.privacy_settings()
+        .update_join_rule(join_rule)
+        .await
+        .map_err(|e| format!("Failed to change join rule: {}", e))?;
+
+    info!("Set join rule of {} to {}", room_id, rule);
+
+    Ok(format!("Join rule set to {}", rule))
+}
+
+/// The room's version and whether it allows the `knock` and `restricted`
+/// join rules. Rooms without a known version are treated as version 1.
+fn version_support(room: &Room) -> (RoomVersionId, bool, bool) {
+    let version = room.version().unwrap_or(RoomVersionId::V1);
+    let (knock, restricted) = version
+        .rules()
+        .map(|rules| (rules.authorization.knocking, rules.authorization.restricted_join_rule))
+        .unwrap_or_default();
+    (version, knock, restricted)
+}
+
+async fn can_change_join_rule(client: &Client, room: &Room) -> bool {
+    let Some(user_id) = client.user_id() else {
+        return false;
+    };
+    room.power_levels()
+        .await
+        .is_ok_and(|levels| levels.user_can_send_state(user_id, StateEventType::RoomJoinRules))
+}
+
+async fn space_name(client: &Client, space_id: &OwnedRoomId) -> Option<String> {
+    let space = client.get_room(space_id)?;
+    space.display_name().await.ok().map(|name| name.to_string())
+}
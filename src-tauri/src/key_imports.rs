@@ -0,0 +1,111 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use futures::StreamExt;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::Client;
+use matrix_sdk_crypto::store::types::RoomKeyInfo;
+use serde::{Deserialize, Serialize};
+use tauri::Emitter;
+use tokio::sync::oneshot;
+use tracing::{debug, info};
+
+use crate::state::MatrixState;
+
+/// How long keys keep being collected after the import call returns, for
+/// batches the crypto store is still saving.
+const IMPORT_QUIET_PERIOD: Duration = Duration::from_secs(3);
+
+/// Payload of `matrix://keys-imported`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeysImported {
+    /// Most sessions first.
+    pub rooms: Vec<RoomKeysImported>,
+    pub sessions: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomKeysImported {
+    pub room_id: String,
+    pub sessions: usize,
+}
+
+/// Collects the room keys stored while a recovery or backup download runs.
+/// Dropping it marks the import as done.
+pub(crate) struct KeyImportWatch {
+    _done: oneshot::Sender<()>,
+}
+
+/// Starts watching for imported room keys; call right before the import.
+/// Once it is done, the rooms that got keys have their cached first page
+/// and room info dropped, so the next `get_messages` fetches and decrypts
+/// again, and `matrix://keys-imported` tells the frontend to reload them.
+pub(crate) async fn watch_key_import(state: &MatrixState, client: &Client) -> Option<KeyImportWatch> {
+    let stream = client.encryption().room_keys_received_stream().await?;
+    let (done_tx, mut done) = oneshot::channel::<()>();
+
+    let app = state.app.clone();
+    let prefetched_pages = state.prefetched_pages.clone();
+    let page_boundaries = state.page_boundaries.clone();
+    let room_cache = state.room_cache.clone();
+    tauri::async_runtime::spawn(async move {
+        let mut stream = Box::pin(stream);
+        let mut sessions: BTreeMap<OwnedRoomId, BTreeSet<String>> = BTreeMap::new();
+        let mut collect = |batch: Vec<RoomKeyInfo>| {
+            for key in batch {
+                sessions.entry(key.room_id).or_default().insert(key.session_id);
+            }
+        };
+
+        loop {
+            tokio::select! {
+                _ = &mut done => break,
+                batch = stream.next() => match batch {
+                    Some(Ok(batch)) => collect(batch),
+                    // Lagged behind; those keys still decrypt, we just
+                    // can't name their rooms.
+                    Some(Err(_)) => continue,
+                    None => break,
+                },
+            }
+        }
+        while let Ok(Some(batch)) = tokio::time::timeout(IMPORT_QUIET_PERIOD, stream.next()).await {
+            if let Ok(batch) = batch {
+                collect(batch);
+            }
+        }
+
+        if sessions.is_empty() {
+            debug!("Key import finished without new room keys");
+            return;
+        }
+
+        {
+            let mut pages = prefetched_pages.write().await;
+            let mut boundaries = page_boundaries.write().await;
+            let mut cache = room_cache.write().await;
+            for room_id in sessions.keys() {
+                pages.remove(room_id);
+                boundaries.remove(room_id);
+                cache.remove(room_id);
+            }
+        }
+
+        let mut rooms: Vec<RoomKeysImported> = sessions
+            .into_iter()
+            .map(|(room_id, ids)| RoomKeysImported {
+                room_id: room_id.to_string(),
+                sessions: ids.len(),
+            })
+            .collect();
+        rooms.sort_by_key(|room| std::cmp::Reverse(room.sessions));
+        let imported = KeysImported {
+            sessions: rooms.iter().map(|room| room.sessions).sum(),
+            rooms,
+        };
+        info!("Imported {} room keys for {} rooms", imported.sessions, imported.rooms.len());
+        let _ = app.emit("matrix://keys-imported", imported);
+    });
+
+    Some(KeyImportWatch { _done: done_tx })
+}
@@ -2,17 +2,153 @@ use tauri::{ Manager};
 
 mod state;
 mod auth;
+mod autocomplete;
 mod sync_mod;
+mod sync_health;
 mod rooms;
 mod messages;
+mod membership;
+mod members;
+mod notifications;
+mod dehydration;
+mod room_directory;
 mod verification;
+mod export;
+mod profiles;
+mod previews;
+mod emoji;
+mod receipts;
+mod account_data;
+mod warnings;
+mod stickers;
+mod threepids;
+mod threads;
+mod errors;
+mod identity;
+mod uiaa;
+mod encryption_debug;
+mod breadcrumbs;
+mod sas_emoji;
+mod invites;
+mod session;
+mod room_list;
+mod qr_login;
+mod logging;
+mod devices;
+mod join_rules;
+mod media_cache;
+mod state_history;
+mod client_config;
+mod local_store;
+mod identity_changes;
+mod dm_search;
+mod backfill;
+mod room_upgrades;
+mod retention;
+mod formatting;
+mod account_export;
+mod view_state;
+mod app_lock;
+mod bridges;
+mod own_activity;
+mod prefetch;
+mod reports;
+mod user_notes;
+mod room_notifications;
+mod security;
+mod store_meta;
+mod settings;
+mod compose;
+mod polls;
+mod saved_messages;
+mod key_imports;
+mod ban_lists;
+mod dev_console;
+mod instance_lock;
+mod guest;
+mod local_retention;
+mod room_renames;
+mod push_rules;
+mod merged_dms;
+mod outbound;
+mod room_verification;
+mod sync_gaps;
+mod via_servers;
+mod snooze;
+mod transactions;
 
 pub use state::*;
 pub use auth::*;
+pub use autocomplete::*;
 pub use sync_mod::*;
+pub use sync_health::*;
 pub use rooms::*;
 pub use messages::*;
+pub use membership::*;
+pub use members::*;
+pub use notifications::*;
+pub use dehydration::*;
+pub use room_directory::*;
 pub use verification::*;
+pub use export::*;
+pub use previews::*;
+pub use emoji::*;
+pub use receipts::*;
+pub use warnings::*;
+pub use stickers::*;
+pub use threepids::*;
+pub use threads::*;
+pub use errors::*;
+pub use identity::*;
+pub use uiaa::*;
+pub use encryption_debug::*;
+pub use breadcrumbs::*;
+pub use invites::*;
+pub use room_list::*;
+pub use qr_login::*;
+pub use logging::*;
+pub use devices::*;
+pub use join_rules::*;
+pub use media_cache::*;
+pub use state_history::*;
+pub use client_config::*;
+pub use local_store::*;
+pub use identity_changes::*;
+pub use dm_search::*;
+pub use backfill::*;
+pub use room_upgrades::*;
+pub use retention::*;
+pub use formatting::*;
+pub use account_export::*;
+pub use view_state::*;
+pub use app_lock::*;
+pub use bridges::*;
+pub use own_activity::*;
+pub use prefetch::*;
+pub use reports::*;
+pub use user_notes::*;
+pub use room_notifications::*;
+pub use security::*;
+pub use store_meta::*;
+pub use settings::*;
+pub use compose::*;
+pub use polls::*;
+pub use saved_messages::*;
+pub use key_imports::*;
+pub use ban_lists::*;
+pub use dev_console::*;
+pub use instance_lock::*;
+pub use guest::*;
+pub use local_retention::*;
+pub use room_renames::*;
+pub use push_rules::*;
+pub use merged_dms::*;
+pub use outbound::*;
+pub use room_verification::*;
+pub use sync_gaps::*;
+pub use via_servers::*;
+pub use snooze::*;
+pub use transactions::*;
 
 #[tauri::command]
 fn greet(name: &str) -> String {
@@ -21,6 +157,171 @@ fn greet(name: &str) -> String {
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
+    let handler: fn(tauri::ipc::Invoke) -> bool = tauri::generate_handler![
+        greet,
+        matrix_login,
+        check_session,
+        logout,
+        matrix_sync,
+        get_sync_diagnostics,
+        get_rooms,
+        get_messages,
+        send_message,
+        check_verification_status,
+        request_verification,
+        get_verification_emoji,
+        confirm_verification,
+        cancel_verification,
+        verify_with_recovery_key,
+        request_room_keys,
+        export_room_history,
+        cancel_export,
+        get_url_preview,
+        send_reaction,
+        send_video,
+        send_image,
+        get_emoji_packs,
+        mark_read,
+        mark_room_as_read,
+        mark_all_rooms_as_read,
+        get_send_warnings,
+        dismiss_send_warning,
+        get_sticker_packs,
+        send_sticker,
+        get_threepids,
+        add_email,
+        submit_email_token,
+        remove_threepid,
+        get_identity_server,
+        set_identity_server,
+        get_identity_server_terms,
+        accept_identity_server_terms,
+        invite_by_email,
+        complete_auth,
+        cancel_auth,
+        register_account,
+        get_encryption_debug,
+        rotate_room_key,
+        get_send_readiness,
+        prepare_encryption,
+        get_thumbnail,
+        get_media_cache_stats,
+        set_media_cache_limit,
+        get_room_state_history,
+        login_with_token,
+        get_client_config,
+        get_store_stats,
+        clear_local_cache,
+        invite_user,
+        acknowledge_identity_change,
+        withdraw_verification,
+        search_dm_history,
+        get_devices,
+        rename_current_device,
+        backfill_room,
+        cancel_backfill,
+        upgrade_room,
+        forward_message,
+        get_settings,
+        update_settings,
+        rejoin_room,
+        invite_users,
+        cancel_invites,
+        get_read_marker,
+        get_event_context,
+        set_room_retention,
+        autocomplete_members,
+        leave_room,
+        kick_user,
+        ban_user,
+        unban_user,
+        get_room_members,
+        cancel_member_loading,
+        get_dehydration_status,
+        get_room_state,
+        get_room_directory_visibility,
+        set_room_directory_visibility,
+        export_account_data,
+        get_room_view_state,
+        set_room_view_state,
+        get_encrypted_media,
+        set_app_lock,
+        unlock_app,
+        lock_app,
+        is_locked,
+        notify_activity,
+        get_my_recent_activity,
+        report_user,
+        get_user_note,
+        set_user_note,
+        get_room_notification_settings,
+        add_room_keyword,
+        remove_room_keyword,
+        get_security_recommendation,
+        check_alias_available,
+        publish_room_alias,
+        get_thread_notification_mode,
+        set_thread_notification_mode,
+        get_thread_messages,
+        mark_thread_read,
+        get_full_event_body,
+        get_draft,
+        set_draft,
+        set_compose_context,
+        send_composed,
+        vote_poll,
+        end_poll,
+        save_message,
+        unsave_message,
+        get_saved_messages,
+        set_active_room,
+        export_ban_list,
+        import_ban_list,
+        get_policy_lists,
+        set_policy_lists,
+        raw_api_request,
+        force_takeover,
+        guest_login,
+        upgrade_guest_account,
+        join_room,
+        prune_local_history,
+        refresh_push_rules,
+        get_merged_dms,
+        get_outbound_queue,
+        request_user_verification,
+        get_user_verification,
+        confirm_user_verification,
+        cancel_user_verification,
+        fill_gap,
+        fetch_media,
+        get_media_support,
+        preview_room,
+        snooze_notifications,
+        unsnooze_notifications,
+        get_notification_settings,
+        get_notification_badge,
+        set_typing,
+        new_transaction_id,
+        record_room_visit,
+        get_recent_rooms,
+        get_invite_filter,
+        set_invite_filter,
+        get_invites,
+        test_invite_rule,
+        get_rooms_filtered,
+        start_qr_login_grant,
+        confirm_qr_login,
+        deny_qr_login,
+        set_log_level,
+        create_debug_bundle,
+        get_device_trust,
+        manually_verify_device,
+        block_device,
+        unblock_device,
+        get_join_rule,
+        set_join_rule,
+    ];
+
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .setup(|app| {
@@ -28,27 +329,23 @@ pub fn run() {
                 .map_err(|e| format!("Failed to get app data dir: {}", e))?;
             std::fs::create_dir_all(&data_dir)
                 .map_err(|e| format!("Failed to create app data dir: {}", e))?;
-            println!("Using data directory: {:?}", data_dir);
-            app.manage(MatrixState::new(data_dir));
+            let logs = init_logging(&data_dir)?;
+            tracing::info!("Using data directory: {:?}", data_dir);
+            app.manage(logs);
+            app.manage(MatrixState::new(data_dir, app.handle().clone()));
             Ok(())
         })
-        .invoke_handler(tauri::generate_handler![
-            greet,
-            matrix_login,
-            check_session,
-            logout,
-            matrix_sync,
-            get_rooms,
-            get_messages,
-            send_message,
-            check_verification_status,
-            request_verification,
-            get_verification_emoji,
-            confirm_verification,
-            cancel_verification,
-            verify_with_recovery_key,
-            request_room_keys,
-        ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .invoke_handler(move |invoke| match refuse_while_locked(invoke).and_then(refuse_for_guests) {
+            Some(invoke) => handler(invoke),
+            None => true,
+        })
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event {
+                if let Some(state) = app.try_state::<MatrixState>() {
+                    tauri::async_runtime::block_on(unlock_store(&state));
+                }
+            }
+        });
 }
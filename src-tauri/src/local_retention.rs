@@ -0,0 +1,194 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use matrix_sdk::ruma::MilliSecondsSinceUnixEpoch;
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use tauri::async_runtime::JoinHandle;
+use tauri::{Manager, State};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::auth::sanitize_user_id;
+use crate::backfill::{message_index_size, prune_index};
+use crate::media_cache::prune_media_cache;
+use crate::settings::Settings;
+use crate::state::MatrixState;
+
+/// How often the maintenance task runs after the one at startup.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// The daily maintenance task; set once it has been started for the session.
+pub type RetentionTaskSlot = Arc<RwLock<Option<JoinHandle<()>>>>;
+
+/// What the last prune of this session removed.
+pub type LastPrune = Arc<RwLock<Option<PruneReport>>>;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PruneReport {
+    pub pruned_at: u64,
+    /// Rows dropped from the local message index.
+    pub messages_removed: u64,
+    pub media_files_removed: u64,
+    /// Disk space given back, after vacuuming.
+    pub freed_bytes: u64,
+}
+
+/// Prunes the local history now instead of waiting for the daily run, and
+/// returns what was removed.
+#[tauri::command]
+pub async fn prune_local_history(state: State<'_, MatrixState>) -> Result<PruneReport, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+    prune(&state, &client).await
+}
+
+/// Starts the retention maintenance, once per session: a prune right away,
+/// then one a day. Called after each sync.
+pub(crate) async fn start_retention_task(state: &MatrixState, client: &Client) {
+    let mut task = state.retention_task.write().await;
+    if task.is_some() {
+        return;
+    }
+
+    let app = state.app.clone();
+    let client = client.clone();
+    *task = Some(tauri::async_runtime::spawn(async move {
+        loop {
+            let state = app.state::<MatrixState>();
+            if let Err(e) = prune(&state, &client).await {
+                warn!("Pruning local history failed: {}", e);
+            }
+            tokio::time::sleep(MAINTENANCE_INTERVAL).await;
+        }
+    }));
+}
+
+/// Prunes right away when a settings change keeps less than before, so the
+/// user doesn't wait a day for it. Runs in the background.
+pub(crate) async fn prune_if_narrowed(state: &MatrixState, old: &Settings, new: &Settings) {
+    if !retention_narrowed(old, new) {
+        return;
+    }
+    let Some(client) = state.client.read().await.clone() else {
+        return;
+    };
+
+    let app = state.app.clone();
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = prune(&app.state::<MatrixState>(), &client).await {
+            warn!("Pruning local history failed: {}", e);
+        }
+    });
+}
+
+/// Drops what the retention settings no longer allow: indexed messages older
+/// than the window or from encrypted rooms that mustn't be indexed, and
+/// cached media not used within the window. The SDK's stores, the crypto
+/// store above all, are never touched.
+async fn prune(state: &MatrixState, client: &Client) -> Result<PruneReport, String> {
+    let dir = session_dir(state).await?;
+    let (retention_days, index_encrypted_rooms) = {
+        let settings = state.settings.read().await;
+        (settings.local_history_retention_days, settings.index_encrypted_rooms)
+    };
+
+    let now = MilliSecondsSinceUnixEpoch::now().get().into();
+    let cutoff = retention_days.map(|days| cutoff_ms(now, days));
+    let mut unindexed_rooms = Vec::new();
+    if !index_encrypted_rooms {
+        for room in client.rooms() {
+            if room.latest_encryption_state().await.is_ok_and(|s| s.is_encrypted()) {
+                unindexed_rooms.push(room.room_id().to_string());
+            }
+        }
+    }
+
+    // Deleting, vacuuming and walking the media cache all block.
+    let (messages_removed, index_freed, media_files_removed, media_freed) =
+        tauri::async_runtime::spawn_blocking(move || {
+            let index_before = message_index_size(&dir);
+            let messages_removed = prune_index(&dir, cutoff, &unindexed_rooms)?;
+            let index_freed = index_before.saturating_sub(message_index_size(&dir));
+
+            let (media_files_removed, media_freed) = match cutoff {
+                Some(cutoff) => prune_media_cache(&dir, SystemTime::UNIX_EPOCH + Duration::from_millis(cutoff)),
+                None => (0, 0),
+            };
+            Ok::<_, String>((messages_removed, index_freed, media_files_removed, media_freed))
+        })
+        .await
+        .map_err(|e| format!("Failed to prune local history: {}", e))??;
+
+    let report = PruneReport {
+        pruned_at: now,
+        messages_removed,
+        media_files_removed,
+        freed_bytes: index_freed + media_freed,
+    };
+    info!(
+        "Pruned {} messages and {} media files, freeing {} bytes",
+        report.messages_removed, report.media_files_removed, report.freed_bytes
+    );
+    *state.last_prune.write().await = Some(report.clone());
+    Ok(report)
+}
+
+async fn session_dir(state: &MatrixState) -> Result<PathBuf, String> {
+    let user_id = state.user_id.read().await.clone().ok_or("Not logged in")?;
+    Ok(state.data_dir.join(sanitize_user_id(&user_id)))
+}
+
+fn cutoff_ms(now: u64, days: u32) -> u64 {
+    now.saturating_sub(u64::from(days) * DAY_MS)
+}
+
+/// Whether `new` keeps less local history than `old`: a shorter window, a
+/// window where there was none, or encrypted rooms no longer indexed.
+fn retention_narrowed(old: &Settings, new: &Settings) -> bool {
+    let shorter = match (old.local_history_retention_days, new.local_history_retention_days) {
+        (_, None) => false,
+        (None, Some(_)) => true,
+        (Some(old), Some(new)) => new < old,
+    };
+    shorter || (old.index_encrypted_rooms && !new.index_encrypted_rooms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(days: Option<u32>, index_encrypted_rooms: bool) -> Settings {
+        Settings {
+            local_history_retention_days: days,
+            index_encrypted_rooms,
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn shorter_window_narrows() {
+        assert!(retention_narrowed(&settings(Some(30), true), &settings(Some(7), true)));
+        assert!(retention_narrowed(&settings(None, true), &settings(Some(365), true)));
+    }
+
+    #[test]
+    fn longer_or_no_window_does_not_narrow() {
+        assert!(!retention_narrowed(&settings(Some(7), true), &settings(Some(30), true)));
+        assert!(!retention_narrowed(&settings(Some(7), true), &settings(None, true)));
+        assert!(!retention_narrowed(&settings(Some(7), true), &settings(Some(7), true)));
+    }
+
+    #[test]
+    fn turning_off_encrypted_indexing_narrows() {
+        assert!(retention_narrowed(&settings(None, true), &settings(None, false)));
+        assert!(!retention_narrowed(&settings(None, false), &settings(None, true)));
+    }
+
+    #[test]
+    fn cutoff_counts_whole_days_back() {
+        assert_eq!(cutoff_ms(10 * DAY_MS, 3), 7 * DAY_MS);
+        assert_eq!(cutoff_ms(DAY_MS, 3), 0);
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use matrix_sdk::config::SyncSettings;
+use rusqlite::{Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use tauri::{Emitter, State};
+use tracing::{info, warn};
+
+use crate::auth::{reopen_client, sanitize_user_id, stop_session_tasks};
+use crate::backfill::message_index_size;
+use crate::client_config::refresh_client_config;
+use crate::identity_changes::watch_identity_changes;
+use crate::local_retention::PruneReport;
+use crate::state::MatrixState;
+use crate::sync_mod::install_event_handlers;
+
+pub(crate) const STATE_DB: &str = "matrix-sdk-state.sqlite3";
+pub(crate) const EVENT_CACHE_DB: &str = "matrix-sdk-event-cache.sqlite3";
+pub(crate) const MEDIA_DB: &str = "matrix-sdk-media.sqlite3";
+pub(crate) const CRYPTO_DB: &str = "matrix-sdk-crypto.sqlite3";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoreStats {
+    /// Size of each SDK database, including its write-ahead log.
+    pub state_bytes: u64,
+    pub event_cache_bytes: u64,
+    pub media_bytes: u64,
+    pub crypto_bytes: u64,
+    /// Size of the local message index.
+    pub message_index_bytes: u64,
+    pub rooms: usize,
+    pub olm_sessions: Option<u64>,
+    pub megolm_sessions: Option<u64>,
+    /// What the last retention prune of this session removed.
+    pub last_prune: Option<PruneReport>,
+}
+
+/// Payload of `matrix://cache-rebuild-progress`.
+///
+/// `stage` is one of `stopping`, `clearing`, `restoring`, `syncing`, `done`
+/// or `failed`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CacheRebuildProgress {
+    pub stage: String,
+    pub error: Option<String>,
+}
+
+impl CacheRebuildProgress {
+    fn stage(stage: &str) -> Self {
+        Self {
+            stage: stage.to_string(),
+            error: None,
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn get_store_stats(state: State<'_, MatrixState>) -> Result<StoreStats, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+    let dir = store_dir(&state).await?;
+
+    let crypto_db = dir.join(CRYPTO_DB);
+    let count = |table: &str| count_rows(&crypto_db, table);
+
+    Ok(StoreStats {
+        state_bytes: db_size(&dir, STATE_DB),
+        event_cache_bytes: db_size(&dir, EVENT_CACHE_DB),
+        media_bytes: db_size(&dir, MEDIA_DB),
+        crypto_bytes: db_size(&dir, CRYPTO_DB),
+        message_index_bytes: message_index_size(&dir),
+        rooms: client.rooms().len(),
+        olm_sessions: count("session"),
+        megolm_sessions: count("inbound_group_session"),
+        last_prune: state.last_prune.read().await.clone(),
+    })
+}
+
+/// Throws away the cached rooms and timelines and syncs them again from
+/// scratch, for when the store is wedged. The crypto store is kept, so the
+/// device, its keys and its verification survive. Progress is reported
+/// through `matrix://cache-rebuild-progress`.
+#[tauri::command]
+pub async fn clear_local_cache(state: State<'_, MatrixState>) -> Result<(), String> {
+    let result = rebuild_store(&state).await;
+
+    let progress = match &result {
+        Ok(()) => CacheRebuildProgress::stage("done"),
+        Err(e) => {
+            warn!("Rebuilding the local cache failed: {}", e);
+            CacheRebuildProgress {
+                error: Some(e.clone()),
+                ..CacheRebuildProgress::stage("failed")
+            }
+        }
+    };
+    let _ = state.app.emit("matrix://cache-rebuild-progress", progress);
+
+    result
+}
+
+async fn rebuild_store(state: &MatrixState) -> Result<(), String> {
+    let dir = store_dir(state).await?;
+    let emit = |stage: &str| {
+        let _ = state
+            .app
+            .emit("matrix://cache-rebuild-progress", CacheRebuildProgress::stage(stage));
+    };
+
+    // Taking the client out of the state waits for a running `matrix_sync`
+    // and keeps new commands off the old store. The background tasks and
+    // event handlers hold their own handles to it, so they go too before
+    // its databases are removed.
+    emit("stopping");
+    let client = state.client.write().await.take().ok_or("Not logged in")?;
+    let user_id = client.user_id().ok_or("Not logged in")?.to_owned();
+    let session = client.matrix_auth().session().ok_or("The session has no access token")?;
+    let homeserver = client.homeserver().to_string();
+    for task in stop_session_tasks(state).await {
+        let _ = task.await;
+    }
+    drop(client);
+
+    emit("clearing");
+    for name in [STATE_DB, EVENT_CACHE_DB] {
+        for suffix in ["", "-wal", "-shm"] {
+            let path = dir.join(format!("{}{}", name, suffix));
+            if path.exists() {
+                fs::remove_file(&path).map_err(|e| format!("Failed to remove {:?}: {}", path, e))?;
+            }
+        }
+    }
+    state.room_cache.write().await.clear();
+    state.room_activity.write().await.clear();
+    state.pagination_tokens.write().await.clear();
+
+    emit("restoring");
+    let client = reopen_client(state, session, &homeserver, user_id.as_str()).await?;
+    install_event_handlers(&client, state).await;
+    refresh_client_config(&state.data_dir, &state.client_config, &client, false);
+    watch_identity_changes(&state.app, &state.identity_watcher, &client).await;
+    *state.client.write().await = Some(client.clone());
+
+    emit("syncing");
+    client
+        .sync_once(SyncSettings::default())
+        .await
+        .map_err(|e| format!("Initial sync failed: {}", e))?;
+
+    info!("Rebuilt the local cache for {:?}", client.user_id());
+
+    Ok(())
+}
+
+async fn store_dir(state: &MatrixState) -> Result<PathBuf, String> {
+    let user_id = state.user_id.read().await.clone().ok_or("Not logged in")?;
+    Ok(state.data_dir.join(sanitize_user_id(&user_id)))
+}
+
+pub(crate) fn db_size(dir: &Path, name: &str) -> u64 {
+    ["", "-wal"]
+        .iter()
+        .filter_map(|suffix| fs::metadata(dir.join(format!("{}{}", name, suffix))).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Counts rows in a crypto store table, read-only next to the SDK's own
+/// connection. `None` when the database can't be read.
+fn count_rows(db: &Path, table: &str) -> Option<u64> {
+    let connection = Connection::open_with_flags(db, OpenFlags::SQLITE_OPEN_READ_ONLY).ok()?;
+    connection
+        .query_row(&format!("SELECT COUNT(*) FROM \"{}\"", table), [], |row| row.get(0))
+        .ok()
+}
@@ -0,0 +1,268 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use matrix_sdk::RoomState;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tokio::sync::RwLock;
+use tracing::info;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::state::MatrixState;
+use crate::sync_health::SyncDiagnostics;
+
+const LOG_FILE_PREFIX: &str = "matrix-client";
+const LOG_FILE_SUFFIX: &str = "log";
+/// Daily log files kept on disk; older ones are deleted on rotation.
+const MAX_LOG_FILES: usize = 7;
+/// Log files included in a debug bundle when the caller doesn't say.
+const DEFAULT_BUNDLE_LOG_FILES: usize = 3;
+const DEFAULT_LOG_LEVEL: &str = "info";
+const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+/// Patterns for secrets that may end up in log lines: token and password
+/// fields, bearer headers and Synapse-style access tokens.
+static SECRET_PATTERNS: LazyLock<Vec<(Regex, &'static str)>> = LazyLock::new(|| {
+    vec![
+        (
+            Regex::new(r#"(?i)((?:access_token|refresh_token|password|client_secret)"?\s*[:=]\s*"?)[^"&\s,}]+"#)
+                .unwrap(),
+            "${1}<redacted>",
+        ),
+        (Regex::new(r"(?i)(bearer\s+)\S+").unwrap(), "${1}<redacted>"),
+        (Regex::new(r"\b(syt|mct|mat)_[A-Za-z0-9_]+").unwrap(), "<redacted>"),
+    ]
+});
+
+/// Logging handles, managed alongside `MatrixState`.
+pub struct LogState {
+    pub dir: PathBuf,
+    level: RwLock<String>,
+    filter: reload::Handle<EnvFilter, Registry>,
+    _guard: WorkerGuard,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DebugSummary {
+    pub app_version: String,
+    pub os: String,
+    pub log_level: String,
+    pub logged_in: bool,
+    pub homeserver: Option<String>,
+    pub device_id: Option<String>,
+    pub joined_rooms: usize,
+    pub invited_rooms: usize,
+    pub left_rooms: usize,
+    pub encrypted_rooms: usize,
+    pub crypto: Option<CryptoSummary>,
+    pub sync: SyncDiagnostics,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CryptoSummary {
+    pub tracked_users: usize,
+    pub own_devices: usize,
+    pub has_master_key: bool,
+    pub has_self_signing_key: bool,
+    pub has_user_signing_key: bool,
+    pub backup_state: String,
+    pub recovery_state: String,
+}
+
+/// Sets up `tracing` for the app and the SDK: human-readable logs on stdout
+/// and in a daily rotating file under `<data_dir>/logs`. `RUST_LOG` overrides
+/// the default level at startup.
+pub fn init_logging(data_dir: &Path) -> Result<LogState, String> {
+    let dir = data_dir.join("logs");
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create log dir: {}", e))?;
+
+    let appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix(LOG_FILE_SUFFIX)
+        .max_log_files(MAX_LOG_FILES)
+        .build(&dir)
+        .map_err(|e| format!("Failed to open log file: {}", e))?;
+    let (writer, guard) = tracing_appender::non_blocking(appender);
+
+    let level = std::env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_LOG_LEVEL.to_string());
+    let filter = EnvFilter::try_new(&level).unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_LEVEL));
+    let (filter, handle) = reload::Layer::new(filter);
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .with(fmt::layer().with_ansi(false).with_writer(writer))
+        .try_init()
+        .map_err(|e| format!("Failed to set up logging: {}", e))?;
+
+    Ok(LogState {
+        dir,
+        level: RwLock::new(level),
+        filter: handle,
+        _guard: guard,
+    })
+}
+
+/// Changes log verbosity until the app restarts; `level` is one of `error`,
+/// `warn`, `info`, `debug` or `trace`.
+#[tauri::command]
+pub async fn set_log_level(logs: State<'_, LogState>, level: String) -> Result<String, String> {
+    let level = level.trim().to_lowercase();
+    if !LOG_LEVELS.contains(&level.as_str()) {
+        return Err(format!("Unknown log level: {}", level));
+    }
+
+    logs.filter
+        .reload(EnvFilter::new(&level))
+        .map_err(|e| format!("Failed to change log level: {}", e))?;
+    *logs.level.write().await = level.clone();
+
+    info!("Log level set to {}", level);
+
+    Ok(level)
+}
+
+/// Zips the newest `log_files` log files, with secrets redacted, together with
+/// a summary of the client's state for attaching to bug reports. The summary
+/// holds counts and identifiers only; no tokens or message contents. Returns
+/// the path of the bundle.
+#[tauri::command]
+pub async fn create_debug_bundle(
+    state: State<'_, MatrixState>,
+    logs: State<'_, LogState>,
+    log_files: Option<usize>,
+) -> Result<String, String> {
+    let summary = debug_summary(&state, &logs).await;
+
+    let bundle_dir = state.data_dir.join("debug-bundles");
+    fs::create_dir_all(&bundle_dir)
+        .map_err(|e| format!("Failed to create bundle dir: {}", e))?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let path = bundle_dir.join(format!("debug-bundle-{}.zip", timestamp));
+
+    let file = File::create(&path).map_err(|e| format!("Failed to create bundle: {}", e))?;
+    let mut zip = ZipWriter::new(file);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    let summary_json = serde_json::to_string_pretty(&summary)
+        .map_err(|e| format!("Failed to serialize summary: {}", e))?;
+    zip.start_file("summary.json", options)
+        .and_then(|_| Ok(zip.write_all(summary_json.as_bytes())?))
+        .map_err(|e| format!("Failed to write bundle: {}", e))?;
+
+    for log_path in newest_log_files(&logs.dir, log_files.unwrap_or(DEFAULT_BUNDLE_LOG_FILES))? {
+        let Ok(contents) = fs::read_to_string(&log_path) else {
+            continue;
+        };
+        let name = log_path.file_name().unwrap_or_default().to_string_lossy();
+        zip.start_file(format!("logs/{}", name), options)
+            .and_then(|_| Ok(zip.write_all(redact(&contents).as_bytes())?))
+            .map_err(|e| format!("Failed to write bundle: {}", e))?;
+    }
+
+    zip.finish().map_err(|e| format!("Failed to write bundle: {}", e))?;
+
+    info!("Created debug bundle at {:?}", path);
+
+    Ok(path.to_string_lossy().to_string())
+}
+
+async fn debug_summary(state: &MatrixState, logs: &LogState) -> DebugSummary {
+    let client = state.client.read().await.clone();
+
+    let mut summary = DebugSummary {
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        os: std::env::consts::OS.to_string(),
+        log_level: logs.level.read().await.clone(),
+        logged_in: client.is_some(),
+        homeserver: None,
+        device_id: None,
+        joined_rooms: 0,
+        invited_rooms: 0,
+        left_rooms: 0,
+        encrypted_rooms: 0,
+        crypto: None,
+        sync: state.sync_health.read().await.snapshot(),
+    };
+    let Some(client) = client else {
+        return summary;
+    };
+
+    summary.homeserver = Some(client.homeserver().to_string());
+    summary.device_id = client.device_id().map(|d| d.to_string());
+
+    for room in client.rooms() {
+        match room.state() {
+            RoomState::Joined => summary.joined_rooms += 1,
+            RoomState::Invited => summary.invited_rooms += 1,
+            RoomState::Left => summary.left_rooms += 1,
+            _ => {}
+        }
+        if room.encryption_settings().is_some() {
+            summary.encrypted_rooms += 1;
+        }
+    }
+
+    let encryption = client.encryption();
+    let cross_signing = encryption.cross_signing_status().await;
+    let own_devices = match client.user_id() {
+        Some(user_id) => encryption
+            .get_user_devices(user_id)
+            .await
+            .map(|d| d.devices().count())
+            .unwrap_or(0),
+        None => 0,
+    };
+
+    summary.crypto = Some(CryptoSummary {
+        tracked_users: encryption.tracked_users().await.map(|u| u.len()).unwrap_or(0),
+        own_devices,
+        has_master_key: cross_signing.as_ref().is_some_and(|s| s.has_master),
+        has_self_signing_key: cross_signing.as_ref().is_some_and(|s| s.has_self_signing),
+        has_user_signing_key: cross_signing.as_ref().is_some_and(|s| s.has_user_signing),
+        backup_state: format!("{:?}", encryption.backups().state()),
+        recovery_state: format!("{:?}", encryption.recovery().state()),
+    });
+
+    summary
+}
+
+fn newest_log_files(dir: &Path, count: usize) -> Result<Vec<PathBuf>, String> {
+    let mut files: Vec<(SystemTime, PathBuf)> = fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read log dir: {}", e))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(LOG_FILE_PREFIX)
+        })
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .collect();
+
+    files.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+
+    Ok(files.into_iter().take(count).map(|(_, path)| path).collect())
+}
+
+fn redact(contents: &str) -> String {
+    SECRET_PATTERNS
+        .iter()
+        .fold(contents.to_string(), |text, (pattern, replacement)| {
+            pattern.replace_all(&text, *replacement).into_owned()
+        })
+}
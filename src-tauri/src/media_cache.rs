@@ -0,0 +1,434 @@
+use std::fs::{self, File};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use matrix_sdk::media::{MediaFormat, MediaRequestParameters, MediaThumbnailSettings};
+use matrix_sdk::ruma::api::client::authenticated_media;
+use matrix_sdk::ruma::api::Metadata;
+use matrix_sdk::ruma::api::client::media::get_content_thumbnail::v3::Method;
+use matrix_sdk::ruma::events::room::{EncryptedFile, MediaSource};
+use matrix_sdk::ruma::{OwnedMxcUri, UInt};
+use matrix_sdk_crypto::{AttachmentDecryptor, MediaEncryptionInfo};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tauri::State;
+use tracing::{debug, warn};
+
+use crate::auth::sanitize_user_id;
+use crate::errors::CommandError;
+use crate::settings::change_settings;
+use crate::state::MatrixState;
+
+/// Byte cap for the on-disk media cache unless the user picks another.
+pub const DEFAULT_MEDIA_CACHE_LIMIT: u64 = 200 * 1024 * 1024;
+const MEDIA_CACHE_DIR: &str = "media-cache";
+
+/// The sizes the UI asks for, mapped onto the homeserver's usual thumbnail
+/// dimensions.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ThumbnailSize {
+    /// Room list avatars.
+    Small,
+    /// The room header.
+    Medium,
+    /// Previews in the timeline.
+    Large,
+    /// The full file, for the lightbox.
+    Original,
+}
+
+impl ThumbnailSize {
+    /// Thumbnails are still images unless `animated` asks the server to keep
+    /// a GIF or APNG moving.
+    fn format(self, animated: bool) -> MediaFormat {
+        let (method, width, height) = match self {
+            ThumbnailSize::Small => (Method::Crop, 32u32, 32u32),
+            ThumbnailSize::Medium => (Method::Crop, 96, 96),
+            ThumbnailSize::Large => (Method::Scale, 800, 600),
+            ThumbnailSize::Original => return MediaFormat::File,
+        };
+        let mut settings = MediaThumbnailSettings::with_method(method, UInt::from(width), UInt::from(height));
+        settings.animated = animated;
+        MediaFormat::Thumbnail(settings)
+    }
+
+    fn key(self) -> &'static str {
+        match self {
+            ThumbnailSize::Small => "small",
+            ThumbnailSize::Medium => "medium",
+            ThumbnailSize::Large => "large",
+            ThumbnailSize::Original => "original",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MediaCacheStats {
+    pub entries: usize,
+    pub total_bytes: u64,
+    pub max_bytes: u64,
+}
+
+/// Returns the path of a cached copy of `mxc` at `size`, downloading it on a
+/// miss. The SDK picks the authenticated media endpoints when the server
+/// supports them and the legacy ones otherwise. Thumbnails of animated
+/// images are still frames unless `animated` is set; `Original` is always
+/// the file itself.
+#[tauri::command]
+pub async fn get_thumbnail(
+    state: State<'_, MatrixState>,
+    mxc: String,
+    size: ThumbnailSize,
+    animated: Option<bool>,
+) -> Result<String, String> {
+    let animated = animated.unwrap_or(false) && !matches!(size, ThumbnailSize::Original);
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+
+    let uri = OwnedMxcUri::from(mxc.as_str());
+    if !uri.is_valid() {
+        return Err("Invalid media URI".to_string());
+    }
+
+    let dir = cache_dir(&state).await?;
+    let path = dir.join(cache_key(&mxc, size, animated));
+
+    if path.exists() {
+        touch(&path);
+        return Ok(path.to_string_lossy().to_string());
+    }
+
+    debug!("Fetching {} thumbnail for {}", size.key(), mxc);
+
+    let request = MediaRequestParameters {
+        source: MediaSource::Plain(uri),
+        format: size.format(animated),
+    };
+    let bytes = client
+        .media()
+        .get_media_content(&request, false)
+        .await
+        .map_err(|e| format!("Failed to download media: {}", e))?;
+
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create media cache: {}", e))?;
+    fs::write(&path, bytes).map_err(|e| format!("Failed to cache media: {}", e))?;
+
+    let limit = state.settings.read().await.media_cache_limit;
+    evict(&dir, limit, Some(&path));
+
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// Returns the path of a decrypted copy of an encrypted attachment,
+/// downloading and decrypting it on a miss. The download is checked against
+/// the hash in the event first; files failing the check are not cached and
+/// give `INTEGRITY_CHECK_FAILED`.
+#[tauri::command]
+pub async fn get_encrypted_media(state: State<'_, MatrixState>, file: EncryptedFile) -> Result<String, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+
+    if !file.url.is_valid() {
+        return Err("Invalid media URI".to_string());
+    }
+
+    let dir = cache_dir(&state).await?;
+    let path = dir.join(hash_key(&format!("{}|decrypted", file.url)));
+
+    if path.exists() {
+        touch(&path);
+        return Ok(path.to_string_lossy().to_string());
+    }
+
+    debug!("Fetching encrypted file {}", file.url);
+
+    // Fetched as a plain file: the SDK would decrypt without us seeing
+    // the ciphertext.
+    let request = MediaRequestParameters {
+        source: MediaSource::Plain(file.url.clone()),
+        format: MediaFormat::File,
+    };
+    let ciphertext = client
+        .media()
+        .get_media_content(&request, false)
+        .await
+        .map_err(|e| format!("Failed to download media: {}", e))?;
+
+    let plaintext = decrypt_attachment(&ciphertext, &file).inspect_err(|e| {
+        warn!("Encrypted file {} failed its integrity check: {}", file.url, e.message);
+    })?;
+
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create media cache: {}", e))?;
+    fs::write(&path, plaintext).map_err(|e| format!("Failed to cache media: {}", e))?;
+
+    let limit = state.settings.read().await.media_cache_limit;
+    evict(&dir, limit, Some(&path));
+
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// Returns the path of a cached copy of any media: `file` for attachments
+/// in encrypted rooms, else `mxc` at `size`, the original by default. Media
+/// goes through the SDK so requests carry the access token; the webview
+/// can't authenticate an HTTP URL itself.
+#[tauri::command]
+pub async fn fetch_media(
+    state: State<'_, MatrixState>,
+    mxc: Option<String>,
+    file: Option<EncryptedFile>,
+    size: Option<ThumbnailSize>,
+    animated: Option<bool>,
+) -> Result<String, String> {
+    match (file, mxc) {
+        (Some(file), _) => get_encrypted_media(state, file).await,
+        (None, Some(mxc)) => get_thumbnail(state, mxc, size.unwrap_or(ThumbnailSize::Original), animated).await,
+        (None, None) => Err("No media to fetch".to_string()),
+    }
+}
+
+/// Whether media can be fetched from the homeserver; see `get_media_support`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MediaSupport {
+    /// The server offers the authenticated media endpoints (MSC3916,
+    /// Matrix 1.11), and may have turned the unauthenticated ones off.
+    /// The SDK switches to them whenever they are, so media keeps loading
+    /// when the server turns the old ones off.
+    pub authenticated_media: bool,
+}
+
+/// Probes the homeserver for authenticated media.
+#[tauri::command]
+pub async fn get_media_support(state: State<'_, MatrixState>) -> Result<MediaSupport, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+
+    let versions = client
+        .supported_versions()
+        .await
+        .map_err(|e| format!("Failed to get server versions: {}", e))?;
+    let authenticated_media = authenticated_media::get_content::v1::Request::PATH_BUILDER.is_supported(&versions);
+
+    Ok(MediaSupport { authenticated_media })
+}
+
+#[tauri::command]
+pub async fn get_media_cache_stats(state: State<'_, MatrixState>) -> Result<MediaCacheStats, String> {
+    let dir = cache_dir(&state).await?;
+    let entries = cache_entries(&dir);
+
+    Ok(MediaCacheStats {
+        entries: entries.len(),
+        total_bytes: entries.iter().map(|(_, size, _)| size).sum(),
+        max_bytes: state.settings.read().await.media_cache_limit,
+    })
+}
+
+/// Changes the cache's byte cap and evicts down to it right away.
+#[tauri::command]
+pub async fn set_media_cache_limit(
+    state: State<'_, MatrixState>,
+    max_bytes: u64,
+) -> Result<MediaCacheStats, String> {
+    change_settings(&state, |settings| settings.media_cache_limit = max_bytes).await?;
+
+    let dir = cache_dir(&state).await?;
+    evict(&dir, max_bytes, None);
+
+    get_media_cache_stats(state).await
+}
+
+async fn cache_dir(state: &MatrixState) -> Result<PathBuf, String> {
+    let user_id = state.user_id.read().await.clone().ok_or("Not logged in")?;
+    Ok(state
+        .data_dir
+        .join(sanitize_user_id(&user_id))
+        .join(MEDIA_CACHE_DIR))
+}
+
+/// Files are named after a hash of the URI and size, so they need no index.
+/// Animated thumbnails get their own entries; still ones keep the old keys.
+fn cache_key(mxc: &str, size: ThumbnailSize, animated: bool) -> String {
+    if animated {
+        hash_key(&format!("{}|{}|animated", mxc, size.key()))
+    } else {
+        hash_key(&format!("{}|{}", mxc, size.key()))
+    }
+}
+
+fn hash_key(input: &str) -> String {
+    let digest = Sha256::digest(input.as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decrypts an encrypted attachment after checking the ciphertext against
+/// the SHA-256 hash from its event, so nothing tampered with or corrupted
+/// is ever decrypted. The key must be a v2 AES-256-CTR key.
+pub(crate) fn decrypt_attachment(ciphertext: &[u8], file: &EncryptedFile) -> Result<Vec<u8>, CommandError> {
+    let integrity_failed = |reason: String| {
+        CommandError::new(
+            "INTEGRITY_CHECK_FAILED",
+            format!("The file failed its integrity check: {}", reason),
+        )
+    };
+
+    let expected = file
+        .hashes
+        .get("sha256")
+        .ok_or_else(|| integrity_failed("it has no SHA-256 hash".to_string()))?;
+    if Sha256::digest(ciphertext).as_slice() != expected.as_bytes() {
+        return Err(integrity_failed("its SHA-256 hash doesn't match".to_string()));
+    }
+
+    let key = &file.key;
+    if file.v != "v2" || key.kty != "oct" || key.alg != "A256CTR" || !key.key_ops.iter().any(|op| op == "decrypt") {
+        return Err(CommandError::new(
+            "UNSUPPORTED_ENCRYPTION",
+            "The file is encrypted in a way this app doesn't support",
+        ));
+    }
+
+    let mut reader = Cursor::new(ciphertext);
+    let mut decryptor = AttachmentDecryptor::new(&mut reader, MediaEncryptionInfo::from(file.clone()))
+        .map_err(|e| integrity_failed(e.to_string()))?;
+    let mut plaintext = Vec::with_capacity(ciphertext.len());
+    decryptor
+        .read_to_end(&mut plaintext)
+        .map_err(|e| integrity_failed(e.to_string()))?;
+
+    Ok(plaintext)
+}
+
+/// Marks a file as recently used; the modification time is the LRU clock.
+fn touch(path: &Path) {
+    if let Ok(file) = File::options().append(true).open(path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+}
+
+/// Removes cached files not used since `cutoff`. Returns how many were
+/// removed and the bytes they took.
+pub(crate) fn prune_media_cache(session_dir: &Path, cutoff: SystemTime) -> (u64, u64) {
+    let mut removed = (0, 0);
+    for (path, size, used) in cache_entries(&session_dir.join(MEDIA_CACHE_DIR)) {
+        if used >= cutoff {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => removed = (removed.0 + 1, removed.1 + size),
+            Err(e) => warn!("Failed to prune cached media {:?}: {}", path, e),
+        }
+    }
+    removed
+}
+
+fn cache_entries(dir: &Path) -> Vec<(PathBuf, u64, SystemTime)> {
+    let Ok(read_dir) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    read_dir
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            if !metadata.is_file() {
+                return None;
+            }
+            Some((entry.path(), metadata.len(), metadata.modified().ok()?))
+        })
+        .collect()
+}
+
+/// Deletes least recently used files until the cache fits in `max_bytes`,
+/// sparing `keep` (the file just written).
+fn evict(dir: &Path, max_bytes: u64, keep: Option<&Path>) {
+    let mut entries = cache_entries(dir);
+    let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+    if total <= max_bytes {
+        return;
+    }
+
+    entries.sort_by_key(|(_, _, used)| *used);
+    for (path, size, _) in entries {
+        if total <= max_bytes {
+            break;
+        }
+        if Some(path.as_path()) == keep {
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => total -= size,
+            Err(e) => warn!("Failed to evict {:?} from media cache: {}", path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// The attachment test vector used by libolm and the matrix-rust-sdk.
+    const CIPHERTEXT: &[u8] = &[
+        179, 154, 118, 127, 186, 127, 110, 33, 203, 33, 33, 134, 67, 100, 173, 46, 235, 27, 215, 172, 36, 26, 75,
+        47, 33, 160,
+    ];
+    const PLAINTEXT: &str = "It's a secret to everybody";
+
+    fn encrypted_file() -> serde_json::Value {
+        json!({
+            "url": "mxc://example.org/secret",
+            "v": "v2",
+            "key": {
+                "kty": "oct",
+                "alg": "A256CTR",
+                "ext": true,
+                "k": "Voq2nkPme_x8no5-Tjq_laDAdxE6iDbxnlQXxwFPgE4",
+                "key_ops": ["encrypt", "decrypt"]
+            },
+            "iv": "i0DovxYdJEcAAAAAAAAAAA",
+            "hashes": {
+                "sha256": "ANdt819a8bZl4jKy3Z+jcqtiNICa2y0AW4BBJ/iQRAU"
+            }
+        })
+    }
+
+    fn parse(value: serde_json::Value) -> EncryptedFile {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn decrypts_test_vector() {
+        let plaintext = decrypt_attachment(CIPHERTEXT, &parse(encrypted_file())).unwrap();
+        assert_eq!(plaintext, PLAINTEXT.as_bytes());
+    }
+
+    #[test]
+    fn rejects_tampered_ciphertext() {
+        let mut tampered = CIPHERTEXT.to_vec();
+        tampered[0] ^= 1;
+        let error = decrypt_attachment(&tampered, &parse(encrypted_file())).unwrap_err();
+        assert_eq!(error.code, "INTEGRITY_CHECK_FAILED");
+    }
+
+    #[test]
+    fn rejects_missing_hash() {
+        let mut file = encrypted_file();
+        file["hashes"] = json!({});
+        let error = decrypt_attachment(CIPHERTEXT, &parse(file)).unwrap_err();
+        assert_eq!(error.code, "INTEGRITY_CHECK_FAILED");
+    }
+
+    #[test]
+    fn rejects_wrong_iv_length() {
+        let mut file = encrypted_file();
+        file["iv"] = json!("i0DovxYdJEc");
+        let error = decrypt_attachment(CIPHERTEXT, &parse(file)).unwrap_err();
+        assert_eq!(error.code, "INTEGRITY_CHECK_FAILED");
+    }
+
+    #[test]
+    fn rejects_unknown_algorithm() {
+        let mut file = encrypted_file();
+        file["key"]["alg"] = json!("A128CTR");
+        let error = decrypt_attachment(CIPHERTEXT, &parse(file)).unwrap_err();
+        assert_eq!(error.code, "UNSUPPORTED_ENCRYPTION");
+    }
+}
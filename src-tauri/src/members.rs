@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::{Room, RoomMemberships};
+use serde::{Deserialize, Serialize};
+use tauri::async_runtime::JoinHandle;
+use tauri::{AppHandle, Emitter, State};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::ban_lists::{matching_rules, room_policy_rules, PolicyRule};
+use crate::state::MatrixState;
+
+/// Rooms with more joined and invited members than this load their member
+/// list in the background instead of blocking `get_room_members`.
+const LARGE_ROOM_MEMBERS: u64 = 1000;
+/// Members per `matrix://members-chunk` event.
+const MEMBERS_CHUNK_SIZE: usize = 500;
+
+/// Background member list fetches, per room.
+pub type MemberFetches = Arc<RwLock<HashMap<OwnedRoomId, JoinHandle<()>>>>;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomMemberInfo {
+    pub user_id: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    /// `join` or `invite`.
+    pub membership: String,
+    /// Rules of the room's linked policy lists that match the user.
+    pub policy_flags: Vec<PolicyRule>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomMembersResponse {
+    pub members: Vec<RoomMemberInfo>,
+    /// Unset when only the locally known members were returned; the rest
+    /// follow as `matrix://members-chunk` events.
+    pub complete: bool,
+}
+
+/// Payload of `matrix://members-chunk`. Chunks repeat members already
+/// returned by `get_room_members`; the frontend merges by user ID.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MembersChunk {
+    pub room_id: String,
+    pub members: Vec<RoomMemberInfo>,
+}
+
+/// Payload of `matrix://members-complete`, sent after the last chunk or
+/// when the fetch failed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MembersComplete {
+    pub room_id: String,
+    pub total: usize,
+    pub error: Option<String>,
+}
+
+/// The room's joined and invited members. Small rooms are loaded in full
+/// before returning. Large rooms whose member list was lazy-loaded return
+/// what is known locally and fetch the rest in the background.
+#[tauri::command]
+pub async fn get_room_members(
+    state: State<'_, MatrixState>,
+    room_id: String,
+) -> Result<RoomMembersResponse, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let policies = room_policy_rules(client, &*state.settings.read().await, &room_id).await;
+    let large = room.joined_members_count() + room.invited_members_count() > LARGE_ROOM_MEMBERS;
+    let complete = room.are_members_synced() || !large;
+    if !room.are_members_synced() {
+        if large {
+            start_member_fetch(&state.member_fetches, state.app.clone(), room.clone(), policies.clone()).await;
+        } else {
+            room.sync_members()
+                .await
+                .map_err(|e| format!("Failed to load members: {}", e))?;
+        }
+    }
+
+    Ok(RoomMembersResponse {
+        members: local_members(&room, &policies).await?,
+        complete,
+    })
+}
+
+/// Stops loading the room's member list in the background, e.g. when the
+/// member panel closes. Returns whether a fetch was running.
+#[tauri::command]
+pub async fn cancel_member_loading(state: State<'_, MatrixState>, room_id: String) -> Result<bool, String> {
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+
+    match state.member_fetches.write().await.remove(&room_id) {
+        Some(task) => {
+            task.abort();
+            info!("Cancelled member loading for {}", room_id);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+async fn local_members(room: &Room, policies: &[PolicyRule]) -> Result<Vec<RoomMemberInfo>, String> {
+    let members = room
+        .members_no_sync(RoomMemberships::JOIN | RoomMemberships::INVITE)
+        .await
+        .map_err(|e| format!("Failed to load members: {}", e))?;
+
+    Ok(members
+        .iter()
+        .map(|member| RoomMemberInfo {
+            user_id: member.user_id().to_string(),
+            display_name: member.display_name().map(str::to_string),
+            avatar_url: member.avatar_url().map(|url| url.to_string()),
+            membership: member.membership().to_string(),
+            policy_flags: matching_rules(policies, member.user_id()),
+        })
+        .collect())
+}
+
+/// Fetches the full member list unless a fetch for the room is already
+/// running, then streams it to the frontend in chunks.
+async fn start_member_fetch(fetches: &MemberFetches, app: AppHandle, room: Room, policies: Vec<PolicyRule>) {
+    let room_id = room.room_id().to_owned();
+    let mut running = fetches.write().await;
+    if running.contains_key(&room_id) {
+        return;
+    }
+
+    let slot = fetches.clone();
+    let task = tauri::async_runtime::spawn(async move {
+        let room_id = room.room_id().to_owned();
+        info!("Loading members of {} in the background", room_id);
+
+        let result = match room.sync_members().await {
+            Ok(_) => local_members(&room, &policies).await,
+            Err(e) => Err(format!("Failed to load members: {}", e)),
+        };
+        let complete = match result {
+            Ok(members) => {
+                for chunk in members.chunks(MEMBERS_CHUNK_SIZE) {
+                    let _ = app.emit(
+                        "matrix://members-chunk",
+                        MembersChunk {
+                            room_id: room_id.to_string(),
+                            members: chunk.to_vec(),
+                        },
+                    );
+                }
+                MembersComplete {
+                    room_id: room_id.to_string(),
+                    total: members.len(),
+                    error: None,
+                }
+            }
+            Err(e) => {
+                warn!("{}", e);
+                MembersComplete {
+                    room_id: room_id.to_string(),
+                    total: 0,
+                    error: Some(e),
+                }
+            }
+        };
+        let _ = app.emit("matrix://members-complete", complete);
+
+        slot.write().await.remove(&room_id);
+    });
+    running.insert(room_id, task);
+}
@@ -0,0 +1,271 @@
+use matrix_sdk::deserialized_responses::RawAnySyncOrStrippedState;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::api::client::membership::{ban_user, kick_user, leave_room, unban_user};
+use matrix_sdk::ruma::events::room::member::{
+    MembershipChange, MembershipState, OriginalSyncRoomMemberEvent, SyncRoomMemberEvent,
+};
+use matrix_sdk::ruma::events::StateEventType;
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId};
+use matrix_sdk::{Client, Room};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, State};
+use tracing::info;
+
+use crate::errors::CommandError;
+use crate::outbound::{paced, OutboundClass};
+use crate::profiles::ProfileResolver;
+use crate::rooms::MessageContent;
+use crate::state::MatrixState;
+
+/// Why we are no longer in a room someone else removed us from. Also the
+/// payload of `matrix://removed-from-room`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomRemoval {
+    pub room_id: String,
+    /// Who kicked or banned us.
+    pub actor: String,
+    pub reason: Option<String>,
+    pub banned: bool,
+}
+
+/// Leaves the room, telling the other members `reason` if given.
+#[tauri::command]
+pub async fn leave_room(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    reason: Option<String>,
+) -> Result<(), String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    match reason.filter(|r| !r.trim().is_empty()) {
+        // The SDK's `leave` can't carry a reason; sync records the leave.
+        Some(reason) => {
+            let mut request = leave_room::v3::Request::new(room_id.clone());
+            request.reason = Some(reason);
+            client
+                .send(request)
+                .await
+                .map_err(|e| format!("Failed to leave room: {}", e))?;
+        }
+        None => room
+            .leave()
+            .await
+            .map_err(|e| format!("Failed to leave room: {}", e))?,
+    }
+
+    info!("Left {}", room_id);
+
+    state.room_cache.write().await.remove(&room_id);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn kick_user(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    user_id: String,
+    reason: Option<String>,
+) -> Result<(), String> {
+    let (room, user_id) = moderation_target(&state, &room_id, &user_id).await?;
+    let description = format!("Remove {} from {}", user_id, room.room_id());
+    // Sent directly rather than through `Room::kick_user` so that a 429 comes
+    // back to the pacer. The same goes for bans and unbans.
+    let mut request = kick_user::v3::Request::new(room.room_id().to_owned(), user_id.clone());
+    request.reason = reason;
+    paced(&state, OutboundClass::Moderation, description, |config| {
+        room.client().send(request.clone()).with_request_config(config)
+    })
+    .await
+    .map_err(|e| moderation_error("remove", e))?;
+
+    info!("Kicked {} from {}", user_id, room.room_id());
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn ban_user(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    user_id: String,
+    reason: Option<String>,
+) -> Result<(), String> {
+    let (room, user_id) = moderation_target(&state, &room_id, &user_id).await?;
+    let description = format!("Ban {} from {}", user_id, room.room_id());
+    let mut request = ban_user::v3::Request::new(room.room_id().to_owned(), user_id.clone());
+    request.reason = reason;
+    paced(&state, OutboundClass::Moderation, description, |config| {
+        room.client().send(request.clone()).with_request_config(config)
+    })
+    .await
+    .map_err(|e| moderation_error("ban", e))?;
+
+    info!("Banned {} from {}", user_id, room.room_id());
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn unban_user(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    user_id: String,
+    reason: Option<String>,
+) -> Result<(), String> {
+    let (room, user_id) = moderation_target(&state, &room_id, &user_id).await?;
+    let description = format!("Unban {} from {}", user_id, room.room_id());
+    let mut request = unban_user::v3::Request::new(room.room_id().to_owned(), user_id.clone());
+    request.reason = reason;
+    paced(&state, OutboundClass::Moderation, description, |config| {
+        room.client().send(request.clone()).with_request_config(config)
+    })
+    .await
+    .map_err(|e| moderation_error("unban", e))?;
+
+    info!("Unbanned {} from {}", user_id, room.room_id());
+    Ok(())
+}
+
+async fn moderation_target(
+    state: &MatrixState,
+    room_id: &str,
+    user_id: &str,
+) -> Result<(Room, OwnedUserId), String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let user_id: OwnedUserId = user_id
+        .parse()
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    Ok((room, user_id))
+}
+
+fn moderation_error(action: &str, e: matrix_sdk::Error) -> String {
+    match e.client_api_error_kind() {
+        Some(ErrorKind::Forbidden { .. }) => CommandError::new(
+            "INSUFFICIENT_POWER_LEVEL",
+            format!("You don't have permission to {} this user", action),
+        )
+        .into(),
+        _ => format!("Failed to {} user: {}", action, e),
+    }
+}
+
+/// The timeline entry for a membership change, such as "Bob was banned by
+/// Alice: spam". Profile changes and no-op events give `None`.
+pub(crate) fn membership_entry(
+    event: &OriginalSyncRoomMemberEvent,
+    profiles: &ProfileResolver,
+) -> Option<(String, MessageContent)> {
+    let sender = profiles.disambiguated_name(event.sender.as_str());
+    let target = event
+        .content
+        .displayname
+        .clone()
+        .or_else(|| event.unsigned.prev_content.as_ref()?.displayname.clone())
+        .unwrap_or_else(|| profiles.disambiguated_name(event.state_key.as_str()));
+
+    let (change, text) = match event.membership_change() {
+        MembershipChange::Joined | MembershipChange::InvitationAccepted => ("joined", format!("{} joined", target)),
+        MembershipChange::Left => ("left", format!("{} left", target)),
+        MembershipChange::Kicked => ("kicked", format!("{} was removed by {}", target, sender)),
+        MembershipChange::Banned | MembershipChange::KickedAndBanned => {
+            ("banned", format!("{} was banned by {}", target, sender))
+        }
+        MembershipChange::Unbanned => ("unbanned", format!("{} was unbanned by {}", target, sender)),
+        MembershipChange::Invited => ("invited", format!("{} invited {}", sender, target)),
+        MembershipChange::InvitationRejected => {
+            ("invite_rejected", format!("{} declined the invitation", target))
+        }
+        MembershipChange::InvitationRevoked => {
+            ("invite_revoked", format!("{} withdrew the invitation for {}", sender, target))
+        }
+        MembershipChange::Knocked => ("knocked", format!("{} asked to join", target)),
+        MembershipChange::KnockAccepted => {
+            ("knock_accepted", format!("{} let {} in", sender, target))
+        }
+        MembershipChange::KnockRetracted => {
+            ("knock_retracted", format!("{} no longer asks to join", target))
+        }
+        MembershipChange::KnockDenied => {
+            ("knock_denied", format!("{} turned down {}'s request to join", sender, target))
+        }
+        _ => return None,
+    };
+
+    let reason = event.content.reason.clone().filter(|r| !r.trim().is_empty());
+    let body = match &reason {
+        Some(reason) => format!("{}: {}", text, reason),
+        None => text,
+    };
+
+    Some((
+        body,
+        MessageContent::Membership {
+            change: change.to_string(),
+            target: event.state_key.to_string(),
+            reason,
+        },
+    ))
+}
+
+/// Who removed us from `room` and why, if we were kicked or banned rather
+/// than leaving ourselves.
+pub(crate) async fn own_removal(room: &Room) -> Option<RoomRemoval> {
+    let raw = room
+        .get_state_event(StateEventType::RoomMember, room.own_user_id().as_str())
+        .await
+        .ok()
+        .flatten()?;
+    let RawAnySyncOrStrippedState::Sync(raw) = raw else {
+        return None;
+    };
+    let SyncRoomMemberEvent::Original(event) = raw.deserialize_as_unchecked().ok()? else {
+        return None;
+    };
+    removal(room, &event)
+}
+
+/// Sync handler emitting `matrix://removed-from-room` when someone kicks or
+/// bans us.
+pub(crate) async fn on_own_membership(event: SyncRoomMemberEvent, room: Room, client: Client, app: AppHandle) {
+    let SyncRoomMemberEvent::Original(event) = event else {
+        return;
+    };
+    if Some(event.state_key.as_ref()) != client.user_id() {
+        return;
+    }
+    let Some(removal) = removal(&room, &event) else {
+        return;
+    };
+
+    info!("Removed from {} by {}", removal.room_id, removal.actor);
+    let _ = app.emit("matrix://removed-from-room", removal);
+}
+
+fn removal(room: &Room, event: &OriginalSyncRoomMemberEvent) -> Option<RoomRemoval> {
+    let banned = match event.content.membership {
+        MembershipState::Ban => true,
+        MembershipState::Leave => false,
+        _ => return None,
+    };
+    if event.sender == event.state_key {
+        return None;
+    }
+
+    Some(RoomRemoval {
+        room_id: room.room_id().to_string(),
+        actor: event.sender.to_string(),
+        reason: event.content.reason.clone().filter(|r| !r.trim().is_empty()),
+        banned,
+    })
+}
@@ -0,0 +1,361 @@
+use std::collections::{BTreeMap, HashMap};
+
+use matrix_sdk::deserialized_responses::TimelineEvent;
+use matrix_sdk::room::{Messages, MessagesOptions};
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId, RoomId};
+use matrix_sdk::{Client, Room, RoomState};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use tauri::State;
+use tracing::{debug, info, warn};
+
+use crate::profiles::ProfileResolver;
+use crate::room_list::last_active;
+use crate::rooms::{
+    has_more, insert_day_dividers, is_archived, origin_server_ts, page_messages, start_of_history, Message, MessagesResponse,
+};
+use crate::state::MatrixState;
+
+/// One contact's DMs, when there are several of them.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MergedDm {
+    pub user_id: String,
+    pub display_name: Option<String>,
+    /// The room messages to this contact are sent to: of the rooms not
+    /// replaced by an upgrade, the encrypted ones before the others, the most
+    /// recently active of those. `send_message` to any of the others goes
+    /// here too.
+    pub canonical_room_id: String,
+    /// Every DM with the contact in that same order, so canonical first.
+    /// Passed to `get_messages` as `merged_room_ids` to read them as one.
+    pub room_ids: Vec<String>,
+    /// The latest activity in any of them.
+    pub last_active: u64,
+}
+
+/// Where one room of a merged timeline continues.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct RoomCursor {
+    /// The token to page back from, `None` for the newest page.
+    from: Option<String>,
+    /// Events at or after this were returned already; set while the page at
+    /// `from` was only partly returned.
+    before: Option<u64>,
+    done: bool,
+}
+
+/// Pagination token of a merged timeline: a cursor per room, as JSON.
+type MergedToken = BTreeMap<OwnedRoomId, RoomCursor>;
+
+/// The user's one-to-one DMs grouped by the other person, one entry per
+/// contact, most recently active first. With `deprioritize_merged_dms` on
+/// in the settings, the rooms other than the canonical one are tagged low
+/// priority.
+#[tauri::command]
+pub async fn get_merged_dms(state: State<'_, MatrixState>) -> Result<Vec<MergedDm>, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+
+    let mut contacts: HashMap<OwnedUserId, Vec<Room>> = HashMap::new();
+    for room in client.joined_rooms() {
+        if room.is_space() || room.direct_targets_length() != 1 {
+            continue;
+        }
+        let target = room.direct_targets().iter().find_map(|target| target.as_user_id().map(ToOwned::to_owned));
+        if let Some(user_id) = target {
+            contacts.entry(user_id).or_default().push(room);
+        }
+    }
+
+    let activity = state.room_activity.read().await.clone();
+    let mut merged = Vec::new();
+    for (user_id, rooms) in contacts {
+        let ranked = rank_dms(&activity, rooms).await;
+
+        let canonical = &ranked[0];
+        let display_name = match canonical.get_member_no_sync(&user_id).await {
+            Ok(Some(member)) => member.display_name().map(str::to_string),
+            _ => None,
+        };
+        merged.push(MergedDm {
+            user_id: user_id.to_string(),
+            display_name,
+            canonical_room_id: canonical.room_id().to_string(),
+            room_ids: ranked.iter().map(|room| room.room_id().to_string()).collect(),
+            last_active: ranked.iter().map(|room| last_active(&activity, room)).max().unwrap_or(0),
+        });
+
+        if state.settings.read().await.deprioritize_merged_dms {
+            deprioritize(ranked.iter().skip(1)).await;
+        }
+    }
+
+    merged.sort_by_key(|dm| Reverse(dm.last_active));
+    Ok(merged)
+}
+
+/// The DMs with one contact, the canonical one first: rooms not replaced by
+/// an upgrade before those that were, encrypted before unencrypted, then the
+/// most recently active.
+async fn rank_dms(activity: &HashMap<OwnedRoomId, u64>, rooms: Vec<Room>) -> Vec<Room> {
+    let mut ranked = Vec::new();
+    for room in rooms {
+        let encrypted = room.latest_encryption_state().await.is_ok_and(|s| s.is_encrypted());
+        ranked.push((!room.is_tombstoned(), encrypted, last_active(activity, &room), room));
+    }
+    ranked.sort_by_key(|(current, encrypted, last_active, _)| Reverse((*current, *encrypted, *last_active)));
+    ranked.into_iter().map(|(_, _, _, room)| room).collect()
+}
+
+/// The canonical room of the merged DMs `room` is one of, when that's
+/// another room. Messages to the contact all go there, whichever of their
+/// DMs is open.
+pub(crate) async fn canonical_dm(state: &MatrixState, client: &Client, room: &Room) -> Option<Room> {
+    if room.is_space() || room.direct_targets_length() != 1 {
+        return None;
+    }
+    let target = room.direct_targets().iter().find_map(|target| target.as_user_id().map(ToOwned::to_owned))?;
+    let rooms: Vec<Room> = client
+        .joined_rooms()
+        .into_iter()
+        .filter(|other| {
+            !other.is_space()
+                && other.direct_targets_length() == 1
+                && other.direct_targets().iter().any(|t| t.as_user_id() == Some(&*target))
+        })
+        .collect();
+    if rooms.len() < 2 {
+        return None;
+    }
+
+    let activity = state.room_activity.read().await.clone();
+    let canonical = rank_dms(&activity, rooms).await.into_iter().next()?;
+    (canonical.room_id() != room.room_id()).then_some(canonical)
+}
+
+/// Tags rooms low priority, unless the user already sorted them somewhere.
+async fn deprioritize<'a>(rooms: impl Iterator<Item = &'a Room>) {
+    for room in rooms {
+        if room.is_low_priority() || room.is_favourite() {
+            continue;
+        }
+        match room.set_is_low_priority(true, None).await {
+            Ok(()) => info!("Tagged merged DM {} as low priority", room.room_id()),
+            Err(e) => warn!("Failed to tag {} as low priority: {}", room.room_id(), e),
+        }
+    }
+}
+
+/// A page of the timelines of `room_ids` merged by timestamp, for reading
+/// several DMs with the same person as one conversation. Each message says
+/// which room it is in. Day dividers follow `canonical`'s, whose timeline
+/// this stands in for.
+///
+/// Each room is paged back on its own. A page only goes back as far as the
+/// oldest event fetched from the room that has the most recent such event
+/// and more history; older events wait for the next page, so pages never
+/// overlap in time.
+pub(crate) async fn merged_messages(
+    state: &MatrixState,
+    client: &Client,
+    canonical: &RoomId,
+    room_ids: &[String],
+    from_token: Option<String>,
+    tz_offset_minutes: i32,
+) -> Result<MessagesResponse, String> {
+    let mut rooms = vec![client.get_room(canonical).ok_or("Room not found")?];
+    for room_id in room_ids {
+        let room_id: OwnedRoomId = room_id
+            .parse()
+            .map_err(|e| format!("Invalid room ID: {}", e))?;
+        if rooms.iter().all(|room| room.room_id() != room_id) {
+            rooms.push(client.get_room(&room_id).ok_or("Room not found")?);
+        }
+    }
+
+    let mut cursors: MergedToken = match &from_token {
+        Some(token) => serde_json::from_str(token).map_err(|_| "Invalid pagination token".to_string())?,
+        None => MergedToken::new(),
+    };
+
+    let mut pages: Vec<(Room, Messages, bool)> = Vec::new();
+    for room in rooms {
+        let cursor = cursors.entry(room.room_id().to_owned()).or_default();
+        if cursor.done {
+            continue;
+        }
+        match room.messages(MessagesOptions::backward().from(cursor.from.as_deref())).await {
+            Ok(page) => {
+                let more = has_more(&page);
+                pages.push((room, page, more));
+            }
+            Err(e) if is_archived(&room) || room.state() != RoomState::Joined => {
+                debug!("Leaving {} out of the merged timeline: {}", room.room_id(), e);
+                cursor.done = true;
+            }
+            Err(e) => return Err(format!("Failed to fetch messages: {}", e)),
+        }
+    }
+
+    let windows = {
+        let pages: Vec<_> = pages.iter().map(|(room, page, more)| (room.room_id(), page, *more)).collect();
+        merge_window(&mut cursors, &pages)
+    };
+
+    let mut messages: Vec<Message> = Vec::new();
+    let mut oldest_room: Option<(u64, Room, ProfileResolver)> = None;
+    for ((room, page, _), events) in pages.iter().zip(windows) {
+        let (mut room_messages, profiles) = page_messages(state, room, &page.state, events, true).await;
+        for message in &mut room_messages {
+            message.room_id = Some(room.room_id().to_string());
+        }
+        if let Some(first) = room_messages.first() {
+            if oldest_room.as_ref().is_none_or(|(timestamp, _, _)| first.timestamp < *timestamp) {
+                oldest_room = Some((first.timestamp, room.clone(), profiles));
+            }
+        }
+        messages.extend(room_messages);
+    }
+    messages.sort_by_key(|message| message.timestamp);
+
+    let more = cursors.values().any(|cursor| !cursor.done);
+    let mut days = state.page_days.write().await;
+    let day = days.entry(canonical.to_owned()).or_default();
+    let newer = day.newer(from_token.as_deref());
+    day.served(insert_day_dividers(&mut messages, tz_offset_minutes, newer));
+    drop(days);
+    if let (false, Some((_, room, profiles))) = (more, oldest_room) {
+        start_of_history(&room, &profiles, &mut messages, tz_offset_minutes).await;
+    }
+
+    let next_token = match more {
+        true => Some(serde_json::to_string(&cursors).map_err(|e| e.to_string())?),
+        false => None,
+    };
+    Ok(MessagesResponse {
+        messages,
+        has_more: more,
+        next_token,
+    })
+}
+
+/// Picks the events of each room's page that go on this page of the merged
+/// timeline and moves the room's cursor past them. `pages` holds each
+/// room's page and whether the room has more history.
+fn merge_window<'a>(cursors: &mut MergedToken, pages: &[(&RoomId, &'a Messages, bool)]) -> Vec<Vec<&'a TimelineEvent>> {
+    let oldest = |page: &Messages| page.chunk.iter().map(origin_server_ts).min();
+    let watermark = pages
+        .iter()
+        .filter(|(_, _, more)| *more)
+        .filter_map(|(_, page, _)| oldest(page))
+        .max()
+        .unwrap_or(0);
+
+    pages
+        .iter()
+        .map(|(room_id, page, more)| {
+            let cursor = cursors.entry((*room_id).to_owned()).or_default();
+            let events: Vec<&TimelineEvent> = page
+                .chunk
+                .iter()
+                .filter(|event| cursor.before.is_none_or(|before| origin_server_ts(event) < before))
+                .filter(|event| origin_server_ts(event) >= watermark)
+                .collect();
+
+            if oldest(page).is_none_or(|oldest| oldest >= watermark) {
+                *cursor = RoomCursor {
+                    from: page.end.clone(),
+                    before: None,
+                    done: !more,
+                };
+            } else {
+                cursor.before = Some(cursor.before.map_or(watermark, |before| before.min(watermark)));
+            }
+            events
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::serde::Raw;
+    use matrix_sdk::ruma::room_id;
+
+    fn page(from: &str, end: Option<&str>, timestamps: &[u64]) -> Messages {
+        let chunk = timestamps
+            .iter()
+            .map(|ts| {
+                let json = serde_json::json!({
+                    "type": "m.room.message",
+                    "event_id": format!("${}", ts),
+                    "sender": "@alice:example.org",
+                    "origin_server_ts": ts,
+                    "content": { "msgtype": "m.text", "body": ts.to_string() },
+                });
+                TimelineEvent::from_plaintext(Raw::from_json(serde_json::value::to_raw_value(&json).unwrap()))
+            })
+            .collect();
+        Messages {
+            start: from.to_string(),
+            end: end.map(str::to_string),
+            chunk,
+            state: Vec::new(),
+        }
+    }
+
+    fn timestamps(window: &[&TimelineEvent]) -> Vec<u64> {
+        window.iter().map(|event| origin_server_ts(event)).collect()
+    }
+
+    #[test]
+    fn pages_stop_at_the_newest_oldest_event() {
+        let (a, b) = (room_id!("!a:example.org"), room_id!("!b:example.org"));
+        let mut cursors = MergedToken::new();
+
+        // `a` goes back to 80 and `b` to 50, so only what is newer than 80 is
+        // certain to be complete.
+        let (a1, b1) = (page("a0", Some("a1"), &[100, 90, 80]), page("b0", Some("b1"), &[95, 50]));
+        let windows = merge_window(&mut cursors, &[(a, &a1, true), (b, &b1, true)]);
+        assert_eq!(timestamps(&windows[0]), [100, 90, 80]);
+        assert_eq!(timestamps(&windows[1]), [95]);
+        assert_eq!(cursors[a].from.as_deref(), Some("a1"));
+        // `b` pages from the same token again, skipping what it gave.
+        assert_eq!(cursors[b].from, None);
+        assert_eq!(cursors[b].before, Some(80));
+
+        let a2 = page("a1", Some("a2"), &[70, 60]);
+        let windows = merge_window(&mut cursors, &[(a, &a2, true), (b, &b1, true)]);
+        assert_eq!(timestamps(&windows[0]), [70, 60]);
+        assert!(windows[1].is_empty());
+        assert_eq!(cursors[b].before, Some(60));
+
+        // Once `a` is out of history it doesn't hold `b` back.
+        let a3 = page("a2", None, &[40]);
+        let windows = merge_window(&mut cursors, &[(a, &a3, false), (b, &b1, true)]);
+        assert!(windows[0].is_empty());
+        assert_eq!(timestamps(&windows[1]), [50]);
+        assert_eq!(cursors[a].before, Some(50));
+        assert!(!cursors[a].done);
+        assert_eq!(cursors[b].from.as_deref(), Some("b1"));
+
+        let windows = merge_window(&mut cursors, &[(a, &a3, false)]);
+        assert_eq!(timestamps(&windows[0]), [40]);
+        assert!(cursors[a].done);
+    }
+
+    #[test]
+    fn token_round_trips() {
+        let mut cursors = MergedToken::new();
+        cursors.insert(
+            room_id!("!a:example.org").to_owned(),
+            RoomCursor {
+                from: Some("t1".to_string()),
+                before: Some(5),
+                done: false,
+            },
+        );
+        let token = serde_json::to_string(&cursors).unwrap();
+        let parsed: MergedToken = serde_json::from_str(&token).unwrap();
+        assert_eq!(parsed[room_id!("!a:example.org")].before, Some(5));
+    }
+}
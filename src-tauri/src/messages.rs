@@ -1,14 +1,67 @@
-use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
-use matrix_sdk::ruma::OwnedRoomId;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use matrix_sdk::attachment::{AttachmentConfig, AttachmentInfo, BaseImageInfo, BaseVideoInfo, Thumbnail};
+use matrix_sdk::media::{MediaFormat, MediaRequestParameters};
+use matrix_sdk::ruma::events::reaction::ReactionEventContent;
+use matrix_sdk::ruma::events::relation::Annotation;
+use matrix_sdk::ruma::events::room::message::sanitize::remove_plain_reply_fallback;
+use matrix_sdk::ruma::events::room::message::{
+    FormattedBody, MessageType, Relation, RoomMessageEventContent, TextMessageEventContent,
+};
+use matrix_sdk::ruma::events::room::MediaSource;
+use matrix_sdk::ruma::{
+    EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedMxcUri, OwnedRoomId, OwnedTransactionId,
+    TransactionId, UInt,
+};
+use matrix_sdk::{Client, Room};
+use serde_json::json;
 use tauri::State;
+use tracing::{debug, info};
 
+use crate::emoji::expand_shortcodes;
+use crate::encryption_debug::{own_device_blocker, send_readiness, SendReadiness};
+use crate::errors::CommandError;
+use crate::formatting::spoiler_bodies;
+use crate::identity_changes::identity_violations;
+use crate::merged_dms::canonical_dm;
+use crate::outbound::{paced, OutboundClass};
+use crate::rooms::{is_animated_mimetype, read_only_reason};
 use crate::state::MatrixState;
+use crate::transactions::{begin_transaction, complete_transaction};
+
+/// Longest unicode reaction key. Keys are meant to be an emoji or a short
+/// word; the longest emoji sequences are around 30 bytes.
+const MAX_REACTION_KEY_BYTES: usize = 64;
+/// Longest custom emote reaction key, the emote's mxc URI.
+const MAX_EMOTE_KEY_BYTES: usize = 255;
+
+/// How long the plaintext of a message we sent is kept for display.
+const SENT_PLAINTEXT_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// A message sent from this device, kept until its event decrypts normally.
+pub struct SentPlaintext {
+    pub body: String,
+    pub event_id: Option<OwnedEventId>,
+    queued: Instant,
+}
 
+/// Sends a text message. `transaction_id`, from `new_transaction_id`,
+/// makes retries safe: pass the same one to every attempt at this message
+/// and a retry of a send that actually went through returns its event
+/// instead of posting it again. A failure carries it in `details`. A message
+/// to one of several DMs with the same person goes to their canonical room,
+/// see `get_merged_dms`.
 #[tauri::command]
 pub async fn send_message(
     state: State<'_, MatrixState>,
     room_id: String,
     message: String,
+    expand_emoji_shortcodes: Option<bool>,
+    transaction_id: Option<String>,
 ) -> Result<String, String> {
     let client = state.client.read().await;
     let client = client.as_ref().ok_or("Not logged in")?;
@@ -20,13 +73,639 @@ pub async fn send_message(
     let room = client
         .get_room(&room_id)
         .ok_or("Room not found")?;
+    let room = match canonical_dm(&state, client, &room).await {
+        Some(canonical) => {
+            debug!("Sending to {} instead of its merged DM {}", canonical.room_id(), room_id);
+            canonical
+        }
+        None => room,
+    };
+
+    let txn_id = transaction_id.map(parse_transaction_id).transpose()?;
+    let event_id = send_text(&state, client, &room, &message, expand_emoji_shortcodes, txn_id, |content| content).await?;
+    Ok(event_id.to_string())
+}
+
+/// Parses a transaction ID from `new_transaction_id`.
+pub(crate) fn parse_transaction_id(transaction_id: String) -> Result<OwnedTransactionId, String> {
+    match transaction_id.trim().is_empty() {
+        true => Err("Invalid transaction ID: empty string".to_string()),
+        false => Ok(transaction_id.trim().into()),
+    }
+}
+
+/// Sends `message` as a text message, with `relate` adding any reply, edit
+/// or thread relation to the content first. A retry passing the `txn_id` of
+/// an earlier attempt returns that attempt's event when it went through, and
+/// otherwise reuses the ID so the server drops a duplicate; every error
+/// carries the ID in `details.transaction_id` for that retry. Encrypted
+/// rooms refuse sends from a device that isn't cross-signed yet.
+pub(crate) async fn send_text(
+    state: &MatrixState,
+    client: &Client,
+    room: &Room,
+    message: &str,
+    expand_emoji_shortcodes: Option<bool>,
+    txn_id: Option<OwnedTransactionId>,
+    relate: impl FnOnce(RoomMessageEventContent) -> RoomMessageEventContent,
+) -> Result<OwnedEventId, String> {
+    let txn_id = txn_id.unwrap_or_else(TransactionId::new);
+
+    if let Some(reason) = read_only_reason(room).await {
+        return Err(CommandError::new(
+            reason.code(),
+            format!("You can't post here: {}", reason.message()),
+        )
+        .with_details(json!({ "transaction_id": txn_id }))
+        .into());
+    }
+
+    let changed = identity_violations(room).await;
+    if !changed.is_empty() {
+        return Err(CommandError::new(
+            "IDENTITY_CHANGED",
+            "The identity of someone in this room changed. Review it before sending.",
+        )
+        .with_details(json!({ "users": changed, "transaction_id": txn_id }))
+        .into());
+    }
+
+    if room.encryption_settings().is_some() {
+        let blocker = own_device_blocker(client).await.map_err(|e| {
+            String::from(CommandError::new("SEND_FAILED", e).with_details(json!({ "transaction_id": txn_id })))
+        })?;
+        if let Some(blocker) = blocker {
+            let readiness = SendReadiness {
+                room_id: room.room_id().to_string(),
+                encrypted: true,
+                ready: false,
+                blockers: vec![blocker],
+            };
+            return Err(not_ready_error(&readiness, &txn_id));
+        }
+    }
+
+    let expand = match expand_emoji_shortcodes {
+        Some(expand) => expand,
+        None => state.settings.read().await.expand_emoji_shortcodes,
+    };
+    let body = if expand {
+        expand_shortcodes(message.trim())
+    } else {
+        message.trim().to_string()
+    };
+
+    // `||spoiler||` needs an HTML body; the plain one hides what it covers.
+    let content = match spoiler_bodies(&body) {
+        Some((plain, html)) => RoomMessageEventContent::text_html(plain, html),
+        None => RoomMessageEventContent::text_plain(body.clone()),
+    };
+    let content = relate(content);
+
+    if let Some(event_id) = begin_transaction(state, room.room_id(), &txn_id).await? {
+        return Ok(event_id);
+    }
+    if room.encryption_settings().is_some() {
+        remember_plaintext(state, &txn_id, body).await;
+    }
+
+    let queued_at = MilliSecondsSinceUnixEpoch::now();
+    let description = format!("Message to {}", room.room_id());
+    let request = |config| {
+        room.send(content.clone())
+            .with_transaction_id(txn_id.clone())
+            .with_request_config(config)
+    };
+    let response = match paced(state, OutboundClass::Messages, description, request).await {
+        Ok(response) => response,
+        Err(e) => {
+            state.sent_plaintext.write().await.remove(&txn_id);
+            // When encrypting failed, explain what is missing rather than
+            // passing on a generic failure.
+            if is_crypto_error(&e) {
+                if let Ok(readiness) = send_readiness(client, room).await {
+                    if !readiness.ready {
+                        return Err(not_ready_error(&readiness, &txn_id));
+                    }
+                }
+            }
+            return Err(CommandError::new("SEND_FAILED", format!("Failed to send: {}", e))
+                .with_details(json!({ "transaction_id": txn_id }))
+                .into());
+        }
+    };
+    complete_transaction(state, &txn_id, &response.event_id).await;
+
+    record_sent(state, &response.event_id, queued_at).await;
+    if let Some(sent) = state.sent_plaintext.write().await.get_mut(&txn_id) {
+        sent.event_id = Some(response.event_id.clone());
+    }
+
+    Ok(response.event_id)
+}
+
+/// `ENCRYPTION_NOT_READY` with the first blocker as its message, and the
+/// readiness and the transaction ID as its details.
+fn not_ready_error(readiness: &SendReadiness, txn_id: &TransactionId) -> String {
+    let message = readiness.blockers.first().map(|blocker| blocker.message.clone()).unwrap_or_default();
+    let mut details = json!(readiness);
+    details["transaction_id"] = json!(txn_id);
+    CommandError::new("ENCRYPTION_NOT_READY", message).with_details(details).into()
+}
+
+/// Whether a send failed while encrypting, rather than on the way to or at
+/// the server.
+fn is_crypto_error(error: &matrix_sdk::Error) -> bool {
+    matches!(
+        error,
+        matrix_sdk::Error::OlmError(_)
+            | matrix_sdk::Error::MegolmError(_)
+            | matrix_sdk::Error::CryptoStoreError(_)
+            | matrix_sdk::Error::NoOlmMachine
+            | matrix_sdk::Error::BadCryptoStoreState
+    )
+}
+
+/// Reacts to an event. `key` is either a unicode emoji or, for image pack
+/// emotes, the emote's mxc URI together with its `shortcode`.
+#[tauri::command]
+pub async fn send_reaction(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    event_id: String,
+    key: String,
+    shortcode: Option<String>,
+) -> Result<String, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let event_id: OwnedEventId = event_id
+        .parse()
+        .map_err(|e| format!("Invalid event ID: {}", e))?;
+
+    let room = client
+        .get_room(&room_id)
+        .ok_or("Room not found")?;
+
+    if key.trim().is_empty() {
+        return Err("Reaction key is required".to_string());
+    }
+    if !reaction_key_fits(&key) {
+        return Err("Reaction key is too long".to_string());
+    }
+
+    let description = format!("Reaction in {}", room_id);
+    let response = if key.starts_with("mxc://") {
+        let mxc = OwnedMxcUri::from(key.as_str());
+        if !mxc.is_valid() {
+            return Err("Invalid emote URI".to_string());
+        }
+        let shortcode = shortcode.ok_or("Custom emote reactions need a shortcode")?;
+        let content = json!({
+            "m.relates_to": {
+                "rel_type": "m.annotation",
+                "event_id": event_id,
+                "key": key,
+            },
+            "shortcode": shortcode,
+            "com.beeper.reaction.shortcode": shortcode,
+        });
+        paced(&state, OutboundClass::Messages, description, |config| {
+            room.send_raw("m.reaction", content.clone()).with_request_config(config)
+        })
+        .await
+        .map_err(|e| format!("Failed to send reaction: {}", e))?
+    } else {
+        let content = ReactionEventContent::new(Annotation::new(event_id, key));
+        paced(&state, OutboundClass::Messages, description, |config| {
+            room.send(content.clone()).with_request_config(config)
+        })
+        .await
+        .map_err(|e| format!("Failed to send reaction: {}", e))?
+    };
+
+    Ok(response.event_id.to_string())
+}
+
+/// Sends a copy of a message to another room. Formatting is kept, while
+/// reply fallbacks and relations are dropped so the copy stands alone.
+/// Media is re-uploaded when the copy can't reuse the original file: an
+/// encrypted file can't be shared into an unencrypted room, and a plain file
+/// is encrypted when forwarded into an encrypted one. Returns the new event
+/// ID.
+#[tauri::command]
+pub async fn forward_message(
+    state: State<'_, MatrixState>,
+    source_room_id: String,
+    event_id: String,
+    target_room_id: String,
+) -> Result<String, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let source_room_id: OwnedRoomId = source_room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let target_room_id: OwnedRoomId = target_room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let event_id: OwnedEventId = event_id
+        .parse()
+        .map_err(|e| format!("Invalid event ID: {}", e))?;
+
+    let source = client.get_room(&source_room_id).ok_or("Room not found")?;
+    let target = client.get_room(&target_room_id).ok_or("Room not found")?;
+
+    if let Some(reason) = read_only_reason(&target).await {
+        return Err(CommandError::new(
+            reason.code(),
+            format!("You can't post here: {}", reason.message()),
+        )
+        .into());
+    }
+
+    let event = source
+        .event(&event_id, None)
+        .await
+        .map_err(|e| format!("Failed to fetch message: {}", e))?;
+    if event.raw().get_field::<String>("type").ok().flatten().as_deref() != Some("m.room.message") {
+        return Err("Only messages can be forwarded".to_string());
+    }
+    let mut content: RoomMessageEventContent = event
+        .raw()
+        .get_field("content")
+        .ok()
+        .flatten()
+        .ok_or("This message can't be forwarded")?;
+
+    if matches!(content.relates_to, Some(Relation::Reply { .. })) {
+        strip_reply_fallback(&mut content.msgtype);
+    }
+    content.relates_to = None;
+    content.mentions = None;
+
+    let target_encrypted = target.encryption_settings().is_some();
+    match &mut content.msgtype {
+        MessageType::Image(image) => {
+            let mimetype = image.info.as_ref().and_then(|info| info.mimetype.clone());
+            image.source = forward_media(client, &image.source, mimetype, target_encrypted).await?;
+            drop_thumbnail(image.info.as_deref_mut().map(|info| &mut info.thumbnail_source));
+        }
+        MessageType::Video(video) => {
+            let mimetype = video.info.as_ref().and_then(|info| info.mimetype.clone());
+            video.source = forward_media(client, &video.source, mimetype, target_encrypted).await?;
+            drop_thumbnail(video.info.as_deref_mut().map(|info| &mut info.thumbnail_source));
+        }
+        MessageType::Audio(audio) => {
+            let mimetype = audio.info.as_ref().and_then(|info| info.mimetype.clone());
+            audio.source = forward_media(client, &audio.source, mimetype, target_encrypted).await?;
+        }
+        MessageType::File(file) => {
+            let mimetype = file.info.as_ref().and_then(|info| info.mimetype.clone());
+            file.source = forward_media(client, &file.source, mimetype, target_encrypted).await?;
+            drop_thumbnail(file.info.as_deref_mut().map(|info| &mut info.thumbnail_source));
+        }
+        _ => {}
+    }
+
+    let queued_at = MilliSecondsSinceUnixEpoch::now();
+    let description = format!("Forwarded message to {}", target_room_id);
+    let response = paced(&state, OutboundClass::Messages, description, |config| {
+        target.send(content.clone()).with_request_config(config)
+    })
+    .await
+    .map_err(|e| format!("Failed to forward message: {}", e))?;
+
+    record_sent(&state, &response.event_id, queued_at).await;
+
+    info!("Forwarded {} from {} to {}", event_id, source_room_id, target_room_id);
+
+    Ok(response.event_id.to_string())
+}
+
+/// Uploads and sends a video, encrypting it in encrypted rooms. The
+/// frontend supplies the metadata and, optionally, a thumbnail image it
+/// extracted from the video; without one the event simply has no thumbnail.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn send_video(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    file_path: String,
+    thumbnail_path: Option<String>,
+    duration_ms: Option<u64>,
+    width: Option<u32>,
+    height: Option<u32>,
+    caption: Option<String>,
+) -> Result<String, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
 
-    let content = RoomMessageEventContent::text_plain(message.trim());
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let path = Path::new(&file_path);
+    let data = fs::read(path).map_err(|e| format!("Failed to read video: {}", e))?;
+    let filename = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "video".to_string());
+    let content_type = mime_guess::from_path(path).first_or_octet_stream();
+    if content_type.type_() != mime::VIDEO {
+        return Err(format!("{} is not a video file", filename));
+    }
+
+    let thumbnail = match thumbnail_path {
+        Some(thumbnail_path) => Some(load_thumbnail(Path::new(&thumbnail_path))?),
+        None => None,
+    };
 
-    let response = room
-        .send(content)
+    let info = BaseVideoInfo {
+        duration: duration_ms.map(Duration::from_millis),
+        width: width.map(UInt::from),
+        height: height.map(UInt::from),
+        size: UInt::new(data.len() as u64),
+        blurhash: None,
+    };
+
+    let caption = caption
+        .map(|c| c.trim().to_string())
+        .filter(|c| !c.is_empty())
+        .map(TextMessageEventContent::plain);
+
+    let queued_at = MilliSecondsSinceUnixEpoch::now();
+    let description = format!("Video to {}", room_id);
+    // `SendAttachment` takes no request config, so the SDK retries 429s of
+    // the upload and send itself, and only one it gave up on is queued again.
+    let request = |_| {
+        let attachment = AttachmentConfig::new()
+            .info(AttachmentInfo::Video(info.clone()))
+            .thumbnail(thumbnail.as_ref().map(copy_thumbnail))
+            .caption(caption.clone());
+        room.send_attachment(filename.clone(), &content_type, data.clone(), attachment)
+    };
+    let response = paced(&state, OutboundClass::Messages, description, request)
         .await
-        .map_err(|e| format!("Failed to send: {}", e))?;
+        .map_err(|e| format!("Failed to send video: {}", e))?;
+
+    record_sent(&state, &response.event_id, queued_at).await;
 
     Ok(response.event_id.to_string())
 }
+
+/// Uploads and sends an image as-is, encrypting it in encrypted rooms. The
+/// dimensions, size and whether it is animated are read from the file. For
+/// a GIF or APNG the frontend can pass a still frame as the thumbnail, which
+/// other clients show instead of autoplaying the original.
+#[tauri::command]
+pub async fn send_image(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    file_path: String,
+    thumbnail_path: Option<String>,
+    caption: Option<String>,
+) -> Result<String, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let path = Path::new(&file_path);
+    let data = fs::read(path).map_err(|e| format!("Failed to read image: {}", e))?;
+    let filename = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "image".to_string());
+    let content_type = mime_guess::from_path(path).first_or_octet_stream();
+    if content_type.type_() != mime::IMAGE {
+        return Err(format!("{} is not an image file", filename));
+    }
+    let dimensions = imagesize::blob_size(&data)
+        .map_err(|e| format!("Failed to read image dimensions: {}", e))?;
+
+    let thumbnail = match thumbnail_path {
+        Some(thumbnail_path) => {
+            let thumbnail = load_thumbnail(Path::new(&thumbnail_path))?;
+            if is_animated_image(thumbnail.content_type.essence_str(), &thumbnail.data) {
+                return Err("The thumbnail must be a still image".to_string());
+            }
+            Some(thumbnail)
+        }
+        None => None,
+    };
+
+    let info = BaseImageInfo {
+        width: UInt::new(dimensions.width as u64),
+        height: UInt::new(dimensions.height as u64),
+        size: UInt::new(data.len() as u64),
+        blurhash: None,
+        is_animated: Some(is_animated_image(content_type.essence_str(), &data)),
+    };
+
+    let caption = caption
+        .map(|c| c.trim().to_string())
+        .filter(|c| !c.is_empty())
+        .map(TextMessageEventContent::plain);
+
+    let queued_at = MilliSecondsSinceUnixEpoch::now();
+    let description = format!("Image to {}", room_id);
+    let request = |_| {
+        let attachment = AttachmentConfig::new()
+            .info(AttachmentInfo::Image(info.clone()))
+            .thumbnail(thumbnail.as_ref().map(copy_thumbnail))
+            .caption(caption.clone());
+        room.send_attachment(filename.clone(), &content_type, data.clone(), attachment)
+    };
+    let response = paced(&state, OutboundClass::Messages, description, request)
+        .await
+        .map_err(|e| format!("Failed to send image: {}", e))?;
+
+    record_sent(&state, &response.event_id, queued_at).await;
+
+    Ok(response.event_id.to_string())
+}
+
+/// Remembers when an outgoing event was queued, which `get_messages` reports
+/// as its `received_at`.
+pub(crate) async fn record_sent(state: &MatrixState, event_id: &EventId, queued_at: MilliSecondsSinceUnixEpoch) {
+    state
+        .sent_at
+        .write()
+        .await
+        .insert(event_id.to_owned(), queued_at.get().into());
+}
+
+/// Keeps the body of a message this device is sending to an encrypted room,
+/// so it can be shown even if the event comes back before we can decrypt it.
+/// Entries older than `SENT_PLAINTEXT_TTL` are dropped.
+async fn remember_plaintext(state: &MatrixState, txn_id: &TransactionId, body: String) {
+    let mut sent = state.sent_plaintext.write().await;
+    sent.retain(|_, entry| entry.queued.elapsed() < SENT_PLAINTEXT_TTL);
+    sent.insert(
+        txn_id.to_owned(),
+        SentPlaintext {
+            body,
+            event_id: None,
+            queued: Instant::now(),
+        },
+    );
+}
+
+/// The body we sent as `event_id`, if it is still remembered.
+pub(crate) fn sent_plaintext(sent: &HashMap<OwnedTransactionId, SentPlaintext>, event_id: &EventId) -> Option<String> {
+    sent.values()
+        .find(|entry| entry.event_id.as_deref() == Some(event_id) && entry.queued.elapsed() < SENT_PLAINTEXT_TTL)
+        .map(|entry| entry.body.clone())
+}
+
+/// Where the forwarded copy of a media file should point. The original is
+/// reused unless it is encrypted and the target isn't, or the other way
+/// round; then it is downloaded (and decrypted) and uploaded again.
+async fn forward_media(
+    client: &Client,
+    source: &MediaSource,
+    mimetype: Option<String>,
+    target_encrypted: bool,
+) -> Result<MediaSource, String> {
+    let source_encrypted = matches!(source, MediaSource::Encrypted(_));
+    if source_encrypted == target_encrypted {
+        return Ok(source.clone());
+    }
+
+    let request = MediaRequestParameters {
+        source: source.clone(),
+        format: MediaFormat::File,
+    };
+    let data = client
+        .media()
+        .get_media_content(&request, true)
+        .await
+        .map_err(|e| format!("Failed to download media: {}", e))?;
+
+    if target_encrypted {
+        let file = client
+            .upload_encrypted_file(&mut Cursor::new(data))
+            .await
+            .map_err(|e| format!("Failed to upload media: {}", e))?;
+        Ok(MediaSource::Encrypted(Box::new(file)))
+    } else {
+        let content_type: mime::Mime = mimetype
+            .and_then(|m| m.parse().ok())
+            .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+        let response = client
+            .media()
+            .upload(&content_type, data, None)
+            .await
+            .map_err(|e| format!("Failed to upload media: {}", e))?;
+        Ok(MediaSource::Plain(response.content_uri))
+    }
+}
+
+/// Removes the quoted original that replies carry for clients without reply
+/// support, from both the plain and the formatted body.
+fn strip_reply_fallback(msgtype: &mut MessageType) {
+    let (body, formatted) = match msgtype {
+        MessageType::Text(text) => (&mut text.body, &mut text.formatted),
+        MessageType::Notice(notice) => (&mut notice.body, &mut notice.formatted),
+        MessageType::Emote(emote) => (&mut emote.body, &mut emote.formatted),
+        _ => return,
+    };
+
+    *body = remove_plain_reply_fallback(body).to_string();
+    if let Some(FormattedBody { body: html, .. }) = formatted {
+        if let (Some(start), Some(end)) = (html.find("<mx-reply>"), html.find("</mx-reply>")) {
+            if start < end {
+                html.replace_range(start..end + "</mx-reply>".len(), "");
+            }
+        }
+    }
+}
+
+/// Thumbnails aren't copied along with forwarded media.
+fn drop_thumbnail(thumbnail_source: Option<&mut Option<MediaSource>>) {
+    if let Some(thumbnail_source) = thumbnail_source {
+        *thumbnail_source = None;
+    }
+}
+
+fn load_thumbnail(path: &Path) -> Result<Thumbnail, String> {
+    let data = fs::read(path).map_err(|e| format!("Failed to read thumbnail: {}", e))?;
+    let content_type = mime_guess::from_path(path).first_or_octet_stream();
+    if content_type.type_() != mime::IMAGE {
+        return Err("The thumbnail must be an image".to_string());
+    }
+    let dimensions = imagesize::blob_size(&data)
+        .map_err(|e| format!("Failed to read thumbnail dimensions: {}", e))?;
+
+    Ok(Thumbnail {
+        width: UInt::new(dimensions.width as u64).unwrap_or_default(),
+        height: UInt::new(dimensions.height as u64).unwrap_or_default(),
+        size: UInt::new(data.len() as u64).unwrap_or_default(),
+        content_type,
+        data,
+    })
+}
+
+fn copy_thumbnail(thumbnail: &Thumbnail) -> Thumbnail {
+    Thumbnail {
+        data: thumbnail.data.clone(),
+        content_type: thumbnail.content_type.clone(),
+        height: thumbnail.height,
+        width: thumbnail.width,
+        size: thumbnail.size,
+    }
+}
+
+/// Whether an image file is animated: any GIF, and PNGs with an animation
+/// control chunk ahead of their image data, which is what makes them APNGs.
+fn is_animated_image(mimetype: &str, data: &[u8]) -> bool {
+    if is_animated_mimetype(mimetype) {
+        return true;
+    }
+    if mimetype != "image/png" || !data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return false;
+    }
+
+    let mut offset = 8;
+    while let Some(header) = data.get(offset..offset + 8) {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        match &header[4..] {
+            b"acTL" => return true,
+            b"IDAT" | b"IEND" => return false,
+            _ => offset = offset.saturating_add(12).saturating_add(length),
+        }
+    }
+    false
+}
+
+fn reaction_key_fits(key: &str) -> bool {
+    let max = if key.starts_with("mxc://") { MAX_EMOTE_KEY_BYTES } else { MAX_REACTION_KEY_BYTES };
+    key.len() <= max
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emoji_reaction_keys_fit() {
+        assert!(reaction_key_fits("👍"));
+        assert!(reaction_key_fits("👨‍👩‍👧‍👦"));
+        assert!(reaction_key_fits("🏴󠁧󠁢󠁳󠁣󠁴󠁿"));
+        assert!(reaction_key_fits("mxc://example.org/abcdefghijklmnopqrstuvwxyz"));
+    }
+
+    #[test]
+    fn thousand_emoji_reaction_key_is_rejected() {
+        assert!(!reaction_key_fits(&"😀".repeat(1000)));
+        assert!(!reaction_key_fits(&format!("mxc://example.org/{}", "a".repeat(300))));
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use matrix_sdk::deserialized_responses::RawAnySyncOrStrippedTimelineEvent;
+use matrix_sdk::ruma::events::receipt::{ReceiptThread, ReceiptType, SyncReceiptEvent};
+use matrix_sdk::ruma::events::room::message::SyncRoomMessageEvent;
+use matrix_sdk::ruma::events::{AnySyncMessageLikeEvent, AnySyncTimelineEvent};
+use matrix_sdk::ruma::push::Action;
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedRoomId};
+use matrix_sdk::sync::Notification;
+use matrix_sdk::{Client, Room};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::{AppHandle, Emitter, Manager, State};
+use tokio::sync::RwLock;
+use tracing::debug;
+
+use crate::formatting::{contains_spoiler, parse_formatted, plain_text};
+use crate::push_rules::{cached_ruleset, push_context, PushRulesCache};
+use crate::room_notifications::{matches_keyword, room_keywords};
+use crate::rooms::{formatted_html, origin_server_ts};
+use crate::settings::Settings;
+use crate::snooze::is_snoozed;
+use crate::state::MatrixState;
+use crate::threads::{mentions_user, thread_notifies, thread_root, ThreadParticipation};
+
+/// Notified events remembered per room, oldest dropped first.
+const MAX_NOTIFIED_PER_ROOM: usize = 50;
+/// Notification body shown in place of the message while the app is locked.
+const LOCKED_BODY: &str = "New message";
+
+pub type NotificationTracker = Arc<RwLock<HashMap<OwnedRoomId, RoomNotifications>>>;
+
+/// The room the user is looking at, as last told by `set_active_room`.
+pub type ActiveRoom = Arc<RwLock<Option<OwnedRoomId>>>;
+
+/// What the notification pipeline knows about one room.
+#[derive(Default)]
+pub struct RoomNotifications {
+    /// Timestamp of the newest event any of our sessions has read.
+    read_up_to: u64,
+    /// Events a notification was shown for, with their timestamps.
+    notified: Vec<(OwnedEventId, u64)>,
+}
+
+/// How loud a notification should be, from the push rule actions.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationImportance {
+    Highlight,
+    /// Notifies with a sound.
+    Default,
+    /// Notifies silently.
+    Low,
+    None,
+}
+
+/// Payload of `matrix://notification`. While the app is locked, the room
+/// name, sender and body are withheld; the body then only says a message
+/// arrived.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MessageNotification {
+    pub room_id: String,
+    pub room_name: Option<String>,
+    pub event_id: String,
+    pub sender: String,
+    pub sender_name: Option<String>,
+    pub body: String,
+    pub timestamp: u64,
+    pub importance: NotificationImportance,
+    /// The push rule's `sound` tweak, e.g. `default`.
+    pub sound: Option<String>,
+    pub is_dm: bool,
+    /// The event mentions the user or the whole room.
+    pub is_mention: bool,
+    /// The body contains one of the user's keywords.
+    pub is_keyword: bool,
+    /// The room is the one open in the app. Only highlights are sent then.
+    pub viewing_room: bool,
+}
+
+/// Payload of `matrix://dismiss-notification`: these were read elsewhere.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DismissNotifications {
+    pub room_id: String,
+    pub event_ids: Vec<String>,
+}
+
+/// Unread counts for the app badge.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NotificationBadge {
+    pub notifications: u64,
+    pub highlights: u64,
+}
+
+/// Unread notifications and highlights across joined rooms, for the app
+/// badge. Snoozed rooms don't count, nor does anything while snoozed
+/// globally.
+#[tauri::command]
+pub async fn get_notification_badge(state: State<'_, MatrixState>) -> Result<NotificationBadge, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+    let settings = state.settings.read().await;
+
+    let mut badge = NotificationBadge {
+        notifications: 0,
+        highlights: 0,
+    };
+    for room in client.joined_rooms() {
+        if is_snoozed(&settings, room.room_id()) {
+            continue;
+        }
+        badge.notifications += room.num_unread_notifications();
+        badge.highlights += room.num_unread_mentions();
+    }
+    Ok(badge)
+}
+
+/// Records the room the user is looking at, or `None` when no room is in
+/// view or the window is in the background. Called on navigation.
+#[tauri::command]
+pub async fn set_active_room(state: State<'_, MatrixState>, room_id: Option<String>) -> Result<(), String> {
+    let room_id = room_id
+        .map(|id| OwnedRoomId::try_from(id).map_err(|e| format!("Invalid room ID: {}", e)))
+        .transpose()?;
+    *state.active_room.write().await = room_id;
+    Ok(())
+}
+
+/// Notification handler: emits `matrix://notification` for events push
+/// rules say to notify about, unless the room is snoozed, one of our
+/// sessions already read past them, they are thread replies the room's
+/// thread mode filters out, or the user is looking at the room and they
+/// aren't highlights.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn on_notification(
+    notification: Notification,
+    room: Room,
+    client: Client,
+    app: AppHandle,
+    tracker: NotificationTracker,
+    settings: Arc<RwLock<Settings>>,
+    participation: ThreadParticipation,
+    active_room: ActiveRoom,
+    push_rules: PushRulesCache,
+) {
+    let RawAnySyncOrStrippedTimelineEvent::Sync(raw) = notification.event else {
+        return;
+    };
+    // The cached rules may have a keyword change the SDK's copy hasn't.
+    let actions = match push_context(&push_rules, &room).await {
+        Some(context) => context.for_event(&raw).await,
+        None => notification.actions,
+    };
+    let importance = importance(&actions);
+    if importance == NotificationImportance::None {
+        return;
+    }
+    if is_snoozed(&*settings.read().await, room.room_id()) {
+        debug!("Not notifying about an event in {}, snoozed", room.room_id());
+        return;
+    }
+    let Ok(event) = raw.deserialize() else {
+        return;
+    };
+    if Some(event.sender()) == client.user_id() {
+        return;
+    }
+    if let Some(root) = thread_root(&raw) {
+        if !thread_notifies(&room, &client, &settings, &participation, &raw, &root).await {
+            debug!("Not notifying about {}, muted by thread settings", event.event_id());
+            return;
+        }
+    }
+
+    let event_id = event.event_id().to_owned();
+    let timestamp: u64 = event.origin_server_ts().get().into();
+    let room_id = room.room_id().to_owned();
+
+    let viewing_room = active_room.read().await.as_ref() == Some(&room_id);
+    if viewing_room && importance != NotificationImportance::Highlight {
+        debug!("Not notifying about {}, the room is open", event_id);
+        return;
+    }
+
+    let read_up_to = match tracker.read().await.get(&room_id) {
+        Some(known) => known.read_up_to,
+        None => own_read_position(&room, &client).await,
+    };
+    let mut tracker = tracker.write().await;
+    let notifications = tracker.entry(room_id.clone()).or_default();
+    notifications.read_up_to = notifications.read_up_to.max(read_up_to);
+    if timestamp <= notifications.read_up_to {
+        debug!("Not notifying about {}, already read", event_id);
+        return;
+    }
+    notifications.notified.push((event_id.clone(), timestamp));
+    if notifications.notified.len() > MAX_NOTIFIED_PER_ROOM {
+        notifications.notified.remove(0);
+    }
+    drop(tracker);
+
+    let sender_name = match room.get_member_no_sync(event.sender()).await {
+        Ok(Some(member)) => member.display_name().map(str::to_string),
+        _ => None,
+    };
+    let body = notification_body(&event);
+    let is_mention = client.user_id().is_some_and(|user_id| mentions_user(&raw, user_id)) || mentions_room(&raw);
+    let is_keyword = match cached_ruleset(&push_rules, &client).await {
+        Some(ruleset) => room_keywords(&ruleset, &room_id)
+            .iter()
+            .any(|keyword| matches_keyword(&body, keyword)),
+        None => false,
+    };
+    let locked = app.state::<MatrixState>().app_locked.load(Ordering::SeqCst);
+    let room_name = match locked {
+        true => None,
+        false => room.display_name().await.ok().map(|name| name.to_string()),
+    };
+    let _ = app.emit(
+        "matrix://notification",
+        MessageNotification {
+            room_id: room_id.to_string(),
+            room_name,
+            event_id: event_id.to_string(),
+            sender: if locked { String::new() } else { event.sender().to_string() },
+            sender_name: sender_name.filter(|_| !locked),
+            body: if locked { LOCKED_BODY.to_string() } else { body },
+            timestamp,
+            importance,
+            sound: actions.iter().find_map(Action::sound).map(str::to_string),
+            is_dm: room.is_direct().await.unwrap_or(false),
+            is_mention,
+            is_keyword,
+            viewing_room,
+        },
+    );
+}
+
+/// Sync handler for receipts: when one of our sessions reads a room, moves
+/// its read position forward and dismisses the notifications that covers.
+pub(crate) async fn on_receipt(
+    event: SyncReceiptEvent,
+    room: Room,
+    client: Client,
+    app: AppHandle,
+    tracker: NotificationTracker,
+) {
+    let Some(user_id) = client.user_id() else {
+        return;
+    };
+    let own_receipts: Vec<&EventId> = event
+        .content
+        .iter()
+        .filter(|(_, receipts)| {
+            [ReceiptType::Read, ReceiptType::ReadPrivate]
+                .iter()
+                .any(|kind| receipts.get(kind).is_some_and(|users| users.contains_key(user_id)))
+        })
+        .map(|(event_id, _)| event_id.as_ref())
+        .collect();
+    if own_receipts.is_empty() {
+        return;
+    }
+
+    let mut read_up_to = 0;
+    for event_id in own_receipts {
+        read_up_to = read_up_to.max(event_timestamp(&room, &tracker, event_id).await.unwrap_or(0));
+    }
+
+    let room_id = room.room_id().to_owned();
+    let mut tracker = tracker.write().await;
+    let notifications = tracker.entry(room_id.clone()).or_default();
+    notifications.read_up_to = notifications.read_up_to.max(read_up_to);
+    let read_up_to = notifications.read_up_to;
+
+    let (read, unread): (Vec<_>, Vec<_>) = notifications
+        .notified
+        .drain(..)
+        .partition(|(_, timestamp)| *timestamp <= read_up_to);
+    notifications.notified = unread;
+    drop(tracker);
+
+    if !read.is_empty() {
+        let _ = app.emit(
+            "matrix://dismiss-notification",
+            DismissNotifications {
+                room_id: room_id.to_string(),
+                event_ids: read.into_iter().map(|(event_id, _)| event_id.to_string()).collect(),
+            },
+        );
+    }
+}
+
+/// Timestamp of the event our newest read receipt in the room points at, or
+/// 0 when there is none.
+async fn own_read_position(room: &Room, client: &Client) -> u64 {
+    let Some(user_id) = client.user_id() else {
+        return 0;
+    };
+    let mut newest = 0;
+    for kind in [ReceiptType::Read, ReceiptType::ReadPrivate] {
+        if let Ok(Some((event_id, _))) = room.load_user_receipt(kind, ReceiptThread::Unthreaded, user_id).await {
+            if let Ok(event) = room.load_or_fetch_event(&event_id, None).await {
+                newest = newest.max(origin_server_ts(&event));
+            }
+        }
+    }
+    newest
+}
+
+async fn event_timestamp(room: &Room, tracker: &NotificationTracker, event_id: &EventId) -> Option<u64> {
+    let notified = tracker
+        .read()
+        .await
+        .get(room.room_id())
+        .and_then(|n| n.notified.iter().find(|(id, _)| id == event_id).map(|(_, ts)| *ts));
+    if notified.is_some() {
+        return notified;
+    }
+    let event = room.load_or_fetch_event(event_id, None).await.ok()?;
+    Some(origin_server_ts(&event))
+}
+
+fn importance(actions: &[Action]) -> NotificationImportance {
+    if !actions.iter().any(Action::should_notify) {
+        NotificationImportance::None
+    } else if actions.iter().any(Action::is_highlight) {
+        NotificationImportance::Highlight
+    } else if actions.iter().any(|action| action.sound().is_some()) {
+        NotificationImportance::Default
+    } else {
+        NotificationImportance::Low
+    }
+}
+
+/// Whether the event is an `@room` mention, going by its `m.mentions`.
+fn mentions_room<T>(event: &Raw<T>) -> bool {
+    event
+        .get_field::<Value>("content")
+        .ok()
+        .flatten()
+        .and_then(|content| content.get("m.mentions")?.get("room")?.as_bool())
+        .unwrap_or(false)
+}
+
+fn notification_body(event: &AnySyncTimelineEvent) -> String {
+    match event {
+        AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncRoomMessageEvent::Original(message),
+        )) => {
+            let msgtype = &message.content.msgtype;
+            match formatted_html(msgtype).map(parse_formatted) {
+                // The plain body may spell the spoiler out.
+                Some(segments) if contains_spoiler(&segments) => plain_text(&segments),
+                _ => msgtype.body().to_string(),
+            }
+        }
+        AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::Sticker(_)) => "Sent a sticker".to_string(),
+        AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomEncrypted(_)) => {
+            "Encrypted message".to_string()
+        }
+        AnySyncTimelineEvent::State(_) => "Room update".to_string(),
+        _ => "New activity".to_string(),
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::IntoFuture;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use matrix_sdk::config::RequestConfig;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::MilliSecondsSinceUnixEpoch;
+use serde::{Deserialize, Serialize};
+use tauri::{Emitter, State};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::errors::rate_limit_delay;
+use crate::state::MatrixState;
+
+/// How long a class keeps to the pace a 429 suggested before going back to
+/// its configured limit.
+const LEARNED_LIMIT_TTL: Duration = Duration::from_secs(10 * 60);
+/// Longest `retry_after` taken as the server's pace. Longer ones still hold
+/// the class back for as long as they say, but aren't kept afterwards.
+const MAX_LEARNED_INTERVAL: Duration = Duration::from_secs(60);
+/// How often `paced` puts a rate-limited request back in the queue before
+/// giving up on it.
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+
+/// The kinds of outgoing requests that are paced, each with its own bucket.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum OutboundClass {
+    /// Messages, reactions, stickers and poll events.
+    Messages,
+    Invites,
+    /// Redactions, kicks and bans.
+    Moderation,
+}
+
+/// A token bucket: up to `burst` requests at once, then `per_minute`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+impl RateLimit {
+    fn interval(&self) -> Duration {
+        Duration::from_secs(60) / self.per_minute.max(1)
+    }
+}
+
+/// Pacing of outgoing requests per class. A class set to `None` isn't
+/// limited on our side, though a `retry_after` from the server still holds
+/// it back.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct OutboundLimits {
+    pub messages: Option<RateLimit>,
+    pub invites: Option<RateLimit>,
+    pub moderation: Option<RateLimit>,
+}
+
+impl Default for OutboundLimits {
+    /// Below Synapse's default limits of 0.2 events and 0.3 invites a second
+    /// with bursts of 10, so that a session stays clear of them.
+    fn default() -> Self {
+        Self {
+            messages: Some(RateLimit { burst: 10, per_minute: 10 }),
+            invites: Some(RateLimit { burst: 5, per_minute: 15 }),
+            moderation: Some(RateLimit { burst: 5, per_minute: 10 }),
+        }
+    }
+}
+
+impl OutboundLimits {
+    fn get(&self, class: OutboundClass) -> Option<RateLimit> {
+        match class {
+            OutboundClass::Messages => self.messages,
+            OutboundClass::Invites => self.invites,
+            OutboundClass::Moderation => self.moderation,
+        }
+    }
+}
+
+/// An operation waiting for its turn, as listed by `get_outbound_queue`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueuedOperation {
+    pub id: u64,
+    pub class: OutboundClass,
+    pub description: String,
+    pub queued_at: u64,
+    /// When it is expected to go out.
+    pub ready_at: u64,
+}
+
+/// Payload of `matrix://outbound-throttled`, emitted when a class starts
+/// holding operations back.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OutboundThrottled {
+    pub class: OutboundClass,
+    /// How long the operation that started the queue waits.
+    pub wait_ms: u64,
+    /// Whether the server asked for the wait with a 429, rather than the
+    /// configured limit.
+    pub server_limited: bool,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    /// Nothing goes out before this, after a 429.
+    blocked_until: Option<Instant>,
+    /// The last `retry_after` the server gave, and when.
+    learned: Option<(Duration, Instant)>,
+}
+
+impl Bucket {
+    fn new(limit: Option<RateLimit>, now: Instant) -> Self {
+        Self {
+            tokens: limit.map_or(1, |limit| limit.burst) as f64,
+            refilled_at: now,
+            blocked_until: None,
+            learned: None,
+        }
+    }
+
+    /// The time between requests: the configured one, or the server's pace
+    /// while it is slower and recent.
+    fn interval(&self, limit: Option<RateLimit>, now: Instant) -> Option<Duration> {
+        let learned = self
+            .learned
+            .filter(|(_, at)| now.duration_since(*at) < LEARNED_LIMIT_TTL)
+            .map(|(interval, _)| interval);
+        match (limit.map(|limit| limit.interval()), learned) {
+            (Some(configured), Some(learned)) => Some(configured.max(learned)),
+            (configured, learned) => configured.or(learned),
+        }
+    }
+
+    /// Takes the next token, which may not have been refilled yet, and
+    /// returns how long to wait until it is. Tokens go negative while
+    /// requests queue, so each one waits its turn behind those before it.
+    fn reserve(&mut self, limit: Option<RateLimit>, now: Instant) -> Duration {
+        let blocked = self
+            .blocked_until
+            .map_or(Duration::ZERO, |until| until.saturating_duration_since(now));
+        let Some(interval) = self.interval(limit, now) else {
+            return blocked;
+        };
+
+        let burst = limit.map_or(1, |limit| limit.burst.max(1)) as f64;
+        let refilled = now.duration_since(self.refilled_at).as_secs_f64() / interval.as_secs_f64();
+        self.tokens = (self.tokens + refilled).min(burst) - 1.0;
+        self.refilled_at = now;
+
+        let behind = match self.tokens < 0.0 {
+            true => interval.mul_f64(-self.tokens),
+            false => Duration::ZERO,
+        };
+        behind.max(blocked)
+    }
+
+    /// Holds the class back for `retry_after`, and keeps it to that pace for
+    /// a while after.
+    fn rate_limited(&mut self, retry_after: Duration, now: Instant) {
+        let until = now + retry_after;
+        self.blocked_until = Some(self.blocked_until.map_or(until, |blocked| blocked.max(until)));
+        self.tokens = self.tokens.min(0.0);
+        self.learned = Some((retry_after.min(MAX_LEARNED_INTERVAL), now));
+    }
+}
+
+/// The buckets and the operations waiting on them.
+#[derive(Default)]
+pub struct Pacer {
+    buckets: HashMap<OutboundClass, Bucket>,
+    queued: BTreeMap<u64, QueuedOperation>,
+    next_id: u64,
+}
+
+pub type OutboundPacer = Arc<RwLock<Pacer>>;
+
+/// The operations held back by the outbound limits, oldest first.
+#[tauri::command]
+pub async fn get_outbound_queue(state: State<'_, MatrixState>) -> Result<Vec<QueuedOperation>, String> {
+    if state.client.read().await.is_none() {
+        return Err("Not logged in".to_string());
+    }
+
+    Ok(state.outbound.read().await.queued.values().cloned().collect())
+}
+
+/// The config for paced requests. The SDK would otherwise retry a 429 by
+/// itself, and the pacer would never learn of the `retry_after`.
+pub(crate) fn paced_request_config() -> RequestConfig {
+    RequestConfig::new().disable_retry()
+}
+
+/// Sends the request `request` builds once `pace` lets it through, with
+/// `paced_request_config`. A 429 is fed back to the class and the request
+/// queued again, up to `MAX_RATE_LIMIT_RETRIES` times.
+pub(crate) async fn paced<T, E, F>(
+    state: &MatrixState,
+    class: OutboundClass,
+    description: impl Into<String>,
+    mut request: impl FnMut(RequestConfig) -> F,
+) -> Result<T, matrix_sdk::Error>
+where
+    F: IntoFuture<Output = Result<T, E>>,
+    E: Into<matrix_sdk::Error>,
+{
+    let description = description.into();
+    let mut retries = 0;
+    loop {
+        pace(state, class, description.clone()).await;
+        let error: matrix_sdk::Error = match request(paced_request_config()).await {
+            Ok(response) => return Ok(response),
+            Err(e) => e.into(),
+        };
+        // The next `pace` waits the delay out.
+        let Some(delay) = note_rate_limit(state, class, error.client_api_error_kind()).await else {
+            return Err(error);
+        };
+        if retries == MAX_RATE_LIMIT_RETRIES {
+            return Err(error);
+        }
+        retries += 1;
+        warn!("Rate limited sending {}, queueing it again after {:?}", description, delay);
+    }
+}
+
+/// Removes a queued operation from the list once it goes out, or when the
+/// caller gave up on it and dropped the future.
+struct QueueEntry {
+    pacer: OutboundPacer,
+    id: u64,
+}
+
+impl Drop for QueueEntry {
+    fn drop(&mut self) {
+        let id = self.id;
+        match self.pacer.try_write() {
+            Ok(mut pacer) => {
+                pacer.queued.remove(&id);
+            }
+            Err(_) => {
+                let pacer = self.pacer.clone();
+                tauri::async_runtime::spawn(async move {
+                    pacer.write().await.queued.remove(&id);
+                });
+            }
+        }
+    }
+}
+
+/// Waits until an operation of `class` may go out, under the configured
+/// limit and any `retry_after` the server gave. While waiting it is listed
+/// by `get_outbound_queue`; the first operation of a class to queue emits
+/// `matrix://outbound-throttled`.
+pub(crate) async fn pace(state: &MatrixState, class: OutboundClass, description: impl Into<String>) {
+    let limit = state.settings.read().await.outbound_limits.get(class);
+    let mut pacer = state.outbound.write().await;
+    let now = Instant::now();
+    let bucket = pacer.buckets.entry(class).or_insert_with(|| Bucket::new(limit, now));
+    let wait = bucket.reserve(limit, now);
+    if wait.is_zero() {
+        return;
+    }
+    let server_limited = bucket.blocked_until.is_some_and(|until| until > now);
+
+    let starts_queue = !pacer.queued.values().any(|operation| operation.class == class);
+    let id = pacer.next_id;
+    pacer.next_id += 1;
+    let queued_at: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+    pacer.queued.insert(
+        id,
+        QueuedOperation {
+            id,
+            class,
+            description: description.into(),
+            queued_at,
+            ready_at: queued_at + wait.as_millis() as u64,
+        },
+    );
+    drop(pacer);
+    let _entry = QueueEntry {
+        pacer: state.outbound.clone(),
+        id,
+    };
+
+    if starts_queue {
+        info!("Throttling outbound {:?} requests for {:?}", class, wait);
+        let _ = state.app.emit(
+            "matrix://outbound-throttled",
+            OutboundThrottled {
+                class,
+                wait_ms: wait.as_millis() as u64,
+                server_limited,
+            },
+        );
+    }
+
+    let mut until = now + wait;
+    loop {
+        tokio::time::sleep(until.saturating_duration_since(Instant::now())).await;
+
+        // A 429 while this one waited pushes it back further.
+        let mut pacer = state.outbound.write().await;
+        let now = Instant::now();
+        let blocked = pacer
+            .buckets
+            .get(&class)
+            .and_then(|bucket| bucket.blocked_until)
+            .filter(|blocked| *blocked > now);
+        let Some(blocked) = blocked else {
+            return;
+        };
+        if let Some(operation) = pacer.queued.get_mut(&id) {
+            let now_ms: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+            operation.ready_at = now_ms + blocked.duration_since(now).as_millis() as u64;
+        }
+        until = blocked;
+    }
+}
+
+/// Feeds a failed request's error back to its class: on a 429, nothing more
+/// of the class goes out until `retry_after` has passed, and the class keeps
+/// to that pace for a while. Returns the delay for a rate limit, or `None`
+/// for any other error.
+pub(crate) async fn note_rate_limit(
+    state: &MatrixState,
+    class: OutboundClass,
+    kind: Option<&ErrorKind>,
+) -> Option<Duration> {
+    let retry_after = rate_limit_delay(kind)?;
+    warn!("Rate limited on outbound {:?} requests, holding them for {:?}", class, retry_after);
+
+    let limit = state.settings.read().await.outbound_limits.get(class);
+    let mut pacer = state.outbound.write().await;
+    let now = Instant::now();
+    pacer
+        .buckets
+        .entry(class)
+        .or_insert_with(|| Bucket::new(limit, now))
+        .rate_limited(retry_after, now);
+    Some(retry_after)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit { burst: 3, per_minute: 6 };
+
+    #[test]
+    fn burst_goes_out_at_once() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(Some(LIMIT), now);
+        for _ in 0..3 {
+            assert_eq!(bucket.reserve(Some(LIMIT), now), Duration::ZERO);
+        }
+        assert_eq!(bucket.reserve(Some(LIMIT), now), Duration::from_secs(10));
+        assert_eq!(bucket.reserve(Some(LIMIT), now), Duration::from_secs(20));
+    }
+
+    #[test]
+    fn tokens_refill_up_to_the_burst() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(Some(LIMIT), now);
+        bucket.reserve(Some(LIMIT), now);
+        let later = now + Duration::from_secs(600);
+        for _ in 0..3 {
+            assert_eq!(bucket.reserve(Some(LIMIT), later), Duration::ZERO);
+        }
+        assert!(bucket.reserve(Some(LIMIT), later) > Duration::ZERO);
+    }
+
+    #[test]
+    fn unlimited_class_goes_out_at_once() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(None, now);
+        for _ in 0..100 {
+            assert_eq!(bucket.reserve(None, now), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn retry_after_blocks_and_slows_the_class() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(None, now);
+        bucket.rate_limited(Duration::from_secs(30), now);
+        assert_eq!(bucket.reserve(None, now), Duration::from_secs(30));
+
+        // Afterwards requests keep to the server's pace until it is forgotten.
+        let later = now + Duration::from_secs(60);
+        assert_eq!(bucket.reserve(None, later), Duration::ZERO);
+        assert_eq!(bucket.reserve(None, later), Duration::from_secs(30));
+        let much_later = later + LEARNED_LIMIT_TTL;
+        assert_eq!(bucket.reserve(None, much_later), Duration::ZERO);
+        assert_eq!(bucket.reserve(None, much_later), Duration::ZERO);
+    }
+
+    #[test]
+    fn learned_pace_never_speeds_up_the_configured_one() {
+        let now = Instant::now();
+        let bucket = Bucket {
+            learned: Some((Duration::from_secs(1), now)),
+            ..Bucket::new(Some(LIMIT), now)
+        };
+        assert_eq!(bucket.interval(Some(LIMIT), now), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn dropped_operation_leaves_the_queue() {
+        let pacer: OutboundPacer = Default::default();
+        let operation = QueuedOperation {
+            id: 0,
+            class: OutboundClass::Messages,
+            description: "Message".to_string(),
+            queued_at: 0,
+            ready_at: 0,
+        };
+        pacer.try_write().unwrap().queued.insert(0, operation);
+
+        drop(QueueEntry { pacer: pacer.clone(), id: 0 });
+        assert!(pacer.try_read().unwrap().queued.is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use matrix_sdk::deserialized_responses::EncryptionInfo;
+use matrix_sdk::ruma::events::AnySyncTimelineEvent;
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedUserId};
+use matrix_sdk::{Client, Room};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::State;
+use tokio::sync::RwLock;
+
+use crate::state::MatrixState;
+
+/// Event types that count as the user having replied.
+const MESSAGE_EVENT_TYPES: [&str; 3] = ["m.room.message", "m.sticker", "m.room.encrypted"];
+
+/// Per room, the newest message the user sent from any of their devices, as
+/// seen in sync.
+pub type OwnActivity = Arc<RwLock<HashMap<OwnedRoomId, OwnMessage>>>;
+
+#[derive(Clone, Debug)]
+pub struct OwnMessage {
+    event_id: OwnedEventId,
+    timestamp: u64,
+    device_id: Option<OwnedDeviceId>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MyRecentActivity {
+    pub event_id: String,
+    pub timestamp: u64,
+    /// The device that sent it, known for encrypted messages only.
+    pub device_id: Option<String>,
+    pub device_name: Option<String>,
+    pub this_device: bool,
+}
+
+/// The last message the user sent in the room from any device, so the
+/// compose box can say they already replied elsewhere. Only what sync has
+/// delivered since login or restore is known.
+#[tauri::command]
+pub async fn get_my_recent_activity(
+    state: State<'_, MatrixState>,
+    room_id: String,
+) -> Result<Option<MyRecentActivity>, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let Some(message) = state.own_activity.read().await.get(&room_id).cloned() else {
+        return Ok(None);
+    };
+
+    let device_name = match (&message.device_id, client.user_id()) {
+        (Some(device_id), Some(user_id)) => client
+            .encryption()
+            .get_device(user_id, device_id)
+            .await
+            .ok()
+            .flatten()
+            .and_then(|device| device.display_name().map(str::to_string)),
+        _ => None,
+    };
+
+    Ok(Some(MyRecentActivity {
+        event_id: message.event_id.to_string(),
+        timestamp: message.timestamp,
+        this_device: message.device_id.is_some() && message.device_id.as_deref() == client.device_id(),
+        device_id: message.device_id.map(|id| id.to_string()),
+        device_name,
+    }))
+}
+
+/// Sync handler recording the user's own messages. The sending device comes
+/// from decryption; events that didn't decrypt still name it in their
+/// content.
+pub(crate) async fn on_own_message(
+    event: Raw<AnySyncTimelineEvent>,
+    room: Room,
+    client: Client,
+    encryption_info: Option<EncryptionInfo>,
+    activity: OwnActivity,
+) {
+    let sender = event.get_field::<OwnedUserId>("sender").ok().flatten();
+    if sender.is_none() || sender.as_deref() != client.user_id() {
+        return;
+    }
+    let event_type = event.get_field::<String>("type").ok().flatten();
+    if !event_type.is_some_and(|t| MESSAGE_EVENT_TYPES.contains(&t.as_str())) {
+        return;
+    }
+    let (Some(event_id), Some(timestamp)) = (
+        event.get_field::<OwnedEventId>("event_id").ok().flatten(),
+        event.get_field::<u64>("origin_server_ts").ok().flatten(),
+    ) else {
+        return;
+    };
+
+    let device_id = encryption_info.and_then(|info| info.sender_device).or_else(|| {
+        let content = event.get_field::<Value>("content").ok().flatten()?;
+        Some(content.get("device_id")?.as_str()?.into())
+    });
+
+    let mut activity = activity.write().await;
+    let newer = activity
+        .get(room.room_id())
+        .is_none_or(|last| last.timestamp <= timestamp);
+    if newer {
+        activity.insert(
+            room.room_id().to_owned(),
+            OwnMessage {
+                event_id,
+                timestamp,
+                device_id,
+            },
+        );
+    }
+}
@@ -0,0 +1,552 @@
+use std::collections::{BTreeMap, HashMap};
+
+use matrix_sdk::deserialized_responses::TimelineEvent;
+use matrix_sdk::room::{IncludeRelations, RelationsOptions};
+use matrix_sdk::ruma::events::message::TextContentBlock;
+use matrix_sdk::ruma::events::poll::end::PollEndEventContent;
+use matrix_sdk::ruma::events::poll::response::PollResponseEventContent;
+use matrix_sdk::ruma::events::poll::start::{PollKind as PollStartKind, PollStartEventContent};
+use matrix_sdk::ruma::events::poll::unstable_end::UnstablePollEndEventContent;
+use matrix_sdk::ruma::events::poll::unstable_response::UnstablePollResponseEventContent;
+use matrix_sdk::ruma::events::poll::unstable_start::UnstablePollStartEventContent;
+use matrix_sdk::ruma::events::relation::RelationType;
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedUserId, UInt, UserId};
+use matrix_sdk::Room;
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tracing::{info, warn};
+
+use crate::errors::CommandError;
+use crate::outbound::{paced, OutboundClass};
+use crate::rooms::{origin_server_ts, read_only_reason, Message, MessageContent};
+use crate::state::MatrixState;
+
+/// Whether a poll's results show while it is open.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PollKind {
+    Disclosed,
+    /// Results are only revealed once the poll has ended.
+    Undisclosed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PollAnswer {
+    pub id: String,
+    pub text: String,
+    /// `None` while an undisclosed poll is still open.
+    pub votes: Option<u64>,
+}
+
+/// A poll start event, in the stable `m.poll.start` form or the unstable
+/// one Element sends. Votes and the end are sent in the same form.
+struct PollStart {
+    question: String,
+    kind: PollKind,
+    max_selections: u64,
+    answers: Vec<PollAnswer>,
+    unstable: bool,
+}
+
+/// One user's vote, from a poll response event.
+#[derive(Clone, Debug)]
+struct PollResponse {
+    sender: OwnedUserId,
+    timestamp: u64,
+    selections: Vec<String>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Tally {
+    votes: HashMap<String, u64>,
+    /// Users with a valid vote.
+    voters: u64,
+    own_vote: Vec<String>,
+}
+
+/// Votes in a poll. `answer_ids` replaces any earlier vote of the user's;
+/// an empty list withdraws it.
+#[tauri::command]
+pub async fn vote_poll(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    poll_event_id: String,
+    answer_ids: Vec<String>,
+) -> Result<String, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let poll_id: OwnedEventId = poll_event_id
+        .parse()
+        .map_err(|e| format!("Invalid event ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    if let Some(reason) = read_only_reason(&room).await {
+        return Err(CommandError::new(
+            reason.code(),
+            format!("You can't vote here: {}", reason.message()),
+        )
+        .into());
+    }
+
+    let (start, creator) = load_poll(&room, &poll_id).await?;
+    if let Some(answer) = answer_ids.iter().find(|id| !start.answers.iter().any(|a| &a.id == *id)) {
+        return Err(format!("Unknown poll answer: {}", answer));
+    }
+    if answer_ids.len() as u64 > start.max_selections {
+        return Err(format!("This poll allows at most {} answers", start.max_selections));
+    }
+
+    let (_, ended_at) = poll_activity(&room, &poll_id, &creator).await?;
+    if ended_at.is_some() {
+        return Err(CommandError::new("POLL_ENDED", "This poll has ended").into());
+    }
+
+    let description = format!("Poll vote in {}", room_id);
+    let response = if start.unstable {
+        let content = UnstablePollResponseEventContent::new(answer_ids, poll_id.clone());
+        paced(&state, OutboundClass::Messages, description, |config| {
+            room.send(content.clone()).with_request_config(config)
+        })
+        .await
+    } else {
+        let content = PollResponseEventContent::new(answer_ids.into(), poll_id.clone());
+        paced(&state, OutboundClass::Messages, description, |config| {
+            room.send(content.clone()).with_request_config(config)
+        })
+        .await
+    }
+    .map_err(|e| format!("Failed to vote: {}", e))?;
+
+    info!("Voted in poll {} in {}", poll_id, room_id);
+    Ok(response.event_id.to_string())
+}
+
+/// Ends a poll, after which votes no longer count. Allowed for its creator
+/// and for anyone who may redact others' messages.
+#[tauri::command]
+pub async fn end_poll(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    poll_event_id: String,
+) -> Result<String, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let poll_id: OwnedEventId = poll_event_id
+        .parse()
+        .map_err(|e| format!("Invalid event ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+    let user_id = client.user_id().ok_or("No user ID")?;
+
+    let (start, creator) = load_poll(&room, &poll_id).await?;
+    if !can_end(&room, &creator, user_id).await {
+        return Err(CommandError::new(
+            "INSUFFICIENT_POWER_LEVEL",
+            "Only the poll's creator or a moderator can end it",
+        )
+        .into());
+    }
+
+    let (responses, ended_at) = poll_activity(&room, &poll_id, &creator).await?;
+    if ended_at.is_some() {
+        return Err(CommandError::new("POLL_ENDED", "This poll has ended").into());
+    }
+
+    let answer_ids: Vec<&str> = start.answers.iter().map(|a| a.id.as_str()).collect();
+    let tally = tally(&answer_ids, start.max_selections, &responses, None, user_id);
+    let text = end_text(&start, &tally);
+
+    let description = format!("Poll end in {}", room_id);
+    let response = if start.unstable {
+        let content = UnstablePollEndEventContent::new(text, poll_id.clone());
+        paced(&state, OutboundClass::Messages, description, |config| {
+            room.send(content.clone()).with_request_config(config)
+        })
+        .await
+    } else {
+        let mut content = PollEndEventContent::new(TextContentBlock::plain(text), poll_id.clone());
+        content.poll_results = Some(
+            start
+                .answers
+                .iter()
+                .map(|a| {
+                    let votes = tally.votes.get(&a.id).copied().unwrap_or(0);
+                    (a.id.clone(), UInt::new(votes).unwrap_or_default())
+                })
+                .collect::<BTreeMap<_, _>>()
+                .into(),
+        );
+        paced(&state, OutboundClass::Messages, description, |config| {
+            room.send(content.clone()).with_request_config(config)
+        })
+        .await
+    }
+    .map_err(|e| format!("Failed to end poll: {}", e))?;
+
+    info!("Ended poll {} in {}", poll_id, room_id);
+    Ok(response.event_id.to_string())
+}
+
+/// The timeline entry of a poll start event, without its votes, which
+/// `annotate_polls` fills in.
+pub(crate) fn poll_content<T>(event: &Raw<T>) -> Option<(String, MessageContent)> {
+    let start = parse_poll_start(event)?;
+    let content = MessageContent::Poll {
+        question: start.question.clone(),
+        poll_kind: start.kind,
+        max_selections: start.max_selections,
+        answers: start.answers,
+        own_vote: Vec::new(),
+        voters: 0,
+        ended: false,
+    };
+    Some((start.question, content))
+}
+
+/// Fills in the votes of the polls among `messages` from their responses,
+/// counting only each user's latest vote from before the poll ended.
+pub(crate) async fn annotate_polls(room: &Room, messages: &mut [Message]) {
+    let own_user_id = room.own_user_id();
+    for message in messages {
+        let MessageContent::Poll {
+            poll_kind,
+            max_selections,
+            answers,
+            own_vote,
+            voters,
+            ended,
+            ..
+        } = &mut message.content
+        else {
+            continue;
+        };
+        let (Some(poll_id), Ok(creator)) = (
+            message.event_id.as_deref().and_then(|id| EventId::parse(id).ok()),
+            UserId::parse(message.sender.as_str()),
+        ) else {
+            continue;
+        };
+
+        let (responses, ended_at) = match poll_activity(room, &poll_id, &creator).await {
+            Ok(activity) => activity,
+            Err(e) => {
+                warn!("{}", e);
+                continue;
+            }
+        };
+        let answer_ids: Vec<&str> = answers.iter().map(|a| a.id.as_str()).collect();
+        let tally = tally(&answer_ids, *max_selections, &responses, ended_at, own_user_id);
+
+        *ended = ended_at.is_some();
+        let disclosed = *poll_kind == PollKind::Disclosed || *ended;
+        for answer in answers.iter_mut() {
+            answer.votes = disclosed.then(|| tally.votes.get(&answer.id).copied().unwrap_or(0));
+        }
+        *voters = tally.voters;
+        *own_vote = tally.own_vote;
+    }
+}
+
+fn parse_poll_start<T>(event: &Raw<T>) -> Option<PollStart> {
+    let kind = |kind: &PollStartKind| match kind {
+        PollStartKind::Disclosed => PollKind::Disclosed,
+        _ => PollKind::Undisclosed,
+    };
+    match event.get_field::<String>("type").ok().flatten()?.as_str() {
+        "m.poll.start" => {
+            let poll = event.get_field::<PollStartEventContent>("content").ok().flatten()?.poll;
+            Some(PollStart {
+                question: poll.question.text.find_plain()?.to_string(),
+                kind: kind(&poll.kind),
+                max_selections: poll.max_selections.into(),
+                answers: poll
+                    .answers
+                    .iter()
+                    .map(|a| PollAnswer {
+                        id: a.id.clone(),
+                        text: a.text.find_plain().unwrap_or_default().to_string(),
+                        votes: None,
+                    })
+                    .collect(),
+                unstable: false,
+            })
+        }
+        "org.matrix.msc3381.poll.start" => {
+            // Edits of a poll come as `Replacement`s and aren't polls of their own.
+            let UnstablePollStartEventContent::New(content) = event.get_field("content").ok().flatten()? else {
+                return None;
+            };
+            let poll = content.poll_start;
+            Some(PollStart {
+                question: poll.question.text,
+                kind: kind(&poll.kind),
+                max_selections: poll.max_selections.into(),
+                answers: poll
+                    .answers
+                    .iter()
+                    .map(|a| PollAnswer {
+                        id: a.id.clone(),
+                        text: a.text.clone(),
+                        votes: None,
+                    })
+                    .collect(),
+                unstable: true,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The poll at `poll_id` and who started it.
+async fn load_poll(room: &Room, poll_id: &EventId) -> Result<(PollStart, OwnedUserId), String> {
+    let event = room
+        .load_or_fetch_event(poll_id, None)
+        .await
+        .map_err(|e| format!("Failed to load poll: {}", e))?;
+    let start = parse_poll_start(event.raw()).ok_or("Not a poll")?;
+    let creator = event
+        .raw()
+        .get_field::<OwnedUserId>("sender")
+        .ok()
+        .flatten()
+        .ok_or("The poll has no sender")?;
+    Ok((start, creator))
+}
+
+/// The poll's responses, and when it ended if it did: the earliest end
+/// event sent by someone allowed to end it.
+async fn poll_activity(
+    room: &Room,
+    poll_id: &EventId,
+    creator: &UserId,
+) -> Result<(Vec<PollResponse>, Option<u64>), String> {
+    let mut responses = Vec::new();
+    let mut ended_at: Option<u64> = None;
+    for event in poll_relations(room, poll_id).await? {
+        let raw = event.raw();
+        let Some(sender) = raw.get_field::<OwnedUserId>("sender").ok().flatten() else {
+            continue;
+        };
+        let timestamp = origin_server_ts(&event);
+        match raw.get_field::<String>("type").ok().flatten().as_deref() {
+            Some("m.poll.response") => {
+                if let Ok(Some(content)) = raw.get_field::<PollResponseEventContent>("content") {
+                    responses.push(PollResponse {
+                        sender,
+                        timestamp,
+                        selections: content.selections.to_vec(),
+                    });
+                }
+            }
+            Some("org.matrix.msc3381.poll.response") => {
+                if let Ok(Some(content)) = raw.get_field::<UnstablePollResponseEventContent>("content") {
+                    responses.push(PollResponse {
+                        sender,
+                        timestamp,
+                        selections: content.poll_response.answers,
+                    });
+                }
+            }
+            Some("m.poll.end" | "org.matrix.msc3381.poll.end") if can_end(room, creator, &sender).await => {
+                ended_at = Some(ended_at.map_or(timestamp, |at| at.min(timestamp)));
+            }
+            _ => {}
+        }
+    }
+    Ok((responses, ended_at))
+}
+
+/// Every event referencing the poll, across as many pages as there are.
+async fn poll_relations(room: &Room, poll_id: &EventId) -> Result<Vec<TimelineEvent>, String> {
+    let mut events = Vec::new();
+    let mut from = None;
+    loop {
+        let page = room
+            .relations(
+                poll_id.to_owned(),
+                RelationsOptions {
+                    from,
+                    include_relations: IncludeRelations::RelationsOfType(RelationType::Reference),
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|e| format!("Failed to load votes of {}: {}", poll_id, e))?;
+        events.extend(page.chunk);
+        match page.next_batch_token {
+            Some(token) => from = Some(token),
+            None => return Ok(events),
+        }
+    }
+}
+
+async fn can_end(room: &Room, creator: &UserId, user_id: &UserId) -> bool {
+    user_id == creator
+        || room
+            .power_levels()
+            .await
+            .is_ok_and(|levels| levels.user_can_redact_event_of_other(user_id))
+}
+
+/// Counts each user's latest response from before the poll ended. A
+/// response naming an unknown answer is spoiled and counts as no vote, as
+/// does an empty one; extra answers beyond `max_selections` are ignored.
+fn tally(
+    answer_ids: &[&str],
+    max_selections: u64,
+    responses: &[PollResponse],
+    ended_at: Option<u64>,
+    own_user_id: &UserId,
+) -> Tally {
+    let mut latest: HashMap<&UserId, &PollResponse> = HashMap::new();
+    for response in responses.iter().filter(|r| ended_at.is_none_or(|end| r.timestamp <= end)) {
+        let entry = latest.entry(&response.sender).or_insert(response);
+        if response.timestamp > entry.timestamp {
+            *entry = response;
+        }
+    }
+
+    let mut tally = Tally::default();
+    for (sender, response) in latest {
+        if response.selections.iter().any(|s| !answer_ids.contains(&s.as_str())) {
+            continue;
+        }
+        let mut selections: Vec<String> = Vec::new();
+        for selection in &response.selections {
+            if !selections.contains(selection) {
+                selections.push(selection.clone());
+            }
+        }
+        selections.truncate(usize::try_from(max_selections).unwrap_or(usize::MAX));
+        if selections.is_empty() {
+            continue;
+        }
+
+        for selection in &selections {
+            *tally.votes.entry(selection.clone()).or_default() += 1;
+        }
+        tally.voters += 1;
+        if sender == own_user_id {
+            tally.own_vote = selections;
+        }
+    }
+    tally
+}
+
+/// Fallback text of the end event, naming the winning answer.
+fn end_text(start: &PollStart, tally: &Tally) -> String {
+    let top = start
+        .answers
+        .iter()
+        .map(|a| (a, tally.votes.get(&a.id).copied().unwrap_or(0)))
+        .filter(|(_, votes)| *votes > 0)
+        .max_by_key(|(_, votes)| *votes);
+    match top {
+        Some((answer, _)) => format!("The poll has ended. Top answer: {}", answer.text),
+        None => "The poll has ended. No votes were cast.".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ANSWERS: &[&str] = &["pizza", "sushi", "tacos"];
+
+    fn response(sender: &str, timestamp: u64, selections: &[&str]) -> PollResponse {
+        PollResponse {
+            sender: UserId::parse(sender).unwrap(),
+            timestamp,
+            selections: selections.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn own() -> OwnedUserId {
+        UserId::parse("@me:example.org").unwrap()
+    }
+
+    #[test]
+    fn only_the_latest_response_of_a_user_counts() {
+        let responses = [
+            response("@me:example.org", 10, &["pizza"]),
+            response("@me:example.org", 30, &["tacos"]),
+            response("@me:example.org", 20, &["sushi"]),
+            response("@bob:example.org", 15, &["tacos"]),
+        ];
+        let tally = tally(ANSWERS, 1, &responses, None, &own());
+        assert_eq!(tally.voters, 2);
+        assert_eq!(tally.votes.get("tacos"), Some(&2));
+        assert_eq!(tally.votes.get("pizza"), None);
+        assert_eq!(tally.own_vote, vec!["tacos".to_string()]);
+    }
+
+    #[test]
+    fn responses_after_the_end_are_ignored() {
+        let responses = [
+            response("@me:example.org", 10, &["pizza"]),
+            response("@me:example.org", 50, &["sushi"]),
+            response("@bob:example.org", 60, &["sushi"]),
+        ];
+        let tally = tally(ANSWERS, 1, &responses, Some(40), &own());
+        assert_eq!(tally.voters, 1);
+        assert_eq!(tally.votes.get("pizza"), Some(&1));
+        assert_eq!(tally.votes.get("sushi"), None);
+        assert_eq!(tally.own_vote, vec!["pizza".to_string()]);
+    }
+
+    #[test]
+    fn spoiled_and_empty_responses_withdraw_the_vote() {
+        let responses = [
+            response("@me:example.org", 10, &["pizza"]),
+            response("@me:example.org", 20, &[]),
+            response("@bob:example.org", 10, &["sushi"]),
+            response("@bob:example.org", 20, &["sushi", "burgers"]),
+        ];
+        let tally = tally(ANSWERS, 2, &responses, None, &own());
+        assert_eq!(tally, Tally::default());
+    }
+
+    #[test]
+    fn selections_are_capped_at_max_selections() {
+        let responses = [response("@bob:example.org", 10, &["sushi", "sushi", "tacos", "pizza"])];
+        let tally = tally(ANSWERS, 2, &responses, None, &own());
+        assert_eq!(tally.votes.get("sushi"), Some(&1));
+        assert_eq!(tally.votes.get("tacos"), Some(&1));
+        assert_eq!(tally.votes.get("pizza"), None);
+    }
+
+    #[test]
+    fn unstable_poll_start_is_parsed() {
+        let event = Raw::<serde_json::Value>::new(&serde_json::json!({
+            "type": "org.matrix.msc3381.poll.start",
+            "sender": "@bob:example.org",
+            "content": {
+                "org.matrix.msc3381.poll.start": {
+                    "question": { "org.matrix.msc1767.text": "Lunch?" },
+                    "kind": "org.matrix.msc3381.poll.undisclosed",
+                    "max_selections": 1,
+                    "answers": [
+                        { "id": "pizza", "org.matrix.msc1767.text": "Pizza" },
+                        { "id": "sushi", "org.matrix.msc1767.text": "Sushi" },
+                    ],
+                },
+                "org.matrix.msc1767.text": "Lunch?\n1. Pizza\n2. Sushi",
+            },
+        }))
+        .unwrap();
+        let start = parse_poll_start(&event).unwrap();
+        assert_eq!(start.question, "Lunch?");
+        assert_eq!(start.kind, PollKind::Undisclosed);
+        assert!(start.unstable);
+        assert_eq!(start.answers.len(), 2);
+        assert_eq!(start.answers[1].text, "Sushi");
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use matrix_sdk::room::{Messages, MessagesOptions};
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use matrix_sdk::{Client, Room};
+use serde::{Deserialize, Serialize};
+use tauri::async_runtime::JoinHandle;
+use tauri::{AppHandle, Emitter};
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+use crate::errors::rate_limit_delay;
+use crate::state::MatrixState;
+
+/// How many of the most recently active DMs get prefetched.
+const PREFETCH_ROOMS: usize = 15;
+const PREFETCH_EVENTS: u32 = 20;
+/// Pause between rooms, so startup doesn't compete with what the user opens.
+const PREFETCH_PACING: Duration = Duration::from_millis(500);
+/// 429s in a row before a room is skipped.
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+
+/// First pages of DM history fetched in the background at startup, taken by
+/// `get_messages` when the room is opened. A room's page is dropped when
+/// sync brings it new events, since it would then be stale.
+pub type PrefetchedPages = Arc<RwLock<HashMap<OwnedRoomId, Messages>>>;
+
+/// The startup prefetch; set once it has been started for the session.
+pub type PrefetchTaskSlot = Arc<RwLock<Option<JoinHandle<()>>>>;
+
+/// Payload of `matrix://room-preview-ready`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomPreviewReady {
+    pub room_id: String,
+    pub events: usize,
+}
+
+/// Starts prefetching the latest messages of the most recent DMs, once per
+/// session, unless the user turned it off. Called after each sync; the
+/// first one has filled in which rooms were active last.
+pub(crate) async fn start_dm_prefetch(state: &MatrixState, client: &Client) {
+    if !state.settings.read().await.prefetch_dm_previews {
+        return;
+    }
+    let mut task = state.prefetch_task.write().await;
+    if task.is_some() {
+        return;
+    }
+
+    let rooms = recent_dms(state, client).await;
+    if rooms.is_empty() {
+        return;
+    }
+    info!("Prefetching {} DM rooms", rooms.len());
+
+    let app = state.app.clone();
+    let pages = state.prefetched_pages.clone();
+    *task = Some(tauri::async_runtime::spawn(async move {
+        for room in rooms {
+            prefetch_room(&app, &pages, &room).await;
+            tokio::time::sleep(PREFETCH_PACING).await;
+        }
+        debug!("DM prefetch finished");
+    }));
+}
+
+/// The prefetched first page of `room_id`, if there is one. It is handed out
+/// once; later calls go to the server as usual.
+pub(crate) async fn take_prefetched(pages: &PrefetchedPages, room_id: &RoomId) -> Option<Messages> {
+    pages.write().await.remove(room_id)
+}
+
+/// Joined DMs, most recently active first.
+async fn recent_dms(state: &MatrixState, client: &Client) -> Vec<Room> {
+    let activity = state.room_activity.read().await;
+    let mut dms = Vec::new();
+    for room in client.joined_rooms() {
+        if room.is_direct().await.unwrap_or(false) {
+            let last_active = activity.get(room.room_id()).copied().unwrap_or(0);
+            dms.push((last_active, room));
+        }
+    }
+    dms.sort_by_key(|(last_active, _)| std::cmp::Reverse(*last_active));
+    dms.into_iter().take(PREFETCH_ROOMS).map(|(_, room)| room).collect()
+}
+
+/// Fetches and decrypts one room's latest events, giving up on the room if
+/// the server keeps rate-limiting.
+async fn prefetch_room(app: &AppHandle, pages: &PrefetchedPages, room: &Room) {
+    let mut retries = 0;
+    let page = loop {
+        let mut options = MessagesOptions::backward();
+        options.limit = PREFETCH_EVENTS.into();
+        match room.messages(options).await {
+            Ok(page) => break page,
+            Err(e) => {
+                let Some(delay) = rate_limit_delay(e.client_api_error_kind()) else {
+                    warn!("Failed to prefetch {}: {}", room.room_id(), e);
+                    return;
+                };
+                if retries == MAX_RATE_LIMIT_RETRIES {
+                    warn!("Skipping prefetch of {}: rate limited", room.room_id());
+                    return;
+                }
+                retries += 1;
+                tokio::time::sleep(delay).await;
+            }
+        }
+    };
+
+    let events = page.chunk.len();
+    pages.write().await.insert(room.room_id().to_owned(), page);
+    let _ = app.emit(
+        "matrix://room-preview-ready",
+        RoomPreviewReady {
+            room_id: room.room_id().to_string(),
+            events,
+        },
+    );
+}
@@ -0,0 +1,129 @@
+use matrix_sdk::ruma::api::client::authenticated_media::get_media_preview;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::api::client::media::get_media_preview as legacy_get_media_preview;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::HttpError;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tauri::State;
+use tracing::debug;
+
+use crate::state::MatrixState;
+
+/// How long a fetched preview is reused before asking the homeserver again.
+const PREVIEW_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UrlPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image_mxc: Option<String>,
+    pub site_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum UrlPreviewResult {
+    Available { preview: UrlPreview },
+    /// The homeserver has the preview endpoint turned off.
+    Unsupported { reason: String },
+    /// Previews are disabled for this room by the user's preference.
+    Disabled,
+}
+
+#[tauri::command]
+pub async fn get_url_preview(
+    state: State<'_, MatrixState>,
+    url: String,
+    room_id: Option<String>,
+    allow_in_encrypted_rooms: Option<bool>,
+) -> Result<UrlPreviewResult, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let url = url.trim().to_string();
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err("Only http:// and https:// links can be previewed".to_string());
+    }
+
+    if let Some(room_id) = room_id {
+        let room_id: OwnedRoomId = room_id
+            .parse()
+            .map_err(|e| format!("Invalid room ID: {}", e))?;
+        let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+        let encrypted = room.latest_encryption_state().await.map(|s| s.is_encrypted()).unwrap_or(true);
+        if encrypted && !allow_in_encrypted_rooms.unwrap_or(false) {
+            return Ok(UrlPreviewResult::Disabled);
+        }
+    }
+
+    if let Some((fetched_at, preview)) = state.url_previews.read().await.get(&url) {
+        if fetched_at.elapsed() < PREVIEW_TTL {
+            return Ok(UrlPreviewResult::Available { preview: preview.clone() });
+        }
+    }
+
+    debug!("Fetching URL preview for {}", url);
+
+    let data = match client.send(get_media_preview::v1::Request::new(url.clone())).await {
+        Ok(response) => response.data,
+        Err(e) if endpoint_missing(&e) => {
+            #[allow(deprecated)]
+            let legacy = client
+                .send(legacy_get_media_preview::v3::Request::new(url.clone()))
+                .await;
+            match legacy {
+                Ok(response) => response.data,
+                Err(e) if endpoint_missing(&e) || is_forbidden(&e) => {
+                    return Ok(UrlPreviewResult::Unsupported { reason: e.to_string() });
+                }
+                Err(e) => return Err(format!("Failed to fetch URL preview: {}", e)),
+            }
+        }
+        Err(e) if is_forbidden(&e) => {
+            return Ok(UrlPreviewResult::Unsupported { reason: e.to_string() });
+        }
+        Err(e) => return Err(format!("Failed to fetch URL preview: {}", e)),
+    };
+
+    let og: serde_json::Value = data
+        .map(|raw| serde_json::from_str(raw.get()).unwrap_or_default())
+        .unwrap_or_default();
+
+    let field = |key: &str| og.get(key).and_then(|v| v.as_str()).map(str::to_string);
+
+    let preview = UrlPreview {
+        url: url.clone(),
+        title: field("og:title"),
+        description: field("og:description"),
+        image_mxc: field("og:image").filter(|image| image.starts_with("mxc://")),
+        site_name: field("og:site_name"),
+    };
+
+    // Expired entries go as new ones come in, so the cache doesn't grow
+    // with every link ever seen.
+    let mut previews = state.url_previews.write().await;
+    previews.retain(|_, (fetched_at, _)| fetched_at.elapsed() < PREVIEW_TTL);
+    previews.insert(url, (Instant::now(), preview.clone()));
+    drop(previews);
+
+    Ok(UrlPreviewResult::Available { preview })
+}
+
+/// The server doesn't know the endpoint at all (old server, or no path the
+/// client can pick for the advertised versions).
+pub(crate) fn endpoint_missing(error: &HttpError) -> bool {
+    if matches!(error, HttpError::IntoHttp(_)) {
+        return true;
+    }
+    matches!(error.client_api_error_kind(), Some(ErrorKind::Unrecognized))
+        || error
+            .as_client_api_error()
+            .is_some_and(|e| e.status_code.as_u16() == 404 || e.status_code.as_u16() == 501)
+}
+
+fn is_forbidden(error: &HttpError) -> bool {
+    matches!(error.client_api_error_kind(), Some(ErrorKind::Forbidden { .. }))
+}
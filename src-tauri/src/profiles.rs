@@ -0,0 +1,104 @@
+use matrix_sdk::ruma::events::room::member::MembershipState;
+use matrix_sdk::ruma::events::{
+    AnyStateEvent, AnySyncStateEvent, AnySyncTimelineEvent, StateEvent, SyncStateEvent,
+};
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::OwnedUserId;
+use matrix_sdk::{Room, RoomMemberships};
+use std::collections::{HashMap, HashSet};
+
+/// Resolves sender display names for a page of timeline events.
+///
+/// Names start from the lazy-loaded member state returned with the page (falling
+/// back to the room's current members), and are rewound through `prev_content`
+/// as older member events are walked, so historical messages show the name the
+/// sender had at the time.
+pub(crate) struct ProfileResolver {
+    names: HashMap<OwnedUserId, String>,
+    name_owners: HashMap<String, HashSet<OwnedUserId>>,
+}
+
+impl ProfileResolver {
+    pub(crate) async fn new(room: &Room, state: &[Raw<AnyStateEvent>]) -> Self {
+        let mut names = HashMap::new();
+        let mut name_owners: HashMap<String, HashSet<OwnedUserId>> = HashMap::new();
+
+        let members = room
+            .members_no_sync(RoomMemberships::JOIN | RoomMemberships::INVITE)
+            .await
+            .unwrap_or_default();
+
+        for member in members {
+            if let Some(name) = member.display_name() {
+                names.insert(member.user_id().to_owned(), name.to_string());
+                name_owners
+                    .entry(name.to_string())
+                    .or_default()
+                    .insert(member.user_id().to_owned());
+            }
+        }
+
+        for raw in state {
+            if let Ok(AnyStateEvent::RoomMember(StateEvent::Original(member))) = raw.deserialize() {
+                let Ok(user_id) = OwnedUserId::try_from(member.state_key.as_str()) else {
+                    continue;
+                };
+                match member.content.displayname {
+                    Some(name) if member.content.membership == MembershipState::Join => {
+                        names.insert(user_id, name);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Self { names, name_owners }
+    }
+
+    /// Rewinds a sender's name when a member event is passed while walking
+    /// backwards, so older events see the name from before the change.
+    pub(crate) fn observe(&mut self, event: &Raw<AnySyncTimelineEvent>) {
+        let Ok(AnySyncTimelineEvent::State(AnySyncStateEvent::RoomMember(SyncStateEvent::Original(
+            member,
+        )))) = event.deserialize()
+        else {
+            return;
+        };
+
+        let Ok(user_id) = OwnedUserId::try_from(member.state_key.as_str()) else {
+            return;
+        };
+
+        match member.unsigned.prev_content.and_then(|prev| prev.displayname) {
+            Some(name) => {
+                self.names.insert(user_id, name);
+            }
+            None => {
+                self.names.remove(&user_id);
+            }
+        }
+    }
+
+    pub(crate) fn display_name(&self, user_id: &str) -> Option<String> {
+        let user_id = OwnedUserId::try_from(user_id).ok()?;
+        self.names.get(&user_id).cloned()
+    }
+
+    /// The display name, with the MXID appended when another member uses the same name.
+    pub(crate) fn disambiguated_name(&self, user_id: &str) -> String {
+        let Some(name) = self.display_name(user_id) else {
+            return user_id.to_string();
+        };
+
+        let ambiguous = self
+            .name_owners
+            .get(&name)
+            .is_some_and(|owners| owners.iter().any(|owner| owner.as_str() != user_id));
+
+        if ambiguous {
+            format!("{} ({})", name, user_id)
+        } else {
+            name
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use matrix_sdk::ruma::api::client::push::get_pushrules_all;
+use matrix_sdk::ruma::events::push_rules::{PushRulesEvent, PushRulesEventContent};
+use matrix_sdk::room::PushContext;
+use matrix_sdk::ruma::push::Ruleset;
+use matrix_sdk::{Client, Room};
+use tauri::async_runtime::JoinHandle;
+use tauri::State;
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+use crate::state::MatrixState;
+
+/// The account's push rules, fetched at login or restore so they are known
+/// before the first sync finishes, then kept current from synced account
+/// data.
+pub type PushRulesCache = Arc<RwLock<Option<Ruleset>>>;
+
+/// The fetch `track_push_rules` starts, until it is done.
+pub type PushRulesFetchSlot = Arc<RwLock<Option<JoinHandle<()>>>>;
+
+/// Fetches the push rules from the server again, replacing the cached ones,
+/// and returns them. For checking what the server has when notifications
+/// differ from other clients.
+#[tauri::command]
+pub async fn refresh_push_rules(state: State<'_, MatrixState>) -> Result<Ruleset, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+
+    let ruleset = fetch_ruleset(&client).await?;
+    *state.push_rules.write().await = Some(ruleset.clone());
+
+    info!("Refreshed push rules");
+    Ok(ruleset)
+}
+
+/// Starts fetching the push rules alongside the initial sync, and keeps the
+/// cache in step with `m.push_rules` account data from then on. A fetch
+/// that loses the race with sync leaves the synced rules in place.
+/// Replaces a fetch still running for an earlier session.
+pub(crate) async fn track_push_rules(state: &MatrixState, client: &Client) {
+    let cache = state.push_rules.clone();
+    client.add_event_handler(move |event: PushRulesEvent| {
+        let cache = cache.clone();
+        async move {
+            debug!("Push rules updated by sync");
+            *cache.write().await = Some(event.content.global);
+        }
+    });
+
+    let cache = state.push_rules.clone();
+    let client = client.clone();
+    let task = tauri::async_runtime::spawn(async move {
+        match fetch_ruleset(&client).await {
+            Ok(ruleset) => {
+                let mut cache = cache.write().await;
+                if cache.is_none() {
+                    *cache = Some(ruleset);
+                }
+            }
+            Err(e) => warn!("{}", e),
+        }
+    });
+    if let Some(previous) = state.push_rules_fetch.write().await.replace(task) {
+        previous.abort();
+    }
+}
+
+/// The cached push rules, falling back to the ones last synced into the
+/// store while the fetch is still running.
+pub(crate) async fn cached_ruleset(cache: &PushRulesCache, client: &Client) -> Option<Ruleset> {
+    if let Some(ruleset) = cache.read().await.clone() {
+        return Some(ruleset);
+    }
+    let raw = client.account().account_data::<PushRulesEventContent>().await.ok()??;
+    raw.deserialize().ok().map(|content| content.global)
+}
+
+/// A context evaluating the cached push rules for events of `room`, so that
+/// keyword changes apply as soon as the cache has them rather than once the
+/// SDK's own copy catches up. `None` while the room's state is incomplete.
+pub(crate) async fn push_context(cache: &PushRulesCache, room: &Room) -> Option<PushContext> {
+    let ruleset = cached_ruleset(cache, &room.client()).await?;
+    let room_context = room.push_condition_room_ctx().await.ok()??;
+    Some(PushContext::new(room_context, ruleset))
+}
+
+/// The push rules as the server has them now.
+pub(crate) async fn fetch_ruleset(client: &Client) -> Result<Ruleset, String> {
+    client
+        .send(get_pushrules_all::v3::Request::new())
+        .await
+        .map(|response| response.global)
+        .map_err(|e| format!("Failed to load push rules: {}", e))
+}
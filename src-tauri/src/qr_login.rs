@@ -0,0 +1,259 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::StreamExt;
+use matrix_sdk::authentication::oauth::qrcode::{
+    CheckCodeSender, GeneratedQrProgress, GrantLoginProgress, QRCodeGrantLoginError,
+};
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use tauri::async_runtime::JoinHandle;
+use tauri::{AppHandle, Emitter, State};
+use tokio::sync::{oneshot, RwLock};
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::errors::CommandError;
+use crate::state::MatrixState;
+
+/// The unstable feature homeservers advertise for the MSC4108 rendezvous API.
+const RENDEZVOUS_FEATURE: &str = "org.matrix.msc4108";
+/// How long the whole exchange may take, from showing the QR code until the
+/// secrets are handed over.
+const QR_LOGIN_TIMEOUT: Duration = Duration::from_secs(300);
+/// How long to wait for the rendezvous session before giving up on showing a
+/// QR code.
+const QR_READY_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the homeserver gets to create the new device once the user
+/// consented.
+const DEVICE_CREATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A login grant in progress, driven by a background task.
+pub struct QrLoginGrant {
+    task: JoinHandle<()>,
+    check_code: Arc<RwLock<Option<CheckCodeSender>>>,
+}
+
+pub type QrLoginSlot = Arc<RwLock<Option<QrLoginGrant>>>;
+
+/// Payload of `matrix://qr-login-progress`.
+///
+/// `stage` is one of `qr_ready`, `scanned` (the new device shows a two-digit
+/// check code to enter with `confirm_qr_login`), `waiting_for_auth` (open
+/// `verification_uri` to approve the login), `syncing_secrets`, `done`,
+/// `timed_out`, `denied` or `failed`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QrLoginProgress {
+    pub stage: String,
+    pub verification_uri: Option<String>,
+    pub error: Option<String>,
+}
+
+impl QrLoginProgress {
+    fn stage(stage: &str) -> Self {
+        Self {
+            stage: stage.to_string(),
+            verification_uri: None,
+            error: None,
+        }
+    }
+}
+
+/// Starts letting a new device sign in by scanning a QR code shown here
+/// (MSC4108). Returns the QR payload, base64-encoded; the rest of the
+/// exchange is reported through `matrix://qr-login-progress`.
+#[tauri::command]
+pub async fn start_qr_login_grant(
+    app: AppHandle,
+    state: State<'_, MatrixState>,
+) -> Result<String, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+
+    ensure_qr_login_supported(&client).await?;
+
+    if let Some(previous) = state.qr_login.write().await.take() {
+        previous.task.abort();
+    }
+
+    let check_code = Arc::new(RwLock::new(None));
+    let (qr_tx, qr_rx) = oneshot::channel();
+    let task = tauri::async_runtime::spawn(run_grant(app, client, check_code.clone(), qr_tx));
+
+    *state.qr_login.write().await = Some(QrLoginGrant { task, check_code });
+
+    match timeout(QR_READY_TIMEOUT, qr_rx).await {
+        Ok(Ok(Ok(qr))) => Ok(qr),
+        Ok(Ok(Err(e))) => Err(e),
+        Ok(Err(_)) => Err("QR login ended before a QR code was ready".to_string()),
+        Err(_) => {
+            if let Some(grant) = state.qr_login.write().await.take() {
+                grant.task.abort();
+            }
+            Err(CommandError::new(
+                "QR_LOGIN_TIMED_OUT",
+                "The homeserver did not open a sign-in session in time",
+            )
+            .into())
+        }
+    }
+}
+
+/// Confirms the check code shown on the new device, which proves both
+/// devices share the same secure channel.
+#[tauri::command]
+pub async fn confirm_qr_login(
+    state: State<'_, MatrixState>,
+    check_code: u8,
+) -> Result<(), String> {
+    if check_code > 99 {
+        return Err("The check code has two digits".to_string());
+    }
+
+    let sender = {
+        let grant = state.qr_login.read().await;
+        let grant = grant.as_ref().ok_or("No QR login in progress")?;
+        let sender = grant.check_code.write().await.take();
+        sender.ok_or("The new device has not scanned the QR code yet")?
+    };
+
+    sender
+        .send(check_code)
+        .await
+        .map_err(|e| format!("Failed to confirm check code: {}", e))
+}
+
+/// Aborts the QR login, e.g. when the user does not recognise the device.
+#[tauri::command]
+pub async fn deny_qr_login(app: AppHandle, state: State<'_, MatrixState>) -> Result<(), String> {
+    let grant = state.qr_login.write().await.take();
+    let grant = grant.ok_or("No QR login in progress")?;
+    grant.task.abort();
+
+    info!("QR login denied");
+    let _ = app.emit("matrix://qr-login-progress", QrLoginProgress::stage("denied"));
+
+    Ok(())
+}
+
+/// Checks up front that the homeserver offers the rendezvous API and an OAuth
+/// 2.0 server the new device can sign in with.
+async fn ensure_qr_login_supported(client: &Client) -> Result<(), String> {
+    let unsupported = |message: &str| -> String {
+        CommandError::new("QR_LOGIN_UNSUPPORTED", message).into()
+    };
+
+    let features = client
+        .unstable_features()
+        .await
+        .map_err(|e| format!("Failed to get server features: {}", e))?;
+    if !features.iter().any(|f| f.as_str() == RENDEZVOUS_FEATURE) {
+        return Err(unsupported(
+            "Your homeserver does not support signing in with a QR code",
+        ));
+    }
+
+    let metadata = client
+        .oauth()
+        .server_metadata()
+        .await
+        .map_err(|_| unsupported("Your homeserver does not use OAuth 2.0 sign-in, which QR login needs"))?;
+    if metadata.device_authorization_endpoint.is_none() {
+        return Err(unsupported(
+            "Your homeserver's sign-in service does not support device authorization",
+        ));
+    }
+
+    Ok(())
+}
+
+async fn run_grant(
+    app: AppHandle,
+    client: Client,
+    check_code: Arc<RwLock<Option<CheckCodeSender>>>,
+    qr_tx: oneshot::Sender<Result<String, String>>,
+) {
+    let oauth = client.oauth();
+    let grant = oauth
+        .grant_login_with_qr_code()
+        .device_creation_timeout(DEVICE_CREATION_TIMEOUT)
+        .generate();
+
+    let qr_tx = Arc::new(std::sync::Mutex::new(Some(qr_tx)));
+    let mut progress = grant.subscribe_to_progress();
+    let watcher = {
+        let app = app.clone();
+        let qr_tx = qr_tx.clone();
+        tauri::async_runtime::spawn(async move {
+            while let Some(update) = progress.next().await {
+                let event = match update {
+                    GrantLoginProgress::Starting => continue,
+                    GrantLoginProgress::EstablishingSecureChannel(GeneratedQrProgress::QrReady(
+                        data,
+                    )) => {
+                        if let Some(tx) = qr_tx.lock().unwrap().take() {
+                            let _ = tx.send(Ok(STANDARD.encode(data.to_bytes())));
+                        }
+                        QrLoginProgress::stage("qr_ready")
+                    }
+                    GrantLoginProgress::EstablishingSecureChannel(
+                        GeneratedQrProgress::QrScanned(sender),
+                    ) => {
+                        *check_code.write().await = Some(sender);
+                        QrLoginProgress::stage("scanned")
+                    }
+                    GrantLoginProgress::WaitingForAuth { verification_uri } => QrLoginProgress {
+                        verification_uri: Some(verification_uri.to_string()),
+                        ..QrLoginProgress::stage("waiting_for_auth")
+                    },
+                    GrantLoginProgress::SyncingSecrets => QrLoginProgress::stage("syncing_secrets"),
+                    GrantLoginProgress::Done => continue,
+                };
+                let _ = app.emit("matrix://qr-login-progress", event);
+            }
+        })
+    };
+
+    let event = match timeout(QR_LOGIN_TIMEOUT, grant).await {
+        Ok(Ok(())) => {
+            info!("Granted QR login to a new device");
+            QrLoginProgress::stage("done")
+        }
+        Ok(Err(QRCodeGrantLoginError::NotFound)) | Err(_) => QrLoginProgress::stage("timed_out"),
+        Ok(Err(e)) => {
+            warn!("QR login failed: {}", e);
+            QrLoginProgress {
+                error: Some(grant_error_message(&e)),
+                ..QrLoginProgress::stage("failed")
+            }
+        }
+    };
+    watcher.abort();
+
+    if let Some(tx) = qr_tx.lock().unwrap().take() {
+        let _ = tx.send(Err(event
+            .error
+            .clone()
+            .unwrap_or_else(|| "QR login ended before a QR code was ready".to_string())));
+    }
+    let _ = app.emit("matrix://qr-login-progress", event);
+}
+
+fn grant_error_message(error: &QRCodeGrantLoginError) -> String {
+    match error {
+        QRCodeGrantLoginError::MissingSecretsBackup(_) => {
+            "Set up key backup and cross-signing on this device first".to_string()
+        }
+        QRCodeGrantLoginError::InvalidCheckCode => {
+            "The check code did not match the one on the new device".to_string()
+        }
+        QRCodeGrantLoginError::DeviceIDAlreadyInUse => {
+            "The new device asked for a device ID that is already in use".to_string()
+        }
+        QRCodeGrantLoginError::UnableToCreateDevice => {
+            "The homeserver did not create the new device in time".to_string()
+        }
+        e => e.to_string(),
+    }
+}
@@ -0,0 +1,350 @@
+use matrix_sdk::room::{MessagesOptions, Receipts};
+use matrix_sdk::deserialized_responses::TimelineEvent;
+use matrix_sdk::ruma::api::client::receipt::create_receipt;
+use matrix_sdk::ruma::events::fully_read::FullyReadEventContent;
+use matrix_sdk::ruma::{uint, OwnedEventId, OwnedRoomId, UInt};
+use matrix_sdk::{Client, Room};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::State;
+use tokio::sync::RwLock;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::settings::Settings;
+use crate::state::MatrixState;
+
+/// Receipts for the same room are sent at most this often.
+const RECEIPT_INTERVAL: Duration = Duration::from_secs(2);
+/// Delay between rooms when clearing every badge at once.
+const MARK_ALL_PACING: Duration = Duration::from_millis(500);
+
+/// Which read receipts `mark_read`, `mark_room_as_read` and
+/// `mark_thread_read` send, besides the fully-read marker that is always
+/// set.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadReceiptMode {
+    /// `m.read`, seen by everyone in the room.
+    #[default]
+    Public,
+    /// `m.read.private`: our other sessions follow along, others don't see it.
+    Private,
+    /// No receipt; only the fully-read marker in our account data.
+    Off,
+}
+
+impl ReadReceiptMode {
+    /// The receipt type to send, `None` when receipts are off.
+    pub(crate) fn receipt_type(self) -> Option<create_receipt::v3::ReceiptType> {
+        match self {
+            Self::Public => Some(create_receipt::v3::ReceiptType::Read),
+            Self::Private => Some(create_receipt::v3::ReceiptType::ReadPrivate),
+            Self::Off => None,
+        }
+    }
+}
+
+pub type ReadMarkerQueue = Arc<RwLock<HashMap<OwnedRoomId, PendingReadMarker>>>;
+
+pub struct PendingReadMarker {
+    pub event_id: OwnedEventId,
+    pub last_sent: Option<Instant>,
+    pub flush_scheduled: bool,
+}
+
+#[tauri::command]
+pub async fn mark_read(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    event_id: String,
+) -> Result<bool, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let event_id: OwnedEventId = event_id
+        .parse()
+        .map_err(|e| format!("Invalid event ID: {}", e))?;
+
+    if client.get_room(&room_id).is_none() {
+        return Err("Room not found".to_string());
+    }
+
+    queue_read_marker(client, &state.read_markers, &state.settings, room_id, event_id).await
+}
+
+#[tauri::command]
+pub async fn mark_room_as_read(
+    state: State<'_, MatrixState>,
+    room_id: String,
+) -> Result<bool, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let event_id = latest_event_id(&room).await?.ok_or("Room has no events yet")?;
+
+    queue_read_marker(client, &state.read_markers, &state.settings, room_id, event_id).await
+}
+
+/// Where the user stopped reading a room, for the unread divider.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReadMarker {
+    /// The divider goes after this event; `None` when the room has nothing
+    /// unread and no marker.
+    pub event_id: Option<String>,
+    /// Messages after the marker, as counted from sync; approximate.
+    pub unread_count: u64,
+    /// Why `event_id` isn't the fully-read marker, when it isn't:
+    /// `no_marker`, `marker_redacted` or `marker_unavailable`. The event is
+    /// then the one before the oldest unread message we can find.
+    pub fallback_reason: Option<String>,
+}
+
+/// The fully-read marker of the room. Load the timeline around it with
+/// `get_event_context`.
+#[tauri::command]
+pub async fn get_read_marker(state: State<'_, MatrixState>, room_id: String) -> Result<ReadMarker, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let unread_count = room.num_unread_messages();
+    let marker = room
+        .account_data_static::<FullyReadEventContent>()
+        .await
+        .ok()
+        .flatten()
+        .and_then(|raw| raw.deserialize().ok())
+        .map(|event| event.content.event_id);
+
+    let fallback_reason = match &marker {
+        None => "no_marker",
+        Some(event_id) => match room.event(event_id, None).await {
+            Ok(event) if !is_redacted(&event) => {
+                return Ok(ReadMarker {
+                    event_id: Some(event_id.to_string()),
+                    unread_count,
+                    fallback_reason: None,
+                });
+            }
+            Ok(_) => "marker_redacted",
+            Err(_) => "marker_unavailable",
+        },
+    };
+
+    Ok(ReadMarker {
+        event_id: unread_boundary(&room, unread_count).await?.map(|id| id.to_string()),
+        unread_count,
+        fallback_reason: Some(fallback_reason.to_string()),
+    })
+}
+
+/// Outcome of `mark_all_rooms_as_read`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MarkAllReadResult {
+    /// Rooms whose read receipt was sent.
+    pub marked: usize,
+    /// Rooms that couldn't be marked, with why; the others were still done.
+    pub failed: Vec<RoomReadFailure>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomReadFailure {
+    pub room_id: String,
+    pub error: String,
+}
+
+#[tauri::command]
+pub async fn mark_all_rooms_as_read(state: State<'_, MatrixState>) -> Result<MarkAllReadResult, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+
+    let unread_rooms: Vec<Room> = client
+        .joined_rooms()
+        .into_iter()
+        .filter(|room| {
+            room.num_unread_messages() > 0
+                || room.num_unread_notifications() > 0
+                || room.is_marked_unread()
+        })
+        .collect();
+
+    info!("Marking {} rooms as read", unread_rooms.len());
+
+    let mut marked = 0;
+    let mut failed = Vec::new();
+    for (idx, room) in unread_rooms.iter().enumerate() {
+        if idx > 0 {
+            sleep(MARK_ALL_PACING).await;
+        }
+
+        if room.is_marked_unread() {
+            if let Err(e) = room.set_unread_flag(false).await {
+                warn!("Failed to clear unread flag for {}: {}", room.room_id(), e);
+            }
+        }
+
+        match latest_event_id(room).await {
+            Ok(Some(event_id)) => {
+                if let Err(e) = send_read_receipts(room, event_id.clone(), &state.settings).await {
+                    warn!("Failed to mark {} as read: {}", room.room_id(), e);
+                    failed.push(RoomReadFailure {
+                        room_id: room.room_id().to_string(),
+                        error: e,
+                    });
+                    continue;
+                }
+                if let Some(pending) = state.read_markers.write().await.get_mut(room.room_id()) {
+                    pending.event_id = event_id;
+                    pending.last_sent = Some(Instant::now());
+                }
+                marked += 1;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Skipping {}: {}", room.room_id(), e);
+                failed.push(RoomReadFailure {
+                    room_id: room.room_id().to_string(),
+                    error: e,
+                });
+            }
+        }
+    }
+
+    Ok(MarkAllReadResult { marked, failed })
+}
+
+/// Records `event_id` as the newest read event of the room and sends it,
+/// coalescing calls that arrive within [`RECEIPT_INTERVAL`] of the last send
+/// into one delayed flush of the newest event. Returns whether it was sent
+/// immediately.
+pub(crate) async fn queue_read_marker(
+    client: &Client,
+    queue: &ReadMarkerQueue,
+    settings: &Arc<RwLock<Settings>>,
+    room_id: OwnedRoomId,
+    event_id: OwnedEventId,
+) -> Result<bool, String> {
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let mut markers = queue.write().await;
+    let pending = markers.entry(room_id.clone()).or_insert(PendingReadMarker {
+        event_id: event_id.clone(),
+        last_sent: None,
+        flush_scheduled: false,
+    });
+    pending.event_id = event_id.clone();
+
+    if pending.flush_scheduled {
+        return Ok(false);
+    }
+
+    let wait = pending
+        .last_sent
+        .map(|sent| RECEIPT_INTERVAL.saturating_sub(sent.elapsed()))
+        .unwrap_or_default();
+
+    if wait.is_zero() {
+        pending.last_sent = Some(Instant::now());
+        drop(markers);
+        send_read_receipts(&room, event_id, settings).await?;
+        return Ok(true);
+    }
+
+    pending.flush_scheduled = true;
+    drop(markers);
+
+    let queue = queue.clone();
+    let settings = settings.clone();
+    tauri::async_runtime::spawn(async move {
+        sleep(wait).await;
+
+        let event_id = {
+            let mut markers = queue.write().await;
+            let Some(pending) = markers.get_mut(&room_id) else {
+                return;
+            };
+            pending.flush_scheduled = false;
+            pending.last_sent = Some(Instant::now());
+            pending.event_id.clone()
+        };
+
+        if let Err(e) = send_read_receipts(&room, event_id, &settings).await {
+            warn!("Failed to flush read marker for {}: {}", room_id, e);
+        }
+    });
+
+    Ok(false)
+}
+
+/// Sends the marker and the receipt `send_read_receipts` in the settings
+/// asks for, as set when it goes out, so a delayed flush follows a change.
+async fn send_read_receipts(
+    room: &Room,
+    event_id: OwnedEventId,
+    settings: &Arc<RwLock<Settings>>,
+) -> Result<(), String> {
+    let receipts = Receipts::new().fully_read_marker(event_id.clone());
+    let receipts = match settings.read().await.send_read_receipts {
+        ReadReceiptMode::Public => receipts.public_read_receipt(event_id),
+        ReadReceiptMode::Private => receipts.private_read_receipt(event_id),
+        ReadReceiptMode::Off => receipts,
+    };
+
+    room.send_multiple_receipts(receipts)
+        .await
+        .map_err(|e| format!("Failed to send read receipt: {}", e))
+}
+
+/// The event just before the last `unread_count` events, i.e. the newest
+/// one that is read. `None` when nothing is unread, or when everything we can
+/// load in one page is.
+async fn unread_boundary(room: &Room, unread_count: u64) -> Result<Option<OwnedEventId>, String> {
+    if unread_count == 0 {
+        return Ok(None);
+    }
+
+    let mut options = MessagesOptions::backward();
+    options.limit = UInt::new(unread_count + 1).unwrap_or(uint!(100)).min(uint!(100));
+
+    let response = room
+        .messages(options)
+        .await
+        .map_err(|e| format!("Failed to fetch unread messages: {}", e))?;
+
+    Ok(response.chunk.get(unread_count as usize).and_then(|event| event.event_id()))
+}
+
+fn is_redacted(event: &TimelineEvent) -> bool {
+    event
+        .raw()
+        .get_field::<serde_json::Value>("unsigned")
+        .ok()
+        .flatten()
+        .is_some_and(|unsigned| unsigned.get("redacted_because").is_some())
+}
+
+pub(crate) async fn latest_event_id(room: &Room) -> Result<Option<OwnedEventId>, String> {
+    let mut options = MessagesOptions::backward();
+    options.limit = uint!(1);
+
+    let response = room
+        .messages(options)
+        .await
+        .map_err(|e| format!("Failed to fetch latest event: {}", e))?;
+
+    Ok(response.chunk.first().and_then(|event| event.event_id()))
+}
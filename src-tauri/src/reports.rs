@@ -0,0 +1,49 @@
+use matrix_sdk::ruma::api::client::reporting::report_user;
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId};
+use tauri::State;
+use tracing::info;
+
+use crate::errors::CommandError;
+use crate::previews::endpoint_missing;
+use crate::state::MatrixState;
+
+/// Reports a user to the homeserver's admins. The endpoint is new (Matrix
+/// 1.14, MSC4260), so servers without it get `USER_REPORTS_UNSUPPORTED`
+/// and the UI can offer reporting their messages instead. The room the
+/// report was made from is added to the reason for context.
+#[tauri::command]
+pub async fn report_user(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    user_id: String,
+    reason: String,
+) -> Result<(), String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let user_id: OwnedUserId = user_id
+        .parse()
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
+    client.get_room(&room_id).ok_or("Room not found")?;
+
+    let reason = match reason.trim() {
+        "" => format!("Reported from {}", room_id),
+        reason => format!("{} (reported from {})", reason, room_id),
+    };
+
+    match client.send(report_user::v3::Request::new(user_id.clone(), reason)).await {
+        Ok(_) => {
+            info!("Reported {}", user_id);
+            Ok(())
+        }
+        Err(e) if endpoint_missing(&e) => Err(CommandError::new(
+            "USER_REPORTS_UNSUPPORTED",
+            "Your homeserver doesn't accept reports about users. Report their messages instead.",
+        )
+        .into()),
+        Err(e) => Err(format!("Failed to report user: {}", e)),
+    }
+}
@@ -0,0 +1,118 @@
+use matrix_sdk::ruma::events::StateEventType;
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedRoomId};
+use matrix_sdk::{Client, Room};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tauri::State;
+use tracing::info;
+
+use crate::emoji::state_event_json;
+use crate::errors::CommandError;
+use crate::rooms::Message;
+use crate::state::MatrixState;
+
+/// Not in ruma yet; defined by MSC1763.
+const RETENTION_EVENT_TYPE: &str = "m.room.retention";
+
+/// A room's `m.room.retention` policy, in milliseconds. Servers enforce it;
+/// the client only shows when messages will go.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RoomRetention {
+    pub min_lifetime: Option<u64>,
+    pub max_lifetime: Option<u64>,
+}
+
+impl RoomRetention {
+    /// When a message sent at `timestamp` expires, if the policy has a
+    /// maximum lifetime.
+    pub(crate) fn expires_at(&self, timestamp: u64) -> Option<u64> {
+        self.max_lifetime.map(|lifetime| timestamp.saturating_add(lifetime))
+    }
+}
+
+/// The room's retention policy, or `None` when it has none or the event sets
+/// neither lifetime.
+pub(crate) async fn room_retention(room: &Room) -> Option<RoomRetention> {
+    let raw = room
+        .get_state_event(StateEventType::from(RETENTION_EVENT_TYPE), "")
+        .await
+        .ok()
+        .flatten()?;
+    let (_, content) = state_event_json(&raw)?;
+    let lifetime = |key: &str| content.get(key).and_then(Value::as_u64);
+
+    let retention = RoomRetention {
+        min_lifetime: lifetime("min_lifetime"),
+        max_lifetime: lifetime("max_lifetime"),
+    };
+    (retention != RoomRetention::default()).then_some(retention)
+}
+
+/// Sets `expires_at` on `messages` from the room's policy.
+pub(crate) fn annotate_expiry(messages: &mut [Message], retention: Option<&RoomRetention>) {
+    for message in messages {
+        message.expires_at = retention.and_then(|r| r.expires_at(message.timestamp));
+    }
+}
+
+/// Whether a message with this expiry is past it, so cached copies should no
+/// longer be shown.
+pub(crate) fn is_expired(expires_at: Option<u64>) -> bool {
+    let now: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+    expires_at.is_some_and(|at| at <= now)
+}
+
+/// Sets how long the server keeps the room's messages; `None` removes the
+/// limit. Any `min_lifetime` already in the policy is kept.
+#[tauri::command]
+pub async fn set_room_retention(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    max_lifetime_ms: Option<u64>,
+) -> Result<(), String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    if max_lifetime_ms == Some(0) {
+        return Err("Messages must be kept for some time".to_string());
+    }
+    if !can_change_retention(client, &room).await {
+        return Err(CommandError::new(
+            "INSUFFICIENT_POWER_LEVEL",
+            "You don't have permission to change how long messages are kept",
+        )
+        .into());
+    }
+
+    let current = room_retention(&room).await.unwrap_or_default();
+    let mut content = json!({});
+    if let Some(min) = current.min_lifetime {
+        content["min_lifetime"] = json!(min);
+    }
+    if let Some(max) = max_lifetime_ms {
+        content["max_lifetime"] = json!(max);
+    }
+
+    room.send_state_event_raw(RETENTION_EVENT_TYPE, "", content)
+        .await
+        .map_err(|e| format!("Failed to change retention policy: {}", e))?;
+
+    info!("Set max message lifetime of {} to {:?} ms", room_id, max_lifetime_ms);
+
+    state.room_cache.write().await.remove(&room_id);
+    Ok(())
+}
+
+async fn can_change_retention(client: &Client, room: &Room) -> bool {
+    let Some(user_id) = client.user_id() else {
+        return false;
+    };
+    room.power_levels().await.is_ok_and(|levels| {
+        levels.user_can_send_state(user_id, StateEventType::from(RETENTION_EVENT_TYPE))
+    })
+}
@@ -0,0 +1,285 @@
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::api::client::room::Visibility;
+use matrix_sdk::ruma::events::StateEventType;
+use matrix_sdk::ruma::{OwnedRoomAliasId, OwnedRoomId};
+use matrix_sdk::{Client, Room};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tauri::State;
+use tracing::{info, warn};
+
+use crate::errors::CommandError;
+use crate::state::MatrixState;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DirectoryVisibility {
+    /// Whether the room is listed in the server's public room directory.
+    pub public: bool,
+    /// Whether we may change it: publishing needs a canonical alias, and
+    /// either way enough power to set one.
+    pub can_change: bool,
+}
+
+/// Whether an alias can be created, for validating it as the user types.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AliasAvailability {
+    /// The alias as it would be created, e.g. `#room:example.org`; the input
+    /// when it couldn't be made into one.
+    pub alias: String,
+    /// `free`, `taken` or `invalid`.
+    pub status: String,
+    /// Why the alias is invalid.
+    pub reason: Option<String>,
+    /// The room a taken alias points to.
+    pub room_id: Option<String>,
+}
+
+/// A room's settings, for the room settings screen.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomSettings {
+    pub room_id: String,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub canonical_alias: Option<String>,
+    pub join_rule: Option<String>,
+    pub history_visibility: Option<String>,
+    pub encrypted: bool,
+    /// Whether the room is in the public directory; `None` when the server
+    /// couldn't be asked.
+    pub directory_public: Option<bool>,
+}
+
+#[tauri::command]
+pub async fn get_room_state(state: State<'_, MatrixState>, room_id: String) -> Result<RoomSettings, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let directory_public = match room.privacy_settings().get_room_visibility().await {
+        Ok(visibility) => Some(visibility == Visibility::Public),
+        Err(e) => {
+            warn!("Failed to get directory visibility of {}: {}", room_id, e);
+            None
+        }
+    };
+
+    Ok(RoomSettings {
+        room_id: room_id.to_string(),
+        name: room.name(),
+        topic: room.topic(),
+        canonical_alias: room.canonical_alias().map(|alias| alias.to_string()),
+        join_rule: room.join_rule().map(|rule| rule.as_str().to_string()),
+        history_visibility: room.history_visibility().map(|h| h.to_string()),
+        encrypted: room.encryption_settings().is_some(),
+        directory_public,
+    })
+}
+
+#[tauri::command]
+pub async fn get_room_directory_visibility(
+    state: State<'_, MatrixState>,
+    room_id: String,
+) -> Result<DirectoryVisibility, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let visibility = room
+        .privacy_settings()
+        .get_room_visibility()
+        .await
+        .map_err(|e| format!("Failed to get directory visibility: {}", e))?;
+
+    let public = visibility == Visibility::Public;
+    Ok(DirectoryVisibility {
+        public,
+        // Unlisting works without an alias.
+        can_change: (public || room.canonical_alias().is_some()) && can_publish(client, &room).await,
+    })
+}
+
+/// Lists the room in the server's public directory, or removes it.
+#[tauri::command]
+pub async fn set_room_directory_visibility(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    public: bool,
+) -> Result<(), String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    if !can_publish(client, &room).await {
+        return Err(CommandError::new(
+            "INSUFFICIENT_POWER_LEVEL",
+            "You don't have permission to change whether this room is listed",
+        )
+        .into());
+    }
+    if public && room.canonical_alias().is_none() {
+        return Err(CommandError::new(
+            "NO_CANONICAL_ALIAS",
+            "Give the room an address before publishing it",
+        )
+        .into());
+    }
+
+    let visibility = if public { Visibility::Public } else { Visibility::Private };
+    room.privacy_settings()
+        .update_room_visibility(visibility)
+        .await
+        .map_err(|e| -> String {
+            match e.client_api_error_kind() {
+                Some(ErrorKind::Forbidden { .. }) => CommandError::new(
+                    "PUBLISHING_NOT_ALLOWED",
+                    "Your server does not allow publishing rooms to its directory",
+                )
+                .into(),
+                _ => format!("Failed to change directory visibility: {}", e),
+            }
+        })?;
+
+    info!("Set directory visibility of {} to {}", room_id, if public { "public" } else { "private" });
+
+    Ok(())
+}
+
+/// Checks whether `alias` could be created, without creating it. `room`,
+/// `#room` and `room:server` are completed with the user's homeserver.
+#[tauri::command]
+pub async fn check_alias_available(
+    state: State<'_, MatrixState>,
+    alias: String,
+) -> Result<AliasAvailability, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let alias = match local_alias(client, &alias) {
+        Ok(alias) => alias,
+        Err(reason) => {
+            return Ok(AliasAvailability {
+                alias: alias.trim().to_string(),
+                status: "invalid".to_string(),
+                reason: Some(reason),
+                room_id: None,
+            })
+        }
+    };
+
+    let room_id = match client.resolve_room_alias(&alias).await {
+        Ok(response) => Some(response.room_id.to_string()),
+        Err(e) if matches!(e.client_api_error_kind(), Some(ErrorKind::NotFound)) => None,
+        Err(e) => return Err(format!("Failed to look up alias: {}", e)),
+    };
+
+    Ok(AliasAvailability {
+        alias: alias.to_string(),
+        status: if room_id.is_some() { "taken" } else { "free" }.to_string(),
+        reason: None,
+        room_id,
+    })
+}
+
+/// Creates `alias` for the room and makes it the room's address. The
+/// previous address stays on as an alternative. Returns the alias created,
+/// completed like in `check_alias_available`.
+#[tauri::command]
+pub async fn publish_room_alias(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    alias: String,
+) -> Result<String, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let alias = local_alias(client, &alias)
+        .map_err(|reason| -> String { CommandError::new("INVALID_ALIAS", reason).into() })?;
+    if !can_publish(client, &room).await {
+        return Err(CommandError::new(
+            "INSUFFICIENT_POWER_LEVEL",
+            "You don't have permission to change this room's address",
+        )
+        .into());
+    }
+
+    let created = room
+        .privacy_settings()
+        .publish_room_alias_in_room_directory(&alias)
+        .await
+        .map_err(|e| format!("Failed to create alias: {}", e))?;
+    if !created {
+        return Err(CommandError::new("ALIAS_TAKEN", format!("{} is already taken", alias))
+            .with_details(json!({ "alias": alias }))
+            .into());
+    }
+
+    let mut alt_aliases = room.alt_aliases();
+    if let Some(previous) = room.canonical_alias() {
+        if previous != alias && !alt_aliases.contains(&previous) {
+            alt_aliases.push(previous);
+        }
+    }
+    alt_aliases.retain(|a| *a != alias);
+    room.privacy_settings()
+        .update_canonical_alias(Some(alias.clone()), alt_aliases)
+        .await
+        .map_err(|e| format!("Created {} but failed to make it the room's address: {}", alias, e))?;
+
+    info!("Published {} for {}", alias, room_id);
+
+    Ok(alias.to_string())
+}
+
+/// Completes and validates an alias the user typed. Only aliases on the
+/// user's own homeserver can be created.
+fn local_alias(client: &Client, input: &str) -> Result<OwnedRoomAliasId, String> {
+    let server = client.user_id().ok_or("Not logged in")?.server_name();
+
+    let input = input.trim().trim_start_matches('#');
+    let (localpart, alias_server) = match input.split_once(':') {
+        Some((localpart, alias_server)) => (localpart, alias_server),
+        None => (input, server.as_str()),
+    };
+    if localpart.is_empty() {
+        return Err("The alias needs a name before the server".to_string());
+    }
+    if localpart.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err("Aliases can't contain spaces".to_string());
+    }
+
+    let alias = OwnedRoomAliasId::try_from(format!("#{}:{}", localpart, alias_server))
+        .map_err(|e| format!("Not a valid alias: {}", e))?;
+    if alias.server_name() != server {
+        return Err(format!(
+            "You can only create aliases on your own homeserver ({})",
+            server
+        ));
+    }
+    Ok(alias)
+}
+
+async fn can_publish(client: &Client, room: &Room) -> bool {
+    let Some(user_id) = client.user_id() else {
+        return false;
+    };
+    room.power_levels()
+        .await
+        .is_ok_and(|levels| levels.user_can_send_state(user_id, StateEventType::RoomCanonicalAlias))
+}
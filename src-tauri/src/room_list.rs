@@ -0,0 +1,175 @@
+use matrix_sdk::ruma::events::StateEventType;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::{Room, RoomState};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use tauri::State;
+
+use crate::emoji::state_event_json;
+use crate::rooms::{cached_room_infos, is_archived, RoomInfo};
+use crate::state::MatrixState;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomSection {
+    Dm,
+    Group,
+    Favourite,
+    LowPriority,
+    /// Children of the space given as `space_id`.
+    Space,
+    Invites,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RoomFilter {
+    /// Matched case-insensitively against name, alias, topic and room ID.
+    #[serde(default)]
+    pub query: Option<String>,
+    #[serde(default)]
+    pub section: Option<RoomSection>,
+    #[serde(default)]
+    pub space_id: Option<String>,
+    #[serde(default)]
+    pub unread_only: bool,
+    #[serde(default)]
+    pub encrypted_only: bool,
+    /// Also list rooms we left or were banned from.
+    #[serde(default)]
+    pub include_left: bool,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomListPage {
+    /// Number of rooms matching the filter, before `offset`/`limit`.
+    pub total: usize,
+    pub rooms: Vec<RoomInfo>,
+}
+
+/// Rooms matching `filter`, most recently active first. Runs against the
+/// cached `RoomInfo`s so it is cheap enough to call on every keystroke.
+#[tauri::command]
+pub async fn get_rooms_filtered(
+    state: State<'_, MatrixState>,
+    filter: RoomFilter,
+) -> Result<RoomListPage, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let space_children = match (filter.section, filter.space_id.as_deref()) {
+        (Some(RoomSection::Space), Some(space_id)) => {
+            let space_id: OwnedRoomId = space_id
+                .parse()
+                .map_err(|e| format!("Invalid space ID: {}", e))?;
+            let space = client.get_room(&space_id).ok_or("Space not found")?;
+            Some(space_children(&space).await?)
+        }
+        (Some(RoomSection::Space), None) => {
+            return Err("The space section needs a space_id".to_string())
+        }
+        _ => None,
+    };
+
+    let rooms: Vec<Room> = client
+        .rooms()
+        .into_iter()
+        .filter(|room| in_section(room, filter.section, space_children.as_ref()))
+        .filter(|room| filter.include_left || !is_archived(room))
+        .filter(|room| !filter.unread_only || is_unread(room))
+        .filter(|room| !filter.encrypted_only || room.encryption_state().is_encrypted())
+        .collect();
+
+    let infos = cached_room_infos(&state, &rooms).await;
+    let query = filter
+        .query
+        .as_deref()
+        .map(|q| q.trim().to_lowercase())
+        .filter(|q| !q.is_empty());
+
+    let activity = state.room_activity.read().await;
+    let mut matches: Vec<(u64, RoomInfo)> = rooms
+        .iter()
+        .zip(infos)
+        .filter(|(room, info)| match &query {
+            Some(query) => matches_query(room, info, query),
+            None => true,
+        })
+        .map(|(room, info)| (last_active(&activity, room), info))
+        .collect();
+    drop(activity);
+
+    matches.sort_by_key(|(last_active, _)| Reverse(*last_active));
+
+    let total = matches.len();
+    let rooms = matches
+        .into_iter()
+        .skip(filter.offset)
+        .take(filter.limit.unwrap_or(usize::MAX))
+        .map(|(_, info)| info)
+        .collect();
+
+    Ok(RoomListPage { total, rooms })
+}
+
+fn in_section(room: &Room, section: Option<RoomSection>, space_children: Option<&HashSet<String>>) -> bool {
+    let Some(section) = section else {
+        return true;
+    };
+
+    let joined = room.state() == RoomState::Joined;
+
+    match section {
+        RoomSection::Invites => room.state() == RoomState::Invited,
+        RoomSection::Dm => joined && room.direct_targets_length() > 0 && !room.is_space(),
+        RoomSection::Group => joined && room.direct_targets_length() == 0 && !room.is_space(),
+        RoomSection::Favourite => joined && room.is_favourite(),
+        RoomSection::LowPriority => joined && room.is_low_priority(),
+        RoomSection::Space => {
+            joined && space_children.is_some_and(|c| c.contains(room.room_id().as_str()))
+        }
+    }
+}
+
+/// When the room last saw an event: from sync, or else the latest event the
+/// store knows of.
+pub(crate) fn last_active(activity: &HashMap<OwnedRoomId, u64>, room: &Room) -> u64 {
+    activity
+        .get(room.room_id())
+        .copied()
+        .or_else(|| room.new_latest_event_timestamp().map(|ts| ts.get().into()))
+        .unwrap_or(0)
+}
+
+fn is_unread(room: &Room) -> bool {
+    room.num_unread_messages() > 0 || room.num_unread_notifications() > 0 || room.is_marked_unread()
+}
+
+fn matches_query(room: &Room, info: &RoomInfo, query: &str) -> bool {
+    let contains = |value: Option<&str>| value.is_some_and(|v| v.to_lowercase().contains(query));
+
+    contains(info.name.as_deref())
+        || contains(info.topic.as_deref())
+        || contains(room.canonical_alias().as_ref().map(|a| a.as_str()))
+        || contains(Some(room.room_id().as_str()))
+}
+
+/// Room IDs listed by the space's `m.space.child` events. Children removed by
+/// sending empty content are skipped.
+async fn space_children(space: &Room) -> Result<HashSet<String>, String> {
+    let events = space
+        .get_state_events(StateEventType::SpaceChild)
+        .await
+        .map_err(|e| format!("Failed to load space children: {}", e))?;
+
+    Ok(events
+        .iter()
+        .filter_map(state_event_json)
+        .filter(|(_, content)| content.get("via").is_some())
+        .map(|(state_key, _)| state_key)
+        .collect())
+}
@@ -0,0 +1,284 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use matrix_sdk::notification_settings::{IsEncrypted, IsOneToOne, RoomNotificationMode};
+use matrix_sdk::ruma::api::client::push::{delete_pushrule, set_pushrule};
+use matrix_sdk::ruma::push::{
+    Action, NewConditionalPushRule, NewPushRule, PushCondition, RuleKind, Ruleset, Tweak,
+};
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tauri::State;
+use tracing::{info, warn};
+
+use crate::account_data::APP_NAMESPACE;
+use crate::errors::CommandError;
+use crate::push_rules::{cached_ruleset, fetch_ruleset};
+use crate::state::MatrixState;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomNotificationSettings {
+    /// `all_messages`, `mentions_and_keywords` or `mute`.
+    pub mode: String,
+    /// Whether `mode` was chosen for this room rather than being the default.
+    pub custom: bool,
+    pub keywords: Vec<RoomKeyword>,
+}
+
+/// A keyword that notifies only in one room.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomKeyword {
+    pub keyword: String,
+    pub enabled: bool,
+    /// Set when the same keyword also notifies everywhere, which makes this
+    /// one redundant.
+    pub also_global: bool,
+}
+
+/// The room's notification mode and its room-only keywords. Keywords come
+/// from the cached push rules, which sync keeps current with changes made on
+/// other devices.
+#[tauri::command]
+pub async fn get_room_notification_settings(
+    state: State<'_, MatrixState>,
+    room_id: String,
+) -> Result<RoomNotificationSettings, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let settings = client.notification_settings().await;
+    let custom = settings.get_user_defined_room_notification_mode(&room_id).await;
+    let mode = match custom {
+        Some(mode) => mode,
+        None => {
+            let encrypted = room.latest_encryption_state().await.is_ok_and(|s| s.is_encrypted());
+            let one_to_one = room.active_members_count() == 2;
+            let (encrypted, one_to_one) = (IsEncrypted::from(encrypted), IsOneToOne::from(one_to_one));
+            settings.get_default_room_notification_mode(encrypted, one_to_one).await
+        }
+    };
+
+    let ruleset = match cached_ruleset(&state.push_rules, client).await {
+        Some(ruleset) => ruleset,
+        None => fetch_ruleset(client).await?,
+    };
+    let keywords = ruleset
+        .override_
+        .iter()
+        .filter_map(|rule| {
+            let keyword = room_keyword(&rule.rule_id, &rule.conditions, &room_id)?;
+            Some(RoomKeyword {
+                also_global: global_keyword_exists(&ruleset, &keyword),
+                keyword,
+                enabled: rule.enabled,
+            })
+        })
+        .collect();
+
+    Ok(RoomNotificationSettings {
+        mode: match mode {
+            RoomNotificationMode::AllMessages => "all_messages",
+            RoomNotificationMode::MentionsAndKeywordsOnly => "mentions_and_keywords",
+            RoomNotificationMode::Mute => "mute",
+        }
+        .to_string(),
+        custom: custom.is_some(),
+        keywords,
+    })
+}
+
+/// Makes `keyword` notify and highlight in this room only, as an override
+/// push rule matching the room and the word. Being a push rule, it also
+/// applies on the user's other devices and in the SDK's local evaluation of
+/// synced events. Fails with `KEYWORD_ALREADY_GLOBAL` or
+/// `KEYWORD_ALREADY_IN_ROOM` instead of adding a duplicate.
+#[tauri::command]
+pub async fn add_room_keyword(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    keyword: String,
+) -> Result<(), String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    client.get_room(&room_id).ok_or("Room not found")?;
+    let keyword = keyword.trim();
+    if keyword.is_empty() {
+        return Err("Keyword cannot be empty".to_string());
+    }
+
+    let mut ruleset = fetch_ruleset(client).await?;
+    if global_keyword_exists(&ruleset, keyword) {
+        return Err(CommandError::new(
+            "KEYWORD_ALREADY_GLOBAL",
+            format!("\"{}\" already notifies you in every room", keyword),
+        )
+        .with_details(json!({ "keyword": keyword }))
+        .into());
+    }
+    let existing = ruleset.override_.iter().any(|rule| {
+        room_keyword(&rule.rule_id, &rule.conditions, &room_id)
+            .is_some_and(|existing| existing.to_lowercase() == keyword.to_lowercase())
+    });
+    if existing {
+        return Err(CommandError::new(
+            "KEYWORD_ALREADY_IN_ROOM",
+            format!("\"{}\" already notifies you in this room", keyword),
+        )
+        .with_details(json!({ "keyword": keyword }))
+        .into());
+    }
+
+    let rule = NewConditionalPushRule::new(
+        room_keyword_rule_id(&room_id, keyword),
+        vec![
+            PushCondition::EventMatch {
+                key: "room_id".to_string(),
+                pattern: room_id.to_string(),
+            },
+            PushCondition::EventMatch {
+                key: "content.body".to_string(),
+                pattern: keyword.to_string(),
+            },
+        ],
+        vec![
+            Action::Notify,
+            Action::SetTweak(Tweak::Sound("default".to_string())),
+            Action::SetTweak(Tweak::Highlight(true)),
+        ],
+    );
+    client
+        .send(set_pushrule::v3::Request::new(NewPushRule::Override(rule.clone())))
+        .await
+        .map_err(|e| format!("Failed to add keyword: {}", e))?;
+    // Sync brings the change too, but only after the next response.
+    match ruleset.insert(NewPushRule::Override(rule), None, None) {
+        Ok(()) => *state.push_rules.write().await = Some(ruleset),
+        Err(_) => refresh_cache(&state, client).await,
+    }
+
+    info!("Added keyword {:?} for {}", keyword, room_id);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn remove_room_keyword(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    keyword: String,
+) -> Result<(), String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let keyword = keyword.trim();
+
+    let mut ruleset = fetch_ruleset(client).await?;
+    let rule_ids: Vec<String> = ruleset
+        .override_
+        .iter()
+        .filter(|rule| {
+            room_keyword(&rule.rule_id, &rule.conditions, &room_id)
+                .is_some_and(|existing| existing.to_lowercase() == keyword.to_lowercase())
+        })
+        .map(|rule| rule.rule_id.clone())
+        .collect();
+    if rule_ids.is_empty() {
+        return Err("Keyword not found".to_string());
+    }
+
+    for rule_id in rule_ids {
+        client
+            .send(delete_pushrule::v3::Request::new(RuleKind::Override, rule_id.clone()))
+            .await
+            .map_err(|e| format!("Failed to remove keyword: {}", e))?;
+        let _ = ruleset.remove(RuleKind::Override, rule_id);
+    }
+    *state.push_rules.write().await = Some(ruleset);
+
+    info!("Removed keyword {:?} for {}", keyword, room_id);
+    Ok(())
+}
+
+/// Replaces the cached push rules with the server's, keeping the cached
+/// ones when the fetch fails.
+async fn refresh_cache(state: &MatrixState, client: &Client) {
+    match fetch_ruleset(client).await {
+        Ok(ruleset) => *state.push_rules.write().await = Some(ruleset),
+        Err(e) => warn!("{}", e),
+    }
+}
+
+/// The rule ID for a room keyword. The room and keyword are hashed rather
+/// than embedded, since a keyword may contain anything (`/`, `.`, spaces)
+/// and the ID ends up in the push rule URL path.
+fn room_keyword_rule_id(room_id: &RoomId, keyword: &str) -> String {
+    let digest = Sha256::digest(format!("{}\n{}", room_id, keyword.to_lowercase()).as_bytes());
+    format!("{}.room_keyword.{}", APP_NAMESPACE, URL_SAFE_NO_PAD.encode(digest))
+}
+
+/// The keyword of one of our room keyword rules for `room_id`: an override
+/// with exactly a room condition and a body condition.
+fn room_keyword(rule_id: &str, conditions: &[PushCondition], room_id: &RoomId) -> Option<String> {
+    if !rule_id.starts_with(&format!("{}.room_keyword.", APP_NAMESPACE)) {
+        return None;
+    }
+    match conditions {
+        [PushCondition::EventMatch { key: room_key, pattern: room }, PushCondition::EventMatch { key: body_key, pattern: keyword }]
+            if room_key == "room_id" && room == room_id.as_str() && body_key == "content.body" =>
+        {
+            Some(keyword.clone())
+        }
+        _ => None,
+    }
+}
+
+/// The enabled keywords that notify in `room_id`: the user's global ones
+/// and the room's own.
+pub(crate) fn room_keywords(ruleset: &Ruleset, room_id: &RoomId) -> Vec<String> {
+    let global = ruleset
+        .content
+        .iter()
+        .filter(|rule| !rule.default && rule.enabled)
+        .map(|rule| rule.pattern.clone());
+    let room = ruleset
+        .override_
+        .iter()
+        .filter(|rule| rule.enabled)
+        .filter_map(|rule| room_keyword(&rule.rule_id, &rule.conditions, room_id));
+    global.chain(room).collect()
+}
+
+/// Whether `keyword` occurs in `body` as a whole word, ignoring case, the
+/// way push rule content patterns match.
+pub(crate) fn matches_keyword(body: &str, keyword: &str) -> bool {
+    let (body, keyword) = (body.to_lowercase(), keyword.to_lowercase());
+    if keyword.is_empty() {
+        return false;
+    }
+    body.match_indices(&keyword).any(|(start, _)| {
+        let before = body[..start].chars().next_back();
+        let after = body[start + keyword.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+/// Whether a user-defined global keyword (content rule) has the same pattern.
+fn global_keyword_exists(ruleset: &Ruleset, keyword: &str) -> bool {
+    ruleset
+        .content
+        .iter()
+        .any(|rule| !rule.default && rule.pattern.to_lowercase() == keyword.to_lowercase())
+}
@@ -0,0 +1,136 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use tauri::async_runtime::JoinHandle;
+use tauri::{AppHandle, Emitter};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+use crate::rooms::RoomInfo;
+use crate::state::MatrixState;
+
+/// How long room updates are collected before renames are emitted. A bridge
+/// syncing in hundreds of members renames the same rooms over and over;
+/// each room is reported once per window with its latest name.
+const RENAME_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The background task behind `matrix://room-renamed`.
+pub type RenameWatcherSlot = Arc<RwLock<Option<JoinHandle<()>>>>;
+
+/// The name the room list was last given for each room, by `get_rooms` or
+/// `matrix://room-renamed`. Kept apart from the `RoomInfo` cache, which
+/// drops rooms on every member event.
+pub type ReportedRoomNames = Arc<RwLock<HashMap<OwnedRoomId, String>>>;
+
+/// Payload of `matrix://room-renamed`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomRenamed {
+    pub room_id: String,
+    pub name: String,
+}
+
+/// Records the names `get_rooms` hands out, which renames are told apart
+/// from.
+pub(crate) async fn note_room_names(reported: &ReportedRoomNames, infos: &[RoomInfo]) {
+    let mut reported = reported.write().await;
+    for info in infos {
+        if let (Ok(room_id), Some(name)) = (info.room_id.parse::<OwnedRoomId>(), &info.name) {
+            reported.insert(room_id, name.clone());
+        }
+    }
+}
+
+/// Watches the SDK's room info updates and emits `matrix://room-renamed`
+/// for rooms whose computed name changed, such as a DM whose other member
+/// picked a new display name. Replaces any earlier watcher.
+pub(crate) async fn watch_room_renames(state: &MatrixState, client: &Client) {
+    let app = state.app.clone();
+    let (room_cache, reported) = (state.room_cache.clone(), state.reported_room_names.clone());
+    let client = client.clone();
+    let mut updates = client.room_info_notable_update_receiver();
+
+    let task: JoinHandle<()> = tauri::async_runtime::spawn(async move {
+        loop {
+            let mut updated = BTreeSet::new();
+            let mut lagged = false;
+            // The first update opens the window; the rest of it is collected.
+            let mut deadline = None;
+            loop {
+                let next = match deadline {
+                    Some(deadline) => match tokio::time::timeout_at(deadline, updates.recv()).await {
+                        Ok(next) => next,
+                        Err(_) => break,
+                    },
+                    None => updates.recv().await,
+                };
+                match next {
+                    Ok(update) => {
+                        updated.insert(update.room_id);
+                    }
+                    Err(RecvError::Lagged(_)) => lagged = true,
+                    Err(RecvError::Closed) => return,
+                }
+                deadline.get_or_insert_with(|| Instant::now() + RENAME_DEBOUNCE);
+            }
+
+            if lagged {
+                warn!("Missed room info updates, checking every room name");
+                updated.extend(client.rooms().iter().map(|room| room.room_id().to_owned()));
+            }
+            emit_renames(&app, &client, &room_cache, &reported, updated).await;
+        }
+    });
+
+    if let Some(previous) = state.rename_watcher.write().await.replace(task) {
+        previous.abort();
+    }
+}
+
+/// Emits the rooms whose name differs from the one last reported, and keeps
+/// the cached `RoomInfo`s in step so the next `get_rooms` agrees. Rooms the
+/// room list hasn't been given yet are skipped.
+async fn emit_renames(
+    app: &AppHandle,
+    client: &Client,
+    room_cache: &Arc<RwLock<HashMap<OwnedRoomId, RoomInfo>>>,
+    reported: &ReportedRoomNames,
+    updated: BTreeSet<OwnedRoomId>,
+) {
+    let mut reported = reported.write().await;
+    let mut cache = room_cache.write().await;
+    let mut renamed = 0;
+    for room_id in updated {
+        let Some(name) = client
+            .get_room(&room_id)
+            .and_then(|room| room.cached_display_name())
+            .map(|name| name.to_string())
+        else {
+            continue;
+        };
+        match reported.get_mut(&room_id) {
+            Some(previous) if *previous != name => *previous = name.clone(),
+            _ => continue,
+        }
+
+        if let Some(info) = cache.get_mut(&room_id) {
+            info.name = Some(name.clone());
+        }
+        renamed += 1;
+        let _ = app.emit(
+            "matrix://room-renamed",
+            RoomRenamed {
+                room_id: room_id.to_string(),
+                name,
+            },
+        );
+    }
+    if renamed > 0 {
+        debug!("Renamed {} rooms", renamed);
+    }
+}
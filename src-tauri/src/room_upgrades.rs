@@ -0,0 +1,405 @@
+use matrix_sdk::deserialized_responses::{RawAnySyncOrStrippedState, TimelineEvent};
+use matrix_sdk::room::{Messages, MessagesOptions};
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::api::client::message::get_message_events;
+use matrix_sdk::ruma::api::client::room::upgrade_room;
+use matrix_sdk::ruma::api::client::state::get_state_event_for_key::{self, v3::StateEventFormat};
+use matrix_sdk::ruma::events::room::tombstone::SyncRoomTombstoneEvent;
+use matrix_sdk::ruma::events::StateEventType;
+use matrix_sdk::ruma::{OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, RoomOrAliasId, RoomVersionId};
+use matrix_sdk::{Client, Room, RoomState};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::{AppHandle, Emitter, State};
+use tracing::{info, warn};
+
+use crate::errors::CommandError;
+use crate::state::MatrixState;
+
+/// Upgrades `get_messages` follows back before giving up on older history.
+pub(crate) const MAX_HISTORY_HOPS: usize = 10;
+
+/// Payload of `matrix://room-replaced`: a room we are in was upgraded and we
+/// are now in its replacement.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomReplaced {
+    pub room_id: String,
+    pub replacement_room_id: String,
+}
+
+/// A room replaced by an upgrade whose history can't be read.
+pub(crate) struct InaccessiblePredecessor {
+    pub room_id: OwnedRoomId,
+    pub reason: String,
+}
+
+/// Pagination token of history continued into a room the requested one
+/// replaced. Serialized as JSON, which server tokens never are.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PredecessorToken {
+    pub room_id: OwnedRoomId,
+    pub from: Option<String>,
+}
+
+impl PredecessorToken {
+    pub(crate) fn new(room_id: &RoomId, from: String) -> Self {
+        Self {
+            room_id: room_id.to_owned(),
+            from: Some(from),
+        }
+    }
+
+    pub(crate) fn parse(token: &str) -> Option<Self> {
+        serde_json::from_str(token).ok()
+    }
+
+    pub(crate) fn encode(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Upgrades the room to `new_version` and returns the replacement room's ID.
+/// The old room is tombstoned, which `matrix://room-replaced` reports once
+/// sync delivers it.
+#[tauri::command]
+pub async fn upgrade_room(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    new_version: String,
+) -> Result<String, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+    let new_version = RoomVersionId::try_from(new_version.trim())
+        .map_err(|e| format!("Invalid room version: {}", e))?;
+
+    if room.is_tombstoned() {
+        return Err(CommandError::new("ROOM_TOMBSTONED", "This room has already been replaced").into());
+    }
+    if !can_upgrade(client, &room).await {
+        return Err(CommandError::new(
+            "INSUFFICIENT_POWER_LEVEL",
+            "You don't have permission to upgrade this room",
+        )
+        .into());
+    }
+
+    let response = client
+        .send(upgrade_room::v3::Request::new(room_id.clone(), new_version.clone()))
+        .await
+        .map_err(|e| -> String {
+            match e.client_api_error_kind() {
+                Some(ErrorKind::Forbidden { .. }) => CommandError::new(
+                    "INSUFFICIENT_POWER_LEVEL",
+                    "You don't have permission to upgrade this room",
+                )
+                .into(),
+                Some(ErrorKind::UnsupportedRoomVersion) => CommandError::new(
+                    "UNSUPPORTED_ROOM_VERSION",
+                    format!("The server does not support room version {}", new_version),
+                )
+                .into(),
+                _ => format!("Failed to upgrade room: {}", e),
+            }
+        })?;
+
+    info!("Upgraded {} to version {} as {}", room_id, new_version, response.replacement_room);
+
+    Ok(response.replacement_room.to_string())
+}
+
+/// Sync handler following tombstones: when a room we are in is replaced,
+/// joins the replacement (the upgrader is already in it) and emits
+/// `matrix://room-replaced` so the UI can switch over.
+pub(crate) async fn on_tombstone(event: SyncRoomTombstoneEvent, room: Room, client: Client, app: AppHandle) {
+    let SyncRoomTombstoneEvent::Original(event) = event else {
+        return;
+    };
+    if room.state() != RoomState::Joined {
+        return;
+    }
+    let replacement = event.content.replacement_room;
+
+    let joined = client
+        .get_room(&replacement)
+        .is_some_and(|room| room.state() == RoomState::Joined);
+    if !joined {
+        let via: Vec<OwnedServerName> = vec![event.sender.server_name().to_owned()];
+        let target: &RoomOrAliasId = (&*replacement).into();
+        if let Err(e) = client.join_room_by_id_or_alias(target, &via).await {
+            warn!("Failed to join {}, which replaces {}: {}", replacement, room.room_id(), e);
+            return;
+        }
+    }
+
+    info!("{} was replaced by {}", room.room_id(), replacement);
+
+    let _ = app.emit(
+        "matrix://room-replaced",
+        RoomReplaced {
+            room_id: room.room_id().to_string(),
+            replacement_room_id: replacement.to_string(),
+        },
+    );
+}
+
+async fn can_upgrade(client: &Client, room: &Room) -> bool {
+    let Some(user_id) = client.user_id() else {
+        return false;
+    };
+    room.power_levels()
+        .await
+        .is_ok_and(|levels| levels.user_can_send_state(user_id, StateEventType::RoomTombstone))
+}
+
+/// A room replaced by an upgrade whose history can be read.
+pub(crate) enum Predecessor {
+    /// One we are or were in.
+    Known(Room),
+    /// A world-readable one we never joined, read without joining it.
+    Peeked(OwnedRoomId),
+}
+
+impl Predecessor {
+    pub(crate) fn room_id(&self) -> &RoomId {
+        match self {
+            Self::Known(room) => room.room_id(),
+            Self::Peeked(room_id) => room_id,
+        }
+    }
+
+    /// A page of the room's history. Events of a peeked room are taken as
+    /// they come, as one we aren't in can't have sent us its keys.
+    pub(crate) async fn messages(&self, client: &Client, options: MessagesOptions) -> Result<Messages, matrix_sdk::Error> {
+        let room_id = match self {
+            Self::Known(room) => return room.messages(options).await,
+            Self::Peeked(room_id) => room_id,
+        };
+        let mut request = get_message_events::v3::Request::new(room_id.clone(), options.dir);
+        request.from = options.from;
+        request.to = options.to;
+        request.limit = options.limit;
+        request.filter = options.filter;
+        let response = client.send(request).await?;
+        Ok(Messages {
+            start: response.start,
+            end: response.end,
+            chunk: response
+                .chunk
+                .into_iter()
+                .map(|event| TimelineEvent::from_plaintext(event.cast_unchecked()))
+                .collect(),
+            state: response.state,
+        })
+    }
+
+    /// The room's `m.room.create` event.
+    pub(crate) async fn create_event(&self, client: &Client) -> Option<Value> {
+        match self {
+            Self::Known(room) => room_create_event(room).await,
+            Self::Peeked(room_id) => peek_state(client, room_id, StateEventType::RoomCreate).await,
+        }
+    }
+
+    /// The room this one replaced, and the servers to join it through: its
+    /// own and those of this room's creators.
+    async fn predecessor(&self, client: &Client) -> Option<(OwnedRoomId, Vec<OwnedServerName>)> {
+        let (room_id, creators) = match self {
+            Self::Known(room) => (room.predecessor_room()?.room_id, room.creators().unwrap_or_default()),
+            Self::Peeked(_) => predecessor_in_create(&self.create_event(client).await?)?,
+        };
+        let mut via: Vec<OwnedServerName> = room_id.server_name().map(ToOwned::to_owned).into_iter().collect();
+        for creator in creators {
+            if !via.iter().any(|server| server == creator.server_name()) {
+                via.push(creator.server_name().to_owned());
+            }
+        }
+        Some((room_id, via))
+    }
+}
+
+/// The `m.room.create` event of a room we know, from the store.
+pub(crate) async fn room_create_event(room: &Room) -> Option<Value> {
+    match room.get_state_event(StateEventType::RoomCreate, "").await {
+        Ok(Some(RawAnySyncOrStrippedState::Sync(raw))) => raw.deserialize_as_unchecked().ok(),
+        Ok(Some(RawAnySyncOrStrippedState::Stripped(raw))) => raw.deserialize_as_unchecked().ok(),
+        _ => None,
+    }
+}
+
+/// A state event of a room we aren't in, which the server only hands out
+/// for world-readable rooms.
+async fn peek_state(client: &Client, room_id: &RoomId, event_type: StateEventType) -> Option<Value> {
+    let mut request = get_state_event_for_key::v3::Request::new(room_id.to_owned(), event_type, String::new());
+    request.format = StateEventFormat::Event;
+    let response = client.send(request).await.ok()?;
+    serde_json::from_str(response.event_or_content.get()).ok()
+}
+
+/// The replaced room named by a create event, and the room's creators.
+fn predecessor_in_create(create: &Value) -> Option<(OwnedRoomId, Vec<OwnedUserId>)> {
+    let room_id = create.pointer("/content/predecessor/room_id")?.as_str()?.parse().ok()?;
+    let additional = create
+        .pointer("/content/additional_creators")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten();
+    let creators = create
+        .get("sender")
+        .into_iter()
+        .chain(additional)
+        .filter_map(|user_id| user_id.as_str()?.parse().ok())
+        .collect();
+    Some((room_id, creators))
+}
+
+/// How the history of a replaced room can be read.
+#[derive(Debug, PartialEq)]
+enum PredecessorAccess {
+    /// Through the room we are or were in.
+    Known,
+    /// Without joining, as it is world-readable.
+    Peek,
+    Join,
+    Unavailable(&'static str),
+}
+
+/// `state` is ours in the replaced room, if we know it. Joining is left to
+/// the caller asking for it, as it puts us in a room the user may never
+/// have been in.
+fn predecessor_access(state: Option<RoomState>, world_readable: bool, join: bool) -> PredecessorAccess {
+    match state {
+        Some(RoomState::Joined | RoomState::Left) => PredecessorAccess::Known,
+        Some(RoomState::Banned) => PredecessorAccess::Unavailable("You are banned from the previous room"),
+        _ if world_readable => PredecessorAccess::Peek,
+        _ if join => PredecessorAccess::Join,
+        _ => PredecessorAccess::Unavailable("You aren't in the previous room; join it to read its history"),
+    }
+}
+
+/// The room `room` replaced, for paging on into the history from before the
+/// upgrade. Rooms we left are read as far as the server allows, and
+/// world-readable ones without joining them. Others are only joined with
+/// `join`, through the servers of their ID and of the upgrade's creators;
+/// we only read from them.
+pub(crate) async fn history_predecessor(
+    client: &Client,
+    room: &Predecessor,
+    join: bool,
+) -> Result<Option<Predecessor>, InaccessiblePredecessor> {
+    let Some((room_id, via)) = room.predecessor(client).await else {
+        return Ok(None);
+    };
+    let inaccessible = |reason: String| InaccessiblePredecessor {
+        room_id: room_id.clone(),
+        reason,
+    };
+
+    let known = client.get_room(&room_id);
+    let state = known.as_ref().map(|room| room.state());
+    let world_readable = match state {
+        Some(RoomState::Joined | RoomState::Left | RoomState::Banned) => false,
+        _ => peek_state(client, &room_id, StateEventType::RoomHistoryVisibility)
+            .await
+            .is_some_and(|event| event.pointer("/content/history_visibility") == Some(&Value::from("world_readable"))),
+    };
+
+    match predecessor_access(state, world_readable, join) {
+        PredecessorAccess::Known => Ok(known.map(Predecessor::Known)),
+        PredecessorAccess::Peek => Ok(Some(Predecessor::Peeked(room_id))),
+        PredecessorAccess::Unavailable(reason) => Err(inaccessible(reason.to_string())),
+        PredecessorAccess::Join => {
+            let target: &RoomOrAliasId = (&*room_id).into();
+            match client.join_room_by_id_or_alias(target, &via).await {
+                Ok(joined) => {
+                    info!("Joined {} to read the history before {}", room_id, room.room_id());
+                    Ok(Some(Predecessor::Known(joined)))
+                }
+                Err(e) => Err(inaccessible(match e.client_api_error_kind() {
+                    Some(ErrorKind::Forbidden { .. }) => "The previous room isn't open to you".to_string(),
+                    _ => format!("Failed to join the previous room: {}", e),
+                })),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn create(predecessor: Option<&str>) -> Value {
+        let mut content = json!({ "room_version": "11" });
+        if let Some(predecessor) = predecessor {
+            content["predecessor"] = json!({ "room_id": predecessor });
+        }
+        json!({ "type": "m.room.create", "sender": "@alice:example.org", "content": content })
+    }
+
+    #[test]
+    fn create_names_the_predecessor_and_creators() {
+        let mut event = create(Some("!old:example.org"));
+        event["content"]["additional_creators"] = json!(["@bob:other.org", "not a user"]);
+        let (room_id, creators) = predecessor_in_create(&event).unwrap();
+        assert_eq!(room_id, "!old:example.org");
+        assert_eq!(creators, ["@alice:example.org", "@bob:other.org"]);
+        assert!(predecessor_in_create(&create(None)).is_none());
+    }
+
+    #[test]
+    fn history_follows_a_chain_of_upgrades() {
+        // !c replaced !b, which replaced !a: one we left, one world-readable
+        // one we never joined, and the original.
+        let creates = HashMap::from([
+            ("!c:example.org", create(Some("!b:example.org"))),
+            ("!b:example.org", create(Some("!a:example.org"))),
+            ("!a:example.org", create(None)),
+        ]);
+        let access = HashMap::from([
+            ("!b:example.org", (Some(RoomState::Left), false)),
+            ("!a:example.org", (None, true)),
+        ]);
+
+        let mut chain = Vec::new();
+        let mut room_id = "!c:example.org".to_string();
+        for _ in 0..MAX_HISTORY_HOPS {
+            let Some((predecessor, _)) = predecessor_in_create(&creates[room_id.as_str()]) else {
+                break;
+            };
+            let (state, world_readable) = access[predecessor.as_str()];
+            chain.push((predecessor.to_string(), predecessor_access(state, world_readable, false)));
+            room_id = predecessor.to_string();
+        }
+        assert_eq!(
+            chain,
+            [
+                ("!b:example.org".to_string(), PredecessorAccess::Known),
+                ("!a:example.org".to_string(), PredecessorAccess::Peek),
+            ]
+        );
+    }
+
+    #[test]
+    fn inaccessible_predecessor_is_only_joined_when_asked() {
+        assert!(matches!(predecessor_access(None, false, false), PredecessorAccess::Unavailable(_)));
+        assert!(matches!(
+            predecessor_access(Some(RoomState::Invited), false, false),
+            PredecessorAccess::Unavailable(_)
+        ));
+        assert_eq!(predecessor_access(None, false, true), PredecessorAccess::Join);
+        assert!(matches!(
+            predecessor_access(Some(RoomState::Banned), false, true),
+            PredecessorAccess::Unavailable(_)
+        ));
+        // World-readable history is read without joining, asked or not.
+        assert_eq!(predecessor_access(None, true, true), PredecessorAccess::Peek);
+    }
+}
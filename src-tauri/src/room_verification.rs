@@ -0,0 +1,802 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use matrix_sdk::ruma::api::client::redact::redact_event;
+use matrix_sdk::ruma::canonical_json::to_canonical_value;
+use matrix_sdk::ruma::events::key::verification::VerificationMethod;
+use matrix_sdk::ruma::events::room::member::MembershipState;
+use matrix_sdk::ruma::events::room::message::{
+    KeyVerificationRequestEventContent, MessageType, RoomMessageEventContent,
+};
+use matrix_sdk::ruma::events::AnySyncTimelineEvent;
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{
+    DeviceId, EventId, OwnedDeviceId, OwnedEventId, OwnedRoomId, OwnedUserId, TransactionId, UserId,
+};
+use matrix_sdk::{Client, Room, RoomState};
+use matrix_sdk_crypto::vodozemac::sas::{EstablishedSas, Mac, Sas};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use tauri::{AppHandle, Emitter, Manager, State};
+use tokio::sync::{mpsc, RwLock};
+use tracing::{info, warn};
+
+use crate::outbound::{paced, OutboundClass};
+use crate::sas_emoji::emoji_at;
+use crate::state::MatrixState;
+
+/// How long an in-room request stays valid, per the spec.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+const SAS_METHOD: &str = "m.sas.v1";
+const KEY_AGREEMENT_PROTOCOL: &str = "curve25519-hkdf-sha256";
+const HASH: &str = "sha256";
+const MAC_METHOD: &str = "hkdf-hmac-sha256.v2";
+
+pub type UserVerificationSlot = Arc<RwLock<Option<UserVerification>>>;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UserVerificationPhase {
+    /// Our request is in the room, waiting for them to accept it.
+    Requested,
+    Ready,
+    /// Keys are being exchanged.
+    Started,
+    /// The emoji are ready to be compared.
+    Comparing,
+    /// We confirmed the emoji match and wait for the other side.
+    Confirmed,
+    Done,
+    Cancelled,
+}
+
+/// Where a verification of another user stands, as returned by the commands
+/// and emitted as `matrix://user-verification`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserVerificationStatus {
+    /// The ID of the request event, which every later step relates to.
+    pub flow_id: String,
+    pub room_id: String,
+    pub user_id: String,
+    pub phase: UserVerificationPhase,
+    /// `(symbol, localized_description, english_description)` once the
+    /// phase is `comparing`.
+    pub emoji: Option<Vec<(String, String, String)>>,
+    pub cancel_code: Option<String>,
+    pub cancel_reason: Option<String>,
+}
+
+/// Who sent the `m.key.verification.start` the flow goes on with.
+enum Starter {
+    /// Us, with the content as sent, which their commitment hashes.
+    Us(Value),
+    Them,
+}
+
+/// A verification of another user run in a room timeline.
+///
+/// The SDK only sends in-room requests to a DM with the user and can't be
+/// pointed at another room, so the SAS exchange is done here on top of
+/// vodozemac, with the room events sent and received as raw JSON.
+pub struct UserVerification {
+    flow_id: OwnedEventId,
+    room_id: OwnedRoomId,
+    user_id: OwnedUserId,
+    started_at: Instant,
+    phase: UserVerificationPhase,
+    their_device: Option<OwnedDeviceId>,
+    sas: Option<Sas>,
+    starter: Option<Starter>,
+    /// The commitment from their `accept`, checked against their key.
+    commitment: Option<String>,
+    our_key: Option<String>,
+    established: Option<EstablishedSas>,
+    emoji: Option<[u8; 7]>,
+    /// Their `mac` content, kept until we confirmed the emoji ourselves.
+    their_mac: Option<Value>,
+    cancel: Option<(String, String)>,
+}
+
+/// Why a flow is cancelled: an `m.key.verification.cancel` code and reason.
+type Cancel = (&'static str, String);
+
+impl UserVerification {
+    fn is_active(&self) -> bool {
+        !matches!(self.phase, UserVerificationPhase::Done | UserVerificationPhase::Cancelled)
+    }
+
+    fn status(&self, language: Option<&str>) -> UserVerificationStatus {
+        UserVerificationStatus {
+            flow_id: self.flow_id.to_string(),
+            room_id: self.room_id.to_string(),
+            user_id: self.user_id.to_string(),
+            phase: self.phase,
+            emoji: self
+                .emoji
+                .filter(|_| self.phase == UserVerificationPhase::Comparing)
+                .map(|indices| indices.iter().map(|index| emoji_at(*index, language)).collect()),
+            cancel_code: self.cancel.as_ref().map(|(code, _)| code.clone()),
+            cancel_reason: self.cancel.as_ref().map(|(_, reason)| reason.clone()),
+        }
+    }
+
+    /// Sends a step of the flow as a room event related to the request.
+    async fn send(&self, room: &Room, step: &str, mut content: Value) -> Result<(), Cancel> {
+        content["m.relates_to"] = json!({ "rel_type": "m.reference", "event_id": self.flow_id });
+        room.send_raw(&format!("m.key.verification.{}", step), content)
+            .await
+            .map(|_| ())
+            .map_err(|e| ("m.user", format!("Failed to send verification {}: {}", step, e)))
+    }
+
+    async fn on_ready(&mut self, client: &Client, room: &Room, content: &Value) -> Result<(), Cancel> {
+        let from_device = device_field(content, "from_device")?;
+        if self.phase != UserVerificationPhase::Requested {
+            // Another of their devices answered too late; the flow goes on
+            // with the first one.
+            if self.their_device.as_ref() != Some(&from_device) {
+                info!("Ignoring ready from {} of {}, another device answered", from_device, self.user_id);
+                return Ok(());
+            }
+            return Err(("m.unexpected_message", "Unexpected ready".to_string()));
+        }
+        if !str_list(content, "methods").contains(&SAS_METHOD) {
+            return Err(("m.unknown_method", "Emoji verification is not supported".to_string()));
+        }
+        self.their_device = Some(from_device);
+        self.phase = UserVerificationPhase::Ready;
+
+        let device_id = client.device_id().ok_or(("m.user", "No device ID".to_string()))?;
+        let sas = Sas::new();
+        let mut start = json!({
+            "from_device": device_id,
+            "method": SAS_METHOD,
+            "key_agreement_protocols": [KEY_AGREEMENT_PROTOCOL],
+            "hashes": [HASH],
+            "message_authentication_codes": [MAC_METHOD],
+            "short_authentication_string": ["decimal", "emoji"],
+        });
+        self.send(room, "start", start.clone()).await?;
+        start["m.relates_to"] = json!({ "rel_type": "m.reference", "event_id": self.flow_id });
+        self.our_key = Some(sas.public_key().to_base64());
+        self.sas = Some(sas);
+        self.starter = Some(Starter::Us(start));
+        self.phase = UserVerificationPhase::Started;
+        Ok(())
+    }
+
+    async fn on_start(&mut self, client: &Client, room: &Room, content: &Value) -> Result<(), Cancel> {
+        let from_device = device_field(content, "from_device")?;
+        if self.their_device.as_ref().is_some_and(|device| *device != from_device) {
+            info!("Ignoring start from {} of {}, not the device that answered", from_device, self.user_id);
+            return Ok(());
+        }
+        match (&self.starter, self.commitment.is_some()) {
+            (None, _) => {}
+            // Both sides started at once: the start of the lower user ID wins.
+            (Some(Starter::Us(_)), false) => {
+                let own_user = client.user_id().ok_or(("m.user", "No user ID".to_string()))?;
+                if own_user < &*self.user_id {
+                    return Ok(());
+                }
+            }
+            _ => return Err(("m.unexpected_message", "Unexpected start".to_string())),
+        }
+        if content.get("method").and_then(Value::as_str) != Some(SAS_METHOD)
+            || !str_list(content, "key_agreement_protocols").contains(&KEY_AGREEMENT_PROTOCOL)
+            || !str_list(content, "hashes").contains(&HASH)
+            || !str_list(content, "message_authentication_codes").contains(&MAC_METHOD)
+            || !str_list(content, "short_authentication_string").contains(&"emoji")
+        {
+            return Err(("m.unknown_method", "Emoji verification is not supported".to_string()));
+        }
+        self.their_device = Some(from_device);
+
+        let sas = Sas::new();
+        let our_key = sas.public_key().to_base64();
+        let accept = json!({
+            "method": SAS_METHOD,
+            "key_agreement_protocol": KEY_AGREEMENT_PROTOCOL,
+            "hash": HASH,
+            "message_authentication_code": MAC_METHOD,
+            "short_authentication_string": ["emoji"],
+            "commitment": commitment(&our_key, content)?,
+        });
+        self.send(room, "accept", accept).await?;
+        self.our_key = Some(our_key);
+        self.sas = Some(sas);
+        self.starter = Some(Starter::Them);
+        self.phase = UserVerificationPhase::Started;
+        Ok(())
+    }
+
+    async fn on_accept(&mut self, room: &Room, content: &Value) -> Result<(), Cancel> {
+        if !matches!(self.starter, Some(Starter::Us(_))) || self.commitment.is_some() {
+            return Err(("m.unexpected_message", "Unexpected accept".to_string()));
+        }
+        if content.get("key_agreement_protocol").and_then(Value::as_str) != Some(KEY_AGREEMENT_PROTOCOL)
+            || content.get("hash").and_then(Value::as_str) != Some(HASH)
+            || content.get("message_authentication_code").and_then(Value::as_str) != Some(MAC_METHOD)
+            || !str_list(content, "short_authentication_string").contains(&"emoji")
+        {
+            return Err(("m.unknown_method", "Emoji verification is not supported".to_string()));
+        }
+        let commitment = content.get("commitment").and_then(Value::as_str);
+        self.commitment = Some(commitment.ok_or(invalid("accept"))?.to_string());
+        let our_key = self.our_key.clone().unwrap_or_default();
+        self.send(room, "key", json!({ "key": our_key })).await
+    }
+
+    async fn on_key(&mut self, client: &Client, room: &Room, content: &Value) -> Result<(), Cancel> {
+        let their_key = content.get("key").and_then(Value::as_str).ok_or(invalid("key"))?;
+        let our_key = self.our_key.clone().unwrap_or_default();
+        let we_started = match &self.starter {
+            Some(Starter::Us(start)) => {
+                let expected = self.commitment.as_deref().ok_or(("m.unexpected_message", "Key before accept".to_string()))?;
+                if commitment(their_key, start)? != expected {
+                    return Err(("m.mismatched_commitment", "The key doesn't match the commitment".to_string()));
+                }
+                true
+            }
+            Some(Starter::Them) => {
+                self.send(room, "key", json!({ "key": our_key })).await?;
+                false
+            }
+            None => return Err(("m.unexpected_message", "Key before start".to_string())),
+        };
+        let sas = self.sas.take().ok_or(("m.unexpected_message", "Unexpected key".to_string()))?;
+        let established = sas
+            .diffie_hellman_with_raw(their_key)
+            .map_err(|e| ("m.key_mismatch", format!("Invalid key: {}", e)))?;
+
+        let ours = self.our_party(client, &our_key)?;
+        let theirs = self.their_party(their_key)?;
+        let (starter, acceptor) = if we_started { (ours, theirs) } else { (theirs, ours) };
+        self.emoji = Some(established.bytes(&sas_info(&starter, &acceptor, self.flow_id.as_str())).emoji_indices());
+        self.established = Some(established);
+        self.phase = UserVerificationPhase::Comparing;
+        Ok(())
+    }
+
+    fn our_party(&self, client: &Client, key: &str) -> Result<String, Cancel> {
+        let user_id = client.user_id().ok_or(("m.user", "No user ID".to_string()))?;
+        let device_id = client.device_id().ok_or(("m.user", "No device ID".to_string()))?;
+        Ok(format!("{}|{}|{}", user_id, device_id, key))
+    }
+
+    fn their_party(&self, key: &str) -> Result<String, Cancel> {
+        let device_id = self.their_device.as_ref().ok_or(invalid("start"))?;
+        Ok(format!("{}|{}|{}", self.user_id, device_id, key))
+    }
+
+    /// Sends our MAC once the user confirmed the emoji match.
+    async fn confirm(&mut self, client: &Client, room: &Room) -> Result<(), Cancel> {
+        let established = self.established.as_ref().ok_or(("m.user", "Nothing to confirm".to_string()))?;
+        let user_id = client.user_id().ok_or(("m.user", "No user ID".to_string()))?;
+        let device_id = client.device_id().ok_or(("m.user", "No device ID".to_string()))?;
+        let their_device = self.their_device.as_ref().ok_or(invalid("start"))?;
+        let base_info = mac_base_info((user_id, device_id), (&self.user_id, their_device), &self.flow_id);
+
+        let encryption = client.encryption();
+        let mut keys = Vec::new();
+        if let Some(key) = encryption.ed25519_key().await {
+            keys.push((format!("ed25519:{}", device_id), key));
+        }
+        if let Ok(Some(identity)) = encryption.get_user_identity(user_id).await {
+            if let Some(key) = identity.master_key().get_first_key() {
+                keys.push((format!("ed25519:{}", key.to_base64()), key.to_base64()));
+            }
+        }
+        self.send(room, "mac", mac_content(established, &base_info, keys)).await?;
+        self.phase = UserVerificationPhase::Confirmed;
+
+        match self.their_mac.take() {
+            Some(their_mac) => self.check_mac(client, room, &their_mac).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn on_mac(&mut self, client: &Client, room: &Room, content: &Value) -> Result<(), Cancel> {
+        match self.phase {
+            UserVerificationPhase::Comparing => {
+                self.their_mac = Some(content.clone());
+                Ok(())
+            }
+            UserVerificationPhase::Confirmed => self.check_mac(client, room, content).await,
+            _ => Err(("m.unexpected_message", "Unexpected mac".to_string())),
+        }
+    }
+
+    /// Checks their MACs over their device and master keys, then marks
+    /// their identity verified.
+    async fn check_mac(&mut self, client: &Client, room: &Room, content: &Value) -> Result<(), Cancel> {
+        let established = self.established.as_ref().ok_or(("m.unexpected_message", "Unexpected mac".to_string()))?;
+        let user_id = client.user_id().ok_or(("m.user", "No user ID".to_string()))?;
+        let device_id = client.device_id().ok_or(("m.user", "No device ID".to_string()))?;
+        let their_device = self.their_device.clone().ok_or(invalid("start"))?;
+        let base_info = mac_base_info((&self.user_id, &their_device), (user_id, device_id), &self.flow_id);
+
+        let encryption = client.encryption();
+        let identity = encryption
+            .get_user_identity(&self.user_id)
+            .await
+            .ok()
+            .flatten()
+            .ok_or(("m.key_mismatch", "They have no cross-signing identity".to_string()))?;
+        let master_key = identity.master_key().get_first_key().map(|key| key.to_base64());
+        let device_key = match encryption.get_device(&self.user_id, &their_device).await {
+            Ok(Some(device)) => device.ed25519_key().map(|key| key.to_base64()),
+            _ => None,
+        };
+
+        let device_key_id = format!("ed25519:{}", their_device);
+        let master_key_id = master_key.as_ref().map(|key| format!("ed25519:{}", key));
+        let verified = verify_macs(established, &base_info, content, |key_id| {
+            if key_id == device_key_id {
+                // A MAC over a device key we don't have can't be checked.
+                return Some(device_key.clone().ok_or_else(mismatch));
+            }
+            (Some(key_id) == master_key_id.as_deref()).then(|| Ok(master_key.clone().unwrap_or_default()))
+        })?;
+        if !master_key_id.is_some_and(|key_id| verified.contains(&key_id)) {
+            return Err(("m.key_mismatch", "Their identity wasn't part of the verification".to_string()));
+        }
+
+        identity
+            .verify()
+            .await
+            .map_err(|e| ("m.user", format!("Failed to sign their identity: {}", e)))?;
+        self.send(room, "done", json!({})).await?;
+        self.phase = UserVerificationPhase::Done;
+        info!("Verified {} in {}", self.user_id, self.room_id);
+        Ok(())
+    }
+
+    /// Cancels the flow and redacts the request, so it no longer shows as
+    /// pending in other clients. `code` is `None` when they cancelled. The
+    /// redaction goes out in the background, as the pacer may hold it back.
+    async fn abort(&mut self, state: &MatrixState, room: &Room, code: Option<&str>, reason: String) {
+        if let Some(code) = code {
+            if let Err((_, e)) = self.send(room, "cancel", json!({ "code": code, "reason": reason })).await {
+                warn!("{}", e);
+            }
+            self.cancel = Some((code.to_string(), reason));
+        }
+        self.phase = UserVerificationPhase::Cancelled;
+
+        let (app, room, flow_id) = (state.app.clone(), room.clone(), self.flow_id.clone());
+        tauri::async_runtime::spawn(async move {
+            let description = format!("Remove the verification request in {}", room.room_id());
+            let mut request = redact_event::v3::Request::new(room.room_id().to_owned(), flow_id.clone(), TransactionId::new());
+            request.reason = Some("Verification cancelled".to_string());
+            let redaction = |config| room.client().send(request.clone()).with_request_config(config);
+            if let Err(e) = paced(&app.state::<MatrixState>(), OutboundClass::Moderation, description, redaction).await {
+                warn!("Failed to redact verification request {}: {}", flow_id, e);
+            }
+        });
+    }
+}
+
+fn str_list<'a>(content: &'a Value, field: &str) -> Vec<&'a str> {
+    content
+        .get(field)
+        .and_then(Value::as_array)
+        .map(|values| values.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default()
+}
+
+fn device_field(content: &Value, field: &str) -> Result<OwnedDeviceId, Cancel> {
+    content
+        .get(field)
+        .and_then(Value::as_str)
+        .map(OwnedDeviceId::from)
+        .ok_or(invalid(field))
+}
+
+fn invalid(what: &str) -> Cancel {
+    ("m.invalid_message", format!("Malformed verification {}", what))
+}
+
+/// The info the SAS bytes are derived with. Each party is
+/// `user_id|device_id|public_key`, the one whose `start` was used first.
+fn sas_info(starter: &str, acceptor: &str, flow_id: &str) -> String {
+    format!("MATRIX_KEY_VERIFICATION_SAS|{}|{}|{}", starter, acceptor, flow_id)
+}
+
+/// The info prefix of the MACs `sender` sends `receiver`, each as
+/// `(user_id, device_id)`. The key ID is appended for each key, `KEY_IDS`
+/// for the list of them.
+fn mac_base_info(sender: (&UserId, &DeviceId), receiver: (&UserId, &DeviceId), flow_id: &EventId) -> String {
+    format!(
+        "MATRIX_KEY_VERIFICATION_MAC{}{}{}{}{}",
+        sender.0, sender.1, receiver.0, receiver.1, flow_id
+    )
+}
+
+/// The `mac` step content over `keys`, `(key_id, public_key)` pairs.
+fn mac_content(established: &EstablishedSas, base_info: &str, mut keys: Vec<(String, String)>) -> Value {
+    keys.sort();
+    let mut mac = Map::new();
+    for (key_id, key) in &keys {
+        let tag = established.calculate_mac(key, &format!("{}{}", base_info, key_id));
+        mac.insert(key_id.clone(), Value::String(tag.to_base64()));
+    }
+    let key_ids = keys.iter().map(|(key_id, _)| key_id.as_str()).collect::<Vec<_>>().join(",");
+    let key_ids_mac = established.calculate_mac(&key_ids, &format!("{}KEY_IDS", base_info));
+    json!({ "mac": mac, "keys": key_ids_mac.to_base64() })
+}
+
+/// Checks the MACs of a `mac` step: the one over the key ID list, then
+/// each key `key_for` knows. It returns `None` for key IDs to skip. Returns
+/// the key IDs that were checked.
+fn verify_macs(
+    established: &EstablishedSas,
+    base_info: &str,
+    content: &Value,
+    key_for: impl Fn(&str) -> Option<Result<String, Cancel>>,
+) -> Result<Vec<String>, Cancel> {
+    let macs = content.get("mac").and_then(Value::as_object).ok_or(invalid("mac"))?;
+    let keys_mac = content.get("keys").and_then(Value::as_str).ok_or(invalid("mac"))?;
+    let check = |input: &str, info: &str, tag: &str| {
+        let tag = Mac::from_base64(tag).map_err(|_| invalid("mac"))?;
+        established.verify_mac(input, info, &tag).map_err(|_| mismatch())
+    };
+
+    let mut key_ids: Vec<&str> = macs.keys().map(String::as_str).collect();
+    key_ids.sort();
+    check(&key_ids.join(","), &format!("{}KEY_IDS", base_info), keys_mac)?;
+
+    let mut verified = Vec::new();
+    for (key_id, tag) in macs {
+        let tag = tag.as_str().ok_or(invalid("mac"))?;
+        if let Some(key) = key_for(key_id).transpose()? {
+            check(&key, &format!("{}{}", base_info, key_id), tag)?;
+            verified.push(key_id.clone());
+        }
+    }
+    Ok(verified)
+}
+
+fn mismatch() -> Cancel {
+    ("m.key_mismatch", "The keys don't match".to_string())
+}
+
+/// The hash an acceptor commits to: their key followed by the canonical
+/// JSON of the start content, as unpadded base64 SHA-256.
+fn commitment(key: &str, start: &Value) -> Result<String, Cancel> {
+    let canonical = to_canonical_value(start).map_err(|_| invalid("start"))?;
+    Ok(STANDARD_NO_PAD.encode(Sha256::digest(format!("{}{}", key, canonical))))
+}
+
+fn emit_status(app: &AppHandle, flow: &UserVerification) {
+    let _ = app.emit("matrix://user-verification", flow.status(None));
+}
+
+/// Asks another user to verify each other by comparing emoji, in `room_id`
+/// or, when none is given, in the DM with them. They must be in the room.
+#[tauri::command]
+pub async fn request_user_verification(
+    state: State<'_, MatrixState>,
+    user_id: String,
+    room_id: Option<String>,
+) -> Result<UserVerificationStatus, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+    let user_id = OwnedUserId::try_from(user_id).map_err(|e| format!("Invalid user ID: {}", e))?;
+    let device_id = client.device_id().ok_or("No device ID")?.to_owned();
+
+    if state.user_verification.read().await.as_ref().is_some_and(UserVerification::is_active) {
+        return Err("Another user verification is in progress".to_string());
+    }
+
+    let room = match room_id {
+        Some(room_id) => {
+            let room_id = OwnedRoomId::try_from(room_id).map_err(|e| format!("Invalid room ID: {}", e))?;
+            client.get_room(&room_id).ok_or("Room not found")?
+        }
+        None => client.get_dm_room(&user_id).ok_or("No direct message with this user; pick a room to verify in")?,
+    };
+    if room.state() != RoomState::Joined {
+        return Err("Not joined to this room".to_string());
+    }
+    let joined = matches!(
+        room.get_member_no_sync(&user_id).await,
+        Ok(Some(member)) if *member.membership() == MembershipState::Join
+    );
+    if !joined {
+        return Err(format!("{} is not in this room", user_id));
+    }
+    client
+        .encryption()
+        .request_user_identity(&user_id)
+        .await
+        .map_err(|e| format!("Failed to get identity: {}", e))?
+        .ok_or_else(|| format!("{} has no cross-signing identity", user_id))?;
+
+    let own_user = client.user_id().ok_or("No user ID")?;
+    let body = format!(
+        "{} is requesting to verify your key, but your client does not support in-chat key verification. \
+         You will need to use legacy key verification to verify keys.",
+        own_user
+    );
+    let request = KeyVerificationRequestEventContent::new(body, vec![VerificationMethod::SasV1], device_id, user_id.clone());
+    let content = RoomMessageEventContent::new(MessageType::VerificationRequest(request));
+    let description = format!("Verification request to {}", user_id);
+    let response = paced(&state, OutboundClass::Messages, description, |config| {
+        room.send(content.clone()).with_request_config(config)
+    })
+    .await
+    .map_err(|e| format!("Failed to send verification request: {}", e))?;
+
+    info!("Requested verification of {} in {}", user_id, room.room_id());
+    let flow = UserVerification {
+        flow_id: response.event_id,
+        room_id: room.room_id().to_owned(),
+        user_id,
+        started_at: Instant::now(),
+        phase: UserVerificationPhase::Requested,
+        their_device: None,
+        sas: None,
+        starter: None,
+        commitment: None,
+        our_key: None,
+        established: None,
+        emoji: None,
+        their_mac: None,
+        cancel: None,
+    };
+    let status = flow.status(None);
+    *state.user_verification.write().await = Some(flow);
+    Ok(status)
+}
+
+/// The current or last user verification, with the emoji in `language`.
+#[tauri::command]
+pub async fn get_user_verification(
+    state: State<'_, MatrixState>,
+    language: Option<String>,
+) -> Result<Option<UserVerificationStatus>, String> {
+    let slot = state.user_verification.read().await;
+    Ok(slot.as_ref().map(|flow| flow.status(language.as_deref())))
+}
+
+/// Confirms the emoji match what the other user sees.
+#[tauri::command]
+pub async fn confirm_user_verification(state: State<'_, MatrixState>) -> Result<UserVerificationStatus, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+    let mut slot = state.user_verification.write().await;
+    let flow = slot.as_mut().ok_or("No user verification in progress")?;
+    if flow.phase != UserVerificationPhase::Comparing {
+        return Err("The emoji are not ready to be compared".to_string());
+    }
+    let room = client.get_room(&flow.room_id).ok_or("Room not found")?;
+    if let Err((code, reason)) = flow.confirm(&client, &room).await {
+        flow.abort(&state, &room, Some(code), reason.clone()).await;
+        emit_status(&state.app, flow);
+        return Err(reason);
+    }
+    emit_status(&state.app, flow);
+    Ok(flow.status(None))
+}
+
+/// Cancels the user verification; `mismatch` when the emoji didn't match.
+#[tauri::command]
+pub async fn cancel_user_verification(state: State<'_, MatrixState>, mismatch: Option<bool>) -> Result<(), String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+    let mut slot = state.user_verification.write().await;
+    let Some(flow) = slot.as_mut().filter(|flow| flow.is_active()) else {
+        return Ok(());
+    };
+    let room = client.get_room(&flow.room_id).ok_or("Room not found")?;
+    let (code, reason) = match mismatch.unwrap_or(false) {
+        true => ("m.mismatched_sas", "The emoji did not match"),
+        false => ("m.user", "The user cancelled the verification"),
+    };
+    flow.abort(&state, &room, Some(code), reason.to_string()).await;
+    emit_status(&state.app, flow);
+    Ok(())
+}
+
+/// Cancels the user verification once its request timed out. Called after
+/// each sync.
+pub(crate) async fn expire_stale_user_verification(state: &MatrixState, client: &Client) {
+    let mut slot = state.user_verification.write().await;
+    let Some(flow) = slot.as_mut().filter(|flow| flow.is_active() && flow.started_at.elapsed() > REQUEST_TIMEOUT) else {
+        return;
+    };
+    let Some(room) = client.get_room(&flow.room_id) else {
+        return;
+    };
+    info!("Verification of {} timed out", flow.user_id);
+    flow.abort(state, &room, Some("m.timeout"), "The verification request timed out".to_string()).await;
+    emit_status(&state.app, flow);
+}
+
+/// Follows the steps of the user verification as they arrive in its room.
+/// The steps are handled in order by a task of their own, so the events
+/// they send don't hold up sync.
+pub(crate) fn track_user_verification(client: &Client, app: AppHandle) {
+    let (steps, mut queue) = mpsc::unbounded_channel::<(Raw<AnySyncTimelineEvent>, Room, Client)>();
+    tauri::async_runtime::spawn(async move {
+        while let Some((event, room, client)) = queue.recv().await {
+            on_verification_event(&app, &event, &room, &client).await;
+        }
+    });
+
+    client.add_event_handler(move |event: Raw<AnySyncTimelineEvent>, room: Room, client: Client| {
+        let steps = steps.clone();
+        async move {
+            let is_step = event
+                .get_field::<String>("type")
+                .ok()
+                .flatten()
+                .is_some_and(|event_type| event_type.starts_with("m.key.verification."));
+            if is_step {
+                let _ = steps.send((event, room, client));
+            }
+        }
+    });
+}
+
+async fn on_verification_event(app: &AppHandle, event: &Raw<AnySyncTimelineEvent>, room: &Room, client: &Client) {
+    let Ok(Some(event_type)) = event.get_field::<String>("type") else {
+        return;
+    };
+    let Some(step) = event_type.strip_prefix("m.key.verification.") else {
+        return;
+    };
+    let (Ok(Some(sender)), Ok(Some(content))) = (event.get_field::<OwnedUserId>("sender"), event.get_field::<Value>("content"))
+    else {
+        return;
+    };
+    let Some(flow_id) = content.pointer("/m.relates_to/event_id").and_then(Value::as_str) else {
+        return;
+    };
+
+    let state = app.state::<MatrixState>();
+    let mut slot = state.user_verification.write().await;
+    let Some(flow) = slot.as_mut().filter(|flow| {
+        flow.is_active() && flow.room_id == room.room_id() && flow.flow_id == flow_id && flow.user_id == sender
+    }) else {
+        return;
+    };
+
+    let result = match step {
+        "ready" => flow.on_ready(client, room, &content).await,
+        "start" => flow.on_start(client, room, &content).await,
+        "accept" => flow.on_accept(room, &content).await,
+        "key" => flow.on_key(client, room, &content).await,
+        "mac" => flow.on_mac(client, room, &content).await,
+        "done" => Ok(()),
+        "cancel" => {
+            let code = content.get("code").and_then(Value::as_str).unwrap_or("m.user").to_string();
+            let reason = content.get("reason").and_then(Value::as_str).unwrap_or_default().to_string();
+            info!("{} cancelled the verification: {}", sender, code);
+            flow.cancel = Some((code, reason));
+            flow.abort(&state, room, None, String::new()).await;
+            Ok(())
+        }
+        _ => Ok(()),
+    };
+    if let Err((code, reason)) = result {
+        warn!("Cancelling verification of {}: {}", flow.user_id, reason);
+        flow.abort(&state, room, Some(code), reason).await;
+    }
+    emit_status(app, flow);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn established_pair() -> (EstablishedSas, String, EstablishedSas, String) {
+        let (alice, bob) = (Sas::new(), Sas::new());
+        let (alice_key, bob_key) = (alice.public_key(), bob.public_key());
+        let alice = alice.diffie_hellman(bob_key).unwrap();
+        let bob = bob.diffie_hellman(alice_key).unwrap();
+        (alice, alice_key.to_base64(), bob, bob_key.to_base64())
+    }
+
+    fn user(id: &str) -> OwnedUserId {
+        OwnedUserId::try_from(id).unwrap()
+    }
+
+    #[test]
+    fn commitment_hashes_key_and_canonical_start() {
+        // Field order doesn't matter: the start is hashed as canonical JSON.
+        let start = json!({
+            "short_authentication_string": ["decimal", "emoji"],
+            "method": "m.sas.v1",
+            "from_device": "BOBDEVICE",
+            "m.relates_to": { "rel_type": "m.reference", "event_id": "$request:example.org" },
+            "key_agreement_protocols": ["curve25519-hkdf-sha256"],
+            "hashes": ["sha256"],
+            "message_authentication_codes": ["hkdf-hmac-sha256.v2"],
+        });
+        // base64(sha256("AliceKey" + canonical JSON of the start)), unpadded.
+        assert_eq!(commitment("AliceKey", &start).unwrap(), "gsLlSy4gmbOasRj0wrHL90BcS8FWngm4RMa4YGGoB1w");
+        assert_ne!(commitment("BobKey", &start).unwrap(), "gsLlSy4gmbOasRj0wrHL90BcS8FWngm4RMa4YGGoB1w");
+    }
+
+    #[test]
+    fn sas_info_follows_the_spec() {
+        assert_eq!(
+            sas_info("@alice:example.org|ALICE|akey", "@bob:example.org|BOB|bkey", "$request:example.org"),
+            "MATRIX_KEY_VERIFICATION_SAS|@alice:example.org|ALICE|akey|@bob:example.org|BOB|bkey|$request:example.org"
+        );
+    }
+
+    #[test]
+    fn mac_info_names_sender_then_receiver() {
+        let flow_id = OwnedEventId::try_from("$request:example.org").unwrap();
+        let (alice, bob) = (user("@alice:example.org"), user("@bob:example.org"));
+        let (alice_device, bob_device) = (OwnedDeviceId::from("ALICE"), OwnedDeviceId::from("BOB"));
+        assert_eq!(
+            mac_base_info((&alice, &alice_device), (&bob, &bob_device), &flow_id),
+            "MATRIX_KEY_VERIFICATION_MAC@alice:example.orgALICE@bob:example.orgBOB$request:example.org"
+        );
+    }
+
+    #[test]
+    fn both_sides_see_the_same_emoji() {
+        let (alice, alice_key, bob, bob_key) = established_pair();
+        let starter = format!("@alice:example.org|ALICE|{}", alice_key);
+        let acceptor = format!("@bob:example.org|BOB|{}", bob_key);
+        let info = sas_info(&starter, &acceptor, "$request:example.org");
+        assert_eq!(alice.bytes(&info).emoji_indices(), bob.bytes(&info).emoji_indices());
+    }
+
+    #[test]
+    fn macs_verify_on_the_other_side() {
+        let (alice, _, bob, _) = established_pair();
+        let keys = vec![
+            ("ed25519:MASTERKEY".to_string(), "MASTERKEY".to_string()),
+            ("ed25519:ALICE".to_string(), "devicekey".to_string()),
+        ];
+        let content = mac_content(&alice, "info", keys.clone());
+        let key_for = |key_id: &str| keys.iter().find(|(id, _)| id == key_id).map(|(_, key)| Ok(key.clone()));
+
+        let mut verified = verify_macs(&bob, "info", &content, key_for).unwrap();
+        verified.sort();
+        assert_eq!(verified, vec!["ed25519:ALICE", "ed25519:MASTERKEY"]);
+
+        // Unknown key IDs are skipped, not trusted.
+        let verified = verify_macs(&bob, "info", &content, |key_id| {
+            (key_id == "ed25519:ALICE").then(|| Ok("devicekey".to_string()))
+        })
+        .unwrap();
+        assert_eq!(verified, vec!["ed25519:ALICE"]);
+    }
+
+    #[test]
+    fn tampered_macs_are_rejected() {
+        let (alice, _, bob, _) = established_pair();
+        let keys = vec![
+            ("ed25519:MASTERKEY".to_string(), "MASTERKEY".to_string()),
+            ("ed25519:ALICE".to_string(), "devicekey".to_string()),
+        ];
+        let content = mac_content(&alice, "info", keys.clone());
+        let key_for = |key_id: &str| keys.iter().find(|(id, _)| id == key_id).map(|(_, key)| Ok(key.clone()));
+        let code = |result: Result<Vec<String>, Cancel>| result.unwrap_err().0;
+
+        // Another key than the one MACed.
+        let other_key = |key_id: &str| key_for(key_id).map(|_| Ok("otherkey".to_string()));
+        assert_eq!(code(verify_macs(&bob, "info", &content, other_key)), "m.key_mismatch");
+
+        // MACs meant for the other direction.
+        assert_eq!(code(verify_macs(&bob, "other info", &content, key_for)), "m.key_mismatch");
+
+        // A key dropped from the list breaks the key ID MAC.
+        let mut dropped = content.clone();
+        dropped["mac"].as_object_mut().unwrap().remove("ed25519:ALICE");
+        assert_eq!(code(verify_macs(&bob, "info", &dropped, key_for)), "m.key_mismatch");
+
+        // A tag that isn't base64.
+        let mut garbled = content.clone();
+        garbled["mac"]["ed25519:ALICE"] = json!("not a mac!");
+        assert_eq!(code(verify_macs(&bob, "info", &garbled, key_for)), "m.invalid_message");
+    }
+}
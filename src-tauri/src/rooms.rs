@@ -1,22 +1,325 @@
-use matrix_sdk::ruma::OwnedRoomId;
-use matrix_sdk::room::MessagesOptions;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use futures::stream::{self, StreamExt};
+use matrix_sdk::deserialized_responses::TimelineEvent;
+use matrix_sdk::ruma::events::room::message::{
+    ImageMessageEventContent, MessageType, RoomMessageEventContent, VideoMessageEventContent,
+};
+use matrix_sdk::ruma::events::room::{EncryptedFile, MediaSource, ThumbnailInfo};
+use matrix_sdk::ruma::events::sticker::{StickerEventContent, StickerMediaSource};
+use matrix_sdk::ruma::events::{AnyStateEvent, MessageLikeEventType};
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::{
+    EventId, OwnedEventId, OwnedRoomId, OwnedRoomOrAliasId, OwnedTransactionId, RoomVersionId,
+    UInt, UserId,
+};
+use matrix_sdk::room::{Messages, MessagesOptions};
+use matrix_sdk::{Room, RoomState};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tauri::State;
+use tokio::sync::RwLock;
+use tracing::debug;
 
+use crate::auth::sanitize_user_id;
+use crate::autocomplete::note_sender;
+use crate::backfill::indexed_messages;
+use crate::emoji::{inline_emotes, InlineEmote};
+use crate::errors::CommandError;
+use crate::export::civil_from_days;
+use crate::formatting::{contains_spoiler, parse_formatted, parse_formatted_capped, plain_text, Segment};
+use crate::messages::{sent_plaintext, SentPlaintext};
+use crate::merged_dms::merged_messages;
+use crate::membership::{membership_entry, own_removal, RoomRemoval};
+use crate::polls::{annotate_polls, poll_content, PollAnswer, PollKind};
+use crate::prefetch::take_prefetched;
+use crate::profiles::ProfileResolver;
+use crate::push_rules::push_context;
+use crate::bridges::{annotate_bridges, bridge_for_sender, room_bridges, BridgeInfo};
+use crate::retention::{annotate_expiry, is_expired, room_retention, RoomRetention};
+use crate::room_renames::note_room_names;
+use crate::room_upgrades::{
+    history_predecessor, room_create_event, InaccessiblePredecessor, Predecessor, PredecessorToken, MAX_HISTORY_HOPS,
+};
 use crate::state::MatrixState;
+use crate::sync_gaps::{gap_in_page, GapInfo};
+use crate::via_servers::{through_each, via_failure_error, via_servers};
+
+/// How many room display names `get_rooms` computes at once.
+const ROOM_INFO_CONCURRENCY: usize = 16;
+/// Events loaded around the target by `get_event_context`, split between
+/// before and after by the server.
+const DEFAULT_CONTEXT_SIZE: u16 = 20;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RoomInfo {
     pub room_id: String,
     pub name: Option<String>,
     pub topic: Option<String>,
+    pub avatar_url: Option<String>,
+    pub history_visibility: Option<String>,
+    pub can_send_messages: bool,
+    pub read_only_reason: Option<String>,
+    /// From `m.room.create`; `"1"` when the create event isn't known.
+    pub room_version: String,
+    /// Whether users on other servers may join.
+    pub federated: bool,
+    /// Set for rooms we left or were banned from, kept for rereading.
+    pub archived: bool,
+    /// The room's `m.room.retention` policy, if it has one.
+    pub retention: Option<RoomRetention>,
+    /// Set when someone else kicked or banned us.
+    pub removal: Option<RoomRemoval>,
+    /// Bridges to other networks in the room.
+    pub bridges: Vec<BridgeInfo>,
+}
+
+/// Why the user cannot post in a room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ReadOnlyReason {
+    InsufficientPowerLevel,
+    Banned,
+    Tombstoned,
+    Left,
+}
+
+impl ReadOnlyReason {
+    pub(crate) fn code(self) -> &'static str {
+        match self {
+            ReadOnlyReason::InsufficientPowerLevel => "INSUFFICIENT_POWER_LEVEL",
+            ReadOnlyReason::Banned => "ROOM_BANNED",
+            ReadOnlyReason::Tombstoned => "ROOM_TOMBSTONED",
+            ReadOnlyReason::Left => "ROOM_LEFT",
+        }
+    }
+
+    pub(crate) fn message(self) -> &'static str {
+        match self {
+            ReadOnlyReason::InsufficientPowerLevel => "insufficient power level",
+            ReadOnlyReason::Banned => "you were banned",
+            ReadOnlyReason::Tombstoned => "room is tombstoned",
+            ReadOnlyReason::Left => "you left this room",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Message {
+    pub event_id: Option<String>,
     pub sender: String,
+    pub sender_display_name: Option<String>,
+    pub sender_disambiguated_name: String,
     pub body: String,
     pub timestamp: u64,
+    /// When this device queued the event, for messages sent from here; lets
+    /// pending sends be ordered against incoming events.
+    pub received_at: Option<u64>,
+    pub content: MessageContent,
+    pub emotes: Vec<InlineEmote>,
+    /// Whether the user's push rules highlight this event (keywords, their
+    /// name, or an @room from someone allowed to use it).
+    pub highlight: bool,
+    /// When the room's retention policy lets the server delete this event.
+    pub expires_at: Option<u64>,
+    /// The formatted body, for messages that have one. `body` then hides
+    /// any spoilers.
+    pub segments: Option<Vec<Segment>>,
+    /// Protocol of the bridge that relayed this message, when the sender is
+    /// one of its ghost users; matches `RoomInfo.bridges[].protocol`.
+    pub via_bridge: Option<String>,
+    /// Set when `body` or `segments` were cut short for being too large;
+    /// `get_full_event_body` has the rest.
+    pub truncated: bool,
+    /// The room the message is in, in merged DM timelines that span several
+    /// rooms.
+    pub room_id: Option<String>,
+    /// Set on messages of the room this one replaced, which history paged
+    /// back into after reaching the upgrade.
+    pub from_predecessor: bool,
+}
+
+/// What kind of timeline item a [`Message`] is, with any extra data needed to render it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MessageContent {
+    Text,
+    Notice,
+    Emote,
+    /// A message of a `msgtype` outside the spec, e.g. from a bridge, shown
+    /// by its `body` unless the frontend knows the type.
+    Custom { msgtype: String },
+    /// An event we don't have the keys for yet.
+    Encrypted,
+    Sticker {
+        url: String,
+        encrypted: bool,
+        mimetype: Option<String>,
+        width: Option<u64>,
+        height: Option<u64>,
+    },
+    Image {
+        /// The full-size image.
+        url: String,
+        encrypted: bool,
+        file: Option<Box<EncryptedFile>>,
+        filename: String,
+        caption: Option<String>,
+        mimetype: Option<String>,
+        size_bytes: Option<u64>,
+        /// GIF or APNG, which the frontend may not want to autoplay; its
+        /// `thumbnail`, when the sender attached one, is a still frame.
+        is_animated: bool,
+        width: Option<u64>,
+        height: Option<u64>,
+        thumbnail: Option<MediaThumbnail>,
+    },
+    Video {
+        url: String,
+        encrypted: bool,
+        /// Key and hashes for decrypting the video in encrypted rooms.
+        file: Option<Box<EncryptedFile>>,
+        filename: String,
+        caption: Option<String>,
+        mimetype: Option<String>,
+        size: Option<u64>,
+        duration_ms: Option<u64>,
+        width: Option<u64>,
+        height: Option<u64>,
+        thumbnail: Option<MediaThumbnail>,
+    },
+    /// Someone joined, left, was invited, kicked or banned; `change` names
+    /// which, e.g. `banned`.
+    Membership {
+        change: String,
+        target: String,
+        reason: Option<String>,
+    },
+    /// An MSC3381 poll. Votes are filled in by `annotate_polls`.
+    Poll {
+        question: String,
+        poll_kind: PollKind,
+        max_selections: u64,
+        answers: Vec<PollAnswer>,
+        /// The answers the user's counted vote picked.
+        own_vote: Vec<String>,
+        /// Users whose vote counts.
+        voters: u64,
+        ended: bool,
+    },
+    /// An event type the timeline can't render, shown so the conversation
+    /// has no unexplained gaps. `body` is the event's text fallback when it
+    /// has one.
+    Unsupported {
+        event_type: String,
+        has_fallback: bool,
+    },
+    /// Not an event: starts the messages of a local day, `YYYY-MM-DD`.
+    DayDivider { date: String },
+    /// Not an event: the start of the room's history, once paginating back
+    /// reaches it. The sender fields name the room's creator.
+    HistoryBoundary { created_at: Option<u64> },
+    /// Not an event: in place of `HistoryBoundary` when the history goes on
+    /// in a room this one replaced that can't be read, `room_id`, for the
+    /// given reason.
+    HistoryUnavailable { room_id: String, reason: String },
+    /// Not an event: history sync skipped, e.g. after the app was closed for
+    /// a while, in place of the missing messages until `fill_gap` fetches
+    /// them.
+    Gap { gap: GapInfo },
+}
+
+/// A thumbnail attached to a media message.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MediaThumbnail {
+    pub url: String,
+    pub encrypted: bool,
+    pub file: Option<Box<EncryptedFile>>,
+    pub mimetype: Option<String>,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    pub size: Option<u64>,
+}
+
+/// The last page `get_messages` returned, per room, for dropping the events
+/// overlapping pages repeat.
+pub type PageBoundaries = Arc<RwLock<HashMap<OwnedRoomId, PageBoundary>>>;
+
+/// Event IDs of the last page returned for a room and of the page before
+/// it, with the token the last page was asked for. A retry of that token is
+/// checked against the page before, like the first attempt was, rather than
+/// against its own events.
+#[derive(Default)]
+pub struct PageBoundary {
+    from: Option<String>,
+    before: HashSet<OwnedEventId>,
+    events: HashSet<OwnedEventId>,
+}
+
+impl PageBoundary {
+    /// The events of `chunk`, the page asked for with `from`, that weren't
+    /// on the page before it, or earlier on this one.
+    fn unseen<'a>(&mut self, from: Option<&str>, chunk: &'a [TimelineEvent]) -> Vec<&'a TimelineEvent> {
+        if from.is_none() {
+            *self = Self::default();
+        } else if self.from.as_deref() == from {
+            self.events.clear();
+        } else {
+            self.before = std::mem::take(&mut self.events);
+            self.from = from.map(str::to_string);
+        }
+        chunk
+            .iter()
+            .filter(|event| match event.event_id() {
+                Some(event_id) => !self.before.contains(&event_id) && self.events.insert(event_id),
+                None => true,
+            })
+            .collect()
+    }
+}
+
+/// The oldest message `get_messages` returned per room, for closing the
+/// next older page with a divider when the day changes between them.
+pub type PageDays = Arc<RwLock<HashMap<OwnedRoomId, PageDay>>>;
+
+/// The oldest messages of the last page returned for a room and of the
+/// page before it, with the token the last page was asked for. Like
+/// `PageBoundary`, a retry of that token continues from the page before
+/// rather than from its own first attempt.
+#[derive(Default)]
+pub struct PageDay {
+    from: Option<String>,
+    before: Option<DayEdge>,
+    oldest: Option<DayEdge>,
+}
+
+impl PageDay {
+    /// The edge the page asked for with `from` closes with: the oldest
+    /// message of the page before it. A page without `from` has none.
+    pub(crate) fn newer(&mut self, from: Option<&str>) -> Option<DayEdge> {
+        if from.is_none() {
+            *self = Self::default();
+        } else if self.from.as_deref() != from {
+            self.before = self.oldest.take();
+            self.from = from.map(str::to_string);
+        }
+        self.before
+    }
+
+    /// Records the oldest message of the page just served. An empty page
+    /// passes the edge before it on to the next.
+    pub(crate) fn served(&mut self, oldest: Option<DayEdge>) {
+        self.oldest = oldest.or(self.before);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DayEdge {
+    /// Local days since 1970-01-01.
+    day: i64,
+    timestamp: u64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -26,144 +329,1660 @@ pub struct MessagesResponse {
     pub next_token: Option<String>,
 }
 
+/// Renders the text-like message types the timeline knows how to show.
+pub(crate) fn text_body(msgtype: &MessageType) -> Option<String> {
+    match msgtype {
+        MessageType::Text(t) => Some(t.body.clone()),
+        MessageType::Notice(n) => Some(n.body.clone()),
+        MessageType::Emote(e) => Some(format!("* {}", e.body)),
+        _ => None,
+    }
+}
+
+/// The body and content kind for message types the timeline can render.
+fn message_content(msgtype: &MessageType) -> Option<(String, MessageContent)> {
+    let content = match msgtype {
+        MessageType::Text(_) => MessageContent::Text,
+        MessageType::Notice(_) => MessageContent::Notice,
+        MessageType::Emote(_) => MessageContent::Emote,
+        MessageType::Image(image) => return Some(image_content(image)),
+        MessageType::Video(video) => return Some(video_content(video)),
+        MessageType::_Custom(_) => {
+            let content = MessageContent::Custom {
+                msgtype: msgtype.msgtype().to_string(),
+            };
+            return Some((msgtype.body().to_string(), content));
+        }
+        _ => return None,
+    };
+    Some((text_body(msgtype)?, content))
+}
+
+/// Splits a media source into its URL, whether it is encrypted, and the
+/// decryption info when it is.
+fn media_source(source: &MediaSource) -> (String, bool, Option<Box<EncryptedFile>>) {
+    match source {
+        MediaSource::Plain(url) => (url.to_string(), false, None),
+        MediaSource::Encrypted(file) => (file.url.to_string(), true, Some(file.clone())),
+    }
+}
+
+/// Whether an image of this type is animated. PNGs are only known to be
+/// animated when sent as `image/apng`.
+pub(crate) fn is_animated_mimetype(mimetype: &str) -> bool {
+    matches!(mimetype, "image/gif" | "image/apng")
+}
+
+fn media_thumbnail(source: &MediaSource, info: Option<&ThumbnailInfo>) -> MediaThumbnail {
+    let (url, encrypted, file) = media_source(source);
+    MediaThumbnail {
+        url,
+        encrypted,
+        file,
+        mimetype: info.and_then(|t| t.mimetype.clone()),
+        width: info.and_then(|t| t.width).map(u64::from),
+        height: info.and_then(|t| t.height).map(u64::from),
+        size: info.and_then(|t| t.size).map(u64::from),
+    }
+}
+
+/// Body and content for an image. An `is_animated` flag from the sender
+/// (MSC4230) wins over guessing from the mimetype.
+fn image_content(image: &ImageMessageEventContent) -> (String, MessageContent) {
+    let (url, encrypted, file) = media_source(&image.source);
+    let info = image.info.as_deref();
+    let mimetype = info.and_then(|i| i.mimetype.clone());
+
+    let thumbnail = info.and_then(|info| {
+        Some(media_thumbnail(info.thumbnail_source.as_ref()?, info.thumbnail_info.as_deref()))
+    });
+    let is_animated = info
+        .and_then(|i| i.is_animated)
+        .unwrap_or_else(|| mimetype.as_deref().is_some_and(is_animated_mimetype));
+
+    let caption = image.caption().map(str::to_string);
+    let body = caption.clone().unwrap_or_else(|| image.filename().to_string());
+
+    let content = MessageContent::Image {
+        url,
+        encrypted,
+        file,
+        filename: image.filename().to_string(),
+        caption,
+        mimetype,
+        size_bytes: info.and_then(|i| i.size).map(u64::from),
+        is_animated,
+        width: info.and_then(|i| i.width).map(u64::from),
+        height: info.and_then(|i| i.height).map(u64::from),
+        thumbnail,
+    };
+
+    (body, content)
+}
+
+fn video_content(video: &VideoMessageEventContent) -> (String, MessageContent) {
+    let (url, encrypted, file) = media_source(&video.source);
+    let info = video.info.as_deref();
+
+    let thumbnail = info.and_then(|info| {
+        Some(media_thumbnail(info.thumbnail_source.as_ref()?, info.thumbnail_info.as_deref()))
+    });
+
+    let caption = video.caption().map(str::to_string);
+    let body = caption.clone().unwrap_or_else(|| video.filename().to_string());
+
+    let content = MessageContent::Video {
+        url,
+        encrypted,
+        file,
+        filename: video.filename().to_string(),
+        caption,
+        mimetype: info.and_then(|i| i.mimetype.clone()),
+        size: info.and_then(|i| i.size).map(u64::from),
+        duration_ms: info.and_then(|i| i.duration).map(|d| d.as_millis() as u64),
+        width: info.and_then(|i| i.width).map(u64::from),
+        height: info.and_then(|i| i.height).map(u64::from),
+        thumbnail,
+    };
+
+    (body, content)
+}
+
+fn sticker_content(sticker: &StickerEventContent) -> Option<(String, MessageContent)> {
+    let (url, encrypted) = match &sticker.source {
+        StickerMediaSource::Plain(url) => (url.to_string(), false),
+        StickerMediaSource::Encrypted(file) => (file.url.to_string(), true),
+        _ => return None,
+    };
+
+    let content = MessageContent::Sticker {
+        url,
+        encrypted,
+        mimetype: sticker.info.mimetype.clone(),
+        width: sticker.info.width.map(u64::from),
+        height: sticker.info.height.map(u64::from),
+    };
+
+    Some((sticker.body.clone(), content))
+}
+
+fn build_message(
+    timeline_event: &TimelineEvent,
+    profiles: &ProfileResolver,
+    sender: String,
+    body: String,
+    content: MessageContent,
+    emotes: Vec<InlineEmote>,
+) -> Message {
+    Message {
+        event_id: timeline_event.event_id().map(|id| id.to_string()),
+        sender_display_name: profiles.display_name(&sender),
+        sender_disambiguated_name: profiles.disambiguated_name(&sender),
+        sender,
+        body,
+        timestamp: origin_server_ts(timeline_event),
+        received_at: None,
+        content,
+        emotes,
+        highlight: is_highlighted(timeline_event),
+        expires_at: None,
+        segments: None,
+        via_bridge: None,
+        truncated: false,
+        room_id: None,
+        from_predecessor: false,
+    }
+}
+
+/// A message event's timeline entry, with its formatted body split into
+/// segments. The sender's plain `body` may spell out spoilers, so when there
+/// are any it is rebuilt from the segments.
+fn text_message(
+    timeline_event: &TimelineEvent,
+    profiles: &ProfileResolver,
+    sender: String,
+    msgtype: &MessageType,
+) -> Option<Message> {
+    let (body, content) = message_content(msgtype)?;
+    let html = formatted_html(msgtype);
+    let emotes = html.map(inline_emotes).unwrap_or_default();
+    let (segments, truncated) = match html.map(parse_formatted_capped) {
+        Some((segments, truncated)) => (Some(segments), truncated),
+        None => (None, false),
+    };
+
+    let body = match &segments {
+        Some(segments) if contains_spoiler(segments) => match msgtype {
+            MessageType::Emote(_) => format!("* {}", plain_text(segments)),
+            _ => plain_text(segments),
+        },
+        _ => body,
+    };
+
+    let mut message = build_message(timeline_event, profiles, sender, body, content, emotes);
+    message.segments = segments;
+    message.truncated = truncated;
+    Some(message)
+}
+
+/// Requests to verify in a room, followed by `room_verification` rather than
+/// shown in the timeline.
+const VERIFICATION_REQUEST_MSGTYPE: &str = "m.key.verification.request";
+
+/// `event` with its message content put through `normalize_message_content`
+/// when it is an `m.room.message`, or `None` when the message has nothing to
+/// show. Other events and redacted messages come back as they are.
+fn normalize_message_event<T>(event: &Raw<T>) -> Option<Cow<'_, Raw<T>>> {
+    if event.get_field::<String>("type").ok().flatten().as_deref() != Some("m.room.message") {
+        return Some(Cow::Borrowed(event));
+    }
+    let Ok(mut json) = serde_json::from_str::<Value>(event.json().get()) else {
+        return Some(Cow::Borrowed(event));
+    };
+    if json.pointer("/unsigned/redacted_because").is_some() {
+        return Some(Cow::Borrowed(event));
+    }
+
+    let content = json.get("content").cloned().unwrap_or(Value::Null);
+    let Some(normalized) = normalize_message_content(&content) else {
+        debug!("Dropping message {:?} with nothing to show", json.get("event_id"));
+        return None;
+    };
+    if normalized == content {
+        return Some(Cow::Borrowed(event));
+    }
+    json["content"] = normalized;
+    let raw = serde_json::value::to_raw_value(&json).ok()?;
+    Some(Cow::Owned(Raw::from_json(raw)))
+}
+
+/// Cleans up the content of a message as bridges sometimes send it: the body
+/// is trimmed, taken from the formatted body when missing or empty, and
+/// stringified when it is a number or boolean, and a missing or empty
+/// `msgtype` becomes `m.text`. Custom msgtypes are kept for the timeline
+/// to show by their body. `None` when there is no text left and no
+/// attachment either, and for verification requests, whose body is only a
+/// fallback for clients without in-room verification.
+pub(crate) fn normalize_message_content(content: &Value) -> Option<Value> {
+    let mut content = content.as_object()?.clone();
+    if content.get("msgtype").and_then(Value::as_str) == Some(VERIFICATION_REQUEST_MSGTYPE) {
+        return None;
+    }
+
+    let body = match content.get("body") {
+        Some(Value::String(body)) => body.trim().to_string(),
+        Some(Value::Number(number)) => number.to_string(),
+        Some(Value::Bool(value)) => value.to_string(),
+        _ => String::new(),
+    };
+    let body = match content.get("formatted_body").and_then(Value::as_str) {
+        Some(html) if body.is_empty() => plain_text(&parse_formatted(html)).trim().to_string(),
+        _ => body,
+    };
+    let has_attachment = content.contains_key("url") || content.contains_key("file");
+    if body.is_empty() && !has_attachment {
+        return None;
+    }
+    content.insert("body".to_string(), Value::String(body));
+
+    let msgtype = content.get("msgtype").and_then(Value::as_str);
+    if msgtype.is_none_or(str::is_empty) {
+        content.insert("msgtype".to_string(), Value::String("m.text".to_string()));
+    }
+    Some(Value::Object(content))
+}
+
+/// Event types that never get a timeline entry of their own: they annotate
+/// other events, are shown elsewhere, or are call and verification
+/// signalling.
+const HIDDEN_EVENT_TYPES: &[&str] = &[
+    "m.reaction",
+    "m.room.redaction",
+    "m.room.encrypted",
+    "m.receipt",
+    "m.typing",
+    "m.call.candidates",
+    "m.call.negotiate",
+    "m.call.select_answer",
+    "m.poll.response",
+    "org.matrix.msc3381.poll.response",
+    "m.poll.end",
+    "org.matrix.msc3381.poll.end",
+    "m.beacon",
+    "org.matrix.msc3672.beacon",
+];
+const HIDDEN_EVENT_TYPE_PREFIXES: &[&str] = &["m.key.verification."];
+
+fn is_hidden_event_type(event_type: &str) -> bool {
+    HIDDEN_EVENT_TYPES.contains(&event_type)
+        || HIDDEN_EVENT_TYPE_PREFIXES.iter().any(|prefix| event_type.starts_with(prefix))
+}
+
+/// The entry for a message event the timeline has no rendering for, or
+/// `None` when it should stay hidden. State events other than membership
+/// and redacted events stay hidden too.
+fn unsupported_message<T>(
+    timeline_event: &TimelineEvent,
+    profiles: &ProfileResolver,
+    sender: String,
+    event: &Raw<T>,
+) -> Option<Message> {
+    let event_type = event.get_field::<String>("type").ok().flatten()?;
+    if is_hidden_event_type(&event_type) || event.get_field::<Value>("state_key").ok().flatten().is_some() {
+        return None;
+    }
+    let unsigned = event.get_field::<Value>("unsigned").ok().flatten();
+    if unsigned.is_some_and(|unsigned| unsigned.get("redacted_because").is_some()) {
+        return None;
+    }
+
+    let fallback = event
+        .get_field::<Value>("content")
+        .ok()
+        .flatten()
+        .and_then(|content| text_fallback(&content));
+    let content = MessageContent::Unsupported {
+        event_type: event_type.clone(),
+        has_fallback: fallback.is_some(),
+    };
+    let body = fallback.unwrap_or_else(|| format!("Unsupported event ({})", event_type));
+    Some(build_message(timeline_event, profiles, sender, body, content, Vec::new()))
+}
+
+/// The plain text an event carries for clients that don't know its type:
+/// the extensible-event `m.text` (a string in older drafts, otherwise a list
+/// of representations), or a `body`.
+fn text_fallback(content: &Value) -> Option<String> {
+    let text = ["m.text", "org.matrix.msc1767.text"]
+        .iter()
+        .find_map(|key| match content.get(key)? {
+            Value::String(text) => Some(text.as_str()),
+            Value::Array(representations) => {
+                let plain = representations
+                    .iter()
+                    .find(|r| r.get("mimetype").and_then(Value::as_str).is_none_or(|m| m == "text/plain"))
+                    .or(representations.first())?;
+                plain.get("body")?.as_str()
+            }
+            _ => None,
+        })
+        .or_else(|| content.get("body")?.as_str())?;
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// Cuts bodies down to `max_bytes`, so an oversized event isn't shipped to
+/// the webview whole on every page load. Segments are dropped instead when
+/// their text is too long, leaving the cut plain body.
+pub(crate) fn limit_body_sizes(messages: &mut [Message], max_bytes: usize) {
+    for message in messages {
+        if message.body.len() > max_bytes {
+            message.body.truncate(floor_char_boundary(&message.body, max_bytes));
+            message.body.push('…');
+            message.truncated = true;
+        }
+        if message.segments.as_deref().is_some_and(|s| plain_text(s).len() > max_bytes) {
+            message.segments = None;
+            message.truncated = true;
+        }
+    }
+}
+
+/// The largest index up to `index` that is on a character boundary.
+fn floor_char_boundary(text: &str, index: usize) -> usize {
+    (0..=index.min(text.len()))
+        .rev()
+        .find(|&i| text.is_char_boundary(i))
+        .unwrap_or(0)
+}
+
+/// The event's own `origin_server_ts`. The wrapper's `timestamp` is only
+/// filled in for some events, e.g. not for ones decrypted from `/messages`.
+pub(crate) fn origin_server_ts(timeline_event: &TimelineEvent) -> u64 {
+    timeline_event
+        .raw()
+        .get_field::<u64>("origin_server_ts")
+        .ok()
+        .flatten()
+        .or_else(|| timeline_event.timestamp.map(|ts| ts.get().into()))
+        .unwrap_or(0)
+}
+
+/// Reads the highlight tweak off the push actions the SDK evaluated for the
+/// event. Encrypted events are evaluated after decryption, which the server
+/// cannot do.
+fn is_highlighted(timeline_event: &TimelineEvent) -> bool {
+    timeline_event
+        .push_actions()
+        .is_some_and(|actions| actions.iter().any(|action| action.is_highlight()))
+}
+
+/// Evaluates the highlights of `messages` again with the cached push rules,
+/// which may be newer than the SDK's. `events` are the ones the messages
+/// were made from.
+pub(crate) async fn annotate_highlights(state: &MatrixState, room: &Room, events: &[&TimelineEvent], messages: &mut [Message]) {
+    let Some(context) = push_context(&state.push_rules, room).await else {
+        return;
+    };
+    let mut highlights = HashMap::new();
+    for event in events {
+        if let Some(event_id) = event.event_id() {
+            let highlight = context.for_event(event.raw()).await.iter().any(|action| action.is_highlight());
+            highlights.insert(event_id.to_string(), highlight);
+        }
+    }
+    for message in messages {
+        if let Some(highlight) = message.event_id.as_ref().and_then(|id| highlights.get(id)) {
+            message.highlight = *highlight;
+        }
+    }
+}
+
+/// The HTML `formatted_body` of the text-like message types, if any.
+pub(crate) fn formatted_html(msgtype: &MessageType) -> Option<&str> {
+    let formatted = match msgtype {
+        MessageType::Text(t) => t.formatted.as_ref(),
+        MessageType::Notice(n) => n.formatted.as_ref(),
+        MessageType::Emote(e) => e.formatted.as_ref(),
+        _ => None,
+    };
+    formatted.map(|f| f.body.as_str())
+}
+
+/// Lists all rooms. `RoomInfo`s are cached and only recomputed for rooms
+/// whose name-affecting state changed since the last call; `force_refresh`
+/// recomputes everything.
 #[tauri::command]
-pub async fn get_rooms(state: State<'_, MatrixState>) -> Result<Vec<RoomInfo>, String> {
+pub async fn get_rooms(
+    state: State<'_, MatrixState>,
+    force_refresh: Option<bool>,
+    include_left: Option<bool>,
+) -> Result<Vec<RoomInfo>, String> {
     let client_lock = state.client.read().await;
     let client = client_lock.as_ref().ok_or("Not logged in")?;
 
-    println!("Getting rooms for client...");
+    debug!("Getting rooms for client...");
+
+    let include_left = include_left.unwrap_or(false);
+    let rooms: Vec<Room> = client
+        .rooms()
+        .into_iter()
+        .filter(|room| include_left || !is_archived(room))
+        .collect();
+
+    if force_refresh.unwrap_or(false) {
+        state.room_cache.write().await.clear();
+    }
+
+    let rooms_info = cached_room_infos(&state, &rooms).await;
+
+    debug!("Found {} rooms", rooms_info.len());
+
+    Ok(rooms_info)
+}
+
+/// Joins a room we left again, for when the user wants to continue an
+/// archived conversation. Only works if the room's join rules let us in.
+#[tauri::command]
+pub async fn rejoin_room(state: State<'_, MatrixState>, room_id: String) -> Result<RoomInfo, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    if room.state() == RoomState::Banned {
+        return Err("You are banned from this room".to_string());
+    }
+    if room.state() != RoomState::Left {
+        return Err("You haven't left this room".to_string());
+    }
+
+    room.join()
+        .await
+        .map_err(|e| format!("Failed to rejoin room: {}", e))?;
+
+    state.room_cache.write().await.remove(&room_id);
+    Ok(room_info(&room).await)
+}
+
+/// Joins a room by ID or alias, such as one from a link or the room
+/// directory. `via` names servers to join through when ours doesn't know the
+/// room; they are tried one at a time, and when all fail the error says how
+/// each went (see `via_failure_error`). `retry_via` adds servers to try
+/// after those. Guests can only join rooms that allow guest access and get
+/// `GUEST_NOT_ALLOWED` for the rest.
+#[tauri::command]
+pub async fn join_room(
+    state: State<'_, MatrixState>,
+    room_id_or_alias: String,
+    via: Option<Vec<String>>,
+    retry_via: Option<Vec<String>>,
+) -> Result<RoomInfo, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
 
-    let mut rooms_info = Vec::new();
+    let target = OwnedRoomOrAliasId::try_from(room_id_or_alias.trim())
+        .map_err(|e| format!("Invalid room ID or alias: {}", e))?;
+    let servers = via_servers(via, retry_via);
 
-    for room in client.rooms() {
-        let name = room
-            .display_name()
+    let guest_not_allowed = |e: &matrix_sdk::Error| -> Option<String> {
+        matches!(e.client_api_error_kind(), Some(ErrorKind::GuestAccessForbidden)).then(|| {
+            CommandError::new("GUEST_NOT_ALLOWED", "This room doesn't allow guests; create an account to join it")
+                .into()
+        })
+    };
+    let room = match servers.is_empty() {
+        true => client
+            .join_room_by_id_or_alias(&target, &[])
             .await
-            .ok()
-            .map(|dn| dn.to_string())
-            .or_else(|| Some(room.room_id().to_string()));
+            .map_err(|e| guest_not_allowed(&e).unwrap_or_else(|| format!("Failed to join room: {}", e)))?,
+        false => {
+            let target = &target;
+            through_each(&servers, |server| async move { client.join_room_by_id_or_alias(target, &[server]).await })
+                .await
+                .map_err(|failure| {
+                    failure
+                        .last
+                        .as_ref()
+                        .and_then(guest_not_allowed)
+                        .unwrap_or_else(|| via_failure_error("join the room", failure.outcomes))
+                })?
+        }
+    };
 
-        let topic = room.topic();
+    state.room_cache.write().await.remove(room.room_id());
+    Ok(room_info(&room).await)
+}
 
-        rooms_info.push(RoomInfo {
-            room_id: room.room_id().to_string(),
-            name,
-            topic,
-        });
+/// The messages of a left room as far as `backfill_room` stored them, with
+/// nothing more to page through.
+async fn archived_messages(
+    state: &MatrixState,
+    room: &Room,
+    tz_offset_minutes: i32,
+) -> Result<MessagesResponse, String> {
+    let user_id = state.user_id.read().await.clone().ok_or("Not logged in")?;
+    let session_dir = state.data_dir.join(sanitize_user_id(&user_id));
+    let room_id = room.room_id().to_string();
+    let indexed = tauri::async_runtime::spawn_blocking(move || indexed_messages(&session_dir, &room_id))
+        .await
+        .map_err(|e| format!("Failed to read the message index: {}", e))??;
+
+    let profiles = ProfileResolver::new(room, &[]).await;
+    let retention = room_retention(room).await;
+    let bridges = room_bridges(room).await;
+    let mut messages: Vec<Message> = indexed
+        .into_iter()
+        .map(|message| Message {
+            via_bridge: bridge_for_sender(&bridges, &message.sender).map(|b| b.protocol.clone()),
+            event_id: Some(message.event_id),
+            sender_display_name: profiles.display_name(&message.sender),
+            sender_disambiguated_name: profiles.disambiguated_name(&message.sender),
+            sender: message.sender,
+            body: message.body,
+            timestamp: message.timestamp,
+            received_at: None,
+            content: MessageContent::Text,
+            emotes: Vec::new(),
+            highlight: false,
+            expires_at: retention.and_then(|r| r.expires_at(message.timestamp)),
+            segments: None,
+            truncated: false,
+            room_id: None,
+            from_predecessor: false,
+        })
+        // The server has deleted these by now; the index just hasn't noticed.
+        .filter(|message| !is_expired(message.expires_at))
+        .collect();
+    limit_body_sizes(&mut messages, state.settings.read().await.message_body_limit);
+    insert_day_dividers(&mut messages, tz_offset_minutes, None);
+
+    Ok(MessagesResponse {
+        messages,
+        has_more: false,
+        next_token: None,
+    })
+}
+
+/// `RoomInfo`s for `rooms` in the same order, computing and caching the ones
+/// not cached yet.
+pub(crate) async fn cached_room_infos(state: &MatrixState, rooms: &[Room]) -> Vec<RoomInfo> {
+    let missing: Vec<Room> = {
+        let cache = state.room_cache.read().await;
+        rooms
+            .iter()
+            .filter(|room| !cache.contains_key(room.room_id()))
+            .cloned()
+            .collect()
+    };
+
+    let computed: Vec<(OwnedRoomId, RoomInfo)> = stream::iter(missing)
+        .map(|room| async move { (room.room_id().to_owned(), room_info(&room).await) })
+        .buffer_unordered(ROOM_INFO_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut cache = state.room_cache.write().await;
+    cache.extend(computed);
+
+    let infos: Vec<RoomInfo> = rooms
+        .iter()
+        .filter_map(|room| cache.get(room.room_id()).cloned())
+        .collect();
+    drop(cache);
+    note_room_names(&state.reported_room_names, &infos).await;
+    infos
+}
+
+pub(crate) async fn room_info(room: &Room) -> RoomInfo {
+    let name = room
+        .display_name()
+        .await
+        .ok()
+        .map(|dn| dn.to_string())
+        .or_else(|| Some(room.room_id().to_string()));
+    let read_only = read_only_reason(room).await;
+    let create = room.create_content();
+
+    RoomInfo {
+        room_id: room.room_id().to_string(),
+        name,
+        topic: room.topic(),
+        avatar_url: room.avatar_url().map(|url| url.to_string()),
+        history_visibility: room.history_visibility().map(|h| h.to_string()),
+        can_send_messages: read_only.is_none(),
+        read_only_reason: read_only.map(|r| r.message().to_string()),
+        room_version: room.version().unwrap_or(RoomVersionId::V1).to_string(),
+        federated: create.is_none_or(|create| create.federate),
+        archived: is_archived(room),
+        retention: room_retention(room).await,
+        removal: if is_archived(room) { own_removal(room).await } else { None },
+        bridges: room_bridges(room).await,
     }
+}
 
-    println!("Found {} rooms", rooms_info.len());
+pub(crate) fn is_archived(room: &Room) -> bool {
+    matches!(room.state(), RoomState::Left | RoomState::Banned)
+}
 
-    Ok(rooms_info)
+/// Why the user can't send messages to `room`, if they can't. Membership and
+/// tombstones take precedence over power levels.
+pub(crate) async fn read_only_reason(room: &Room) -> Option<ReadOnlyReason> {
+    match room.state() {
+        RoomState::Banned => return Some(ReadOnlyReason::Banned),
+        RoomState::Left => return Some(ReadOnlyReason::Left),
+        _ => {}
+    }
+    if room.is_tombstoned() {
+        return Some(ReadOnlyReason::Tombstoned);
+    }
+
+    let can_send = room.power_levels().await.is_ok_and(|levels| {
+        levels.user_can_send_message(room.own_user_id(), MessageLikeEventType::RoomMessage)
+    });
+    (!can_send).then_some(ReadOnlyReason::InsufficientPowerLevel)
+}
+
+/// Turns a timeline event into a `Message`, or `None` for events the
+/// timeline doesn't show. Our own messages that don't decrypt yet are shown
+/// from `sent_plaintexts`, which forgets events that decrypt normally.
+pub(crate) fn timeline_message(
+    timeline_event: &TimelineEvent,
+    profiles: &ProfileResolver,
+    own_user_id: Option<&UserId>,
+    sent_plaintexts: &mut HashMap<OwnedTransactionId, SentPlaintext>,
+) -> Option<Message> {
+    use matrix_sdk::deserialized_responses::TimelineEventKind;
+    use matrix_sdk::ruma::events::{AnyTimelineEvent, AnySyncTimelineEvent, AnyMessageLikeEvent, AnySyncMessageLikeEvent, AnySyncStateEvent, SyncStateEvent};
+    use matrix_sdk::ruma::events::room::message::{RoomMessageEvent, SyncRoomMessageEvent};
+    use matrix_sdk::ruma::events::sticker::{StickerEvent, SyncStickerEvent};
+
+    match &timeline_event.kind {
+        TimelineEventKind::Decrypted(decrypted) => {
+            if let Some(event_id) = timeline_event.event_id() {
+                sent_plaintexts.retain(|_, sent| sent.event_id.as_ref() != Some(&event_id));
+            }
+            let sender = decrypted.encryption_info.sender.to_string();
+            let event = normalize_message_event(&decrypted.event)?;
+            match event.deserialize() {
+                Ok(AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+                    RoomMessageEvent::Original(original),
+                ))) => {
+                    text_message(timeline_event, profiles, sender, &original.content.msgtype)
+                }
+                Ok(AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::Sticker(
+                    StickerEvent::Original(sticker),
+                ))) => {
+                    let (body, content) = sticker_content(&sticker.content)?;
+                    Some(build_message(timeline_event, profiles, sender, body, content, Vec::new()))
+                }
+                _ => match poll_content(&event) {
+                    Some((body, content)) => {
+                        Some(build_message(timeline_event, profiles, sender, body, content, Vec::new()))
+                    }
+                    None => unsupported_message(timeline_event, profiles, sender, &event),
+                },
+            }
+        }
+        TimelineEventKind::PlainText { event } => {
+            let event = normalize_message_event(event)?;
+            match event.deserialize() {
+                Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+                    SyncRoomMessageEvent::Original(original),
+                ))) => {
+                    let sender = original.sender.to_string();
+                    text_message(timeline_event, profiles, sender, &original.content.msgtype)
+                }
+                Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::Sticker(
+                    SyncStickerEvent::Original(sticker),
+                ))) => {
+                    let (body, content) = sticker_content(&sticker.content)?;
+                    let sender = sticker.sender.to_string();
+                    Some(build_message(timeline_event, profiles, sender, body, content, Vec::new()))
+                }
+                Ok(AnySyncTimelineEvent::State(AnySyncStateEvent::RoomMember(SyncStateEvent::Original(member)))) => {
+                    let (body, content) = membership_entry(&member, profiles)?;
+                    let sender = member.sender.to_string();
+                    Some(build_message(timeline_event, profiles, sender, body, content, Vec::new()))
+                }
+                _ => {
+                    let sender = event.get_field::<String>("sender").ok().flatten()?;
+                    match poll_content(&event) {
+                        Some((body, content)) => {
+                            Some(build_message(timeline_event, profiles, sender, body, content, Vec::new()))
+                        }
+                        None => unsupported_message(timeline_event, profiles, sender, &event),
+                    }
+                }
+            }
+        }
+        TimelineEventKind::UnableToDecrypt { .. } => {
+            debug!("Event {:?}: UnableToDecrypt - waiting for keys", timeline_event.event_id());
+
+            // Our own message that came back before we could decrypt it.
+            let own = timeline_event
+                .event_id()
+                .and_then(|event_id| sent_plaintext(sent_plaintexts, &event_id));
+            if let (Some(body), Some(user_id)) = (own, own_user_id) {
+                return Some(build_message(
+                    timeline_event,
+                    profiles,
+                    user_id.to_string(),
+                    body,
+                    MessageContent::Text,
+                    Vec::new(),
+                ));
+            }
+
+            Some(build_message(
+                timeline_event,
+                profiles,
+                "[Encrypted]".to_string(),
+                "🔒 Waiting for encryption keys...".to_string(),
+                MessageContent::Encrypted,
+                Vec::new(),
+            ))
+        }
+    }
 }
 
+/// A page of the room's history. Paging back past an upgrade goes on into
+/// the rooms it replaced; one that isn't world-readable and that we were
+/// never in is only joined with `join_predecessors`.
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 pub async fn get_messages(
     state: State<'_, MatrixState>,
     room_id: String,
     _limit: u32,
     from_token: Option<String>,
+    forward: Option<bool>,
+    tz_offset_minutes: Option<i32>,
+    merged_room_ids: Option<Vec<String>>,
+    join_predecessors: Option<bool>,
 ) -> Result<MessagesResponse, String> {
     let client = state.client.read().await;
     let client = client.as_ref().ok_or("Not logged in")?;
+    let tz_offset_minutes = tz_offset_minutes.unwrap_or(0);
 
-    println!("Getting messages for room: {}", room_id);
-    println!("From token: {:?}", from_token);
+    debug!("Getting messages for room: {}", room_id);
+    debug!("From token: {:?}", from_token);
 
     let room_id_parsed: OwnedRoomId = room_id
         .parse()
         .map_err(|e| format!("Invalid room ID: {}", e))?;
+    if let Some(room_ids) = merged_room_ids.filter(|room_ids| room_ids.iter().any(|id| *id != room_id)) {
+        return merged_messages(&state, client, &room_id_parsed, &room_ids, from_token, tz_offset_minutes).await;
+    }
+
+    // History paged back into the rooms this one replaced has tokens that
+    // name the room.
+    let continued = from_token.as_deref().and_then(PredecessorToken::parse);
+    let requested = client.get_room(&room_id_parsed).ok_or("Room not found")?;
+    let source = match continued.as_ref().map(|token| &token.room_id) {
+        None => Predecessor::Known(requested.clone()),
+        Some(room_id) => match client.get_room(room_id) {
+            Some(room) => Predecessor::Known(room),
+            None => Predecessor::Peeked(room_id.clone()),
+        },
+    };
+    // A room we never joined has no members or settings of its own for us;
+    // the requested room's stand in.
+    let room = match &source {
+        Predecessor::Known(room) => room.clone(),
+        Predecessor::Peeked(_) => requested.clone(),
+    };
+    let page_token = match &continued {
+        Some(token) => token.from.as_deref(),
+        None => from_token.as_deref(),
+    };
 
-    let room = client
-        .get_room(&room_id_parsed)
-        .ok_or("Room not found")?;
+    // Forward pagination continues after a `get_event_context` window.
+    let forward = forward.unwrap_or(false);
+    let options = match (forward, page_token) {
+        (true, token) => MessagesOptions::forward().from(token),
+        (false, Some(token)) => MessagesOptions::backward().from(Some(token)),
+        (false, None) => MessagesOptions::backward(),
+    };
 
-    let options = if let Some(token) = from_token {
-        MessagesOptions::backward().from(Some(token.as_str()))
-    } else {
-        MessagesOptions::backward()
+    // A room we left is read from what `backfill_room` stored; the server,
+    // which usually refuses anyway, is only asked when nothing was.
+    if continued.is_none() && from_token.is_none() && is_archived(&room) {
+        let archived = archived_messages(&state, &room, tz_offset_minutes).await?;
+        if !archived.messages.is_empty() {
+            return Ok(archived);
+        }
+    }
+
+    // A page prefetched at startup stands in for the first one, without
+    // going to the server.
+    let prefetched = match (forward, &from_token) {
+        (false, None) => take_prefetched(&state.prefetched_pages, &room_id_parsed).await,
+        _ => None,
+    };
+    let messages_response = match prefetched {
+        Some(response) => Ok(response),
+        None => source.messages(client, options).await,
+    };
+    let messages_response = match messages_response {
+        Ok(response) => response,
+        // Servers usually refuse history for rooms we are no longer in, and
+        // what was stored locally has been served already.
+        Err(e) if continued.is_none() && is_archived(&room) => {
+            debug!("Server refused history for left room {}: {}", room_id, e);
+            return Ok(MessagesResponse {
+                messages: Vec::new(),
+                has_more: false,
+                next_token: None,
+            });
+        }
+        Err(e) => return Err(format!("Failed to fetch messages: {}", e)),
     };
 
-    let messages_response = room
-        .messages(options)
-        .await
-        .map_err(|e| format!("Failed to fetch messages: {}", e))?;
+    debug!("Received {} events from server", messages_response.chunk.len());
 
-    println!("Received {} events from server", messages_response.chunk.len());
+    // The page the user scrolled to last, saved with their view position.
+    if !forward {
+        let mut tokens = state.pagination_tokens.write().await;
+        match &from_token {
+            Some(token) => tokens.insert(room_id.clone(), token.clone()),
+            None => tokens.remove(&room_id),
+        };
+    }
 
-    let mut result = Vec::new();
+    let mut boundaries = state.page_boundaries.write().await;
+    let boundary = boundaries.entry(room_id_parsed.clone()).or_default();
+    let events = boundary.unseen(from_token.as_deref(), &messages_response.chunk);
+    drop(boundaries);
 
-    for (idx, timeline_event) in messages_response.chunk.iter().enumerate() {
-        use matrix_sdk::deserialized_responses::TimelineEventKind;
-        use matrix_sdk::ruma::events::{AnyTimelineEvent, AnySyncTimelineEvent, AnyMessageLikeEvent, AnySyncMessageLikeEvent};
-        use matrix_sdk::ruma::events::room::message::{MessageType, RoomMessageEvent, SyncRoomMessageEvent};
-
-        match &timeline_event.kind {
-            TimelineEventKind::Decrypted(decrypted) => {
-                println!("Event {}: Decrypted successfully!", idx);
-                if let Ok(any_event) = decrypted.event.deserialize() {
-                    if let AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(msg)) = any_event {
-                        if let RoomMessageEvent::Original(original) = msg {
-                            let sender = decrypted.encryption_info.sender.to_string();
-                            let body = match &original.content.msgtype {
-                                MessageType::Text(t) => t.body.clone(),
-                                MessageType::Notice(n) => n.body.clone(),
-                                MessageType::Emote(e) => format!("* {}", e.body),
-                                _ => continue,
-                            };
-
-                            let timestamp = timeline_event.timestamp.map(|ts| ts.get().into()).unwrap_or(0);
-                            println!("  -> Decrypted message: {}", body);
-                            result.push(Message { sender, body, timestamp });
-                        }
-                    }
-                }
+    let (mut result, profiles) = page_messages(&state, &room, &messages_response.state, events, !forward).await;
+    if continued.is_some() {
+        for message in &mut result {
+            message.from_predecessor = true;
+        }
+    }
+
+    debug!("Parsed {} messages out of {} events", result.len(), messages_response.chunk.len());
+
+    let mut more = has_more(&messages_response);
+    let mut next_token = match &continued {
+        Some(_) => messages_response.end.clone().map(|end| PredecessorToken::new(source.room_id(), end).encode()),
+        None => messages_response.end.clone(),
+    };
+    // Sync skipped history before the newest events; the gap's entry stands
+    // in for it, and paging goes on from before the gap.
+    let mut gap = None;
+    if !forward && continued.is_none() {
+        let end = messages_response.end.as_deref();
+        if let Some((info, resume_token)) = gap_in_page(&state, &room_id_parsed, &messages_response.chunk, end).await {
+            more = true;
+            next_token = Some(resume_token);
+            gap = Some(info);
+        }
+    }
+    let mut oldest = source;
+    let mut oldest_profiles = profiles;
+    let mut unavailable = None;
+    if !forward {
+        // The start of an upgraded room goes on into the room it replaced.
+        for _ in 0..MAX_HISTORY_HOPS {
+            if more {
+                break;
             }
-            TimelineEventKind::PlainText { event } => {
-                println!("Event {}: PlainText", idx);
-                if let Ok(any_event) = event.deserialize() {
-                    if let AnySyncTimelineEvent::MessageLike(msg) = any_event {
-                        if let AnySyncMessageLikeEvent::RoomMessage(room_msg) = msg {
-                            if let SyncRoomMessageEvent::Original(original) = room_msg {
-                                let sender = original.sender.to_string();
-                                let body = match &original.content.msgtype {
-                                    MessageType::Text(t) => t.body.clone(),
-                                    MessageType::Notice(n) => n.body.clone(),
-                                    MessageType::Emote(e) => format!("* {}", e.body),
-                                    _ => continue,
-                                };
-
-                                let timestamp = timeline_event.timestamp.map(|ts| ts.get().into()).unwrap_or(0);
-                                result.push(Message { sender, body, timestamp });
-                            }
-                        }
-                    }
+            let predecessor = match history_predecessor(client, &oldest, join_predecessors.unwrap_or(false)).await {
+                Ok(Some(predecessor)) => predecessor,
+                Ok(None) => break,
+                Err(inaccessible) => {
+                    unavailable = Some(inaccessible);
+                    break;
                 }
-            }
-            TimelineEventKind::UnableToDecrypt { .. } => {
-                println!("Event {}: UnableToDecrypt - waiting for keys", idx);
+            };
+            let page = match predecessor.messages(client, MessagesOptions::backward()).await {
+                Ok(page) => page,
+                Err(e) => {
+                    unavailable = Some(InaccessiblePredecessor {
+                        room_id: predecessor.room_id().to_owned(),
+                        reason: format!("The server refused its history: {}", e),
+                    });
+                    break;
+                }
+            };
+            debug!("Continuing the history of {} in {}", room_id, predecessor.room_id());
 
-                let timestamp = timeline_event.timestamp.map(|ts| ts.get().into()).unwrap_or(0);
+            let mut boundaries = state.page_boundaries.write().await;
+            let boundary = boundaries.entry(room_id_parsed.clone()).or_default();
+            let events = boundary.unseen(None, &page.chunk);
+            drop(boundaries);
 
-                result.push(Message {
-                    sender: "[Encrypted]".to_string(),
-                    body: "🔒 Waiting for encryption keys...".to_string(),
-                    timestamp,
-                });
+            let predecessor_room = match &predecessor {
+                Predecessor::Known(room) => room,
+                Predecessor::Peeked(_) => &requested,
+            };
+            let (mut older, profiles) = page_messages(&state, predecessor_room, &page.state, events, true).await;
+            for message in &mut older {
+                message.from_predecessor = true;
             }
+            older.append(&mut result);
+            result = older;
+
+            more = has_more(&page);
+            next_token = page.end.map(|end| PredecessorToken::new(predecessor.room_id(), end).encode());
+            oldest = predecessor;
+            oldest_profiles = profiles;
         }
     }
 
-    result.reverse();
-
-    println!("Parsed {} messages out of {} events", result.len(), messages_response.chunk.len());
-
-    let next_token = messages_response.end.clone();
-    let has_more = next_token.is_some() && messages_response.chunk.len() > 0;
+    if forward {
+        insert_day_dividers(&mut result, tz_offset_minutes, None);
+    } else {
+        let mut days = state.page_days.write().await;
+        let day = days.entry(room_id_parsed.clone()).or_default();
+        let newer = day.newer(from_token.as_deref());
+        day.served(insert_day_dividers(&mut result, tz_offset_minutes, newer));
+        drop(days);
+        if let Some(gap) = gap {
+            let timestamp = gap.until_timestamp;
+            result.insert(0, separator(MessageContent::Gap { gap }, timestamp));
+        }
+        if !more {
+            match unavailable {
+                Some(inaccessible) => history_unavailable(inaccessible, &mut result, tz_offset_minutes),
+                None => {
+                    let create = oldest.create_event(client).await;
+                    open_history(create, &oldest_profiles, &mut result, tz_offset_minutes);
+                }
+            }
+        }
+    }
 
     Ok(MessagesResponse {
         messages: result,
-        has_more,
+        has_more: more,
         next_token,
     })
 }
+
+/// Turns a page of events, in the order the server sent them, into
+/// timeline messages oldest first, with the annotations every timeline
+/// gets. Also returns the profiles the page resolved.
+pub(crate) async fn page_messages(
+    state: &MatrixState,
+    room: &Room,
+    page_state: &[Raw<AnyStateEvent>],
+    events: Vec<&TimelineEvent>,
+    backward: bool,
+) -> (Vec<Message>, ProfileResolver) {
+    let own_user_id = room.client().user_id().map(UserId::to_owned);
+    let mut profiles = ProfileResolver::new(room, page_state).await;
+    let mut result = Vec::new();
+    let mut sent_plaintexts = state.sent_plaintext.write().await;
+
+    for timeline_event in &events {
+        profiles.observe(timeline_event.raw());
+        if let Some(message) = timeline_message(timeline_event, &profiles, own_user_id.as_deref(), &mut sent_plaintexts) {
+            result.push(message);
+        }
+    }
+
+    drop(sent_plaintexts);
+    annotate_highlights(state, room, &events, &mut result).await;
+    if backward {
+        result.reverse();
+    }
+
+    let sent_at = state.sent_at.read().await;
+    for message in &mut result {
+        message.received_at = message
+            .event_id
+            .as_deref()
+            .and_then(|id| <&EventId>::try_from(id).ok())
+            .and_then(|id| sent_at.get(id).copied());
+    }
+    drop(sent_at);
+    let room_id = room.room_id().to_owned();
+    for message in &result {
+        if let Ok(sender) = <&UserId>::try_from(message.sender.as_str()) {
+            note_sender(&state.recent_senders, &room_id, sender, message.timestamp).await;
+        }
+    }
+    annotate_expiry(&mut result, room_retention(room).await.as_ref());
+    limit_body_sizes(&mut result, state.settings.read().await.message_body_limit);
+    annotate_bridges(&mut result, &room_bridges(room).await);
+    annotate_polls(room, &mut result).await;
+
+    (result, profiles)
+}
+
+/// Puts a `DayDivider` before each message that starts a new local day, and
+/// after the last one when `newer`, the oldest message of the page loaded
+/// before, is on a later day. The first message only gets one from the page
+/// before it, or at the start of history. Returns the page's oldest message.
+pub(crate) fn insert_day_dividers(messages: &mut Vec<Message>, tz_offset_minutes: i32, newer: Option<DayEdge>) -> Option<DayEdge> {
+    let edge = |message: &Message| DayEdge {
+        day: local_day(message.timestamp, tz_offset_minutes),
+        timestamp: message.timestamp,
+    };
+    let oldest = messages.first().map(edge);
+    let newest = messages.last().map(edge);
+
+    let mut previous_day = None;
+    for message in std::mem::take(messages) {
+        let day = local_day(message.timestamp, tz_offset_minutes);
+        if previous_day.is_some_and(|previous| previous != day) {
+            messages.push(day_divider(day, message.timestamp));
+        }
+        previous_day = Some(day);
+        messages.push(message);
+    }
+    if let (Some(newest), Some(newer)) = (newest, newer) {
+        if newest.day != newer.day {
+            messages.push(day_divider(newer.day, newer.timestamp));
+        }
+    }
+    oldest
+}
+
+/// Opens the oldest page with the first day's divider, preceded by a
+/// `HistoryBoundary` with the room's creation.
+pub(crate) async fn start_of_history(
+    room: &Room,
+    profiles: &ProfileResolver,
+    messages: &mut Vec<Message>,
+    tz_offset_minutes: i32,
+) {
+    open_history(room_create_event(room).await, profiles, messages, tz_offset_minutes);
+}
+
+/// `start_of_history` with the room's `m.room.create` event at hand.
+fn open_history(create: Option<Value>, profiles: &ProfileResolver, messages: &mut Vec<Message>, tz_offset_minutes: i32) {
+    open_first_day(messages, tz_offset_minutes);
+
+    let created_at = create.as_ref().and_then(|c| c.get("origin_server_ts")?.as_u64());
+    let creator = create
+        .as_ref()
+        .and_then(|c| c.get("sender")?.as_str().map(str::to_string))
+        .unwrap_or_default();
+
+    let mut boundary = separator(
+        MessageContent::HistoryBoundary { created_at },
+        created_at.or(messages.first().map(|m| m.timestamp)).unwrap_or(0),
+    );
+    if !creator.is_empty() {
+        boundary.sender_display_name = profiles.display_name(&creator);
+        boundary.sender_disambiguated_name = profiles.disambiguated_name(&creator);
+        boundary.sender = creator;
+    }
+    messages.insert(0, boundary);
+}
+
+/// Opens the oldest page with the first day's divider, preceded by a
+/// `HistoryUnavailable` for the replaced room that couldn't be read.
+fn history_unavailable(inaccessible: InaccessiblePredecessor, messages: &mut Vec<Message>, tz_offset_minutes: i32) {
+    open_first_day(messages, tz_offset_minutes);
+    let timestamp = messages.first().map_or(0, |m| m.timestamp);
+    let content = MessageContent::HistoryUnavailable {
+        room_id: inaccessible.room_id.to_string(),
+        reason: inaccessible.reason,
+    };
+    messages.insert(0, separator(content, timestamp));
+}
+
+fn open_first_day(messages: &mut Vec<Message>, tz_offset_minutes: i32) {
+    if let Some(first) = messages.first() {
+        let divider = day_divider(local_day(first.timestamp, tz_offset_minutes), first.timestamp);
+        messages.insert(0, divider);
+    }
+}
+
+/// Days since 1970-01-01 in the time zone `tz_offset_minutes` east of UTC.
+fn local_day(timestamp_ms: u64, tz_offset_minutes: i32) -> i64 {
+    (timestamp_ms as i64 / 1000 + tz_offset_minutes as i64 * 60).div_euclid(86_400)
+}
+
+fn day_divider(day: i64, timestamp: u64) -> Message {
+    let (year, month, day) = civil_from_days(day);
+    separator(
+        MessageContent::DayDivider {
+            date: format!("{:04}-{:02}-{:02}", year, month, day),
+        },
+        timestamp,
+    )
+}
+
+/// A timeline entry that isn't an event.
+fn separator(content: MessageContent, timestamp: u64) -> Message {
+    Message {
+        event_id: None,
+        sender: String::new(),
+        sender_display_name: None,
+        sender_disambiguated_name: String::new(),
+        body: String::new(),
+        timestamp,
+        received_at: None,
+        content,
+        emotes: Vec::new(),
+        highlight: false,
+        expires_at: None,
+        segments: None,
+        via_bridge: None,
+        truncated: false,
+        room_id: None,
+        from_predecessor: false,
+    }
+}
+
+/// Whether paging on can give more events. Servers keep handing out an
+/// `end` token at the start of a room, so an empty page or one that doesn't
+/// move the token means there is nothing more.
+pub(crate) fn has_more(response: &Messages) -> bool {
+    !response.chunk.is_empty() && response.end.as_deref().is_some_and(|end| end != response.start)
+}
+
+/// The whole body of a message, for showing one the timeline truncated.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FullEventBody {
+    pub body: String,
+    /// Still capped in how much markup is converted, which protects the
+    /// parser rather than the webview.
+    pub segments: Option<Vec<Segment>>,
+}
+
+/// The uncut body of a message the timeline marked `truncated`.
+#[tauri::command]
+pub async fn get_full_event_body(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    event_id: String,
+) -> Result<FullEventBody, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let event_id: OwnedEventId = event_id
+        .parse()
+        .map_err(|e| format!("Invalid event ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let event = room
+        .event(&event_id, None)
+        .await
+        .map_err(|e| format!("Failed to fetch message: {}", e))?;
+    let content: RoomMessageEventContent = event
+        .raw()
+        .get_field("content")
+        .ok()
+        .flatten()
+        .ok_or("Not a message, or it couldn't be decrypted")?;
+
+    let segments = formatted_html(&content.msgtype).map(|html| parse_formatted_capped(html).0);
+    let body = match &segments {
+        Some(segments) if contains_spoiler(segments) => plain_text(segments),
+        _ => content.msgtype.body().to_string(),
+    };
+
+    Ok(FullEventBody { body, segments })
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EventContextResponse {
+    /// Oldest first, with the requested event somewhere in the middle.
+    pub messages: Vec<Message>,
+    pub event_id: String,
+    /// For `get_messages` to load older messages.
+    pub before_token: Option<String>,
+    /// For `get_messages` with `forward` to load newer messages.
+    pub after_token: Option<String>,
+}
+
+/// Loads the timeline around `event_id`, for jumping to a message such as
+/// the read marker.
+#[tauri::command]
+pub async fn get_event_context(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    event_id: String,
+    context_size: Option<u16>,
+) -> Result<EventContextResponse, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let event_id: OwnedEventId = event_id
+        .parse()
+        .map_err(|e| format!("Invalid event ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let context_size = UInt::from(context_size.unwrap_or(DEFAULT_CONTEXT_SIZE));
+    let response = room
+        .event_with_context(&event_id, true, context_size, None)
+        .await
+        .map_err(|e| format!("Failed to load the message's context: {}", e))?;
+
+    let mut profiles = ProfileResolver::new(&room, &response.state).await;
+    let events: Vec<&TimelineEvent> = response
+        .events_before
+        .iter()
+        .rev()
+        .chain(response.event.iter())
+        .chain(response.events_after.iter())
+        .collect();
+
+    let mut sent_plaintexts = state.sent_plaintext.write().await;
+    let mut messages = Vec::new();
+    for timeline_event in &events {
+        profiles.observe(timeline_event.raw());
+        if let Some(message) = timeline_message(timeline_event, &profiles, client.user_id(), &mut sent_plaintexts) {
+            messages.push(message);
+        }
+    }
+    drop(sent_plaintexts);
+    annotate_highlights(&state, &room, &events, &mut messages).await;
+    annotate_expiry(&mut messages, room_retention(&room).await.as_ref());
+    limit_body_sizes(&mut messages, state.settings.read().await.message_body_limit);
+    annotate_bridges(&mut messages, &room_bridges(&room).await);
+    annotate_polls(&room, &mut messages).await;
+
+    Ok(EventContextResponse {
+        messages,
+        event_id: event_id.to_string(),
+        before_token: response.prev_batch_token,
+        after_token: response.next_batch_token,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::events::AnySyncTimelineEvent;
+
+    /// A `/messages` response as a server would send it, parsed the way the
+    /// SDK parses it.
+    fn served(json: serde_json::Value) -> Messages {
+        use matrix_sdk::ruma::api::client::message::get_message_events;
+        use matrix_sdk::ruma::api::IncomingResponse;
+        use matrix_sdk::ruma::exports::http;
+
+        let body = serde_json::to_vec(&json).unwrap();
+        let response = http::Response::builder().status(200).body(body).unwrap();
+        let response = get_message_events::v3::Response::try_from_http_response(response).unwrap();
+        Messages {
+            start: response.start,
+            end: response.end,
+            chunk: response
+                .chunk
+                .into_iter()
+                .map(|raw| TimelineEvent::from_plaintext(raw.cast_unchecked()))
+                .collect(),
+            state: response.state,
+        }
+    }
+
+    fn page(start: &str, end: Option<&str>, ids: &[&str]) -> Messages {
+        let chunk: Vec<_> = ids
+            .iter()
+            .map(|id| {
+                serde_json::json!({
+                    "type": "m.room.message",
+                    "event_id": id,
+                    "room_id": "!room:example.org",
+                    "sender": "@alice:example.org",
+                    "origin_server_ts": 1,
+                    "content": { "msgtype": "m.text", "body": id },
+                })
+            })
+            .collect();
+        let mut json = serde_json::json!({ "start": start, "chunk": chunk });
+        if let Some(end) = end {
+            json["end"] = end.into();
+        }
+        served(json)
+    }
+
+    fn ids(events: &[&TimelineEvent]) -> Vec<String> {
+        events.iter().filter_map(|e| e.event_id()).map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn more_history_after_a_full_page() {
+        assert!(has_more(&page("t1", Some("t2"), &["$a", "$b"])));
+    }
+
+    #[test]
+    fn no_more_history_at_the_start_of_the_room() {
+        // Servers keep returning an end token with the final, empty page.
+        assert!(!has_more(&page("t2", Some("t3"), &[])));
+        // Or the same token again.
+        assert!(!has_more(&page("t3", Some("t3"), &["$a"])));
+        assert!(!has_more(&page("t3", None, &["$a"])));
+    }
+
+    #[test]
+    fn overlapping_pages_are_deduplicated() {
+        let mut boundary = PageBoundary::default();
+
+        let first = page("t1", Some("t2"), &["$c", "$b", "$a"]);
+        assert_eq!(ids(&boundary.unseen(None, &first.chunk)), ["$c", "$b", "$a"]);
+
+        let second = page("t2", Some("t3"), &["$a", "$z", "$y"]);
+        assert_eq!(ids(&boundary.unseen(Some("t2"), &second.chunk)), ["$z", "$y"]);
+
+        // Only the previous page is remembered.
+        let third = page("t3", Some("t4"), &["$y", "$c", "$x"]);
+        assert_eq!(ids(&boundary.unseen(Some("t3"), &third.chunk)), ["$c", "$x"]);
+    }
+
+    #[test]
+    fn retried_pages_come_back_whole() {
+        let mut boundary = PageBoundary::default();
+
+        let first = page("t1", Some("t2"), &["$c", "$b", "$a"]);
+        boundary.unseen(None, &first.chunk);
+
+        // The response to the first attempt never made it to the timeline.
+        let second = page("t2", Some("t3"), &["$a", "$z", "$y"]);
+        assert_eq!(ids(&boundary.unseen(Some("t2"), &second.chunk)), ["$z", "$y"]);
+        assert_eq!(ids(&boundary.unseen(Some("t2"), &second.chunk)), ["$z", "$y"]);
+        assert!(has_more(&second));
+
+        // And paging goes on from the retried page.
+        let third = page("t3", Some("t4"), &["$y", "$x"]);
+        assert_eq!(ids(&boundary.unseen(Some("t3"), &third.chunk)), ["$x"]);
+    }
+
+    #[test]
+    fn the_last_page_of_a_room_ends_the_history() {
+        // The final page Synapse sends, with no `end` at all.
+        let last = served(serde_json::json!({ "start": "t9", "chunk": [] }));
+        assert!(!has_more(&last));
+    }
+
+    fn at(timestamp: u64) -> Message {
+        separator(MessageContent::Text, timestamp)
+    }
+
+    fn dates(messages: &[Message]) -> Vec<Option<String>> {
+        messages
+            .iter()
+            .map(|message| match &message.content {
+                MessageContent::DayDivider { date } => Some(date.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // 2024-03-09 23:30 and 2024-03-10 00:30 UTC.
+    const LATE: u64 = 1_710_027_000_000;
+    const EARLY: u64 = 1_710_030_600_000;
+
+    #[test]
+    fn days_are_divided_in_local_time() {
+        let mut utc = vec![at(LATE), at(EARLY)];
+        insert_day_dividers(&mut utc, 0, None);
+        assert_eq!(dates(&utc), [None, Some("2024-03-10".to_string()), None]);
+
+        // Both are in the evening of the 9th two hours west of UTC.
+        let mut west = vec![at(LATE), at(EARLY)];
+        insert_day_dividers(&mut west, -120, None);
+        assert_eq!(dates(&west), [None, None]);
+
+        // And the 10th an hour east.
+        let mut east = vec![at(LATE), at(EARLY)];
+        insert_day_dividers(&mut east, 60, None);
+        assert_eq!(dates(&east), [None, None]);
+    }
+
+    #[test]
+    fn older_pages_end_with_the_newer_page_day() {
+        let mut newer = vec![at(EARLY)];
+        let edge = insert_day_dividers(&mut newer, 0, None);
+        assert_eq!(dates(&newer), [None]);
+
+        let mut older = vec![at(LATE)];
+        insert_day_dividers(&mut older, 0, edge);
+        assert_eq!(dates(&older), [None, Some("2024-03-10".to_string())]);
+
+        let mut same_day = vec![at(EARLY - 60_000)];
+        insert_day_dividers(&mut same_day, 0, edge);
+        assert_eq!(dates(&same_day), [None]);
+    }
+
+    #[test]
+    fn retried_pages_close_with_the_same_day() {
+        let mut day = PageDay::default();
+        let mut newest = vec![at(EARLY)];
+        day.newer(None);
+        day.served(insert_day_dividers(&mut newest, 0, None));
+
+        // The first attempt at the older page never made it to the timeline.
+        for _ in 0..2 {
+            let mut older = vec![at(LATE)];
+            let newer = day.newer(Some("t2"));
+            day.served(insert_day_dividers(&mut older, 0, newer));
+            assert_eq!(dates(&older), [None, Some("2024-03-10".to_string())]);
+        }
+
+        // An empty page in between leaves the edge for the one after it.
+        day.newer(Some("t3"));
+        day.served(insert_day_dividers(&mut Vec::new(), 0, None));
+        let mut oldest = vec![at(LATE - 86_400_000)];
+        let newer = day.newer(Some("t4"));
+        insert_day_dividers(&mut oldest, 0, newer);
+        assert_eq!(dates(&oldest), [None, Some("2024-03-09".to_string())]);
+    }
+
+    #[test]
+    fn repeats_within_a_page_are_dropped() {
+        let mut boundary = PageBoundary::default();
+        let chunk = page("t1", Some("t2"), &["$a", "$b", "$a"]);
+        assert_eq!(ids(&boundary.unseen(None, &chunk.chunk)), ["$a", "$b"]);
+    }
+
+    fn message(body: &str, segments: Option<Vec<Segment>>) -> Message {
+        Message {
+            event_id: Some("$big".to_string()),
+            sender: "@mallory:example.org".to_string(),
+            sender_display_name: None,
+            sender_disambiguated_name: "mallory".to_string(),
+            body: body.to_string(),
+            timestamp: 1,
+            received_at: None,
+            content: MessageContent::Text,
+            emotes: Vec::new(),
+            highlight: false,
+            expires_at: None,
+            segments,
+            via_bridge: None,
+            truncated: false,
+            room_id: None,
+            from_predecessor: false,
+        }
+    }
+
+    #[test]
+    fn oversized_bodies_are_cut_on_a_char_boundary() {
+        // 60KB of four-byte emoji, so the limit falls inside a character.
+        let mut messages = vec![message(&"😀".repeat(15_000), None)];
+        limit_body_sizes(&mut messages, 16_385);
+
+        let body = &messages[0].body;
+        assert!(messages[0].truncated);
+        assert!(body.len() <= 16_385 + '…'.len_utf8());
+        assert!(body.trim_end_matches('…').chars().all(|c| c == '😀'));
+    }
+
+    #[test]
+    fn small_bodies_are_left_alone() {
+        let mut messages = vec![message("hi", Some(vec![Segment::Text { text: "hi".to_string() }]))];
+        limit_body_sizes(&mut messages, 16_384);
+        assert_eq!(messages[0].body, "hi");
+        assert!(messages[0].segments.is_some());
+        assert!(!messages[0].truncated);
+    }
+
+    #[test]
+    fn oversized_segments_are_dropped() {
+        let segments = vec![Segment::Text { text: "a".repeat(20_000) }];
+        let mut messages = vec![message("short", Some(segments))];
+        limit_body_sizes(&mut messages, 16_384);
+        assert!(messages[0].segments.is_none());
+        assert!(messages[0].truncated);
+    }
+
+    #[test]
+    fn text_fallback_prefers_plain_text_representation() {
+        let content = serde_json::json!({
+            "m.text": [
+                { "mimetype": "text/html", "body": "<b>Lunch?</b>" },
+                { "body": "Lunch?" },
+            ],
+        });
+        assert_eq!(text_fallback(&content).as_deref(), Some("Lunch?"));
+    }
+
+    #[test]
+    fn text_fallback_reads_older_drafts_and_bodies() {
+        let poll = serde_json::json!({ "org.matrix.msc1767.text": "Lunch?\n1. Yes\n2. No" });
+        assert_eq!(text_fallback(&poll).as_deref(), Some("Lunch?\n1. Yes\n2. No"));
+        assert_eq!(text_fallback(&serde_json::json!({ "body": " hi " })).as_deref(), Some("hi"));
+        assert_eq!(text_fallback(&serde_json::json!({ "m.text": "" })), None);
+    }
+
+    #[test]
+    fn signalling_events_stay_hidden() {
+        assert!(is_hidden_event_type("m.reaction"));
+        assert!(is_hidden_event_type("m.key.verification.start"));
+        assert!(!is_hidden_event_type("m.call.invite"));
+        assert!(!is_hidden_event_type("org.matrix.msc3381.poll.start"));
+    }
+
+    fn normalized(content: Value) -> Option<Value> {
+        normalize_message_content(&content)
+    }
+
+    #[test]
+    fn empty_and_blank_messages_are_dropped() {
+        assert_eq!(normalized(serde_json::json!({ "msgtype": "m.text", "body": "" })), None);
+        assert_eq!(normalized(serde_json::json!({ "msgtype": "m.text", "body": " \n\t " })), None);
+        assert_eq!(normalized(serde_json::json!({ "msgtype": "m.text" })), None);
+        let blank_html = serde_json::json!({ "msgtype": "m.notice", "body": [], "formatted_body": "<p> </p>" });
+        assert_eq!(normalized(blank_html), None);
+        assert_eq!(normalized(serde_json::json!("not an object")), None);
+    }
+
+    #[test]
+    fn verification_requests_are_dropped() {
+        let request = serde_json::json!({
+            "msgtype": "m.key.verification.request",
+            "body": "@alice:example.org is requesting to verify your key, but your client does not support in-chat key verification.",
+            "from_device": "ALICE",
+            "methods": ["m.sas.v1"],
+            "to": "@bob:example.org",
+        });
+        assert_eq!(normalized(request), None);
+    }
+
+    #[test]
+    fn bodies_are_trimmed() {
+        assert_eq!(
+            normalized(serde_json::json!({ "msgtype": "m.text", "body": "  hello \n" })),
+            Some(serde_json::json!({ "msgtype": "m.text", "body": "hello" }))
+        );
+    }
+
+    #[test]
+    fn missing_body_comes_from_formatted_body() {
+        assert_eq!(
+            normalized(serde_json::json!({
+                "msgtype": "m.text",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "<p>Hello <b>world</b></p>",
+            })),
+            Some(serde_json::json!({
+                "msgtype": "m.text",
+                "body": "Hello world",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "<p>Hello <b>world</b></p>",
+            }))
+        );
+        assert_eq!(
+            normalized(serde_json::json!({
+                "msgtype": "m.text",
+                "body": "   ",
+                "formatted_body": "<mx-reply><blockquote>Earlier</blockquote></mx-reply>Answer",
+            })),
+            Some(serde_json::json!({
+                "msgtype": "m.text",
+                "body": "Answer",
+                "formatted_body": "<mx-reply><blockquote>Earlier</blockquote></mx-reply>Answer",
+            }))
+        );
+    }
+
+    #[test]
+    fn missing_msgtypes_become_text() {
+        assert_eq!(
+            normalized(serde_json::json!({ "msgtype": "", "body": "from a bridge" })),
+            Some(serde_json::json!({ "msgtype": "m.text", "body": "from a bridge" }))
+        );
+        assert_eq!(
+            normalized(serde_json::json!({ "msgtype": "org.example.status", "body": "Away", "state": "away" })),
+            Some(serde_json::json!({ "msgtype": "org.example.status", "body": "Away", "state": "away" }))
+        );
+        assert_eq!(
+            normalized(serde_json::json!({ "body": "no msgtype" })),
+            Some(serde_json::json!({ "msgtype": "m.text", "body": "no msgtype" }))
+        );
+    }
+
+    #[test]
+    fn non_string_bodies() {
+        assert_eq!(
+            normalized(serde_json::json!({ "msgtype": "m.text", "body": 42 })),
+            Some(serde_json::json!({ "msgtype": "m.text", "body": "42" }))
+        );
+        assert_eq!(
+            normalized(serde_json::json!({ "msgtype": "m.text", "body": true })),
+            Some(serde_json::json!({ "msgtype": "m.text", "body": "true" }))
+        );
+        assert_eq!(normalized(serde_json::json!({ "msgtype": "m.text", "body": { "text": "hi" } })), None);
+        assert_eq!(normalized(serde_json::json!({ "msgtype": "m.text", "body": null })), None);
+    }
+
+    #[test]
+    fn attachments_without_a_body_are_kept() {
+        assert_eq!(
+            normalized(serde_json::json!({ "msgtype": "m.image", "body": "", "url": "mxc://example.org/a" })),
+            Some(serde_json::json!({ "msgtype": "m.image", "body": "", "url": "mxc://example.org/a" }))
+        );
+    }
+
+    #[test]
+    fn message_events_are_rewritten_or_dropped() {
+        let raw = |content: Value| -> Raw<AnySyncTimelineEvent> {
+            let json = serde_json::json!({
+                "type": "m.room.message",
+                "event_id": "$a",
+                "sender": "@bridge:example.org",
+                "origin_server_ts": 1,
+                "content": content,
+            });
+            Raw::from_json(serde_json::value::to_raw_value(&json).unwrap())
+        };
+
+        let clean = raw(serde_json::json!({ "msgtype": "m.text", "body": "hi" }));
+        assert!(matches!(normalize_message_event(&clean), Some(Cow::Borrowed(_))));
+
+        let padded = raw(serde_json::json!({ "msgtype": "m.text", "body": " hi " }));
+        let fixed = normalize_message_event(&padded).unwrap();
+        assert_eq!(fixed.get_field::<Value>("content").unwrap().unwrap()["body"], "hi");
+
+        assert!(normalize_message_event(&raw(serde_json::json!({ "msgtype": "m.text", "body": "" }))).is_none());
+    }
+}
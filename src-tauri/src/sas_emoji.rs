@@ -0,0 +1,135 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// The spec's SAS emoji table, `data-definitions/sas-emoji.json` of
+/// matrix-org/matrix-spec. Update it by replacing the file with upstream's
+/// as is, so it picks up their translations.
+const SAS_EMOJI_JSON: &str = include_str!("../resources/sas-emoji.json");
+
+#[derive(Deserialize, Debug)]
+struct SasEmojiDefinition {
+    emoji: String,
+    description: String,
+    #[serde(default)]
+    translated_descriptions: HashMap<String, String>,
+}
+
+/// The table in SAS order. Panics when the bundled file doesn't parse:
+/// falling back to an empty table would quietly show every emoji as
+/// unknown during verification.
+fn table() -> &'static [SasEmojiDefinition] {
+    static TABLE: OnceLock<Vec<SasEmojiDefinition>> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        serde_json::from_str(SAS_EMOJI_JSON)
+            .unwrap_or_else(|e| panic!("The bundled sas-emoji.json is invalid: {}", e))
+    })
+}
+
+/// Returns `(symbol, localized_description, english_description)` for an
+/// emoji as reported by the SDK.
+///
+/// `language` is a BCP-47 tag; `pt-BR` tries `pt_BR` and then `pt`. Unknown
+/// languages and missing entries fall back to English.
+pub(crate) fn localize(
+    symbol: &str,
+    english_description: &str,
+    language: Option<&str>,
+) -> (String, String, String) {
+    let localized = table()
+        .iter()
+        .find(|e| e.emoji == symbol || e.description.eq_ignore_ascii_case(english_description))
+        .and_then(|e| translation(e, language?))
+        .unwrap_or(english_description);
+
+    (
+        symbol.to_string(),
+        localized.to_string(),
+        english_description.to_string(),
+    )
+}
+
+/// Returns `(symbol, localized_description, english_description)` for the
+/// emoji at `index` of the spec's table, as derived from the SAS bytes.
+pub(crate) fn emoji_at(index: u8, language: Option<&str>) -> (String, String, String) {
+    match table().get(usize::from(index)) {
+        Some(entry) => localize(&entry.emoji, &entry.description, language),
+        None => localize("❓", "Unknown", language),
+    }
+}
+
+fn translation<'a>(entry: &'a SasEmojiDefinition, language: &str) -> Option<&'a str> {
+    let tag = language.trim().replace('-', "_");
+    let primary = tag.split('_').next().unwrap_or_default();
+
+    entry
+        .translated_descriptions
+        .get(&tag)
+        .or_else(|| {
+            entry
+                .translated_descriptions
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(&tag))
+                .map(|(_, value)| value)
+        })
+        .or_else(|| entry.translated_descriptions.get(&primary.to_lowercase()))
+        .map(String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHIPPED_LANGUAGES: &[&str] = &["de", "es", "fr", "it", "nl"];
+
+    #[test]
+    fn table_has_all_64_distinct_emoji() {
+        let table = table();
+        assert_eq!(table.len(), 64);
+
+        let mut symbols: Vec<_> = table.iter().map(|e| e.emoji.as_str()).collect();
+        symbols.sort();
+        symbols.dedup();
+        assert_eq!(symbols.len(), 64);
+        assert!(table.iter().all(|e| !e.description.is_empty()));
+    }
+
+    #[test]
+    fn every_emoji_is_translated_for_shipped_languages() {
+        for entry in table() {
+            for language in SHIPPED_LANGUAGES {
+                let translated = entry.translated_descriptions.get(*language);
+                assert!(
+                    translated.is_some_and(|t| !t.trim().is_empty()),
+                    "missing {} translation for {}",
+                    language,
+                    entry.description
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn localizes_with_regional_tags() {
+        let (symbol, localized, english) = localize("🐶", "Dog", Some("de-AT"));
+        assert_eq!(symbol, "🐶");
+        assert_eq!(localized, "Hund");
+        assert_eq!(english, "Dog");
+
+        assert_eq!(localize("🐱", "Cat", Some("nl_BE")).1, "Kat");
+    }
+
+    #[test]
+    fn looks_up_emoji_by_index() {
+        assert_eq!(emoji_at(0, None), ("🐶".to_string(), "Dog".to_string(), "Dog".to_string()));
+        assert_eq!(emoji_at(63, Some("de")).0, "📌");
+        assert_eq!(emoji_at(64, None).0, "❓");
+    }
+
+    #[test]
+    fn falls_back_to_english() {
+        assert_eq!(localize("🐶", "Dog", Some("tlh")).1, "Dog");
+        assert_eq!(localize("🐶", "Dog", None).1, "Dog");
+        assert_eq!(localize("❓", "Not an emoji", Some("de")).1, "Not an emoji");
+    }
+}
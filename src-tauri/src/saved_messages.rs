@@ -0,0 +1,216 @@
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId};
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tauri::State;
+use tracing::{info, warn};
+
+use crate::account_data::{get_global_json, set_global_json, APP_NAMESPACE};
+use crate::profiles::ProfileResolver;
+use crate::rooms::{limit_body_sizes, timeline_message, Message};
+use crate::state::MatrixState;
+
+/// Most messages the collection holds; account data events should stay small.
+const MAX_SAVED_MESSAGES: usize = 500;
+
+/// Account data holding `{"messages": [{room_id, event_id, saved_at}]}`,
+/// newest first. Like the user notes it syncs to the user's other sessions
+/// unencrypted.
+pub(crate) fn saved_messages_type() -> String {
+    format!("{}.saved_messages", APP_NAMESPACE)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct SavedReference {
+    room_id: String,
+    event_id: String,
+    saved_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SavedMessageStatus {
+    Available,
+    Redacted,
+    /// The room was left or the event can't be fetched any more.
+    Unavailable,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SavedMessage {
+    pub room_id: String,
+    pub room_name: Option<String>,
+    pub event_id: String,
+    pub saved_at: u64,
+    pub status: SavedMessageStatus,
+    /// Set when `status` is `available`.
+    pub message: Option<Message>,
+}
+
+/// Adds a message to the saved collection. Saving it again keeps its place.
+#[tauri::command]
+pub async fn save_message(state: State<'_, MatrixState>, room_id: String, event_id: String) -> Result<(), String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let event_id: OwnedEventId = event_id
+        .parse()
+        .map_err(|e| format!("Invalid event ID: {}", e))?;
+
+    let mut saved = saved_references(client).await?;
+    if saved.iter().any(|r| r.room_id == room_id.as_str() && r.event_id == event_id.as_str()) {
+        return Ok(());
+    }
+    if saved.len() >= MAX_SAVED_MESSAGES {
+        return Err(format!(
+            "You can save at most {} messages; remove some first",
+            MAX_SAVED_MESSAGES
+        ));
+    }
+
+    saved.insert(
+        0,
+        SavedReference {
+            room_id: room_id.to_string(),
+            event_id: event_id.to_string(),
+            saved_at: MilliSecondsSinceUnixEpoch::now().get().into(),
+        },
+    );
+    set_global_json(client, &saved_messages_type(), &json!({ "messages": saved })).await?;
+
+    info!("Saved {} in {}", event_id, room_id);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn unsave_message(state: State<'_, MatrixState>, room_id: String, event_id: String) -> Result<(), String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let mut saved = saved_references(client).await?;
+    let before = saved.len();
+    saved.retain(|r| r.room_id != room_id || r.event_id != event_id);
+    if saved.len() == before {
+        return Ok(());
+    }
+
+    set_global_json(client, &saved_messages_type(), &json!({ "messages": saved })).await
+}
+
+/// The saved messages, newest first. Ones that were redacted or can't be
+/// loaded any more are still listed, without a `message`, so they can be
+/// removed.
+#[tauri::command]
+pub async fn get_saved_messages(state: State<'_, MatrixState>) -> Result<Vec<SavedMessage>, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let body_limit = state.settings.read().await.message_body_limit;
+    let mut result = Vec::new();
+    for reference in saved_references(client).await? {
+        let mut saved = SavedMessage {
+            room_id: reference.room_id.clone(),
+            room_name: None,
+            event_id: reference.event_id.clone(),
+            saved_at: reference.saved_at,
+            status: SavedMessageStatus::Unavailable,
+            message: None,
+        };
+
+        let room = OwnedRoomId::try_from(reference.room_id.as_str())
+            .ok()
+            .and_then(|room_id| client.get_room(&room_id));
+        let event_id = OwnedEventId::try_from(reference.event_id.as_str()).ok();
+        let (Some(room), Some(event_id)) = (room, event_id) else {
+            result.push(saved);
+            continue;
+        };
+        saved.room_name = room.display_name().await.ok().map(|name| name.to_string());
+
+        let Ok(event) = room.load_or_fetch_event(&event_id, None).await else {
+            result.push(saved);
+            continue;
+        };
+        let redacted = event
+            .raw()
+            .get_field::<serde_json::Value>("unsigned")
+            .ok()
+            .flatten()
+            .is_some_and(|unsigned| unsigned.get("redacted_because").is_some());
+        if redacted {
+            saved.status = SavedMessageStatus::Redacted;
+            result.push(saved);
+            continue;
+        }
+
+        let mut profiles = ProfileResolver::new(&room, &[]).await;
+        profiles.observe(event.raw());
+        let message = {
+            let mut sent_plaintexts = state.sent_plaintext.write().await;
+            timeline_message(&event, &profiles, client.user_id(), &mut sent_plaintexts)
+        };
+        if let Some(message) = message {
+            let mut messages = vec![message];
+            limit_body_sizes(&mut messages, body_limit);
+            saved.status = SavedMessageStatus::Available;
+            saved.message = messages.pop();
+        }
+        result.push(saved);
+    }
+    Ok(result)
+}
+
+async fn saved_references(client: &Client) -> Result<Vec<SavedReference>, String> {
+    Ok(get_global_json(client, &saved_messages_type())
+        .await?
+        .map(|content| parse_references(&content))
+        .unwrap_or_default())
+}
+
+/// The references in the account data content, skipping any entry that
+/// doesn't parse, e.g. one written by a broken client, instead of losing the
+/// whole collection over it.
+fn parse_references(content: &Value) -> Vec<SavedReference> {
+    let Some(entries) = content.get("messages").and_then(Value::as_array) else {
+        return Vec::new();
+    };
+    entries
+        .iter()
+        .filter_map(|entry| match serde_json::from_value(entry.clone()) {
+            Ok(reference) => Some(reference),
+            Err(e) => {
+                warn!("Skipping unreadable saved message {}: {}", entry, e);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_entries_are_skipped_one_by_one() {
+        let content = json!({
+            "messages": [
+                { "room_id": "!a:example.org", "event_id": "$1", "saved_at": 2 },
+                { "room_id": "!a:example.org", "saved_at": 1 },
+                "$2",
+                { "room_id": "!b:example.org", "event_id": "$3", "saved_at": "yesterday" },
+                { "room_id": "!b:example.org", "event_id": "$4", "saved_at": 0 },
+            ]
+        });
+        let ids: Vec<_> = parse_references(&content).into_iter().map(|r| r.event_id).collect();
+        assert_eq!(ids, ["$1", "$4"]);
+    }
+
+    #[test]
+    fn missing_or_malformed_collection_is_empty() {
+        assert!(parse_references(&json!({})).is_empty());
+        assert!(parse_references(&json!({ "messages": { "$1": true } })).is_empty());
+    }
+}
@@ -0,0 +1,267 @@
+use matrix_sdk::encryption::recovery::RecoveryState;
+use serde::{Deserialize, Serialize};
+use tauri::State;
+
+use crate::state::MatrixState;
+
+/// The one thing the security banner should ask the user to do.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityRecommendationCode {
+    SetupRecovery,
+    VerifyThisSession,
+    EnterRecoveryKey,
+    TurnOnBackup,
+    /// Nothing can vouch for this session: no other verified session and no
+    /// recovery. Only resetting the identity gets the user out.
+    IdentityResetRequired,
+    AllGood,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SecurityRecommendation {
+    pub code: SecurityRecommendationCode,
+    /// Other sessions of the user's that are verified and could verify this one.
+    pub other_verified_sessions: usize,
+    pub this_session_verified: bool,
+    /// Whether recovery (secret storage) is set up on the account.
+    pub recovery_set_up: bool,
+    /// Whether a key backup exists on the server, for `turn_on_backup`.
+    pub backup_on_server: bool,
+}
+
+/// What the recommendation is decided from.
+#[derive(Clone, Copy, Debug)]
+struct SecurityFacts {
+    /// Whether the account has a cross-signing identity at all.
+    identity_exists: bool,
+    this_session_verified: bool,
+    other_verified_sessions: usize,
+    recovery_set_up: bool,
+    /// Whether this session holds every secret recovery stores.
+    recovery_complete: bool,
+    backup_enabled: bool,
+}
+
+/// Combines verification, recovery and backup status into the single most
+/// pressing thing for the user to do, so the UI shows one banner.
+#[tauri::command]
+pub async fn get_security_recommendation(
+    state: State<'_, MatrixState>,
+) -> Result<SecurityRecommendation, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let user_id = client.user_id().ok_or("No user ID")?;
+    let encryption = client.encryption();
+
+    let identity_exists = encryption
+        .get_user_identity(user_id)
+        .await
+        .map_err(|e| format!("Failed to load identity: {}", e))?
+        .is_some();
+    let this_session_verified = encryption
+        .cross_signing_status()
+        .await
+        .is_some_and(|status| status.is_complete());
+
+    let devices = encryption
+        .get_user_devices(user_id)
+        .await
+        .map_err(|e| format!("Failed to get devices: {}", e))?;
+    let other_verified_sessions = devices
+        .devices()
+        .filter(|d| Some(d.device_id()) != client.device_id())
+        .filter(|d| !d.is_dehydrated() && d.is_cross_signed_by_owner())
+        .count();
+
+    let recovery_set_up = encryption
+        .secret_storage()
+        .is_enabled()
+        .await
+        .map_err(|e| format!("Failed to check recovery: {}", e))?;
+    let recovery_complete = encryption.recovery().state() == RecoveryState::Enabled;
+
+    let backups = encryption.backups();
+    let backup_enabled = backups.are_enabled().await;
+    let backup_on_server = backup_enabled
+        || backups
+            .fetch_exists_on_server()
+            .await
+            .map_err(|e| format!("Failed to check key backup: {}", e))?;
+
+    let facts = SecurityFacts {
+        identity_exists,
+        this_session_verified,
+        other_verified_sessions,
+        recovery_set_up,
+        recovery_complete,
+        backup_enabled,
+    };
+
+    Ok(SecurityRecommendation {
+        code: recommend(&facts),
+        other_verified_sessions,
+        this_session_verified,
+        recovery_set_up,
+        backup_on_server,
+    })
+}
+
+/// An unverified session is dealt with first, since recovery and backup
+/// can't be set up from it. Verifying from another session is preferred
+/// over the recovery key, which the user is less likely to have at hand.
+fn recommend(facts: &SecurityFacts) -> SecurityRecommendationCode {
+    use SecurityRecommendationCode::*;
+
+    if !facts.identity_exists {
+        // Setting up recovery creates the identity.
+        return SetupRecovery;
+    }
+    if !facts.this_session_verified {
+        return if facts.other_verified_sessions > 0 {
+            VerifyThisSession
+        } else if facts.recovery_set_up {
+            EnterRecoveryKey
+        } else {
+            IdentityResetRequired
+        };
+    }
+    if !facts.recovery_set_up {
+        return SetupRecovery;
+    }
+    if !facts.recovery_complete {
+        // Recovering fills in the secrets this session is missing.
+        return EnterRecoveryKey;
+    }
+    if !facts.backup_enabled {
+        return TurnOnBackup;
+    }
+    AllGood
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use SecurityRecommendationCode::*;
+
+    fn all_good() -> SecurityFacts {
+        SecurityFacts {
+            identity_exists: true,
+            this_session_verified: true,
+            other_verified_sessions: 1,
+            recovery_set_up: true,
+            recovery_complete: true,
+            backup_enabled: true,
+        }
+    }
+
+    fn unverified() -> SecurityFacts {
+        SecurityFacts {
+            this_session_verified: false,
+            recovery_complete: false,
+            backup_enabled: false,
+            ..all_good()
+        }
+    }
+
+    #[test]
+    fn fully_set_up_is_all_good() {
+        assert_eq!(recommend(&all_good()), AllGood);
+        let alone = SecurityFacts {
+            other_verified_sessions: 0,
+            ..all_good()
+        };
+        assert_eq!(recommend(&alone), AllGood);
+    }
+
+    #[test]
+    fn no_identity_means_setting_up_recovery() {
+        let fresh = SecurityFacts {
+            identity_exists: false,
+            this_session_verified: false,
+            other_verified_sessions: 0,
+            recovery_set_up: false,
+            recovery_complete: false,
+            backup_enabled: false,
+        };
+        assert_eq!(recommend(&fresh), SetupRecovery);
+    }
+
+    #[test]
+    fn unverified_session_prefers_another_session() {
+        assert_eq!(recommend(&unverified()), VerifyThisSession);
+        let without_recovery = SecurityFacts {
+            recovery_set_up: false,
+            ..unverified()
+        };
+        assert_eq!(recommend(&without_recovery), VerifyThisSession);
+    }
+
+    #[test]
+    fn unverified_session_alone_uses_recovery_key() {
+        let alone = SecurityFacts {
+            other_verified_sessions: 0,
+            ..unverified()
+        };
+        assert_eq!(recommend(&alone), EnterRecoveryKey);
+    }
+
+    #[test]
+    fn unverified_session_without_any_way_back_needs_reset() {
+        let stranded = SecurityFacts {
+            other_verified_sessions: 0,
+            recovery_set_up: false,
+            ..unverified()
+        };
+        assert_eq!(recommend(&stranded), IdentityResetRequired);
+    }
+
+    #[test]
+    fn unverified_session_ignores_backup() {
+        let with_backup = SecurityFacts {
+            backup_enabled: true,
+            ..unverified()
+        };
+        assert_eq!(recommend(&with_backup), VerifyThisSession);
+    }
+
+    #[test]
+    fn verified_session_without_recovery_sets_it_up() {
+        let facts = SecurityFacts {
+            recovery_set_up: false,
+            recovery_complete: false,
+            backup_enabled: false,
+            ..all_good()
+        };
+        assert_eq!(recommend(&facts), SetupRecovery);
+    }
+
+    #[test]
+    fn verified_session_missing_secrets_enters_recovery_key() {
+        let facts = SecurityFacts {
+            recovery_complete: false,
+            backup_enabled: false,
+            ..all_good()
+        };
+        assert_eq!(recommend(&facts), EnterRecoveryKey);
+    }
+
+    #[test]
+    fn backup_comes_last() {
+        let facts = SecurityFacts {
+            backup_enabled: false,
+            ..all_good()
+        };
+        assert_eq!(recommend(&facts), TurnOnBackup);
+    }
+
+    #[test]
+    fn codes_serialize_as_snake_case() {
+        assert_eq!(
+            serde_json::to_value(IdentityResetRequired).unwrap(),
+            serde_json::json!("identity_reset_required")
+        );
+        assert_eq!(serde_json::to_value(AllGood).unwrap(), serde_json::json!("all_good"));
+    }
+}
@@ -0,0 +1,203 @@
+use matrix_sdk::authentication::matrix::MatrixSession;
+use matrix_sdk::store::RoomLoadSettings;
+use matrix_sdk::ruma::{OwnedDeviceId, OwnedUserId};
+use matrix_sdk::{Client, SqliteStoreConfig};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::app_lock::store_key;
+use crate::client_config::refresh_client_config;
+use crate::guest::is_guest_store;
+use crate::identity_changes::watch_identity_changes;
+use crate::instance_lock::lock_store;
+use crate::settings::load_settings;
+use crate::state::MatrixState;
+use crate::store_meta::{prepare_store, write_store_meta};
+use crate::sync_gaps::load_sync_gaps;
+use crate::sync_mod::install_event_handlers;
+use crate::transactions::load_sent_transactions;
+use crate::view_state::load_view_states;
+
+const SESSION_FILE: &str = "session.json";
+/// Written to a session directory kept on logout.
+const PRESERVED_DEVICE_FILE: &str = "preserved_device.json";
+
+/// What is needed to bring a logged-in client back after a restart.
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+    homeserver: String,
+    store_dir: PathBuf,
+    session: MatrixSession,
+}
+
+/// A device whose crypto store was kept on logout, so logging in again
+/// with the same device ID brings back its keys and verification.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PreservedDevice {
+    pub homeserver: String,
+    pub user_id: OwnedUserId,
+    pub device_id: OwnedDeviceId,
+}
+
+/// Persists the client's session so it can be restored on the next start.
+pub(crate) fn save_session(data_dir: &Path, client: &Client, store_dir: &Path) -> Result<(), String> {
+    let session = client
+        .matrix_auth()
+        .session()
+        .ok_or("Client has no session to save")?;
+
+    let stored = StoredSession {
+        homeserver: client.homeserver().to_string(),
+        store_dir: store_dir.to_path_buf(),
+        session,
+    };
+
+    if let Err(e) = write_store_meta(store_dir) {
+        warn!("{}", e);
+    }
+
+    let json = serde_json::to_string(&stored)
+        .map_err(|e| format!("Failed to serialize session: {}", e))?;
+    fs::write(data_dir.join(SESSION_FILE), json)
+        .map_err(|e| format!("Failed to save session: {}", e))
+}
+
+pub(crate) fn clear_saved_session(data_dir: &Path) {
+    let path = data_dir.join(SESSION_FILE);
+    if path.exists() {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Failed to remove saved session: {}", e);
+        }
+    }
+}
+
+/// Marks `store_dir` as kept for the client's device after logout.
+pub(crate) fn preserve_device(store_dir: &Path, client: &Client) -> Result<(), String> {
+    let preserved = PreservedDevice {
+        homeserver: client.homeserver().to_string(),
+        user_id: client.user_id().ok_or("Client has no session to keep")?.to_owned(),
+        device_id: client.device_id().ok_or("Client has no session to keep")?.to_owned(),
+    };
+    let json = serde_json::to_string(&preserved)
+        .map_err(|e| format!("Failed to serialize device: {}", e))?;
+    fs::write(store_dir.join(PRESERVED_DEVICE_FILE), json).map_err(|e| format!("Failed to keep device: {}", e))
+}
+
+/// The device kept in `store_dir` if it belongs to `username` on
+/// `homeserver`. `username` may be a localpart or a full user ID.
+pub(crate) fn preserved_device(store_dir: &Path, homeserver: &str, username: &str) -> Option<PreservedDevice> {
+    let json = fs::read_to_string(store_dir.join(PRESERVED_DEVICE_FILE)).ok()?;
+    let preserved: PreservedDevice = serde_json::from_str(&json).ok()?;
+
+    let same_server = Url::parse(homeserver.trim()).ok()? == Url::parse(&preserved.homeserver).ok()?;
+    let username = username.trim();
+    let same_user = username == preserved.user_id.as_str() || username == preserved.user_id.localpart();
+    (same_server && same_user).then_some(preserved)
+}
+
+/// Called once the kept device is logged in again.
+pub(crate) fn clear_preserved_device(store_dir: &Path) {
+    let path = store_dir.join(PRESERVED_DEVICE_FILE);
+    if path.exists() {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Failed to remove preserved device marker: {}", e);
+        }
+    }
+}
+
+/// The session directory of the saved session, if there is one.
+pub(crate) fn saved_store_dir(data_dir: &Path) -> Option<PathBuf> {
+    let json = fs::read_to_string(data_dir.join(SESSION_FILE)).ok()?;
+    let stored: StoredSession = serde_json::from_str(&json).ok()?;
+    Some(stored.store_dir)
+}
+
+/// A client for the saved session without its store, for logging the
+/// session out when its store can't be opened.
+pub(crate) async fn saved_session_client(data_dir: &Path) -> Option<Client> {
+    let json = fs::read_to_string(data_dir.join(SESSION_FILE)).ok()?;
+    let stored: StoredSession = serde_json::from_str(&json).ok()?;
+    let client = Client::builder()
+        .homeserver_url(&stored.homeserver)
+        .build()
+        .await
+        .ok()?;
+    client
+        .matrix_auth()
+        .restore_session(stored.session, RoomLoadSettings::default())
+        .await
+        .ok()?;
+    Some(client)
+}
+
+/// Rebuilds the client from the saved session and its sqlite store, without
+/// touching the network. Returns `None` when there is nothing to restore.
+pub(crate) async fn restore_saved_session(state: &MatrixState) -> Result<Option<Client>, String> {
+    let path = state.data_dir.join(SESSION_FILE);
+    let Ok(json) = fs::read_to_string(&path) else {
+        return Ok(None);
+    };
+
+    let stored: StoredSession = match serde_json::from_str(&json) {
+        Ok(stored) => stored,
+        Err(e) => {
+            warn!("Ignoring unreadable saved session: {}", e);
+            clear_saved_session(&state.data_dir);
+            return Ok(None);
+        }
+    };
+
+    info!("Restoring session for {}", stored.session.meta.user_id);
+
+    // Before migrations, which open the stores too.
+    if stored.store_dir.exists() {
+        lock_store(state, &stored.store_dir).await?;
+    }
+    let key = store_key(state, &stored.store_dir).await?;
+    let store_dir = prepare_store(
+        &state.app,
+        &state.data_dir,
+        stored.session.meta.user_id.as_str(),
+        &stored.store_dir,
+        key.as_ref(),
+    )
+    .await?;
+    lock_store(state, &store_dir).await?;
+    state.is_guest.store(is_guest_store(&store_dir), Ordering::SeqCst);
+
+    let client = Client::builder()
+        .homeserver_url(&stored.homeserver)
+        .sqlite_store_with_config_and_cache_path(SqliteStoreConfig::new(&store_dir).key(key.as_ref()), None::<&Path>)
+        .with_enable_share_history_on_invite(true)
+        .build()
+        .await
+        .map_err(|e| format!("Failed to restore client: {}", e))?;
+
+    install_event_handlers(&client, state).await;
+
+    client
+        .matrix_auth()
+        .restore_session(stored.session, RoomLoadSettings::default())
+        .await
+        .map_err(|e| format!("Failed to restore session: {}", e))?;
+
+    refresh_client_config(&state.data_dir, &state.client_config, &client, false);
+    load_settings(&state.data_dir, &state.settings, &client).await;
+    watch_identity_changes(&state.app, &state.identity_watcher, &client).await;
+
+    if let Some(user_id) = client.user_id() {
+        load_view_states(state, user_id.as_str()).await;
+        load_sent_transactions(state, user_id.as_str()).await;
+        load_sync_gaps(state, user_id.as_str()).await;
+    }
+
+    let user_id = client.user_id().map(|u| u.to_string());
+    *state.client.write().await = Some(client.clone());
+    *state.user_id.write().await = user_id;
+
+    Ok(Some(client))
+}
@@ -0,0 +1,194 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tauri::State;
+use tokio::sync::RwLock;
+use tracing::warn;
+
+use crate::auth::sanitize_user_id;
+use crate::local_retention::prune_if_narrowed;
+use crate::media_cache::DEFAULT_MEDIA_CACHE_LIMIT;
+use crate::outbound::OutboundLimits;
+use crate::receipts::ReadReceiptMode;
+use crate::state::MatrixState;
+use crate::threads::ThreadNotificationMode;
+
+const SETTINGS_FILE: &str = "settings.json";
+const DEFAULT_MESSAGE_BODY_LIMIT: usize = 16 * 1024;
+
+/// Per-account preferences, stored in the session directory.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Settings {
+    /// Byte cap for the on-disk media cache.
+    pub media_cache_limit: u64,
+    /// Whether `send_message` expands `:shortcodes:` when the caller doesn't
+    /// say.
+    pub expand_emoji_shortcodes: bool,
+    /// Whether the latest messages of recent DMs are fetched in the
+    /// background after login; worth turning off on metered connections.
+    pub prefetch_dm_previews: bool,
+    /// Thread notification mode per room ID, for rooms not on the default
+    /// of notifying for all threads.
+    pub thread_notifications: HashMap<String, ThreadNotificationMode>,
+    /// Policy list room IDs linked to each room ID, whose user rules are
+    /// shown on its members.
+    pub policy_lists: HashMap<String, Vec<String>>,
+    /// Bytes of a message body sent to the timeline; longer ones are cut
+    /// and marked `truncated`.
+    pub message_body_limit: usize,
+    /// Enables developer tools such as `raw_api_request`.
+    pub developer_mode: bool,
+    /// Days of history kept in the local message index and media cache;
+    /// older entries are pruned daily. `None` keeps everything.
+    pub local_history_retention_days: Option<u32>,
+    /// Whether messages of encrypted rooms may be stored in plaintext in the
+    /// local message index. Off until the user opts in.
+    pub index_encrypted_rooms: bool,
+    /// Tags the DMs `get_merged_dms` merges into another room as low
+    /// priority, so each contact shows once in the room list.
+    pub deprioritize_merged_dms: bool,
+    /// How fast messages, invites and moderation actions are sent.
+    pub outbound_limits: OutboundLimits,
+    /// When each notification snooze ends, in milliseconds since the epoch,
+    /// by room ID or `global`. Local only; push rules are left alone.
+    pub notification_snoozes: BTreeMap<String, u64>,
+    /// Which read receipts are sent when marking rooms read.
+    pub send_read_receipts: ReadReceiptMode,
+    /// Whether `set_typing` tells the room the user is typing.
+    pub send_typing_notifications: bool,
+    /// Keys this version doesn't know, kept so that saving doesn't drop
+    /// settings written by a newer version.
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            media_cache_limit: DEFAULT_MEDIA_CACHE_LIMIT,
+            expand_emoji_shortcodes: false,
+            prefetch_dm_previews: true,
+            thread_notifications: HashMap::new(),
+            policy_lists: HashMap::new(),
+            message_body_limit: DEFAULT_MESSAGE_BODY_LIMIT,
+            developer_mode: false,
+            local_history_retention_days: None,
+            index_encrypted_rooms: false,
+            deprioritize_merged_dms: false,
+            outbound_limits: OutboundLimits::default(),
+            notification_snoozes: BTreeMap::new(),
+            send_read_receipts: ReadReceiptMode::Public,
+            send_typing_notifications: true,
+            other: Map::new(),
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn get_settings(state: State<'_, MatrixState>) -> Result<Settings, String> {
+    if state.client.read().await.is_none() {
+        return Err("Not logged in".to_string());
+    }
+
+    Ok(state.settings.read().await.clone())
+}
+
+/// Merges `patch` into the settings and saves them. Objects are merged key by
+/// key; `null` resets a setting to its default.
+#[tauri::command]
+pub async fn update_settings(state: State<'_, MatrixState>, patch: Value) -> Result<Settings, String> {
+    if !patch.is_object() {
+        return Err("Settings must be a JSON object".to_string());
+    }
+    let path = settings_path(&state).await?;
+
+    let mut settings = state.settings.write().await;
+    let mut merged = serde_json::to_value(&*settings)
+        .map_err(|e| format!("Failed to serialize settings: {}", e))?;
+    merge(&mut merged, patch);
+    let updated: Settings =
+        serde_json::from_value(merged).map_err(|e| format!("Invalid settings: {}", e))?;
+
+    save_settings(&path, &updated)?;
+    let previous = std::mem::replace(&mut *settings, updated.clone());
+    drop(settings);
+    prune_if_narrowed(&state, &previous, &updated).await;
+
+    Ok(updated)
+}
+
+/// Applies `update` to the settings and saves them, for commands that change
+/// a single knob.
+pub(crate) async fn change_settings(
+    state: &MatrixState,
+    update: impl FnOnce(&mut Settings),
+) -> Result<Settings, String> {
+    let path = settings_path(state).await?;
+
+    let mut settings = state.settings.write().await;
+    let mut updated = settings.clone();
+    update(&mut updated);
+
+    save_settings(&path, &updated)?;
+    *settings = updated.clone();
+
+    Ok(updated)
+}
+
+/// Loads the account's settings into `settings` after login or restore. A
+/// missing or unreadable file gives the defaults; an unreadable one is kept
+/// next to it as `settings.json.bak` first, as the next save replaces it.
+pub(crate) async fn load_settings(data_dir: &Path, settings: &Arc<RwLock<Settings>>, client: &Client) {
+    let Some(user_id) = client.user_id() else {
+        return;
+    };
+    let path = data_dir.join(sanitize_user_id(user_id.as_str())).join(SETTINGS_FILE);
+
+    let loaded = match fs::read(&path) {
+        Ok(json) => serde_json::from_slice(&json).unwrap_or_else(|e| {
+            let backup = path.with_extension("json.bak");
+            warn!("Ignoring unreadable settings, keeping them as {:?}: {}", backup, e);
+            if let Err(e) = fs::rename(&path, &backup) {
+                warn!("Failed to back up unreadable settings: {}", e);
+            }
+            Settings::default()
+        }),
+        Err(_) => Settings::default(),
+    };
+    *settings.write().await = loaded;
+}
+
+async fn settings_path(state: &MatrixState) -> Result<PathBuf, String> {
+    let user_id = state.user_id.read().await.clone().ok_or("Not logged in")?;
+    Ok(state.data_dir.join(sanitize_user_id(&user_id)).join(SETTINGS_FILE))
+}
+
+/// Writes to a temporary file first so a crash never leaves half a file.
+fn save_settings(path: &Path, settings: &Settings) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(settings)
+        .map_err(|e| format!("Failed to serialize settings: {}", e))?;
+    let temp = path.with_extension("json.tmp");
+    fs::write(&temp, json).map_err(|e| format!("Failed to save settings: {}", e))?;
+    fs::rename(&temp, path).map_err(|e| format!("Failed to save settings: {}", e))
+}
+
+fn merge(target: &mut Value, patch: Value) {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(&key);
+                } else {
+                    merge(target.entry(key).or_insert(Value::Null), value);
+                }
+            }
+        }
+        (target, patch) => *target = patch,
+    }
+}
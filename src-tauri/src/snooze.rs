@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedRoomId, RoomId};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager, State};
+use tracing::{info, warn};
+
+use crate::settings::{change_settings, Settings};
+use crate::state::MatrixState;
+
+/// The snooze scope that quiets every room.
+pub const GLOBAL_SNOOZE: &str = "global";
+
+/// A paused scope: a room ID, or `global` for all rooms.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NotificationSnooze {
+    pub scope: String,
+    /// When notifications resume, in milliseconds since the epoch.
+    pub until: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NotificationSettings {
+    /// Snoozes that haven't lapsed yet, soonest to end first.
+    pub snoozes: Vec<NotificationSnooze>,
+}
+
+/// Payload of `matrix://snooze-expired`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SnoozeExpired {
+    pub scope: String,
+}
+
+fn now() -> u64 {
+    MilliSecondsSinceUnixEpoch::now().get().into()
+}
+
+/// Whether notifications for `room_id` are snoozed, by a snooze of the room
+/// or a global one.
+pub(crate) fn is_snoozed(settings: &Settings, room_id: &RoomId) -> bool {
+    snoozed_at(&settings.notification_snoozes, room_id, now())
+}
+
+fn snoozed_at(snoozes: &BTreeMap<String, u64>, room_id: &RoomId, now: u64) -> bool {
+    [GLOBAL_SNOOZE, room_id.as_str()]
+        .iter()
+        .any(|scope| snoozes.get(*scope).is_some_and(|until| *until > now))
+}
+
+/// Pauses notifications for `duration_minutes`, in the room given as
+/// `scope` or everywhere with `global`. Snoozing again replaces the end
+/// time. This only quiets this app: push rules are left alone, so other
+/// devices keep notifying. `matrix://snooze-expired` is emitted when it
+/// lapses.
+#[tauri::command]
+pub async fn snooze_notifications(
+    state: State<'_, MatrixState>,
+    scope: String,
+    duration_minutes: u32,
+) -> Result<NotificationSnooze, String> {
+    if scope != GLOBAL_SNOOZE {
+        OwnedRoomId::try_from(scope.as_str()).map_err(|e| format!("Invalid room ID: {}", e))?;
+    }
+    if duration_minutes == 0 {
+        return Err("Snooze for at least a minute".to_string());
+    }
+
+    let until = now() + u64::from(duration_minutes) * 60 * 1000;
+    change_settings(&state, |settings| {
+        settings.notification_snoozes.insert(scope.clone(), until);
+    })
+    .await?;
+    info!("Snoozed notifications for {} for {} minutes", scope, duration_minutes);
+
+    schedule_expiry(state.app.clone(), Duration::from_secs(u64::from(duration_minutes) * 60));
+    Ok(NotificationSnooze { scope, until })
+}
+
+/// Ends a snooze early. Ending one that isn't active does nothing.
+#[tauri::command]
+pub async fn unsnooze_notifications(state: State<'_, MatrixState>, scope: String) -> Result<(), String> {
+    if !state.settings.read().await.notification_snoozes.contains_key(&scope) {
+        return Ok(());
+    }
+    change_settings(&state, |settings| {
+        settings.notification_snoozes.remove(&scope);
+    })
+    .await?;
+    Ok(())
+}
+
+/// The app's local notification settings: the active snoozes.
+#[tauri::command]
+pub async fn get_notification_settings(state: State<'_, MatrixState>) -> Result<NotificationSettings, String> {
+    if state.client.read().await.is_none() {
+        return Err("Not logged in".to_string());
+    }
+    let now = now();
+    let mut snoozes: Vec<NotificationSnooze> = state
+        .settings
+        .read()
+        .await
+        .notification_snoozes
+        .iter()
+        .filter(|(_, until)| **until > now)
+        .map(|(scope, until)| NotificationSnooze {
+            scope: scope.clone(),
+            until: *until,
+        })
+        .collect();
+    snoozes.sort_by_key(|snooze| snooze.until);
+    Ok(NotificationSettings { snoozes })
+}
+
+/// Wakes up once a snooze should have lapsed and ends it.
+fn schedule_expiry(app: AppHandle, after: Duration) {
+    tauri::async_runtime::spawn(async move {
+        tokio::time::sleep(after).await;
+        expire_snoozes(&app.state::<MatrixState>()).await;
+    });
+}
+
+/// Drops the snoozes that have lapsed and emits `matrix://snooze-expired`
+/// for each. Called when a snooze's timer fires and after each sync, which
+/// covers snoozes carried over from an earlier run of the app.
+pub(crate) async fn expire_snoozes(state: &MatrixState) {
+    let now = now();
+    let lapsed = |settings: &Settings| settings.notification_snoozes.values().any(|until| *until <= now);
+    if !lapsed(&*state.settings.read().await) {
+        return;
+    }
+
+    let mut expired = Vec::new();
+    let result = change_settings(state, |settings| {
+        expired = remove_lapsed(&mut settings.notification_snoozes, now);
+    })
+    .await;
+    if let Err(e) = result {
+        warn!("Failed to save lapsed snoozes: {}", e);
+        return;
+    }
+    for scope in expired {
+        info!("Snooze of {} lapsed", scope);
+        let _ = state.app.emit("matrix://snooze-expired", SnoozeExpired { scope });
+    }
+}
+
+/// Removes the snoozes that ended by `now` and returns their scopes.
+fn remove_lapsed(snoozes: &mut BTreeMap<String, u64>, now: u64) -> Vec<String> {
+    let mut lapsed = Vec::new();
+    snoozes.retain(|scope, until| {
+        if *until <= now {
+            lapsed.push(scope.clone());
+        }
+        *until > now
+    });
+    lapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::room_id;
+
+    fn snoozes(entries: &[(&str, u64)]) -> BTreeMap<String, u64> {
+        entries.iter().map(|(scope, until)| (scope.to_string(), *until)).collect()
+    }
+
+    #[test]
+    fn a_room_is_snoozed_by_its_own_or_a_global_snooze() {
+        let room = room_id!("!a:example.org");
+        let other = room_id!("!b:example.org");
+
+        let own = snoozes(&[("!a:example.org", 1000)]);
+        assert!(snoozed_at(&own, room, 999));
+        assert!(!snoozed_at(&own, other, 999));
+
+        let global = snoozes(&[(GLOBAL_SNOOZE, 1000)]);
+        assert!(snoozed_at(&global, room, 999));
+        assert!(snoozed_at(&global, other, 999));
+    }
+
+    #[test]
+    fn a_snooze_ends_at_its_end_time() {
+        let room = room_id!("!a:example.org");
+        let own = snoozes(&[("!a:example.org", 1000)]);
+        assert!(!snoozed_at(&own, room, 1000));
+        assert!(!snoozed_at(&snoozes(&[]), room, 0));
+    }
+
+    #[test]
+    fn only_lapsed_snoozes_are_removed() {
+        let mut active = snoozes(&[(GLOBAL_SNOOZE, 500), ("!a:example.org", 1000), ("!b:example.org", 2000)]);
+        assert_eq!(remove_lapsed(&mut active, 1000), ["!a:example.org", GLOBAL_SNOOZE]);
+        assert_eq!(active, snoozes(&[("!b:example.org", 2000)]));
+        assert!(remove_lapsed(&mut active, 1000).is_empty());
+    }
+}
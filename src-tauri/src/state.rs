@@ -1,25 +1,166 @@
+use matrix_sdk::ruma::{OwnedEventId, OwnedRoomId, OwnedSessionId, OwnedTransactionId};
 use matrix_sdk::Client;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::time::Instant;
+use tauri::AppHandle;
 use tokio::sync::RwLock;
 
+use crate::app_lock::{AppLock, AppLockState};
+use crate::autocomplete::{MemberIndex, RecentSenders};
+use crate::backfill::BackfillFlags;
+use crate::breadcrumbs::BreadcrumbQueue;
+use crate::client_config::ClientConfig;
+use crate::identity::IdentityServerSession;
+use crate::identity_changes::IdentityWatcherSlot;
+use crate::instance_lock::StoreLockSlot;
+use crate::local_retention::{LastPrune, RetentionTaskSlot};
+use crate::members::MemberFetches;
+use crate::messages::SentPlaintext;
+use crate::notifications::{ActiveRoom, NotificationTracker};
+use crate::outbound::OutboundPacer;
+use crate::own_activity::OwnActivity;
+use crate::prefetch::{PrefetchTaskSlot, PrefetchedPages};
+use crate::previews::UrlPreview;
+use crate::push_rules::{PushRulesCache, PushRulesFetchSlot};
+use crate::qr_login::QrLoginSlot;
+use crate::receipts::ReadMarkerQueue;
+use crate::room_renames::{RenameWatcherSlot, ReportedRoomNames};
+use crate::room_verification::UserVerificationSlot;
+use crate::rooms::{PageBoundaries, PageDays, RoomInfo};
+use crate::settings::Settings;
+use crate::sync_gaps::SyncGaps;
+use crate::sync_health::SyncHealthState;
+use crate::threepids::PendingThreePid;
+use crate::transactions::SentTransactions;
+use crate::threads::ThreadParticipation;
+use crate::uiaa::PendingAuth;
+use crate::verification::VerificationFlowSlot;
+use crate::view_state::ViewStateStore;
+
 pub struct MatrixState {
     pub client: Arc<RwLock<Option<Client>>>,
     pub user_id: Arc<RwLock<Option<String>>>,
+    /// `from_token` of the last backward page fetched per room.
     pub pagination_tokens: Arc<RwLock<HashMap<String, String>>>,
     pub data_dir: PathBuf,
-    pub verification_flow_id: Arc<RwLock<Option<String>>>,
+    /// For emitting events from sync handlers and background tasks.
+    pub app: AppHandle,
+    pub verification_flow: VerificationFlowSlot,
+    pub user_verification: UserVerificationSlot,
+    pub export_cancelled: Arc<AtomicBool>,
+    pub invites_cancelled: Arc<AtomicBool>,
+    pub url_previews: Arc<RwLock<HashMap<String, (Instant, UrlPreview)>>>,
+    pub read_markers: ReadMarkerQueue,
+    pub pending_threepids: Arc<RwLock<HashMap<OwnedSessionId, PendingThreePid>>>,
+    pub identity_server: Arc<RwLock<Option<IdentityServerSession>>>,
+    pub pending_auth: Arc<RwLock<HashMap<String, PendingAuth>>>,
+    pub breadcrumbs: BreadcrumbQueue,
+    pub room_cache: Arc<RwLock<HashMap<OwnedRoomId, RoomInfo>>>,
+    /// Timestamp of the newest timeline event seen per room during sync.
+    pub room_activity: Arc<RwLock<HashMap<OwnedRoomId, u64>>>,
+    pub qr_login: QrLoginSlot,
+    /// The logged-in account's preferences.
+    pub settings: Arc<RwLock<Settings>>,
+    /// Defaults from the homeserver's well-known, per logged-in account.
+    pub client_config: Arc<RwLock<Option<ClientConfig>>>,
+    /// Local time, in milliseconds, at which each event sent from this device
+    /// was queued.
+    pub sent_at: Arc<RwLock<HashMap<OwnedEventId, u64>>>,
+    pub identity_watcher: IdentityWatcherSlot,
+    pub rename_watcher: RenameWatcherSlot,
+    pub push_rules: PushRulesCache,
+    pub push_rules_fetch: PushRulesFetchSlot,
+    pub reported_room_names: ReportedRoomNames,
+    pub backfills: BackfillFlags,
+    /// Bodies of messages this device sent to encrypted rooms, by
+    /// transaction ID, shown in place of our own events that don't decrypt.
+    pub sent_plaintext: Arc<RwLock<HashMap<OwnedTransactionId, SentPlaintext>>>,
+    pub sent_transactions: SentTransactions,
+    pub sync_health: SyncHealthState,
+    pub sync_gaps: SyncGaps,
+    pub recent_senders: RecentSenders,
+    pub member_index: MemberIndex,
+    pub member_fetches: MemberFetches,
+    pub page_boundaries: PageBoundaries,
+    pub page_days: PageDays,
+    pub notifications: NotificationTracker,
+    pub active_room: ActiveRoom,
+    pub view_states: ViewStateStore,
+    pub own_activity: OwnActivity,
+    pub prefetched_pages: PrefetchedPages,
+    pub prefetch_task: PrefetchTaskSlot,
+    pub retention_task: RetentionTaskSlot,
+    pub last_prune: LastPrune,
+    pub thread_participation: ThreadParticipation,
+    pub outbound: OutboundPacer,
+    pub app_lock: AppLockState,
+    /// Whether the app lock is engaged, readable outside async code so
+    /// commands can be refused before they run.
+    pub app_locked: Arc<AtomicBool>,
+    pub store_lock: StoreLockSlot,
+    /// Whether the session is a read-only guest, readable outside async code
+    /// so commands can be refused before they run.
+    pub is_guest: Arc<AtomicBool>,
 }
 
 impl MatrixState {
-    pub fn new(data_dir: PathBuf) -> Self {
+    pub fn new(data_dir: PathBuf, app: AppHandle) -> Self {
+        let app_lock = AppLock::load(&data_dir);
+        let app_locked = app_lock.is_enabled();
         Self {
             client: Arc::new(RwLock::new(None)),
             user_id: Arc::new(RwLock::new(None)),
             pagination_tokens: Arc::new(RwLock::new(HashMap::new())),
             data_dir,
-            verification_flow_id: Arc::new(RwLock::new(None)),
+            app,
+            verification_flow: Arc::new(RwLock::new(None)),
+            user_verification: Arc::new(RwLock::new(None)),
+            export_cancelled: Arc::new(AtomicBool::new(false)),
+            invites_cancelled: Arc::new(AtomicBool::new(false)),
+            url_previews: Arc::new(RwLock::new(HashMap::new())),
+            read_markers: Arc::new(RwLock::new(HashMap::new())),
+            pending_threepids: Arc::new(RwLock::new(HashMap::new())),
+            identity_server: Arc::new(RwLock::new(None)),
+            pending_auth: Arc::new(RwLock::new(HashMap::new())),
+            breadcrumbs: Arc::new(RwLock::new(Default::default())),
+            room_cache: Arc::new(RwLock::new(HashMap::new())),
+            room_activity: Arc::new(RwLock::new(HashMap::new())),
+            qr_login: Arc::new(RwLock::new(None)),
+            settings: Arc::new(RwLock::new(Settings::default())),
+            client_config: Arc::new(RwLock::new(None)),
+            sent_at: Arc::new(RwLock::new(HashMap::new())),
+            identity_watcher: Arc::new(RwLock::new(None)),
+            rename_watcher: Arc::new(RwLock::new(None)),
+            push_rules: Arc::new(RwLock::new(None)),
+            push_rules_fetch: Arc::new(RwLock::new(None)),
+            reported_room_names: Arc::new(RwLock::new(HashMap::new())),
+            backfills: Arc::new(RwLock::new(HashMap::new())),
+            sent_plaintext: Arc::new(RwLock::new(HashMap::new())),
+            sent_transactions: Arc::new(RwLock::new(HashMap::new())),
+            sync_health: Arc::new(RwLock::new(Default::default())),
+            sync_gaps: Arc::new(RwLock::new(HashMap::new())),
+            recent_senders: Arc::new(RwLock::new(HashMap::new())),
+            member_index: Arc::new(RwLock::new(HashMap::new())),
+            member_fetches: Arc::new(RwLock::new(HashMap::new())),
+            page_boundaries: Arc::new(RwLock::new(HashMap::new())),
+            page_days: Arc::new(RwLock::new(HashMap::new())),
+            notifications: Arc::new(RwLock::new(HashMap::new())),
+            active_room: Arc::new(RwLock::new(None)),
+            view_states: Arc::new(RwLock::new(Default::default())),
+            own_activity: Arc::new(RwLock::new(HashMap::new())),
+            prefetched_pages: Arc::new(RwLock::new(HashMap::new())),
+            prefetch_task: Arc::new(RwLock::new(None)),
+            retention_task: Arc::new(RwLock::new(None)),
+            last_prune: Arc::new(RwLock::new(None)),
+            thread_participation: Arc::new(RwLock::new(HashMap::new())),
+            outbound: Arc::new(RwLock::new(Default::default())),
+            app_lock: Arc::new(RwLock::new(app_lock)),
+            app_locked: Arc::new(AtomicBool::new(app_locked)),
+            store_lock: Arc::new(RwLock::new(None)),
+            is_guest: Arc::new(AtomicBool::new(false)),
         }
     }
 }
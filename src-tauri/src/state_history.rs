@@ -0,0 +1,127 @@
+use matrix_sdk::room::MessagesOptions;
+use matrix_sdk::ruma::{uint, OwnedRoomId};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::State;
+
+use crate::state::MatrixState;
+
+/// State events whose history can be audited.
+const AUDITED_EVENT_TYPES: [&str; 3] = ["m.room.name", "m.room.topic", "m.room.power_levels"];
+/// How many changes to collect when the caller doesn't say.
+const DEFAULT_MAX_EVENTS: usize = 100;
+/// Largest `prev_content` passed on. It comes from the server's unsigned
+/// data, which the event size limit doesn't cover.
+const MAX_PREV_CONTENT_BYTES: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StateChange {
+    pub event_id: Option<String>,
+    pub sender: String,
+    pub timestamp: u64,
+    /// The event content before the change, or `None` for the first one.
+    pub old_value: Option<Value>,
+    pub new_value: Value,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StateHistory {
+    pub event_type: String,
+    /// Oldest change first.
+    pub changes: Vec<StateChange>,
+    /// Set when `max_events` was reached before the start of the room.
+    pub truncated: bool,
+}
+
+/// The parts of a state event we need, read straight from the JSON so the
+/// same code handles every audited type.
+#[derive(Deserialize)]
+struct RawStateEvent {
+    event_id: Option<String>,
+    sender: String,
+    origin_server_ts: u64,
+    state_key: Option<String>,
+    content: Value,
+    #[serde(default)]
+    unsigned: RawUnsigned,
+}
+
+#[derive(Deserialize, Default)]
+struct RawUnsigned {
+    prev_content: Option<Value>,
+}
+
+/// Lists who changed the room's name, topic or power levels and when, by
+/// paging back through the room's history filtered to `event_type`. Stops
+/// after `max_events` changes.
+#[tauri::command]
+pub async fn get_room_state_history(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    event_type: String,
+    max_events: Option<usize>,
+) -> Result<StateHistory, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    if !AUDITED_EVENT_TYPES.contains(&event_type.as_str()) {
+        return Err(format!("No history available for {}", event_type));
+    }
+    let max_events = max_events.unwrap_or(DEFAULT_MAX_EVENTS);
+
+    let mut changes = Vec::new();
+    let mut token: Option<String> = None;
+    let mut truncated = false;
+
+    'pages: loop {
+        let mut options = MessagesOptions::backward().from(token.as_deref());
+        options.limit = uint!(50);
+        options.filter.types = Some(vec![event_type.clone()]);
+
+        let response = room
+            .messages(options)
+            .await
+            .map_err(|e| format!("Failed to fetch room history: {}", e))?;
+
+        for timeline_event in &response.chunk {
+            let Ok(event) = timeline_event.raw().deserialize_as_unchecked::<RawStateEvent>() else {
+                continue;
+            };
+            if event.state_key.as_deref() != Some("") {
+                continue;
+            }
+            if changes.len() >= max_events {
+                truncated = true;
+                break 'pages;
+            }
+            changes.push(StateChange {
+                event_id: event.event_id,
+                sender: event.sender,
+                timestamp: event.origin_server_ts,
+                old_value: event
+                    .unsigned
+                    .prev_content
+                    .filter(|prev| prev.to_string().len() <= MAX_PREV_CONTENT_BYTES),
+                new_value: event.content,
+            });
+        }
+
+        if response.chunk.is_empty() || response.end.is_none() {
+            break;
+        }
+        token = response.end;
+    }
+
+    changes.reverse();
+
+    Ok(StateHistory {
+        event_type,
+        changes,
+        truncated,
+    })
+}
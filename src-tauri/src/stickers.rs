@@ -0,0 +1,90 @@
+use matrix_sdk::ruma::events::room::ImageInfo;
+use matrix_sdk::ruma::events::sticker::StickerEventContent;
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedMxcUri, OwnedRoomId};
+use tauri::State;
+
+use crate::emoji::{load_image_packs, ImagePack};
+use crate::messages::record_sent;
+use crate::outbound::{paced, OutboundClass};
+use crate::state::MatrixState;
+
+/// The user's sticker packs plus, when `room_id` is given, that room's packs.
+#[tauri::command]
+pub async fn get_sticker_packs(
+    state: State<'_, MatrixState>,
+    room_id: Option<String>,
+) -> Result<Vec<ImagePack>, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room = match room_id {
+        Some(room_id) => {
+            let room_id: OwnedRoomId = room_id
+                .parse()
+                .map_err(|e| format!("Invalid room ID: {}", e))?;
+            Some(client.get_room(&room_id).ok_or("Room not found")?)
+        }
+        None => None,
+    };
+
+    load_image_packs(client, room.as_ref(), "sticker").await
+}
+
+/// Sends the sticker `shortcode` from the pack identified by `pack_source`
+/// ("user" or the room id holding the pack) and `pack_state_key`.
+#[tauri::command]
+pub async fn send_sticker(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    pack_source: String,
+    pack_state_key: Option<String>,
+    shortcode: String,
+) -> Result<String, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let pack_room = if pack_source == "user" {
+        None
+    } else {
+        let pack_room_id: OwnedRoomId = pack_source
+            .parse()
+            .map_err(|e| format!("Invalid pack room ID: {}", e))?;
+        Some(client.get_room(&pack_room_id).ok_or("Sticker pack room not found")?)
+    };
+
+    let packs = load_image_packs(client, pack_room.as_ref(), "sticker").await?;
+    let pack = packs
+        .into_iter()
+        .find(|p| p.source == pack_source && p.state_key == pack_state_key)
+        .ok_or("Sticker pack not found")?;
+    let image = pack
+        .images
+        .get(&shortcode)
+        .ok_or("Sticker not found in pack")?;
+
+    let info: ImageInfo = image
+        .info
+        .clone()
+        .and_then(|info| serde_json::from_value(info).ok())
+        .unwrap_or_default();
+
+    let body = image.body.clone().unwrap_or_else(|| shortcode.clone());
+    let content = StickerEventContent::new(body, info, OwnedMxcUri::from(image.url.as_str()));
+
+    let queued_at = MilliSecondsSinceUnixEpoch::now();
+    let description = format!("Sticker to {}", room.room_id());
+    let response = paced(&state, OutboundClass::Messages, description, |config| {
+        room.send(content.clone()).with_request_config(config)
+    })
+    .await
+    .map_err(|e| format!("Failed to send sticker: {}", e))?;
+
+    record_sent(&state, &response.event_id, queued_at).await;
+
+    Ok(response.event_id.to_string())
+}
@@ -0,0 +1,295 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use matrix_sdk::{SqliteCryptoStore, SqliteEventCacheStore, SqliteMediaStore, SqliteStateStore};
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter};
+use tracing::{info, warn};
+
+use crate::auth::sanitize_user_id;
+use crate::errors::CommandError;
+use crate::local_store::{CRYPTO_DB, EVENT_CACHE_DB, MEDIA_DB, STATE_DB};
+
+const STORE_META_FILE: &str = "store_meta.json";
+/// Version of the `sanitize_user_id` naming scheme. Bump it when the scheme
+/// changes; directories are then renamed on the next start.
+const SANITIZE_SCHEME_VERSION: u32 = 1;
+/// Schema version of each SDK database this build's matrix-sdk writes.
+const STORE_VERSIONS: [(&str, u8); 4] = [(STATE_DB, 14), (CRYPTO_DB, 13), (EVENT_CACHE_DB, 13), (MEDIA_DB, 2)];
+
+/// `store_meta.json`: what wrote a session directory.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct StoreMeta {
+    pub sanitize_scheme: u32,
+    /// Schema version of each SDK database, by file name.
+    pub store_versions: BTreeMap<String, u8>,
+    pub app_version: String,
+}
+
+/// Payload of `matrix://store-migration`.
+///
+/// `stage` is one of `renaming`, `migrating` (with `store` set), `done` or
+/// `failed`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoreMigrationProgress {
+    pub stage: String,
+    pub store: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Gets a session directory ready to open before restoring: renames it if
+/// the naming scheme changed, refuses stores written by a newer version of
+/// the app, and runs the SDK's schema migrations one store at a time so
+/// progress can be shown. Returns the directory to open.
+pub(crate) async fn prepare_store(
+    app: &AppHandle,
+    data_dir: &Path,
+    user_id: &str,
+    store_dir: &Path,
+    key: Option<&[u8; 32]>,
+) -> Result<PathBuf, String> {
+    let emit = |stage: &str, store: Option<&str>, error: Option<String>| {
+        let _ = app.emit(
+            "matrix://store-migration",
+            StoreMigrationProgress {
+                stage: stage.to_string(),
+                store: store.map(str::to_string),
+                error,
+            },
+        );
+    };
+
+    let result: Result<PathBuf, String> = async {
+        if store_dir != session_dir(data_dir, user_id) {
+            emit("renaming", None, None);
+        }
+        let dir = migrate_dir_name(data_dir, user_id, store_dir)?;
+        check_versions(&dir)?;
+
+        for (name, version) in STORE_VERSIONS {
+            if db_version(&dir.join(name)).is_none_or(|current| current >= version) {
+                continue;
+            }
+            emit("migrating", Some(name), None);
+            info!("Migrating {} to version {}", name, version);
+            migrate_store(&dir, name, key).await?;
+        }
+
+        write_store_meta(&dir)?;
+        Ok(dir)
+    }
+    .await;
+
+    match &result {
+        Ok(_) => emit("done", None, None),
+        Err(e) => emit("failed", None, Some(e.clone())),
+    }
+    result
+}
+
+/// Records which scheme and store versions wrote `dir`, after login and
+/// after migrations.
+pub(crate) fn write_store_meta(dir: &Path) -> Result<(), String> {
+    let meta = StoreMeta {
+        sanitize_scheme: SANITIZE_SCHEME_VERSION,
+        store_versions: STORE_VERSIONS
+            .iter()
+            .filter_map(|(name, _)| Some((name.to_string(), db_version(&dir.join(name))?)))
+            .collect(),
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    let json = serde_json::to_string_pretty(&meta)
+        .map_err(|e| format!("Failed to serialize store metadata: {}", e))?;
+    fs::write(dir.join(STORE_META_FILE), json).map_err(|e| format!("Failed to save store metadata: {}", e))
+}
+
+fn read_store_meta(dir: &Path) -> Option<StoreMeta> {
+    let json = fs::read_to_string(dir.join(STORE_META_FILE)).ok()?;
+    serde_json::from_str(&json)
+        .inspect_err(|e| warn!("Ignoring unreadable store metadata: {}", e))
+        .ok()
+}
+
+fn session_dir(data_dir: &Path, user_id: &str) -> PathBuf {
+    data_dir.join(sanitize_user_id(user_id))
+}
+
+/// Moves a session directory named under an older scheme to the current
+/// name. Leaves it where it is if something already has that name.
+fn migrate_dir_name(data_dir: &Path, user_id: &str, store_dir: &Path) -> Result<PathBuf, String> {
+    let current = session_dir(data_dir, user_id);
+    if store_dir == current || !store_dir.exists() {
+        return Ok(current);
+    }
+    if current.exists() {
+        warn!("Not renaming {:?}: {:?} already exists", store_dir, current);
+        return Ok(store_dir.to_path_buf());
+    }
+
+    info!("Renaming session directory {:?} to {:?}", store_dir, current);
+    fs::rename(store_dir, &current).map_err(|e| format!("Failed to rename session directory: {}", e))?;
+    Ok(current)
+}
+
+/// Refuses directories written by a newer version of the app, whose stores
+/// this build would misread or corrupt.
+fn check_versions(dir: &Path) -> Result<(), String> {
+    let newer = |found: String| -> String {
+        CommandError::new(
+            "STORE_FROM_NEWER_VERSION",
+            "This session was saved by a newer version of the app. Update the app to open it.",
+        )
+        .with_details(serde_json::json!({ "found": found }))
+        .into()
+    };
+
+    if let Some(meta) = read_store_meta(dir) {
+        if meta.sanitize_scheme > SANITIZE_SCHEME_VERSION
+            || version_parts(&meta.app_version) > version_parts(env!("CARGO_PKG_VERSION"))
+        {
+            return Err(newer(meta.app_version));
+        }
+    }
+    for (name, version) in STORE_VERSIONS {
+        if let Some(current) = db_version(&dir.join(name)).filter(|current| *current > version) {
+            return Err(newer(format!("{} version {}", name, current)));
+        }
+    }
+    Ok(())
+}
+
+fn version_parts(version: &str) -> Vec<u64> {
+    version.split('.').map(|part| part.parse().unwrap_or(0)).collect()
+}
+
+/// The schema version the SDK recorded in a database, or `None` when it
+/// doesn't exist or can't be read.
+fn db_version(db: &Path) -> Option<u8> {
+    if !db.exists() {
+        return None;
+    }
+    let connection = Connection::open_with_flags(db, OpenFlags::SQLITE_OPEN_READ_ONLY).ok()?;
+    let version: Option<Vec<u8>> = connection
+        .query_row("SELECT value FROM kv WHERE key = 'version'", [], |row| row.get(0))
+        .optional()
+        .ok()?;
+    match version.as_deref() {
+        Some([version]) => Some(*version),
+        _ => None,
+    }
+}
+
+/// Whether a database's private data is encrypted, which the SDK records by
+/// storing its cipher.
+pub(crate) fn has_cipher(db: &Path) -> bool {
+    let Ok(connection) = Connection::open_with_flags(db, OpenFlags::SQLITE_OPEN_READ_ONLY) else {
+        return false;
+    };
+    connection
+        .query_row("SELECT 1 FROM kv WHERE key = 'cipher'", [], |_| Ok(()))
+        .optional()
+        .is_ok_and(|row| row.is_some())
+}
+
+/// Opening an SDK store runs its migrations; the store is closed again
+/// straight after.
+async fn migrate_store(dir: &Path, name: &str, key: Option<&[u8; 32]>) -> Result<(), String> {
+    let result = match name {
+        STATE_DB => SqliteStateStore::open_with_key(dir, key).await.map(drop),
+        CRYPTO_DB => SqliteCryptoStore::open_with_key(dir, key).await.map(drop),
+        EVENT_CACHE_DB => SqliteEventCacheStore::open_with_key(dir, key).await.map(drop),
+        MEDIA_DB => SqliteMediaStore::open_with_key(dir, key).await.map(drop),
+        _ => Ok(()),
+    };
+    result.map_err(|e| format!("Failed to migrate {}: {}", name, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scratch data directory, removed when dropped.
+    struct DataDir(PathBuf);
+
+    impl DataDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("store-meta-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for DataDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn fixture_db(path: &Path, version: u8) {
+        let connection = Connection::open(path).unwrap();
+        connection
+            .execute_batch("CREATE TABLE kv (key TEXT PRIMARY KEY NOT NULL, value BLOB NOT NULL);")
+            .unwrap();
+        connection
+            .execute("INSERT INTO kv VALUES ('version', ?1)", [vec![version]])
+            .unwrap();
+    }
+
+    #[test]
+    fn renames_directory_from_older_scheme() {
+        let data = DataDir::new("rename");
+        let old = data.0.join("user-at-example.org");
+        fs::create_dir_all(&old).unwrap();
+        fs::write(old.join("settings.json"), "{}").unwrap();
+
+        let dir = migrate_dir_name(&data.0, "@user:example.org", &old).unwrap();
+
+        assert_eq!(dir, data.0.join("user_example.org"));
+        assert!(dir.join("settings.json").exists());
+        assert!(!old.exists());
+    }
+
+    #[test]
+    fn keeps_directory_in_current_scheme() {
+        let data = DataDir::new("current");
+        let current = data.0.join("user_example.org");
+        fs::create_dir_all(&current).unwrap();
+        fixture_db(&current.join(STATE_DB), 14);
+
+        let dir = migrate_dir_name(&data.0, "@user:example.org", &current).unwrap();
+        assert_eq!(dir, current);
+        check_versions(&dir).unwrap();
+
+        write_store_meta(&dir).unwrap();
+        let meta = read_store_meta(&dir).unwrap();
+        assert_eq!(meta.sanitize_scheme, SANITIZE_SCHEME_VERSION);
+        assert_eq!(meta.store_versions.get(STATE_DB), Some(&14));
+        assert_eq!(meta.app_version, env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn refuses_store_from_newer_sdk() {
+        let data = DataDir::new("newer-sdk");
+        fixture_db(&data.0.join(CRYPTO_DB), 200);
+
+        let error = check_versions(&data.0).unwrap_err();
+        assert!(error.contains("STORE_FROM_NEWER_VERSION"));
+    }
+
+    #[test]
+    fn refuses_store_from_newer_app() {
+        let data = DataDir::new("newer-app");
+        let meta = StoreMeta {
+            sanitize_scheme: SANITIZE_SCHEME_VERSION,
+            store_versions: BTreeMap::new(),
+            app_version: "999.0.0".to_string(),
+        };
+        fs::write(data.0.join(STORE_META_FILE), serde_json::to_string(&meta).unwrap()).unwrap();
+
+        let error = check_versions(&data.0).unwrap_err();
+        assert!(error.contains("STORE_FROM_NEWER_VERSION"));
+    }
+}
@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use matrix_sdk::deserialized_responses::TimelineEvent;
+use matrix_sdk::room::MessagesOptions;
+use matrix_sdk::ruma::{uint, OwnedRoomId};
+use matrix_sdk::store::StateStoreDataKey;
+use matrix_sdk::sync::SyncResponse;
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use tauri::{Emitter, State};
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
+
+use crate::auth::sanitize_user_id;
+use crate::backfill::index_gap_events;
+use crate::errors::CommandError;
+use crate::rooms::{insert_day_dividers, origin_server_ts, page_messages, Message};
+use crate::state::MatrixState;
+
+const SYNC_GAPS_FILE: &str = "sync_gaps.json";
+/// Pages `fill_gap` fetches per call; a longer gap is left open from where
+/// it stopped.
+const MAX_FILL_PAGES: usize = 5;
+
+/// History sync skipped per room, oldest first.
+pub type SyncGaps = Arc<RwLock<HashMap<OwnedRoomId, Vec<SyncGap>>>>;
+
+/// Events sync left out of a room when its timeline came back `limited`,
+/// typically after the app was closed for a while. Kept per account, so a
+/// gap outlives the run that found it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SyncGap {
+    pub info: GapInfo,
+    /// The newer end of the gap: the `prev_batch` of the limited timeline,
+    /// moved back as the gap is filled.
+    from: String,
+    /// The older end: the sync token we resumed from.
+    to: String,
+}
+
+/// A gap as the timeline shows it, and payload of `matrix://sync-gap`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GapInfo {
+    pub gap_id: String,
+    pub room_id: String,
+    /// The first event sync delivered after the gap.
+    pub after_event_id: String,
+    /// When that event was sent; everything missing is older.
+    pub until_timestamp: u64,
+    /// When the newest event seen before the gap was sent, if one was.
+    pub since_timestamp: Option<u64>,
+}
+
+/// What `fill_gap` fetched: messages to splice in at the gap, oldest first,
+/// and the part of the gap that is still missing, if any.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GapFill {
+    pub messages: Vec<Message>,
+    pub remaining: Option<GapInfo>,
+}
+
+/// The sync token the next sync resumes from, `None` before the first one.
+pub(crate) async fn resume_token(client: &Client) -> Option<String> {
+    match client.state_store().get_kv_data(StateStoreDataKey::SyncToken).await {
+        Ok(value) => value.and_then(|value| value.into_sync_token()),
+        Err(e) => {
+            warn!("Failed to read the sync token: {}", e);
+            None
+        }
+    }
+}
+
+/// Records a gap for every joined room whose timeline sync cut short. Only
+/// a resumed sync can leave one; `resumed_from` is the token it resumed
+/// from and `activity` the newest event per room seen before it.
+pub(crate) async fn record_sync_gaps(
+    state: &MatrixState,
+    response: &SyncResponse,
+    resumed_from: Option<&str>,
+    activity: &HashMap<OwnedRoomId, u64>,
+) {
+    let Some(to) = resumed_from else {
+        return;
+    };
+    let found = gaps_in_sync(response, to, activity);
+    if found.is_empty() {
+        return;
+    }
+    let mut gaps = state.sync_gaps.write().await;
+    for (room_id, gap) in found {
+        info!("Sync skipped history in {} before {}", room_id, gap.info.after_event_id);
+        let _ = state.app.emit("matrix://sync-gap", gap.info.clone());
+        gaps.entry(room_id).or_default().push(gap);
+    }
+    persist(state, &gaps).await;
+}
+
+fn gaps_in_sync(response: &SyncResponse, to: &str, activity: &HashMap<OwnedRoomId, u64>) -> Vec<(OwnedRoomId, SyncGap)> {
+    let mut gaps = Vec::new();
+    for (room_id, update) in &response.rooms.joined {
+        let timeline = &update.timeline;
+        let (true, Some(from), Some(first)) = (timeline.limited, &timeline.prev_batch, timeline.events.first()) else {
+            continue;
+        };
+        let Some(after_event_id) = first.event_id() else {
+            continue;
+        };
+        let info = GapInfo {
+            gap_id: format!("{}:{}", to, after_event_id),
+            room_id: room_id.to_string(),
+            after_event_id: after_event_id.to_string(),
+            until_timestamp: origin_server_ts(first),
+            since_timestamp: activity.get(room_id).copied(),
+        };
+        let gap = SyncGap {
+            info,
+            from: from.clone(),
+            to: to.to_string(),
+        };
+        gaps.push((room_id.clone(), gap));
+    }
+    gaps
+}
+
+/// What a page paged back through a gap's newer end did to it.
+#[derive(Debug, PartialEq)]
+enum GapReached {
+    /// The page went on past the gap's older end; nothing is missing.
+    Crossed,
+    /// The page holds the newest part of the gap, down to this event.
+    Narrowed { event_id: String, timestamp: u64 },
+}
+
+impl SyncGap {
+    /// Whether a page of `events`, newest first, reaches the gap. Paging
+    /// goes on from `end`, the page's end token.
+    fn reached(&self, events: &[TimelineEvent], end: Option<&str>) -> Option<GapReached> {
+        let after = events
+            .iter()
+            .position(|event| event.event_id().is_some_and(|id| id == self.info.after_event_id))?;
+        let older = &events[after + 1..];
+        let crossed = end.is_none()
+            || older
+                .iter()
+                .any(|event| self.info.since_timestamp.is_some_and(|since| origin_server_ts(event) <= since));
+        if crossed {
+            return Some(GapReached::Crossed);
+        }
+        let oldest = older.last().unwrap_or(&events[after]);
+        Some(GapReached::Narrowed {
+            event_id: oldest.event_id().map_or_else(|| self.info.after_event_id.clone(), |id| id.to_string()),
+            timestamp: origin_server_ts(oldest),
+        })
+    }
+
+    /// Moves the newer end back to `from`, past `oldest`, the oldest event
+    /// fetched from the gap so far.
+    fn narrow(&mut self, from: String, oldest: Option<(String, u64)>) {
+        self.from = from;
+        if let Some((event_id, timestamp)) = oldest {
+            self.info.after_event_id = event_id;
+            self.info.until_timestamp = timestamp;
+        }
+    }
+
+    /// Of the events fetched from the gap, those missing from the timeline:
+    /// not older than the newest event seen before it, and older than the
+    /// one it ends at.
+    fn missing<'a>(&self, events: &'a [TimelineEvent]) -> Vec<&'a TimelineEvent> {
+        events
+            .iter()
+            .filter(|event| self.info.since_timestamp.is_none_or(|since| origin_server_ts(event) > since))
+            .filter(|event| origin_server_ts(event) <= self.info.until_timestamp)
+            .filter(|event| event.event_id().is_none_or(|id| id != self.info.after_event_id))
+            .collect()
+    }
+}
+
+/// The open gap of `room_id` that a page of `events` reaches, with the
+/// token paging goes on from past it. The events of the gap the page holds
+/// stay in it: the gap shrinks to what is older, or closes when the page
+/// reached across it.
+pub(crate) async fn gap_in_page(
+    state: &MatrixState,
+    room_id: &OwnedRoomId,
+    events: &[TimelineEvent],
+    end: Option<&str>,
+) -> Option<(GapInfo, String)> {
+    let mut gaps = state.sync_gaps.write().await;
+    let room_gaps = gaps.get_mut(room_id)?;
+    let (index, reached) = room_gaps
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(index, gap)| Some((index, gap.reached(events, end)?)))?;
+
+    let found = match reached {
+        GapReached::Crossed => {
+            let gap = room_gaps.remove(index);
+            info!("Paging closed gap {} of {}", gap.info.gap_id, room_id);
+            None
+        }
+        GapReached::Narrowed { event_id, timestamp } => {
+            let gap = &mut room_gaps[index];
+            if let Some(end) = end {
+                gap.narrow(end.to_string(), Some((event_id, timestamp)));
+            }
+            Some((gap.info.clone(), gap.to.clone()))
+        }
+    };
+    if room_gaps.is_empty() {
+        gaps.remove(room_id);
+    }
+    persist(state, &gaps).await;
+    found
+}
+
+/// Fetches history missing at a gap, up to a few pages, for splicing into
+/// the timeline at the gap's entry. Messages are also added to the local
+/// message index when the room is indexed. The gap closes once reached
+/// across; until then the rest of it is returned to fill next.
+#[tauri::command]
+pub async fn fill_gap(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    gap_id: String,
+    tz_offset_minutes: Option<i32>,
+) -> Result<GapFill, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let gap = state
+        .sync_gaps
+        .read()
+        .await
+        .get(&room_id)
+        .and_then(|gaps| gaps.iter().find(|gap| gap.info.gap_id == gap_id).cloned())
+        .ok_or_else(|| String::from(CommandError::new("GAP_NOT_FOUND", "This gap was filled already")))?;
+
+    let mut events: Vec<TimelineEvent> = Vec::new();
+    let mut page_state = Vec::new();
+    let mut from = Some(gap.from.clone());
+    for _ in 0..MAX_FILL_PAGES {
+        let Some(token) = from.take() else {
+            break;
+        };
+        let mut options = MessagesOptions::backward().from(Some(token.as_str()));
+        options.to = Some(gap.to.clone());
+        options.limit = uint!(100);
+        let page = room
+            .messages(options)
+            .await
+            .map_err(|e| format!("Failed to fetch messages: {}", e))?;
+
+        let exhausted = page.chunk.is_empty();
+        let reached_since = page
+            .chunk
+            .iter()
+            .any(|event| gap.info.since_timestamp.is_some_and(|since| origin_server_ts(event) <= since));
+        events.extend(page.chunk);
+        page_state.extend(page.state);
+        if reached_since || exhausted {
+            break;
+        }
+        from = page.end.filter(|end| *end != token);
+    }
+    debug!("Fetched {} events into gap {} of {}", events.len(), gap_id, room_id);
+
+    // Events before the gap are in the timeline already.
+    let events = gap.missing(&events);
+    let oldest = events.iter().min_by_key(|event| origin_server_ts(event)).and_then(|event| {
+        Some((event.event_id()?.to_string(), origin_server_ts(event)))
+    });
+    if let Err(e) = index_gap_events(&state, &room, &events).await {
+        warn!("Failed to index the events of gap {}: {}", gap_id, e);
+    }
+
+    let (mut messages, _) = page_messages(&state, &room, &page_state, events, true).await;
+    insert_day_dividers(&mut messages, tz_offset_minutes.unwrap_or(0), None);
+
+    let mut gaps = state.sync_gaps.write().await;
+    let room_gaps = gaps.entry(room_id.clone()).or_default();
+    let remaining = match from {
+        Some(from) => room_gaps.iter_mut().find(|g| g.info.gap_id == gap_id).map(|g| {
+            g.narrow(from, oldest);
+            g.info.clone()
+        }),
+        None => {
+            room_gaps.retain(|g| g.info.gap_id != gap_id);
+            info!("Filled gap {} of {}", gap_id, room_id);
+            None
+        }
+    };
+    if room_gaps.is_empty() {
+        gaps.remove(&room_id);
+    }
+    persist(&state, &gaps).await;
+    drop(gaps);
+    // The prefetched first page may end at the gap.
+    state.prefetched_pages.write().await.remove(&room_id);
+
+    Ok(GapFill { messages, remaining })
+}
+
+/// Loads the gaps earlier runs left open after a session restore.
+pub(crate) async fn load_sync_gaps(state: &MatrixState, user_id: &str) {
+    let path = gaps_path(&state.data_dir, user_id);
+    let saved = match fs::read_to_string(&path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable sync gaps: {}", e);
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    };
+    *state.sync_gaps.write().await = saved;
+}
+
+async fn persist(state: &MatrixState, gaps: &HashMap<OwnedRoomId, Vec<SyncGap>>) {
+    let Some(user_id) = state.user_id.read().await.clone() else {
+        return;
+    };
+    if let Err(e) = save_gaps(&gaps_path(&state.data_dir, &user_id), gaps) {
+        warn!("{}", e);
+    }
+}
+
+fn gaps_path(data_dir: &Path, user_id: &str) -> PathBuf {
+    data_dir.join(sanitize_user_id(user_id)).join(SYNC_GAPS_FILE)
+}
+
+/// Writes to a temporary file first so a crash never leaves half a file.
+fn save_gaps(path: &Path, gaps: &HashMap<OwnedRoomId, Vec<SyncGap>>) -> Result<(), String> {
+    let json = serde_json::to_string(gaps).map_err(|e| format!("Failed to serialize sync gaps: {}", e))?;
+    let temp = path.with_extension("json.tmp");
+    fs::write(&temp, json).map_err(|e| format!("Failed to save sync gaps: {}", e))?;
+    fs::rename(&temp, path).map_err(|e| format!("Failed to save sync gaps: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::serde::Raw;
+    use matrix_sdk::sync::{JoinedRoomUpdate, Timeline};
+    use serde_json::json;
+
+    use super::*;
+
+    fn event(event_id: &str, timestamp: u64) -> TimelineEvent {
+        let json = json!({
+            "type": "m.room.message",
+            "event_id": event_id,
+            "sender": "@alice:example.org",
+            "origin_server_ts": timestamp,
+            "content": { "msgtype": "m.text", "body": event_id },
+        });
+        TimelineEvent::from_plaintext(Raw::new(&json).unwrap().cast_unchecked())
+    }
+
+    fn gap(since_timestamp: Option<u64>) -> SyncGap {
+        SyncGap {
+            info: GapInfo {
+                gap_id: "s1:$after".to_string(),
+                room_id: "!room:example.org".to_string(),
+                after_event_id: "$after".to_string(),
+                until_timestamp: 500,
+                since_timestamp,
+            },
+            from: "t500".to_string(),
+            to: "s1".to_string(),
+        }
+    }
+
+    fn joined(limited: bool, events: Vec<TimelineEvent>) -> JoinedRoomUpdate {
+        JoinedRoomUpdate {
+            timeline: Timeline {
+                limited,
+                prev_batch: Some("t500".to_string()),
+                events,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn limited_timelines_of_a_resumed_sync_leave_gaps() {
+        let room: OwnedRoomId = "!room:example.org".try_into().unwrap();
+        let other: OwnedRoomId = "!other:example.org".try_into().unwrap();
+        let mut response = SyncResponse::default();
+        response.rooms.joined.insert(room.clone(), joined(true, vec![event("$after", 500), event("$next", 600)]));
+        response.rooms.joined.insert(other, joined(false, vec![event("$other", 700)]));
+        let activity = HashMap::from([(room.clone(), 100)]);
+
+        let gaps = gaps_in_sync(&response, "s1", &activity);
+        assert_eq!(gaps.len(), 1);
+        let (room_id, gap) = &gaps[0];
+        assert_eq!(room_id, &room);
+        assert_eq!(gap.info.gap_id, "s1:$after");
+        assert_eq!(gap.info.after_event_id, "$after");
+        assert_eq!(gap.info.until_timestamp, 500);
+        assert_eq!(gap.info.since_timestamp, Some(100));
+        assert_eq!((gap.from.as_str(), gap.to.as_str()), ("t500", "s1"));
+    }
+
+    #[test]
+    fn page_without_the_gap_does_not_reach_it() {
+        let events = [event("$new", 900), event("$newer", 800)];
+        assert_eq!(gap(Some(100)).reached(&events, Some("t800")), None);
+    }
+
+    #[test]
+    fn page_into_the_gap_keeps_its_events_and_narrows_it() {
+        let events = [event("$next", 600), event("$after", 500), event("$gap2", 400), event("$gap1", 300)];
+        assert_eq!(
+            gap(Some(100)).reached(&events, Some("t300")),
+            Some(GapReached::Narrowed {
+                event_id: "$gap1".to_string(),
+                timestamp: 300,
+            })
+        );
+    }
+
+    #[test]
+    fn page_across_the_gap_closes_it() {
+        let events = [event("$after", 500), event("$gap", 300), event("$seen", 100)];
+        assert_eq!(gap(Some(100)).reached(&events, Some("t100")), Some(GapReached::Crossed));
+        // The start of the room closes it too.
+        let events = [event("$after", 500), event("$gap", 300)];
+        assert_eq!(gap(Some(100)).reached(&events, None), Some(GapReached::Crossed));
+    }
+
+    #[test]
+    fn partial_fill_moves_the_newer_end_back() {
+        let mut gap = gap(Some(100));
+        gap.narrow("t300".to_string(), Some(("$gap1".to_string(), 300)));
+        assert_eq!(gap.from, "t300");
+        assert_eq!(gap.info.after_event_id, "$gap1");
+        assert_eq!(gap.info.until_timestamp, 300);
+        // The gap keeps its ID and older end, so the timeline's entry for it
+        // stays put.
+        assert_eq!(gap.info.gap_id, "s1:$after");
+        assert_eq!(gap.to, "s1");
+    }
+
+    #[test]
+    fn fill_keeps_only_missing_events() {
+        let events = [event("$after", 500), event("$gap", 300), event("$seen", 100), event("$newer", 600)];
+        let missing: Vec<_> = gap(Some(100))
+            .missing(&events)
+            .iter()
+            .filter_map(|event| event.event_id())
+            .collect();
+        assert_eq!(missing, ["$gap"]);
+    }
+
+    #[test]
+    fn gaps_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("sync-gaps-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(SYNC_GAPS_FILE);
+        let room: OwnedRoomId = "!room:example.org".try_into().unwrap();
+        save_gaps(&path, &HashMap::from([(room.clone(), vec![gap(Some(100))])])).unwrap();
+
+        let saved: HashMap<OwnedRoomId, Vec<SyncGap>> = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved[&room][0].info.gap_id, "s1:$after");
+        assert_eq!(saved[&room][0].from, "t500");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
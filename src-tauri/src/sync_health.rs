@@ -0,0 +1,106 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use matrix_sdk::sync::SyncResponse;
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tokio::sync::RwLock;
+
+use crate::state::MatrixState;
+
+/// Failed syncs in a row after which `matrix://sync-degraded` is emitted.
+const DEGRADED_AFTER_FAILURES: u32 = 3;
+/// Window over which the average gap between syncs is computed.
+const GAP_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+pub type SyncHealthState = Arc<RwLock<SyncHealth>>;
+
+/// What `matrix_sync` has observed since login, for telling "messages
+/// arrive late" apart from "sync is failing".
+#[derive(Default)]
+pub struct SyncHealth {
+    last_success_at: Option<u64>,
+    last_duration_ms: Option<u64>,
+    consecutive_failures: u32,
+    last_error: Option<String>,
+    /// Completion times of the successful syncs within `GAP_WINDOW`.
+    recent_syncs: VecDeque<u64>,
+    events_processed: u64,
+}
+
+/// A snapshot of [`SyncHealth`], also the payload of `matrix://sync-degraded`.
+/// Times are milliseconds since the epoch, durations milliseconds.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SyncDiagnostics {
+    pub last_success_at: Option<u64>,
+    pub last_duration_ms: Option<u64>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    /// Over the past hour; `None` until two syncs succeeded in it.
+    pub average_gap_ms: Option<u64>,
+    /// Timeline and to-device events received since login.
+    pub events_processed: u64,
+    pub degraded: bool,
+}
+
+impl SyncHealth {
+    pub(crate) fn record_success(&mut self, duration: Duration, response: &SyncResponse) {
+        let now = now_ms();
+        self.last_success_at = Some(now);
+        self.last_duration_ms = Some(duration.as_millis() as u64);
+        self.consecutive_failures = 0;
+        self.events_processed += event_count(response);
+
+        self.recent_syncs.push_back(now);
+        let cutoff = now.saturating_sub(GAP_WINDOW.as_millis() as u64);
+        while self.recent_syncs.front().is_some_and(|&at| at < cutoff) {
+            self.recent_syncs.pop_front();
+        }
+    }
+
+    /// Records a failed sync and returns whether it is the one that crossed
+    /// the degraded threshold.
+    pub(crate) fn record_failure(&mut self, error: String) -> bool {
+        self.consecutive_failures += 1;
+        self.last_error = Some(error);
+        self.consecutive_failures == DEGRADED_AFTER_FAILURES
+    }
+
+    pub(crate) fn snapshot(&self) -> SyncDiagnostics {
+        let average_gap_ms = match (self.recent_syncs.front(), self.recent_syncs.back()) {
+            (Some(first), Some(last)) if self.recent_syncs.len() > 1 => {
+                Some((last - first) / (self.recent_syncs.len() as u64 - 1))
+            }
+            _ => None,
+        };
+
+        SyncDiagnostics {
+            last_success_at: self.last_success_at,
+            last_duration_ms: self.last_duration_ms,
+            consecutive_failures: self.consecutive_failures,
+            last_error: self.last_error.clone(),
+            average_gap_ms,
+            events_processed: self.events_processed,
+            degraded: self.consecutive_failures >= DEGRADED_AFTER_FAILURES,
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn get_sync_diagnostics(state: State<'_, MatrixState>) -> Result<SyncDiagnostics, String> {
+    Ok(state.sync_health.read().await.snapshot())
+}
+
+fn event_count(response: &SyncResponse) -> u64 {
+    let joined: usize = response.rooms.joined.values().map(|room| room.timeline.events.len()).sum();
+    let left: usize = response.rooms.left.values().map(|room| room.timeline.events.len()).sum();
+    (joined + left + response.to_device.len()) as u64
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
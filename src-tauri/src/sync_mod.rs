@@ -1,21 +1,266 @@
-use tauri::State;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tauri::{AppHandle, Emitter, State};
 use matrix_sdk::config::SyncSettings;
+use matrix_sdk::deserialized_responses::EncryptionInfo;
+use matrix_sdk::ruma::events::receipt::SyncReceiptEvent;
+use matrix_sdk::ruma::events::room::member::{MembershipState, SyncRoomMemberEvent};
+use matrix_sdk::ruma::events::room::tombstone::SyncRoomTombstoneEvent;
+use matrix_sdk::ruma::events::{AnyStrippedStateEvent, AnySyncStateEvent, AnySyncTimelineEvent};
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::OwnedUserId;
+use matrix_sdk::{Client, Room};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
 
+use crate::autocomplete::note_sender;
+use crate::invites::on_stripped_member;
+use crate::local_retention::start_retention_task;
+use crate::membership::on_own_membership;
+use crate::own_activity::on_own_message;
+use crate::prefetch::start_dm_prefetch;
+use crate::notifications::{on_notification, on_receipt};
+use crate::push_rules::track_push_rules;
+use crate::room_renames::watch_room_renames;
+use crate::room_upgrades::on_tombstone;
+use crate::snooze::expire_snoozes;
+use crate::sync_gaps::{record_sync_gaps, resume_token};
+use crate::threads::on_thread_event;
 use crate::state::MatrixState;
+use crate::room_verification::{expire_stale_user_verification, track_user_verification};
+use crate::verification::expire_stale_flow;
+
+/// State event types that feed into a cached `RoomInfo`. Member events matter
+/// because rooms without a name are named after their heroes, and because our
+/// own membership decides whether we can still post.
+const ROOM_INFO_EVENT_TYPES: &[&str] = &[
+    "m.room.name",
+    "m.room.canonical_alias",
+    "m.room.member",
+    "m.room.topic",
+    "m.room.avatar",
+    "m.room.history_visibility",
+    "m.room.power_levels",
+    "m.room.tombstone",
+    "m.room.retention",
+    "m.room.create",
+    "m.bridge",
+    "uk.half-shot.bridge",
+];
+
+/// How long profile changes for one user are collected before a single
+/// `matrix://member-updated` is emitted. A new avatar arrives as a member
+/// event in every shared room, often spread over a couple of syncs.
+const MEMBER_UPDATE_DEBOUNCE: Duration = Duration::from_millis(750);
+
+/// Payload of `matrix://member-updated`: a member's new display name or
+/// avatar, with every room the change was seen in.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MemberUpdate {
+    pub user_id: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub room_ids: BTreeSet<String>,
+}
+
+type PendingMemberUpdates = Arc<RwLock<HashMap<OwnedUserId, MemberUpdate>>>;
 
 #[tauri::command]
 pub async fn matrix_sync(state: State<'_, MatrixState>) -> Result<String, String> {
     let client_lock = state.client.read().await;
     let client = client_lock.as_ref().ok_or("Not logged in")?;
 
-    println!("Starting sync...");
+    info!("Starting sync...");
 
-    client
-        .sync_once(SyncSettings::default())
-        .await
-        .map_err(|e| format!("Sync failed: {}", e))?;
+    let resumed_from = resume_token(client).await;
+    let activity = state.room_activity.read().await.clone();
+    let started = Instant::now();
+    let result = client.sync_once(SyncSettings::default()).await;
+
+    let mut health = state.sync_health.write().await;
+    let response = match result {
+        Ok(response) => response,
+        Err(e) => {
+            if health.record_failure(e.to_string()) {
+                warn!("Sync keeps failing: {}", e);
+                let _ = state.app.emit("matrix://sync-degraded", health.snapshot());
+            }
+            return Err(format!("Sync failed: {}", e));
+        }
+    };
+    health.record_success(started.elapsed(), &response);
+    drop(health);
 
-    println!("Sync completed");
+    record_sync_gaps(&state, &response, resumed_from.as_deref(), &activity).await;
+
+    expire_stale_flow(&state, client).await;
+    expire_stale_user_verification(&state, client).await;
+    expire_snoozes(&state).await;
+    start_dm_prefetch(&state, client).await;
+    start_retention_task(&state, client).await;
+
+    info!("Sync completed");
 
     Ok("Synced successfully".to_string())
 }
+
+/// Registers the handlers that keep `MatrixState` in step with what sync
+/// delivers. Called once per client, right after it is logged in.
+pub(crate) async fn install_event_handlers(client: &Client, state: &MatrixState) {
+    track_user_verification(client, state.app.clone());
+
+    let room_cache = state.room_cache.clone();
+    let member_index = state.member_index.clone();
+    client.add_event_handler(move |event: Raw<AnySyncStateEvent>, room: Room| {
+        let room_cache = room_cache.clone();
+        let member_index = member_index.clone();
+        async move {
+            let event_type = event.get_field::<String>("type").ok().flatten();
+            if event_type.as_deref() == Some("m.room.member") {
+                member_index.write().await.remove(room.room_id());
+            }
+            if affects_room_info(event_type) {
+                room_cache.write().await.remove(room.room_id());
+            }
+        }
+    });
+
+    let room_cache = state.room_cache.clone();
+    client.add_event_handler(move |event: Raw<AnyStrippedStateEvent>, room: Room| {
+        let room_cache = room_cache.clone();
+        async move {
+            if affects_room_info(event.get_field::<String>("type").ok().flatten()) {
+                room_cache.write().await.remove(room.room_id());
+            }
+        }
+    });
+
+    let room_activity = state.room_activity.clone();
+    let recent_senders = state.recent_senders.clone();
+    let prefetched_pages = state.prefetched_pages.clone();
+    client.add_event_handler(move |event: Raw<AnySyncTimelineEvent>, room: Room| {
+        let room_activity = room_activity.clone();
+        let recent_senders = recent_senders.clone();
+        let prefetched_pages = prefetched_pages.clone();
+        async move {
+            prefetched_pages.write().await.remove(room.room_id());
+            let Some(ts) = event.get_field::<u64>("origin_server_ts").ok().flatten() else {
+                return;
+            };
+            let room_id = room.room_id().to_owned();
+            if let Ok(Some(sender)) = event.get_field::<OwnedUserId>("sender") {
+                note_sender(&recent_senders, &room_id, &sender, ts).await;
+            }
+            let mut activity = room_activity.write().await;
+            let last = activity.entry(room_id).or_insert(0);
+            *last = (*last).max(ts);
+        }
+    });
+
+    let activity = state.own_activity.clone();
+    client.add_event_handler(move |event: Raw<AnySyncTimelineEvent>, room: Room, client: Client, info: Option<EncryptionInfo>| {
+        on_own_message(event, room, client, info, activity.clone())
+    });
+
+    let participation = state.thread_participation.clone();
+    client.add_event_handler(move |event: Raw<AnySyncTimelineEvent>, room: Room, client: Client| {
+        on_thread_event(event, room, client, participation.clone())
+    });
+
+    let app = state.app.clone();
+    let pending: PendingMemberUpdates = Default::default();
+    client.add_event_handler(move |event: SyncRoomMemberEvent, room: Room| {
+        on_member_profile_change(app.clone(), pending.clone(), event, room)
+    });
+
+    client.add_event_handler(on_stripped_member);
+
+    let app = state.app.clone();
+    client.add_event_handler(move |event: SyncRoomMemberEvent, room: Room, client: Client| {
+        on_own_membership(event, room, client, app.clone())
+    });
+
+    let app = state.app.clone();
+    client.add_event_handler(move |event: SyncRoomTombstoneEvent, room: Room, client: Client| {
+        on_tombstone(event, room, client, app.clone())
+    });
+
+    let (app, tracker) = (state.app.clone(), state.notifications.clone());
+    client.add_event_handler(move |event: SyncReceiptEvent, room: Room, client: Client| {
+        on_receipt(event, room, client, app.clone(), tracker.clone())
+    });
+
+    let (app, tracker) = (state.app.clone(), state.notifications.clone());
+    let (settings, participation) = (state.settings.clone(), state.thread_participation.clone());
+    let (active_room, push_rules) = (state.active_room.clone(), state.push_rules.clone());
+    client
+        .register_notification_handler(move |notification, room, client| {
+            on_notification(
+                notification,
+                room,
+                client,
+                app.clone(),
+                tracker.clone(),
+                settings.clone(),
+                participation.clone(),
+                active_room.clone(),
+                push_rules.clone(),
+            )
+        })
+        .await;
+
+    track_push_rules(state, client).await;
+    watch_room_renames(state, client).await;
+}
+
+/// Queues a display name or avatar change of a joined member. The first
+/// change for a user starts the debounce window; later ones only add rooms.
+async fn on_member_profile_change(
+    app: AppHandle,
+    pending: PendingMemberUpdates,
+    event: SyncRoomMemberEvent,
+    room: Room,
+) {
+    let SyncRoomMemberEvent::Original(event) = event else {
+        return;
+    };
+    let Some(prev) = event.unsigned.prev_content.as_ref() else {
+        return;
+    };
+    if event.content.membership != MembershipState::Join || prev.membership != MembershipState::Join {
+        return;
+    }
+    if event.content.displayname == prev.displayname && event.content.avatar_url == prev.avatar_url {
+        return;
+    }
+
+    let user_id = event.state_key.clone();
+    let mut updates = pending.write().await;
+    let first = !updates.contains_key(&user_id);
+    let update = updates.entry(user_id.clone()).or_insert_with(|| MemberUpdate {
+        user_id: user_id.to_string(),
+        display_name: None,
+        avatar_url: None,
+        room_ids: BTreeSet::new(),
+    });
+    update.display_name = event.content.displayname.clone();
+    update.avatar_url = event.content.avatar_url.as_ref().map(|url| url.to_string());
+    update.room_ids.insert(room.room_id().to_string());
+    drop(updates);
+
+    if first {
+        tauri::async_runtime::spawn(async move {
+            tokio::time::sleep(MEMBER_UPDATE_DEBOUNCE).await;
+            if let Some(update) = pending.write().await.remove(&user_id) {
+                let _ = app.emit("matrix://member-updated", update);
+            }
+        });
+    }
+}
+
+fn affects_room_info(event_type: Option<String>) -> bool {
+    event_type.is_some_and(|t| ROOM_INFO_EVENT_TYPES.contains(&t.as_str()))
+}
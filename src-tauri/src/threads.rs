@@ -0,0 +1,369 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use matrix_sdk::deserialized_responses::{ThreadSummary, ThreadSummaryStatus, TimelineEvent};
+use matrix_sdk::room::{IncludeRelations, RelationsOptions};
+use matrix_sdk::ruma::api::Direction;
+use matrix_sdk::ruma::events::receipt::{ReceiptThread, ReceiptType};
+use matrix_sdk::ruma::events::relation::RelationType;
+use matrix_sdk::ruma::events::AnySyncTimelineEvent;
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId};
+use matrix_sdk::{Client, Room};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::State;
+use tokio::sync::RwLock;
+use tracing::{debug, info};
+
+use crate::bridges::{annotate_bridges, room_bridges};
+use crate::polls::annotate_polls;
+use crate::profiles::ProfileResolver;
+use crate::retention::{annotate_expiry, room_retention};
+use crate::rooms::{annotate_highlights, limit_body_sizes, origin_server_ts, timeline_message, Message};
+use crate::settings::{change_settings, Settings};
+use crate::state::MatrixState;
+
+/// Per room, the roots of the threads the user takes part in: they posted in
+/// them or were mentioned there. Gathered from sync and opened threads.
+pub type ThreadParticipation = Arc<RwLock<HashMap<OwnedRoomId, HashSet<OwnedEventId>>>>;
+
+/// Which thread replies notify in a room. Events outside threads follow the
+/// room's own notification settings either way.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ThreadNotificationMode {
+    #[default]
+    All,
+    /// Only threads the user posted in, started, or was mentioned in.
+    Participating,
+    /// No thread replies, except ones that mention the user.
+    None,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ThreadMessages {
+    /// Oldest first.
+    pub messages: Vec<Message>,
+    pub next_token: Option<String>,
+    /// Replies from others after our read receipt for the thread, among
+    /// those in this page.
+    pub unread_count: u64,
+    pub participating: bool,
+}
+
+#[tauri::command]
+pub async fn get_thread_notification_mode(
+    state: State<'_, MatrixState>,
+    room_id: String,
+) -> Result<ThreadNotificationMode, String> {
+    if state.client.read().await.is_none() {
+        return Err("Not logged in".to_string());
+    }
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+
+    Ok(thread_mode(&*state.settings.read().await, &room_id))
+}
+
+#[tauri::command]
+pub async fn set_thread_notification_mode(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    mode: ThreadNotificationMode,
+) -> Result<(), String> {
+    if state.client.read().await.is_none() {
+        return Err("Not logged in".to_string());
+    }
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+
+    change_settings(&state, |settings| {
+        if mode == ThreadNotificationMode::default() {
+            settings.thread_notifications.remove(room_id.as_str());
+        } else {
+            settings.thread_notifications.insert(room_id.to_string(), mode);
+        }
+    })
+    .await?;
+
+    info!("Set thread notifications of {} to {:?}", room_id, mode);
+    Ok(())
+}
+
+/// A page of replies in the thread started by `root_event_id`, newest page
+/// first, with how much of it is unread.
+#[tauri::command]
+pub async fn get_thread_messages(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    root_event_id: String,
+    from_token: Option<String>,
+) -> Result<ThreadMessages, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let root: OwnedEventId = root_event_id
+        .parse()
+        .map_err(|e| format!("Invalid event ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+    let user_id = client.user_id().ok_or("No user ID")?;
+
+    let relations = room
+        .relations(
+            root.clone(),
+            RelationsOptions {
+                from: from_token,
+                dir: Direction::Backward,
+                include_relations: IncludeRelations::RelationsOfType(RelationType::Thread),
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(|e| format!("Failed to load thread: {}", e))?;
+
+    let read_up_to = thread_read_position(&room, user_id, &root, &relations.chunk).await;
+    let unread_count = relations
+        .chunk
+        .iter()
+        .filter(|event| event.raw().get_field::<OwnedUserId>("sender").ok().flatten().as_deref() != Some(user_id))
+        .filter(|event| origin_server_ts(event) > read_up_to)
+        .count() as u64;
+
+    let mut profiles = ProfileResolver::new(&room, &[]).await;
+    let mut sent_plaintexts = state.sent_plaintext.write().await;
+    let mut messages = Vec::new();
+    let events: Vec<&TimelineEvent> = relations.chunk.iter().rev().collect();
+    for timeline_event in &events {
+        profiles.observe(timeline_event.raw());
+        if let Some(message) = timeline_message(timeline_event, &profiles, Some(user_id), &mut sent_plaintexts) {
+            messages.push(message);
+        }
+    }
+    drop(sent_plaintexts);
+    annotate_highlights(&state, &room, &events, &mut messages).await;
+    annotate_expiry(&mut messages, room_retention(&room).await.as_ref());
+    limit_body_sizes(&mut messages, state.settings.read().await.message_body_limit);
+    annotate_bridges(&mut messages, &room_bridges(&room).await);
+    annotate_polls(&room, &mut messages).await;
+
+    if messages.iter().any(|message| message.sender == user_id.as_str()) {
+        note_participation(&state.thread_participation, &room_id, &root).await;
+    }
+    let participating = participates(&state.thread_participation, &room, user_id, &root).await;
+
+    Ok(ThreadMessages {
+        messages,
+        next_token: relations.next_batch_token,
+        unread_count,
+        participating,
+    })
+}
+
+/// Sends a threaded read receipt for the newest reply in the thread, or the
+/// root when it has none, leaving the rest of the room unread. The receipt
+/// is public or private as `send_read_receipts` says; with receipts off
+/// nothing is sent, as there is no threaded fully-read marker.
+#[tauri::command]
+pub async fn mark_thread_read(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    root_event_id: String,
+) -> Result<(), String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let root: OwnedEventId = root_event_id
+        .parse()
+        .map_err(|e| format!("Invalid event ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let Some(receipt_type) = state.settings.read().await.send_read_receipts.receipt_type() else {
+        debug!("Not marking thread {} as read, read receipts are off", root);
+        return Ok(());
+    };
+    let latest = latest_in_thread(&room, &root).await?;
+
+    room.send_single_receipt(receipt_type, ReceiptThread::Thread(root.clone()), latest)
+        .await
+        .map_err(|e| format!("Failed to send read receipt: {}", e))?;
+
+    debug!("Marked thread {} in {} as read", root, room_id);
+    Ok(())
+}
+
+/// The newest reply in the thread at `root`, or the root when it has none.
+/// When the relations come back empty the root's bundled thread summary is
+/// asked too, so a thread with replies is never read up to its root only.
+pub(crate) async fn latest_in_thread(room: &Room, root: &EventId) -> Result<OwnedEventId, String> {
+    let latest = room
+        .relations(
+            root.to_owned(),
+            RelationsOptions {
+                dir: Direction::Backward,
+                limit: Some(1u32.into()),
+                include_relations: IncludeRelations::RelationsOfType(RelationType::Thread),
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(|e| format!("Failed to load thread: {}", e))?
+        .chunk
+        .first()
+        .and_then(TimelineEvent::event_id);
+    if let Some(latest) = latest {
+        return Ok(latest);
+    }
+
+    let root_event = room
+        .load_or_fetch_event(root, None)
+        .await
+        .map_err(|e| format!("Failed to load thread root: {}", e))?;
+    Ok(match root_event.thread_summary {
+        ThreadSummaryStatus::Some(ThreadSummary { latest_reply: Some(reply), .. }) => reply,
+        _ => root.to_owned(),
+    })
+}
+
+/// Sync handler recording threads the user posts in or is mentioned in.
+pub(crate) async fn on_thread_event(
+    event: Raw<AnySyncTimelineEvent>,
+    room: Room,
+    client: Client,
+    participation: ThreadParticipation,
+) {
+    let (Some(root), Some(user_id)) = (thread_root(&event), client.user_id()) else {
+        return;
+    };
+    let sender = event.get_field::<OwnedUserId>("sender").ok().flatten();
+    if sender.as_deref() == Some(user_id) || mentions_user(&event, user_id) {
+        note_participation(&participation, room.room_id(), &root).await;
+    }
+}
+
+/// Whether a notification for `event`, a reply in the thread at `root`,
+/// should be shown under the room's thread notification mode.
+pub(crate) async fn thread_notifies(
+    room: &Room,
+    client: &Client,
+    settings: &RwLock<Settings>,
+    participation: &ThreadParticipation,
+    event: &Raw<AnySyncTimelineEvent>,
+    root: &EventId,
+) -> bool {
+    let Some(user_id) = client.user_id() else {
+        return true;
+    };
+    let mode = thread_mode(&*settings.read().await, room.room_id());
+    let mentioned = mentions_user(event, user_id);
+    let participating = match mode {
+        ThreadNotificationMode::Participating if !mentioned => participates(participation, room, user_id, root).await,
+        _ => false,
+    };
+    mode_allows(mode, participating, mentioned)
+}
+
+/// The thread an event replies in, from its `m.thread` relation. Relations
+/// stay unencrypted, so this works before decryption too.
+pub(crate) fn thread_root<T>(event: &Raw<T>) -> Option<OwnedEventId> {
+    let content = event.get_field::<Value>("content").ok().flatten()?;
+    let relates_to = content.get("m.relates_to")?;
+    if relates_to.get("rel_type")?.as_str()? != "m.thread" {
+        return None;
+    }
+    relates_to.get("event_id")?.as_str()?.parse().ok()
+}
+
+fn thread_mode(settings: &Settings, room_id: &RoomId) -> ThreadNotificationMode {
+    settings
+        .thread_notifications
+        .get(room_id.as_str())
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Intentional mentions always get through; that's what `None` is for.
+fn mode_allows(mode: ThreadNotificationMode, participating: bool, mentioned: bool) -> bool {
+    match mode {
+        ThreadNotificationMode::All => true,
+        ThreadNotificationMode::Participating => participating || mentioned,
+        ThreadNotificationMode::None => mentioned,
+    }
+}
+
+/// Whether the user takes part in the thread. Starting it counts, which is
+/// checked against the root event the first time and then remembered.
+async fn participates(participation: &ThreadParticipation, room: &Room, user_id: &UserId, root: &EventId) -> bool {
+    let known = participation
+        .read()
+        .await
+        .get(room.room_id())
+        .is_some_and(|roots| roots.contains(root));
+    if known {
+        return true;
+    }
+
+    let started = room
+        .load_or_fetch_event(root, None)
+        .await
+        .ok()
+        .and_then(|event| event.raw().get_field::<OwnedUserId>("sender").ok().flatten())
+        .is_some_and(|sender| sender == user_id);
+    if started {
+        note_participation(participation, room.room_id(), root).await;
+    }
+    started
+}
+
+async fn note_participation(participation: &ThreadParticipation, room_id: &RoomId, root: &EventId) {
+    participation
+        .write()
+        .await
+        .entry(room_id.to_owned())
+        .or_default()
+        .insert(root.to_owned());
+}
+
+/// Whether the event names the user in its `m.mentions`.
+pub(crate) fn mentions_user<T>(event: &Raw<T>, user_id: &UserId) -> bool {
+    event
+        .get_field::<Value>("content")
+        .ok()
+        .flatten()
+        .and_then(|content| content.get("m.mentions")?.get("user_ids")?.as_array().cloned())
+        .is_some_and(|ids| ids.iter().any(|id| id.as_str() == Some(user_id.as_str())))
+}
+
+/// Timestamp of the newest event our receipts say was read in the thread:
+/// the threaded receipt, or else the unthreaded one, which covers threads
+/// too. Public and private receipts count alike.
+async fn thread_read_position(room: &Room, user_id: &UserId, root: &EventId, chunk: &[TimelineEvent]) -> u64 {
+    for thread in [ReceiptThread::Thread(root.to_owned()), ReceiptThread::Unthreaded] {
+        let mut newest = None;
+        for kind in [ReceiptType::Read, ReceiptType::ReadPrivate] {
+            let Ok(Some((event_id, _))) = room.load_user_receipt(kind, thread.clone(), user_id).await else {
+                continue;
+            };
+            let timestamp = match chunk.iter().find(|event| event.event_id().as_ref() == Some(&event_id)) {
+                Some(event) => origin_server_ts(event),
+                None => match room.load_or_fetch_event(&event_id, None).await {
+                    Ok(event) => origin_server_ts(&event),
+                    Err(_) => continue,
+                },
+            };
+            newest = newest.max(Some(timestamp));
+        }
+        if let Some(newest) = newest {
+            return newest;
+        }
+    }
+    0
+}
@@ -0,0 +1,268 @@
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::thirdparty::Medium;
+use matrix_sdk::ruma::{uint, ClientSecret, OwnedClientSecret, OwnedSessionId};
+use matrix_sdk::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use tauri::State;
+
+use crate::state::MatrixState;
+use crate::uiaa::{default_auth_error, run_with_auth, AuthOperation};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ThreePid {
+    /// "email" today; "msisdn" once phone numbers are supported.
+    pub medium: String,
+    pub address: String,
+    pub validated_at: u64,
+    pub added_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ThreePidValidation {
+    pub sid: String,
+    pub client_secret: String,
+    /// Whether the homeserver accepts the emailed token directly; otherwise
+    /// the user has to click the link in the email.
+    pub accepts_token: bool,
+    pub instructions: String,
+}
+
+/// A validation session started by `add_email`, kept until it is submitted.
+#[derive(Clone, Debug)]
+pub struct PendingThreePid {
+    pub medium: Medium,
+    pub address: String,
+    pub client_secret: OwnedClientSecret,
+    pub submit_url: Option<String>,
+}
+
+#[tauri::command]
+pub async fn get_threepids(state: State<'_, MatrixState>) -> Result<Vec<ThreePid>, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let response = client
+        .account()
+        .get_3pids()
+        .await
+        .map_err(|e| format!("Failed to get third-party identifiers: {}", e))?;
+
+    Ok(response
+        .threepids
+        .into_iter()
+        .map(|t| ThreePid {
+            medium: t.medium.to_string(),
+            address: t.address,
+            validated_at: t.validated_at.get().into(),
+            added_at: t.added_at.get().into(),
+        })
+        .collect())
+}
+
+#[tauri::command]
+pub async fn add_email(
+    state: State<'_, MatrixState>,
+    email: String,
+) -> Result<ThreePidValidation, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let email = email.trim().to_string();
+    if !email.contains('@') {
+        return Err("Invalid email address".to_string());
+    }
+
+    ensure_threepid_changes_allowed(client).await?;
+
+    let client_secret = ClientSecret::new();
+    let response = client
+        .account()
+        .request_3pid_email_token(&client_secret, &email, uint!(1))
+        .await
+        .map_err(|e| match e.client_api_error_kind() {
+            Some(ErrorKind::ThreepidInUse) => {
+                "This email address is already in use by another account".to_string()
+            }
+            Some(ErrorKind::ThreepidDenied) => {
+                "This homeserver does not accept this email address".to_string()
+            }
+            Some(ErrorKind::ThreepidMediumNotSupported) => {
+                "This homeserver cannot send validation emails".to_string()
+            }
+            _ => format!("Failed to request validation email: {}", e),
+        })?;
+
+    let submit_url = response.submit_url.clone();
+    let accepts_token = submit_url.is_some();
+
+    state.pending_threepids.write().await.insert(
+        response.sid.clone(),
+        PendingThreePid {
+            medium: Medium::Email,
+            address: email.clone(),
+            client_secret: client_secret.clone(),
+            submit_url,
+        },
+    );
+
+    let instructions = if accepts_token {
+        format!("Enter the code sent to {}", email)
+    } else {
+        format!("Click the link in the email sent to {}, then continue", email)
+    };
+
+    Ok(ThreePidValidation {
+        sid: response.sid.to_string(),
+        client_secret: client_secret.to_string(),
+        accepts_token,
+        instructions,
+    })
+}
+
+/// Completes an `add_email` session: submits the emailed `token` when the
+/// server accepts one, then binds the address to the account. Binding needs
+/// interactive auth, which surfaces as an `AUTH_REQUIRED` error to finish with
+/// `complete_auth`.
+#[tauri::command]
+pub async fn submit_email_token(
+    state: State<'_, MatrixState>,
+    sid: String,
+    client_secret: String,
+    token: Option<String>,
+) -> Result<String, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let sid = OwnedSessionId::try_from(sid).map_err(|e| format!("Invalid session ID: {}", e))?;
+    let client_secret = OwnedClientSecret::try_from(client_secret)
+        .map_err(|e| format!("Invalid client secret: {}", e))?;
+
+    let pending = state
+        .pending_threepids
+        .read()
+        .await
+        .get(&sid)
+        .cloned()
+        .ok_or("No pending validation for this session")?;
+
+    if pending.client_secret != client_secret {
+        return Err("Client secret does not match this session".to_string());
+    }
+
+    if let Some(token) = token {
+        let submit_url = pending
+            .submit_url
+            .as_ref()
+            .ok_or("This homeserver validates by link; click the link in the email instead")?;
+        submit_validation_token(client, submit_url, &sid, &client_secret, token.trim()).await?;
+    }
+
+    let message = format!("{} added to your account", pending.address);
+    let pending_threepids = state.pending_threepids.clone();
+
+    let operation: AuthOperation = Arc::new(move |client, auth| {
+        let sid = sid.clone();
+        let client_secret = client_secret.clone();
+        let message = message.clone();
+        let pending_threepids = pending_threepids.clone();
+        Box::pin(async move {
+            client.account().add_3pid(&client_secret, &sid, auth).await?;
+            pending_threepids.write().await.remove(&sid);
+            Ok(json!(message))
+        })
+    });
+
+    let result = run_with_auth(
+        &state,
+        client,
+        "add the email address",
+        operation,
+        Vec::new(),
+        map_add_3pid_error,
+    )
+    .await?;
+
+    Ok(result.as_str().unwrap_or_default().to_string())
+}
+
+#[tauri::command]
+pub async fn remove_threepid(
+    state: State<'_, MatrixState>,
+    medium: String,
+    address: String,
+) -> Result<String, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let medium = match medium.as_str() {
+        "email" => Medium::Email,
+        "msisdn" => Medium::Msisdn,
+        other => return Err(format!("Unsupported medium: {}", other)),
+    };
+
+    ensure_threepid_changes_allowed(client).await?;
+
+    client
+        .account()
+        .delete_3pid(&address, medium, None)
+        .await
+        .map_err(|e| format!("Failed to remove {}: {}", address, e))?;
+
+    Ok(format!("{} removed from your account", address))
+}
+
+async fn ensure_threepid_changes_allowed(client: &Client) -> Result<(), String> {
+    let capabilities = client
+        .get_capabilities()
+        .await
+        .map_err(|e| format!("Failed to get server capabilities: {}", e))?;
+
+    if !capabilities.thirdparty_id_changes.enabled {
+        return Err("This homeserver does not allow changing email addresses".to_string());
+    }
+
+    Ok(())
+}
+
+async fn submit_validation_token(
+    client: &Client,
+    submit_url: &str,
+    sid: &OwnedSessionId,
+    client_secret: &OwnedClientSecret,
+    token: &str,
+) -> Result<(), String> {
+    let body = json!({
+        "sid": sid,
+        "client_secret": client_secret,
+        "token": token,
+    });
+
+    let response = client
+        .http_client()
+        .post(submit_url)
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .send()
+        .await
+        .map_err(|e| format!("Failed to submit validation code: {}", e))?;
+
+    if !response.status().is_success() {
+        return Err("The validation code was not accepted".to_string());
+    }
+
+    Ok(())
+}
+
+fn map_add_3pid_error(error: &matrix_sdk::Error, description: &str) -> String {
+    match error.client_api_error_kind() {
+        Some(ErrorKind::ThreepidAuthFailed) => {
+            "The email address has not been validated yet".to_string()
+        }
+        Some(ErrorKind::ThreepidInUse) => {
+            "This email address is already in use by another account".to_string()
+        }
+        _ => default_auth_error(error, description),
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedTransactionId, RoomId, TransactionId};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tauri::State;
+use tokio::sync::RwLock;
+use tracing::{debug, warn};
+
+use crate::auth::sanitize_user_id;
+use crate::errors::CommandError;
+use crate::state::MatrixState;
+
+const SENT_TRANSACTIONS_FILE: &str = "sent_transactions.json";
+/// How long a transaction ID is remembered: long enough to cover a retry
+/// after the app was closed mid-send.
+const TRANSACTION_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Messages sent or being sent, by transaction ID.
+pub type SentTransactions = Arc<RwLock<HashMap<OwnedTransactionId, SentTransaction>>>;
+
+/// One logical send. The entry is made before the request goes out, so a
+/// retry after a failure or a restart reuses it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SentTransaction {
+    pub room_id: String,
+    /// Set once the server accepted the event.
+    pub event_id: Option<OwnedEventId>,
+    /// When the first attempt started, in milliseconds since the epoch.
+    pub started_at: u64,
+}
+
+fn now() -> u64 {
+    MilliSecondsSinceUnixEpoch::now().get().into()
+}
+
+/// A transaction ID for a message about to be sent. Pass it to every
+/// attempt at sending that message, so a retry after a timeout or a crash
+/// can't post it twice.
+#[tauri::command]
+pub async fn new_transaction_id(state: State<'_, MatrixState>) -> Result<String, String> {
+    if state.client.read().await.is_none() {
+        return Err("Not logged in".to_string());
+    }
+    Ok(TransactionId::new().to_string())
+}
+
+/// Starts or resumes the send of `txn_id` in `room_id`. Returns the event
+/// ID when an earlier attempt already went through, in which case nothing
+/// should be sent.
+pub(crate) async fn begin_transaction(
+    state: &MatrixState,
+    room_id: &RoomId,
+    txn_id: &TransactionId,
+) -> Result<Option<OwnedEventId>, String> {
+    let mut transactions = state.sent_transactions.write().await;
+    let now = now();
+    prune(&mut transactions, now);
+    let known = transactions.contains_key(txn_id);
+    let sent = start_transaction(&mut transactions, room_id, txn_id, now)?;
+    if !known {
+        persist(state, &transactions).await;
+    }
+    Ok(sent)
+}
+
+/// `begin_transaction` on the map itself: records `txn_id` as started when
+/// it is new.
+fn start_transaction(
+    transactions: &mut HashMap<OwnedTransactionId, SentTransaction>,
+    room_id: &RoomId,
+    txn_id: &TransactionId,
+    now: u64,
+) -> Result<Option<OwnedEventId>, String> {
+    if let Some(sent) = transactions.get(txn_id) {
+        if sent.room_id != room_id.as_str() {
+            return Err(CommandError::new(
+                "TRANSACTION_REUSED",
+                "This transaction ID was used for a message in another room",
+            )
+            .with_details(json!({ "transaction_id": txn_id, "room_id": sent.room_id }))
+            .into());
+        }
+        if let Some(event_id) = &sent.event_id {
+            debug!("Transaction {} was sent already as {}", txn_id, event_id);
+            return Ok(Some(event_id.clone()));
+        }
+        return Ok(None);
+    }
+
+    transactions.insert(
+        txn_id.to_owned(),
+        SentTransaction {
+            room_id: room_id.to_string(),
+            event_id: None,
+            started_at: now,
+        },
+    );
+    Ok(None)
+}
+
+/// Records the event the server created for `txn_id`.
+pub(crate) async fn complete_transaction(state: &MatrixState, txn_id: &TransactionId, event_id: &OwnedEventId) {
+    let mut transactions = state.sent_transactions.write().await;
+    if let Some(sent) = transactions.get_mut(txn_id) {
+        sent.event_id = Some(event_id.clone());
+        persist(state, &transactions).await;
+    }
+}
+
+/// Loads the transactions of earlier runs after a session restore.
+pub(crate) async fn load_sent_transactions(state: &MatrixState, user_id: &str) {
+    let saved = read_transactions(&transactions_path(&state.data_dir, user_id), now());
+    *state.sent_transactions.write().await = saved;
+}
+
+/// The transactions saved at `path` that haven't expired by `now`.
+fn read_transactions(path: &Path, now: u64) -> HashMap<OwnedTransactionId, SentTransaction> {
+    let mut saved: HashMap<OwnedTransactionId, SentTransaction> = match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable sent transactions: {}", e);
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    };
+    prune(&mut saved, now);
+    saved
+}
+
+/// Drops transactions older than `TRANSACTION_TTL_MS`.
+fn prune(transactions: &mut HashMap<OwnedTransactionId, SentTransaction>, now: u64) {
+    transactions.retain(|_, sent| now.saturating_sub(sent.started_at) < TRANSACTION_TTL_MS);
+}
+
+async fn persist(state: &MatrixState, transactions: &HashMap<OwnedTransactionId, SentTransaction>) {
+    let Some(user_id) = state.user_id.read().await.clone() else {
+        return;
+    };
+    if let Err(e) = save_transactions(&transactions_path(&state.data_dir, &user_id), transactions) {
+        warn!("{}", e);
+    }
+}
+
+fn transactions_path(data_dir: &Path, user_id: &str) -> PathBuf {
+    data_dir.join(sanitize_user_id(user_id)).join(SENT_TRANSACTIONS_FILE)
+}
+
+/// Writes to a temporary file first so a crash never leaves half a file.
+fn save_transactions(path: &Path, transactions: &HashMap<OwnedTransactionId, SentTransaction>) -> Result<(), String> {
+    let json = serde_json::to_string(transactions)
+        .map_err(|e| format!("Failed to serialize sent transactions: {}", e))?;
+    let temp = path.with_extension("json.tmp");
+    fs::write(&temp, json).map_err(|e| format!("Failed to save sent transactions: {}", e))?;
+    fs::rename(&temp, path).map_err(|e| format!("Failed to save sent transactions: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::{event_id, room_id};
+
+    #[test]
+    fn a_retry_resumes_its_transaction() {
+        let mut transactions = HashMap::new();
+        let txn_id: OwnedTransactionId = "txn1".into();
+        let room = room_id!("!room:example.org");
+
+        assert_eq!(start_transaction(&mut transactions, room, &txn_id, 1000), Ok(None));
+        assert_eq!(transactions[&txn_id].started_at, 1000);
+        // Not through yet: send again, under the same ID.
+        assert_eq!(start_transaction(&mut transactions, room, &txn_id, 2000), Ok(None));
+        assert_eq!(transactions[&txn_id].started_at, 1000);
+
+        transactions.get_mut(&txn_id).unwrap().event_id = Some(event_id!("$sent").to_owned());
+        assert_eq!(
+            start_transaction(&mut transactions, room, &txn_id, 3000),
+            Ok(Some(event_id!("$sent").to_owned())),
+        );
+    }
+
+    #[test]
+    fn a_transaction_belongs_to_one_room() {
+        let mut transactions = HashMap::new();
+        let txn_id: OwnedTransactionId = "txn1".into();
+        start_transaction(&mut transactions, room_id!("!a:example.org"), &txn_id, 0).unwrap();
+
+        let error = start_transaction(&mut transactions, room_id!("!b:example.org"), &txn_id, 0).unwrap_err();
+        assert_eq!(crate::errors::error_code(&error).as_deref(), Some("TRANSACTION_REUSED"));
+    }
+
+    #[test]
+    fn expired_transactions_are_pruned() {
+        let mut transactions = HashMap::new();
+        let room = room_id!("!room:example.org");
+        start_transaction(&mut transactions, room, "old".into(), 0).unwrap();
+        start_transaction(&mut transactions, room, "new".into(), TRANSACTION_TTL_MS).unwrap();
+
+        prune(&mut transactions, TRANSACTION_TTL_MS + 1);
+        assert_eq!(transactions.keys().map(|id| id.as_str()).collect::<Vec<_>>(), ["new"]);
+    }
+
+    #[test]
+    fn transactions_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("sent-transactions-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(SENT_TRANSACTIONS_FILE);
+        let room = room_id!("!room:example.org");
+
+        let mut transactions = HashMap::new();
+        start_transaction(&mut transactions, room, "sent".into(), 1000).unwrap();
+        start_transaction(&mut transactions, room, "expired".into(), 0).unwrap();
+        transactions.get_mut(<&TransactionId>::from("sent")).unwrap().event_id = Some(event_id!("$sent").to_owned());
+        save_transactions(&path, &transactions).unwrap();
+
+        let saved = read_transactions(&path, TRANSACTION_TTL_MS + 500);
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[<&TransactionId>::from("sent")].event_id.as_deref(), Some(event_id!("$sent")));
+        assert!(read_transactions(&dir.join("missing.json"), 0).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
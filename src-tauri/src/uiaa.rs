@@ -0,0 +1,277 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use matrix_sdk::ruma::api::client::uiaa::{
+    AuthData, AuthType, Dummy, Password, ReCaptcha, RegistrationToken, UiaaInfo, UserIdentifier,
+};
+use matrix_sdk::Client;
+use serde_json::json;
+use tauri::State;
+
+use crate::errors::CommandError;
+use crate::state::MatrixState;
+
+/// An operation that may need User-Interactive Auth. It is called once
+/// without auth, then again with the auth data built by `complete_auth` for as
+/// many stages as the server asks for.
+pub type AuthOperation = Arc<
+    dyn Fn(
+            Client,
+            Option<AuthData>,
+        ) -> Pin<Box<dyn Future<Output = Result<serde_json::Value, matrix_sdk::Error>> + Send>>
+        + Send
+        + Sync,
+>;
+
+/// Turns a non-UIAA failure into the command's error, given the operation's
+/// description.
+pub type AuthErrorMapper = fn(&matrix_sdk::Error, &str) -> String;
+
+/// An operation waiting on the user to complete an auth stage.
+#[derive(Clone)]
+pub struct PendingAuth {
+    pub client: Client,
+    pub description: String,
+    pub operation: AuthOperation,
+    /// Stage bodies supplied up front, submitted whenever the server asks for
+    /// that stage.
+    pub provided_stages: Vec<serde_json::Value>,
+    pub map_error: AuthErrorMapper,
+}
+
+/// Runs `operation`, answering dummy stages and any of `provided_stages`
+/// (stage bodies with a `type`, as accepted by `complete_auth`) on the spot.
+/// When the server asks for anything else the operation is parked under its
+/// UIAA session and an `AUTH_REQUIRED` error carrying the session, flows and
+/// params is returned; `complete_auth` picks it up from there. Other failures
+/// are reported through `map_error`, which can fall back to `default_auth_error`.
+pub(crate) async fn run_with_auth(
+    state: &MatrixState,
+    client: &Client,
+    description: &str,
+    operation: AuthOperation,
+    provided_stages: Vec<serde_json::Value>,
+    map_error: AuthErrorMapper,
+) -> Result<serde_json::Value, String> {
+    drive(
+        state,
+        PendingAuth {
+            client: client.clone(),
+            description: description.to_string(),
+            operation,
+            provided_stages,
+            map_error,
+        },
+        None,
+    )
+    .await
+}
+
+/// Resumes the operation parked under `session` with the given auth stage.
+///
+/// `auth` is the stage's JSON body including its `type`, e.g.
+/// `{ "type": "m.login.password", "password": "..." }`; the password, dummy,
+/// registration token and recaptcha stages are understood, anything else is
+/// passed through as-is. Use `{ "type": "fallback" }` after finishing a
+/// stage in the homeserver's fallback web page.
+#[tauri::command]
+pub async fn complete_auth(
+    state: State<'_, MatrixState>,
+    session: String,
+    auth: serde_json::Value,
+) -> Result<serde_json::Value, String> {
+    let pending = state
+        .pending_auth
+        .write()
+        .await
+        .remove(&session)
+        .ok_or("No pending operation for this auth session")?;
+
+    let auth_data = build_auth_data(&pending.client, &session, auth)?;
+
+    drive(&state, pending, Some(auth_data)).await
+}
+
+/// Drops an operation the user backed out of.
+#[tauri::command]
+pub async fn cancel_auth(state: State<'_, MatrixState>, session: String) -> Result<(), String> {
+    state.pending_auth.write().await.remove(&session);
+    Ok(())
+}
+
+pub(crate) fn default_auth_error(error: &matrix_sdk::Error, description: &str) -> String {
+    format!("Failed to {}: {}", description, error)
+}
+
+/// Calls the operation with `auth`, then keeps answering stages we can fill in
+/// ourselves until it succeeds, fails outright, or needs the user.
+async fn drive(
+    state: &MatrixState,
+    pending: PendingAuth,
+    mut auth: Option<AuthData>,
+) -> Result<serde_json::Value, String> {
+    let mut attempted: Vec<String> = Vec::new();
+
+    loop {
+        let error = match (pending.operation)(pending.client.clone(), auth.take()).await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+
+        let Some(info) = error.as_uiaa_response() else {
+            return Err((pending.map_error)(&error, &pending.description));
+        };
+
+        let automatic = if info.auth_error.is_some() {
+            None
+        } else {
+            next_automatic_stage(info, &pending.provided_stages, &attempted)
+        };
+
+        match (automatic, info.session.as_deref()) {
+            (Some(stage), Some(session)) => {
+                attempted.push(stage["type"].as_str().unwrap_or_default().to_string());
+                auth = Some(build_auth_data(&pending.client, session, stage)?);
+            }
+            _ => return Err(park(state, info, pending).await),
+        }
+    }
+}
+
+/// The body of the next stage we can submit without the user: a dummy stage,
+/// or one the caller provided. Each stage is tried at most once.
+fn next_automatic_stage(
+    info: &UiaaInfo,
+    provided_stages: &[serde_json::Value],
+    attempted: &[String],
+) -> Option<serde_json::Value> {
+    info.flows.iter().find_map(|flow| {
+        let next = flow.stages.iter().find(|s| !info.completed.contains(s))?;
+        let next_type = next.to_string();
+        if attempted.contains(&next_type) {
+            return None;
+        }
+        if *next == AuthType::Dummy {
+            return Some(json!({ "type": "m.login.dummy" }));
+        }
+        provided_stages
+            .iter()
+            .find(|stage| stage["type"].as_str() == Some(next_type.as_str()))
+            .cloned()
+    })
+}
+
+async fn park(state: &MatrixState, info: &UiaaInfo, pending: PendingAuth) -> String {
+    let Some(session) = info.session.clone() else {
+        return format!(
+            "Failed to {}: the server did not start an auth session",
+            pending.description
+        );
+    };
+
+    let homeserver = pending.client.homeserver().to_string();
+    let description = pending.description.clone();
+    state.pending_auth.write().await.insert(session.clone(), pending);
+
+    let flows: Vec<Vec<String>> = info
+        .flows
+        .iter()
+        .map(|flow| flow.stages.iter().map(|s| s.to_string()).collect())
+        .collect();
+    let completed: Vec<String> = info.completed.iter().map(|s| s.to_string()).collect();
+    let params: serde_json::Value = info
+        .params
+        .as_ref()
+        .and_then(|p| serde_json::from_str(p.get()).ok())
+        .unwrap_or_else(|| json!({}));
+
+    // Stages the app has no native UI for can be completed in the
+    // homeserver's fallback page, shown in a webview.
+    let fallback_urls: serde_json::Map<String, serde_json::Value> = flows
+        .iter()
+        .flatten()
+        .filter(|stage| !matches!(stage.as_str(), "m.login.password" | "m.login.dummy"))
+        .map(|stage| {
+            (
+                stage.clone(),
+                json!(format!(
+                    "{}/_matrix/client/v3/auth/{}/fallback/web?session={}",
+                    homeserver.trim_end_matches('/'),
+                    stage,
+                    session
+                )),
+            )
+        })
+        .collect();
+
+    let message = match &info.auth_error {
+        Some(error) => error.message.clone(),
+        None => format!("Authentication is required to {}", description),
+    };
+
+    CommandError::new("AUTH_REQUIRED", message)
+        .with_details(json!({
+            "session": session,
+            "flows": flows,
+            "completed": completed,
+            "params": params,
+            "fallback_urls": fallback_urls,
+        }))
+        .into()
+}
+
+fn build_auth_data(
+    client: &Client,
+    session: &str,
+    auth: serde_json::Value,
+) -> Result<AuthData, String> {
+    let serde_json::Value::Object(mut data) = auth else {
+        return Err("Auth payload must be an object".to_string());
+    };
+    let auth_type = data
+        .remove("type")
+        .and_then(|t| t.as_str().map(str::to_string))
+        .ok_or("Auth payload is missing its type")?;
+    data.remove("session");
+
+    let field = |name: &str| -> Result<String, String> {
+        data.get(name)
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+            .ok_or_else(|| format!("{} auth needs a {}", auth_type, name))
+    };
+    if auth_type == "fallback" {
+        return Ok(AuthData::fallback_acknowledgement(session.to_string()));
+    }
+    let session = Some(session.to_string());
+
+    Ok(match auth_type.as_str() {
+        "m.login.password" => {
+            let user_id = client.user_id().ok_or("No user ID")?;
+            let mut password = Password::new(
+                UserIdentifier::UserIdOrLocalpart(user_id.to_string()),
+                field("password")?,
+            );
+            password.session = session;
+            AuthData::Password(password)
+        }
+        "m.login.dummy" => {
+            let mut dummy = Dummy::new();
+            dummy.session = session;
+            AuthData::Dummy(dummy)
+        }
+        "m.login.registration_token" => {
+            let mut token = RegistrationToken::new(field("token")?);
+            token.session = session;
+            AuthData::RegistrationToken(token)
+        }
+        "m.login.recaptcha" => {
+            let mut recaptcha = ReCaptcha::new(field("response")?);
+            recaptcha.session = session;
+            AuthData::ReCaptcha(recaptcha)
+        }
+        other => AuthData::new(other, session, data.clone())
+            .map_err(|e| format!("Invalid auth payload: {}", e))?,
+    })
+}
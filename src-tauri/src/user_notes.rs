@@ -0,0 +1,59 @@
+use matrix_sdk::ruma::OwnedUserId;
+use serde_json::{json, Map, Value};
+use tauri::State;
+
+use crate::account_data::{get_global_json, set_global_json, APP_NAMESPACE};
+use crate::state::MatrixState;
+
+/// Longest note kept per user.
+const MAX_NOTE_LENGTH: usize = 2000;
+
+/// Account data holding `{"notes": {user_id: note}}`. It syncs to the
+/// user's other devices but isn't encrypted, so the homeserver can read it.
+pub(crate) fn user_notes_type() -> String {
+    format!("{}.user_notes", APP_NAMESPACE)
+}
+
+/// The user's private note about someone, if they wrote one.
+#[tauri::command]
+pub async fn get_user_note(state: State<'_, MatrixState>, user_id: String) -> Result<Option<String>, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let user_id: OwnedUserId = user_id
+        .parse()
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
+
+    let notes = get_global_json(client, &user_notes_type()).await?;
+    Ok(notes
+        .as_ref()
+        .and_then(|notes| notes.get("notes")?.get(user_id.as_str())?.as_str())
+        .map(str::to_string))
+}
+
+/// Saves a private note about someone; an empty `note` removes it.
+#[tauri::command]
+pub async fn set_user_note(state: State<'_, MatrixState>, user_id: String, note: String) -> Result<(), String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let user_id: OwnedUserId = user_id
+        .parse()
+        .map_err(|e| format!("Invalid user ID: {}", e))?;
+    let note = note.trim();
+    if note.chars().count() > MAX_NOTE_LENGTH {
+        return Err(format!("Notes can be at most {} characters", MAX_NOTE_LENGTH));
+    }
+
+    let mut notes = get_global_json(client, &user_notes_type())
+        .await?
+        .and_then(|content| content.get("notes")?.as_object().cloned())
+        .unwrap_or_else(Map::new);
+    if note.is_empty() {
+        notes.remove(user_id.as_str());
+    } else {
+        notes.insert(user_id.to_string(), Value::String(note.to_string()));
+    }
+
+    set_global_json(client, &user_notes_type(), &json!({ "notes": notes })).await
+}
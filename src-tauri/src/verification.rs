@@ -1,10 +1,40 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
+
 use matrix_sdk::config::SyncSettings;
+use matrix_sdk::ruma::api::client::to_device::send_event_to_device;
+use matrix_sdk::ruma::events::key::verification::cancel::{CancelCode, ToDeviceKeyVerificationCancelEventContent};
+use matrix_sdk::ruma::events::{AnyToDeviceEventContent, ToDeviceEventType};
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::to_device::DeviceIdOrAllDevices;
+use matrix_sdk::ruma::{OwnedDeviceId, OwnedTransactionId, TransactionId};
+use matrix_sdk::Client;
 use serde::{Deserialize, Serialize};
-use tauri::State;
+use serde_json::json;
+use tauri::{Emitter, State};
+use tokio::sync::RwLock;
 use tokio::time::{sleep, Duration};
+use tracing::{debug, info, warn};
 
+use crate::errors::CommandError;
+use crate::sas_emoji::localize;
 use crate::state::MatrixState;
 
+/// How long a verification request stays valid, per the spec.
+const VERIFICATION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+pub type VerificationFlowSlot = Arc<RwLock<Option<VerificationFlow>>>;
+
+/// The verification the user started from this device.
+pub struct VerificationFlow {
+    pub flow_id: String,
+    pub started_at: Instant,
+    pub we_started: bool,
+    /// The device the request went to.
+    pub other_device: Option<OwnedDeviceId>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct VerificationStatus {
     pub needs_verification: bool,
@@ -41,7 +71,7 @@ pub async fn request_verification(
     let user_id = client.user_id().ok_or("No user ID")?;
     let encryption = client.encryption();
 
-    println!("Requesting verification for user: {}", user_id);
+    info!("Requesting verification for user: {}", user_id);
 
     client.sync_once(SyncSettings::default()).await
         .map_err(|e| format!("Sync failed: {}", e))?;
@@ -51,7 +81,7 @@ pub async fn request_verification(
         .await
         .map_err(|e| format!("Failed to get devices: {}", e))?;
 
-    println!("Found {} devices", devices.devices().count());
+    debug!("Found {} devices", devices.devices().count());
 
     let our_device_id = client.device_id().unwrap();
     let other_devices: Vec<_> = devices.devices()
@@ -62,10 +92,10 @@ pub async fn request_verification(
         return Err("No other devices found. Make sure you're logged in on Element.".to_string());
     }
 
-    println!("Found {} other devices", other_devices.len());
+    debug!("Found {} other devices", other_devices.len());
 
     for device in other_devices {
-        println!("Requesting verification from device: {} ({})",
+        info!("Requesting verification from device: {} ({})",
             device.device_id(),
             device.display_name().unwrap_or("Unknown"),
         );
@@ -73,9 +103,14 @@ pub async fn request_verification(
         match device.request_verification().await {
             Ok(verification) => {
                 let flow_id = verification.flow_id().to_string();
-                println!("Verification requested successfully! Flow ID: {}", flow_id);
+                info!("Verification requested successfully! Flow ID: {}", flow_id);
 
-                *state.verification_flow_id.write().await = Some(flow_id.clone());
+                *state.verification_flow.write().await = Some(VerificationFlow {
+                    flow_id: flow_id.clone(),
+                    started_at: Instant::now(),
+                    we_started: verification.we_started(),
+                    other_device: Some(device.device_id().to_owned()),
+                });
 
                 return Ok(format!(
                     "Verification request sent! Check Element on device: {}",
@@ -83,7 +118,7 @@ pub async fn request_verification(
                 ));
             }
             Err(e) => {
-                println!("Failed to request from device {}: {}", device.device_id(), e);
+                warn!("Failed to request from device {}: {}", device.device_id(), e);
                 continue;
             }
         }
@@ -95,30 +130,37 @@ pub async fn request_verification(
 #[tauri::command]
 pub async fn get_verification_emoji(
     state: State<'_, MatrixState>,
-) -> Result<Vec<(String, String)>, String> {
+    language: Option<String>,
+) -> Result<Vec<(String, String, String)>, String> {
     let client = state.client.read().await;
     let client = client.as_ref().ok_or("Not logged in")?;
 
-    let flow_id_guard = state.verification_flow_id.read().await;
-    let flow_id = flow_id_guard.as_ref().ok_or("No active verification")?;
+    let flow_id = active_flow_id(&state, client).await?;
 
     let user_id = client.user_id().ok_or("No user ID")?;
     let encryption = client.encryption();
 
-    println!("Getting emoji for flow: {}", flow_id);
+    debug!("Getting emoji for flow: {}", flow_id);
 
     let verification = encryption
-        .get_verification_request(user_id, flow_id)
+        .get_verification_request(user_id, &flow_id)
         .await
         .ok_or("Verification not found")?;
 
-    println!("Verification state: is_ready={}, is_done={}, is_cancelled={}",
+    debug!("Verification state: is_ready={}, is_done={}, is_cancelled={}",
         verification.is_ready(),
         verification.is_done(),
         verification.is_cancelled(),
     );
 
     if verification.is_cancelled() {
+        let timed_out = verification
+            .cancel_info()
+            .is_some_and(|info| *info.cancel_code() == CancelCode::Timeout);
+        if timed_out {
+            *state.verification_flow.write().await = None;
+            return Err(flow_expired());
+        }
         return Err("Verification was cancelled".to_string());
     }
 
@@ -126,24 +168,24 @@ pub async fn get_verification_emoji(
         return Err("Waiting for other device to accept...".to_string());
     }
 
-    println!("Starting SAS verification...");
+    debug!("Starting SAS verification...");
     let sas = verification.start_sas()
         .await
         .map_err(|e| format!("Failed to start SAS: {}", e))?
         .ok_or("SAS not available - other device may not support emoji")?;
 
-    println!("SAS started, accepting...");
+    debug!("SAS started, accepting...");
     sas.accept().await
         .map_err(|e| format!("Failed to accept SAS: {}", e))?;
 
     sleep(Duration::from_millis(1000)).await;
 
     if let Some(emoji) = sas.emoji() {
-        let emoji_list: Vec<(String, String)> = emoji
+        let emoji_list: Vec<(String, String, String)> = emoji
             .iter()
-            .map(|e| (e.symbol.to_string(), e.description.to_string()))
+            .map(|e| localize(e.symbol, e.description, language.as_deref()))
             .collect();
-        println!("Got {} emoji", emoji_list.len());
+        debug!("Got {} emoji", emoji_list.len());
         return Ok(emoji_list);
     }
 
@@ -157,14 +199,13 @@ pub async fn confirm_verification(
     let client = state.client.read().await;
     let client = client.as_ref().ok_or("Not logged in")?;
 
-    let flow_id_guard = state.verification_flow_id.read().await;
-    let flow_id = flow_id_guard.as_ref().ok_or("No active verification")?;
+    let flow_id = active_flow_id(&state, client).await?;
 
     let user_id = client.user_id().ok_or("No user ID")?;
     let encryption = client.encryption();
 
     let verification = encryption
-        .get_verification_request(user_id, flow_id)
+        .get_verification_request(user_id, &flow_id)
         .await
         .ok_or("Verification not found")?;
 
@@ -173,23 +214,23 @@ pub async fn confirm_verification(
         .map_err(|e| format!("Failed to get SAS: {}", e))?
         .ok_or("SAS not available")?;
 
-    println!("Confirming verification...");
+    info!("Confirming verification...");
     sas.confirm()
         .await
         .map_err(|e| format!("Failed to confirm: {}", e))?;
 
-    println!("Confirmed! Waiting for completion...");
+    info!("Confirmed! Waiting for completion...");
 
     for _ in 0..20 {
         sleep(Duration::from_millis(500)).await;
 
         let verification_check = encryption
-            .get_verification_request(user_id, flow_id)
+            .get_verification_request(user_id, &flow_id)
             .await;
 
         if let Some(v) = verification_check {
             if v.is_done() {
-                println!("Verification complete!");
+                info!("Verification complete!");
 
                 client.sync_once(SyncSettings::default()).await
                     .map_err(|e| format!("Sync after verification failed: {}", e))?;
@@ -199,8 +240,7 @@ pub async fn confirm_verification(
         }
     }
 
-    drop(flow_id_guard);
-    *state.verification_flow_id.write().await = None;
+    *state.verification_flow.write().await = None;
 
     Ok("Verification confirmed and complete!".to_string())
 }
@@ -212,14 +252,17 @@ pub async fn cancel_verification(
     let client = state.client.read().await;
     let client = client.as_ref().ok_or("Not logged in")?;
 
-    let flow_id_guard = state.verification_flow_id.read().await;
-    let flow_id = flow_id_guard.as_ref().ok_or("No active verification")?;
+    // An expired flow is already cancelled; there is nothing left to do.
+    if expire_stale_flow(&state, client).await {
+        return Ok("Verification cancelled".to_string());
+    }
+    let flow_id = active_flow_id(&state, client).await?;
 
     let user_id = client.user_id().ok_or("No user ID")?;
     let encryption = client.encryption();
 
     let verification = encryption
-        .get_verification_request(user_id, flow_id)
+        .get_verification_request(user_id, &flow_id)
         .await
         .ok_or("Verification not found")?;
 
@@ -228,8 +271,87 @@ pub async fn cancel_verification(
         .await
         .map_err(|e| format!("Failed to cancel: {}", e))?;
 
-    drop(flow_id_guard);
-    *state.verification_flow_id.write().await = None;
+    *state.verification_flow.write().await = None;
 
     Ok("Verification cancelled".to_string())
 }
+
+/// The flow ID of the current verification. Fails with `FLOW_EXPIRED`
+/// when it outlived the spec's timeout, so the UI can start over.
+async fn active_flow_id(state: &MatrixState, client: &Client) -> Result<String, String> {
+    if expire_stale_flow(state, client).await {
+        return Err(flow_expired());
+    }
+    state
+        .verification_flow
+        .read()
+        .await
+        .as_ref()
+        .map(|flow| flow.flow_id.clone())
+        .ok_or_else(|| "No active verification".to_string())
+}
+
+fn flow_expired() -> String {
+    CommandError::new("FLOW_EXPIRED", "This verification request expired. Start a new one.").into()
+}
+
+/// Forgets the current verification once it is older than the spec's
+/// timeout, cancelling it with `m.timeout` when we started it. Called before
+/// each verification command and after each sync, for flows abandoned while
+/// the app stays open. Returns whether a flow expired.
+pub(crate) async fn expire_stale_flow(state: &MatrixState, client: &Client) -> bool {
+    let mut slot = state.verification_flow.write().await;
+    if slot.as_ref().is_none_or(|flow| flow.started_at.elapsed() < VERIFICATION_TIMEOUT) {
+        return false;
+    }
+    let Some(flow) = slot.take() else {
+        return false;
+    };
+    drop(slot);
+
+    info!("Verification {} expired", flow.flow_id);
+    if flow.we_started {
+        if let Err(e) = send_timeout_cancel(client, &flow).await {
+            warn!("Failed to cancel expired verification {}: {}", flow.flow_id, e);
+        }
+    }
+    let _ = state
+        .app
+        .emit("matrix://verification-expired", json!({ "flow_id": flow.flow_id }));
+    true
+}
+
+/// The SDK's own cancel always says `m.user`, so the timeout is sent as a
+/// to-device event directly.
+async fn send_timeout_cancel(client: &Client, flow: &VerificationFlow) -> Result<(), String> {
+    let user_id = client.user_id().ok_or("No user ID")?;
+    if let Some(verification) = client.encryption().get_verification_request(user_id, &flow.flow_id).await {
+        if verification.is_done() || verification.is_cancelled() {
+            return Ok(());
+        }
+    }
+
+    let content = ToDeviceKeyVerificationCancelEventContent::new(
+        OwnedTransactionId::from(flow.flow_id.as_str()),
+        "The verification request timed out".to_string(),
+        CancelCode::Timeout,
+    );
+    let content: Raw<AnyToDeviceEventContent> = Raw::new(&content)
+        .map_err(|e| e.to_string())?
+        .cast_unchecked();
+    let target = match &flow.other_device {
+        Some(device_id) => DeviceIdOrAllDevices::DeviceId(device_id.clone()),
+        None => DeviceIdOrAllDevices::AllDevices,
+    };
+    let messages = BTreeMap::from([(user_id.to_owned(), BTreeMap::from([(target, content)]))]);
+
+    client
+        .send(send_event_to_device::v3::Request::new_raw(
+            ToDeviceEventType::KeyVerificationCancel,
+            TransactionId::new(),
+            messages,
+        ))
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
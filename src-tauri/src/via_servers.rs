@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::{OwnedRoomOrAliasId, OwnedServerName};
+use matrix_sdk::{HttpError, RoomState};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tauri::State;
+use tracing::{debug, warn};
+
+use crate::errors::CommandError;
+use crate::state::MatrixState;
+
+/// How reaching a room through one via server went.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ViaServerOutcome {
+    pub server: String,
+    /// `not_found`, `forbidden`, `timeout`, `unreachable`, `rate_limited`
+    /// or `failed`.
+    pub category: String,
+    pub message: String,
+}
+
+/// Every via server failed; `last` is the error of the last one tried.
+pub(crate) struct ViaFailure {
+    pub outcomes: Vec<ViaServerOutcome>,
+    pub last: Option<matrix_sdk::Error>,
+}
+
+/// A room as seen from outside, for deciding whether to join it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomPreviewInfo {
+    pub room_id: String,
+    pub canonical_alias: Option<String>,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub avatar_url: Option<String>,
+    pub num_joined_members: u64,
+    pub room_type: Option<String>,
+    /// `public`, `invite`, `knock`, `restricted`, ...
+    pub join_rule: Option<String>,
+    pub is_world_readable: Option<bool>,
+    /// Our membership, when we have one: `joined`, `left`, `invited`,
+    /// `knocked` or `banned`.
+    pub membership: Option<String>,
+}
+
+/// The via servers to try, in order: `via`, then `retry_via` the caller
+/// added after an earlier attempt failed. Invalid and repeated names are
+/// dropped.
+pub(crate) fn via_servers(via: Option<Vec<String>>, retry_via: Option<Vec<String>>) -> Vec<OwnedServerName> {
+    let mut servers: Vec<OwnedServerName> = Vec::new();
+    for server in via.into_iter().chain(retry_via).flatten() {
+        match server.trim().parse::<OwnedServerName>() {
+            Ok(server) if !servers.contains(&server) => servers.push(server),
+            Ok(_) => {}
+            Err(_) => debug!("Ignoring invalid via server {:?}", server),
+        }
+    }
+    servers
+}
+
+/// Runs `attempt` through one via server at a time until one succeeds,
+/// so each server's failure can be reported, rather than only the one the
+/// homeserver happened to hit last.
+pub(crate) async fn through_each<T, F, Fut>(servers: &[OwnedServerName], mut attempt: F) -> Result<T, ViaFailure>
+where
+    F: FnMut(OwnedServerName) -> Fut,
+    Fut: Future<Output = Result<T, matrix_sdk::Error>>,
+{
+    let mut outcomes = Vec::new();
+    let mut last = None;
+    for server in servers {
+        match attempt(server.clone()).await {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                warn!("Reaching the room through {} failed: {}", server, e);
+                outcomes.push(ViaServerOutcome {
+                    server: server.to_string(),
+                    category: failure_category(&e).to_string(),
+                    message: e.to_string(),
+                });
+                last = Some(e);
+            }
+        }
+    }
+    Err(ViaFailure { outcomes, last })
+}
+
+fn failure_category(e: &matrix_sdk::Error) -> &'static str {
+    if let Some(error) = e.as_client_api_error() {
+        return match (e.client_api_error_kind(), error.status_code.as_u16()) {
+            (Some(ErrorKind::NotFound), _) | (_, 404) => "not_found",
+            (Some(ErrorKind::Forbidden { .. }), _) | (_, 403) => "forbidden",
+            (Some(ErrorKind::LimitExceeded { .. }), _) | (_, 429) => "rate_limited",
+            (_, 408 | 504) => "timeout",
+            (_, 502 | 503) => "unreachable",
+            _ => "failed",
+        };
+    }
+    match e {
+        matrix_sdk::Error::Http(error) => match error.as_ref() {
+            HttpError::Reqwest(error) if error.is_timeout() => "timeout",
+            HttpError::Reqwest(_) => "unreachable",
+            _ => "failed",
+        },
+        _ => "failed",
+    }
+}
+
+/// The error for `action` failing through every via server, with each
+/// server's category in `details.servers` and the full outcomes in
+/// `details.outcomes`. `ROOM_NOT_FOUND` when every server said the room
+/// doesn't exist, `VIA_SERVERS_FAILED` otherwise; in that case passing
+/// other servers as `retry_via` may help.
+pub(crate) fn via_failure_error(action: &str, outcomes: Vec<ViaServerOutcome>) -> String {
+    let servers: BTreeMap<&str, &str> = outcomes
+        .iter()
+        .map(|outcome| (outcome.server.as_str(), outcome.category.as_str()))
+        .collect();
+    let details = json!({ "servers": servers, "outcomes": outcomes });
+
+    let error = if outcomes.iter().all(|outcome| outcome.category == "not_found") {
+        CommandError::new(
+            "ROOM_NOT_FOUND",
+            format!("Failed to {}: none of the servers tried know this room; it may no longer exist", action),
+        )
+    } else {
+        CommandError::new(
+            "VIA_SERVERS_FAILED",
+            format!("Failed to {} through any of the {} servers tried", action, outcomes.len()),
+        )
+    };
+    error.with_details(details).into()
+}
+
+/// Previews a room by ID or alias before joining it. With `via` servers,
+/// each is tried in turn and a failure reports how every one of them went;
+/// `retry_via` adds servers to try after those.
+#[tauri::command]
+pub async fn preview_room(
+    state: State<'_, MatrixState>,
+    room_id_or_alias: String,
+    via: Option<Vec<String>>,
+    retry_via: Option<Vec<String>>,
+) -> Result<RoomPreviewInfo, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+
+    let target = OwnedRoomOrAliasId::try_from(room_id_or_alias.trim())
+        .map_err(|e| format!("Invalid room ID or alias: {}", e))?;
+    let servers = via_servers(via, retry_via);
+
+    let preview = match servers.is_empty() {
+        true => client
+            .get_room_preview(&target, Vec::new())
+            .await
+            .map_err(|e| format!("Failed to preview room: {}", e))?,
+        false => {
+            let (client, target) = (&client, &target);
+            through_each(&servers, |server| async move { client.get_room_preview(target, vec![server]).await })
+                .await
+                .map_err(|failure| via_failure_error("preview the room", failure.outcomes))?
+        }
+    };
+
+    Ok(RoomPreviewInfo {
+        room_id: preview.room_id.to_string(),
+        canonical_alias: preview.canonical_alias.map(|alias| alias.to_string()),
+        name: preview.name,
+        topic: preview.topic,
+        avatar_url: preview.avatar_url.map(|url| url.to_string()),
+        num_joined_members: preview.num_joined_members,
+        room_type: preview.room_type.map(|room_type| room_type.to_string()),
+        join_rule: preview.join_rule.map(|rule| rule.as_str().to_string()),
+        is_world_readable: preview.is_world_readable,
+        membership: preview.state.map(|state| membership_name(state).to_string()),
+    })
+}
+
+fn membership_name(state: RoomState) -> &'static str {
+    match state {
+        RoomState::Joined => "joined",
+        RoomState::Left => "left",
+        RoomState::Invited => "invited",
+        RoomState::Knocked => "knocked",
+        RoomState::Banned => "banned",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::api::client::error::{ErrorBody, StandardErrorBody};
+    use matrix_sdk::ruma::api::error::FromHttpResponseError;
+    use matrix_sdk::ruma::exports::http::StatusCode;
+    use matrix_sdk::RumaApiError;
+
+    fn api_error(status: u16, kind: ErrorKind) -> matrix_sdk::Error {
+        let body = ErrorBody::Standard(StandardErrorBody::new(kind, "nope".to_string()));
+        let error = matrix_sdk::ruma::api::client::Error::new(StatusCode::from_u16(status).unwrap(), body);
+        let error = HttpError::Api(Box::new(FromHttpResponseError::Server(RumaApiError::ClientApi(error))));
+        matrix_sdk::Error::Http(Box::new(error))
+    }
+
+    fn outcome(server: &str, category: &str) -> ViaServerOutcome {
+        ViaServerOutcome {
+            server: server.to_string(),
+            category: category.to_string(),
+            message: String::new(),
+        }
+    }
+
+    #[test]
+    fn retry_servers_follow_the_first_ones_without_repeats() {
+        let servers = via_servers(
+            Some(vec!["a.org".to_string(), " b.org ".to_string(), "not a server".to_string()]),
+            Some(vec!["b.org".to_string(), "c.org".to_string()]),
+        );
+        let servers: Vec<_> = servers.iter().map(|s| s.as_str()).collect();
+        assert_eq!(servers, ["a.org", "b.org", "c.org"]);
+        assert!(via_servers(None, None).is_empty());
+    }
+
+    #[test]
+    fn failures_are_categorized_by_error_kind_and_status() {
+        assert_eq!(failure_category(&api_error(404, ErrorKind::NotFound)), "not_found");
+        assert_eq!(failure_category(&api_error(400, ErrorKind::NotFound)), "not_found");
+        assert_eq!(failure_category(&api_error(403, ErrorKind::forbidden())), "forbidden");
+        assert_eq!(
+            failure_category(&api_error(429, ErrorKind::LimitExceeded { retry_after: None })),
+            "rate_limited",
+        );
+        assert_eq!(failure_category(&api_error(504, ErrorKind::Unknown)), "timeout");
+        assert_eq!(failure_category(&api_error(502, ErrorKind::Unknown)), "unreachable");
+        assert_eq!(failure_category(&api_error(500, ErrorKind::Unknown)), "failed");
+        assert_eq!(failure_category(&matrix_sdk::Error::InsufficientData), "failed");
+    }
+
+    #[test]
+    fn room_not_found_only_when_every_server_says_so() {
+        let error = via_failure_error("join", vec![outcome("a.org", "not_found"), outcome("b.org", "not_found")]);
+        let error: serde_json::Value = serde_json::from_str(&error).unwrap();
+        assert_eq!(error["code"], "ROOM_NOT_FOUND");
+
+        let error = via_failure_error("join", vec![outcome("a.org", "not_found"), outcome("b.org", "timeout")]);
+        let error: serde_json::Value = serde_json::from_str(&error).unwrap();
+        assert_eq!(error["code"], "VIA_SERVERS_FAILED");
+        assert_eq!(error["details"]["servers"], json!({ "a.org": "not_found", "b.org": "timeout" }));
+        assert_eq!(error["details"]["outcomes"].as_array().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::{OwnedEventId, OwnedRoomId};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tokio::sync::RwLock;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::auth::sanitize_user_id;
+use crate::compose::Draft;
+use crate::state::MatrixState;
+
+const VIEW_STATE_FILE: &str = "view_state.json";
+/// Scroll positions are written to disk at most this often.
+const VIEW_STATE_WRITE_DELAY: Duration = Duration::from_secs(2);
+
+pub type ViewStateStore = Arc<RwLock<ViewStates>>;
+
+/// Where the user left each room and what they were writing there, kept in
+/// the session directory so rooms reopen that way after a restart.
+#[derive(Default)]
+pub struct ViewStates {
+    pub rooms: HashMap<String, RoomViewState>,
+    pub drafts: HashMap<String, Draft>,
+    pub flush_scheduled: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoomViewState {
+    /// The last event the user looked at.
+    pub event_id: String,
+    /// `from_token` for `get_messages` that loads the page around
+    /// `event_id`; `None` when it is within the newest page.
+    pub pagination_token: Option<String>,
+}
+
+/// What is saved to `view_state.json`.
+#[derive(Serialize, Deserialize, Default)]
+struct SavedViewStates {
+    rooms: HashMap<String, RoomViewState>,
+    pagination_tokens: HashMap<String, String>,
+    #[serde(default)]
+    drafts: HashMap<String, Draft>,
+}
+
+/// Where to reopen the room, or `None` to open it at the live edge: when
+/// nothing was saved, or the saved event is gone from the server.
+#[tauri::command]
+pub async fn get_room_view_state(
+    state: State<'_, MatrixState>,
+    room_id: String,
+) -> Result<Option<RoomViewState>, String> {
+    let client = state.client.read().await.clone().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let Some(view) = state.view_states.read().await.rooms.get(room_id.as_str()).cloned() else {
+        return Ok(None);
+    };
+    let Ok(event_id) = OwnedEventId::try_from(view.event_id.as_str()) else {
+        return Ok(None);
+    };
+
+    match room.load_or_fetch_event(&event_id, None).await {
+        Ok(_) => Ok(Some(view)),
+        Err(e) => {
+            // Purged or redacted away; forget it so we don't ask again.
+            if matches!(e.client_api_error_kind(), Some(ErrorKind::NotFound)) {
+                info!("Saved position {} in {} no longer exists", event_id, room_id);
+                state.view_states.write().await.rooms.remove(room_id.as_str());
+                schedule_flush(&state).await;
+            } else {
+                warn!("Failed to check saved position in {}: {}", room_id, e);
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Records the event the user is looking at, or clears the position when
+/// `event_id` is `None` (back at the live edge). Called while scrolling;
+/// writes to disk are batched.
+#[tauri::command]
+pub async fn set_room_view_state(
+    state: State<'_, MatrixState>,
+    room_id: String,
+    event_id: Option<String>,
+) -> Result<(), String> {
+    if state.client.read().await.is_none() {
+        return Err("Not logged in".to_string());
+    }
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let event_id = event_id
+        .map(|id| OwnedEventId::try_from(id).map_err(|e| format!("Invalid event ID: {}", e)))
+        .transpose()?;
+
+    let pagination_token = state.pagination_tokens.read().await.get(room_id.as_str()).cloned();
+    {
+        let mut views = state.view_states.write().await;
+        match event_id {
+            Some(event_id) => {
+                views.rooms.insert(
+                    room_id.to_string(),
+                    RoomViewState {
+                        event_id: event_id.to_string(),
+                        pagination_token,
+                    },
+                );
+            }
+            None => {
+                views.rooms.remove(room_id.as_str());
+            }
+        }
+    }
+
+    schedule_flush(&state).await;
+    Ok(())
+}
+
+/// Loads the saved positions, drafts and pagination tokens after a session restore.
+pub(crate) async fn load_view_states(state: &MatrixState, user_id: &str) {
+    let path = view_state_path(&state.data_dir, user_id);
+    let saved: SavedViewStates = match fs::read_to_string(&path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable view state: {}", e);
+            SavedViewStates::default()
+        }),
+        Err(_) => SavedViewStates::default(),
+    };
+
+    {
+        let mut views = state.view_states.write().await;
+        views.rooms = saved.rooms;
+        views.drafts = saved.drafts;
+    }
+    state.pagination_tokens.write().await.extend(saved.pagination_tokens);
+}
+
+/// Writes the view state to disk after a short delay, so a burst of scroll
+/// or typing updates is saved once.
+pub(crate) async fn schedule_flush(state: &MatrixState) {
+    let Some(user_id) = state.user_id.read().await.clone() else {
+        return;
+    };
+    let mut views = state.view_states.write().await;
+    if views.flush_scheduled {
+        return;
+    }
+    views.flush_scheduled = true;
+
+    let path = view_state_path(&state.data_dir, &user_id);
+    let queue = state.view_states.clone();
+    let tokens = state.pagination_tokens.clone();
+    tauri::async_runtime::spawn(async move {
+        sleep(VIEW_STATE_WRITE_DELAY).await;
+
+        let saved = {
+            let mut views = queue.write().await;
+            views.flush_scheduled = false;
+            SavedViewStates {
+                rooms: views.rooms.clone(),
+                pagination_tokens: tokens.read().await.clone(),
+                drafts: views.drafts.clone(),
+            }
+        };
+        if let Err(e) = save_view_states(&path, &saved) {
+            warn!("{}", e);
+        }
+    });
+}
+
+fn view_state_path(data_dir: &Path, user_id: &str) -> PathBuf {
+    data_dir.join(sanitize_user_id(user_id)).join(VIEW_STATE_FILE)
+}
+
+/// Writes to a temporary file first so a crash never leaves half a file.
+fn save_view_states(path: &Path, saved: &SavedViewStates) -> Result<(), String> {
+    let json = serde_json::to_string(saved).map_err(|e| format!("Failed to serialize view state: {}", e))?;
+    let temp = path.with_extension("json.tmp");
+    fs::write(&temp, json).map_err(|e| format!("Failed to save view state: {}", e))?;
+    fs::rename(&temp, path).map_err(|e| format!("Failed to save view state: {}", e))
+}
@@ -0,0 +1,135 @@
+use matrix_sdk::ruma::events::room::history_visibility::HistoryVisibility;
+use matrix_sdk::ruma::OwnedRoomId;
+use matrix_sdk::{Room, RoomMemberships};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tauri::State;
+
+use crate::account_data::{get_global_json, set_global_json, APP_NAMESPACE};
+use crate::state::MatrixState;
+
+/// Rooms above this many joined members trigger the "large room" warning.
+const LARGE_ROOM_MEMBERS: u64 = 500;
+/// Checking every member's devices is skipped for rooms larger than this.
+const DEVICE_CHECK_MAX_MEMBERS: u64 = 100;
+
+pub(crate) fn dismissed_warnings_type() -> String {
+    format!("{}.dismissed_send_warnings", APP_NAMESPACE)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SendWarning {
+    /// Stable identifier: `world_readable_history`, `unencrypted_room`,
+    /// `unverified_devices` or `large_room`.
+    pub code: String,
+    pub message: String,
+}
+
+#[tauri::command]
+pub async fn get_send_warnings(
+    state: State<'_, MatrixState>,
+    room_id: String,
+) -> Result<Vec<SendWarning>, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let room_id: OwnedRoomId = room_id
+        .parse()
+        .map_err(|e| format!("Invalid room ID: {}", e))?;
+    let room = client.get_room(&room_id).ok_or("Room not found")?;
+
+    let mut warnings = Vec::new();
+
+    if room.history_visibility() == Some(HistoryVisibility::WorldReadable) {
+        warnings.push(SendWarning {
+            code: "world_readable_history".to_string(),
+            message: "Anyone can read this room's history, including people who never join".to_string(),
+        });
+    }
+
+    let encrypted = room
+        .latest_encryption_state()
+        .await
+        .map(|s| s.is_encrypted())
+        .unwrap_or(false);
+
+    if !encrypted {
+        warnings.push(SendWarning {
+            code: "unencrypted_room".to_string(),
+            message: "Messages in this room are not end-to-end encrypted".to_string(),
+        });
+    } else if room.joined_members_count() <= DEVICE_CHECK_MAX_MEMBERS
+        && has_unverified_devices(&room).await?
+    {
+        warnings.push(SendWarning {
+            code: "unverified_devices".to_string(),
+            message: "Some devices in this room have not been verified".to_string(),
+        });
+    }
+
+    if room.joined_members_count() > LARGE_ROOM_MEMBERS {
+        warnings.push(SendWarning {
+            code: "large_room".to_string(),
+            message: format!(
+                "This room has {} members; your message will reach all of them",
+                room.joined_members_count()
+            ),
+        });
+    }
+
+    let dismissed = dismissed_codes(client).await?;
+    warnings.retain(|w| !dismissed.contains(&w.code));
+
+    Ok(warnings)
+}
+
+#[tauri::command]
+pub async fn dismiss_send_warning(
+    state: State<'_, MatrixState>,
+    code: String,
+) -> Result<Vec<String>, String> {
+    let client = state.client.read().await;
+    let client = client.as_ref().ok_or("Not logged in")?;
+
+    let mut codes = dismissed_codes(client).await?;
+    if !codes.contains(&code) {
+        codes.push(code);
+        set_global_json(client, &dismissed_warnings_type(), &json!({ "codes": codes })).await?;
+    }
+
+    Ok(codes)
+}
+
+async fn dismissed_codes(client: &matrix_sdk::Client) -> Result<Vec<String>, String> {
+    Ok(get_global_json(client, &dismissed_warnings_type())
+        .await?
+        .and_then(|content| serde_json::from_value(content.get("codes")?.clone()).ok())
+        .unwrap_or_default())
+}
+
+async fn has_unverified_devices(room: &Room) -> Result<bool, String> {
+    let client = room.client();
+    let own_device = client.device_id();
+
+    let members = room
+        .members_no_sync(RoomMemberships::JOIN)
+        .await
+        .map_err(|e| format!("Failed to load members: {}", e))?;
+
+    for member in members {
+        let devices = client
+            .encryption()
+            .get_user_devices(member.user_id())
+            .await
+            .map_err(|e| format!("Failed to get devices: {}", e))?;
+
+        if devices
+            .devices()
+            .any(|d| Some(d.device_id()) != own_device && !d.is_verified() && !d.is_deleted())
+        {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
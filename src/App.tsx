@@ -36,9 +36,9 @@ function App() {
 
   async function checkExistingSession() {
     try {
-      const userId = await matrixService.checkSession();
-      if (userId) {
-        setCurrentUser(userId);
+      const session = await matrixService.checkSession();
+      if (session.user_id && session.status !== "no_session" && session.status !== "expired") {
+        setCurrentUser(session.user_id);
         setLoggedIn(true);
         await loadRooms();
       }
@@ -91,7 +91,7 @@ export function ChatView({ room, messages, onSendMessage, onRefresh, onLoadMore,
               </div>
             )}
             {messages.map((msg, idx) => (
-              <div key={idx} className="message">
+              <div key={idx} className={msg.highlight ? "message highlight" : "message"}>
                 <div className="message-header">
                   <span className="sender">{msg.sender}</span>
                   <span className="timestamp">
@@ -108,13 +108,17 @@ export function ChatView({ room, messages, onSendMessage, onRefresh, onLoadMore,
       <div className="message-input">
         <input
           type="text"
-          placeholder="Type a message..."
+          placeholder={
+            room.can_send_messages
+              ? "Type a message..."
+              : `You can't post here: ${room.read_only_reason}`
+          }
           value={message}
           onChange={(e) => setMessage(e.target.value)}
           onKeyPress={(e) => e.key === "Enter" && handleSend()}
-          disabled={isLoading}
+          disabled={isLoading || !room.can_send_messages}
         />
-        <button onClick={handleSend} disabled={isLoading}>Send</button>
+        <button onClick={handleSend} disabled={isLoading || !room.can_send_messages}>Send</button>
       </div>
     </div>
   );
@@ -1,5 +1,5 @@
 import { useState } from "react";
-import { matrixService } from "../services/matrixService";
+import { matrixService, parseCommandError } from "../services/matrixService";
 import { loginInfo } from "./login-info";
 
 
@@ -29,7 +29,14 @@ export function Login({ onLoginSuccess }: LoginProps) {
         onLoginSuccess(response.user_id);
       }
     } catch (error) {
-      setError(String(error));
+      const coded = parseCommandError(error);
+      if (coded?.code === "HOMESERVER_UNREACHABLE") {
+        const causes = (coded.details as { causes?: string[] } | undefined)?.causes ?? [];
+        const cause = causes[causes.length - 1];
+        setError(cause ? `${coded.message} (${cause}). Check your network.` : coded.message);
+      } else {
+        setError(coded ? coded.message : String(error));
+      }
       setStatus("");
     } finally {
       setIsLoading(false);
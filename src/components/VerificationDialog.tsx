@@ -8,7 +8,7 @@ interface VerificationDialogProps {
 
 export function VerificationDialog({ onClose, onVerified }: VerificationDialogProps) {
   const [step, setStep] = useState<"request" | "emoji" | "waiting" | "recovery">("request");
-  const [emoji, setEmoji] = useState<[string, string][]>([]);
+  const [emoji, setEmoji] = useState<[string, string, string][]>([]);
   const [error, setError] = useState("");
   const [status, setStatus] = useState("");
   const [recoveryKey, setRecoveryKey] = useState("");
@@ -64,7 +64,7 @@ async function submitRecoveryKey() {
       attempts++;
       
       try {
-        const emojiList = await matrixService.getVerificationEmoji();
+        const emojiList = await matrixService.getVerificationEmoji(navigator.language);
         if (emojiList && emojiList.length > 0) {
           console.log("Got emoji!", emojiList);
           setEmoji(emojiList);
@@ -214,10 +214,11 @@ async function submitRecoveryKey() {
           <>
             <p><strong>Compare these emoji with your other device:</strong></p>
             <div className="emoji-grid">
-              {emoji.map(([symbol, name], idx) => (
+              {emoji.map(([symbol, name, english], idx) => (
                 <div key={idx} className="emoji-item">
                   <div className="emoji-symbol">{symbol}</div>
                   <div className="emoji-name">{name}</div>
+                  {english !== name && <div className="emoji-name-english">{english}</div>}
                 </div>
               ))}
             </div>
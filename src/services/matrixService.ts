@@ -4,51 +4,152 @@ import {
   MessagesResponse,
   LoginResponse,
   VerificationStatus,
+  ExportFormat,
+  ExportResult,
+  UrlPreviewResult,
+  ImagePack,
+  SendWarning,
+  ThreePid,
+  ThreePidValidation,
+  CommandError,
+  IdentityServerPolicy,
+  EmailInviteResult,
+  AuthStagePayload,
+  EncryptionDebugInfo,
+  InviteFilterSettings,
+  InviteInfo,
+  InviteRuleResult,
+  SessionStatus,
+  RoomFilter,
+  RoomListPage,
+  LogLevel,
+  DeviceTrust,
+  JoinRuleInfo,
+  SendReadiness,
+  ThumbnailSize,
+  MediaCacheStats,
+  AuditedStateEventType,
+  StateHistory,
+  ClientConfig,
+  StoreStats,
+  InviteUserResult,
+  DmSearchResult,
+  OwnDevice,
+  BackfillResult,
+  Settings,
+  BatchInviteResult,
+  ReadMarker,
+  EventContextResponse,
+  SyncDiagnostics,
+  MemberAutocomplete,
+  RoomMembersResponse,
+  DehydrationStatus,
+  RoomSettings,
+  DirectoryVisibility,
+  AccountExportResult,
+  RoomViewState,
+  EncryptedFile,
+  AppLockStatus,
+  MyRecentActivity,
+  RoomNotificationSettings,
+  SecurityRecommendation,
+  AliasAvailability,
+  ThreadNotificationMode,
+  ThreadMessages,
+  FullEventBody,
+  ComposeContext,
+  DraftView,
+  SavedMessage,
+  BanListExport,
+  BanImportResult,
+  PolicyList,
+  RawApiResponse,
+  PruneReport,
+  PushRuleset,
+  MergedDm,
+  MarkAllReadResult,
+  QueuedOperation,
+  UserVerificationStatus,
+  GapFill,
+  MediaSupport,
+  RoomPreviewInfo,
+  NotificationSnooze,
+  NotificationSettings,
+  NotificationBadge,
 } from "../types";
 
 export const matrixService = {
   async login(
     homeserver: string,
     username: string,
-    password: string
+    password: string,
+    deviceDisplayName?: string
   ): Promise<LoginResponse> {
     return await invoke<LoginResponse>("matrix_login", {
       homeserver: homeserver.trim(),
       username: username.trim(),
       password,
+      deviceDisplayName: deviceDisplayName ?? null,
     });
   },
 
-  async checkSession(): Promise<string | null> {
-    return await invoke<string | null>("check_session");
+  async checkSession(): Promise<SessionStatus> {
+    return await invoke<SessionStatus>("check_session");
   },
 
-  async logout(): Promise<string> {
-    return await invoke<string>("logout");
+  /** `erase: false` keeps this device's encryption keys for the next login. */
+  async logout(erase: boolean = true): Promise<string> {
+    return await invoke<string>("logout", { erase });
   },
 
   async sync(): Promise<string> {
     return await invoke<string>("matrix_sync");
   },
 
-  async getRooms(): Promise<RoomInfo[]> {
-    return await invoke<RoomInfo[]>("get_rooms");
+  /** `includeLeft` also lists rooms we left, for rereading their history. */
+  async getRooms(forceRefresh = false, includeLeft = false): Promise<RoomInfo[]> {
+    return await invoke<RoomInfo[]>("get_rooms", { forceRefresh, includeLeft });
   },
 
   async getMessages(
     roomId: string,
     limit: number = 100,
-    fromToken?: string
+    fromToken?: string,
+    forward = false,
+    /** A `MergedDm`'s `room_ids`, to read them as one timeline; `roomId` is its canonical room. */
+    mergedRoomIds?: string[],
+    /** Join replaced rooms that aren't world-readable to page on into their history. */
+    joinPredecessors = false
   ): Promise<MessagesResponse> {
     return await invoke<MessagesResponse>("get_messages", {
       roomId,
       limit,
       fromToken: fromToken || null,
+      forward,
+      // Minutes east of UTC, for day dividers.
+      tzOffsetMinutes: -new Date().getTimezoneOffset(),
+      mergedRoomIds: mergedRoomIds ?? null,
+      joinPredecessors,
     });
   },
 
-  async sendMessage(roomId: string, message: string): Promise<string> {
-    return await invoke<string>("send_message", { roomId, message });
+  /**
+   * Pass the same `transactionId` (from `newTransactionId`) to every attempt
+   * at sending one message, so a retry never posts it twice. Messages to a
+   * merged DM go to its canonical room.
+   */
+  async sendMessage(
+    roomId: string,
+    message: string,
+    expandEmojiShortcodes: boolean = true,
+    transactionId?: string
+  ): Promise<string> {
+    return await invoke<string>("send_message", {
+      roomId,
+      message,
+      expandEmojiShortcodes,
+      transactionId,
+    });
   },
 
   async checkVerificationStatus(): Promise<VerificationStatus> {
@@ -62,8 +163,11 @@ export const matrixService = {
   async requestRecoveryKeyVerification(recoveryKey: string): Promise<string> {
     return await invoke<string>("verify_with_recovery_key", { recoveryKey });
   },
-  async getVerificationEmoji(): Promise<[string, string][]> {
-    return await invoke<[string, string][]>("get_verification_emoji");
+  /** Returns `[symbol, localizedDescription, englishDescription]` tuples. */
+  async getVerificationEmoji(language?: string): Promise<[string, string, string][]> {
+    return await invoke<[string, string, string][]>("get_verification_emoji", {
+      language: language ?? null,
+    });
   },
 
   async confirmVerification(): Promise<string> {
@@ -77,4 +181,923 @@ export const matrixService = {
   async requestRoomKeys(roomId: string): Promise<string> {
   return await invoke<string>("request_room_keys", { roomId });
 },
+
+  async exportRoomHistory(
+    roomId: string,
+    format: ExportFormat,
+    path: string,
+    fromTs?: number,
+    toTs?: number,
+    downloadMedia: boolean = false
+  ): Promise<ExportResult> {
+    return await invoke<ExportResult>("export_room_history", {
+      roomId,
+      format,
+      path,
+      fromTs: fromTs ?? null,
+      toTs: toTs ?? null,
+      downloadMedia,
+    });
+  },
+
+  async cancelExport(): Promise<string> {
+    return await invoke<string>("cancel_export");
+  },
+
+  async getUrlPreview(
+    url: string,
+    roomId?: string,
+    allowInEncryptedRooms: boolean = false
+  ): Promise<UrlPreviewResult> {
+    return await invoke<UrlPreviewResult>("get_url_preview", {
+      url,
+      roomId: roomId ?? null,
+      allowInEncryptedRooms,
+    });
+  },
+
+  async sendReaction(
+    roomId: string,
+    eventId: string,
+    key: string,
+    shortcode?: string
+  ): Promise<string> {
+    return await invoke<string>("send_reaction", {
+      roomId,
+      eventId,
+      key,
+      shortcode: shortcode ?? null,
+    });
+  },
+
+  async getEmojiPacks(roomId: string): Promise<ImagePack[]> {
+    return await invoke<ImagePack[]>("get_emoji_packs", { roomId });
+  },
+
+  async markRead(roomId: string, eventId: string): Promise<boolean> {
+    return await invoke<boolean>("mark_read", { roomId, eventId });
+  },
+
+  async markRoomAsRead(roomId: string): Promise<boolean> {
+    return await invoke<boolean>("mark_room_as_read", { roomId });
+  },
+
+  async markAllRoomsAsRead(): Promise<MarkAllReadResult> {
+    return await invoke<MarkAllReadResult>("mark_all_rooms_as_read");
+  },
+
+  async getSendWarnings(roomId: string): Promise<SendWarning[]> {
+    return await invoke<SendWarning[]>("get_send_warnings", { roomId });
+  },
+
+  async dismissSendWarning(code: string): Promise<string[]> {
+    return await invoke<string[]>("dismiss_send_warning", { code });
+  },
+
+  async getStickerPacks(roomId?: string): Promise<ImagePack[]> {
+    return await invoke<ImagePack[]>("get_sticker_packs", {
+      roomId: roomId ?? null,
+    });
+  },
+
+  async sendSticker(
+    roomId: string,
+    packSource: string,
+    packStateKey: string | null,
+    shortcode: string
+  ): Promise<string> {
+    return await invoke<string>("send_sticker", {
+      roomId,
+      packSource,
+      packStateKey,
+      shortcode,
+    });
+  },
+
+  /** Sends a video; `thumbnailPath` is an image of a frame, if available. */
+  async sendVideo(
+    roomId: string,
+    filePath: string,
+    thumbnailPath: string | null,
+    durationMs: number | null,
+    width: number | null,
+    height: number | null,
+    caption?: string
+  ): Promise<string> {
+    return await invoke<string>("send_video", {
+      roomId,
+      filePath,
+      thumbnailPath,
+      durationMs,
+      width,
+      height,
+      caption,
+    });
+  },
+
+  /** `thumbnailPath` should be a still frame when the image is animated. */
+  async sendImage(
+    roomId: string,
+    filePath: string,
+    thumbnailPath: string | null,
+    caption?: string
+  ): Promise<string> {
+    return await invoke<string>("send_image", {
+      roomId,
+      filePath,
+      thumbnailPath,
+      caption,
+    });
+  },
+
+  async getThreepids(): Promise<ThreePid[]> {
+    return await invoke<ThreePid[]>("get_threepids");
+  },
+
+  async addEmail(email: string): Promise<ThreePidValidation> {
+    return await invoke<ThreePidValidation>("add_email", { email });
+  },
+
+  async submitEmailToken(
+    sid: string,
+    clientSecret: string,
+    token?: string
+  ): Promise<string> {
+    return await invoke<string>("submit_email_token", {
+      sid,
+      clientSecret,
+      token: token ?? null,
+    });
+  },
+
+  async removeThreepid(medium: string, address: string): Promise<string> {
+    return await invoke<string>("remove_threepid", { medium, address });
+  },
+
+
+  async getIdentityServer(): Promise<string | null> {
+    return await invoke<string | null>("get_identity_server");
+  },
+
+  async setIdentityServer(url: string | null): Promise<string | null> {
+    return await invoke<string | null>("set_identity_server", { url });
+  },
+
+  async getIdentityServerTerms(): Promise<IdentityServerPolicy[]> {
+    return await invoke<IdentityServerPolicy[]>("get_identity_server_terms");
+  },
+
+  async acceptIdentityServerTerms(policyUrls: string[]): Promise<string> {
+    return await invoke<string>("accept_identity_server_terms", { policyUrls });
+  },
+
+  async inviteByEmail(roomId: string, email: string): Promise<EmailInviteResult> {
+    return await invoke<EmailInviteResult>("invite_by_email", { roomId, email });
+  },
+
+  async completeAuth<T = unknown>(session: string, auth: AuthStagePayload): Promise<T> {
+    return await invoke<T>("complete_auth", { session, auth });
+  },
+
+  async cancelAuth(session: string): Promise<void> {
+    await invoke("cancel_auth", { session });
+  },
+
+  async registerAccount(
+    homeserver: string,
+    username: string,
+    password: string,
+    initialDeviceName?: string,
+    token?: string
+  ): Promise<LoginResponse> {
+    return await invoke<LoginResponse>("register_account", {
+      homeserver,
+      username,
+      password,
+      initialDeviceName: initialDeviceName ?? null,
+      token: token ?? null,
+    });
+  },
+
+  async getEncryptionDebug(roomId: string): Promise<EncryptionDebugInfo> {
+    return await invoke<EncryptionDebugInfo>("get_encryption_debug", { roomId });
+  },
+
+  async rotateRoomKey(roomId: string): Promise<string> {
+    return await invoke<string>("rotate_room_key", { roomId });
+  },
+
+  async recordRoomVisit(roomId: string): Promise<string[]> {
+    return await invoke<string[]>("record_room_visit", { roomId });
+  },
+
+  async getRecentRooms(): Promise<RoomInfo[]> {
+    return await invoke<RoomInfo[]>("get_recent_rooms");
+  },
+
+  async getInviteFilter(): Promise<InviteFilterSettings> {
+    return await invoke<InviteFilterSettings>("get_invite_filter");
+  },
+
+  async setInviteFilter(settings: InviteFilterSettings): Promise<InviteFilterSettings> {
+    return await invoke<InviteFilterSettings>("set_invite_filter", { settings });
+  },
+
+  async getInvites(): Promise<InviteInfo[]> {
+    return await invoke<InviteInfo[]>("get_invites");
+  },
+
+  async testInviteRule(
+    userId: string,
+    settings?: InviteFilterSettings
+  ): Promise<InviteRuleResult> {
+    return await invoke<InviteRuleResult>("test_invite_rule", {
+      userId,
+      settings: settings ?? null,
+    });
+  },
+
+  async getRoomsFiltered(filter: RoomFilter): Promise<RoomListPage> {
+    return await invoke<RoomListPage>("get_rooms_filtered", { filter });
+  },
+
+  /** Returns the base64 QR payload; listen to `matrix://qr-login-progress`. */
+  async startQrLoginGrant(): Promise<string> {
+    return await invoke<string>("start_qr_login_grant");
+  },
+
+  async confirmQrLogin(checkCode: number): Promise<void> {
+    await invoke("confirm_qr_login", { checkCode });
+  },
+
+  async denyQrLogin(): Promise<void> {
+    await invoke("deny_qr_login");
+  },
+
+  /** Bumps log verbosity at runtime: error, warn, info, debug or trace. */
+  async setLogLevel(level: LogLevel): Promise<string> {
+    return await invoke<string>("set_log_level", { level });
+  },
+
+  /** Zips recent logs and a redacted state summary; returns the zip's path. */
+  async createDebugBundle(logFiles?: number): Promise<string> {
+    return await invoke<string>("create_debug_bundle", { logFiles });
+  },
+
+  async getDeviceTrust(userId: string, deviceId: string): Promise<DeviceTrust> {
+    return await invoke<DeviceTrust>("get_device_trust", { userId, deviceId });
+  },
+
+  /** Marks a device verified if `fingerprint` matches its Ed25519 key. */
+  async manuallyVerifyDevice(
+    userId: string,
+    deviceId: string,
+    fingerprint: string
+  ): Promise<DeviceTrust> {
+    return await invoke<DeviceTrust>("manually_verify_device", {
+      userId,
+      deviceId,
+      fingerprint,
+    });
+  },
+
+  /** Withholds room keys from a device so it can't read our messages. */
+  async blockDevice(userId: string, deviceId: string): Promise<DeviceTrust> {
+    return await invoke<DeviceTrust>("block_device", { userId, deviceId });
+  },
+
+  async unblockDevice(userId: string, deviceId: string): Promise<DeviceTrust> {
+    return await invoke<DeviceTrust>("unblock_device", { userId, deviceId });
+  },
+
+  async getJoinRule(roomId: string): Promise<JoinRuleInfo> {
+    return await invoke<JoinRuleInfo>("get_join_rule", { roomId });
+  },
+
+  async setJoinRule(
+    roomId: string,
+    rule: "public" | "invite" | "knock" | "restricted",
+    allowedSpaceIds?: string[]
+  ): Promise<string> {
+    return await invoke<string>("set_join_rule", {
+      roomId,
+      rule,
+      allowedSpaceIds,
+    });
+  },
+
+  async getSendReadiness(roomId: string): Promise<SendReadiness> {
+    return await invoke<SendReadiness>("get_send_readiness", { roomId });
+  },
+
+  /** Loads members and device keys so the first send in a big room is quick. */
+  async prepareEncryption(roomId: string): Promise<SendReadiness> {
+    return await invoke<SendReadiness>("prepare_encryption", { roomId });
+  },
+
+  /** Returns a local file path for the media at the given size, cached on disk. */
+  /** Thumbnails of animated images are still frames unless `animated`. */
+  async getThumbnail(
+    mxc: string,
+    size: ThumbnailSize,
+    animated = false
+  ): Promise<string> {
+    return await invoke<string>("get_thumbnail", { mxc, size, animated });
+  },
+
+  /** Path of the decrypted file. Fails with `INTEGRITY_CHECK_FAILED` when
+   * the download doesn't match the hash in its event. */
+  async getEncryptedMedia(file: EncryptedFile): Promise<string> {
+    return await invoke<string>("get_encrypted_media", { file });
+  },
+
+  /** Local path of any media, fetched with the access token: `file` for
+   * encrypted attachments, else `mxc` at `size` (the original by default). */
+  async fetchMedia(media: {
+    mxc?: string;
+    file?: EncryptedFile;
+    size?: ThumbnailSize;
+    animated?: boolean;
+  }): Promise<string> {
+    return await invoke<string>("fetch_media", {
+      mxc: media.mxc ?? null,
+      file: media.file ?? null,
+      size: media.size ?? null,
+      animated: media.animated ?? null,
+    });
+  },
+
+  async getMediaSupport(): Promise<MediaSupport> {
+    return await invoke<MediaSupport>("get_media_support");
+  },
+
+  async getMediaCacheStats(): Promise<MediaCacheStats> {
+    return await invoke<MediaCacheStats>("get_media_cache_stats");
+  },
+
+  async setMediaCacheLimit(maxBytes: number): Promise<MediaCacheStats> {
+    return await invoke<MediaCacheStats>("set_media_cache_limit", { maxBytes });
+  },
+
+  /** Who changed the room's name, topic or power levels, oldest first. */
+  async getRoomStateHistory(
+    roomId: string,
+    eventType: AuditedStateEventType,
+    maxEvents?: number
+  ): Promise<StateHistory> {
+    return await invoke<StateHistory>("get_room_state_history", {
+      roomId,
+      eventType,
+      maxEvents,
+    });
+  },
+
+  /** Signs in with an existing access token instead of a password. */
+  async loginWithToken(
+    homeserver: string,
+    userId: string,
+    accessToken: string,
+    deviceId: string
+  ): Promise<LoginResponse> {
+    return await invoke<LoginResponse>("login_with_token", {
+      homeserver: homeserver.trim(),
+      userId: userId.trim(),
+      accessToken: accessToken.trim(),
+      deviceId: deviceId.trim(),
+    });
+  },
+
+  /** Defaults from the homeserver's well-known, or null before the first fetch. */
+  async getClientConfig(): Promise<ClientConfig | null> {
+    return await invoke<ClientConfig | null>("get_client_config");
+  },
+
+  async getStoreStats(): Promise<StoreStats> {
+    return await invoke<StoreStats>("get_store_stats");
+  },
+
+  /** Rebuilds the room cache from a fresh sync, keeping encryption keys. */
+  async clearLocalCache(): Promise<void> {
+    await invoke("clear_local_cache");
+  },
+
+  /** Invites a user; `shareHistory` also sends them the keys to read past messages. */
+  async inviteUser(
+    roomId: string,
+    userId: string,
+    shareHistory = false
+  ): Promise<InviteUserResult> {
+    return await invoke<InviteUserResult>("invite_user", {
+      roomId,
+      userId: userId.trim(),
+      shareHistory,
+    });
+  },
+
+  /** Accepts a member's changed identity so messages can be sent to them again. */
+  async acknowledgeIdentityChange(userId: string): Promise<void> {
+    await invoke("acknowledge_identity_change", { userId });
+  },
+
+  async withdrawVerification(userId: string): Promise<void> {
+    await invoke("withdraw_verification", { userId });
+  },
+
+  /** Searches all current and past DMs with a user, newest hits first. */
+  async searchDmHistory(
+    userId: string,
+    query: string,
+    limit?: number
+  ): Promise<DmSearchResult> {
+    return await invoke<DmSearchResult>("search_dm_history", {
+      userId: userId.trim(),
+      query,
+      limit: limit ?? null,
+    });
+  },
+
+  /** Lists our own sessions, the current one first. */
+  async getDevices(): Promise<OwnDevice[]> {
+    return await invoke<OwnDevice[]>("get_devices");
+  },
+
+  async renameCurrentDevice(name: string): Promise<void> {
+    await invoke("rename_current_device", { name });
+  },
+
+  /** Loads older history into the local index; later calls resume where this one stopped. */
+  async backfillRoom(roomId: string, maxEvents?: number): Promise<BackfillResult> {
+    return await invoke<BackfillResult>("backfill_room", {
+      roomId,
+      maxEvents: maxEvents ?? null,
+    });
+  },
+
+  async cancelBackfill(roomId: string): Promise<void> {
+    await invoke("cancel_backfill", { roomId });
+  },
+
+  /** Upgrades a room and returns the ID of the room that replaces it. */
+  async upgradeRoom(roomId: string, newVersion: string): Promise<string> {
+    return await invoke<string>("upgrade_room", { roomId, newVersion });
+  },
+
+  /** Sends a copy of a message to another room and returns the new event ID. */
+  async forwardMessage(
+    sourceRoomId: string,
+    eventId: string,
+    targetRoomId: string
+  ): Promise<string> {
+    return await invoke<string>("forward_message", {
+      sourceRoomId,
+      eventId,
+      targetRoomId,
+    });
+  },
+
+  async getSettings(): Promise<Settings> {
+    return await invoke<Settings>("get_settings");
+  },
+
+  /** Merges `patch` into the stored settings; `null` resets a key to its default. */
+  async updateSettings(patch: Partial<Settings>): Promise<Settings> {
+    return await invoke<Settings>("update_settings", { patch });
+  },
+
+  async rejoinRoom(roomId: string): Promise<RoomInfo> {
+    return await invoke<RoomInfo>("rejoin_room", { roomId });
+  },
+
+  /** Joins a room by ID or alias; `via` lists servers to join through,
+   * tried one at a time. Fails with `ROOM_NOT_FOUND` or `VIA_SERVERS_FAILED`
+   * listing how each server went; `retryVia` adds servers to try next. */
+  async joinRoom(roomIdOrAlias: string, via?: string[], retryVia?: string[]): Promise<RoomInfo> {
+    return await invoke<RoomInfo>("join_room", {
+      roomIdOrAlias,
+      via: via ?? null,
+      retryVia: retryVia ?? null,
+    });
+  },
+
+  /** Previews a room before joining; fails like `joinRoom`. */
+  async previewRoom(roomIdOrAlias: string, via?: string[], retryVia?: string[]): Promise<RoomPreviewInfo> {
+    return await invoke<RoomPreviewInfo>("preview_room", {
+      roomIdOrAlias,
+      via: via ?? null,
+      retryVia: retryVia ?? null,
+    });
+  },
+
+  /** Invites several users, returning one result per user. */
+  async inviteUsers(roomId: string, userIds: string[]): Promise<BatchInviteResult[]> {
+    return await invoke<BatchInviteResult[]>("invite_users", { roomId, userIds });
+  },
+
+  async cancelInvites(): Promise<void> {
+    await invoke("cancel_invites");
+  },
+
+  async getReadMarker(roomId: string): Promise<ReadMarker> {
+    return await invoke<ReadMarker>("get_read_marker", { roomId });
+  },
+
+  /** Loads messages around an event; page on with `getMessages` and the returned tokens. */
+  async getEventContext(
+    roomId: string,
+    eventId: string,
+    contextSize?: number
+  ): Promise<EventContextResponse> {
+    return await invoke<EventContextResponse>("get_event_context", {
+      roomId,
+      eventId,
+      contextSize: contextSize ?? null,
+    });
+  },
+
+  /** Sets how long the server keeps messages; null removes the limit. */
+  async setRoomRetention(roomId: string, maxLifetimeMs: number | null): Promise<void> {
+    await invoke("set_room_retention", { roomId, maxLifetimeMs });
+  },
+
+  async getSyncDiagnostics(): Promise<SyncDiagnostics> {
+    return await invoke<SyncDiagnostics>("get_sync_diagnostics");
+  },
+
+  /** Members matching a mention prefix, from the local store. */
+  async autocompleteMembers(
+    roomId: string,
+    prefix: string,
+    limit?: number
+  ): Promise<MemberAutocomplete> {
+    return await invoke<MemberAutocomplete>("autocomplete_members", {
+      roomId,
+      prefix,
+      limit: limit ?? null,
+    });
+  },
+
+  async leaveRoom(roomId: string, reason?: string): Promise<void> {
+    await invoke("leave_room", { roomId, reason: reason ?? null });
+  },
+
+  async kickUser(roomId: string, userId: string, reason?: string): Promise<void> {
+    await invoke("kick_user", { roomId, userId, reason: reason ?? null });
+  },
+
+  async banUser(roomId: string, userId: string, reason?: string): Promise<void> {
+    await invoke("ban_user", { roomId, userId, reason: reason ?? null });
+  },
+
+  async unbanUser(roomId: string, userId: string, reason?: string): Promise<void> {
+    await invoke("unban_user", { roomId, userId, reason: reason ?? null });
+  },
+
+  /** Members of a room; large rooms stream the rest via `matrix://members-chunk`. */
+  async getRoomMembers(roomId: string): Promise<RoomMembersResponse> {
+    return await invoke<RoomMembersResponse>("get_room_members", { roomId });
+  },
+
+  async cancelMemberLoading(roomId: string): Promise<boolean> {
+    return await invoke<boolean>("cancel_member_loading", { roomId });
+  },
+
+  async getDehydrationStatus(): Promise<DehydrationStatus> {
+    return await invoke<DehydrationStatus>("get_dehydration_status");
+  },
+
+  async getRoomState(roomId: string): Promise<RoomSettings> {
+    return await invoke<RoomSettings>("get_room_state", { roomId });
+  },
+
+  async getRoomDirectoryVisibility(roomId: string): Promise<DirectoryVisibility> {
+    return await invoke<DirectoryVisibility>("get_room_directory_visibility", { roomId });
+  },
+
+  /** Lists or unlists the room; PUBLISHING_NOT_ALLOWED when the server forbids it. */
+  async setRoomDirectoryVisibility(roomId: string, isPublic: boolean): Promise<void> {
+    await invoke("set_room_directory_visibility", { roomId, public: isPublic });
+  },
+
+  /** Zips the account's rooms, keys, account data, profile and devices,
+   * plus message history for `messageRoomIds`. Stopped by `cancelExport`. */
+  async exportAccountData(
+    path: string,
+    passphrase: string,
+    messageRoomIds: string[] = []
+  ): Promise<AccountExportResult> {
+    return await invoke<AccountExportResult>("export_account_data", {
+      path,
+      passphrase,
+      messageRoomIds,
+    });
+  },
+
+  /** `null` means open at the live edge. */
+  async getRoomViewState(roomId: string): Promise<RoomViewState | null> {
+    return await invoke<RoomViewState | null>("get_room_view_state", { roomId });
+  },
+
+  async setRoomViewState(roomId: string, eventId: string | null): Promise<void> {
+    await invoke("set_room_view_state", { roomId, eventId });
+  },
+
+  async setAppLock(
+    pin: string,
+    currentPin?: string,
+    autoLockSecs?: number,
+    wipeAfter?: number,
+  ): Promise<AppLockStatus> {
+    return await invoke<AppLockStatus>("set_app_lock", {
+      pin,
+      currentPin: currentPin ?? null,
+      autoLockSecs: autoLockSecs ?? null,
+      wipeAfter: wipeAfter ?? null,
+    });
+  },
+
+  async unlockApp(pin: string): Promise<void> {
+    return await invoke<void>("unlock_app", { pin });
+  },
+
+  async lockApp(): Promise<void> {
+    return await invoke<void>("lock_app");
+  },
+
+  async isLocked(): Promise<boolean> {
+    return await invoke<boolean>("is_locked");
+  },
+
+  async notifyActivity(): Promise<void> {
+    return await invoke<void>("notify_activity");
+  },
+
+  async getMyRecentActivity(roomId: string): Promise<MyRecentActivity | null> {
+    return await invoke<MyRecentActivity | null>("get_my_recent_activity", { roomId });
+  },
+
+  /** Fails with `USER_REPORTS_UNSUPPORTED` on servers without user reports. */
+  async reportUser(roomId: string, userId: string, reason: string): Promise<void> {
+    return await invoke<void>("report_user", { roomId, userId, reason });
+  },
+
+  async getUserNote(userId: string): Promise<string | null> {
+    return await invoke<string | null>("get_user_note", { userId });
+  },
+
+  /** An empty note removes it. */
+  async setUserNote(userId: string, note: string): Promise<void> {
+    return await invoke<void>("set_user_note", { userId, note });
+  },
+
+  async getRoomNotificationSettings(roomId: string): Promise<RoomNotificationSettings> {
+    return await invoke<RoomNotificationSettings>("get_room_notification_settings", { roomId });
+  },
+
+  /** Fails with `KEYWORD_ALREADY_GLOBAL` or `KEYWORD_ALREADY_IN_ROOM` for duplicates. */
+  async addRoomKeyword(roomId: string, keyword: string): Promise<void> {
+    return await invoke<void>("add_room_keyword", { roomId, keyword });
+  },
+
+  async removeRoomKeyword(roomId: string, keyword: string): Promise<void> {
+    return await invoke<void>("remove_room_keyword", { roomId, keyword });
+  },
+
+  /** The single security banner to show, if any (`all_good`). */
+  async getSecurityRecommendation(): Promise<SecurityRecommendation> {
+    return await invoke<SecurityRecommendation>("get_security_recommendation");
+  },
+
+  /** Live check while typing; `room` is completed with our homeserver. */
+  async checkAliasAvailable(alias: string): Promise<AliasAvailability> {
+    return await invoke<AliasAvailability>("check_alias_available", { alias });
+  },
+
+  /** Creates the alias and makes it the room's address. */
+  async publishRoomAlias(roomId: string, alias: string): Promise<string> {
+    return await invoke<string>("publish_room_alias", { roomId, alias });
+  },
+
+  async getThreadNotificationMode(
+    roomId: string
+  ): Promise<ThreadNotificationMode> {
+    return await invoke<ThreadNotificationMode>(
+      "get_thread_notification_mode",
+      { roomId }
+    );
+  },
+
+  async setThreadNotificationMode(
+    roomId: string,
+    mode: ThreadNotificationMode
+  ): Promise<void> {
+    await invoke("set_thread_notification_mode", { roomId, mode });
+  },
+
+  async getThreadMessages(
+    roomId: string,
+    rootEventId: string,
+    fromToken?: string
+  ): Promise<ThreadMessages> {
+    return await invoke<ThreadMessages>("get_thread_messages", {
+      roomId,
+      rootEventId,
+      fromToken,
+    });
+  },
+
+  /** Threaded read receipt; the main timeline stays unread. */
+  async markThreadRead(roomId: string, rootEventId: string): Promise<void> {
+    await invoke("mark_thread_read", { roomId, rootEventId });
+  },
+
+  /** The uncut body of a message marked `truncated`. */
+  async getFullEventBody(
+    roomId: string,
+    eventId: string
+  ): Promise<FullEventBody> {
+    return await invoke<FullEventBody>("get_full_event_body", {
+      roomId,
+      eventId,
+    });
+  },
+
+  async getDraft(roomId: string): Promise<DraftView> {
+    return await invoke<DraftView>("get_draft", { roomId });
+  },
+
+  async setDraft(roomId: string, body: string): Promise<void> {
+    await invoke("set_draft", { roomId, body });
+  },
+
+  async setComposeContext(roomId: string, context: ComposeContext | null): Promise<DraftView> {
+    return await invoke<DraftView>("set_compose_context", { roomId, context });
+  },
+
+  async sendComposed(
+    roomId: string,
+    body: string,
+    expandEmojiShortcodes: boolean = true,
+    transactionId?: string
+  ): Promise<string> {
+    return await invoke<string>("send_composed", {
+      roomId,
+      body,
+      expandEmojiShortcodes,
+      transactionId,
+    });
+  },
+
+  /** An empty `answerIds` withdraws the user's vote. */
+  async votePoll(roomId: string, pollEventId: string, answerIds: string[]): Promise<string> {
+    return await invoke<string>("vote_poll", { roomId, pollEventId, answerIds });
+  },
+
+  async endPoll(roomId: string, pollEventId: string): Promise<string> {
+    return await invoke<string>("end_poll", { roomId, pollEventId });
+  },
+
+  async saveMessage(roomId: string, eventId: string): Promise<void> {
+    await invoke("save_message", { roomId, eventId });
+  },
+
+  async unsaveMessage(roomId: string, eventId: string): Promise<void> {
+    await invoke("unsave_message", { roomId, eventId });
+  },
+
+  /** Newest first. */
+  async getSavedMessages(): Promise<SavedMessage[]> {
+    return await invoke<SavedMessage[]>("get_saved_messages");
+  },
+
+  /** Tells the backend which room is in view, or `null` for none, so its notifications are held back. */
+  async setActiveRoom(roomId: string | null): Promise<void> {
+    await invoke("set_active_room", { roomId });
+  },
+
+  /** Writes the room's bans and their reasons to `path` as JSON. */
+  async exportBanList(roomId: string, path: string): Promise<BanListExport> {
+    return await invoke<BanListExport>("export_ban_list", { roomId, path });
+  },
+
+  /** Bans the users in a ban list file; `dryRun` only reports what would happen. */
+  async importBanList(roomId: string, path: string, dryRun: boolean): Promise<BanImportResult[]> {
+    return await invoke<BanImportResult[]>("import_ban_list", { roomId, path, dryRun });
+  },
+
+  async getPolicyLists(roomId: string): Promise<PolicyList[]> {
+    return await invoke<PolicyList[]>("get_policy_lists", { roomId });
+  },
+
+  async setPolicyLists(roomId: string, policyRoomIds: string[]): Promise<void> {
+    await invoke("set_policy_lists", { roomId, policyRoomIds });
+  },
+
+  /** Sends a raw `/_matrix/` request; needs developer mode. */
+  async rawApiRequest(method: string, path: string, bodyJson?: string): Promise<RawApiResponse> {
+    return await invoke<RawApiResponse>("raw_api_request", { method, path, bodyJson: bodyJson ?? null });
+  },
+
+  /**
+   * Breaks the session lock left by an app instance that crashed, after an
+   * `ALREADY_RUNNING` error with `stale: true`. Returns how many were broken.
+   */
+  async forceTakeover(): Promise<number> {
+    return await invoke<number>("force_takeover");
+  },
+
+  /** Signs in as a read-only guest, on servers that allow guest access. */
+  async guestLogin(homeserver: string): Promise<LoginResponse> {
+    return await invoke<LoginResponse>("guest_login", { homeserver });
+  },
+
+  /** Turns the guest session into an account, keeping its rooms. */
+  async upgradeGuestAccount(username: string, password: string): Promise<LoginResponse> {
+    return await invoke<LoginResponse>("upgrade_guest_account", { username, password });
+  },
+
+  async pruneLocalHistory(): Promise<PruneReport> {
+    return await invoke<PruneReport>("prune_local_history");
+  },
+
+  /** Re-fetches the push rules from the server, for debugging notification mismatches. */
+  async refreshPushRules(): Promise<PushRuleset> {
+    return await invoke<PushRuleset>("refresh_push_rules");
+  },
+
+  /** DMs grouped by contact, most recently active first. */
+  async getMergedDms(): Promise<MergedDm[]> {
+    return await invoke<MergedDm[]>("get_merged_dms");
+  },
+
+  /** Operations held back by the outbound rate limits, oldest first. */
+  async getOutboundQueue(): Promise<QueuedOperation[]> {
+    return await invoke<QueuedOperation[]>("get_outbound_queue");
+  },
+
+  async requestUserVerification(userId: string, roomId?: string): Promise<UserVerificationStatus> {
+    return await invoke<UserVerificationStatus>("request_user_verification", {
+      userId,
+      roomId: roomId ?? null,
+    });
+  },
+
+  async getUserVerification(language?: string): Promise<UserVerificationStatus | null> {
+    return await invoke<UserVerificationStatus | null>("get_user_verification", {
+      language: language ?? null,
+    });
+  },
+
+  async confirmUserVerification(): Promise<UserVerificationStatus> {
+    return await invoke<UserVerificationStatus>("confirm_user_verification");
+  },
+
+  async cancelUserVerification(mismatch?: boolean): Promise<void> {
+    await invoke("cancel_user_verification", { mismatch: mismatch ?? null });
+  },
+
+  async fillGap(roomId: string, gapId: string, tzOffsetMinutes?: number): Promise<GapFill> {
+    return await invoke<GapFill>("fill_gap", {
+      roomId,
+      gapId,
+      tzOffsetMinutes: tzOffsetMinutes ?? null,
+    });
+  },
+
+  /** Pauses notifications in this app only; `scope` is a room ID or `"global"`. */
+  async snoozeNotifications(scope: string, durationMinutes: number): Promise<NotificationSnooze> {
+    return await invoke<NotificationSnooze>("snooze_notifications", { scope, durationMinutes });
+  },
+
+  async unsnoozeNotifications(scope: string): Promise<void> {
+    await invoke("unsnooze_notifications", { scope });
+  },
+
+  async getNotificationSettings(): Promise<NotificationSettings> {
+    return await invoke<NotificationSettings>("get_notification_settings");
+  },
+
+  /** Unread counts for the app badge, leaving out snoozed rooms. */
+  async getNotificationBadge(): Promise<NotificationBadge> {
+    return await invoke<NotificationBadge>("get_notification_badge");
+  },
+
+  /** Returns false when typing notifications are off in the settings. */
+  async setTyping(roomId: string, typing: boolean): Promise<boolean> {
+    return await invoke<boolean>("set_typing", { roomId, typing });
+  },
+
+  async newTransactionId(): Promise<string> {
+    return await invoke<string>("new_transaction_id");
+  },
 };
+
+/** Parses a coded error thrown by a command; plain string errors yield null. */
+export function parseCommandError(error: unknown): CommandError | null {
+  if (typeof error !== "string") return null;
+  try {
+    const parsed = JSON.parse(error);
+    return parsed && typeof parsed.code === "string" ? (parsed as CommandError) : null;
+  } catch {
+    return null;
+  }
+}
@@ -2,12 +2,68 @@ export interface RoomInfo {
   room_id: string;
   name?: string;
   topic?: string;
+  avatar_url?: string | null;
+  history_visibility?: string | null;
+  can_send_messages: boolean;
+  read_only_reason?: string | null;
+  room_version: string;
+  federated: boolean;
+  /** Left or banned; its history is read-only. */
+  archived: boolean;
+  retention?: RoomRetention | null;
+  /** Set when someone else kicked or banned us. */
+  removal?: RoomRemoval | null;
+  bridges: BridgeInfo[];
+}
+
+/** A bridge to another network, from the room's bridge state event. */
+export interface BridgeInfo {
+  /** e.g. `irc`, `telegram`, `whatsapp`. */
+  protocol: string;
+  protocol_name?: string | null;
+  avatar_url?: string | null;
+  network?: string | null;
+  bot_user_id?: string | null;
+}
+
+/** Also the payload of `matrix://removed-from-room`. */
+export interface RoomRemoval {
+  room_id: string;
+  actor: string;
+  reason?: string | null;
+  banned: boolean;
+}
+
+/** Message lifetimes in milliseconds, from `m.room.retention`. */
+export interface RoomRetention {
+  min_lifetime?: number | null;
+  max_lifetime?: number | null;
 }
 
 export interface Message {
+  event_id?: string | null;
   sender: string;
+  sender_display_name?: string | null;
+  sender_disambiguated_name: string;
   body: string;
   timestamp: number;
+  /** When this device queued the message, for messages sent from here. */
+  received_at?: number;
+  content: MessageContent;
+  emotes: InlineEmote[];
+  highlight: boolean;
+  /** When the room's retention policy lets the server delete it. */
+  expires_at?: number | null;
+  /** The formatted body; `body` then hides any spoilers. */
+  segments?: Segment[] | null;
+  /** Protocol of the bridge that relayed it, matching `RoomInfo.bridges`. */
+  via_bridge?: string | null;
+  /** Body cut short; `getFullEventBody` has all of it. */
+  truncated?: boolean;
+  /** The room it is in, in merged DM timelines. */
+  room_id?: string | null;
+  /** From the room this one replaced, paged back into after the upgrade. */
+  from_predecessor?: boolean;
 }
 
 export interface LoginResponse {
@@ -15,6 +71,9 @@ export interface LoginResponse {
   user_id: string;
   device_id: string;
   message: string;
+  needs_verification: boolean;
+  /** Read-only guest session; sending fails with `GUEST_NOT_ALLOWED`. */
+  is_guest: boolean;
 }
 
 export interface MessagesResponse {
@@ -28,6 +87,1041 @@ export interface VerificationStatus {
   emoji?: [string, string][] | null;
 }
 
+export type ExportFormat = "json" | "text";
+
+export interface ExportResult {
+  path: string;
+  messages_exported: number;
+  undecryptable: number;
+}
+
+export interface ExportProgress {
+  room_id: string;
+  messages_exported: number;
+  oldest_timestamp?: number | null;
+  finished: boolean;
+}
+
+
+export interface UrlPreview {
+  url: string;
+  title?: string | null;
+  description?: string | null;
+  image_mxc?: string | null;
+  site_name?: string | null;
+}
+
+export type UrlPreviewResult =
+  | { status: "available"; preview: UrlPreview }
+  | { status: "unsupported"; reason: string }
+  | { status: "disabled" };
+
+
+export interface InlineEmote {
+  shortcode: string;
+  mxc: string;
+}
+
+export interface PackImage {
+  url: string;
+  body?: string | null;
+  info?: Record<string, unknown> | null;
+}
+
+export interface ImagePack {
+  name: string;
+  source: string;
+  state_key?: string | null;
+  avatar_url?: string | null;
+  images: Record<string, PackImage>;
+}
+
+
+export interface SendWarning {
+  code: "world_readable_history" | "unencrypted_room" | "unverified_devices" | "large_room";
+  message: string;
+}
+
+
+/** A piece of a formatted message body, rendered instead of its HTML. */
+export type Segment =
+  | { kind: "text"; text: string }
+  | { kind: "code"; text: string; block: boolean; language: string | null }
+  | { kind: "spoiler"; reason: string | null; segments: Segment[] }
+  | { kind: "link"; text: string; url: string }
+  | { kind: "mention"; text: string; target: string };
+
+export type MessageContent =
+  | { kind: "text" }
+  | { kind: "notice" }
+  | { kind: "emote" }
+  /** A non-spec msgtype; show `body` unless the type is known. */
+  | { kind: "custom"; msgtype: string }
+  | { kind: "encrypted" }
+  | {
+      kind: "sticker";
+      url: string;
+      encrypted: boolean;
+      mimetype?: string | null;
+      width?: number | null;
+      height?: number | null;
+    }
+  | {
+      kind: "image";
+      url: string;
+      encrypted: boolean;
+      file?: EncryptedFile | null;
+      filename: string;
+      caption?: string | null;
+      mimetype?: string | null;
+      size_bytes?: number | null;
+      /** GIF or APNG; `thumbnail` is then a still frame when present. */
+      is_animated: boolean;
+      width?: number | null;
+      height?: number | null;
+      thumbnail?: MediaThumbnail | null;
+    }
+  | {
+      kind: "video";
+      url: string;
+      encrypted: boolean;
+      file?: EncryptedFile | null;
+      filename: string;
+      caption?: string | null;
+      mimetype?: string | null;
+      size?: number | null;
+      duration_ms?: number | null;
+      width?: number | null;
+      height?: number | null;
+      thumbnail?: MediaThumbnail | null;
+    }
+  | {
+      kind: "membership";
+      /** e.g. "joined", "kicked", "banned", "invited". */
+      change: string;
+      target: string;
+      reason?: string | null;
+    }
+  | {
+      kind: "poll";
+      question: string;
+      poll_kind: PollKind;
+      max_selections: number;
+      answers: PollAnswer[];
+      /** The answers the user's counted vote picked. */
+      own_vote: string[];
+      voters: number;
+      ended: boolean;
+    }
+  | {
+      kind: "unsupported";
+      event_type: string;
+      /** Whether `body` is the event's own text fallback rather than a placeholder. */
+      has_fallback: boolean;
+    }
+  /** Not an event: starts a local day, `YYYY-MM-DD`. */
+  | { kind: "day_divider"; date: string }
+  /** Not an event: the start of the room; `sender` is its creator. */
+  | { kind: "history_boundary"; created_at?: number | null }
+  /** Not an event: in place of `history_boundary` when the room this one replaced can't be read. */
+  | { kind: "history_unavailable"; room_id: string; reason: string }
+  | { kind: "gap"; gap: GapInfo };
+
+/** Decryption info for media in encrypted rooms, as sent in the event. */
+export interface EncryptedFile {
+  url: string;
+  key: { kty: string; key_ops: string[]; alg: string; k: string; ext: boolean };
+  iv: string;
+  hashes: Record<string, string>;
+  v: string;
+}
+
+export interface MediaThumbnail {
+  url: string;
+  encrypted: boolean;
+  file?: EncryptedFile | null;
+  mimetype?: string | null;
+  width?: number | null;
+  height?: number | null;
+  size?: number | null;
+}
+
+
+export interface ThreePid {
+  medium: "email" | "msisdn";
+  address: string;
+  validated_at: number;
+  added_at: number;
+}
+
+export interface ThreePidValidation {
+  sid: string;
+  client_secret: string;
+  accepts_token: boolean;
+  instructions: string;
+}
+
+/** Coded errors are returned by commands as a JSON-encoded string. */
+export interface CommandError {
+  code: string;
+  message: string;
+  details?: unknown;
+}
+
+export interface IdentityServerPolicy {
+  name: string;
+  version: string | null;
+  url: string;
+}
+
+export interface EmailInviteResult {
+  invite_kind: "user" | "email";
+  user_id: string | null;
+}
+
+/** `details` of an `AUTH_REQUIRED` CommandError. */
+export interface AuthRequiredDetails {
+  session: string;
+  /** Each flow is a list of stage types, e.g. ["m.login.password"]. */
+  flows: string[][];
+  completed: string[];
+  params: Record<string, unknown>;
+  /** Fallback web pages for stages without native UI, keyed by stage type. */
+  fallback_urls: Record<string, string>;
+}
+
+/** Body of one auth stage; `{ type: "fallback" }` after a fallback page. */
+export type AuthStagePayload = { type: string } & Record<string, unknown>;
+
+export interface DeviceKeyStatus {
+  user_id: string;
+  device_id: string;
+  display_name: string | null;
+  verified: boolean;
+  blacklisted: boolean;
+  receives_keys: boolean;
+  withheld_reason: "blacklisted" | "invited_joined_only_history" | null;
+}
+
+export interface EncryptionDebugInfo {
+  room_id: string;
+  encrypted: boolean;
+  algorithm: string | null;
+  rotation_period_ms: number | null;
+  rotation_period_msgs: number | null;
+  history_visibility: string;
+  shares_with_invited: boolean;
+  share_strategy: string;
+  devices: DeviceKeyStatus[];
+  blacklisted_devices: DeviceKeyStatus[];
+  sharing_with: number;
+  withheld_from: number;
+  members_without_devices: string[];
+}
+
+export type InviteFilterAction = "hide" | "auto_reject" | "auto_reject_and_ignore";
+
+export interface InviteFilterSettings {
+  enabled: boolean;
+  blocked_servers: string[];
+  block_without_shared_rooms: boolean;
+  user_id_pattern: string | null;
+  action: InviteFilterAction;
+}
+
+export type InviteFilterReason = "blocked_server" | "no_shared_rooms" | "user_id_pattern";
+
+export interface InviteInfo {
+  room_id: string;
+  name: string | null;
+  inviter: string | null;
+  inviter_display_name: string | null;
+  filtered: boolean;
+  filter_reason: InviteFilterReason | null;
+  reason: string | null;
+  invited_at: number | null;
+  is_direct: boolean;
+  shared_rooms: string[];
+}
+
+export interface InviteRuleResult {
+  matched: boolean;
+  reason: InviteFilterReason | null;
+  action: InviteFilterAction | null;
+}
+
+export interface SessionStatus {
+  status: "valid" | "expired" | "no_session" | "offline_assumed_valid";
+  user_id: string | null;
+  device_id: string | null;
+  homeserver: string | null;
+  is_guest: boolean;
+}
+
+export type RoomSection = "dm" | "group" | "favourite" | "low_priority" | "space" | "invites";
+
+export interface RoomFilter {
+  query?: string;
+  section?: RoomSection;
+  /** Required when `section` is "space". */
+  space_id?: string;
+  unread_only?: boolean;
+  encrypted_only?: boolean;
+  include_left?: boolean;
+  limit?: number;
+  offset?: number;
+}
+
+export interface RoomListPage {
+  total: number;
+  rooms: RoomInfo[];
+}
+
+export interface QrLoginProgress {
+  stage:
+    | "qr_ready"
+    | "scanned"
+    | "waiting_for_auth"
+    | "syncing_secrets"
+    | "done"
+    | "timed_out"
+    | "denied"
+    | "failed";
+  verification_uri?: string | null;
+  error?: string | null;
+}
+
+export type LogLevel = "error" | "warn" | "info" | "debug" | "trace";
+
+export interface DeviceTrust {
+  user_id: string;
+  device_id: string;
+  display_name?: string | null;
+  status:
+    | "cross_signed"
+    | "locally_trusted"
+    | "unverified"
+    | "blacklisted"
+    | "unknown"
+    | "deleted";
+  cross_signed_by_owner: boolean;
+  locally_trusted: boolean;
+  blacklisted: boolean;
+  deleted: boolean;
+  fingerprint?: string | null;
+}
+
+export interface AllowedSpace {
+  room_id: string;
+  name?: string | null;
+}
+
+export type JoinRuleKind =
+  | "public"
+  | "invite"
+  | "knock"
+  | "restricted"
+  | "knock_restricted"
+  | "private";
+
+export interface JoinRuleInfo {
+  rule: JoinRuleKind;
+  allowed_spaces: AllowedSpace[];
+  room_version: string;
+  supports_knock: boolean;
+  supports_restricted: boolean;
+  can_change: boolean;
+}
+
+export interface SendBlocker {
+  code:
+    | "own_device_not_cross_signed"
+    | "key_upload_pending"
+    | "members_not_fetched";
+  message: string;
+}
+
+export interface SendReadiness {
+  room_id: string;
+  encrypted: boolean;
+  ready: boolean;
+  blockers: SendBlocker[];
+}
+
+
+export type ThumbnailSize = "small" | "medium" | "large" | "original";
+
+export interface MediaCacheStats {
+  entries: number;
+  total_bytes: number;
+  max_bytes: number;
+}
+
+
+export type AuditedStateEventType = "m.room.name" | "m.room.topic" | "m.room.power_levels";
+
+export interface StateChange {
+  event_id?: string;
+  sender: string;
+  timestamp: number;
+  old_value?: Record<string, unknown>;
+  new_value: Record<string, unknown>;
+}
+
+export interface StateHistory {
+  event_type: AuditedStateEventType;
+  changes: StateChange[];
+  truncated: boolean;
+}
+
+
+/** Payload of the `matrix://member-updated` event. */
+export interface MemberUpdate {
+  user_id: string;
+  display_name?: string;
+  avatar_url?: string;
+  room_ids: string[];
+}
+
+/** Payload of the `matrix://room-renamed` event. */
+export interface RoomRenamed {
+  room_id: string;
+  name: string;
+}
+
+
+export interface ClientConfig {
+  homeserver: string;
+  identity_server?: string;
+  jitsi_domain?: string;
+  fetched_at: number;
+}
+
+
+export interface StoreStats {
+  state_bytes: number;
+  event_cache_bytes: number;
+  media_bytes: number;
+  crypto_bytes: number;
+  /** Size of the local message index. */
+  message_index_bytes: number;
+  rooms: number;
+  olm_sessions: number | null;
+  megolm_sessions: number | null;
+  /** What the last retention prune of this session removed. */
+  last_prune: PruneReport | null;
+}
+
+/** Result of a local history retention prune. */
+export interface PruneReport {
+  pruned_at: number;
+  messages_removed: number;
+  media_files_removed: number;
+  /** Disk space given back, after vacuuming. */
+  freed_bytes: number;
+}
+
+/** Payload of the `matrix://cache-rebuild-progress` event. */
+export interface CacheRebuildProgress {
+  stage: "stopping" | "clearing" | "restoring" | "syncing" | "done" | "failed";
+  error: string | null;
+}
+
+
+export interface InviteUserResult {
+  user_id: string;
+  history_share_attempted: boolean;
+  history_shared: boolean;
+  history_share_error: string | null;
+}
+
+
+export interface ChangedIdentity {
+  user_id: string;
+  state: "pin_violation" | "verification_violation";
+}
+
+/** Payload of the `matrix://identity-changed` event. */
+export interface RoomIdentityChanges {
+  room_id: string;
+  users: ChangedIdentity[];
+}
+
+
+export interface DmSearchHit {
+  room_id: string;
+  /** Newest room of the conversation, shared by rooms before and after an upgrade. */
+  conversation_id: string;
+  event_id: string;
+  sender: string;
+  body: string;
+  timestamp: number;
+}
+
+export interface DmSearchResult {
+  hits: DmSearchHit[];
+  rooms_searched: string[];
+  truncated: boolean;
+}
+
+
+export interface OwnDevice {
+  device_id: string;
+  display_name: string | null;
+  last_seen_ip: string | null;
+  last_seen_ts: number | null;
+  is_current: boolean;
+  trust: DeviceTrust;
+}
+
+
+/** Payload of the `matrix://backfill-progress` event. */
+export interface BackfillProgress {
+  room_id: string;
+  events_fetched: number;
+  oldest_timestamp: number | null;
+  reached_start: boolean;
+  finished: boolean;
+}
+
+export interface BackfillResult {
+  room_id: string;
+  events_fetched: number;
+  total_events: number;
+  oldest_timestamp: number | null;
+  reached_start: boolean;
+}
+
+
+/** Payload of the `matrix://room-replaced` event. */
+export interface RoomReplaced {
+  room_id: string;
+  replacement_room_id: string;
+}
+
+
+/** Per-account preferences. Unknown keys written by newer versions are kept. */
+export interface Settings {
+  media_cache_limit: number;
+  expand_emoji_shortcodes: boolean;
+  /** Off on metered connections to skip the startup DM prefetch. */
+  prefetch_dm_previews: boolean;
+  /** Room ID to mode, for rooms not on "all". */
+  thread_notifications: Record<string, ThreadNotificationMode>;
+  /** Room ID to the policy list room IDs linked to it. */
+  policy_lists: Record<string, string[]>;
+  message_body_limit: number;
+  /** Enables developer tools such as `rawApiRequest`. */
+  developer_mode: boolean;
+  /** Days of history kept in the local message index and media cache; `null` keeps everything. */
+  local_history_retention_days: number | null;
+  /** Whether messages of encrypted rooms may be stored in plaintext in the local message index; off by default. */
+  index_encrypted_rooms: boolean;
+  /** Tags the DMs merged into another room as low priority. */
+  deprioritize_merged_dms: boolean;
+  /** How fast messages, invites and moderation actions are sent. */
+  outbound_limits: OutboundLimits;
+  /** When each notification snooze ends (ms since epoch), by room ID or `global`. */
+  notification_snoozes: Record<string, number>;
+  /** `private` sends `m.read.private`; `off` only moves the fully-read marker. */
+  send_read_receipts: "public" | "private" | "off";
+  /** Whether `setTyping` tells the room the user is typing. */
+  send_typing_notifications: boolean;
+  [key: string]: unknown;
+}
+
+
+export interface BatchInviteResult {
+  user_id: string;
+  status:
+    | "invited"
+    | "already_in_room"
+    | "invalid_id"
+    | "rate_limited"
+    | "permission_denied"
+    | "failed"
+    | "cancelled";
+  error: string | null;
+}
+
+/** Payload of the `matrix://invite-progress` event. */
+export interface InviteProgress {
+  room_id: string;
+  done: number;
+  total: number;
+}
+
+
+export interface ReadMarker {
+  event_id: string | null;
+  unread_count: number;
+  fallback_reason: "no_marker" | "marker_redacted" | "marker_unavailable" | null;
+}
+
+export interface MarkAllReadResult {
+  marked: number;
+  /** Rooms that couldn't be marked; the others were still done. */
+  failed: { room_id: string; error: string }[];
+}
+
+export interface EventContextResponse {
+  /** Oldest first, including the requested event. */
+  messages: Message[];
+  event_id: string;
+  before_token: string | null;
+  after_token: string | null;
+}
+
+/** Sync health since login; times in ms since the epoch. */
+export interface SyncDiagnostics {
+  last_success_at?: number | null;
+  last_duration_ms?: number | null;
+  consecutive_failures: number;
+  last_error?: string | null;
+  /** Over the past hour. */
+  average_gap_ms?: number | null;
+  events_processed: number;
+  degraded: boolean;
+}
+
+export interface MemberSuggestion {
+  user_id: string;
+  display_name?: string | null;
+  avatar_url?: string | null;
+}
+
+export interface MemberAutocomplete {
+  /** Recent senders first, then alphabetical. */
+  members: MemberSuggestion[];
+  /** The member list is still being fetched; ask again for complete results. */
+  may_be_incomplete: boolean;
+}
+
+export interface RoomMemberInfo {
+  user_id: string;
+  display_name?: string | null;
+  avatar_url?: string | null;
+  /** "join" or "invite". */
+  membership: string;
+  /** Rules of the room's linked policy lists that match the user. */
+  policy_flags: PolicyRule[];
+}
+
+export interface RoomMembersResponse {
+  members: RoomMemberInfo[];
+  /** False when the rest follows as `matrix://members-chunk` events. */
+  complete: boolean;
+}
+
+/** Payload of `matrix://members-chunk`; merge by user ID. */
+export interface MembersChunk {
+  room_id: string;
+  members: RoomMemberInfo[];
+}
+
+/** Payload of `matrix://members-complete`. */
+export interface MembersComplete {
+  room_id: string;
+  total: number;
+  error?: string | null;
+}
+
+/** Payload of `matrix://notification`. */
+export type NotificationImportance = "highlight" | "default" | "low" | "none";
+
+export interface MessageNotification {
+  room_id: string;
+  room_name?: string | null;
+  event_id: string;
+  sender: string;
+  sender_name?: string | null;
+  body: string;
+  timestamp: number;
+  importance: NotificationImportance;
+  /** The push rule's `sound` tweak, e.g. `default`. */
+  sound?: string | null;
+  is_dm: boolean;
+  is_mention: boolean;
+  is_keyword: boolean;
+  /** The room is open; only highlights are sent then. */
+  viewing_room: boolean;
+}
+
+/** Payload of `matrix://dismiss-notification`: read on another session. */
+export interface DismissNotifications {
+  room_id: string;
+  event_ids: string[];
+}
+
+export interface DehydrationStatus {
+  /** Another client set one up for the account. */
+  exists: boolean;
+  device_id?: string | null;
+}
+
+export interface DirectoryVisibility {
+  /** Listed in the server's public room directory. */
+  public: boolean;
+  can_change: boolean;
+}
+
+export interface RoomSettings {
+  room_id: string;
+  name?: string | null;
+  topic?: string | null;
+  canonical_alias?: string | null;
+  join_rule?: string | null;
+  history_visibility?: string | null;
+  encrypted: boolean;
+  /** Null when the server couldn't be asked. */
+  directory_public?: boolean | null;
+}
+
+export interface AccountExportResult {
+  path: string;
+  rooms: number;
+  messages_exported: number;
+}
+
+/** Payload of `matrix://account-export-progress`. */
+export interface AccountExportProgress {
+  step: "rooms" | "keys" | "account_data" | "profile" | "devices" | "messages" | "done";
+  rooms_done: number;
+  rooms_total: number;
+}
+
+/** Where the user left a room, for reopening it there. */
+export interface RoomViewState {
+  event_id: string;
+  /** `fromToken` for `getMessages` loading the page around `event_id`. */
+  pagination_token: string | null;
+}
+
+/** Payload of the `matrix://store-migration` event, sent while a saved
+ * session's directory is checked and migrated on start. */
+export interface StoreMigrationProgress {
+  stage: "renaming" | "migrating" | "done" | "failed";
+  /** The database being migrated, during `migrating`. */
+  store: string | null;
+  error: string | null;
+}
+
+export interface AppLockStatus {
+  enabled: boolean;
+  locked: boolean;
+  auto_lock_secs: number | null;
+  wipe_after: number | null;
+  /** False for a session logged in before the PIN was set, until the next login. */
+  store_encrypted: boolean;
+}
+
+/** The user's last message in a room, from any of their devices. */
+export interface MyRecentActivity {
+  event_id: string;
+  timestamp: number;
+  /** Known for encrypted messages only. */
+  device_id?: string | null;
+  device_name?: string | null;
+  this_device: boolean;
+}
+
+export interface RoomNotificationSettings {
+  mode: "all_messages" | "mentions_and_keywords" | "mute";
+  /** Whether `mode` was set for this room rather than being the default. */
+  custom: boolean;
+  keywords: RoomKeyword[];
+}
+
+/** A keyword that notifies only in one room. */
+export interface RoomKeyword {
+  keyword: string;
+  enabled: boolean;
+  /** The same keyword also notifies in every room. */
+  also_global: boolean;
+}
+
+/** Payload of `matrix://verification-expired`. */
+export interface VerificationExpired {
+  flow_id: string;
+}
+
+export type SecurityRecommendationCode =
+  | "setup_recovery"
+  | "verify_this_session"
+  | "enter_recovery_key"
+  | "turn_on_backup"
+  | "identity_reset_required"
+  | "all_good";
+
+export interface SecurityRecommendation {
+  code: SecurityRecommendationCode;
+  other_verified_sessions: number;
+  this_session_verified: boolean;
+  recovery_set_up: boolean;
+  backup_on_server: boolean;
+}
+
+/** Payload of `matrix://room-preview-ready`: the DM's first page is cached,
+ * so opening it needs no network. */
+export interface RoomPreviewReady {
+  room_id: string;
+  events: number;
+}
+
+export interface AliasAvailability {
+  /** Completed alias, e.g. `#room:example.org`. */
+  alias: string;
+  status: "free" | "taken" | "invalid";
+  reason: string | null;
+  room_id: string | null;
+}
+
+/** Which thread replies notify in a room; mentions always do. */
+export type ThreadNotificationMode = "all" | "participating" | "none";
+
+export interface ThreadMessages {
+  /** Oldest first. */
+  messages: Message[];
+  next_token: string | null;
+  unread_count: number;
+  participating: boolean;
+}
+
+export interface FullEventBody {
+  body: string;
+  segments: Segment[] | null;
+}
+
+
+export type ComposeContext =
+  | { kind: "reply"; event_id: string }
+  | { kind: "edit"; event_id: string }
+  | { kind: "thread"; root_event_id: string };
+
+export interface DraftView {
+  body: string;
+  context: ComposeContext | null;
+  /** The event replied to, edited, or starting the thread. */
+  quoted: Message | null;
+}
+
+
+export type PollKind = "disclosed" | "undisclosed";
+
+export interface PollAnswer {
+  id: string;
+  text: string;
+  /** `null` while an undisclosed poll is still open. */
+  votes: number | null;
+}
+
+
+export type SavedMessageStatus = "available" | "redacted" | "unavailable";
+
+export interface SavedMessage {
+  room_id: string;
+  room_name: string | null;
+  event_id: string;
+  saved_at: number;
+  status: SavedMessageStatus;
+  /** Set when `status` is "available". */
+  message: Message | null;
+}
+
+
+/** Payload of `matrix://keys-imported`, after recovery or a backup download. */
+export interface KeysImported {
+  /** Most sessions first. */
+  rooms: { room_id: string; sessions: number }[];
+  sessions: number;
+}
+
+export interface BanListExport {
+  path: string;
+  bans: number;
+}
+
+export interface BanImportResult {
+  user_id: string;
+  status:
+    | "banned"
+    | "would_ban"
+    | "already_banned"
+    | "invalid_id"
+    | "permission_denied"
+    | "rate_limited"
+    | "failed";
+  error?: string | null;
+}
+
+/** Payload of `matrix://ban-import-progress`. */
+export interface BanImportProgress {
+  room_id: string;
+  done: number;
+  total: number;
+}
+
+/** A user rule from a linked policy list; shown, not enforced. */
+export interface PolicyRule {
+  list_room_id: string;
+  list_name?: string | null;
+  /** User ID glob with `*` and `?`. */
+  entity: string;
+  /** Usually "m.ban". */
+  recommendation: string;
+  reason?: string | null;
+}
+
+export interface PolicyList {
+  room_id: string;
+  name?: string | null;
+  joined: boolean;
+  user_rules: number;
+}
+
+export interface RawApiResponse {
+  status: number;
+  headers: Record<string, string>;
+  /** JSON, a string for non-JSON responses, or null when empty. */
+  body: unknown;
+}
+
+
+/** The account's push rules, as returned by `refreshPushRules`. */
+export interface PushRuleset {
+  override: unknown[];
+  content: unknown[];
+  room: unknown[];
+  sender: unknown[];
+  underride: unknown[];
+}
+
+
+/** A contact's DMs; messages to them go to `canonical_room_id`. */
+export interface MergedDm {
+  user_id: string;
+  display_name?: string | null;
+  /** Where `sendMessage` to any of `room_ids` goes. */
+  canonical_room_id: string;
+  /** Canonical first; pass to `getMessages` as `mergedRoomIds`. */
+  room_ids: string[];
+  last_active: number;
+}
+
+
+export type OutboundClass = "messages" | "invites" | "moderation";
+
+/** Up to `burst` requests at once, then `per_minute`. */
+export interface RateLimit {
+  burst: number;
+  per_minute: number;
+}
+
+/** `null` leaves a class unlimited, apart from the server's own `retry_after`. */
+export interface OutboundLimits {
+  messages: RateLimit | null;
+  invites: RateLimit | null;
+  moderation: RateLimit | null;
+}
+
+export interface QueuedOperation {
+  id: number;
+  class: OutboundClass;
+  description: string;
+  queued_at: number;
+  /** When it is expected to go out. */
+  ready_at: number;
+}
+
+/** Payload of `matrix://outbound-throttled`. */
+export interface OutboundThrottled {
+  class: OutboundClass;
+  wait_ms: number;
+  /** Whether the server asked for the wait with a 429. */
+  server_limited: boolean;
+}
+
+export type UserVerificationPhase =
+  | "requested"
+  | "ready"
+  | "started"
+  | "comparing"
+  | "confirmed"
+  | "done"
+  | "cancelled";
+
+export interface UserVerificationStatus {
+  flow_id: string;
+  room_id: string;
+  user_id: string;
+  phase: UserVerificationPhase;
+  emoji: [string, string, string][] | null;
+  cancel_code: string | null;
+  cancel_reason: string | null;
+}
+
+/** History a resumed sync skipped; payload of `matrix://sync-gap`. */
+export interface GapInfo {
+  gap_id: string;
+  room_id: string;
+  after_event_id: string;
+  until_timestamp: number;
+  since_timestamp: number | null;
+}
+
+export interface GapFill {
+  /** Oldest first, to splice in at the gap's entry. */
+  messages: Message[];
+  remaining: GapInfo | null;
+}
+
+export interface MediaSupport {
+  /** The homeserver offers authenticated media (Matrix 1.11). */
+  authenticated_media: boolean;
+}
+
+/** One entry of `details.outcomes` when joining or previewing through
+ * via servers fails. */
+export interface ViaServerOutcome {
+  server: string;
+  category: "not_found" | "forbidden" | "timeout" | "unreachable" | "rate_limited" | "failed";
+  message: string;
+}
+
+export interface RoomPreviewInfo {
+  room_id: string;
+  canonical_alias: string | null;
+  name: string | null;
+  topic: string | null;
+  avatar_url: string | null;
+  num_joined_members: number;
+  room_type: string | null;
+  join_rule: string | null;
+  is_world_readable: boolean | null;
+  membership: "joined" | "left" | "invited" | "knocked" | "banned" | null;
+}
+
+/** Notifications paused in a room, or everywhere with scope `global`. */
+export interface NotificationSnooze {
+  scope: string;
+  /** When notifications resume, ms since epoch. */
+  until: number;
+}
+
+export interface NotificationSettings {
+  snoozes: NotificationSnooze[];
+}
+
+/** Payload of `matrix://snooze-expired`. */
+export interface SnoozeExpired {
+  scope: string;
+}
+
+export interface NotificationBadge {
+  notifications: number;
+  highlights: number;
+}
+
 
 // src/types/index.ts
 export interface VerificationStatus {